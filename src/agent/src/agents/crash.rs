@@ -0,0 +1,134 @@
+//! Core dumps of the workloads killed by a signal.
+//!
+//! The kernel writes the core of a crashed process to [`CORE_DIR`], cut at [`MAX_CORE_BYTES`].
+//! Once the workload is reaped, the core and its backtrace, printed by `gdb` or `rust-lldb` when
+//! the image has one, are sent back with the final message of the run.
+
+use super::supervisor::ProcessTree;
+use crate::agent::RunArtifact;
+use nix::sys::resource::{setrlimit, Resource};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::timeout;
+use tracing::warn;
+
+/// Where the cores are written, named after the pid of the crashed process.
+const CORE_DIR: &str = "/tmp/cores";
+
+/// Size at which the cores are cut.
+pub const MAX_CORE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Size at which the backtraces are cut.
+const MAX_BACKTRACE_BYTES: usize = 1024 * 1024;
+
+/// Time given to the debugger to print the backtrace of a core.
+const DEBUGGER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Have the kernel write the cores of the crashed processes to [`CORE_DIR`].
+pub fn enable_core_dumps() {
+    let result = fs::create_dir_all(CORE_DIR).and_then(|()| {
+        fs::write(
+            "/proc/sys/kernel/core_pattern",
+            format!("{}/core.%p", CORE_DIR),
+        )
+    });
+    if let Err(e) = result {
+        warn!(
+            "The cores of the crashed workloads won't be collected: {}",
+            e
+        );
+    }
+}
+
+/// Let the process spawned by `command` dump its core, up to [`MAX_CORE_BYTES`].
+pub fn allow_core_dump(command: &mut Command) {
+    // SAFETY: `setrlimit` is async-signal-safe.
+    unsafe {
+        command.pre_exec(|| {
+            setrlimit(Resource::RLIMIT_CORE, MAX_CORE_BYTES, MAX_CORE_BYTES)?;
+            Ok(())
+        });
+    }
+}
+
+/// Collect the core dumped by the leader of `tree`, and its backtrace.
+pub async fn collect(tree: &ProcessTree) -> Vec<RunArtifact> {
+    let core_path = PathBuf::from(format!("{}/core.{}", CORE_DIR, tree.leader()));
+    let core = match fs::read(&core_path) {
+        Ok(core) => core,
+        Err(e) => {
+            warn!("Could not read the core of {}: {}", tree.leader(), e);
+            return Vec::new();
+        }
+    };
+    let backtrace = backtrace(tree, &core_path).await;
+    let _ = fs::remove_file(&core_path);
+
+    let mut artifacts = vec![RunArtifact {
+        name: "core".into(),
+        size: core.len() as u64,
+        // The kernel stops writing the core at its limit.
+        truncated: core.len() as u64 >= MAX_CORE_BYTES,
+        content: core,
+    }];
+    if let Some(mut backtrace) = backtrace {
+        let size = backtrace.len() as u64;
+        let truncated = backtrace.len() > MAX_BACKTRACE_BYTES;
+        backtrace.truncate(MAX_BACKTRACE_BYTES);
+        artifacts.push(RunArtifact {
+            name: "backtrace".into(),
+            content: backtrace,
+            size,
+            truncated,
+        });
+    }
+    artifacts
+}
+
+/// Backtrace of the threads of the leader of `tree` in its `core`, by the first debugger found
+/// in the image.
+async fn backtrace(tree: &ProcessTree, core: &Path) -> Option<Vec<u8>> {
+    let program = tree.program();
+    let debuggers: [(&str, Vec<&str>); 2] = [
+        ("gdb", vec!["--batch", "-nx", "-ex", "thread apply all bt"]),
+        ("rust-lldb", vec!["--batch", "-o", "bt all", "-c"]),
+    ];
+    for (debugger, args) in debuggers {
+        let mut command = Command::new(debugger);
+        command
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        // gdb takes the program then the core, lldb the core after `-c` then the program.
+        if debugger == "gdb" {
+            command.arg(program).arg(core);
+        } else {
+            command.arg(core).arg(program);
+        }
+
+        // Tracked like the workloads, so that the agent doesn't reap it before tokio.
+        let (child, debugger_tree) = match tree.spawn_sibling(&mut command).await {
+            Ok(spawned) => spawned,
+            // Not in the image.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                warn!("Could not run {}: {}", debugger, e);
+                continue;
+            }
+        };
+        let output = timeout(DEBUGGER_TIMEOUT, child.wait_with_output()).await;
+        debugger_tree.finish().await;
+        match output {
+            Ok(Ok(output)) if !output.stdout.is_empty() => return Some(output.stdout),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("Could not run {}: {}", debugger, e),
+            Err(_) => warn!("{} timed out printing the backtrace", debugger),
+        }
+    }
+    None
+}
@@ -1,3 +1,4 @@
+use super::files::WORKLOAD_DIR;
 use super::AgentOutput;
 use crate::agent::execute_response::Stage;
 use crate::agents::Agent;
@@ -5,10 +6,12 @@ use crate::{workload, AgentResult};
 use async_trait::async_trait;
 use std::collections::HashSet;
 use std::fs::create_dir_all;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::sync::mpsc::{self, Receiver};
 use tokio::sync::Mutex;
+use tracing::debug;
 
 pub struct DebugAgent {
     workload_config: workload::config::Config,
@@ -22,15 +25,21 @@ impl From<workload::config::Config> for DebugAgent {
 
 #[async_trait]
 impl Agent for DebugAgent {
-    async fn prepare(&self, _: Arc<Mutex<HashSet<u32>>>) -> AgentResult<Receiver<AgentOutput>> {
-        let dir = format!("/tmp/{}", self.workload_config.workload_name);
+    async fn fetch_deps(&self, _: Arc<Mutex<HashSet<u32>>>) -> AgentResult<Receiver<AgentOutput>> {
+        // No dependencies, the channel is closed right away.
+        let (_, rx) = mpsc::channel(1);
+        Ok(rx)
+    }
+
+    async fn build(&self, _: Arc<Mutex<HashSet<u32>>>) -> AgentResult<Receiver<AgentOutput>> {
+        let dir = format!("{}/{}", WORKLOAD_DIR, self.workload_config.workload_name);
 
-        println!("Function directory: {}", dir);
+        debug!("Function directory: {}", dir);
 
         create_dir_all(&dir).expect("Unable to create directory");
 
         std::fs::write(
-            format!("{}/debug.txt", &dir),
+            self.artifact_path(),
             format!(
                 "Debug agent for {} - written at {:?}",
                 self.workload_config.workload_name,
@@ -47,6 +56,7 @@ impl Agent for DebugAgent {
                     stdout: Some("Build successfully!".into()),
                     stderr: None,
                     exit_code: None,
+                    ..Default::default()
                 })
                 .await;
         });
@@ -55,9 +65,9 @@ impl Agent for DebugAgent {
     }
 
     async fn run(&self, _: Arc<Mutex<HashSet<u32>>>) -> AgentResult<Receiver<AgentOutput>> {
-        let dir = format!("/tmp/{}", self.workload_config.workload_name);
+        let dir = format!("{}/{}", WORKLOAD_DIR, self.workload_config.workload_name);
 
-        let content = std::fs::read_to_string(format!("{}/debug.txt", &dir));
+        let content = std::fs::read_to_string(self.artifact_path());
 
         std::fs::remove_dir_all(dir).expect("Unable to remove directory");
 
@@ -67,9 +77,10 @@ impl Agent for DebugAgent {
                 let _ = tx
                     .send(AgentOutput {
                         stage: Stage::Done,
-                        stdout: Some(content),
+                        stdout: Some(content.into_bytes()),
                         stderr: None,
                         exit_code: Some(0),
+                        ..Default::default()
                     })
                     .await;
             }
@@ -80,10 +91,18 @@ impl Agent for DebugAgent {
                     stdout: None,
                     stderr: Some("unable to read debug.txt".into()),
                     exit_code: Some(1),
+                    ..Default::default()
                 })
                 .await;
         });
 
         Ok(rx)
     }
+
+    fn artifact_path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/{}/debug.txt",
+            WORKLOAD_DIR, self.workload_config.workload_name
+        ))
+    }
 }
@@ -0,0 +1,256 @@
+//! Files the workloads leave in the guest, listed and read to debug them, e.g. the project of a
+//! failed build, and the files exchanged with the steps of a pipeline: the inputs written to
+//! [`INPUTS_DIR`] before the workload starts, and the outputs sent back once it succeeded.
+//!
+//! Only the paths under [`WORKLOAD_DIR`] can be inspected: the requested path must be relative,
+//! without `..`, and must still be under the directory once its symlinks are resolved.
+//!
+//! The directory is a tmpfs, kept in the memory of the guest: before each run, it is remounted
+//! with room for the quota of the run on top of what the earlier runs left, so that a workload
+//! writing too much fails with `ENOSPC` instead of the guest running out of memory.
+//!
+//! A guest reused from a pool is scrubbed before its next run: everything in the directory but
+//! the built workload is removed, so that a run doesn't see the files, inputs and cores of the
+//! earlier ones, and the tmpfs gives their pages back.
+
+use crate::agent::{dir_entry::Kind, DirEntry, ListDirResponse, ReadFileResponse, RunArtifact};
+use crate::{AgentError, AgentResult};
+use nix::mount::{mount, MsFlags};
+use nix::sys::statvfs::statvfs;
+use shared_models::OUTPUT_ARTIFACT_PREFIX;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Where the workloads are built and run, and where their cores are written.
+pub const WORKLOAD_DIR: &str = "/tmp";
+
+/// Bytes read at most from a file.
+pub const MAX_READ_BYTES: u64 = 1024 * 1024;
+
+/// Entries listed at most from a directory.
+const MAX_DIR_ENTRIES: usize = 10_000;
+
+/// Where the inputs of a workload are written, also given to it as `CLOUDLET_INPUTS_DIR`.
+pub const INPUTS_DIR: &str = "/tmp/inputs";
+
+/// Size at which the outputs are cut.
+pub const MAX_OUTPUT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Share of the memory of the guest the files of a run may take, when it doesn't set its quota.
+const DEFAULT_TMP_QUOTA_DIVISOR: u64 = 4;
+
+/// `path`, once checked to be relative and without `..`.
+fn relative(path: &str) -> AgentResult<&Path> {
+    let relative = Path::new(path);
+    let normal = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !normal || path.is_empty() {
+        return Err(AgentError::InvalidPath(path.to_string()));
+    }
+    Ok(relative)
+}
+
+/// Path of `path`, relative to [`WORKLOAD_DIR`], once checked to stay under it.
+fn resolve(path: &str) -> AgentResult<PathBuf> {
+    let relative = relative(path)?;
+
+    let root =
+        fs::canonicalize(WORKLOAD_DIR).map_err(|e| AgentError::Inspect(path.to_string(), e))?;
+    let resolved = fs::canonicalize(root.join(relative))
+        .map_err(|e| AgentError::Inspect(path.to_string(), e))?;
+    if !resolved.starts_with(&root) {
+        return Err(AgentError::InvalidPath(path.to_string()));
+    }
+    Ok(resolved)
+}
+
+fn dir_entry(name: String, path: &Path, metadata: &Metadata) -> DirEntry {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        Kind::Symlink
+    } else if file_type.is_dir() {
+        Kind::Directory
+    } else if file_type.is_file() {
+        Kind::File
+    } else {
+        Kind::Other
+    };
+    DirEntry {
+        name,
+        kind: kind as i32,
+        size: metadata.len(),
+        mode: metadata.permissions().mode() & 0o7777,
+        modified: metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_secs()),
+        target: match kind {
+            Kind::Symlink => fs::read_link(path)
+                .map(|target| target.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            _ => String::new(),
+        },
+    }
+}
+
+/// Entries of the directory at `path`, sorted by name.
+pub fn list_dir(path: &str) -> AgentResult<ListDirResponse> {
+    let dir = resolve(path)?;
+    let inspect = |e| AgentError::Inspect(path.to_string(), e);
+
+    let mut entries = Vec::new();
+    let mut truncated = false;
+    for entry in fs::read_dir(&dir).map_err(inspect)? {
+        let entry = entry.map_err(inspect)?;
+        if entries.len() == MAX_DIR_ENTRIES {
+            truncated = true;
+            break;
+        }
+        // Not followed, a symlink being listed as such.
+        let Ok(metadata) = entry.path().symlink_metadata() else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        entries.push(dir_entry(name, &entry.path(), &metadata));
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(ListDirResponse { entries, truncated })
+}
+
+/// Start of the file at `path`, up to `max_bytes` and [`MAX_READ_BYTES`].
+pub fn read_file(path: &str, max_bytes: u64) -> AgentResult<ReadFileResponse> {
+    let file_path = resolve(path)?;
+    let inspect = |e| AgentError::Inspect(path.to_string(), e);
+
+    let file = File::open(&file_path).map_err(inspect)?;
+    let metadata = file.metadata().map_err(inspect)?;
+    if !metadata.is_file() {
+        return Err(AgentError::InvalidPath(path.to_string()));
+    }
+    let max_bytes = match max_bytes {
+        0 => MAX_READ_BYTES,
+        max_bytes => max_bytes.min(MAX_READ_BYTES),
+    };
+
+    let mut content = Vec::new();
+    file.take(max_bytes)
+        .read_to_end(&mut content)
+        .map_err(inspect)?;
+    let size = metadata.len();
+    Ok(ReadFileResponse {
+        truncated: (content.len() as u64) < size,
+        content,
+        size,
+    })
+}
+
+/// Memory of the guest, from `/proc/meminfo`.
+fn memory_bytes() -> io::Result<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo")?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|total| {
+            total
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .map(|kb| kb << 10)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no MemTotal in /proc/meminfo"))
+}
+
+/// Remount [`WORKLOAD_DIR`] with room for `quota_mb` more, or for a quarter of the memory of the
+/// guest if 0. Returns the quota, in bytes.
+pub fn limit_workload_dir(quota_mb: u32) -> io::Result<u64> {
+    let quota = match quota_mb {
+        0 => memory_bytes()? / DEFAULT_TMP_QUOTA_DIVISOR,
+        quota_mb => u64::from(quota_mb) << 20,
+    };
+    let stat = statvfs(WORKLOAD_DIR)?;
+    let used = (stat.blocks() - stat.blocks_free()) as u64 * stat.fragment_size() as u64;
+    // The flags of the mount are replaced, they are those it was mounted with by the init.
+    mount(
+        None::<&str>,
+        WORKLOAD_DIR,
+        None::<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(format!("size={}", used + quota).as_str()),
+    )?;
+    Ok(quota)
+}
+
+/// Remove everything under [`WORKLOAD_DIR`] but the entry holding `built`, the built workload.
+/// Returns the number of entries removed.
+pub fn scrub_workload_dir(built: &Path) -> io::Result<usize> {
+    let kept = built
+        .strip_prefix(WORKLOAD_DIR)
+        .ok()
+        .and_then(|path| path.components().next())
+        .map(|component| component.as_os_str().to_os_string());
+    let mut removed = 0;
+    for entry in fs::read_dir(WORKLOAD_DIR)? {
+        let entry = entry?;
+        if Some(entry.file_name()) == kept {
+            continue;
+        }
+        // Not followed, a symlink being removed as such.
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Whether [`WORKLOAD_DIR`] is full, the quota of the run reached.
+pub fn workload_dir_full() -> bool {
+    statvfs(WORKLOAD_DIR).is_ok_and(|stat| stat.blocks_available() == 0)
+}
+
+/// Write each of `inputs` under [`INPUTS_DIR`], at the path of its name.
+pub fn write_inputs(inputs: &[RunArtifact]) -> AgentResult<()> {
+    for input in inputs {
+        let path = Path::new(INPUTS_DIR).join(relative(&input.name)?);
+        let write = |e| AgentError::WriteInput(input.name.clone(), e);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(write)?;
+        }
+        fs::write(&path, &input.content).map_err(write)?;
+    }
+    Ok(())
+}
+
+/// The files at `paths`, relative to [`WORKLOAD_DIR`], as the artifacts `output:<path>`, each
+/// cut at [`MAX_OUTPUT_BYTES`].
+pub fn collect_outputs(paths: &[String]) -> AgentResult<Vec<RunArtifact>> {
+    paths
+        .iter()
+        .map(|path| {
+            let file_path = resolve(path)?;
+            let inspect = |e| AgentError::Inspect(path.to_string(), e);
+            let file = File::open(&file_path).map_err(inspect)?;
+            let size = file.metadata().map_err(inspect)?.len();
+            let mut content = Vec::new();
+            file.take(MAX_OUTPUT_BYTES)
+                .read_to_end(&mut content)
+                .map_err(inspect)?;
+            Ok(RunArtifact {
+                name: format!("{}{}", OUTPUT_ARTIFACT_PREFIX, path),
+                truncated: (content.len() as u64) < size,
+                content,
+                size,
+            })
+        })
+        .collect()
+}
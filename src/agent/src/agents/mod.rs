@@ -1,51 +1,122 @@
 use crate::{
-    agent::{execute_response::Stage, ExecuteResponse},
+    agent::{
+        execute_response::{Encoding, Stage},
+        ExecuteResponse, OutputSummary, RunArtifact, StageMarker,
+    },
     AgentError, AgentResult,
 };
 use async_trait::async_trait;
 use serde::Deserialize;
+use shared_models::Redactor;
 use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
 
+pub mod crash;
 #[cfg(feature = "debug-agent")]
 pub mod debug;
+pub mod files;
+pub mod output;
+#[cfg(feature = "rust")]
 pub mod rust;
+#[cfg(feature = "exec-shell")]
+pub mod shell;
+pub mod supervisor;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct AgentOutput {
     pub stage: Stage,
-    pub stdout: Option<String>,
-    pub stderr: Option<String>,
+    pub stdout: Option<Vec<u8>>,
+    pub stderr: Option<Vec<u8>>,
     pub exit_code: Option<i32>,
+    /// Whether output was cut at this point, or, on the final message, at all.
+    pub truncated: bool,
+    /// Summary of the output, on the final message.
+    pub output: Option<OutputSummary>,
+    /// Start or end of a stage of the pipeline, on a message without output.
+    pub marker: Option<StageMarker>,
+    /// Built workload, on the marker ending the build when it was requested.
+    pub artifact: Option<Vec<u8>>,
+    /// Signal which killed the process, on the final message.
+    pub signal: Option<i32>,
+    pub core_dumped: bool,
+    /// Core dump and backtrace of a crashed process, on the final message.
+    pub artifacts: Vec<RunArtifact>,
+}
+
+impl AgentOutput {
+    /// The same output, with the values of the secret variables replaced.
+    pub fn redacted(mut self, redactor: &Redactor) -> Self {
+        if !redactor.is_empty() {
+            self.stdout = self.stdout.map(|stdout| redactor.redact_bytes(&stdout));
+            self.stderr = self.stderr.map(|stderr| redactor.redact_bytes(&stderr));
+        }
+        self
+    }
 }
 
 impl From<AgentOutput> for ExecuteResponse {
     fn from(value: AgentOutput) -> Self {
+        let is_utf8 = [&value.stdout, &value.stderr]
+            .into_iter()
+            .flatten()
+            .all(|output| std::str::from_utf8(output).is_ok());
+        let encoding = if is_utf8 {
+            Encoding::Utf8
+        } else {
+            Encoding::Binary
+        };
+
         Self {
             stage: value.stage as i32,
             stdout: value.stdout,
             stderr: value.stderr,
             exit_code: value.exit_code,
+            truncated: value.truncated,
+            output: value.output,
+            encoding: encoding as i32,
+            marker: value.marker,
+            artifact: value.artifact.unwrap_or_default(),
+            build_id: String::new(),
+            timestamp_ms: 0,
+            signal: value.signal.unwrap_or_default(),
+            core_dumped: value.core_dumped,
+            artifacts: value.artifacts,
+            // Set by the orchestrator.
+            vm_exit: None,
+            gap: None,
         }
     }
 }
 
+/// Stages of the pipeline of a language. The output of a stage which fails ends with a
+/// message of the `Failed` stage.
 #[async_trait]
 pub trait Agent {
-    async fn prepare(
+    /// Fetch the dependencies of the workload, ahead of its build.
+    async fn fetch_deps(
+        &self,
+        child_processes: Arc<Mutex<HashSet<u32>>>,
+    ) -> AgentResult<mpsc::Receiver<AgentOutput>>;
+    /// Build the workload to [`Agent::artifact_path`].
+    async fn build(
         &self,
         child_processes: Arc<Mutex<HashSet<u32>>>,
     ) -> AgentResult<mpsc::Receiver<AgentOutput>>;
+    /// Run the workload found at [`Agent::artifact_path`].
     async fn run(
         &self,
         child_processes: Arc<Mutex<HashSet<u32>>>,
     ) -> AgentResult<mpsc::Receiver<AgentOutput>>;
+    /// Where the built workload is, whether it was built in the guest or sent by the request.
+    fn artifact_path(&self) -> PathBuf;
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum Language {
+    #[cfg(feature = "rust")]
     Rust,
     #[cfg(feature = "debug-agent")]
     Debug,
@@ -54,6 +125,7 @@ pub enum Language {
 impl std::fmt::Display for Language {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            #[cfg(feature = "rust")]
             Language::Rust => write!(f, "rust"),
             #[cfg(feature = "debug-agent")]
             Language::Debug => write!(f, "debug"),
@@ -66,6 +138,7 @@ impl TryFrom<&str> for Language {
 
     fn try_from(value: &str) -> Result<Self, AgentError> {
         match value {
+            #[cfg(feature = "rust")]
             "rust" => Ok(Language::Rust),
             #[cfg(feature = "debug-agent")]
             "debug" => Ok(Language::Debug),
@@ -78,71 +151,108 @@ impl TryFrom<&str> for Language {
 }
 
 mod process_utils {
-    use super::AgentOutput;
+    use super::output::{is_truncated, read_line_capped, Admission, OutputBudget};
+    use super::supervisor::ProcessTree;
+    use super::{crash, AgentOutput};
     use crate::agent::execute_response::Stage;
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::Arc;
     use tokio::{
-        io::{AsyncBufReadExt, BufReader},
+        io::{AsyncRead, BufReader},
         process::{ChildStderr, ChildStdout},
         sync::mpsc,
         task::JoinHandle,
     };
 
-    /// Spawn a tokio thread and send each line of `stdout`` to the `tx` given as a parameter.
-    pub async fn send_stdout_to_tx(
-        stdout: ChildStdout,
+    /// Spawn a tokio thread sending each line of `reader` to `tx`, within the `budget`.
+    fn send_lines_to_tx<R>(
+        reader: R,
         tx: mpsc::Sender<AgentOutput>,
         stage: Option<Stage>,
-    ) -> JoinHandle<()> {
+        budget: Arc<OutputBudget>,
+        is_stderr: bool,
+    ) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
         tokio::spawn(async move {
-            let reader = BufReader::new(stdout);
-            let mut reader_lines = reader.lines();
+            let stage = stage.unwrap_or(Stage::Running);
+            let mut reader = BufReader::new(reader);
+            let mut line = Vec::new();
 
-            while let Ok(Some(line)) = reader_lines.next_line().await {
+            // Keep reading past the limits, so that the workload isn't blocked on a full pipe.
+            while let Ok(Some(cut)) = read_line_capped(&mut reader, &mut line).await {
+                let (output, truncated) = match budget.admit(line.len(), cut) {
+                    Admission::Send { cut } => (std::mem::take(&mut line), cut),
+                    Admission::Drop { mark: true } => (budget.marker().into_bytes(), true),
+                    Admission::Drop { mark: false } => continue,
+                };
+                let (stdout, stderr) = if is_stderr {
+                    (None, Some(output))
+                } else {
+                    (Some(output), None)
+                };
                 let _ = tx
                     .send(AgentOutput {
-                        stage: stage.unwrap_or(Stage::Running),
-                        stdout: Some(line),
-                        stderr: None,
-                        exit_code: None,
+                        stage,
+                        stdout,
+                        stderr,
+                        truncated,
+                        ..Default::default()
                     })
                     .await;
             }
         })
     }
 
+    /// Spawn a tokio thread and send each line of `stdout`` to the `tx` given as a parameter.
+    pub async fn send_stdout_to_tx(
+        stdout: ChildStdout,
+        tx: mpsc::Sender<AgentOutput>,
+        stage: Option<Stage>,
+        budget: Arc<OutputBudget>,
+    ) -> JoinHandle<()> {
+        send_lines_to_tx(stdout, tx, stage, budget, false)
+    }
+
     /// Same as [`send_stdout_to_tx`].
     pub async fn send_stderr_to_tx(
         stderr: ChildStderr,
         tx: mpsc::Sender<AgentOutput>,
         stage: Option<Stage>,
+        budget: Arc<OutputBudget>,
     ) -> JoinHandle<()> {
-        tokio::spawn(async move {
-            let reader = BufReader::new(stderr);
-            let mut reader_lines = reader.lines();
-
-            while let Ok(Some(line)) = reader_lines.next_line().await {
-                let _ = tx
-                    .send(AgentOutput {
-                        stage: stage.unwrap_or(Stage::Running),
-                        stdout: Some(line),
-                        stderr: None,
-                        exit_code: None,
-                    })
-                    .await;
-            }
-        })
+        send_lines_to_tx(stderr, tx, stage, budget, true)
     }
 
     /// Function to wait for the `child` to finish and send the result to the `tx` given as a parameter.
+    /// What is left of its `tree` is killed first, and its output `streams` read to their end, so
+    /// that the final message carries the summary of the whole output streamed within the `budget`.
+    /// If the `child` crashed, the message carries its signal, and its core dump and backtrace.
     pub async fn send_exit_status_to_tx(
         mut child: tokio::process::Child,
+        tree: ProcessTree,
+        streams: Vec<JoinHandle<()>>,
         tx: mpsc::Sender<AgentOutput>,
         send_done: bool,
+        budget: &OutputBudget,
     ) -> Result<(), ()> {
-        let exit_status = child.wait().await.map(|status| status.code());
+        let exit_status = child.wait().await;
+        let artifacts = match &exit_status {
+            Ok(status) if status.core_dumped() => crash::collect(&tree).await,
+            _ => Vec::new(),
+        };
+        // A process left behind could keep the pipes open, the streams end once it's killed.
+        tree.finish().await;
+        for stream in streams {
+            let _ = stream.await;
+        }
+        let output = budget.summary();
+        let truncated = is_truncated(&output);
 
         match exit_status {
-            Ok(exit_code) => {
+            Ok(status) => {
+                let exit_code = status.code();
                 if exit_code != Some(0_i32) {
                     let _ = tx
                         .send(AgentOutput {
@@ -150,6 +260,12 @@ mod process_utils {
                             stdout: None,
                             stderr: None,
                             exit_code,
+                            truncated,
+                            output: Some(output),
+                            signal: status.signal(),
+                            core_dumped: status.core_dumped(),
+                            artifacts,
+                            ..Default::default()
                         })
                         .await;
 
@@ -162,6 +278,9 @@ mod process_utils {
                                 stdout: None,
                                 stderr: None,
                                 exit_code,
+                                truncated,
+                                output: Some(output),
+                                ..Default::default()
                             })
                             .await;
                     }
@@ -174,8 +293,11 @@ mod process_utils {
                     .send(AgentOutput {
                         stage: Stage::Failed,
                         stdout: None,
-                        stderr: Some(e.to_string()),
+                        stderr: Some(e.to_string().into_bytes()),
                         exit_code: None,
+                        truncated,
+                        output: Some(output),
+                        ..Default::default()
                     })
                     .await;
 
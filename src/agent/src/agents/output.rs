@@ -0,0 +1,116 @@
+use crate::{agent::OutputSummary, workload::config::OutputLimits};
+use std::sync::Mutex;
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt};
+
+/// Longest line streamed, longer ones are cut.
+pub const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// What to do with a line of output.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admission {
+    /// Stream the line, `cut` if it was longer than [`MAX_LINE_BYTES`].
+    Send { cut: bool },
+    /// Drop the line, after sending the truncation marker if `mark` is set.
+    Drop { mark: bool },
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    summary: OutputSummary,
+    marked: bool,
+}
+
+/// Output a workload may still stream, shared by its build and run, stdout and stderr.
+#[derive(Debug)]
+pub struct OutputBudget {
+    limits: OutputLimits,
+    state: Mutex<BudgetState>,
+}
+
+impl OutputBudget {
+    pub fn new(limits: OutputLimits) -> Self {
+        Self {
+            limits,
+            state: Mutex::new(BudgetState::default()),
+        }
+    }
+
+    /// Account for a line of `len` bytes, `cut_bytes` more having already been cut from it.
+    pub fn admit(&self, len: usize, cut_bytes: u64) -> Admission {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let summary = &mut state.summary;
+        let len = len as u64;
+
+        // Once the output was cut, drop the rest of it rather than streaming lines which happen to fit.
+        if state.marked
+            || summary.lines >= self.limits.max_lines
+            || summary.bytes + len > self.limits.max_bytes
+        {
+            summary.dropped_bytes += len + cut_bytes;
+            summary.dropped_lines += 1;
+            let mark = !state.marked;
+            state.marked = true;
+            return Admission::Drop { mark };
+        }
+
+        summary.bytes += len;
+        summary.lines += 1;
+        summary.dropped_bytes += cut_bytes;
+        Admission::Send { cut: cut_bytes > 0 }
+    }
+
+    /// Line replacing the output beyond the limits.
+    pub fn marker(&self) -> String {
+        format!(
+            "[cloudlet] output truncated: the limit of {} bytes or {} lines was reached\n",
+            self.limits.max_bytes, self.limits.max_lines
+        )
+    }
+
+    pub fn summary(&self) -> OutputSummary {
+        self.state.lock().unwrap().summary.clone()
+    }
+}
+
+/// Whether any output was dropped or cut.
+pub fn is_truncated(summary: &OutputSummary) -> bool {
+    summary.dropped_bytes > 0 || summary.dropped_lines > 0
+}
+
+/// Read a line into `line`, keeping at most [`MAX_LINE_BYTES`] of it and its newline, if any.
+/// Returns the number of bytes cut from the line, or `None` at the end of the input.
+pub async fn read_line_capped<R>(reader: &mut R, line: &mut Vec<u8>) -> io::Result<Option<u64>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    let mut cut = 0;
+    let mut read_any = false;
+
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(read_any.then_some(cut));
+        }
+        read_any = true;
+
+        let newline = available.iter().position(|byte| *byte == b'\n');
+        let chunk = &available[..newline.unwrap_or(available.len())];
+        let kept = chunk.len().min(MAX_LINE_BYTES.saturating_sub(line.len()));
+        line.extend_from_slice(&chunk[..kept]);
+        cut += (chunk.len() - kept) as u64;
+
+        match newline {
+            Some(position) => {
+                line.push(b'\n');
+                reader.consume(position + 1);
+                return Ok(Some(cut));
+            }
+            None => {
+                let len = chunk.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
@@ -1,102 +1,65 @@
-use super::{Agent, AgentOutput};
+use super::files::WORKLOAD_DIR;
+use super::output::OutputBudget;
+use super::supervisor::ProcessTree;
+use super::{crash, Agent, AgentOutput};
 use crate::agent::execute_response::Stage;
 use crate::agents::process_utils;
-use crate::{workload, AgentError, AgentResult};
+use crate::{workload, AgentResult};
 use async_trait::async_trait;
 use rand::distributions::{Alphanumeric, DistString};
-use serde::Deserialize;
 use std::collections::HashSet;
 use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 use tokio::process::{Child, Command};
 use tokio::sync::{
-    broadcast,
     mpsc::{self, Receiver},
     Mutex,
 };
-
-#[derive(Deserialize)]
-#[serde(rename_all = "kebab-case")]
-struct RustAgentBuildConfig {
-    release: bool,
-}
-
-#[derive(Deserialize)]
-struct RustAgentConfig {
-    build: RustAgentBuildConfig,
-}
+use tracing::{debug, info, warn};
 
 pub struct RustAgent {
     workload_config: workload::config::Config,
-    rust_config: RustAgentConfig,
-    build_notifier: broadcast::Sender<Result<(), ()>>,
+    output_budget: Arc<OutputBudget>,
+    /// Cargo project of the workload, from the fetch of its dependencies to its build.
+    function_dir: String,
 }
 
-// TODO should change with a TryFrom
 impl From<workload::config::Config> for RustAgent {
     fn from(workload_config: workload::config::Config) -> Self {
-        let rust_config: RustAgentConfig = toml::from_str(&workload_config.config_string).unwrap();
-
         Self {
+            output_budget: Arc::new(OutputBudget::new(workload_config.output_limits)),
             workload_config,
-            rust_config,
-            build_notifier: broadcast::channel::<Result<(), ()>>(1).0,
+            function_dir: format!(
+                "{}/{}",
+                WORKLOAD_DIR,
+                Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+            ),
         }
     }
 }
 
 impl RustAgent {
-    async fn get_build_child_process(
-        &self,
-        function_dir: &str,
-        child_processes: Arc<Mutex<HashSet<u32>>>,
-    ) -> Child {
-        let mut command = Command::new("cargo");
-        let command = if self.rust_config.build.release {
-            command
-                .stderr(Stdio::piped())
-                .arg("build")
-                .current_dir(function_dir)
-                .arg("--release")
-        } else {
-            command
-                .stderr(Stdio::piped())
-                .arg("build")
-                .current_dir(function_dir)
-        };
-        let child = command.spawn().expect("Failed to start build");
-
-        {
-            child_processes.lock().await.insert(child.id().unwrap());
+    /// Write the Cargo project of the workload, unless an earlier stage already did.
+    fn write_project(&self) {
+        let function_dir = &self.function_dir;
+        if Path::new(&format!("{}/Cargo.toml", function_dir)).exists() {
+            return;
         }
 
-        child
-    }
-}
-
-#[async_trait]
-impl Agent for RustAgent {
-    async fn prepare(
-        &self,
-        child_processes: Arc<Mutex<HashSet<u32>>>,
-    ) -> AgentResult<Receiver<AgentOutput>> {
-        let function_dir = format!(
-            "/tmp/{}",
-            Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
-        );
-
-        println!("Function directory: {}", function_dir);
+        debug!("Function directory: {}", function_dir);
 
-        create_dir_all(format!("{}/src", &function_dir)).expect("Unable to create directory");
+        create_dir_all(format!("{}/src", function_dir)).expect("Unable to create directory");
 
         std::fs::write(
-            format!("{}/src/main.rs", &function_dir),
+            format!("{}/src/main.rs", function_dir),
             &self.workload_config.code,
         )
         .expect("Unable to write main.rs file");
 
-        let cargo_toml = format!(
+        let mut cargo_toml = format!(
             r#"
             [package]
             name = "{}"
@@ -105,43 +68,133 @@ impl Agent for RustAgent {
         "#,
             self.workload_config.workload_name
         );
+        // Declare the requested features, so that the code can check them with `cfg`.
+        if !self.workload_config.build.features.is_empty() {
+            cargo_toml.push_str("\n[features]\n");
+            for feature in &self.workload_config.build.features {
+                cargo_toml.push_str(&format!("{} = []\n", feature));
+            }
+        }
 
-        std::fs::write(format!("{}/Cargo.toml", &function_dir), cargo_toml)
+        std::fs::write(format!("{}/Cargo.toml", function_dir), cargo_toml)
             .expect("Unable to write Cargo.toml file");
 
-        let mut child = self
-            .get_build_child_process(&function_dir, child_processes)
-            .await;
+        // Fetch the crates through the caching proxy of the host, if it has one.
+        if let Some(url) = &self.workload_config.package_cache_url {
+            create_dir_all(format!("{}/.cargo", function_dir)).expect("Unable to create directory");
+            let config = format!(
+                r#"
+            [source.crates-io]
+            replace-with = "cloudlet-cache"
+
+            [source.cloudlet-cache]
+            registry = "sparse+{}/crates-io/index/"
+        "#,
+                url
+            );
+            std::fs::write(format!("{}/.cargo/config.toml", function_dir), config)
+                .expect("Unable to write .cargo/config.toml file");
+        }
+    }
+
+    /// Stream the stderr of the cargo `child` as build output, and its exit status.
+    /// Returns whether it succeeded.
+    async fn stream_cargo(
+        (mut child, tree): (Child, ProcessTree),
+        tx: mpsc::Sender<AgentOutput>,
+        budget: Arc<OutputBudget>,
+    ) -> bool {
+        let stderr = child.stderr.take().unwrap();
+        let stderr = process_utils::send_stderr_to_tx(
+            stderr,
+            tx.clone(),
+            Some(Stage::Building),
+            budget.clone(),
+        )
+        .await;
+        process_utils::send_exit_status_to_tx(child, tree, vec![stderr], tx, false, &budget)
+            .await
+            .is_ok()
+    }
+
+    async fn get_build_child_process(
+        &self,
+        child_processes: Arc<Mutex<HashSet<u32>>>,
+    ) -> (Child, ProcessTree) {
+        let build = &self.workload_config.build;
+        let mut command = Command::new("cargo");
+        command
+            .stderr(Stdio::piped())
+            .arg("build")
+            .current_dir(&self.function_dir);
+        if build.release {
+            command.arg("--release");
+        }
+        if !build.features.is_empty() {
+            command.arg("--features").arg(build.features.join(","));
+        }
+        if !build.compiler_flags.is_empty() {
+            command.env("RUSTFLAGS", build.compiler_flags.join(" "));
+        }
+        ProcessTree::spawn(&mut command, child_processes)
+            .await
+            .expect("Failed to start build")
+    }
+}
+
+#[async_trait]
+impl Agent for RustAgent {
+    async fn fetch_deps(
+        &self,
+        child_processes: Arc<Mutex<HashSet<u32>>>,
+    ) -> AgentResult<Receiver<AgentOutput>> {
+        self.write_project();
+
+        let mut command = Command::new("cargo");
+        command
+            .stderr(Stdio::piped())
+            .arg("fetch")
+            .current_dir(&self.function_dir);
+        let child = ProcessTree::spawn(&mut command, child_processes)
+            .await
+            .expect("Failed to start fetching the dependencies");
+
+        let (tx, rx) = mpsc::channel(10);
+        tokio::spawn(Self::stream_cargo(child, tx, self.output_budget.clone()));
+
+        Ok(rx)
+    }
+
+    async fn build(
+        &self,
+        child_processes: Arc<Mutex<HashSet<u32>>>,
+    ) -> AgentResult<Receiver<AgentOutput>> {
+        self.write_project();
+
+        let child = self.get_build_child_process(child_processes).await;
+        let function_dir = self.function_dir.clone();
         let workload_name = self.workload_config.workload_name.clone();
-        let is_release = self.rust_config.build.release;
-        let tx_build_notifier = self.build_notifier.clone();
+        let is_release = self.workload_config.build.release;
+        let artifact_path = self.artifact_path();
+        let budget = self.output_budget.clone();
 
         let (tx, rx) = mpsc::channel(10);
         tokio::spawn(async move {
-            let stderr = child.stderr.take().unwrap();
-            let _ = process_utils::send_stderr_to_tx(stderr, tx.clone(), Some(Stage::Building))
-                .await
-                .await;
-            let build_result = process_utils::send_exit_status_to_tx(child, tx, false).await;
-            // if error in build, short-circuit the execution
-            if build_result.is_err() {
-                let _ = tx_build_notifier.send(Err(()));
-            } else {
-                // Once finished: copy the binary to /tmp
+            // `tx` is dropped, closing the channel, once the binary is in place.
+            if Self::stream_cargo(child, tx.clone(), budget).await {
+                // Once finished: copy the binary to the workload directory
                 // We could imagine a more complex scenario where we would put this in an artifact repository (like S3)
                 let binary_path = match is_release {
                     true => format!("{}/target/release/{}", &function_dir, workload_name),
                     false => format!("{}/target/debug/{}", &function_dir, workload_name),
                 };
 
-                std::fs::copy(binary_path, format!("/tmp/{}", workload_name))
-                    .expect("Unable to copy binary");
-
-                // notify when build is done
-                let _ = tx_build_notifier.send(build_result);
+                std::fs::copy(binary_path, artifact_path).expect("Unable to copy binary");
+                std::fs::remove_dir_all(&function_dir).expect("Unable to remove directory");
+            } else {
+                // Kept for `cloudlet fs` to inspect what the failed build left.
+                info!("Build failed, keeping {}", function_dir);
             }
-
-            std::fs::remove_dir_all(&function_dir).expect("Unable to remove directory");
         });
 
         Ok(rx)
@@ -151,44 +204,58 @@ impl Agent for RustAgent {
         &self,
         child_processes: Arc<Mutex<HashSet<u32>>>,
     ) -> AgentResult<Receiver<AgentOutput>> {
-        // wait for build to finish
-        self.build_notifier
-            .subscribe()
-            .recv()
-            .await
-            .map_err(|_| AgentError::BuildNotifier)?
-            .map_err(|_| AgentError::BuildFailed)?;
-
-        println!("Starting run()");
-        let mut child = Command::new(format!("/tmp/{}", self.workload_config.workload_name))
+        debug!("Starting run()");
+        let mut command = Command::new(self.artifact_path());
+        command
+            .envs(&self.workload_config.env)
+            .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
+            .stderr(Stdio::piped());
+        crash::allow_core_dump(&mut command);
+        let (mut child, tree) = ProcessTree::spawn(&mut command, child_processes)
+            .await
             .expect("Failed to run function");
 
-        {
-            child_processes.lock().await.insert(child.id().unwrap());
-        }
+        // Written from its own task, so that a workload which doesn't read its input isn't
+        // blocked on a full pipe. It sees the end of its input once written.
+        let mut child_stdin = child.stdin.take().unwrap();
+        let stdin = self.workload_config.stdin.clone();
+        tokio::spawn(async move {
+            if let Err(e) = child_stdin.write_all(&stdin).await {
+                warn!("Could not write the input of the workload: {}", e);
+            }
+        });
 
         let (tx, rx) = mpsc::channel(10);
         let child_stdout = child.stdout.take().unwrap();
-        let tx_stdout = tx.clone();
         let child_stderr = child.stderr.take().unwrap();
-        let tx_stderr = tx;
-
-        tokio::spawn(async move {
-            let _ = process_utils::send_stdout_to_tx(child_stdout, tx_stdout.clone(), None)
-                .await
-                .await;
-            let _ = process_utils::send_exit_status_to_tx(child, tx_stdout, true).await;
-        });
+        let budget = self.output_budget.clone();
 
         tokio::spawn(async move {
-            let _ = process_utils::send_stderr_to_tx(child_stderr, tx_stderr, None)
-                .await
-                .await;
+            let stdout =
+                process_utils::send_stdout_to_tx(child_stdout, tx.clone(), None, budget.clone())
+                    .await;
+            let stderr =
+                process_utils::send_stderr_to_tx(child_stderr, tx.clone(), None, budget.clone())
+                    .await;
+            let _ = process_utils::send_exit_status_to_tx(
+                child,
+                tree,
+                vec![stdout, stderr],
+                tx,
+                true,
+                &budget,
+            )
+            .await;
         });
 
         Ok(rx)
     }
+
+    fn artifact_path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/{}",
+            WORKLOAD_DIR, self.workload_config.workload_name
+        ))
+    }
 }
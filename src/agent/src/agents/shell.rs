@@ -0,0 +1,215 @@
+//! Interactive shells opened in the guest to debug it, e.g. its rootfs.
+//!
+//! The shell runs in a terminal of its own, a PTY, as the leader of a [`ProcessTree`]: what it
+//! leaves running is killed once it exits, or once the client leaves. Shells can only be opened
+//! when the guest booted with the [`EXEC_SHELL_PARAMETER`] set to `1`, which the orchestrator
+//! only passes when it is started with `--enable-exec-shell`.
+
+use super::files::WORKLOAD_DIR;
+use super::supervisor::ProcessTree;
+use crate::agent::{
+    shell_input::Input, shell_output::Output, ShellInput, ShellOutput, ShellStart, TerminalSize,
+};
+use nix::pty::{openpty, Winsize};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::termios::Termios;
+use nix::unistd::Pid;
+use shared_models::EXEC_SHELL_PARAMETER;
+use std::collections::HashSet;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, Mutex};
+use tonic::{Status, Streaming};
+use tracing::{info, warn};
+
+/// Shell run when the client doesn't name a command.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Time given to the output of the shell to be sent once it exited.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time given to the shell to exit once hung up, before it is killed.
+const HANGUP_GRACE: Duration = Duration::from_secs(2);
+
+/// Whether the guest booted with the shells enabled.
+pub fn enabled() -> bool {
+    let enabled = format!("{}=1", EXEC_SHELL_PARAMETER);
+    std::fs::read_to_string("/proc/cmdline")
+        .map(|cmdline| cmdline.split_whitespace().any(|param| param == enabled))
+        .unwrap_or(false)
+}
+
+fn winsize(size: &TerminalSize) -> Winsize {
+    Winsize {
+        ws_row: size.rows.min(u16::MAX as u32) as u16,
+        ws_col: size.cols.min(u16::MAX as u32) as u16,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+fn resize(terminal: &File, size: &TerminalSize) {
+    let size = winsize(size);
+    // SAFETY: `size` outlives the call, which only reads it.
+    if unsafe { libc::ioctl(terminal.as_raw_fd(), libc::TIOCSWINSZ, &size) } < 0 {
+        warn!(
+            "Could not resize the terminal: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Hang the shell led by `leader` up, then kill it if it doesn't exit.
+async fn hang_up(child: &mut Child, leader: u32) -> io::Result<ExitStatus> {
+    let _ = killpg(Pid::from_raw(leader as i32), Signal::SIGHUP);
+    match tokio::time::timeout(HANGUP_GRACE, child.wait()).await {
+        Ok(status) => status,
+        Err(_) => {
+            child.kill().await?;
+            child.wait().await
+        }
+    }
+}
+
+/// Shell running in its terminal.
+pub struct Shell {
+    /// Side of the terminal of the agent.
+    terminal: File,
+    child: Child,
+    tree: ProcessTree,
+}
+
+impl Shell {
+    /// Start the shell of `start` in a new terminal.
+    pub async fn spawn(
+        start: ShellStart,
+        child_processes: Arc<Mutex<HashSet<u32>>>,
+    ) -> io::Result<Self> {
+        let pty = openpty(start.size.as_ref().map(winsize).as_ref(), None::<&Termios>)?;
+        // SAFETY: `fcntl` is given a file descriptor owned by `pty`.
+        unsafe { libc::fcntl(pty.master.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+
+        let (program, args) = match start.command.split_first() {
+            Some((program, args)) => (program.clone(), args.to_vec()),
+            None => (DEFAULT_SHELL.to_string(), Vec::new()),
+        };
+        let term = match start.term.as_str() {
+            "" => "xterm".to_string(),
+            term => term.to_string(),
+        };
+        let slave: OwnedFd = pty.slave;
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .env("TERM", term)
+            .current_dir(WORKLOAD_DIR)
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave))
+            .kill_on_drop(true);
+        let (child, tree) = ProcessTree::spawn_session(&mut command, child_processes).await?;
+        // Drops the copies of the terminal of the shell, for its output to end with it.
+        drop(command);
+
+        Ok(Self {
+            terminal: File::from_std(std::fs::File::from(pty.master)),
+            child,
+            tree,
+        })
+    }
+
+    /// Forward the `inputs` of the client to the shell, and its output to `tx`, until either
+    /// ends. The last message carries the exit code of the shell.
+    pub async fn run(
+        self,
+        mut inputs: Streaming<ShellInput>,
+        tx: mpsc::Sender<Result<ShellOutput, Status>>,
+    ) {
+        let Self {
+            mut terminal,
+            mut child,
+            tree,
+        } = self;
+        info!("Shell {} started", tree.leader());
+
+        let output = match terminal.try_clone().await {
+            Ok(mut reader) => {
+                let tx = tx.clone();
+                Some(tokio::spawn(async move {
+                    let mut buffer = vec![0; 16 * 1024];
+                    // Fails with EIO once the shell and its children closed the terminal.
+                    while let Ok(read @ 1..) = reader.read(&mut buffer).await {
+                        let data = Output::Data(buffer[..read].to_vec());
+                        if tx
+                            .send(Ok(ShellOutput { output: Some(data) }))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }))
+            }
+            Err(e) => {
+                warn!("Could not read the terminal of the shell: {}", e);
+                None
+            }
+        };
+
+        let status = loop {
+            tokio::select! {
+                input = inputs.message() => match input {
+                    Ok(Some(ShellInput { input: Some(Input::Stdin(stdin)) })) => {
+                        let written = match terminal.write_all(&stdin).await {
+                            Ok(()) => terminal.flush().await,
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = written {
+                            warn!("Could not write to shell {}: {}", tree.leader(), e);
+                            break hang_up(&mut child, tree.leader()).await;
+                        }
+                    }
+                    Ok(Some(ShellInput { input: Some(Input::Resize(size)) })) => {
+                        resize(&terminal, &size)
+                    }
+                    Ok(Some(_)) => {}
+                    // The client left, the shell is hung up.
+                    Ok(None) | Err(_) => {
+                        info!("Client of shell {} left", tree.leader());
+                        break hang_up(&mut child, tree.leader()).await;
+                    }
+                },
+                status = child.wait() => break status,
+            }
+        };
+
+        let exit_code = match status {
+            Ok(status) => status
+                .code()
+                .or(status.signal().map(|signal| 128 + signal))
+                .unwrap_or(-1),
+            Err(e) => {
+                warn!("Could not wait for shell {}: {}", tree.leader(), e);
+                -1
+            }
+        };
+        // What the shell left running would keep the terminal open.
+        tree.finish().await;
+        if let Some(output) = output {
+            let _ = tokio::time::timeout(OUTPUT_DRAIN_TIMEOUT, output).await;
+        }
+        info!("Shell exited with {}", exit_code);
+        let _ = tx
+            .send(Ok(ShellOutput {
+                output: Some(Output::ExitCode(exit_code)),
+            }))
+            .await;
+    }
+}
@@ -0,0 +1,318 @@
+//! Supervision of the processes of the workloads.
+//!
+//! The agent makes itself the subreaper of the workloads: the processes they orphan are
+//! reparented to it instead of the init of the guest, and reaped as they exit, so that they can
+//! be told apart from the processes it tracks.
+//!
+//! Each process spawned by a stage leads a [`ProcessTree`]: its own process group and, with
+//! cgroup v2, its own cgroup, which also keeps the processes which left the group with `setsid`.
+//! Once the leader exits, what is left of its tree is killed before its exit code is reported,
+//! so that a finished stage leaves no process behind.
+
+use nix::mount::{mount, MsFlags};
+use nix::sys::prctl;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::Pid;
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Parent of the cgroups of the process trees.
+const CGROUP_PARENT: &str = "/sys/fs/cgroup/cloudlet";
+
+/// Period of the reaping, besides when a child exits.
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time given to the processes left by a workload to exit after `SIGTERM`, before `SIGKILL`.
+const TERMINATION_GRACE: Duration = Duration::from_secs(2);
+
+/// Whether the process trees can be kept in cgroups, mounting cgroup v2 if it wasn't.
+static CGROUPS: Lazy<bool> = Lazy::new(|| match setup_cgroups() {
+    Ok(()) => true,
+    Err(e) => {
+        warn!("Tracking the workloads by process group only: {}", e);
+        false
+    }
+});
+
+static NEXT_TREE: AtomicU64 = AtomicU64::new(0);
+
+fn setup_cgroups() -> io::Result<()> {
+    if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        mount(
+            Some("cgroup2"),
+            CGROUP_ROOT,
+            Some("cgroup2"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            None::<&str>,
+        )?;
+    }
+    fs::create_dir_all(CGROUP_PARENT)
+}
+
+/// Adopt the orphans of the workloads and reap them as they exit. The `child_processes`, spawned
+/// through [`ProcessTree::spawn`], are left to tokio.
+pub fn supervise(child_processes: Arc<Mutex<HashSet<u32>>>) {
+    if let Err(e) = prctl::set_child_subreaper(true) {
+        warn!("Could not adopt the orphans of the workloads: {}", e);
+    }
+    Lazy::force(&CGROUPS);
+
+    tokio::spawn(async move {
+        let mut exits = match signal(SignalKind::child()) {
+            Ok(exits) => Some(exits),
+            Err(e) => {
+                warn!("Reaping every {:?} only: {}", REAP_INTERVAL, e);
+                None
+            }
+        };
+        loop {
+            match exits.as_mut() {
+                Some(exits) => {
+                    tokio::select! {
+                        _ = exits.recv() => {}
+                        _ = sleep(REAP_INTERVAL) => {}
+                    }
+                }
+                None => sleep(REAP_INTERVAL).await,
+            }
+            reap(&child_processes).await;
+        }
+    });
+}
+
+/// Reap the zombies reparented to the agent.
+async fn reap(child_processes: &Mutex<HashSet<u32>>) {
+    let agent = std::process::id();
+    // Held while looking for zombies, so that a child isn't reaped before it is tracked.
+    let tracked = child_processes.lock().await;
+    for (pid, stat) in processes() {
+        if stat.ppid == agent && stat.state == 'Z' && !tracked.contains(&pid) {
+            let _ = waitpid(Pid::from_raw(pid as i32), Some(WaitPidFlag::WNOHANG));
+        }
+    }
+}
+
+/// Fields of `/proc/<pid>/stat` used to follow the processes.
+struct Stat {
+    state: char,
+    ppid: u32,
+    pgrp: u32,
+}
+
+fn stat(pid: u32) -> Option<Stat> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name of the command, between parentheses, can contain spaces.
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+    Some(Stat {
+        state: fields.next()?.chars().next()?,
+        ppid: fields.next()?.parse().ok()?,
+        pgrp: fields.next()?.parse().ok()?,
+    })
+}
+
+/// Every process of the guest.
+fn processes() -> Vec<(u32, Stat)> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter_map(|pid| Some((pid, stat(pid)?)))
+        .collect()
+}
+
+/// Processes spawned by a stage: a leader and its descendants.
+pub struct ProcessTree {
+    leader: u32,
+    /// What the leader runs.
+    program: PathBuf,
+    cgroup: Option<PathBuf>,
+    child_processes: Arc<Mutex<HashSet<u32>>>,
+}
+
+impl ProcessTree {
+    /// Spawn `command` as the leader of a new tree, tracked in `child_processes` until
+    /// [`ProcessTree::finish`].
+    pub async fn spawn(
+        command: &mut Command,
+        child_processes: Arc<Mutex<HashSet<u32>>>,
+    ) -> io::Result<(Child, Self)> {
+        command.process_group(0);
+        Self::spawn_leader(command, child_processes).await
+    }
+
+    /// Spawn `command` as the leader of a new tree in a new session, its stdin becoming its
+    /// controlling terminal.
+    pub async fn spawn_session(
+        command: &mut Command,
+        child_processes: Arc<Mutex<HashSet<u32>>>,
+    ) -> io::Result<(Child, Self)> {
+        // SAFETY: `setsid` and `ioctl` are async-signal-safe.
+        unsafe {
+            command.pre_exec(|| {
+                // Also leads its process group, whose id is its pid.
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Self::spawn_leader(command, child_processes).await
+    }
+
+    async fn spawn_leader(
+        command: &mut Command,
+        child_processes: Arc<Mutex<HashSet<u32>>>,
+    ) -> io::Result<(Child, Self)> {
+        let program = PathBuf::from(command.as_std().get_program());
+
+        let cgroup = if *CGROUPS {
+            let path = Path::new(CGROUP_PARENT).join(format!(
+                "tree-{}",
+                NEXT_TREE.fetch_add(1, Ordering::Relaxed)
+            ));
+            match fs::create_dir(&path) {
+                Ok(()) => Some(path),
+                Err(e) => {
+                    warn!("Could not create the cgroup {:?}: {}", path, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // Opened here, the child only writes to it between the fork and the exec.
+        let procs = match &cgroup {
+            Some(cgroup) => Some(
+                File::options()
+                    .write(true)
+                    .open(cgroup.join("cgroup.procs"))?,
+            ),
+            None => None,
+        };
+        if let Some(procs) = &procs {
+            let fd = procs.as_raw_fd();
+            // SAFETY: `write` is async-signal-safe, and `fd` is open until the spawn returns.
+            unsafe {
+                command.pre_exec(move || {
+                    // Moves the child into the cgroup. If it can't, it's still in the group.
+                    libc::write(fd, b"0".as_ptr().cast(), 1);
+                    Ok(())
+                });
+            }
+        }
+
+        let mut tracked = child_processes.lock().await;
+        let child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                if let Some(cgroup) = &cgroup {
+                    let _ = fs::remove_dir(cgroup);
+                }
+                return Err(e);
+            }
+        };
+        let leader = child.id().unwrap();
+        tracked.insert(leader);
+        drop(tracked);
+
+        Ok((
+            child,
+            Self {
+                leader,
+                program,
+                cgroup,
+                child_processes,
+            },
+        ))
+    }
+
+    pub fn leader(&self) -> u32 {
+        self.leader
+    }
+
+    pub fn program(&self) -> &Path {
+        &self.program
+    }
+
+    /// Spawn `command` as the leader of another tree, tracked with this one.
+    pub async fn spawn_sibling(&self, command: &mut Command) -> io::Result<(Child, Self)> {
+        Self::spawn(command, self.child_processes.clone()).await
+    }
+
+    /// Processes of the tree still running, besides its leader.
+    fn members(&self) -> HashSet<u32> {
+        let mut members: HashSet<u32> = processes()
+            .into_iter()
+            .filter(|(_, stat)| stat.pgrp == self.leader && stat.state != 'Z')
+            .map(|(pid, _)| pid)
+            .collect();
+        if let Some(cgroup) = &self.cgroup {
+            if let Ok(procs) = fs::read_to_string(cgroup.join("cgroup.procs")) {
+                members.extend(procs.lines().filter_map(|pid| pid.parse::<u32>().ok()));
+            }
+        }
+        members.remove(&self.leader);
+        members
+    }
+
+    fn signal(members: &HashSet<u32>, signal: Signal) {
+        for &pid in members {
+            let _ = kill(Pid::from_raw(pid as i32), signal);
+        }
+    }
+
+    /// Wait until the tree is empty, for at most `timeout`.
+    async fn wait_empty(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if self.members().is_empty() {
+                return true;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        self.members().is_empty()
+    }
+
+    /// Kill what is left of the tree once its leader exited, and stop tracking it. Returns how
+    /// many processes were left.
+    pub async fn finish(self) -> usize {
+        let stray = self.members();
+        if !stray.is_empty() {
+            info!(
+                "Terminating the {} process(es) left by {}",
+                stray.len(),
+                self.leader
+            );
+            Self::signal(&stray, Signal::SIGTERM);
+            if !self.wait_empty(TERMINATION_GRACE).await {
+                Self::signal(&self.members(), Signal::SIGKILL);
+                if !self.wait_empty(TERMINATION_GRACE).await {
+                    warn!("Processes of {} survived SIGKILL", self.leader);
+                }
+            }
+        }
+
+        self.child_processes.lock().await.remove(&self.leader);
+        if let Some(cgroup) = &self.cgroup {
+            let _ = fs::remove_dir(cgroup);
+        }
+        stray.len()
+    }
+}
@@ -1,5 +1,12 @@
+// Without the Rust agent, the helpers running the processes of the stages are unused.
+#![cfg_attr(not(feature = "rust"), allow(dead_code))]
+
+use shared_models::ErrorCode;
 use std::fmt;
 
+#[cfg(not(any(feature = "rust", feature = "debug-agent")))]
+compile_error!("The agent needs a language, enable the `rust` or the `debug-agent` feature");
+
 mod agents;
 pub mod workload;
 
@@ -8,8 +15,21 @@ pub enum AgentError {
     OpenConfigFileError(std::io::Error),
     ParseConfigError(toml::de::Error),
     InvalidLanguage(String),
-    BuildNotifier,
-    BuildFailed,
+    /// The run was requested without a build, nor the workload built earlier.
+    NotBuilt,
+    WriteArtifact(std::io::Error),
+    /// An input of the workload could not be written.
+    WriteInput(String, std::io::Error),
+    /// A path outside of the workload directory, or not of a file to read.
+    InvalidPath(String),
+    /// The file or directory at the path could not be inspected.
+    Inspect(String, std::io::Error),
+    /// The guest didn't boot with the shells enabled.
+    ShellDisabled,
+    /// The shell could not be started.
+    Shell(std::io::Error),
+    /// What the earlier runs left could not be removed.
+    Scrub(std::io::Error),
 }
 
 impl fmt::Display for AgentError {
@@ -18,16 +38,61 @@ impl fmt::Display for AgentError {
             AgentError::OpenConfigFileError(e) => write!(f, "Failed to open config file: {}", e),
             AgentError::ParseConfigError(e) => write!(f, "Failed to parse config file: {}", e),
             AgentError::InvalidLanguage(e) => write!(f, "Invalid language: {}", e),
-            AgentError::BuildNotifier => {
-                write!(f, "Could not get notification from build notifier")
+            AgentError::NotBuilt => write!(
+                f,
+                "The workload was not built, request its build stage or send it built"
+            ),
+            AgentError::WriteArtifact(e) => write!(f, "Could not write the built workload: {}", e),
+            AgentError::WriteInput(name, e) => {
+                write!(f, "Could not write the input {:?}: {}", name, e)
+            }
+            AgentError::InvalidPath(path) => write!(
+                f,
+                "Invalid path {:?}, expected a path relative to the workload directory",
+                path
+            ),
+            AgentError::Inspect(path, e) => write!(f, "Could not inspect {:?}: {}", path, e),
+            AgentError::ShellDisabled => write!(f, "Shells are disabled in this guest"),
+            AgentError::Shell(e) => write!(f, "Could not start the shell: {}", e),
+            AgentError::Scrub(e) => {
+                write!(f, "Could not remove the files of the earlier runs: {}", e)
             }
-            AgentError::BuildFailed => write!(f, "Build has failed"),
         }
     }
 }
 
-pub type AgentResult<T> = Result<T, AgentError>;
+impl AgentError {
+    /// User-facing code of the error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AgentError::OpenConfigFileError(_) | AgentError::ParseConfigError(_) => {
+                ErrorCode::AgentInvalidConfig
+            }
+            AgentError::InvalidLanguage(_) => ErrorCode::AgentInvalidLanguage,
+            AgentError::NotBuilt | AgentError::WriteArtifact(_) | AgentError::WriteInput(..) => {
+                ErrorCode::AgentBuildFailed
+            }
+            AgentError::InvalidPath(_) | AgentError::Inspect(..) => ErrorCode::AgentInvalidPath,
+            AgentError::ShellDisabled | AgentError::Shell(_) => ErrorCode::AgentShell,
+            AgentError::Scrub(_) => ErrorCode::AgentScrubFailed,
+        }
+    }
+}
 
-pub mod agent {
-    tonic::include_proto!("cloudlet.agent");
+impl From<AgentError> for tonic::Status {
+    fn from(error: AgentError) -> Self {
+        let code = match &error {
+            AgentError::InvalidPath(_) => tonic::Code::InvalidArgument,
+            AgentError::ShellDisabled => tonic::Code::PermissionDenied,
+            AgentError::Inspect(_, e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tonic::Code::NotFound
+            }
+            _ => tonic::Code::Internal,
+        };
+        error.code().status(code, error.to_string())
+    }
 }
+
+pub type AgentResult<T> = Result<T, AgentError>;
+
+pub use shared_models::cloudlet::agent;
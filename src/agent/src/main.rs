@@ -1,9 +1,14 @@
 use agent::{
-    agent::workload_runner_server::WorkloadRunnerServer, workload::service::WorkloadRunnerService,
+    agent::workload_runner_server::WorkloadRunnerServer,
+    workload::service::{self, WorkloadRunnerService},
 };
 use clap::Parser;
+#[cfg(feature = "reflection")]
+use shared_models::FILE_DESCRIPTOR_SET;
+use shared_models::{logging::LogArgs, AGENT_MAX_MESSAGE_SIZE};
 use std::net::ToSocketAddrs;
 use tonic::transport::Server;
+use tracing::level_filters::LevelFilter;
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -11,11 +16,14 @@ struct Args {
     grpc_server_address: String,
     #[clap(long, env, default_value = "50051")]
     grpc_server_port: u16,
+    #[clap(flatten)]
+    log: LogArgs,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    args.log.init(LevelFilter::INFO);
 
     let bind_address = format!("{}:{}", args.grpc_server_address, args.grpc_server_port)
         .to_socket_addrs()
@@ -23,13 +31,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .next()
         .unwrap();
 
+    service::supervise_workloads();
     let server = WorkloadRunnerService;
 
-    Server::builder()
-        .add_service(WorkloadRunnerServer::new(server))
-        .serve(bind_address)
-        .await
-        .unwrap();
+    let router = Server::builder().add_service(shared_models::compressed!(
+        WorkloadRunnerServer::new(server).max_decoding_message_size(AGENT_MAX_MESSAGE_SIZE)
+    ));
+    #[cfg(feature = "reflection")]
+    let router = router.add_service(
+        tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+            .build()?,
+    );
+
+    router.serve(bind_address).await.unwrap();
 
     Ok(())
 }
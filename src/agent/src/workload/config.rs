@@ -1,10 +1,10 @@
 use crate::{
-    agent::{execute_request, ExecuteRequest},
-    agents::Language,
+    agent::{self, execute_request, ExecuteRequest, RunArtifact},
+    agents::{files, Language},
     AgentError, AgentResult,
 };
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 /// Generic agent configuration.
 #[derive(Debug, Clone, Deserialize)]
@@ -18,10 +18,129 @@ pub struct Config {
     pub action: Action,
     /// Code
     pub code: String,
+    /// How to build the workload.
+    #[serde(default)]
+    pub build: BuildConfig,
+    /// Caps on the output streamed back.
+    #[serde(default)]
+    pub output_limits: OutputLimits,
+    /// Environment variables of the workload when it runs.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Stages to go through, in order, instead of those of `action` when set.
+    #[serde(default)]
+    pub stages: Vec<PipelineStage>,
+    /// Workload built by an earlier build, run as is.
+    #[serde(skip)]
+    pub artifact: Option<Vec<u8>>,
+    /// Send the built workload back once built.
+    #[serde(skip)]
+    pub return_artifact: bool,
+    /// Standard input of the workload when it runs.
+    #[serde(skip)]
+    pub stdin: Vec<u8>,
+    /// Files written under the inputs directory before the first stage.
+    #[serde(skip)]
+    pub inputs: Vec<RunArtifact>,
+    /// Files sent back once the run succeeded, relative to the workload directory.
+    #[serde(skip)]
+    pub outputs: Vec<String>,
+    /// Space in MB the files written by the run under the workload directory may take, 0 for
+    /// the default of the agent.
+    #[serde(default)]
+    pub tmp_quota_mb: u32,
+    /// Base URL of the caching proxy of the package registries on the host, if any.
+    #[serde(default)]
+    pub package_cache_url: Option<String>,
+    /// Remove what the earlier runs left in the workload directory before the first stage.
+    #[serde(skip)]
+    pub scrub: bool,
     /// Rest of the configuration as a string.
     pub config_string: String,
 }
 
+/// How to build a workload.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BuildConfig {
+    /// Build with optimizations instead of in debug mode.
+    #[serde(default)]
+    pub release: bool,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub compiler_flags: Vec<String>,
+    /// Version of the runtime, already provided by the rootfs image of the guest.
+    #[serde(default)]
+    pub runtime_version: Option<String>,
+}
+
+impl From<agent::BuildConfig> for BuildConfig {
+    fn from(value: agent::BuildConfig) -> Self {
+        Self {
+            release: value.release,
+            features: value.features,
+            compiler_flags: value.compiler_flags,
+            runtime_version: Some(value.runtime_version).filter(|version| !version.is_empty()),
+        }
+    }
+}
+
+/// Caps on the output of a workload, shared by its build and run, stdout and stderr.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct OutputLimits {
+    pub max_bytes: u64,
+    pub max_lines: u64,
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 16 * 1024 * 1024,
+            max_lines: 100_000,
+        }
+    }
+}
+
+impl From<agent::OutputLimits> for OutputLimits {
+    /// Limits requested by the orchestrator, the defaults standing for those it left to 0.
+    fn from(value: agent::OutputLimits) -> Self {
+        let default = Self::default();
+        Self {
+            max_bytes: Some(value.max_bytes)
+                .filter(|max| *max > 0)
+                .unwrap_or(default.max_bytes),
+            max_lines: Some(value.max_lines)
+                .filter(|max| *max > 0)
+                .unwrap_or(default.max_lines),
+        }
+    }
+}
+
+/// Configuration sent as TOML by older orchestrators, in `config_str`.
+#[derive(Debug, Default, Deserialize)]
+struct LegacyConfig {
+    #[serde(default)]
+    build: BuildConfig,
+}
+
+/// Variables pointing pip and npm to the caching proxy at `url`, for the workloads installing
+/// packages when they run. cargo is pointed to it by the configuration of the project.
+fn package_cache_env(url: &str) -> [(&'static str, String); 3] {
+    let host = url
+        .trim_start_matches("http://")
+        .split([':', '/'])
+        .next()
+        .unwrap_or_default();
+    [
+        ("PIP_INDEX_URL", format!("{}/pypi/simple/", url)),
+        // The proxy is reached over plain HTTP, on the host.
+        ("PIP_TRUSTED_HOST", host.to_string()),
+        ("npm_config_registry", format!("{}/npm/", url)),
+    ]
+}
+
 impl Config {
     pub fn from_file(file_path: &PathBuf) -> AgentResult<Self> {
         let config = std::fs::read_to_string(file_path).map_err(AgentError::OpenConfigFileError)?;
@@ -36,14 +155,69 @@ impl Config {
     }
 
     pub fn new_from_execute_request(execute_request: ExecuteRequest) -> Result<Self, AgentError> {
+        let action = execute_request.action().into();
+        let stages = execute_request.stages().map(PipelineStage::from).collect();
+        let mut env: BTreeMap<String, String> = execute_request.env.into_iter().collect();
+        if !execute_request.inputs.is_empty() {
+            env.entry("CLOUDLET_INPUTS_DIR".to_string())
+                .or_insert_with(|| files::INPUTS_DIR.to_string());
+        }
+        let package_cache_url =
+            Some(execute_request.package_cache_url).filter(|url| !url.is_empty());
+        if let Some(url) = &package_cache_url {
+            // The variables set by the request win over the proxy.
+            for (key, value) in package_cache_env(url) {
+                env.entry(key.to_string()).or_insert(value);
+            }
+        }
+        let build = match execute_request.build {
+            Some(build) => build.into(),
+            None => {
+                toml::from_str::<LegacyConfig>(&execute_request.config_str)
+                    .map_err(AgentError::ParseConfigError)?
+                    .build
+            }
+        };
+
         Ok(Self {
             workload_name: execute_request.workload_name.clone(),
             language: Language::try_from(execute_request.language.clone().as_str())?,
-            action: execute_request.action().into(),
+            action,
+            build,
+            output_limits: execute_request
+                .output_limits
+                .map(OutputLimits::from)
+                .unwrap_or_default(),
+            env,
+            stages,
+            artifact: Some(execute_request.artifact).filter(|artifact| !artifact.is_empty()),
+            return_artifact: execute_request.return_artifact,
+            stdin: execute_request.stdin,
+            inputs: execute_request.inputs,
+            outputs: execute_request.outputs,
+            tmp_quota_mb: execute_request.tmp_quota_mb,
+            package_cache_url,
+            scrub: execute_request.scrub,
             config_string: execute_request.config_str,
             code: execute_request.code,
         })
     }
+
+    /// Stages of the pipeline to go through, in order.
+    pub fn pipeline(&self) -> Vec<PipelineStage> {
+        if !self.stages.is_empty() {
+            return self.stages.clone();
+        }
+        match self.action {
+            Action::Prepare => vec![PipelineStage::FetchDeps, PipelineStage::Build],
+            Action::Run => vec![PipelineStage::Run],
+            Action::PrepareAndRun => vec![
+                PipelineStage::FetchDeps,
+                PipelineStage::Build,
+                PipelineStage::Run,
+            ],
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -54,6 +228,34 @@ pub enum Action {
     PrepareAndRun,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PipelineStage {
+    FetchDeps,
+    Build,
+    Run,
+}
+
+impl From<agent::PipelineStage> for PipelineStage {
+    fn from(value: agent::PipelineStage) -> Self {
+        match value {
+            agent::PipelineStage::FetchDeps => PipelineStage::FetchDeps,
+            agent::PipelineStage::Build => PipelineStage::Build,
+            agent::PipelineStage::Run => PipelineStage::Run,
+        }
+    }
+}
+
+impl From<PipelineStage> for agent::PipelineStage {
+    fn from(value: PipelineStage) -> Self {
+        match value {
+            PipelineStage::FetchDeps => agent::PipelineStage::FetchDeps,
+            PipelineStage::Build => agent::PipelineStage::Build,
+            PipelineStage::Run => agent::PipelineStage::Run,
+        }
+    }
+}
+
 impl From<execute_request::Action> for Action {
     fn from(value: execute_request::Action) -> Self {
         match value {
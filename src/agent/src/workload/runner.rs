@@ -1,16 +1,24 @@
-use super::config::Config;
+use super::config::{Config, PipelineStage};
 use crate::{
-    agent::ExecuteRequest,
-    agents::{rust, Agent, AgentOutput, Language},
-    workload::config::Action,
+    agent::{self, execute_response::Stage, stage_marker::Event, ExecuteRequest, StageMarker},
+    agents::{crash, files, Agent, AgentOutput, Language},
     AgentError, AgentResult,
 };
 use std::collections::HashSet;
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::{mpsc::Receiver, Mutex};
+use tokio::sync::{
+    mpsc::{self, Receiver, Sender},
+    Mutex,
+};
+use tracing::{info, warn};
 
 #[cfg(feature = "debug-agent")]
 use crate::agents::debug;
+#[cfg(feature = "rust")]
+use crate::agents::rust;
 
 /// Runner for a workload.  
 /// Will execute the workload based on the inner agent (language).
@@ -20,9 +28,23 @@ pub struct Runner {
     child_processes: Arc<Mutex<HashSet<u32>>>,
 }
 
+/// Note on the failure of a stage which filled the workload directory.
+const WORKLOAD_DIR_FULL: &str =
+    "The workload directory is full, the files written by the run reached its quota\n";
+
+/// Put the workload built by an earlier build where the agent runs it from.
+fn write_artifact(path: &Path, artifact: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, artifact)?;
+    fs::set_permissions(path, Permissions::from_mode(0o755))
+}
+
 impl Runner {
     pub fn new(config: Config, child_processes: Arc<Mutex<HashSet<u32>>>) -> Self {
         let agent: Box<dyn Agent + Sync + Send> = match config.language {
+            #[cfg(feature = "rust")]
             Language::Rust => Box::new(rust::RustAgent::from(config.clone())),
             #[cfg(feature = "debug-agent")]
             Language::Debug => Box::new(debug::DebugAgent::from(config.clone())),
@@ -43,42 +65,130 @@ impl Runner {
         Ok(Self::new(config, child_processes))
     }
 
+    /// Go through the stages of the pipeline, in order, until one of them fails.
     pub async fn run(self) -> AgentResult<Receiver<AgentOutput>> {
-        let rx = match self.config.action {
-            Action::Prepare => {
-                self.agent
-                    .prepare(Arc::clone(&self.child_processes))
-                    .await?
+        let pipeline = self.config.pipeline();
+        let artifact_path = self.agent.artifact_path();
+        if self.config.scrub {
+            let removed = files::scrub_workload_dir(&artifact_path).map_err(AgentError::Scrub)?;
+            info!("Removed {} entries left by the earlier runs", removed);
+            // Its directory of cores went with the rest.
+            crash::enable_core_dumps();
+        }
+        files::write_inputs(&self.config.inputs)?;
+        if let Some(artifact) = &self.config.artifact {
+            write_artifact(&artifact_path, artifact).map_err(AgentError::WriteArtifact)?;
+        } else if let Some(run) = pipeline
+            .iter()
+            .position(|&stage| stage == PipelineStage::Run)
+        {
+            if !pipeline[..run].contains(&PipelineStage::Build) && !artifact_path.exists() {
+                return Err(AgentError::NotBuilt);
             }
-            Action::Run => self.agent.run(Arc::clone(&self.child_processes)).await?,
-            Action::PrepareAndRun => {
-                let (tx1, rx) = tokio::sync::mpsc::channel::<AgentOutput>(10);
-                let tx2 = tx1.clone();
+        }
+        // Outside of a guest, e.g. in development, the directory may not be a tmpfs.
+        match files::limit_workload_dir(self.config.tmp_quota_mb) {
+            Ok(quota) => info!("Files of the run limited to {} MB", quota >> 20),
+            Err(e) => warn!("Not limiting the files of the run: {}", e),
+        }
 
-                // Merges the two receivers given as parameters and returns only one
-                let mut rx_prepare = self
-                    .agent
-                    .prepare(Arc::clone(&self.child_processes))
-                    .await?;
-                tokio::spawn(async move {
-                    while let Some(output) = rx_prepare.recv().await {
-                        let _ = tx1.send(output).await;
-                    }
-                });
+        let (tx, rx) = mpsc::channel(10);
+        tokio::spawn(async move {
+            for &stage in &pipeline {
+                if !self.run_stage(stage, &tx).await {
+                    return;
+                }
+            }
+            // Otherwise the final message is the one of the run.
+            if !pipeline.contains(&PipelineStage::Run) {
+                let _ = tx
+                    .send(AgentOutput {
+                        stage: Stage::Done,
+                        exit_code: Some(0),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+        });
 
-                tokio::spawn(async move {
-                    let rx_run = self.agent.run(Arc::clone(&self.child_processes)).await;
-                    if let Ok(mut rx_run) = rx_run {
-                        while let Some(output) = rx_run.recv().await {
-                            let _ = tx2.clone().send(output).await;
-                        }
-                    }
-                });
+        Ok(rx)
+    }
 
-                rx
+    /// Attach the outputs of the workload to the final message of its run, which fails if one
+    /// of them can't be read.
+    fn attach_outputs(&self, output: &mut AgentOutput) {
+        match files::collect_outputs(&self.config.outputs) {
+            Ok(outputs) => output.artifacts.extend(outputs),
+            Err(e) => {
+                output.stage = Stage::Failed;
+                output.stderr = Some(format!("Missing output: {}\n", e).into_bytes());
             }
+        }
+    }
+
+    /// Forward the output of `stage` between its markers. Returns whether it succeeded.
+    async fn run_stage(&self, stage: PipelineStage, tx: &Sender<AgentOutput>) -> bool {
+        let output_stage = match stage {
+            PipelineStage::FetchDeps | PipelineStage::Build => Stage::Building,
+            PipelineStage::Run => Stage::Running,
         };
+        let marker = |event: Event| AgentOutput {
+            stage: output_stage,
+            marker: Some(StageMarker {
+                stage: agent::PipelineStage::from(stage) as i32,
+                event: event as i32,
+            }),
+            ..Default::default()
+        };
+        let _ = tx.send(marker(Event::Started)).await;
 
-        Ok(rx)
+        let child_processes = Arc::clone(&self.child_processes);
+        let outputs = match stage {
+            PipelineStage::FetchDeps => self.agent.fetch_deps(child_processes).await,
+            PipelineStage::Build => self.agent.build(child_processes).await,
+            PipelineStage::Run => self.agent.run(child_processes).await,
+        };
+        let mut failed = false;
+        match outputs {
+            Ok(mut outputs) => {
+                while let Some(mut output) = outputs.recv().await {
+                    if stage == PipelineStage::Run && output.stage == Stage::Done {
+                        self.attach_outputs(&mut output);
+                    }
+                    if output.stage == Stage::Failed && files::workload_dir_full() {
+                        output
+                            .stderr
+                            .get_or_insert_with(Vec::new)
+                            .extend_from_slice(WORKLOAD_DIR_FULL.as_bytes());
+                    }
+                    failed |= output.stage == Stage::Failed;
+                    let _ = tx.send(output).await;
+                }
+            }
+            Err(e) => {
+                failed = true;
+                let _ = tx
+                    .send(AgentOutput {
+                        stage: Stage::Failed,
+                        stderr: Some(e.to_string().into_bytes()),
+                        ..Default::default()
+                    })
+                    .await;
+            }
+        }
+
+        if failed {
+            let _ = tx.send(marker(Event::Failed)).await;
+            return false;
+        }
+        let mut end = marker(Event::Finished);
+        if stage == PipelineStage::Build && self.config.return_artifact {
+            match fs::read(self.agent.artifact_path()) {
+                Ok(artifact) => end.artifact = Some(artifact),
+                Err(e) => warn!("Could not read the built workload: {}", e),
+            }
+        }
+        let _ = tx.send(end).await;
+        true
     }
 }
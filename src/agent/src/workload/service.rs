@@ -1,54 +1,199 @@
 use super::runner::Runner;
-use crate::agent::{self, ExecuteRequest, ExecuteResponse, SignalRequest};
+use crate::agent::execute_response::Stage;
+#[cfg(feature = "exec-shell")]
+use crate::agent::shell_input::Input;
+use crate::agent::{
+    self, ExecuteRequest, ExecuteResponse, HelloRequest, HelloResponse, ListDirRequest,
+    ListDirResponse, ReadFileRequest, ReadFileResponse, ShellInput, ShellOutput, SignalRequest,
+};
+#[cfg(feature = "exec-shell")]
+use crate::agents::shell::{self, Shell};
+use crate::agents::{crash, files, supervisor};
+use crate::AgentError;
 use agent::workload_runner_server::WorkloadRunner;
 use once_cell::sync::Lazy;
+use shared_models::{
+    agent_capabilities, Redactor, AGENT_PROTOCOL_VERSION, MIN_AGENT_PROTOCOL_VERSION,
+};
 use std::collections::HashSet;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{process, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response};
+use tokio_stream::Stream;
+use tonic::{Request, Response, Streaming};
+use tracing::{debug, info, warn};
 
 type Result<T> = std::result::Result<Response<T>, tonic::Status>;
 
+/// Time given to the final message of a run to leave the guest before it powers off.
+const POWER_OFF_DELAY: Duration = Duration::from_secs(1);
+
 static CHILD_PROCESSES: Lazy<Arc<Mutex<HashSet<u32>>>> =
     Lazy::new(|| Arc::new(Mutex::new(HashSet::new())));
 
+/// Adopt the processes left by the workloads and reap them, no zombie being left in the guest,
+/// and collect the cores of those which crash.
+pub fn supervise_workloads() {
+    supervisor::supervise(CHILD_PROCESSES.clone());
+    crash::enable_core_dumps();
+}
+
 pub struct WorkloadRunnerService;
 
+/// Responses of a run, dropped once sent to the end or left by the client.
+pub struct ExecuteStream {
+    responses: ReceiverStream<std::result::Result<ExecuteResponse, tonic::Status>>,
+    /// Closed along with the stream.
+    _dropped: oneshot::Sender<()>,
+}
+
+impl Stream for ExecuteStream {
+    type Item = std::result::Result<ExecuteResponse, tonic::Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.responses).poll_next(cx)
+    }
+}
+
 #[tonic::async_trait]
 impl WorkloadRunner for WorkloadRunnerService {
-    type ExecuteStream = ReceiverStream<std::result::Result<ExecuteResponse, tonic::Status>>;
+    type ExecuteStream = ExecuteStream;
+
+    async fn hello(&self, req: Request<HelloRequest>) -> Result<HelloResponse> {
+        let req = req.into_inner();
+        // Orchestrators not setting it speak the first version.
+        let protocol_version = req.protocol_version.max(1);
+        if protocol_version < MIN_AGENT_PROTOCOL_VERSION
+            || req.min_protocol_version > AGENT_PROTOCOL_VERSION
+        {
+            return Err(tonic::Status::failed_precondition(format!(
+                "The agent {} speaks versions {} to {} of the protocol, the orchestrator {} versions {} to {}",
+                env!("CARGO_PKG_VERSION"),
+                MIN_AGENT_PROTOCOL_VERSION,
+                AGENT_PROTOCOL_VERSION,
+                req.version,
+                req.min_protocol_version,
+                protocol_version
+            )));
+        }
+
+        Ok(Response::new(HelloResponse {
+            protocol_version: AGENT_PROTOCOL_VERSION,
+            min_protocol_version: MIN_AGENT_PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: agent_capabilities::ALL
+                .iter()
+                .filter(|c| cfg!(feature = "exec-shell") || **c != agent_capabilities::EXEC_SHELL)
+                .map(|c| c.to_string())
+                .collect(),
+        }))
+    }
 
     async fn execute(&self, req: Request<ExecuteRequest>) -> Result<Self::ExecuteStream> {
-        let runner = Runner::new_from_execute_request(req.into_inner(), CHILD_PROCESSES.clone())
-            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+        let request = req.into_inner();
+        let power_off = request.power_off;
+        let keep_on_failure = request.keep_on_failure;
+        let redactor = Redactor::new(&request.env, &request.secret_env);
+        let runner = Runner::new_from_execute_request(request, CHILD_PROCESSES.clone())?;
 
-        let mut runner_rx = runner
-            .run()
-            .await
-            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+        let mut runner_rx = runner.run().await?;
 
         let (tx, rx) = mpsc::channel(10);
+        let (dropped_tx, dropped) = oneshot::channel::<()>();
         tokio::spawn(async move {
+            let mut failed = false;
             while let Some(agent_output) = runner_rx.recv().await {
-                println!("Sending to the gRPC client: {:?}", agent_output);
+                let mut agent_output = agent_output.redacted(&redactor);
+                // Not printed, a core dump being large.
+                let artifacts = std::mem::take(&mut agent_output.artifacts);
+                debug!("Sending to the gRPC client: {:?}", agent_output);
+                agent_output.artifacts = artifacts;
+                failed = agent_output.stage == Stage::Failed;
                 let _ = tx.send(Ok(agent_output.into())).await;
             }
+
+            if failed && keep_on_failure {
+                info!("Run failed, keeping the guest up for its files to be inspected");
+            } else if power_off {
+                // Ends the stream, then waits for it to be sent.
+                drop(tx);
+                let _ = dropped.await;
+                tokio::time::sleep(POWER_OFF_DELAY).await;
+                info!("Run finished, exiting for the init to power the guest off");
+                process::exit(0);
+            }
         });
 
+        Ok(Response::new(ExecuteStream {
+            responses: ReceiverStream::new(rx),
+            _dropped: dropped_tx,
+        }))
+    }
+
+    async fn list_dir(&self, req: Request<ListDirRequest>) -> Result<ListDirResponse> {
+        let path = req.into_inner().path;
+        let response = tokio::task::spawn_blocking(move || files::list_dir(&path))
+            .await
+            .map_err(|e| tonic::Status::internal(e.to_string()))??;
+        Ok(Response::new(response))
+    }
+
+    async fn read_file(&self, req: Request<ReadFileRequest>) -> Result<ReadFileResponse> {
+        let request = req.into_inner();
+        let response =
+            tokio::task::spawn_blocking(move || files::read_file(&request.path, request.max_bytes))
+                .await
+                .map_err(|e| tonic::Status::internal(e.to_string()))??;
+        Ok(Response::new(response))
+    }
+
+    type ExecShellStream = ReceiverStream<std::result::Result<ShellOutput, tonic::Status>>;
+
+    #[cfg(not(feature = "exec-shell"))]
+    async fn exec_shell(&self, _: Request<Streaming<ShellInput>>) -> Result<Self::ExecShellStream> {
+        Err(AgentError::ShellDisabled.into())
+    }
+
+    #[cfg(feature = "exec-shell")]
+    async fn exec_shell(
+        &self,
+        req: Request<Streaming<ShellInput>>,
+    ) -> Result<Self::ExecShellStream> {
+        if !shell::enabled() {
+            return Err(AgentError::ShellDisabled.into());
+        }
+        let mut inputs = req.into_inner();
+        let Some(ShellInput {
+            input: Some(Input::Start(start)),
+        }) = inputs.message().await?
+        else {
+            return Err(tonic::Status::invalid_argument(
+                "The first message must start the shell",
+            ));
+        };
+        let shell = Shell::spawn(start, CHILD_PROCESSES.clone())
+            .await
+            .map_err(AgentError::Shell)?;
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(shell.run(inputs, tx));
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
     async fn signal(&self, _: Request<SignalRequest>) -> Result<()> {
         let child_processes = CHILD_PROCESSES.lock().await;
 
+        // Each child leads the process group of its tree.
         for &child_id in child_processes.iter() {
-            match nix::sys::signal::kill(
+            match nix::sys::signal::killpg(
                 nix::unistd::Pid::from_raw(child_id as i32),
                 nix::sys::signal::Signal::SIGTERM,
             ) {
-                Ok(_) => println!("Sent SIGTERM to child process {}", child_id),
-                Err(e) => println!(
+                Ok(_) => info!("Sent SIGTERM to child process {}", child_id),
+                Err(e) => warn!(
                     "Failed to send SIGTERM to child process {}: {}",
                     child_id, e
                 ),
@@ -0,0 +1,80 @@
+// Refresh the tables periodically and tail the logs of the selected VM over a WebSocket.
+const REFRESH_MS = 2000;
+let socket = null;
+
+function time(seconds) {
+  return new Date(seconds * 1000).toLocaleTimeString();
+}
+
+function cell(row, text, className) {
+  const td = document.createElement("td");
+  td.textContent = text;
+  if (className) td.className = className;
+  row.appendChild(td);
+}
+
+async function refresh() {
+  try {
+    const vms = await (await fetch("/dashboard/api/vms")).json();
+    const vmRows = document.getElementById("vms");
+    vmRows.replaceChildren();
+    for (const vm of vms) {
+      const row = document.createElement("tr");
+      row.className = "vm";
+      row.onclick = () => tail(vm.vm_id);
+      cell(row, vm.vm_id);
+      cell(row, vm.workload_name);
+      cell(row, vm.state, vm.state);
+      cell(row, time(vm.started_at));
+      cell(row, vm.message);
+      vmRows.appendChild(row);
+    }
+
+    const runs = await (await fetch("/dashboard/api/runs")).json();
+    const runRows = document.getElementById("runs");
+    runRows.replaceChildren();
+    for (const run of runs) {
+      const row = document.createElement("tr");
+      row.className = "vm";
+      row.onclick = () => tail(run.vm_id);
+      cell(row, run.vm_id);
+      cell(row, run.workload_name);
+      cell(row, run.outcome, run.outcome);
+      cell(row, time(run.finished_at));
+      cell(row, run.message);
+      cell(row, run.webhooks.map((webhook) => webhook.message).join(", "));
+      runRows.appendChild(row);
+    }
+  } catch (e) {
+    console.error("refresh failed", e);
+  }
+}
+
+function tail(vmId) {
+  if (socket) socket.close();
+  const logs = document.getElementById("logs");
+  logs.replaceChildren();
+  document.getElementById("logs-vm").textContent = `(VM ${vmId})`;
+
+  const scheme = location.protocol === "https:" ? "wss" : "ws";
+  socket = new WebSocket(`${scheme}://${location.host}/dashboard/ws/logs/${encodeURIComponent(vmId)}`);
+  socket.onmessage = (message) => {
+    const event = JSON.parse(message.data);
+    const decode = (output) =>
+      output && event.encoding === "base64"
+        ? new TextDecoder().decode(Uint8Array.from(atob(output), (c) => c.charCodeAt(0)))
+        : output;
+    const lines = [[decode(event.stdout), ""], [decode(event.stderr), "stderr"], [event.error, "stderr"]];
+    for (const [text, className] of lines) {
+      if (!text) continue;
+      const span = document.createElement("span");
+      span.className = className;
+      span.textContent = text.endsWith("\n") ? text : text + "\n";
+      logs.appendChild(span);
+    }
+    logs.scrollTop = logs.scrollHeight;
+  };
+}
+
+refresh();
+setInterval(refresh, REFRESH_MS);
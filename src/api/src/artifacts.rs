@@ -0,0 +1,68 @@
+//! Files collected by the orchestrator to debug the runs: the core dump and the backtrace of a
+//! workload killed by a signal.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::service::{orchestrator_unavailable, status_response};
+use actix_web::{get, middleware::Compress, web, HttpResponse, Responder};
+use shared_models::cloudlet::agent::RunArtifact;
+use shared_models::CloudletRunArtifact;
+
+impl From<RunArtifact> for CloudletRunArtifact {
+    fn from(artifact: RunArtifact) -> Self {
+        Self {
+            name: artifact.name,
+            size: artifact.size,
+            truncated: artifact.truncated,
+        }
+    }
+}
+
+/// Describe the artifacts collected from a run.
+#[get("/runs/{run_id}/artifacts")]
+pub async fn list(endpoint: web::Data<VmmEndpoint>, run_id: web::Path<String>) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.list_run_artifacts(run_id.into_inner()).await {
+        Ok(artifacts) => HttpResponse::Ok().json(
+            artifacts
+                .into_iter()
+                .map(CloudletRunArtifact::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(status) => status_response(&status),
+    }
+}
+
+/// Download an artifact collected from a run, compressed when the client accepts it: a core
+/// dump compresses well.
+#[get("/runs/{run_id}/artifacts/{name}", wrap = "Compress::default()")]
+pub async fn get(
+    endpoint: web::Data<VmmEndpoint>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (run_id, name) = path.into_inner();
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.run_artifact(run_id.clone(), name.clone()).await {
+        Ok(artifact) => {
+            let content_type = match artifact.name.as_str() {
+                "backtrace" => "text/plain; charset=utf-8",
+                _ => "application/octet-stream",
+            };
+            HttpResponse::Ok()
+                .content_type(content_type)
+                .insert_header((
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{}-{}\"", run_id, name),
+                ))
+                .body(artifact.content)
+        }
+        Err(status) => status_response(&status),
+    }
+}
@@ -0,0 +1,202 @@
+//! Results of the calls of the pure workloads, returned again to the calls with the same input
+//! without booting a VM.
+//!
+//! A workload registered with a cache policy declares that its result only depends on its
+//! input. The result of a successful call is kept under the name and the version of the workload
+//! called and the digest of its input, the types of the input and of the result included, for
+//! the TTL of the policy. The failed calls aren't cached, nor are the streamed invocations. The
+//! cache is in the memory of the API and bounded in size: the results expiring first are dropped
+//! to make room for the new ones.
+
+use actix_web::{web::Bytes, HttpResponse};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Header of the results of the cached workloads, `hit` if the result was cached and `miss` if
+/// the workload ran.
+pub const CACHE_HEADER: &str = "x-cloudlet-cache";
+
+/// Bytes of results kept at most.
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Call of a version of a workload with an input.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    name: String,
+    version: String,
+    digest: [u8; 32],
+}
+
+impl CacheKey {
+    pub fn new(
+        name: &str,
+        version: &str,
+        input_type: &str,
+        output_type: &str,
+        input: &[u8],
+    ) -> Self {
+        let mut hasher = Sha256::new();
+        // Prefixed with their lengths, so that the types can't run into the input.
+        for content_type in [input_type, output_type] {
+            hasher.update((content_type.len() as u64).to_le_bytes());
+            hasher.update(content_type);
+        }
+        hasher.update(input);
+        Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            digest: hasher.finalize().into(),
+        }
+    }
+}
+
+/// Successful result of a call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallResult {
+    pub content_type: String,
+    pub body: Bytes,
+}
+
+impl CallResult {
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type(self.content_type.as_str())
+            .body(self.body.clone())
+    }
+
+    fn size(&self) -> usize {
+        self.content_type.len() + self.body.len()
+    }
+}
+
+struct Entry {
+    result: CallResult,
+    expires_at: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<CacheKey, Entry>,
+    bytes: usize,
+}
+
+impl State {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.result.size();
+        }
+    }
+}
+
+/// Results of the calls of the pure workloads, by call.
+pub struct ResponseCache {
+    max_bytes: usize,
+    state: Mutex<State>,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Result of the call `key`, if it is cached and hasn't expired.
+    pub fn get(&self, key: &CacheKey) -> Option<CallResult> {
+        let mut state = self.state.lock().unwrap();
+        match state.entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.result.clone()),
+            Some(_) => {
+                state.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Keep `result` as the result of the call `key` for `ttl`.
+    pub fn insert(&self, key: CacheKey, result: CallResult, ttl: Duration) {
+        let size = result.size();
+        if size > self.max_bytes {
+            return;
+        }
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        state.entries.retain(|_, entry| entry.expires_at > now);
+        state.bytes = state
+            .entries
+            .values()
+            .map(|entry| entry.result.size())
+            .sum();
+        while state.bytes + size > self.max_bytes {
+            let Some(first) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            state.remove(&first);
+        }
+
+        state.bytes += size;
+        state.entries.insert(
+            key,
+            Entry {
+                result,
+                expires_at: now + ttl,
+            },
+        );
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BYTES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(body: &'static str) -> CallResult {
+        CallResult {
+            content_type: "text/plain".into(),
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn test_cache_results() {
+        let cache = ResponseCache::new(32);
+        let ttl = Duration::from_secs(60);
+        let key = |version, output_type, input: &str| {
+            CacheKey::new("fib", version, "text/plain", output_type, input.as_bytes())
+        };
+
+        cache.insert(key("1", "text/plain", "10"), result("55"), ttl);
+        assert_eq!(cache.get(&key("1", "text/plain", "10")), Some(result("55")));
+        // Another version, type of result or input is another call.
+        assert!(cache.get(&key("2", "text/plain", "10")).is_none());
+        assert!(cache.get(&key("1", "application/json", "10")).is_none());
+        assert!(cache.get(&key("1", "text/plain", "11")).is_none());
+
+        cache.insert(key("1", "text/plain", "0"), result("0"), Duration::ZERO);
+        assert!(cache.get(&key("1", "text/plain", "0")).is_none());
+
+        // Past 32 bytes, the result expiring first is dropped.
+        cache.insert(key("1", "text/plain", "12"), result("144"), ttl * 2);
+        cache.insert(key("1", "text/plain", "20"), result("6765"), ttl * 2);
+        assert!(cache.get(&key("1", "text/plain", "10")).is_none());
+        assert!(cache.get(&key("1", "text/plain", "12")).is_some());
+        assert!(cache.get(&key("1", "text/plain", "20")).is_some());
+    }
+}
@@ -1,29 +1,91 @@
 use std::time::Duration;
 
-use tonic::{transport::Channel, Streaming};
-use vmmorchestrator::vmm_service_client::VmmServiceClient;
+use shared_models::cloudlet::agent::{
+    ExecuteResponse, ListDirResponse, ReadFileResponse, RunArtifact, ShellInput, ShellOutput,
+};
+use shared_models::vmmorchestrator::{
+    self, admin::admin_service_client::AdminServiceClient, vmm_service_client::VmmServiceClient,
+};
+use shared_models::{unix_socket, AGENT_MAX_MESSAGE_SIZE};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    transport::{Channel, Endpoint, Uri},
+    Streaming,
+};
+use tonic_health::pb::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
+use tower::service_fn;
+use tracing::debug;
 
-pub mod vmmorchestrator {
-    tonic::include_proto!("vmmorchestrator");
+/// Address of the VMM orchestrator gRPC server by default.
+pub const DEFAULT_VMM_ADDRESS: &str = "http://[::1]:50051";
+
+/// Name under which the orchestrator reports its health.
+const VMM_SERVICE_NAME: &str = "vmmorchestrator.VmmService";
+
+/// How the API reaches the VMM orchestrator.
+#[derive(Clone, Debug)]
+pub enum VmmEndpoint {
+    /// Connect to an orchestrator listening at the given address, or on the Unix socket of a
+    /// `unix://` one.
+    Remote(String),
+    /// Reuse an already established channel, used when the orchestrator runs in the same process.
+    InMemory(Channel),
+}
+
+impl Default for VmmEndpoint {
+    fn default() -> Self {
+        VmmEndpoint::Remote(DEFAULT_VMM_ADDRESS.into())
+    }
 }
 
 pub struct VmmClient {
     client: VmmServiceClient<Channel>,
+    /// Only served by the orchestrators started with `--enable-admin`.
+    admin: AdminServiceClient<Channel>,
+    health: HealthClient<Channel>,
 }
 
 impl VmmClient {
-    pub async fn new() -> Result<Self, tonic::transport::Error> {
-        let client = VmmServiceClient::connect("http://[::1]:50051")
-            .await
-            .expect("Failed to connect to VMM service");
+    pub async fn new(endpoint: &VmmEndpoint) -> Result<Self, tonic::transport::Error> {
+        let channel = match endpoint {
+            VmmEndpoint::Remote(address) => match unix_socket::socket_path(address) {
+                Some(path) => {
+                    let path = path.to_path_buf();
+                    // The URI is ignored, every connection goes to the socket.
+                    Endpoint::from_static("http://[::]:50051")
+                        .connect_timeout(Duration::from_secs(5))
+                        .connect_with_connector(service_fn(move |_: Uri| {
+                            UnixStream::connect(path.clone())
+                        }))
+                        .await?
+                }
+                None => {
+                    Endpoint::from_shared(address.clone())?
+                        .connect_timeout(Duration::from_secs(5))
+                        .connect()
+                        .await?
+                }
+            },
+            VmmEndpoint::InMemory(channel) => channel.clone(),
+        };
 
-        Ok(VmmClient { client })
+        Ok(VmmClient {
+            // The artifacts of a run, e.g. a core dump, are sent in one message.
+            client: shared_models::compressed!(VmmServiceClient::new(channel.clone())
+                .max_decoding_message_size(AGENT_MAX_MESSAGE_SIZE)),
+            admin: shared_models::compressed!(AdminServiceClient::new(channel.clone())),
+            health: HealthClient::new(channel),
+        })
     }
 
     pub async fn run_vmm(
         &mut self,
         request: vmmorchestrator::RunVmmRequest,
-    ) -> Result<Streaming<vmmorchestrator::ExecuteResponse>, tonic::Status> {
+    ) -> Result<Streaming<ExecuteResponse>, tonic::Status> {
         let request = tonic::Request::new(request);
         let response_stream = self.client.run(request).await?.into_inner();
 
@@ -38,8 +100,258 @@ impl VmmClient {
         request.set_timeout(Duration::from_secs(5));
         let response = self.client.shutdown(request).await?.into_inner();
 
-        println!("shutdown response: {:?}", response);
+        debug!(?response, "Shut the orchestrator down");
+
+        Ok(response)
+    }
+
+    pub async fn plan(
+        &mut self,
+        request: vmmorchestrator::RunVmmRequest,
+    ) -> Result<vmmorchestrator::RunPlan, tonic::Status> {
+        let response = self.client.plan(request).await?.into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn stream_logs(
+        &mut self,
+        request: vmmorchestrator::StreamLogsRequest,
+    ) -> Result<Streaming<ExecuteResponse>, tonic::Status> {
+        let response_stream = self.client.stream_logs(request).await?.into_inner();
+
+        Ok(response_stream)
+    }
+
+    pub async fn watch_events(
+        &mut self,
+        request: vmmorchestrator::WatchEventsRequest,
+    ) -> Result<Streaming<vmmorchestrator::VmEvent>, tonic::Status> {
+        let response_stream = self.client.watch_events(request).await?.into_inner();
+
+        Ok(response_stream)
+    }
+
+    pub async fn run_inputs(
+        &mut self,
+        run_id: String,
+    ) -> Result<vmmorchestrator::RunInputs, tonic::Status> {
+        let response = self
+            .client
+            .get_run_inputs(vmmorchestrator::GetRunInputsRequest { run_id })
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn run_debug_info(
+        &mut self,
+        run_id: String,
+    ) -> Result<vmmorchestrator::RunDebugInfo, tonic::Status> {
+        let response = self
+            .client
+            .get_run_debug_info(vmmorchestrator::GetRunDebugInfoRequest { run_id })
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn list_run_artifacts(
+        &mut self,
+        run_id: String,
+    ) -> Result<Vec<RunArtifact>, tonic::Status> {
+        let response = self
+            .client
+            .list_run_artifacts(vmmorchestrator::ListRunArtifactsRequest { run_id })
+            .await?
+            .into_inner();
+
+        Ok(response.artifacts)
+    }
+
+    pub async fn run_artifact(
+        &mut self,
+        run_id: String,
+        name: String,
+    ) -> Result<RunArtifact, tonic::Status> {
+        let response = self
+            .client
+            .get_run_artifact(vmmorchestrator::GetRunArtifactRequest { run_id, name })
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn list_guest_dir(
+        &mut self,
+        vm: String,
+        path: String,
+    ) -> Result<ListDirResponse, tonic::Status> {
+        let response = self
+            .client
+            .list_guest_dir(vmmorchestrator::ListGuestDirRequest { vm, path })
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn read_guest_file(
+        &mut self,
+        vm: String,
+        path: String,
+        max_bytes: u64,
+    ) -> Result<ReadFileResponse, tonic::Status> {
+        let response = self
+            .client
+            .read_guest_file(vmmorchestrator::ReadGuestFileRequest {
+                vm,
+                path,
+                max_bytes,
+            })
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    /// Open a shell in a VM, named by the first of the `inputs`, hung up once they end.
+    pub async fn exec_shell(
+        &mut self,
+        inputs: mpsc::Receiver<ShellInput>,
+    ) -> Result<Streaming<ShellOutput>, tonic::Status> {
+        let response = self
+            .client
+            .exec_shell(ReceiverStream::new(inputs))
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn register_workload(
+        &mut self,
+        request: vmmorchestrator::RegisterWorkloadRequest,
+    ) -> Result<vmmorchestrator::RegisteredWorkload, tonic::Status> {
+        let response = self.client.register_workload(request).await?.into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn invoke_workload(
+        &mut self,
+        request: vmmorchestrator::InvokeWorkloadRequest,
+    ) -> Result<tonic::Response<Streaming<ExecuteResponse>>, tonic::Status> {
+        self.client.invoke_workload(request).await
+    }
+
+    pub async fn list_pools(
+        &mut self,
+        name: String,
+    ) -> Result<vmmorchestrator::ListPoolsResponse, tonic::Status> {
+        let response = self
+            .client
+            .list_pools(vmmorchestrator::ListPoolsRequest { name })
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn upgrade_pools(
+        &mut self,
+        request: vmmorchestrator::UpgradePoolsRequest,
+    ) -> Result<vmmorchestrator::UpgradePoolsResponse, tonic::Status> {
+        Ok(self.client.upgrade_pools(request).await?.into_inner())
+    }
+
+    pub async fn cordon_node(
+        &mut self,
+        reason: String,
+    ) -> Result<vmmorchestrator::admin::NodeStatus, tonic::Status> {
+        let request = vmmorchestrator::admin::CordonNodeRequest { reason };
+        Ok(self.admin.cordon_node(request).await?.into_inner())
+    }
+
+    pub async fn uncordon_node(
+        &mut self,
+    ) -> Result<vmmorchestrator::admin::NodeStatus, tonic::Status> {
+        let request = vmmorchestrator::admin::UncordonNodeRequest {};
+        Ok(self.admin.uncordon_node(request).await?.into_inner())
+    }
+
+    pub async fn drain_node(
+        &mut self,
+        request: vmmorchestrator::admin::DrainNodeRequest,
+    ) -> Result<vmmorchestrator::admin::NodeStatus, tonic::Status> {
+        Ok(self.admin.drain_node(request).await?.into_inner())
+    }
+
+    pub async fn list_vm_metrics(
+        &mut self,
+        selector: String,
+    ) -> Result<vmmorchestrator::ListVmMetricsResponse, tonic::Status> {
+        let response = self
+            .client
+            .list_vm_metrics(vmmorchestrator::ListVmMetricsRequest { selector })
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn list_runs(
+        &mut self,
+        request: vmmorchestrator::ListRunsRequest,
+    ) -> Result<vmmorchestrator::ListRunsResponse, tonic::Status> {
+        let response = self.client.list_runs(request).await?.into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn export_usage(
+        &mut self,
+        request: vmmorchestrator::ExportUsageRequest,
+    ) -> Result<vmmorchestrator::ExportUsageResponse, tonic::Status> {
+        let response = self.client.export_usage(request).await?.into_inner();
 
         Ok(response)
     }
+
+    pub async fn list_workloads(
+        &mut self,
+        name: String,
+    ) -> Result<vmmorchestrator::ListWorkloadsResponse, tonic::Status> {
+        let response = self
+            .client
+            .list_workloads(vmmorchestrator::ListWorkloadsRequest { name })
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn server_info(&mut self) -> Result<vmmorchestrator::ServerInfo, tonic::Status> {
+        let response = self
+            .client
+            .get_server_info(vmmorchestrator::GetServerInfoRequest {})
+            .await?
+            .into_inner();
+
+        Ok(response)
+    }
+
+    /// Ask the orchestrator whether it is able to run workloads.
+    pub async fn is_serving(&mut self) -> Result<bool, tonic::Status> {
+        let mut request = tonic::Request::new(HealthCheckRequest {
+            service: VMM_SERVICE_NAME.into(),
+        });
+        request.set_timeout(Duration::from_secs(5));
+        let response = self.health.check(request).await?.into_inner();
+
+        Ok(response.status == ServingStatus::Serving as i32)
+    }
 }
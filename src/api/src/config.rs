@@ -0,0 +1,222 @@
+//! Configuration file of the HTTP API, `api --config api.toml`, and its checks for
+//! `api validate-config`.
+//!
+//! The file sets the same settings as the options of the addresses, which it overrides:
+//!
+//! ```toml
+//! listen = "127.0.0.1:3000"
+//! # unix-socket = "/run/cloudlet/api.sock"   # instead of listen
+//! socket-mode = "660"
+//! vmm-address = "http://[::1]:50051"
+//! ```
+
+use crate::listen::{check_addresses, ListenArgs, Listener};
+use serde::Deserialize;
+use shared_models::{unix_socket, validation::Findings};
+use std::{
+    fs, io,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tonic::transport::Uri;
+
+/// Time given to the orchestrator to accept a connection when the configuration is checked.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ApiConfig {
+    pub listen: Option<SocketAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub socket_mode: Option<String>,
+    pub vmm_address: Option<String>,
+}
+
+impl ApiConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+        toml::from_str(&content).map_err(|e| format!("Invalid configuration {:?}: {}", path, e))
+    }
+
+    /// Override the options with the settings of the file.
+    pub fn apply(self, args: &mut ListenArgs) -> Result<(), String> {
+        if let Some(listen) = self.listen {
+            args.listen = listen;
+            args.port = None;
+        }
+        if let Some(path) = self.unix_socket {
+            args.unix_socket = Some(path);
+        }
+        if let Some(mode) = self.socket_mode {
+            args.socket_mode = unix_socket::parse_mode(&mode)?;
+        }
+        if let Some(vmm_address) = self.vmm_address {
+            args.vmm_address = vmm_address;
+        }
+        Ok(())
+    }
+}
+
+/// Load the configuration file at `path` over the options `args`, and check it against this
+/// host.
+pub fn validate_config(path: &Path, mut args: ListenArgs) -> Findings {
+    let mut findings = Findings::default();
+    let applied = ApiConfig::load(path).and_then(|config| config.apply(&mut args));
+    match applied {
+        Ok(()) => findings.ok(format!("{:?} parses", path)),
+        Err(e) => {
+            findings.error(e);
+            return findings;
+        }
+    }
+
+    let listener = args.listener();
+    findings.check(
+        check_addresses(&listener, Some(&args.vmm_address)),
+        "the addresses and ports are consistent",
+    );
+    check_listen(&listener, &mut findings);
+    check_vmm(&args.vmm_address, &mut findings);
+    findings
+}
+
+/// Check that the API can listen on `listener`, which is only known by trying.
+fn check_listen(listener: &Listener, findings: &mut Findings) {
+    match listener {
+        Listener::Tcp(address) => match TcpListener::bind(address) {
+            Ok(_) => findings.ok(format!("the API can listen on {}", address)),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => findings.warning(format!(
+                "{} is already in use, by a running API or another server",
+                address
+            )),
+            Err(e) => findings.error(format!("the API can't listen on {}: {}", address, e)),
+        },
+        Listener::Unix(path, _) => match fs::symlink_metadata(path) {
+            Ok(metadata) if !is_socket(&metadata) => findings.error(format!(
+                "the socket path {:?} exists and is not a socket",
+                path
+            )),
+            _ => findings.ok(format!("the API can listen on {:?}", path)),
+        },
+    }
+}
+
+#[cfg(unix)]
+fn is_socket(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_socket()
+}
+
+#[cfg(not(unix))]
+fn is_socket(_: &fs::Metadata) -> bool {
+    false
+}
+
+/// Check that the orchestrator accepts connections at `vmm_address`. It may not run yet, so
+/// only a warning otherwise.
+fn check_vmm(vmm_address: &str, findings: &mut Findings) {
+    let reachable = match unix_socket::socket_path(vmm_address) {
+        Some(path) => connect_unix(path),
+        None => vmm_address
+            .parse::<Uri>()
+            .map_err(|e| e.to_string())
+            .and_then(|uri| {
+                let host = uri.host().unwrap_or_default();
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                (host, uri.port_u16().unwrap_or_default())
+                    .to_socket_addrs()
+                    .map_err(|e| e.to_string())?
+                    .next()
+                    .ok_or_else(|| format!("{} resolves to no address", host))
+            })
+            .and_then(|address| {
+                TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }),
+    };
+    match reachable {
+        Ok(()) => findings.ok(format!("the VMM answers at {}", vmm_address)),
+        Err(e) => findings.warning(format!(
+            "the VMM at {} can't be reached: {}",
+            vmm_address, e
+        )),
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> Result<(), String> {
+    std::os::unix::net::UnixStream::connect(path)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_: &Path) -> Result<(), String> {
+    Err("Unix sockets are not supported on this platform".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        listen: ListenArgs,
+    }
+
+    fn options() -> ListenArgs {
+        Args::parse_from(["api"]).listen
+    }
+
+    #[test]
+    fn test_config_overrides_options() {
+        let config: ApiConfig = toml::from_str(
+            "unix-socket = \"/run/cloudlet/api.sock\"\nsocket-mode = \"600\"\n\
+             vmm-address = \"unix:///run/cloudlet/vmm.sock\"\n",
+        )
+        .unwrap();
+        let mut args = options();
+        config.apply(&mut args).unwrap();
+        assert_eq!(
+            args.listener(),
+            Listener::Unix("/run/cloudlet/api.sock".into(), 0o600)
+        );
+        assert_eq!(args.vmm_address, "unix:///run/cloudlet/vmm.sock");
+
+        assert!(toml::from_str::<ApiConfig>("port = 3000\n").is_err());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let dir = std::env::temp_dir().join(format!("api-validate-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("api.toml");
+
+        fs::write(
+            &path,
+            "listen = \"127.0.0.1:3000\"\nvmm-address = \"http://127.0.0.1:3000\"\n",
+        )
+        .unwrap();
+        let findings = validate_config(&path, options());
+        assert_eq!(findings.exit_code(), 1);
+        assert!(findings.findings[1]
+            .message
+            .contains("forward the requests to itself"));
+
+        fs::write(
+            &path,
+            "listen = \"127.0.0.1:3000\"\nsocket-mode = \"999\"\n",
+        )
+        .unwrap();
+        assert_eq!(validate_config(&path, options()).errors(), 1);
+        assert_eq!(
+            validate_config(&dir.join("missing.toml"), options()).errors(),
+            1
+        );
+    }
+}
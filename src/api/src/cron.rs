@@ -0,0 +1,228 @@
+//! Cron expressions of the schedules: `minute hour day-of-month month day-of-week`, in UTC.
+//!
+//! Each field is `*`, a value or a range `a-b`, optionally followed by a step `/n`, or a list
+//! of them separated by commas. The days of the week go from 0 (Sunday) to 7 (Sunday again).
+//! Like with cron, a day matching either the day of the month or of the week is selected when
+//! both are restricted. `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` are shortcuts.
+
+use std::fmt;
+use std::str::FromStr;
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// Days searched for the next match: February 29th on a given day of the week comes back
+/// within 28 years.
+const MAX_DAYS: u64 = 28 * 366;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    // Bit `n` is set when the value `n` matches.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of the month is `*`.
+    any_day: bool,
+    /// Whether the day of the week is `*`.
+    any_weekday: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CronError(String);
+
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid cron expression: {}", self.0)
+    }
+}
+
+impl std::error::Error for CronError {}
+
+fn parse_value(value: &str, name: &str) -> Result<u32, CronError> {
+    value
+        .parse()
+        .map_err(|_| CronError(format!("`{}` is not a valid {}", value, name)))
+}
+
+/// Parse `field`, whose values go from `min` to `max`, into a bit set.
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<u64, CronError> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, parse_value(step, "step")?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(CronError(format!("the step of `{}` is 0", part)));
+        }
+
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (parse_value(start, name)?, parse_value(end, name)?),
+            // `5/15` goes from 5 to the end, like with cron.
+            None if step > 1 => (parse_value(range, name)?, max),
+            None => {
+                let value = parse_value(range, name)?;
+                (value, value)
+            }
+        };
+        if start < min || end > max || start > end {
+            return Err(CronError(format!(
+                "`{}` is out of the {} range, {}-{}",
+                range, name, min, max
+            )));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// Civil date `(year, month, day)` of a day count since the epoch, see
+/// http://howardhinnant.github.io/date_algorithms.html
+fn civil_date(days: u64) -> (u64, u32, u32) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+impl Cron {
+    fn matches_day(&self, days: u64) -> bool {
+        let (_, month, day) = civil_date(days);
+        // The epoch was a Thursday.
+        let weekday = (days + 4) % 7;
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+
+        self.months & (1 << month) != 0
+            && match (self.any_day, self.any_weekday) {
+                (true, true) => true,
+                (true, false) => weekday_matches,
+                (false, true) => day_matches,
+                (false, false) => day_matches || weekday_matches,
+            }
+    }
+
+    /// First time matching the expression strictly after `time`, both in seconds since the
+    /// Unix epoch.
+    pub fn next_after(&self, time: u64) -> Option<u64> {
+        let start = time / 60 + 1;
+        let first_day = start / MINUTES_PER_DAY;
+        for days in first_day..first_day + MAX_DAYS {
+            if !self.matches_day(days) {
+                continue;
+            }
+            let first_minute = if days == first_day {
+                start % MINUTES_PER_DAY
+            } else {
+                0
+            };
+            let minute = (first_minute..MINUTES_PER_DAY).find(|minute| {
+                self.hours & (1 << (minute / 60)) != 0 && self.minutes & (1 << (minute % 60)) != 0
+            });
+            if let Some(minute) = minute {
+                return Some((days * MINUTES_PER_DAY + minute) * 60);
+            }
+        }
+        None
+    }
+}
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(CronError(format!(
+                "expected 5 fields, got {}",
+                fields.len()
+            )));
+        };
+
+        let mut weekdays = parse_field(weekdays, "day of the week", 0, 7)?;
+        // Sunday is both 0 and 7.
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        let cron = Self {
+            minutes: parse_field(minutes, "minute", 0, 59)?,
+            hours: parse_field(hours, "hour", 0, 23)?,
+            days: parse_field(days, "day of the month", 1, 31)?,
+            months: parse_field(months, "month", 1, 12)?,
+            weekdays,
+            any_day: days == "*",
+            any_weekday: fields[4] == "*",
+        };
+
+        if cron.next_after(0).is_none() {
+            return Err(CronError(format!("`{}` matches no date", expression)));
+        }
+        Ok(cron)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86400;
+
+    fn next(expression: &str, time: u64) -> Option<u64> {
+        expression.parse::<Cron>().unwrap().next_after(time)
+    }
+
+    #[test]
+    fn test_next_after() {
+        assert_eq!(next("* * * * *", 0), Some(60));
+        assert_eq!(next("* * * * *", 59), Some(60));
+        assert_eq!(next("*/15 * * * *", 900), Some(1800));
+        assert_eq!(next("30 9 * * *", 10 * 3600), Some(DAY + 9 * 3600 + 1800));
+        // 1970-01-01 was a Thursday, the next Monday is the 5th.
+        assert_eq!(next("0 9 * * 1", 0), Some(4 * DAY + 9 * 3600));
+        assert_eq!(next("0 0 * * 7", 0), next("0 0 * * 0", 0));
+        assert_eq!(next("@monthly", 0), Some(31 * DAY));
+        // The 13th, or a Friday.
+        assert_eq!(next("0 0 13 * 5", 0), Some(DAY));
+        // 1972-02-29.
+        assert_eq!(next("0 0 29 2 *", 0), Some((365 + 365 + 31 + 28) * DAY));
+    }
+
+    #[test]
+    fn test_reject_invalid_expressions() {
+        for expression in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "0 0 30 2 *",
+        ] {
+            assert!(expression.parse::<Cron>().is_err(), "{:?}", expression);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Built-in dashboard showing the state of the orchestrator, for operators
+//! who don't want to set up external tooling.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::service::ExecuteJsonResponse;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use shared_models::vmmorchestrator::{StreamLogsRequest, VmEvent, VmEventKind, WatchEventsRequest};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+const INDEX_HTML: &str = include_str!("../assets/dashboard/index.html");
+const APP_JS: &str = include_str!("../assets/dashboard/app.js");
+
+/// Finished runs shown by the dashboard.
+const MAX_RECENT_RUNS: usize = 50;
+
+/// Buffered output sent to a client when it starts tailing the logs of a VM.
+const LOG_TAIL_LINES: u32 = 200;
+
+/// Delay before watching the orchestrator events again after losing it.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VmSummary {
+    pub vm_id: String,
+    pub workload_name: String,
+    pub state: &'static str,
+    pub started_at: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub vm_id: String,
+    pub workload_name: String,
+    pub outcome: &'static str,
+    pub finished_at: u64,
+    pub message: String,
+    /// Notifications of the end of the run to the webhooks, as they are attempted.
+    pub webhooks: Vec<WebhookDelivery>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDelivery {
+    pub delivered: bool,
+    /// Host of the webhook, and the number of attempts or why they failed.
+    pub message: String,
+}
+
+#[derive(Default)]
+struct State {
+    vms: BTreeMap<String, VmSummary>,
+    runs: VecDeque<RunSummary>,
+}
+
+/// State of the orchestrator, rebuilt from the events it streams.
+#[derive(Clone, Default)]
+pub struct Dashboard {
+    state: Arc<Mutex<State>>,
+}
+
+impl Dashboard {
+    fn apply(&self, event: VmEvent) {
+        let mut state = self.state.lock().unwrap();
+        let kind = event.kind();
+
+        // About the pools of the registered workloads, the queue or the artifacts rather than a VM.
+        if matches!(
+            kind,
+            VmEventKind::PoolScaledUp
+                | VmEventKind::PoolScaledDown
+                | VmEventKind::RunQueued
+                | VmEventKind::RunDequeued
+                | VmEventKind::PoolUpgradeStarted
+                | VmEventKind::PoolUpgradeProgress
+                | VmEventKind::PoolUpgradeFinished
+                | VmEventKind::ArtifactCorrupted
+                | VmEventKind::RootfsBuildStarted
+                | VmEventKind::RootfsBuildFinished
+                | VmEventKind::RootfsBuildFailed
+        ) {
+            return;
+        }
+
+        // Sent once the run is over, when its VM may already be stopped.
+        if matches!(
+            kind,
+            VmEventKind::WebhookDelivered | VmEventKind::WebhookFailed
+        ) {
+            if let Some(run) = state.runs.iter_mut().find(|run| run.vm_id == event.vm_id) {
+                run.webhooks.push(WebhookDelivery {
+                    delivered: kind == VmEventKind::WebhookDelivered,
+                    message: event.message,
+                });
+            }
+            return;
+        }
+
+        match kind {
+            VmEventKind::VmStopped | VmEventKind::VmFailed | VmEventKind::RunRetried => {
+                state.vms.remove(&event.vm_id);
+            }
+            _ => {
+                let vm = state
+                    .vms
+                    .entry(event.vm_id.clone())
+                    .or_insert_with(|| VmSummary {
+                        vm_id: event.vm_id.clone(),
+                        workload_name: event.workload_name.clone(),
+                        state: kind.as_str_name(),
+                        started_at: event.timestamp,
+                        message: String::new(),
+                    });
+                vm.state = kind.as_str_name();
+                vm.message = event.message.clone();
+            }
+        }
+
+        // A retried attempt stays in the history of the runs, before the run on its next VM.
+        if matches!(
+            kind,
+            VmEventKind::RunFinished | VmEventKind::RunFailed | VmEventKind::RunRetried
+        ) {
+            if state.runs.len() == MAX_RECENT_RUNS {
+                state.runs.pop_back();
+            }
+            state.runs.push_front(RunSummary {
+                vm_id: event.vm_id,
+                workload_name: event.workload_name,
+                outcome: kind.as_str_name(),
+                finished_at: event.timestamp,
+                message: event.message,
+                webhooks: Vec::new(),
+            });
+        }
+    }
+
+    /// Follow the events of the orchestrator, reconnecting when it goes away.
+    pub async fn track(self, endpoint: VmmEndpoint) {
+        loop {
+            match VmmClient::new(&endpoint).await {
+                Ok(mut client) => match client.watch_events(WatchEventsRequest::default()).await {
+                    Ok(mut events) => {
+                        while let Some(Ok(event)) = events.next().await {
+                            self.apply(event);
+                        }
+                    }
+                    Err(status) => warn!(%status, "Dashboard: cannot watch events"),
+                },
+                Err(e) => warn!(error = %e, "Dashboard: orchestrator is unreachable"),
+            }
+
+            // The VMs seen until now may have stopped meanwhile.
+            self.state.lock().unwrap().vms.clear();
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+#[get("/dashboard")]
+pub async fn index() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(INDEX_HTML)
+}
+
+#[get("/dashboard/app.js")]
+pub async fn app_js() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/javascript; charset=utf-8")
+        .body(APP_JS)
+}
+
+#[get("/dashboard/api/vms")]
+pub async fn vms(dashboard: web::Data<Dashboard>) -> impl Responder {
+    let vms: Vec<VmSummary> = dashboard
+        .state
+        .lock()
+        .unwrap()
+        .vms
+        .values()
+        .cloned()
+        .collect();
+    HttpResponse::Ok().json(vms)
+}
+
+#[get("/dashboard/api/runs")]
+pub async fn runs(dashboard: web::Data<Dashboard>) -> impl Responder {
+    let runs: Vec<RunSummary> = dashboard
+        .state
+        .lock()
+        .unwrap()
+        .runs
+        .iter()
+        .cloned()
+        .collect();
+    HttpResponse::Ok().json(runs)
+}
+
+/// Tail the output of a VM over a WebSocket, one JSON message per output event.
+#[get("/dashboard/ws/logs/{vm}")]
+pub async fn logs_ws(
+    endpoint: web::Data<VmmEndpoint>,
+    vm: web::Path<String>,
+    request: HttpRequest,
+    body: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let (response, mut session, mut messages) = actix_ws::handle(&request, body)?;
+    let endpoint = endpoint.get_ref().clone();
+    let vm = vm.into_inner();
+
+    // Answer pings and notice when the client leaves.
+    let mut pong_session = session.clone();
+    actix_web::rt::spawn(async move {
+        while let Some(Ok(message)) = messages.next().await {
+            match message {
+                actix_ws::Message::Ping(bytes) => {
+                    if pong_session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+                actix_ws::Message::Close(_) => return,
+                _ => {}
+            }
+        }
+    });
+
+    actix_web::rt::spawn(async move {
+        let logs = async {
+            let mut client = VmmClient::new(&endpoint).await.map_err(|e| e.to_string())?;
+            client
+                .stream_logs(StreamLogsRequest {
+                    vm,
+                    follow: true,
+                    tail_lines: LOG_TAIL_LINES,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|status| status.message().to_string())
+        };
+
+        match logs.await {
+            Ok(mut logs) => {
+                while let Some(Ok(response)) = logs.next().await {
+                    let json = ExecuteJsonResponse::from(response);
+                    if session
+                        .text(serde_json::to_string(&json).unwrap())
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                let _ = session
+                    .text(serde_json::json!({ "error": e }).to_string())
+                    .await;
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
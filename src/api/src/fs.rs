@@ -0,0 +1,98 @@
+//! Files the workload left in a running VM, listed and read through its agent to debug it,
+//! e.g. the project of a build which failed in a VM kept with `keep_on_failure`. The paths are
+//! relative to the workload directory of the guest.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::service::{orchestrator_unavailable, status_response};
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Deserialize;
+use shared_models::cloudlet::agent::{dir_entry::Kind, DirEntry};
+use shared_models::{CloudletDirEntry, CloudletDirListing};
+
+/// Size of the file in the guest, the body being cut past the cap of the agent.
+pub const FILE_SIZE_HEADER: &str = "x-cloudlet-file-size";
+/// Set to `true` when the body is cut.
+pub const TRUNCATED_HEADER: &str = "x-cloudlet-truncated";
+
+impl From<DirEntry> for CloudletDirEntry {
+    fn from(entry: DirEntry) -> Self {
+        let kind = match entry.kind() {
+            Kind::File => "file",
+            Kind::Directory => "directory",
+            Kind::Symlink => "symlink",
+            Kind::Other => "other",
+        };
+        Self {
+            name: entry.name,
+            kind: kind.to_string(),
+            size: entry.size,
+            mode: entry.mode,
+            modified: entry.modified,
+            target: Some(entry.target).filter(|target| !target.is_empty()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FsQuery {
+    /// The workload directory itself if unset.
+    #[serde(default)]
+    path: String,
+    /// Bytes read at most from a file, the cap of the agent if unset.
+    #[serde(default)]
+    max_bytes: u64,
+}
+
+/// List a directory in the VM, designated by its id or its workload name.
+#[get("/vms/{vm}/fs")]
+pub async fn list(
+    endpoint: web::Data<VmmEndpoint>,
+    vm: web::Path<String>,
+    query: web::Query<FsQuery>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client
+        .list_guest_dir(vm.into_inner(), query.into_inner().path)
+        .await
+    {
+        Ok(listing) => HttpResponse::Ok().json(CloudletDirListing {
+            entries: listing
+                .entries
+                .into_iter()
+                .map(CloudletDirEntry::from)
+                .collect(),
+            truncated: listing.truncated,
+        }),
+        Err(status) => status_response(&status),
+    }
+}
+
+/// Read the start of a file in the VM.
+#[get("/vms/{vm}/fs/content")]
+pub async fn read(
+    endpoint: web::Data<VmmEndpoint>,
+    vm: web::Path<String>,
+    query: web::Query<FsQuery>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client
+        .read_guest_file(vm.into_inner(), query.path, query.max_bytes)
+        .await
+    {
+        Ok(file) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header((FILE_SIZE_HEADER, file.size.to_string()))
+            .insert_header((TRUNCATED_HEADER, file.truncated.to_string()))
+            .body(file.content),
+        Err(status) => status_response(&status),
+    }
+}
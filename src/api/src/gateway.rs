@@ -0,0 +1,190 @@
+//! Gateway routing the HTTP requests of `/fn/{name}` to the registered workloads, called like
+//! with `/workloads/{name}/call` in their version registered last.
+//!
+//! The orchestrator keeps a pool of VMs for each workload, reused by the next calls and grown
+//! with the calls in flight, see its `pool` module. The gateway keeps metrics of the calls of
+//! each function since the API started.
+
+use crate::cache::ResponseCache;
+use crate::client::VmmEndpoint;
+use crate::workloads::{call_workload, START_HEADER};
+use actix_web::{get, route, web, HttpRequest, HttpResponse, Responder};
+use shared_models::{CloudletErrorResponse, CloudletFunctionMetrics, ErrorCode};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct FunctionStats {
+    invocations: u64,
+    failures: u64,
+    in_flight: u64,
+    peak_in_flight: u64,
+    cold_starts: u64,
+    warm_starts: u64,
+    total_latency: Duration,
+    max_latency: Duration,
+}
+
+impl FunctionStats {
+    fn to_json(&self, name: &str) -> CloudletFunctionMetrics {
+        let finished = self.invocations - self.in_flight;
+        CloudletFunctionMetrics {
+            name: name.to_string(),
+            invocations: self.invocations,
+            failures: self.failures,
+            in_flight: self.in_flight,
+            peak_in_flight: self.peak_in_flight,
+            cold_starts: self.cold_starts,
+            warm_starts: self.warm_starts,
+            mean_latency_ms: match finished {
+                0 => 0,
+                finished => (self.total_latency.as_millis() / finished as u128) as u64,
+            },
+            max_latency_ms: self.max_latency.as_millis() as u64,
+        }
+    }
+}
+
+/// Metrics of the functions called through the gateway.
+#[derive(Clone, Default)]
+pub struct Gateway {
+    functions: Arc<Mutex<BTreeMap<String, FunctionStats>>>,
+}
+
+impl Gateway {
+    fn start(&self, name: &str) {
+        let mut functions = self.functions.lock().unwrap();
+        let stats = functions.entry(name.to_string()).or_default();
+        stats.invocations += 1;
+        stats.in_flight += 1;
+        stats.peak_in_flight = stats.peak_in_flight.max(stats.in_flight);
+    }
+
+    fn finish(&self, name: &str, latency: Duration, response: &HttpResponse) {
+        let mut functions = self.functions.lock().unwrap();
+        let Some(stats) = functions.get_mut(name) else {
+            return;
+        };
+        stats.in_flight -= 1;
+        // The calls of unknown functions aren't kept.
+        if response.status() == actix_web::http::StatusCode::NOT_FOUND {
+            stats.invocations -= 1;
+            if stats.invocations == 0 {
+                functions.remove(name);
+            }
+            return;
+        }
+
+        stats.total_latency += latency;
+        stats.max_latency = stats.max_latency.max(latency);
+        if !response.status().is_success() {
+            stats.failures += 1;
+        }
+        match response
+            .headers()
+            .get(START_HEADER)
+            .and_then(|start| start.to_str().ok())
+        {
+            Some("cold") => stats.cold_starts += 1,
+            Some("warm") => stats.warm_starts += 1,
+            _ => {}
+        }
+    }
+}
+
+/// Call the function `name` with the body of the request, see `/workloads/{name}/call`.
+#[route("/fn/{name}", method = "GET", method = "POST")]
+pub async fn call(
+    endpoint: web::Data<VmmEndpoint>,
+    cache: web::Data<ResponseCache>,
+    gateway: web::Data<Gateway>,
+    name: web::Path<String>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> impl Responder {
+    let name = name.into_inner();
+    gateway.start(&name);
+    let started = Instant::now();
+    let response = call_workload(
+        &endpoint,
+        &cache,
+        name.clone(),
+        String::new(),
+        &request,
+        body,
+    )
+    .await;
+    gateway.finish(&name, started.elapsed(), &response);
+    response
+}
+
+#[get("/fn")]
+pub async fn list(gateway: web::Data<Gateway>) -> impl Responder {
+    let metrics: Vec<CloudletFunctionMetrics> = gateway
+        .functions
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, stats)| stats.to_json(name))
+        .collect();
+    HttpResponse::Ok().json(metrics)
+}
+
+#[get("/fn/{name}/metrics")]
+pub async fn metrics(gateway: web::Data<Gateway>, name: web::Path<String>) -> impl Responder {
+    match gateway.functions.lock().unwrap().get(name.as_str()) {
+        Some(stats) => HttpResponse::Ok().json(stats.to_json(&name)),
+        None => HttpResponse::NotFound().json(CloudletErrorResponse::new(
+            ErrorCode::ApiUnknownFunction,
+            format!("The function {} wasn't called through the gateway", name),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let gateway = Gateway::default();
+        let response = |mut response: actix_web::HttpResponseBuilder, start: &str| {
+            response.insert_header((START_HEADER, start)).finish()
+        };
+
+        gateway.start("fib");
+        gateway.start("fib");
+        gateway.finish(
+            "fib",
+            Duration::from_millis(300),
+            &response(HttpResponse::Ok(), "cold"),
+        );
+        gateway.finish(
+            "fib",
+            Duration::from_millis(100),
+            &response(HttpResponse::BadGateway(), "warm"),
+        );
+        gateway.start("fib");
+        gateway.start("unknown");
+        gateway.finish(
+            "unknown",
+            Duration::ZERO,
+            &HttpResponse::NotFound().finish(),
+        );
+
+        let functions = gateway.functions.lock().unwrap();
+        assert!(!functions.contains_key("unknown"));
+        let metrics = functions["fib"].to_json("fib");
+        assert_eq!(
+            (metrics.invocations, metrics.failures, metrics.in_flight),
+            (3, 1, 1)
+        );
+        assert_eq!(metrics.peak_in_flight, 2);
+        assert_eq!((metrics.cold_starts, metrics.warm_starts), (1, 1));
+        assert_eq!(
+            (metrics.mean_latency_ms, metrics.max_latency_ms),
+            (200, 300)
+        );
+    }
+}
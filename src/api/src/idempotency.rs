@@ -0,0 +1,180 @@
+use actix_web::HttpRequest;
+use async_stream::stream;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tokio_stream::Stream;
+
+/// Header used by clients to make retried run requests safe.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// How long a key is remembered after the run it started.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Return the idempotency key sent with `request`, if any.
+pub fn idempotency_key(request: &HttpRequest) -> Option<String> {
+    request
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Runs started with an idempotency key, kept for `ttl` so that retries get the original run.
+pub struct IdempotencyStore {
+    ttl: Duration,
+    runs: Mutex<HashMap<String, Arc<RunRecord>>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            runs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the run recorded for `key`, creating it if there's none.
+    /// The boolean is `true` when the record was just created and the run must be started.
+    pub fn get_or_insert(&self, key: &str) -> (Arc<RunRecord>, bool) {
+        let mut runs = self.runs.lock().unwrap();
+        runs.retain(|_, run| run.created_at.elapsed() < self.ttl);
+
+        if let Some(run) = runs.get(key) {
+            return (run.clone(), false);
+        }
+
+        let run = Arc::new(RunRecord::new());
+        runs.insert(key.to_string(), run.clone());
+        (run, true)
+    }
+
+    /// Forget `key`, so that the next request using it starts a new run.
+    pub fn remove(&self, key: &str) {
+        self.runs.lock().unwrap().remove(key);
+    }
+}
+
+impl Default for IdempotencyStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL)
+    }
+}
+
+/// Events of a run, recorded as they are produced so they can be replayed.
+pub struct RunRecord {
+    created_at: Instant,
+    state: Mutex<RecordState>,
+    /// Wakes the replays up on each new event, and at the end of the run.
+    changed: Notify,
+}
+
+struct RecordState {
+    events: Vec<String>,
+    done: bool,
+    /// Job the run was queued as, the orchestrator being unreachable.
+    job: Option<u64>,
+}
+
+impl RunRecord {
+    fn new() -> Self {
+        Self {
+            created_at: Instant::now(),
+            state: Mutex::new(RecordState {
+                events: Vec::new(),
+                done: false,
+                job: None,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn push(&self, event: String) {
+        self.state.lock().unwrap().events.push(event);
+        self.changed.notify_waiters();
+    }
+
+    pub fn finish(&self) {
+        self.state.lock().unwrap().done = true;
+        self.changed.notify_waiters();
+    }
+
+    /// Record that the run was queued as `job` instead of started, for the retries to get the
+    /// job rather than queue the run again.
+    pub fn queued(&self, job: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.job = Some(job);
+        state.done = true;
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// Job the run was queued as, if it was.
+    pub fn job(&self) -> Option<u64> {
+        self.state.lock().unwrap().job
+    }
+
+    /// Stream every event of the run, from the beginning, until the run is done. The events
+    /// are read from the record, however far behind the client is: none is skipped.
+    pub fn replay(self: &Arc<Self>) -> impl Stream<Item = String> {
+        let record = self.clone();
+        stream! {
+            let mut next = 0;
+            loop {
+                let changed = record.changed.notified();
+                tokio::pin!(changed);
+                // Registered before reading the events, so that no new one is missed.
+                changed.as_mut().enable();
+                let (events, done) = {
+                    let state = record.state.lock().unwrap();
+                    (state.events[next..].to_vec(), state.done)
+                };
+                next += events.len();
+                for event in events {
+                    yield event;
+                }
+                if done {
+                    break;
+                }
+                changed.await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_replay_skips_no_event() {
+        let (record, is_new) = IdempotencyStore::default().get_or_insert("key");
+        assert!(is_new);
+        record.push("0".to_string());
+        let replay = record.replay();
+
+        // Far more events than a client reads before it catches up.
+        let recorder = record.clone();
+        let producer = tokio::spawn(async move {
+            for i in 1..1000 {
+                recorder.push(i.to_string());
+                if i % 100 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+            recorder.finish();
+        });
+        let events: Vec<String> = replay.collect().await;
+        producer.await.unwrap();
+
+        let expected: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+        assert_eq!(events, expected);
+        // A retry after the end replays the whole run.
+        assert_eq!(record.replay().collect::<Vec<_>>().await, expected);
+    }
+}
@@ -0,0 +1,411 @@
+//! Runs accepted while the orchestrator is unreachable, kept in a queue on disk until it is back.
+//!
+//! With `--job-queue`, a `/run` request which can't reach the orchestrator is answered with
+//! `202 Accepted` and a job instead of an error, unless it was sent with `Cloudlet-Queue: never`.
+//! The jobs and their requests are kept in a redb database, so that a restart of the API loses
+//! none of them, and are started in the order they were queued: every few seconds, or on a
+//! `POST /jobs/drain`. Their output isn't kept, only their outcome and exit code, as for the
+//! scheduled runs.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::schedules::follow;
+use crate::time::unix_now;
+use actix_web::{get, post, web, HttpResponse, Responder};
+use prost::Message;
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition};
+use shared_models::cloudlet::agent::ExecuteResponse;
+use shared_models::vmmorchestrator::RunVmmRequest;
+use shared_models::{
+    CloudletDrainResponse, CloudletErrorResponse, CloudletJob, CloudletJobQueue, ErrorCode,
+};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Code, Status, Streaming};
+use tracing::{error, info, warn};
+
+/// The jobs, as JSON, by id.
+const JOBS: TableDefinition<u64, &str> = TableDefinition::new("jobs");
+
+/// Requests of the queued jobs, encoded as protobuf, dropped once the job started.
+const REQUESTS: TableDefinition<u64, &[u8]> = TableDefinition::new("requests");
+
+/// Finished jobs kept, the oldest ones being dropped beyond.
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// Wait between two attempts to start the queued jobs.
+const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether a run failed with `status` because the orchestrator couldn't be reached, rather
+/// than being rejected by it, which always comes with an error code.
+pub fn is_unreachable(status: &Status) -> bool {
+    status.code() == Code::Unavailable && ErrorCode::from_status(status).is_none()
+}
+
+/// Queue of the jobs, in the database given to `--job-queue`.
+#[derive(Clone)]
+pub struct JobQueue {
+    db: Arc<Database>,
+    /// Held while starting the jobs, for a job not to be started twice.
+    draining: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl JobQueue {
+    /// Open the queue at `path`, creating it if needed. The jobs left running by the previous
+    /// API are failed, their result is unknown.
+    pub fn open(path: &Path) -> Result<Self, redb::Error> {
+        // The requests hold the secrets of the runs.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o600)
+                .open(path)
+                .map_err(redb::Error::Io)?;
+        }
+
+        let queue = Self {
+            db: Arc::new(Database::create(path)?),
+            draining: Arc::default(),
+        };
+        let txn = queue.db.begin_write()?;
+        txn.open_table(REQUESTS)?;
+        let running: Vec<CloudletJob> = {
+            let mut jobs = txn.open_table(JOBS)?;
+            let running: Vec<CloudletJob> = read_jobs(&jobs)?
+                .into_iter()
+                .filter(|job| job.state == "running")
+                .collect();
+            for mut job in running.clone() {
+                job.state = "failed".into();
+                job.finished_at = Some(unix_now());
+                job.message = "The API restarted during the run, its result is unknown".into();
+                jobs.insert(job.id, serde_json::to_string(&job).unwrap().as_str())?;
+            }
+            running
+        };
+        txn.commit()?;
+        if !running.is_empty() {
+            warn!(
+                jobs = running.len(),
+                "Failed the jobs left running by the previous API"
+            );
+        }
+        Ok(queue)
+    }
+
+    /// Queue `request`, to be started once the orchestrator is reachable.
+    pub fn enqueue(&self, request: &RunVmmRequest) -> Result<CloudletJob, redb::Error> {
+        let txn = self.db.begin_write()?;
+        let job = {
+            let mut jobs = txn.open_table(JOBS)?;
+            let id = match jobs.last()? {
+                Some((id, _)) => id.value() + 1,
+                None => 1,
+            };
+            let job = CloudletJob {
+                id,
+                workload_name: request.workload_name.clone(),
+                state: "queued".into(),
+                queued_at: unix_now(),
+                started_at: None,
+                finished_at: None,
+                attempts: 0,
+                exit_code: None,
+                message: String::new(),
+            };
+            jobs.insert(id, serde_json::to_string(&job).unwrap().as_str())?;
+            txn.open_table(REQUESTS)?
+                .insert(id, request.encode_to_vec().as_slice())?;
+            job
+        };
+        txn.commit()?;
+        Ok(job)
+    }
+
+    /// The jobs, the oldest first, and the number of them still queued.
+    pub fn list(&self) -> Result<CloudletJobQueue, redb::Error> {
+        let txn = self.db.begin_read()?;
+        Ok(CloudletJobQueue {
+            depth: txn.open_table(REQUESTS)?.len()? as usize,
+            jobs: read_jobs(&txn.open_table(JOBS)?)?,
+        })
+    }
+
+    pub fn get(&self, id: u64) -> Result<Option<CloudletJob>, redb::Error> {
+        let txn = self.db.begin_read()?;
+        let job = txn
+            .open_table(JOBS)?
+            .get(id)?
+            .map(|json| parse(json.value()));
+        Ok(job)
+    }
+
+    /// Start the queued jobs, the oldest first, until the orchestrator can't be reached.
+    pub async fn drain(
+        &self,
+        endpoint: &VmmEndpoint,
+    ) -> Result<CloudletDrainResponse, redb::Error> {
+        let _draining = self.draining.lock().await;
+        let mut started = 0;
+        for (id, request) in self.queued()? {
+            let request = match request {
+                Ok(request) => request,
+                Err(e) => {
+                    self.finish(id, None, format!("The request can't be read: {}", e))?;
+                    continue;
+                }
+            };
+            match start(endpoint, request).await {
+                Ok(response_stream) => {
+                    self.update(id, |job| {
+                        job.state = "running".into();
+                        job.started_at = Some(unix_now());
+                        job.attempts += 1;
+                        job.message.clear();
+                    })?;
+                    info!(job = id, "Started the queued job");
+                    started += 1;
+
+                    let queue = self.clone();
+                    tokio::spawn(async move {
+                        let (outcome, exit_code, message) = follow(response_stream).await;
+                        if let Err(e) = queue.update(id, |job| {
+                            job.state = outcome.into();
+                            job.finished_at = Some(unix_now());
+                            job.exit_code = exit_code;
+                            job.message = message;
+                        }) {
+                            error!(job = id, error = %e, "Failed to record the end of the job");
+                        }
+                    });
+                }
+                Err(status) if is_unreachable(&status) => {
+                    self.update(id, |job| {
+                        job.attempts += 1;
+                        job.message = status.message().to_string();
+                    })?;
+                    break;
+                }
+                Err(status) => self.finish(id, None, status.message().to_string())?,
+            }
+        }
+
+        let depth = self.db.begin_read()?.open_table(REQUESTS)?.len()? as usize;
+        Ok(CloudletDrainResponse { started, depth })
+    }
+
+    /// Start the queued jobs whenever the orchestrator is reachable, forever.
+    pub async fn run(self, endpoint: VmmEndpoint) {
+        loop {
+            tokio::time::sleep(DRAIN_INTERVAL).await;
+            if let Err(e) = self.drain(&endpoint).await {
+                error!(error = %e, "Failed to start the queued jobs");
+            }
+        }
+    }
+
+    /// The queued jobs, the oldest first, with their request.
+    #[allow(clippy::type_complexity)]
+    fn queued(&self) -> Result<Vec<(u64, Result<RunVmmRequest, prost::DecodeError>)>, redb::Error> {
+        let txn = self.db.begin_read()?;
+        let requests = txn.open_table(REQUESTS)?;
+        let mut queued = Vec::new();
+        for entry in requests.iter()? {
+            let (id, request) = entry?;
+            queued.push((id.value(), RunVmmRequest::decode(request.value())));
+        }
+        Ok(queued)
+    }
+
+    fn finish(&self, id: u64, exit_code: Option<i32>, message: String) -> Result<(), redb::Error> {
+        self.update(id, |job| {
+            job.state = "failed".into();
+            job.finished_at = Some(unix_now());
+            job.exit_code = exit_code;
+            job.message = message;
+        })
+    }
+
+    /// Change the job `id`, dropping its request once it isn't queued anymore, and the oldest
+    /// finished jobs beyond [`MAX_FINISHED_JOBS`].
+    fn update(&self, id: u64, change: impl FnOnce(&mut CloudletJob)) -> Result<(), redb::Error> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut jobs = txn.open_table(JOBS)?;
+            let Some(mut job) = jobs.get(id)?.map(|json| parse(json.value())) else {
+                return Ok(());
+            };
+            change(&mut job);
+            jobs.insert(id, serde_json::to_string(&job).unwrap().as_str())?;
+            if job.state != "queued" {
+                txn.open_table(REQUESTS)?.remove(id)?;
+            }
+
+            let finished: Vec<u64> = read_jobs(&jobs)?
+                .into_iter()
+                .filter(|job| job.finished_at.is_some())
+                .map(|job| job.id)
+                .collect();
+            for id in &finished[..finished.len().saturating_sub(MAX_FINISHED_JOBS)] {
+                jobs.remove(*id)?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+fn parse(json: &str) -> CloudletJob {
+    // Only this module writes the jobs.
+    serde_json::from_str(json).expect("invalid job in the queue")
+}
+
+fn read_jobs(
+    jobs: &impl ReadableTable<u64, &'static str>,
+) -> Result<Vec<CloudletJob>, redb::Error> {
+    let mut list = Vec::new();
+    for entry in jobs.iter()? {
+        let (_, json) = entry?;
+        list.push(parse(json.value()));
+    }
+    Ok(list)
+}
+
+/// Start `request` on the orchestrator, an unreachable one giving an `Unavailable` status.
+async fn start(
+    endpoint: &VmmEndpoint,
+    request: RunVmmRequest,
+) -> Result<Streaming<ExecuteResponse>, Status> {
+    let mut client = VmmClient::new(endpoint)
+        .await
+        .map_err(|e| Status::unavailable(format!("orchestrator is unreachable: {}", e)))?;
+    client.run_vmm(request).await
+}
+
+pub(crate) fn queue_error(error: redb::Error) -> HttpResponse {
+    HttpResponse::InternalServerError().json(CloudletErrorResponse::new(
+        ErrorCode::ApiJobQueue,
+        format!("The job queue failed: {}", error),
+    ))
+}
+
+fn queue_disabled() -> HttpResponse {
+    HttpResponse::NotFound().json(CloudletErrorResponse::new(
+        ErrorCode::ApiJobQueue,
+        "The API runs without a job queue",
+    ))
+}
+
+#[get("/jobs")]
+pub async fn list(queue: web::Data<Option<JobQueue>>) -> impl Responder {
+    let Some(queue) = queue.get_ref() else {
+        return queue_disabled();
+    };
+    match queue.list() {
+        Ok(jobs) => HttpResponse::Ok().json(jobs),
+        Err(e) => queue_error(e),
+    }
+}
+
+#[get("/jobs/{id}")]
+pub async fn get(queue: web::Data<Option<JobQueue>>, id: web::Path<u64>) -> impl Responder {
+    let Some(queue) = queue.get_ref() else {
+        return queue_disabled();
+    };
+    match queue.get(*id) {
+        Ok(Some(job)) => HttpResponse::Ok().json(job),
+        Ok(None) => HttpResponse::NotFound().json(CloudletErrorResponse::new(
+            ErrorCode::ApiUnknownJob,
+            format!("No job {}", id),
+        )),
+        Err(e) => queue_error(e),
+    }
+}
+
+/// Start the queued jobs now, rather than at the next attempt.
+#[post("/jobs/drain")]
+pub async fn drain(
+    queue: web::Data<Option<JobQueue>>,
+    endpoint: web::Data<VmmEndpoint>,
+) -> impl Responder {
+    let Some(queue) = queue.get_ref() else {
+        return queue_disabled();
+    };
+    match queue.drain(&endpoint).await {
+        Ok(drained) => HttpResponse::Ok().json(drained),
+        Err(e) => queue_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("api-jobs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn request(workload_name: &str) -> RunVmmRequest {
+        RunVmmRequest {
+            workload_name: workload_name.into(),
+            code: "print('hello')".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_jobs_survive_restarts() {
+        let path = queue_path("restart.redb");
+        let queue = JobQueue::open(&path).unwrap();
+        assert_eq!(queue.enqueue(&request("first")).unwrap().id, 1);
+        assert_eq!(queue.enqueue(&request("second")).unwrap().id, 2);
+        queue.update(1, |job| job.state = "running".into()).unwrap();
+        drop(queue);
+
+        let queue = JobQueue::open(&path).unwrap();
+        let jobs = queue.list().unwrap();
+        assert_eq!(jobs.depth, 1);
+        assert_eq!(jobs.jobs[0].state, "failed");
+        assert!(jobs.jobs[0].message.contains("restarted"));
+        assert_eq!(jobs.jobs[1].workload_name, "second");
+
+        let queued = queue.queued().unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].1.as_ref().unwrap(), &request("second"));
+        assert_eq!(queue.enqueue(&request("third")).unwrap().id, 3);
+    }
+
+    #[test]
+    fn test_finished_jobs_are_pruned() {
+        let queue = JobQueue::open(&queue_path("prune.redb")).unwrap();
+        for _ in 0..MAX_FINISHED_JOBS + 2 {
+            let id = queue.enqueue(&request("hello")).unwrap().id;
+            queue.finish(id, Some(1), String::new()).unwrap();
+        }
+        let jobs = queue.list().unwrap();
+        assert_eq!(jobs.depth, 0);
+        assert_eq!(jobs.jobs.len(), MAX_FINISHED_JOBS);
+        assert_eq!(jobs.jobs[0].id, 3);
+        assert!(queue.get(1).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_jobs_stay_queued_while_unreachable() {
+        let queue = JobQueue::open(&queue_path("unreachable.redb")).unwrap();
+        queue.enqueue(&request("hello")).unwrap();
+        let endpoint = VmmEndpoint::Remote("http://127.0.0.1:1".into());
+        let drained = queue.drain(&endpoint).await.unwrap();
+        assert_eq!((drained.started, drained.depth), (0, 1));
+
+        let job = queue.get(1).unwrap().unwrap();
+        assert_eq!((job.state.as_str(), job.attempts), ("queued", 1));
+        assert!(job.message.contains("unreachable"));
+    }
+}
@@ -1,2 +1,129 @@
+use actix_web::{web, App, HttpServer};
+use cache::ResponseCache;
+use client::VmmEndpoint;
+use dashboard::Dashboard;
+use gateway::Gateway;
+use idempotency::IdempotencyStore;
+use jobs::JobQueue;
+use schedules::Scheduler;
+use service::{
+    events, healthz, info, logs, plan, readyz, rerun, run, run_debug_info, run_inputs, runs,
+    shutdown, vms,
+};
+use shared_models::unix_socket;
+use tracing::info;
+
+pub use listen::{ListenArgs, Listener};
+
+pub mod artifacts;
+pub mod cache;
 pub mod client;
+pub mod config;
+pub mod cron;
+pub mod dashboard;
+pub mod fs;
+pub mod gateway;
+pub mod idempotency;
+pub mod jobs;
+pub mod listen;
+pub mod metrics;
+pub mod nodes;
+pub mod pipelines;
+pub mod schedules;
 pub mod service;
+pub mod shell;
+pub mod time;
+pub mod usage;
+pub mod workloads;
+
+/// Start the HTTP API, forwarding the requests to the orchestrator reachable through `endpoint`,
+/// and queueing them in `queue`, if any, while it is unreachable.
+pub async fn serve(
+    endpoint: VmmEndpoint,
+    listener: Listener,
+    queue: Option<JobQueue>,
+) -> std::io::Result<()> {
+    let endpoint = web::Data::new(endpoint);
+    let idempotency = web::Data::new(IdempotencyStore::default());
+    let cache = web::Data::new(ResponseCache::default());
+    let dashboard = Dashboard::default();
+    tokio::spawn(dashboard.clone().track(endpoint.get_ref().clone()));
+    let dashboard = web::Data::new(dashboard);
+    let scheduler = Scheduler::default();
+    tokio::spawn(scheduler.clone().run(endpoint.get_ref().clone()));
+    let scheduler = web::Data::new(scheduler);
+    let gateway = web::Data::new(Gateway::default());
+    if let Some(queue) = &queue {
+        tokio::spawn(queue.clone().run(endpoint.get_ref().clone()));
+    }
+    let queue = web::Data::new(queue);
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(endpoint.clone())
+            .app_data(idempotency.clone())
+            .app_data(cache.clone())
+            .app_data(dashboard.clone())
+            .app_data(scheduler.clone())
+            .app_data(gateway.clone())
+            .app_data(queue.clone())
+            .app_data(web::PayloadConfig::new(workloads::MAX_INPUT_BYTES))
+            .service(run)
+            .service(plan)
+            .service(run_inputs)
+            .service(run_debug_info)
+            .service(rerun)
+            .service(artifacts::list)
+            .service(artifacts::get)
+            .service(fs::list)
+            .service(fs::read)
+            .service(shell::shell)
+            .service(logs)
+            .service(events)
+            .service(info)
+            .service(vms)
+            .service(runs)
+            .service(shutdown)
+            .service(healthz)
+            .service(readyz)
+            .service(metrics::metrics)
+            .service(dashboard::index)
+            .service(dashboard::app_js)
+            .service(dashboard::vms)
+            .service(dashboard::runs)
+            .service(dashboard::logs_ws)
+            .service(schedules::list)
+            .service(schedules::create)
+            .service(schedules::get)
+            .service(schedules::update)
+            .service(schedules::remove)
+            .service(workloads::list)
+            .service(workloads::register)
+            .service(workloads::invoke)
+            .service(workloads::call)
+            .service(workloads::pools)
+            .service(workloads::upgrade_pools)
+            .service(usage::export)
+            .service(gateway::list)
+            .service(gateway::metrics)
+            .service(gateway::call)
+            .service(jobs::list)
+            .service(jobs::drain)
+            .service(jobs::get)
+            .service(nodes::cordon)
+            .service(nodes::uncordon)
+            .service(nodes::drain)
+            .service(pipelines::run)
+    });
+    let server = match listener {
+        Listener::Tcp(address) => {
+            info!(%address, "Starting server");
+            server.bind(address)?
+        }
+        Listener::Unix(path, mode) => {
+            info!(socket = %path.display(), "Starting server");
+            server.listen_uds(unix_socket::bind(&path, mode)?)?
+        }
+    };
+    server.run().await
+}
@@ -0,0 +1,155 @@
+//! Addresses of the HTTP API and of the orchestrator it forwards the requests to, checked when
+//! the API starts rather than when the first request fails.
+
+use crate::client::DEFAULT_VMM_ADDRESS;
+use shared_models::unix_socket;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use tonic::transport::Uri;
+
+/// Address the HTTP API listens on by default.
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:3000";
+
+/// Where the HTTP API listens.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Listener {
+    Tcp(SocketAddr),
+    /// Unix socket at the path, created with the permissions.
+    Unix(PathBuf, u32),
+}
+
+/// Options of the addresses of the HTTP API and of the orchestrator.
+#[derive(clap::Args, Debug)]
+pub struct ListenArgs {
+    /// Address the HTTP API listens on.
+    #[arg(long, env, default_value = DEFAULT_LISTEN)]
+    pub listen: SocketAddr,
+
+    /// Port the HTTP API listens on, instead of the one of `--listen`.
+    #[arg(long, env)]
+    pub port: Option<u16>,
+
+    /// Listen on a Unix socket at this path instead of a port, for the CLI to connect to with
+    /// `--api unix://<PATH>`.
+    #[arg(long, env, conflicts_with_all = ["listen", "port"])]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Permissions of the Unix socket, in octal: who can use the API.
+    #[arg(long, env, default_value = "660", value_parser = unix_socket::parse_mode)]
+    pub socket_mode: u32,
+
+    /// Address of the orchestrator to forward the requests to: `http://<HOST>:<PORT>`, or
+    /// `unix://<PATH>` for an orchestrator listening on a Unix socket.
+    #[arg(long, env, default_value = DEFAULT_VMM_ADDRESS)]
+    pub vmm_address: String,
+}
+
+impl ListenArgs {
+    pub fn listener(&self) -> Listener {
+        match &self.unix_socket {
+            Some(path) => Listener::Unix(path.clone(), self.socket_mode),
+            None => Listener::Tcp(SocketAddr::new(
+                self.listen.ip(),
+                self.port.unwrap_or(self.listen.port()),
+            )),
+        }
+    }
+}
+
+/// Check that the API can listen on `listener` and forward to the orchestrator at
+/// `vmm_address`, if it doesn't run in the same process.
+pub fn check_addresses(listener: &Listener, vmm_address: Option<&str>) -> Result<(), String> {
+    if let Listener::Tcp(address) = listener {
+        if address.port() == 0 {
+            return Err(format!("the listen address {} has no port", address));
+        }
+    }
+    let Some(vmm_address) = vmm_address else {
+        return Ok(());
+    };
+
+    if let Some(path) = unix_socket::socket_path(vmm_address) {
+        if path.as_os_str().is_empty() {
+            return Err(format!("the VMM address {} has no path", vmm_address));
+        }
+        if matches!(listener, Listener::Unix(socket, _) if socket == path) {
+            return Err(format!(
+                "the API listens on {:?}, the VMM can't listen there too",
+                path
+            ));
+        }
+        return Ok(());
+    }
+
+    let uri: Uri = vmm_address
+        .parse()
+        .map_err(|e| format!("invalid VMM address {}: {}", vmm_address, e))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) {
+        return Err(format!(
+            "the VMM address {} must start with http://, https:// or unix://",
+            vmm_address
+        ));
+    }
+    let (Some(host), Some(port)) = (uri.host(), uri.port_u16()) else {
+        return Err(format!(
+            "the VMM address {} has no host or port",
+            vmm_address
+        ));
+    };
+
+    if let Listener::Tcp(address) = listener {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        // An API listening on every address also gets the requests sent to the loopback.
+        let same_host = match host.parse::<IpAddr>() {
+            Ok(ip) => ip == address.ip() || (ip.is_loopback() && address.ip().is_unspecified()),
+            Err(_) => {
+                host == "localhost" && (address.ip().is_loopback() || address.ip().is_unspecified())
+            }
+        };
+        if same_host && port == address.port() {
+            return Err(format!(
+                "the API listens on {}, which is the VMM address {}: it would forward the requests to itself",
+                address, vmm_address
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_addresses() {
+        let tcp = Listener::Tcp(DEFAULT_LISTEN.parse().unwrap());
+        let socket = Listener::Unix("/run/cloudlet/api.sock".into(), 0o660);
+
+        assert!(check_addresses(&tcp, Some(DEFAULT_VMM_ADDRESS)).is_ok());
+        assert!(check_addresses(&tcp, None).is_ok());
+        assert!(check_addresses(&tcp, Some("http://127.0.0.2:3000")).is_ok());
+        assert!(check_addresses(&socket, Some("unix:///run/cloudlet/vmm.sock")).is_ok());
+
+        for (listener, vmm_address) in [
+            (&tcp, "http://localhost:3000"),
+            (&tcp, "http://127.0.0.1:3000"),
+            (
+                &Listener::Tcp("0.0.0.0:50051".parse().unwrap()),
+                "http://127.0.0.1:50051",
+            ),
+            (&socket, "unix:///run/cloudlet/api.sock"),
+            (&tcp, "unix://"),
+            (&tcp, "[::1]:50051"),
+            (&tcp, "http://[::1]"),
+        ] {
+            assert!(
+                check_addresses(listener, Some(vmm_address)).is_err(),
+                "{:?} and {} should conflict",
+                listener,
+                vmm_address
+            );
+        }
+
+        assert!(check_addresses(&Listener::Tcp("127.0.0.1:0".parse().unwrap()), None).is_err());
+    }
+}
@@ -1,13 +1,80 @@
-use actix_web::{App, HttpServer};
-use api::service::{run, shutdown};
+use api::{client::VmmEndpoint, config, jobs::JobQueue, listen, ListenArgs};
+use clap::{Parser, Subcommand};
+use shared_models::logging::LogArgs;
+use std::path::PathBuf;
+use tracing::{error, level_filters::LevelFilter};
+
+/// Run the HTTP API, forwarding the requests to a VMM orchestrator.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    #[command(flatten)]
+    listen: ListenArgs,
+
+    /// TOML configuration file overriding the addresses of these options. See the config module.
+    #[arg(long, env)]
+    config: Option<PathBuf>,
+
+    /// Database queueing the runs while the orchestrator is unreachable, created if needed.
+    /// Without it, these runs fail. See the jobs module.
+    #[arg(long, env)]
+    job_queue: Option<PathBuf>,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check a configuration file against the options and this host, without starting the API.
+    ValidateConfig {
+        /// Configuration file, as given to `--config`.
+        file: PathBuf,
+    },
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let port = 3000;
+    let mut args = Args::parse();
+    if let Some(Command::ValidateConfig { file }) = &args.command {
+        let findings = config::validate_config(file, args.listen);
+        findings.print(&file.display().to_string())?;
+        std::process::exit(findings.exit_code());
+    }
+    args.log.init(LevelFilter::INFO);
+    if let Some(path) = &args.config {
+        if let Err(e) =
+            config::ApiConfig::load(path).and_then(|config| config.apply(&mut args.listen))
+        {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    }
+
+    let listener = args.listen.listener();
+    if let Err(e) = listen::check_addresses(&listener, Some(&args.listen.vmm_address)) {
+        error!(error = %e, "Invalid addresses");
+        std::process::exit(2);
+    }
+
+    let queue = match &args.job_queue {
+        Some(path) => match JobQueue::open(path) {
+            Ok(queue) => Some(queue),
+            Err(e) => {
+                error!(path = ?path, error = %e, "Failed to open the job queue");
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
 
-    println!("Starting server on port:  {}", port);
-    HttpServer::new(|| App::new().service(run).service(shutdown))
-        .bind(("127.0.0.1", port))?
-        .run()
-        .await
+    api::serve(
+        VmmEndpoint::Remote(args.listen.vmm_address),
+        listener,
+        queue,
+    )
+    .await
 }
@@ -0,0 +1,172 @@
+//! Metrics of the orchestrator in the Prometheus text format, for them to be scraped with those
+//! of the other hosts: its hypervisor, labeled as emulated when the guests run without KVM, and
+//! the rootfs images built by fs-gen for each language, their cache hits and the time spent
+//! downloading, extracting and packing them.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::service::{orchestrator_unavailable, status_response};
+use actix_web::{get, web, HttpResponse, Responder};
+use shared_models::vmmorchestrator::{RootfsBuildStats, ServerInfo};
+use std::fmt::Write;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Metrics of the rootfs builds, as (name, type, help, value of the stats of a language).
+type Metric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&RootfsBuildStats) -> f64,
+);
+
+const ROOTFS_METRICS: &[Metric] = &[
+    (
+        "cloudlet_rootfs_requests_total",
+        "counter",
+        "Rootfs images needed by the runs.",
+        |s| s.requests as f64,
+    ),
+    (
+        "cloudlet_rootfs_cache_hits_total",
+        "counter",
+        "Rootfs images already built, on disk, in the shared storage or in the registry.",
+        |s| s.cache_hits as f64,
+    ),
+    (
+        "cloudlet_rootfs_cache_hit_ratio",
+        "gauge",
+        "Share of the rootfs images needed which were already built.",
+        |s| match s.requests {
+            0 => 0.0,
+            requests => s.cache_hits as f64 / requests as f64,
+        },
+    ),
+    (
+        "cloudlet_rootfs_builds_total",
+        "counter",
+        "Rootfs images built by fs-gen.",
+        |s| s.builds as f64,
+    ),
+    (
+        "cloudlet_rootfs_failed_builds_total",
+        "counter",
+        "Rootfs builds which failed.",
+        |s| s.failed_builds as f64,
+    ),
+    (
+        "cloudlet_rootfs_builds_in_progress",
+        "gauge",
+        "Rootfs builds in progress.",
+        |s| s.in_progress as f64,
+    ),
+    (
+        "cloudlet_rootfs_download_bytes_total",
+        "counter",
+        "Bytes of the image layers downloaded by the builds.",
+        |s| s.download_bytes as f64,
+    ),
+    (
+        "cloudlet_rootfs_extract_seconds_total",
+        "counter",
+        "Seconds spent downloading and extracting the image layers.",
+        |s| s.extract_seconds,
+    ),
+    (
+        "cloudlet_rootfs_pack_seconds_total",
+        "counter",
+        "Seconds spent packing the rootfs images.",
+        |s| s.pack_seconds,
+    ),
+    (
+        "cloudlet_rootfs_build_seconds_total",
+        "counter",
+        "Seconds spent building the rootfs images, with the init and the agent.",
+        |s| s.build_seconds,
+    ),
+];
+
+/// Escape `value` as a Prometheus label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render(info: &ServerInfo) -> String {
+    let mut text = String::new();
+    text.push_str("# HELP cloudlet_hypervisor_info Hypervisor of the guests, and whether they are emulated without KVM.\n");
+    text.push_str("# TYPE cloudlet_hypervisor_info gauge\n");
+    let _ = writeln!(
+        text,
+        "cloudlet_hypervisor_info{{hypervisor=\"{}\",emulated=\"{}\"}} 1",
+        label(&info.hypervisor),
+        info.emulated
+    );
+    let builds = &info.rootfs_builds;
+    for (name, kind, help, value) in ROOTFS_METRICS {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        for stats in builds {
+            let _ = writeln!(
+                text,
+                "{}{{language=\"{}\"}} {}",
+                name,
+                label(&stats.language),
+                value(stats)
+            );
+        }
+    }
+    text
+}
+
+#[get("/metrics")]
+pub async fn metrics(endpoint: web::Data<VmmEndpoint>) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.server_info().await {
+        Ok(info) => HttpResponse::Ok()
+            .content_type(CONTENT_TYPE)
+            .body(render(&info)),
+        Err(status) => status_response(&status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let stats = RootfsBuildStats {
+            language: "python".into(),
+            requests: 4,
+            cache_hits: 3,
+            builds: 1,
+            download_bytes: 1024,
+            pack_seconds: 2.5,
+            ..Default::default()
+        };
+        let info = ServerInfo {
+            hypervisor: "qemu-tcg".into(),
+            emulated: true,
+            rootfs_builds: vec![stats],
+            ..Default::default()
+        };
+        let text = render(&info);
+        assert!(text
+            .contains("cloudlet_hypervisor_info{hypervisor=\"qemu-tcg\",emulated=\"true\"} 1\n"));
+        assert!(text.contains("# TYPE cloudlet_rootfs_requests_total counter\n"));
+        assert!(text.contains("cloudlet_rootfs_requests_total{language=\"python\"} 4\n"));
+        assert!(text.contains("cloudlet_rootfs_cache_hit_ratio{language=\"python\"} 0.75\n"));
+        assert!(text.contains("cloudlet_rootfs_download_bytes_total{language=\"python\"} 1024\n"));
+        assert!(text.contains("cloudlet_rootfs_pack_seconds_total{language=\"python\"} 2.5\n"));
+        assert_eq!(label("a\"b"), "a\\\"b");
+
+        // Without any build, only the descriptions are written.
+        assert!(!render(&ServerInfo::default()).contains("language"));
+    }
+}
@@ -0,0 +1,74 @@
+//! Maintenance of the node of the orchestrator, through its `AdminService`: a cordoned node takes
+//! no new run, and a drain waits for the VMs it runs to stop, answering once they are gone or
+//! once its timeout is past.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::service::{orchestrator_unavailable, status_response};
+use actix_web::{post, web, HttpResponse, Responder};
+use shared_models::vmmorchestrator::admin::{DrainNodeRequest, NodeStatus};
+use shared_models::{CloudletNodeRequest, CloudletNodeStatus};
+use tonic::{Code, Status};
+
+impl From<NodeStatus> for CloudletNodeStatus {
+    fn from(value: NodeStatus) -> Self {
+        CloudletNodeStatus {
+            cordoned: value.cordoned,
+            reason: value.reason,
+            cordoned_at: value.cordoned_at,
+            running_vms: value.running_vms,
+            killed_vms: value.killed_vms,
+        }
+    }
+}
+
+fn node_response(result: Result<NodeStatus, Status>) -> HttpResponse {
+    match result {
+        Ok(status) => HttpResponse::Ok().json(CloudletNodeStatus::from(status)),
+        // The orchestrator doesn't serve the AdminService.
+        Err(status) if status.code() == Code::Unimplemented => status_response(
+            &Status::failed_precondition("The orchestrator was started without --enable-admin"),
+        ),
+        Err(status) => status_response(&status),
+    }
+}
+
+/// Stop scheduling new runs onto the node, the running VMs go on.
+#[post("/node/cordon")]
+pub async fn cordon(
+    endpoint: web::Data<VmmEndpoint>,
+    body: web::Json<CloudletNodeRequest>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+    node_response(client.cordon_node(body.into_inner().reason).await)
+}
+
+#[post("/node/uncordon")]
+pub async fn uncordon(endpoint: web::Data<VmmEndpoint>) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+    node_response(client.uncordon_node().await)
+}
+
+/// Cordon the node and wait for its VMs to stop.
+#[post("/node/drain")]
+pub async fn drain(
+    endpoint: web::Data<VmmEndpoint>,
+    body: web::Json<CloudletNodeRequest>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+    let body = body.into_inner();
+    let request = DrainNodeRequest {
+        reason: body.reason,
+        timeout_secs: body.timeout_secs,
+        kill: body.kill,
+    };
+    node_response(client.drain_node(request).await)
+}
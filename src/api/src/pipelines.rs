@@ -0,0 +1,286 @@
+//! Pipelines: steps run by the API, each on a VM of its own, once the steps it depends on
+//! succeeded.
+//!
+//! The outputs a step declares are sent back by its agent on the final message of its run, and
+//! given to the steps depending on it as inputs, under `<step>/<path>` in their inputs
+//! directory. The steps ready at the same time run concurrently. Their events are merged into
+//! the stream of the pipeline, each labeled with its step, and the stream ends with the summary
+//! of the steps. A step whose dependency failed is skipped.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::service::{
+    invalid_request, to_vmm_request, validate_request, ExecuteJsonResponse, StageJson,
+};
+use actix_web::{post, web, Either, Responder};
+use actix_web_lab::sse;
+use async_stream::stream;
+use serde::Serialize;
+use shared_models::cloudlet::agent::{execute_response::Stage, RunArtifact};
+use shared_models::{
+    CloudletPipelineRequest, CloudletPipelineStep, CloudletPipelineSummary, CloudletStepResult,
+    ErrorCode,
+};
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+use tonic::{Code, Status};
+use tracing::{error, info};
+
+/// Event of a step, in the stream of its pipeline.
+#[derive(Serialize)]
+struct StepEvent<'a> {
+    step: &'a str,
+    #[serde(flatten)]
+    event: ExecuteJsonResponse,
+}
+
+/// Final event of the stream of a pipeline.
+#[derive(Serialize)]
+struct SummaryEvent {
+    stage: StageJson,
+    pipeline: CloudletPipelineSummary,
+}
+
+/// Return every problem found in the pipeline, formatted for the client.
+fn validate_pipeline(pipeline: &CloudletPipelineRequest) -> Vec<String> {
+    let steps: Vec<(&str, &[String])> = pipeline
+        .steps
+        .iter()
+        .map(|step| (step.name.as_str(), step.depends_on.as_slice()))
+        .collect();
+
+    let mut errors: Vec<String> = cloudlet_spec::validate_workload_name(&pipeline.name)
+        .map(|e| format!("Pipeline name: {}", e))
+        .into_iter()
+        .collect();
+    if pipeline.steps.is_empty() {
+        errors.push("steps: the pipeline has no step".to_string());
+    }
+    errors.extend(
+        cloudlet_spec::validate_steps(&steps)
+            .into_iter()
+            .map(|e| e.to_string()),
+    );
+    for (i, step) in pipeline.steps.iter().enumerate() {
+        errors.extend(
+            cloudlet_spec::validate_outputs(i, &step.outputs)
+                .into_iter()
+                .map(|e| e.to_string()),
+        );
+        errors.extend(
+            validate_request(&step.request)
+                .into_iter()
+                .map(|e| format!("steps[{}].{}", i, e)),
+        );
+    }
+    errors
+}
+
+/// Run the steps of a pipeline, streaming their events.
+#[post("/pipelines")]
+pub async fn run(
+    endpoint: web::Data<VmmEndpoint>,
+    req_body: web::Json<CloudletPipelineRequest>,
+) -> impl Responder {
+    let pipeline = req_body.into_inner();
+    let errors = validate_pipeline(&pipeline);
+    if !errors.is_empty() {
+        return Either::Left(invalid_request(errors));
+    }
+
+    info!(
+        pipeline = %pipeline.name,
+        steps = pipeline.steps.len(),
+        "Running the pipeline"
+    );
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    actix_web::rt::spawn(orchestrate(endpoint.get_ref().clone(), pipeline, tx));
+
+    Either::Right(sse::Sse::from_infallible_stream(stream! {
+        while let Some(data) = rx.recv().await {
+            yield sse::Event::Data(data);
+        }
+    }))
+}
+
+/// Outcome of a step, and the outputs it sent back.
+struct Finished {
+    result: CloudletStepResult,
+    outputs: Vec<RunArtifact>,
+}
+
+/// Start each step once its dependencies are done, until every step finished or was skipped.
+async fn orchestrate(
+    endpoint: VmmEndpoint,
+    pipeline: CloudletPipelineRequest,
+    tx: mpsc::UnboundedSender<sse::Data>,
+) {
+    let mut pending = pipeline.steps;
+    let mut finished: HashMap<String, Finished> = HashMap::new();
+    let mut results: Vec<CloudletStepResult> = Vec::new();
+    let mut skipped: Vec<CloudletStepResult> = Vec::new();
+    let mut running = JoinSet::new();
+
+    loop {
+        // Skipping a step can skip the steps depending on it, until nothing changes.
+        loop {
+            let failed = pending.iter().position(|step| {
+                step.depends_on.iter().any(|d| {
+                    skipped.iter().any(|s| &s.name == d)
+                        || finished
+                            .get(d)
+                            .is_some_and(|f| f.result.outcome != "succeeded")
+                })
+            });
+            let Some(index) = failed else { break };
+            let step = pending.remove(index);
+            skipped.push(CloudletStepResult {
+                name: step.name,
+                outcome: "skipped".to_string(),
+                exit_code: None,
+                message: "a step it depends on didn't succeed".to_string(),
+            });
+        }
+
+        let (ready, waiting): (Vec<CloudletPipelineStep>, Vec<CloudletPipelineStep>) =
+            pending.into_iter().partition(|step| {
+                step.depends_on
+                    .iter()
+                    .all(|dependency| finished.contains_key(dependency))
+            });
+        pending = waiting;
+        for step in ready {
+            let inputs = step
+                .depends_on
+                .iter()
+                .flat_map(|dependency| {
+                    finished[dependency]
+                        .outputs
+                        .iter()
+                        .map(move |output| RunArtifact {
+                            name: format!(
+                                "{}/{}",
+                                dependency,
+                                output.output_path().unwrap_or_default()
+                            ),
+                            ..output.clone()
+                        })
+                })
+                .collect();
+            running.spawn(run_step(endpoint.clone(), step, inputs, tx.clone()));
+        }
+
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        match joined {
+            Ok((name, step)) => {
+                info!(step = %name, outcome = %step.result.outcome, "Step ended");
+                results.push(step.result.clone());
+                finished.insert(name, step);
+            }
+            Err(e) => error!(error = %e, "A step of the pipeline panicked"),
+        }
+    }
+
+    let succeeded = skipped.is_empty() && results.iter().all(|r| r.outcome == "succeeded");
+    results.extend(skipped);
+    let summary = SummaryEvent {
+        stage: if succeeded {
+            StageJson::Done
+        } else {
+            StageJson::Failed
+        },
+        pipeline: CloudletPipelineSummary {
+            succeeded,
+            steps: results,
+        },
+    };
+    let _ = tx.send(sse::Data::new_json(summary).unwrap());
+}
+
+/// Run `step` with `inputs`, relaying its events labeled with its name.
+async fn run_step(
+    endpoint: VmmEndpoint,
+    step: CloudletPipelineStep,
+    inputs: Vec<RunArtifact>,
+    tx: mpsc::UnboundedSender<sse::Data>,
+) -> (String, Finished) {
+    let name = step.name;
+    let send = |event: ExecuteJsonResponse| {
+        let event = StepEvent { step: &name, event };
+        let _ = tx.send(sse::Data::new_json(event).unwrap());
+    };
+    let failed = |status: Status| {
+        send(ExecuteJsonResponse::failed(&status));
+        Finished {
+            result: CloudletStepResult {
+                name: name.clone(),
+                outcome: "failed".to_string(),
+                exit_code: None,
+                message: status.message().to_string(),
+            },
+            outputs: Vec::new(),
+        }
+    };
+
+    let mut request = to_vmm_request(step.request);
+    request.inputs = inputs;
+    request.outputs = step.outputs;
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => {
+            let status = ErrorCode::ApiOrchestratorUnavailable.status(
+                Code::Unavailable,
+                format!("orchestrator is unreachable: {}", e),
+            );
+            let finished = failed(status);
+            return (name, finished);
+        }
+    };
+    let mut response_stream = match client.run_vmm(request).await {
+        Ok(response_stream) => response_stream,
+        Err(status) => {
+            let finished = failed(status);
+            return (name, finished);
+        }
+    };
+
+    let mut outputs = Vec::new();
+    let mut result = None;
+    while let Some(message) = response_stream.next().await {
+        let mut response = match message {
+            Ok(response) => response,
+            Err(status) => {
+                let finished = failed(status);
+                return (name, finished);
+            }
+        };
+        let (step_outputs, artifacts) = std::mem::take(&mut response.artifacts)
+            .into_iter()
+            .partition(|artifact| artifact.output_path().is_some());
+        outputs.extend::<Vec<RunArtifact>>(step_outputs);
+        response.artifacts = artifacts;
+        if matches!(response.stage(), Stage::Done | Stage::Failed) {
+            result = Some((response.stage(), response.exit_code));
+        }
+        send(ExecuteJsonResponse::from(response));
+    }
+
+    let (outcome, exit_code, message) = match result {
+        Some((Stage::Done, Some(0))) => ("succeeded", Some(0), String::new()),
+        Some((_, exit_code)) => ("failed", exit_code, String::new()),
+        None => ("failed", None, "the run ended without a result".to_string()),
+    };
+    let finished = Finished {
+        result: CloudletStepResult {
+            name: name.clone(),
+            outcome: outcome.to_string(),
+            exit_code,
+            message,
+        },
+        outputs,
+    };
+    (name, finished)
+}
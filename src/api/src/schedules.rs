@@ -0,0 +1,294 @@
+//! Workloads run by the API on cron schedules, see [`crate::cron`].
+//!
+//! The schedules are kept in memory, with the results of their last runs. A run due while
+//! the previous one of the schedule is still running is rejected by the orchestrator like any
+//! duplicate run, and recorded as failed.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::cron::Cron;
+use crate::service::{invalid_request, to_vmm_request, validate_request};
+use crate::time::unix_now;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use shared_models::cloudlet::agent::{execute_response::Stage, ExecuteResponse};
+use shared_models::vmmorchestrator::RunVmmRequest;
+use shared_models::{
+    CloudletErrorResponse, CloudletSchedule, CloudletScheduleRequest, CloudletScheduledRun,
+    ErrorCode,
+};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_stream::StreamExt;
+use tonic::Streaming;
+use tracing::info;
+
+/// Runs kept in the history of a schedule.
+const MAX_SCHEDULED_RUNS: usize = 20;
+
+/// Longest wait between two checks of the schedules, in case the clock jumps.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+struct Schedule {
+    expression: String,
+    cron: Cron,
+    enabled: bool,
+    request: RunVmmRequest,
+    next_run: Option<u64>,
+    runs: VecDeque<CloudletScheduledRun>,
+}
+
+impl Schedule {
+    fn to_json(&self, name: &str) -> CloudletSchedule {
+        CloudletSchedule {
+            name: name.to_string(),
+            cron: self.expression.clone(),
+            enabled: self.enabled,
+            workload_name: self.request.workload_name.clone(),
+            next_run: self.next_run,
+            runs: self.runs.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Schedules of the API, and the task starting their runs.
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    schedules: Arc<Mutex<BTreeMap<String, Schedule>>>,
+    changed: Arc<Notify>,
+}
+
+impl Scheduler {
+    /// Create the schedule `name` if `create` is set, or replace it while keeping its history.
+    /// Returns `None` if it already exists, or when replacing it doesn't.
+    fn put(&self, name: &str, mut schedule: Schedule, create: bool) -> Option<CloudletSchedule> {
+        let mut schedules = self.schedules.lock().unwrap();
+        match (schedules.get_mut(name), create) {
+            (None, true) => {}
+            (Some(replaced), false) => schedule.runs = std::mem::take(&mut replaced.runs),
+            _ => return None,
+        }
+
+        let json = schedule.to_json(name);
+        schedules.insert(name.to_string(), schedule);
+        self.changed.notify_one();
+        Some(json)
+    }
+
+    /// Start the runs which are due at `now`, and return when the next one is.
+    fn start_due(&self, now: u64, endpoint: &VmmEndpoint) -> Option<u64> {
+        let mut schedules = self.schedules.lock().unwrap();
+        for (name, schedule) in schedules.iter_mut() {
+            if !schedule.next_run.is_some_and(|next_run| next_run <= now) {
+                continue;
+            }
+            // A run missed while the API was busy isn't caught up on.
+            schedule.next_run = schedule.cron.next_after(now);
+            if schedule.runs.len() == MAX_SCHEDULED_RUNS {
+                schedule.runs.pop_back();
+            }
+            schedule.runs.push_front(CloudletScheduledRun {
+                started_at: now,
+                finished_at: None,
+                outcome: "running".into(),
+                exit_code: None,
+                message: String::new(),
+            });
+
+            info!(schedule = %name, "Starting the scheduled run");
+            let (scheduler, endpoint) = (self.clone(), endpoint.clone());
+            let (name, request) = (name.clone(), schedule.request.clone());
+            tokio::spawn(async move {
+                let run = launch(&endpoint, request, now).await;
+                scheduler.record(&name, run);
+            });
+        }
+        schedules
+            .values()
+            .filter_map(|schedule| schedule.next_run)
+            .min()
+    }
+
+    /// Record the result of a run started by the schedule `name`, unless it was deleted since.
+    fn record(&self, name: &str, run: CloudletScheduledRun) {
+        let mut schedules = self.schedules.lock().unwrap();
+        let recorded = schedules.get_mut(name).and_then(|schedule| {
+            schedule
+                .runs
+                .iter_mut()
+                .find(|recorded| recorded.started_at == run.started_at)
+        });
+        if let Some(recorded) = recorded {
+            *recorded = run;
+        }
+    }
+
+    /// Start the runs of the schedules when they are due, forever.
+    pub async fn run(self, endpoint: VmmEndpoint) {
+        loop {
+            let now = unix_now();
+            let wait = match self.start_due(now, &endpoint) {
+                Some(next_run) => Duration::from_secs(next_run.saturating_sub(now)).min(MAX_WAIT),
+                None => MAX_WAIT,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+}
+
+/// Run `request` to its end, and return its result.
+async fn launch(
+    endpoint: &VmmEndpoint,
+    request: RunVmmRequest,
+    started_at: u64,
+) -> CloudletScheduledRun {
+    let started = async {
+        let mut client = VmmClient::new(endpoint)
+            .await
+            .map_err(|e| format!("orchestrator is unreachable: {}", e))?;
+        client
+            .run_vmm(request)
+            .await
+            .map_err(|status| status.message().to_string())
+    };
+
+    let (outcome, exit_code, message) = match started.await {
+        Ok(response_stream) => follow(response_stream).await,
+        Err(message) => ("failed", None, message),
+    };
+    CloudletScheduledRun {
+        started_at,
+        finished_at: Some(unix_now()),
+        outcome: outcome.into(),
+        exit_code,
+        message,
+    }
+}
+
+/// Follow the output of a started run to its end, and return its outcome, `done` or `failed`,
+/// its exit code, and why it failed if it couldn't run to its end.
+pub(crate) async fn follow(
+    mut response_stream: Streaming<ExecuteResponse>,
+) -> (&'static str, Option<i32>, String) {
+    let mut result = None;
+    while let Some(message) = response_stream.next().await {
+        match message {
+            Ok(response) if matches!(response.stage(), Stage::Done | Stage::Failed) => {
+                result = Some((response.stage(), response.exit_code));
+            }
+            Ok(_) => {}
+            Err(status) => return ("failed", None, status.message().to_string()),
+        }
+    }
+    match result {
+        Some((Stage::Done, Some(0))) => ("done", Some(0), String::new()),
+        Some((_, exit_code)) => ("failed", exit_code, String::new()),
+        None => ("failed", None, "the run ended without a result".to_string()),
+    }
+}
+
+fn unknown_schedule(name: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(CloudletErrorResponse::new(
+        ErrorCode::ApiUnknownSchedule,
+        format!("No schedule named {}", name),
+    ))
+}
+
+/// Check `body` and return the schedule it describes, without any run yet.
+fn parse_schedule(name: &str, body: CloudletScheduleRequest) -> Result<Schedule, HttpResponse> {
+    let mut errors: Vec<String> = cloudlet_spec::validate_workload_name(name)
+        .map(|e| format!("Schedule name: {}", e))
+        .into_iter()
+        .collect();
+    let cron = match body.cron.parse::<Cron>() {
+        Ok(cron) => Some(cron),
+        Err(e) => {
+            errors.push(e.to_string());
+            None
+        }
+    };
+    errors.extend(validate_request(&body.request));
+
+    match cron {
+        Some(cron) if errors.is_empty() => Ok(Schedule {
+            next_run: cron.next_after(unix_now()).filter(|_| body.enabled),
+            expression: body.cron,
+            cron,
+            enabled: body.enabled,
+            request: to_vmm_request(body.request),
+            runs: VecDeque::new(),
+        }),
+        _ => Err(invalid_request(errors)),
+    }
+}
+
+#[get("/schedules")]
+pub async fn list(scheduler: web::Data<Scheduler>) -> impl Responder {
+    let schedules: Vec<CloudletSchedule> = scheduler
+        .schedules
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, schedule)| schedule.to_json(name))
+        .collect();
+    HttpResponse::Ok().json(schedules)
+}
+
+#[post("/schedules")]
+pub async fn create(
+    scheduler: web::Data<Scheduler>,
+    body: web::Json<CloudletScheduleRequest>,
+) -> impl Responder {
+    let body = body.into_inner();
+    let name = body.name.clone();
+    let schedule = match parse_schedule(&name, body) {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+
+    match scheduler.put(&name, schedule, true) {
+        Some(schedule) => HttpResponse::Created().json(schedule),
+        None => HttpResponse::Conflict().json(CloudletErrorResponse::new(
+            ErrorCode::ApiDuplicateSchedule,
+            format!("A schedule named {} already exists", name),
+        )),
+    }
+}
+
+#[get("/schedules/{name}")]
+pub async fn get(scheduler: web::Data<Scheduler>, name: web::Path<String>) -> impl Responder {
+    match scheduler.schedules.lock().unwrap().get(name.as_str()) {
+        Some(schedule) => HttpResponse::Ok().json(schedule.to_json(&name)),
+        None => unknown_schedule(&name),
+    }
+}
+
+/// Replace the cron expression, the state and the workload of a schedule.
+#[put("/schedules/{name}")]
+pub async fn update(
+    scheduler: web::Data<Scheduler>,
+    name: web::Path<String>,
+    body: web::Json<CloudletScheduleRequest>,
+) -> impl Responder {
+    let schedule = match parse_schedule(&name, body.into_inner()) {
+        Ok(schedule) => schedule,
+        Err(response) => return response,
+    };
+
+    match scheduler.put(&name, schedule, false) {
+        Some(schedule) => HttpResponse::Ok().json(schedule),
+        None => unknown_schedule(&name),
+    }
+}
+
+/// Delete a schedule, its running run goes on.
+#[delete("/schedules/{name}")]
+pub async fn remove(scheduler: web::Data<Scheduler>, name: web::Path<String>) -> impl Responder {
+    match scheduler.schedules.lock().unwrap().remove(name.as_str()) {
+        Some(_) => HttpResponse::NoContent().finish(),
+        None => unknown_schedule(&name),
+    }
+}
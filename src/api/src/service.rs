@@ -1,53 +1,765 @@
-use crate::client::{
-    vmmorchestrator::{
-        execute_response::Stage, ExecuteResponse, RunVmmRequest, ShutdownVmRequest,
-        ShutdownVmResponse,
-    },
-    VmmClient,
-};
-use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::idempotency::{idempotency_key, IdempotencyStore, RunRecord};
+use crate::jobs::{self, JobQueue};
+use actix_web::{get, post, web, Either, HttpRequest, HttpResponse, Responder};
 use actix_web_lab::sse;
 use async_stream::stream;
-use serde::Serialize;
-use shared_models::{CloudletDtoRequest, Language};
-use tokio_stream::StreamExt;
-use tonic::Streaming;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use cloudlet_spec::ValidationError;
+use serde::{Deserialize, Serialize};
+use shared_models::cloudlet::agent::{
+    self,
+    execute_response::{Encoding, Stage},
+    stage_marker::Event,
+    ExecuteResponse, PipelineStage, StageMarker,
+};
+use shared_models::vmmorchestrator::{
+    self, LogStream, RunVmmRequest, ShutdownVmRequest, ShutdownVmResponse, StreamLogsRequest,
+    VmEvent, WatchEventsRequest,
+};
+use shared_models::{
+    redact_env, ArtifactPlan, CloudletDtoRequest, CloudletErrorResponse, CloudletPlanResponse,
+    CloudletRunArtifact, CloudletRunInputs, CloudletRunRecord, CloudletServerInfo, CloudletVmExit,
+    CloudletVmMetrics, ErrorCode, KernelInfo, Resources, RuntimeVersions, QUEUE_HEADER, REDACTED,
+};
+use std::fmt::Display;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Code, Status, Streaming};
+use tracing::{debug, info};
+
+/// Stream of server-sent events returned by `/run`.
+type RunEventStream = Pin<Box<dyn Stream<Item = sse::Event>>>;
 
 #[post("/run")]
-pub async fn run(req_body: web::Json<CloudletDtoRequest>) -> impl Responder {
+pub async fn run(
+    endpoint: web::Data<VmmEndpoint>,
+    idempotency: web::Data<IdempotencyStore>,
+    queue: web::Data<Option<JobQueue>>,
+    request: HttpRequest,
+    req_body: web::Json<CloudletDtoRequest>,
+) -> impl Responder {
     let req = req_body.into_inner();
 
-    let mut client = VmmClient::new().await.unwrap();
+    let errors = validate_request(&req);
+    if !errors.is_empty() {
+        return Either::Left(invalid_request(errors));
+    }
+
+    // A retried request gets the events of the run started by the original one.
+    let key = idempotency_key(&request);
+    let record = match &key {
+        Some(key) => {
+            let (record, is_new) = idempotency.get_or_insert(key);
+            let job = record.job().zip(queue.get_ref().as_ref());
+            if let Some((id, queue)) = job {
+                info!(idempotency_key = %key, job = id, "Returning the queued job");
+                return Either::Left(match queue.get(id) {
+                    Ok(Some(job)) => HttpResponse::Accepted().json(job),
+                    Ok(None) => HttpResponse::NotFound().json(CloudletErrorResponse::new(
+                        ErrorCode::ApiUnknownJob,
+                        format!("No job {}", id),
+                    )),
+                    Err(e) => jobs::queue_error(e),
+                });
+            }
+            if !is_new {
+                info!(idempotency_key = %key, "Replaying run");
+                return Either::Right(sse::Sse::from_infallible_stream(replay(&record)));
+            }
+            Some(record)
+        }
+        None => None,
+    };
+
+    debug!(request = ?req, "Run requested");
+
+    let vmm_request = to_vmm_request(req);
+
+    let mut logged_request = vmm_request.clone();
+    redact_env(logged_request.env.iter_mut(), &vmm_request.secret_env);
+    for webhook in &mut logged_request.webhooks {
+        webhook.secret = REDACTED.to_string();
+    }
+    debug!(request = ?logged_request, "Sending the run to the orchestrator");
+
+    let queued_request = vmm_request.clone();
+    let response_stream = match start_run(&endpoint, vmm_request).await {
+        Ok(response_stream) => response_stream,
+        Err(error) => {
+            // Unless the run is queued, a retry with the key starts it again.
+            let forget = || {
+                if let (Some(key), Some(record)) = (&key, &record) {
+                    idempotency.remove(key);
+                    record.finish();
+                }
+            };
+            return Either::Left(match (error, queue.get_ref()) {
+                (StartError::Unreachable(_), Some(queue)) if accepts_queueing(&request) => {
+                    match queue.enqueue(&queued_request) {
+                        Ok(job) => {
+                            info!(job = job.id, "Queued the run");
+                            if let Some(record) = &record {
+                                record.queued(job.id);
+                            }
+                            HttpResponse::Accepted().json(job)
+                        }
+                        Err(e) => {
+                            forget();
+                            HttpResponse::ServiceUnavailable().json(CloudletErrorResponse::new(
+                                ErrorCode::ApiJobQueue,
+                                format!(
+                                "orchestrator is unreachable, and the run couldn't be queued: {}",
+                                e
+                            ),
+                            ))
+                        }
+                    }
+                }
+                (error, _) => {
+                    forget();
+                    error.into_response()
+                }
+            });
+        }
+    };
+
+    let stream: RunEventStream = match record {
+        // Record the run independently of this client, so that retries can pick it up.
+        Some(record) => {
+            let recorder = record.clone();
+            actix_web::rt::spawn(async move {
+                let mut response_stream = response_stream;
+                while let Some(message) = response_stream.next().await {
+                    let json = match message {
+                        Ok(exec_response) => ExecuteJsonResponse::from(exec_response),
+                        // Replayed like `run_events` streams it, e.g. a preemption or deadline.
+                        Err(status) => ExecuteJsonResponse::failed(&status),
+                    };
+                    recorder.push(serde_json::to_string(&json).unwrap());
+                }
+                recorder.finish();
+            });
+            replay(&record)
+        }
+        None => run_events(response_stream),
+    };
+
+    Either::Right(sse::Sse::from_infallible_stream(stream))
+}
+
+/// Describe the inputs recorded for a past run.
+#[get("/runs/{run_id}/inputs")]
+pub async fn run_inputs(
+    endpoint: web::Data<VmmEndpoint>,
+    run_id: web::Path<String>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.run_inputs(run_id.into_inner()).await {
+        Ok(inputs) => HttpResponse::Ok().json(CloudletRunInputs::from(inputs)),
+        Err(status) => status_response(&status),
+    }
+}
+
+/// What the orchestrator knows of a run, for its debug bundle.
+#[derive(Debug, Serialize)]
+pub struct RunDebugInfoJson {
+    pub run: Option<CloudletRunRecord>,
+    pub events: Vec<VmEventJson>,
+    pub request: String,
+    pub config: String,
+    /// File of the console of its guest on the host of the orchestrator, empty if not logged.
+    pub console_log: String,
+}
+
+/// Describe a run for a bug report: its record, the events of its VM, its request and the
+/// settings of the orchestrator, secrets redacted.
+#[get("/runs/{run_id}/debug")]
+pub async fn run_debug_info(
+    endpoint: web::Data<VmmEndpoint>,
+    run_id: web::Path<String>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.run_debug_info(run_id.into_inner()).await {
+        Ok(info) => HttpResponse::Ok().json(RunDebugInfoJson {
+            run: info.run.map(CloudletRunRecord::from),
+            events: info.events.into_iter().map(VmEventJson::from).collect(),
+            request: info.request,
+            config: info.config,
+            console_log: info.console_log,
+        }),
+        Err(status) => status_response(&status),
+    }
+}
+
+/// Start a past run again with the inputs recorded for it, streaming its output like `/run`.
+#[post("/runs/{run_id}/rerun")]
+pub async fn rerun(endpoint: web::Data<VmmEndpoint>, run_id: web::Path<String>) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return Either::Left(orchestrator_unavailable(e)),
+    };
+
+    let run_id = run_id.into_inner();
+    let vmm_request = match client.run_inputs(run_id.clone()).await {
+        Ok(inputs) => inputs.request.unwrap_or_default(),
+        Err(status) => return Either::Left(status_response(&status)),
+    };
+    info!(
+        run = %run_id,
+        workload = %vmm_request.workload_name,
+        "Rerunning"
+    );
+
+    match start_run(&endpoint, vmm_request).await {
+        Ok(response_stream) => Either::Right(sse::Sse::from_infallible_stream(run_events(
+            response_stream,
+        ))),
+        Err(error) => Either::Left(error.into_response()),
+    }
+}
+
+pub(crate) fn run_events(mut response_stream: Streaming<ExecuteResponse>) -> RunEventStream {
+    Box::pin(stream! {
+        while let Some(message) = response_stream.next().await {
+            let json = match message {
+                Ok(exec_response) => ExecuteJsonResponse::from(exec_response),
+                // The orchestrator ended the run, e.g. to preempt its VM.
+                Err(status) => ExecuteJsonResponse::failed(&status),
+            };
+            yield sse::Event::Data(sse::Data::new_json(json).unwrap());
+        }
+    })
+}
+
+/// Why a run couldn't be started, and the response for the client.
+enum StartError {
+    /// The orchestrator couldn't be reached, the run can be queued.
+    Unreachable(HttpResponse),
+    Rejected(HttpResponse),
+}
+
+impl StartError {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            StartError::Unreachable(response) | StartError::Rejected(response) => response,
+        }
+    }
+}
+
+async fn start_run(
+    endpoint: &VmmEndpoint,
+    vmm_request: RunVmmRequest,
+) -> Result<Streaming<ExecuteResponse>, StartError> {
+    let mut client = VmmClient::new(endpoint)
+        .await
+        .map_err(|e| StartError::Unreachable(orchestrator_unavailable(e)))?;
+
+    debug!("Successfully connected to VMM service");
+
+    let response_stream = client.run_vmm(vmm_request).await.map_err(|status| {
+        let response = status_response(&status);
+        match jobs::is_unreachable(&status) {
+            true => StartError::Unreachable(response),
+            false => StartError::Rejected(response),
+        }
+    })?;
+    debug!(?response_stream, "Response stream");
+
+    Ok(response_stream)
+}
+
+/// Whether the client of `request` accepts a job rather than the output of its run.
+fn accepts_queueing(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get(QUEUE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map_or(true, |value| !value.trim().eq_ignore_ascii_case("never"))
+}
+
+fn replay(record: &Arc<RunRecord>) -> RunEventStream {
+    Box::pin(
+        record
+            .replay()
+            .map(|json| sse::Event::Data(sse::Data::new(json))),
+    )
+}
 
-    println!("Request: {:?}", req);
+pub(crate) fn invalid_request(errors: Vec<String>) -> HttpResponse {
+    HttpResponse::BadRequest().json(
+        CloudletErrorResponse::new(ErrorCode::ApiInvalidRequest, "Invalid request")
+            .with_details(errors),
+    )
+}
+
+pub(crate) fn orchestrator_unavailable(error: impl Display) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(CloudletErrorResponse::new(
+        ErrorCode::ApiOrchestratorUnavailable,
+        format!("orchestrator is unreachable: {}", error),
+    ))
+}
+
+/// Forward an error returned by the orchestrator, with its error code.
+pub(crate) fn status_response(status: &Status) -> HttpResponse {
+    let body = CloudletErrorResponse::from_status(status);
+    match status.code() {
+        Code::InvalidArgument => HttpResponse::BadRequest().json(body),
+        Code::PermissionDenied => HttpResponse::Forbidden().json(body),
+        Code::NotFound => HttpResponse::NotFound().json(body),
+        Code::AlreadyExists => HttpResponse::Conflict().json(body),
+        Code::FailedPrecondition => HttpResponse::UnprocessableEntity().json(body),
+        Code::DeadlineExceeded => HttpResponse::GatewayTimeout().json(body),
+        Code::Unavailable | Code::ResourceExhausted => {
+            HttpResponse::ServiceUnavailable().json(body)
+        }
+        _ => HttpResponse::InternalServerError().json(body),
+    }
+}
 
-    let vmm_request = RunVmmRequest {
+/// Return every problem found in the request, formatted for the client.
+pub(crate) fn validate_request(req: &CloudletDtoRequest) -> Vec<String> {
+    cloudlet_spec::validate_workload_name(&req.workload_name)
+        .into_iter()
+        .chain(
+            req.tenant
+                .as_deref()
+                .and_then(cloudlet_spec::validate_tenant),
+        )
+        .chain(cloudlet_spec::validate_resources(&req.resources))
+        .chain(cloudlet_spec::validate_env(&req.env))
+        .chain(cloudlet_spec::validate_secret_env(
+            &req.env,
+            &req.secret_env,
+        ))
+        .chain(cloudlet_spec::validate_webhooks(&req.webhooks))
+        .chain(cloudlet_spec::validate_labels(&req.labels))
+        .chain(req.timeout_secs.and_then(cloudlet_spec::validate_timeout))
+        .chain(confirm_language(req))
+        .map(|e| e.to_string())
+        .collect()
+}
+
+/// Check that the code doesn't contradict the language the client detected for it.
+fn confirm_language(req: &CloudletDtoRequest) -> Option<ValidationError> {
+    let reason = req.language_detection.as_ref()?;
+    let language = cloudlet_spec::code_language(&req.code).filter(|l| *l != req.language)?;
+    Some(ValidationError::new(
+        "language",
+        format!(
+            "was detected as {} from {}, but the code is {}: set it explicitly",
+            req.language.as_str(),
+            reason,
+            language.as_str()
+        ),
+    ))
+}
+
+pub(crate) fn to_vmm_request(req: CloudletDtoRequest) -> RunVmmRequest {
+    RunVmmRequest {
         workload_name: req.workload_name,
         code: req.code,
-        language: match req.language {
-            Language::RUST => 0,
-            Language::PYTHON => 1,
-            Language::NODE => 2,
-        },
-        log_level: req.log_level as i32,
+        language: vmmorchestrator::Language::from(req.language) as i32,
+        log_level: vmmorchestrator::LogLevel::from(req.log_level) as i32,
+        cpus: req.resources.cpus.into(),
+        memory_mb: req.resources.memory_mb,
+        io_limits: Some(vmmorchestrator::IoLimits {
+            net_bytes_per_sec: req.resources.io.net_bytes_per_sec,
+            net_packets_per_sec: req.resources.io.net_packets_per_sec,
+            disk_flushes_per_sec: req.resources.io.disk_flushes_per_sec,
+        }),
+        tmp_quota_mb: req.resources.tmp_quota_mb.unwrap_or_default(),
+        devices: req
+            .devices
+            .into_iter()
+            .map(|device| vmmorchestrator::Device::from(device) as i32)
+            .collect(),
+        capabilities: req
+            .capabilities
+            .into_iter()
+            .map(|capability| vmmorchestrator::Capability::from(capability) as i32)
+            .collect(),
+        node_affinity: Some(req.node_affinity.into()),
+        kernel: req.kernel.unwrap_or_default(),
+        priority_class: req.priority_class.unwrap_or_default(),
+        image_digest: String::new(),
+        env: req.env.into_iter().collect(),
+        secret_env: req.secret_env,
+        stages: req
+            .stages
+            .into_iter()
+            .map(|stage| agent::PipelineStage::from(stage) as i32)
+            .collect(),
+        webhooks: req
+            .webhooks
+            .into_iter()
+            .map(vmmorchestrator::Webhook::from)
+            .collect(),
+        stdin: Vec::new(),
+        tenant: req.tenant.unwrap_or_default(),
+        keep_on_failure: req.keep_on_failure,
+        build: Some(agent::BuildConfig {
+            release: req.build.release,
+            features: req.build.features,
+            compiler_flags: req.build.compiler_flags,
+            runtime_version: req.build.runtime_version.unwrap_or_default(),
+        }),
+        inputs: Vec::new(),
+        outputs: Vec::new(),
+        labels: req.labels.into_iter().collect(),
+        timeout_secs: req.timeout_secs.unwrap_or_default(),
+    }
+}
+
+/// Validate a run request and describe how it would be executed, without starting a VM.
+#[post("/plan")]
+pub async fn plan(
+    endpoint: web::Data<VmmEndpoint>,
+    req_body: web::Json<CloudletDtoRequest>,
+) -> impl Responder {
+    let req = req_body.into_inner();
+
+    let errors = validate_request(&req);
+    if !errors.is_empty() {
+        return invalid_request(errors);
+    }
+
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.plan(to_vmm_request(req)).await {
+        Ok(plan) => HttpResponse::Ok().json(CloudletPlanResponse::from(plan)),
+        Err(status) => status_response(&status),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogsQuery {
+    #[serde(default)]
+    follow: bool,
+    #[serde(default)]
+    tail_lines: u32,
+    /// Only the output received from then, in seconds since the Unix epoch.
+    #[serde(default)]
+    since: u64,
+    /// Only the lines of output matching this regular expression.
+    #[serde(default)]
+    grep: String,
+    /// `stdout` or `stderr`, both if unset.
+    #[serde(default)]
+    stream: Option<String>,
+}
+
+/// Replay the output of the run of a VM, then follow it until it is done if `follow` is set.
+/// The VM is designated by its id, or by its workload name for the last run of the workload.
+#[get("/logs/{vm}")]
+pub async fn logs(
+    endpoint: web::Data<VmmEndpoint>,
+    vm: web::Path<String>,
+    query: web::Query<LogsQuery>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let stream = match query.stream.as_deref() {
+        None => LogStream::AllStreams,
+        Some("stdout") => LogStream::Stdout,
+        Some("stderr") => LogStream::Stderr,
+        Some(stream) => {
+            return Either::Left(invalid_request(vec![format!(
+                "stream: must be stdout or stderr, not {}",
+                stream
+            )]))
+        }
+    };
+
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return Either::Left(orchestrator_unavailable(e)),
     };
 
-    println!("Request: {:?}", vmm_request);
+    let request = StreamLogsRequest {
+        vm: vm.into_inner(),
+        follow: query.follow,
+        tail_lines: query.tail_lines,
+        since: query.since,
+        grep: query.grep,
+        stream: stream as i32,
+    };
+    let response_stream = match client.stream_logs(request).await {
+        Ok(response_stream) => response_stream,
+        Err(status) => return Either::Left(status_response(&status)),
+    };
 
-    println!("Successfully connected to VMM service");
+    Either::Right(sse::Sse::from_infallible_stream(run_events(
+        response_stream,
+    )))
+}
 
-    let mut response_stream: Streaming<ExecuteResponse> =
-        client.run_vmm(vmm_request).await.unwrap();
-    println!("Response stream: {:?}", response_stream);
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    workload_name: Option<String>,
+    vm: Option<String>,
+}
+
+/// Stream the lifecycle and scheduling events of the VMs, optionally filtered by workload or VM.
+#[get("/events")]
+pub async fn events(
+    endpoint: web::Data<VmmEndpoint>,
+    query: web::Query<EventsQuery>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return Either::Left(orchestrator_unavailable(e)),
+    };
 
-    let stream = stream! {
-        while let Some(Ok(exec_response)) = response_stream.next().await {
-            let json: ExecuteJsonResponse = exec_response.into();
+    let query = query.into_inner();
+    let request = WatchEventsRequest {
+        workload_name: query.workload_name.unwrap_or_default(),
+        vm: query.vm.unwrap_or_default(),
+    };
+    let mut event_stream = match client.watch_events(request).await {
+        Ok(event_stream) => event_stream,
+        Err(status) => return Either::Left(status_response(&status)),
+    };
+
+    let stream: RunEventStream = Box::pin(stream! {
+        while let Some(Ok(event)) = event_stream.next().await {
+            let json: VmEventJson = event.into();
             yield sse::Event::Data(sse::Data::new_json(json).unwrap());
         }
+    });
+
+    Either::Right(sse::Sse::from_infallible_stream(stream))
+}
+
+#[derive(Debug, Serialize)]
+pub struct VmEventJson {
+    pub vm_id: String,
+    pub workload_name: String,
+    pub kind: &'static str,
+    pub timestamp: u64,
+    pub message: String,
+}
+
+impl From<VmEvent> for VmEventJson {
+    fn from(value: VmEvent) -> Self {
+        Self {
+            vm_id: value.vm_id,
+            kind: value.kind().as_str_name(),
+            workload_name: value.workload_name,
+            timestamp: value.timestamp,
+            message: value.message,
+        }
+    }
+}
+
+/// Describe the orchestrator and the runtime versions which can be pinned.
+#[get("/info")]
+pub async fn info(endpoint: web::Data<VmmEndpoint>) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.server_info().await {
+        Ok(info) => HttpResponse::Ok().json(CloudletServerInfo::from(info)),
+        Err(status) => status_response(&status),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SelectorQuery {
+    /// Label selector, e.g. `team=infra,env=staging`.
+    #[serde(default)]
+    selector: String,
+}
+
+/// Running VMs, with their resources and what they consumed so far, optionally only those
+/// whose labels match `selector`.
+#[get("/vms")]
+pub async fn vms(
+    endpoint: web::Data<VmmEndpoint>,
+    query: web::Query<SelectorQuery>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.list_vm_metrics(query.into_inner().selector).await {
+        Ok(response) => HttpResponse::Ok().json(
+            response
+                .vms
+                .into_iter()
+                .map(CloudletVmMetrics::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(status) => status_response(&status),
+    }
+}
+
+impl From<vmmorchestrator::VmMetrics> for CloudletVmMetrics {
+    fn from(vm: vmmorchestrator::VmMetrics) -> Self {
+        Self {
+            vm_id: vm.vm_id,
+            workload_name: vm.workload_name,
+            language: vm.language,
+            cpus: vm.cpus,
+            memory_mb: vm.memory_mb,
+            started_at: vm.started_at,
+            cpu_seconds: vm.cpu_seconds,
+            egress_bytes: vm.egress_bytes,
+            priority_class: vm.priority_class,
+            labels: vm.labels.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RunsQuery {
+    #[serde(default)]
+    selector: String,
+    #[serde(default)]
+    workload: String,
+    #[serde(default)]
+    limit: u32,
+}
+
+/// Recent runs of the orchestrator, the most recent first, optionally only those of `workload`
+/// whose labels match `selector`.
+#[get("/runs")]
+pub async fn runs(
+    endpoint: web::Data<VmmEndpoint>,
+    query: web::Query<RunsQuery>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
     };
 
-    sse::Sse::from_infallible_stream(stream)
+    let query = query.into_inner();
+    let request = vmmorchestrator::ListRunsRequest {
+        selector: query.selector,
+        workload_name: query.workload,
+        limit: query.limit,
+    };
+    match client.list_runs(request).await {
+        Ok(response) => HttpResponse::Ok().json(
+            response
+                .runs
+                .into_iter()
+                .map(CloudletRunRecord::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(status) => status_response(&status),
+    }
+}
+
+impl From<vmmorchestrator::RunRecord> for CloudletRunRecord {
+    fn from(run: vmmorchestrator::RunRecord) -> Self {
+        Self {
+            run_id: run.run_id,
+            workload_name: run.workload_name,
+            tenant: run.tenant,
+            labels: run.labels.into_iter().collect(),
+            started_at: run.started_at,
+            finished_at: run.finished_at,
+            outcome: run.outcome,
+            message: run.message,
+            emulated: run.emulated,
+        }
+    }
+}
+
+impl From<vmmorchestrator::ServerInfo> for CloudletServerInfo {
+    fn from(value: vmmorchestrator::ServerInfo) -> Self {
+        Self {
+            version: value.version,
+            runtimes: value
+                .runtimes
+                .into_iter()
+                .map(|runtime| RuntimeVersions {
+                    language: runtime.language().into(),
+                    versions: runtime.versions,
+                })
+                .collect(),
+            kernels: value
+                .kernels
+                .into_iter()
+                .map(|kernel| KernelInfo {
+                    name: kernel.name,
+                    version: kernel.version,
+                    features: kernel.features,
+                })
+                .collect(),
+            default_kernel: value.default_kernel,
+            hypervisor: value.hypervisor,
+            capabilities: value
+                .capabilities()
+                .map(shared_models::Capability::from)
+                .collect(),
+            problems: value.problems,
+            uplink: value.uplink,
+            sriov_vfs: value.sriov_vfs,
+            sriov_vfs_available: value.sriov_vfs_available,
+            node_labels: value.node_labels.into_iter().collect(),
+            cordoned: value.cordoned,
+            emulated: value.emulated,
+        }
+    }
+}
+
+impl From<vmmorchestrator::RunInputs> for CloudletRunInputs {
+    fn from(value: vmmorchestrator::RunInputs) -> Self {
+        let request = value.request.unwrap_or_default();
+        Self {
+            run_id: value.run_id,
+            workload_name: request.workload_name,
+            recorded_at: value.recorded_at,
+            code_digest: value.code_digest,
+            env_digest: value.env_digest,
+            image_digest: value.image_digest,
+            kernel: request.kernel,
+        }
+    }
+}
+
+impl From<vmmorchestrator::ArtifactPlan> for ArtifactPlan {
+    fn from(value: vmmorchestrator::ArtifactPlan) -> Self {
+        Self {
+            path: value.path,
+            cached: value.cached,
+        }
+    }
+}
+
+impl From<vmmorchestrator::RunPlan> for CloudletPlanResponse {
+    fn from(value: vmmorchestrator::RunPlan) -> Self {
+        Self {
+            image: value.image,
+            kernel: value.kernel.unwrap_or_default().into(),
+            initramfs: value.initramfs.unwrap_or_default().into(),
+            resources: Resources {
+                cpus: value.cpus.try_into().unwrap_or(u8::MAX),
+                memory_mb: value.memory_mb,
+                io: Default::default(),
+                tmp_quota_mb: None,
+            },
+            warm_pool_hit: value.warm_pool_hit,
+            build_cached: value.build_cached,
+            registry_pull: value.registry_pull,
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -55,7 +767,118 @@ pub struct ExecuteJsonResponse {
     pub stage: StageJson,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
+    /// Encoding of `stdout` and `stderr`: text, or base64 for output which isn't valid UTF-8.
+    pub encoding: EncodingJson,
     pub exit_code: Option<i32>,
+    /// Whether output was cut at this point, or, on the final event, at all.
+    pub truncated: bool,
+    /// Summary of the output, on the final event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<OutputSummaryJson>,
+    /// Why the orchestrator ended the run, on a final event it sent instead of the agent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<CloudletErrorResponse>,
+    /// Start or end of a stage of the pipeline, on an event without output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub marker: Option<StageMarkerJson>,
+    /// Id under which the orchestrator cached the build, on the end of the build stage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+    /// The run reused a cached build, its build stage skipped, on the final event.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub build_cached: bool,
+    /// Attempts the run took, on the final event of a run retried after infrastructure failures.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+    /// Signal which killed the workload, e.g. `SIGSEGV`, on the final event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub core_dumped: bool,
+    /// Files collected to debug the run, downloaded from `/runs/{run_id}/artifacts/{name}`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<CloudletRunArtifact>,
+    /// How the VM stopped, on the final event of a run whose VM doesn't outlive it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_exit: Option<CloudletVmExit>,
+    /// Output the orchestrator dropped before this event, the client reading too slowly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gap: Option<StreamGapJson>,
+}
+
+impl ExecuteJsonResponse {
+    pub(crate) fn failed(status: &Status) -> Self {
+        Self {
+            stage: StageJson::Failed,
+            stdout: None,
+            stderr: None,
+            encoding: EncodingJson::Utf8,
+            exit_code: None,
+            truncated: false,
+            output: None,
+            error: Some(CloudletErrorResponse::from_status(status)),
+            marker: None,
+            build_id: None,
+            build_cached: false,
+            attempts: None,
+            signal: None,
+            core_dumped: false,
+            artifacts: Vec::new(),
+            vm_exit: None,
+            gap: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct StageMarkerJson {
+    /// `fetch-deps`, `build` or `run`.
+    pub stage: &'static str,
+    /// `started`, `finished` or `failed`.
+    pub event: &'static str,
+}
+
+impl From<StageMarker> for StageMarkerJson {
+    fn from(value: StageMarker) -> Self {
+        Self {
+            stage: match value.stage() {
+                PipelineStage::FetchDeps => "fetch-deps",
+                PipelineStage::Build => "build",
+                PipelineStage::Run => "run",
+            },
+            event: match value.event() {
+                Event::Started => "started",
+                Event::Finished => "finished",
+                Event::Failed => "failed",
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputSummaryJson {
+    pub bytes: u64,
+    pub lines: u64,
+    pub dropped_bytes: u64,
+    pub dropped_lines: u64,
+}
+
+impl From<agent::OutputSummary> for OutputSummaryJson {
+    fn from(value: agent::OutputSummary) -> Self {
+        Self {
+            bytes: value.bytes,
+            lines: value.lines,
+            dropped_bytes: value.dropped_bytes,
+            dropped_lines: value.dropped_lines,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingJson {
+    Utf8,
+    Base64,
 }
 
 #[derive(Debug, Serialize)]
@@ -83,24 +906,89 @@ impl From<Stage> for StageJson {
 
 impl From<ExecuteResponse> for ExecuteJsonResponse {
     fn from(value: ExecuteResponse) -> Self {
+        let encoding = match value.encoding() {
+            Encoding::Utf8 => EncodingJson::Utf8,
+            Encoding::Binary => EncodingJson::Base64,
+        };
+        let encode = |output: Vec<u8>| match encoding {
+            EncodingJson::Utf8 => String::from_utf8(output)
+                .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()),
+            EncodingJson::Base64 => BASE64_STANDARD.encode(output),
+        };
+        let signal = value.signal_name();
+
         Self {
-            stage: Stage::from_i32(value.stage).unwrap().into(),
-            stdout: value.stdout,
-            stderr: value.stderr,
+            stage: value.stage().into(),
+            stdout: value.stdout.map(encode),
+            stderr: value.stderr.map(encode),
+            encoding,
             exit_code: value.exit_code,
+            truncated: value.truncated,
+            output: value.output.map(OutputSummaryJson::from),
+            error: None,
+            marker: value.marker.map(StageMarkerJson::from),
+            build_id: Some(value.build_id).filter(|id| !id.is_empty()),
+            build_cached: value.build_cached,
+            attempts: Some(value.attempts).filter(|attempts| *attempts > 1),
+            signal,
+            core_dumped: value.core_dumped,
+            artifacts: value.artifacts.into_iter().map(Into::into).collect(),
+            vm_exit: value.vm_exit.map(CloudletVmExit::from),
+            gap: value.gap.map(StreamGapJson::from),
         }
     }
 }
 
-#[post("/shutdown")]
-pub async fn shutdown(request: HttpRequest) -> impl Responder {
-    let req = request;
+#[derive(Debug, Serialize)]
+pub struct StreamGapJson {
+    pub dropped_messages: u64,
+    pub dropped_bytes: u64,
+    pub dropped_lines: u64,
+}
+
+impl From<agent::StreamGap> for StreamGapJson {
+    fn from(value: agent::StreamGap) -> Self {
+        Self {
+            dropped_messages: value.dropped_messages,
+            dropped_bytes: value.dropped_bytes,
+            dropped_lines: value.dropped_lines,
+        }
+    }
+}
+
+impl From<agent::VmExit> for CloudletVmExit {
+    fn from(value: agent::VmExit) -> Self {
+        Self {
+            reason: match value.reason() {
+                agent::vm_exit::Reason::Unknown => "unknown",
+                agent::vm_exit::Reason::GuestShutdown => "guest-shutdown",
+                agent::vm_exit::Reason::GuestCrash => "guest-crash",
+                agent::vm_exit::Reason::Killed => "killed",
+                agent::vm_exit::Reason::HostError => "host-error",
+            }
+            .to_string(),
+            message: value.description(),
+        }
+    }
+}
 
-    let mut client = VmmClient::new().await.unwrap();
+#[derive(Debug, Deserialize)]
+pub struct ShutdownRequest {
+    /// Id or workload name of the VM.
+    id: String,
+}
 
-    println!("Request: {:?}", req);
+/// Shut a VM down, the only running one if the request has no body.
+#[post("/shutdown")]
+pub async fn shutdown(
+    endpoint: web::Data<VmmEndpoint>,
+    body: Option<web::Json<ShutdownRequest>>,
+) -> impl Responder {
+    let mut client = VmmClient::new(&endpoint).await.unwrap();
 
-    let shutdown_request = ShutdownVmRequest {};
+    let shutdown_request = ShutdownVmRequest {
+        vm: body.map(|body| body.into_inner().id).unwrap_or_default(),
+    };
     let response_result = client.shutdown_vm(shutdown_request).await;
 
     match response_result {
@@ -127,3 +1015,55 @@ impl From<ShutdownVmResponse> for ShutdownJsonResponse {
         }
     }
 }
+
+#[derive(Debug, Serialize)]
+pub struct HealthJsonResponse {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl HealthJsonResponse {
+    fn ok() -> Self {
+        Self {
+            status: "ok",
+            reason: None,
+        }
+    }
+
+    fn unavailable(reason: String) -> Self {
+        Self {
+            status: "unavailable",
+            reason: Some(reason),
+        }
+    }
+}
+
+/// Liveness probe: the API process is up and able to answer requests.
+#[get("/healthz")]
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(HealthJsonResponse::ok())
+}
+
+/// Readiness probe: the orchestrator is reachable and reports itself as serving.
+#[get("/readyz")]
+pub async fn readyz(endpoint: web::Data<VmmEndpoint>) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => {
+            return HttpResponse::ServiceUnavailable().json(HealthJsonResponse::unavailable(
+                format!("orchestrator is unreachable: {}", e),
+            ))
+        }
+    };
+
+    match client.is_serving().await {
+        Ok(true) => HttpResponse::Ok().json(HealthJsonResponse::ok()),
+        Ok(false) => HttpResponse::ServiceUnavailable().json(HealthJsonResponse::unavailable(
+            "orchestrator is not serving".into(),
+        )),
+        Err(status) => HttpResponse::ServiceUnavailable().json(HealthJsonResponse::unavailable(
+            format!("orchestrator health check failed: {}", status.message()),
+        )),
+    }
+}
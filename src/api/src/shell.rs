@@ -0,0 +1,141 @@
+//! Shells opened in the running VMs over a WebSocket, for `cli vm shell`.
+//!
+//! The client starts the shell with a [`CloudletShellMessage::Start`] text message, then sends
+//! its keystrokes as binary messages and the resizes of its terminal as text ones. The output
+//! of the terminal comes back as binary messages, and the session ends with an
+//! [`CloudletShellMessage::Exit`] or an [`CloudletShellMessage::Error`]. The shell is hung up
+//! when the client leaves.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_ws::{Message, MessageStream, Session};
+use shared_models::cloudlet::agent::{
+    shell_input::Input, shell_output::Output, ShellInput, ShellStart, TerminalSize,
+};
+use shared_models::CloudletShellMessage;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+
+/// Open a shell in the VM, designated by its id or its workload name.
+#[get("/vms/{vm}/shell")]
+pub async fn shell(
+    endpoint: web::Data<VmmEndpoint>,
+    vm: web::Path<String>,
+    request: HttpRequest,
+    body: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let (response, mut session, messages) = actix_ws::handle(&request, body)?;
+    let endpoint = endpoint.get_ref().clone();
+    let vm = vm.into_inner();
+
+    actix_web::rt::spawn(async move {
+        let end = match relay(&endpoint, vm, &mut session, messages).await {
+            Ok(None) => None,
+            Ok(Some(exit_code)) => Some(CloudletShellMessage::Exit { exit_code }),
+            Err(message) => Some(CloudletShellMessage::Error { message }),
+        };
+        if let Some(end) = end {
+            let _ = session.text(serde_json::to_string(&end).unwrap()).await;
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+/// Relay the session until the shell exits, with its exit code, or the client leaves.
+async fn relay(
+    endpoint: &VmmEndpoint,
+    vm: String,
+    session: &mut Session,
+    mut messages: MessageStream,
+) -> Result<Option<i32>, String> {
+    let start = loop {
+        match messages.next().await {
+            Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                Ok(CloudletShellMessage::Start {
+                    command,
+                    rows,
+                    cols,
+                    term,
+                }) => {
+                    break ShellStart {
+                        command,
+                        size: Some(TerminalSize { rows, cols }),
+                        term,
+                        vm,
+                    }
+                }
+                _ => return Err("The first message must start the shell".to_string()),
+            },
+            Some(Ok(Message::Ping(bytes))) => {
+                let _ = session.pong(&bytes).await;
+            }
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(None),
+            Some(Ok(_)) => {}
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(16);
+    let _ = tx
+        .send(ShellInput {
+            input: Some(Input::Start(start)),
+        })
+        .await;
+    let mut client = VmmClient::new(endpoint).await.map_err(|e| e.to_string())?;
+    let mut outputs = client
+        .exec_shell(rx)
+        .await
+        .map_err(|status| status.message().to_string())?;
+
+    let mut pong_session = session.clone();
+    let inputs = actix_web::rt::spawn(async move {
+        while let Some(Ok(message)) = messages.next().await {
+            let input = match message {
+                Message::Binary(bytes) => Input::Stdin(bytes.to_vec()),
+                Message::Text(text) => match serde_json::from_str(&text) {
+                    Ok(CloudletShellMessage::Resize { rows, cols }) => {
+                        Input::Resize(TerminalSize { rows, cols })
+                    }
+                    _ => continue,
+                },
+                Message::Ping(bytes) => {
+                    if pong_session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            if tx.send(ShellInput { input: Some(input) }).await.is_err() {
+                break;
+            }
+        }
+        // Dropping `tx` ends the inputs, hanging the shell up.
+    });
+
+    let mut exit_code = None;
+    while let Some(output) = outputs.next().await {
+        match output
+            .map_err(|status| status.message().to_string())?
+            .output
+        {
+            Some(Output::Data(data)) => {
+                if session.binary(data).await.is_err() {
+                    break;
+                }
+            }
+            Some(Output::ExitCode(code)) => {
+                exit_code = Some(code);
+                break;
+            }
+            None => {}
+        }
+    }
+    inputs.abort();
+    match exit_code {
+        Some(code) => Ok(Some(code)),
+        None => Err("Lost the connection to the shell".to_string()),
+    }
+}
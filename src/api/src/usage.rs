@@ -0,0 +1,188 @@
+//! Export of the resources consumed by the tenants, as recorded by the orchestrator when their
+//! VMs stop: as JSON, or as CSV for spreadsheets and billing systems.
+
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::service::{invalid_request, orchestrator_unavailable, status_response};
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Deserialize;
+use shared_models::vmmorchestrator::{
+    ExportUsageRequest, ExportUsageResponse, TenantUsage, UsageRecord,
+};
+use shared_models::{CloudletTenantUsage, CloudletUsage, CloudletUsageRecord};
+use std::fmt::Write;
+
+const TENANTS_HEADER: &str = "tenant,vms,vcpu_seconds,memory_gb_seconds,egress_bytes,build_seconds";
+
+const RECORDS_HEADER: &str = "vm_id,tenant,workload_name,started_at,finished_at,cpus,memory_mb,vcpu_seconds,memory_gb_seconds,egress_bytes,build_seconds";
+
+impl From<TenantUsage> for CloudletTenantUsage {
+    fn from(usage: TenantUsage) -> Self {
+        Self {
+            tenant: usage.tenant,
+            vms: usage.vms,
+            vcpu_seconds: usage.vcpu_seconds,
+            memory_gb_seconds: usage.memory_gb_seconds,
+            egress_bytes: usage.egress_bytes,
+            build_seconds: usage.build_seconds,
+        }
+    }
+}
+
+impl From<UsageRecord> for CloudletUsageRecord {
+    fn from(record: UsageRecord) -> Self {
+        Self {
+            vm_id: record.vm_id,
+            tenant: record.tenant,
+            workload_name: record.workload_name,
+            started_at: record.started_at,
+            finished_at: record.finished_at,
+            cpus: record.cpus,
+            memory_mb: record.memory_mb,
+            vcpu_seconds: record.vcpu_seconds,
+            memory_gb_seconds: record.memory_gb_seconds,
+            egress_bytes: record.egress_bytes,
+            build_seconds: record.build_seconds,
+        }
+    }
+}
+
+/// The totals per tenant, or the records of the VMs if they were requested, as CSV.
+fn to_csv(usage: &ExportUsageResponse, records: bool) -> String {
+    let mut csv = String::new();
+    if records {
+        csv.push_str(RECORDS_HEADER);
+        csv.push('\n');
+        for r in &usage.records {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{:.3},{:.3},{},{:.3}",
+                r.vm_id,
+                r.tenant,
+                r.workload_name,
+                r.started_at,
+                r.finished_at,
+                r.cpus,
+                r.memory_mb,
+                r.vcpu_seconds,
+                r.memory_gb_seconds,
+                r.egress_bytes,
+                r.build_seconds
+            );
+        }
+    } else {
+        csv.push_str(TENANTS_HEADER);
+        csv.push('\n');
+        for t in &usage.tenants {
+            let _ = writeln!(
+                csv,
+                "{},{},{:.3},{:.3},{},{:.3}",
+                t.tenant,
+                t.vms,
+                t.vcpu_seconds,
+                t.memory_gb_seconds,
+                t.egress_bytes,
+                t.build_seconds
+            );
+        }
+    }
+    csv
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    #[serde(default)]
+    tenant: String,
+    /// Only the VMs which stopped in [since, until), in seconds since the Unix epoch.
+    #[serde(default)]
+    since: u64,
+    #[serde(default)]
+    until: u64,
+    /// `json` (the default) or `csv`.
+    #[serde(default)]
+    format: Option<String>,
+    /// Return the records of the VMs: besides the totals in JSON, instead of them in CSV.
+    #[serde(default)]
+    records: bool,
+}
+
+/// Resources consumed by the VMs which stopped, aggregated per tenant.
+#[get("/usage")]
+pub async fn export(
+    endpoint: web::Data<VmmEndpoint>,
+    query: web::Query<UsageQuery>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(format) => {
+            return invalid_request(vec![format!("format: must be json or csv, not {}", format)])
+        }
+    };
+    if query.until != 0 && query.until <= query.since {
+        return invalid_request(vec!["until: must be after since".into()]);
+    }
+
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    let request = ExportUsageRequest {
+        tenant: query.tenant,
+        since: query.since,
+        until: query.until,
+        records: query.records,
+    };
+    match client.export_usage(request).await {
+        Ok(usage) if csv => HttpResponse::Ok()
+            .content_type("text/csv")
+            .body(to_csv(&usage, query.records)),
+        Ok(usage) => HttpResponse::Ok().json(CloudletUsage {
+            tenants: usage.tenants.into_iter().map(Into::into).collect(),
+            records: usage.records.into_iter().map(Into::into).collect(),
+        }),
+        Err(status) => status_response(&status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_csv() {
+        let usage = ExportUsageResponse {
+            tenants: vec![TenantUsage {
+                tenant: "acme".into(),
+                vms: 2,
+                vcpu_seconds: 12.5,
+                memory_gb_seconds: 3.0,
+                egress_bytes: 1024,
+                build_seconds: 1.25,
+            }],
+            records: vec![UsageRecord {
+                vm_id: "hello-0123abcd".into(),
+                tenant: "acme".into(),
+                workload_name: "hello".into(),
+                started_at: 10,
+                finished_at: 20,
+                cpus: 1,
+                memory_mb: 512,
+                vcpu_seconds: 10.0,
+                memory_gb_seconds: 5.0,
+                egress_bytes: 0,
+                build_seconds: 0.0,
+            }],
+        };
+
+        assert_eq!(
+            to_csv(&usage, false),
+            format!("{}\nacme,2,12.500,3.000,1024,1.250\n", TENANTS_HEADER)
+        );
+        assert_eq!(
+            to_csv(&usage, true).lines().nth(1),
+            Some("hello-0123abcd,acme,hello,10,20,1,512,10.000,5.000,0,0.000")
+        );
+    }
+}
@@ -0,0 +1,522 @@
+//! Workloads registered on the orchestrator under a name and a version, then invoked with
+//! only their inputs.
+
+use crate::cache::{CacheKey, CallResult, ResponseCache, CACHE_HEADER};
+use crate::client::{VmmClient, VmmEndpoint};
+use crate::service::{
+    invalid_request, orchestrator_unavailable, run_events, status_response, to_vmm_request,
+    validate_request,
+};
+use actix_web::http::header;
+use actix_web::{get, post, web, Either, HttpRequest, HttpResponse, Responder};
+use actix_web_lab::sse;
+use serde::Deserialize;
+use shared_models::cloudlet::agent::{execute_response::Stage, ExecuteResponse};
+use shared_models::vmmorchestrator::{
+    CachePolicy, InvokeWorkloadRequest, PoolStatus, RegisterWorkloadRequest, RegisteredWorkload,
+    ScalingPolicy, UpgradePoolsRequest,
+};
+use shared_models::{
+    CloudletErrorResponse, CloudletInvokeRequest, CloudletPool, CloudletRegisterRequest,
+    CloudletUpgradePoolsRequest, CloudletWorkload, ErrorCode,
+};
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tonic::Streaming;
+use tracing::info;
+
+/// Largest body of a call, given to the function on its stdin.
+pub const MAX_INPUT_BYTES: usize = 1024 * 1024;
+
+/// Largest result of a call, read from the stdout of the function.
+const MAX_OUTPUT_BYTES: usize = 1024 * 1024;
+
+/// Lines of stderr in the error of a failed call.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Types a call can return its result as, the first one by default.
+const OUTPUT_TYPES: [&str; 3] = ["application/json", "text/plain", "application/octet-stream"];
+
+/// Header of the results, `warm` if the call reused a running VM and `cold` if it started one.
+pub const START_HEADER: &str = "x-cloudlet-start";
+
+/// Variables giving the type of the input and of the expected result to the function.
+const INPUT_TYPE_VARIABLE: &str = "CLOUDLET_INPUT_TYPE";
+const OUTPUT_TYPE_VARIABLE: &str = "CLOUDLET_OUTPUT_TYPE";
+
+impl From<RegisteredWorkload> for CloudletWorkload {
+    fn from(value: RegisteredWorkload) -> Self {
+        Self {
+            name: value.name,
+            version: value.version,
+            registered_at: value.registered_at,
+            code_digest: value.code_digest,
+            cache_ttl_secs: value.cache.map(|cache| cache.ttl_secs),
+        }
+    }
+}
+
+impl From<PoolStatus> for CloudletPool {
+    fn from(value: PoolStatus) -> Self {
+        Self {
+            name: value.name,
+            version: value.version,
+            min_instances: value.min_instances,
+            max_instances: value.max_instances,
+            instances: value.instances,
+            busy: value.busy,
+            in_flight: value.in_flight,
+            queued: value.queued,
+            cold_starts: value.cold_starts,
+            warm_starts: value.warm_starts,
+            scale_ups: value.scale_ups,
+            scale_downs: value.scale_downs,
+            last_invoked_at: value.last_invoked_at,
+            generation: value.generation,
+            outdated: value.outdated,
+            upgrades: value.upgrades,
+            upgrade_started_at: value.upgrade_started_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// Only list the versions of this workload.
+    #[serde(default)]
+    name: String,
+}
+
+#[get("/workloads")]
+pub async fn list(
+    endpoint: web::Data<VmmEndpoint>,
+    query: web::Query<ListQuery>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.list_workloads(query.into_inner().name).await {
+        Ok(response) => HttpResponse::Ok().json(
+            response
+                .workloads
+                .into_iter()
+                .map(CloudletWorkload::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(status) => status_response(&status),
+    }
+}
+
+/// Pools of VMs of the registered workloads, only the one of `name` if given.
+#[get("/pools")]
+pub async fn pools(
+    endpoint: web::Data<VmmEndpoint>,
+    query: web::Query<ListQuery>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    match client.list_pools(query.into_inner().name).await {
+        Ok(response) => HttpResponse::Ok().json(
+            response
+                .pools
+                .into_iter()
+                .map(CloudletPool::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(status) => status_response(&status),
+    }
+}
+
+/// Start the rolling upgrade of the pools matching the body to the current rootfs image.
+#[post("/pools/upgrade")]
+pub async fn upgrade_pools(
+    endpoint: web::Data<VmmEndpoint>,
+    body: web::Json<CloudletUpgradePoolsRequest>,
+) -> impl Responder {
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+
+    let body = body.into_inner();
+    let request = UpgradePoolsRequest {
+        name: body.name,
+        language: body.language,
+        runtime_version: body.runtime_version,
+    };
+    match client.upgrade_pools(request).await {
+        Ok(response) => HttpResponse::Ok().json(
+            response
+                .pools
+                .into_iter()
+                .map(CloudletPool::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(status) => status_response(&status),
+    }
+}
+
+#[post("/workloads")]
+pub async fn register(
+    endpoint: web::Data<VmmEndpoint>,
+    body: web::Json<CloudletRegisterRequest>,
+) -> impl Responder {
+    let body = body.into_inner();
+    let errors = validate_request(&body.request);
+    if !errors.is_empty() {
+        return invalid_request(errors);
+    }
+
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+    let scaling =
+        (body.min_instances.is_some() || body.max_instances.is_some()).then_some(ScalingPolicy {
+            min_instances: body.min_instances,
+            max_instances: body.max_instances,
+        });
+    let request = RegisterWorkloadRequest {
+        name: body.request.workload_name.clone(),
+        version: body.version,
+        request: Some(to_vmm_request(body.request)),
+        scaling,
+        cache: body
+            .cache_ttl_secs
+            .filter(|ttl_secs| *ttl_secs > 0)
+            .map(|ttl_secs| CachePolicy { ttl_secs }),
+    };
+    info!(workload = %request.name, version = %request.version, "Registering");
+
+    match client.register_workload(request).await {
+        Ok(workload) => HttpResponse::Created().json(CloudletWorkload::from(workload)),
+        Err(status) => status_response(&status),
+    }
+}
+
+/// Type of result accepted by `accept`, the value of an `Accept` header: the supported one it
+/// prefers, `None` if it accepts none of them.
+fn negotiate(accept: Option<&str>) -> Option<&'static str> {
+    let Some(accept) = accept.filter(|accept| !accept.trim().is_empty()) else {
+        return Some(OUTPUT_TYPES[0]);
+    };
+
+    let mut best: Option<(f32, &'static str)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media_range = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let quality = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let output_type = OUTPUT_TYPES.into_iter().find(|output_type| {
+            media_range == "*/*"
+                || media_range == *output_type
+                || media_range
+                    .strip_suffix('*')
+                    .is_some_and(|prefix| prefix.ends_with('/') && output_type.starts_with(prefix))
+        });
+        match (output_type, best) {
+            (None, _) => {}
+            (Some(_), _) if quality <= 0.0 => {}
+            (Some(_), Some((best, _))) if quality <= best => {}
+            (Some(output_type), _) => best = Some((quality, output_type)),
+        }
+    }
+    best.map(|(_, output_type)| output_type)
+}
+
+fn workload_failed(message: String, stderr: &[u8]) -> HttpResponse {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    let tail = lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+    HttpResponse::BadGateway()
+        .json(CloudletErrorResponse::new(ErrorCode::ApiWorkloadFailed, message).with_details(tail))
+}
+
+fn invalid_output(message: impl Into<String>) -> HttpResponse {
+    HttpResponse::BadGateway().json(CloudletErrorResponse::new(
+        ErrorCode::ApiInvalidOutput,
+        message,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct CallQuery {
+    /// The version registered last if unset.
+    version: Option<String>,
+}
+
+/// Run a registered workload as a function, the body of the request on its stdin, and
+/// return its result, read from its stdout.
+#[post("/workloads/{name}/call")]
+pub async fn call(
+    endpoint: web::Data<VmmEndpoint>,
+    cache: web::Data<ResponseCache>,
+    name: web::Path<String>,
+    query: web::Query<CallQuery>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> impl Responder {
+    let version = query.into_inner().version.unwrap_or_default();
+    call_workload(
+        &endpoint,
+        &cache,
+        name.into_inner(),
+        version,
+        &request,
+        body,
+    )
+    .await
+}
+
+/// Invoke `version` of the workload `name` with the body of `request`, and answer with its
+/// result in the type negotiated with the `Accept` header.
+///
+/// A JSON input must be valid, and the result is parsed as JSON unless another type is
+/// accepted. The function fails the call by exiting with an error, the end of its stderr
+/// is then returned in the details of the error. The result of a workload registered with a
+/// cache policy is returned from `cache` while it holds one for the same input.
+pub async fn call_workload(
+    endpoint: &VmmEndpoint,
+    cache: &ResponseCache,
+    name: String,
+    version: String,
+    request: &HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    let header_value = |name: header::HeaderName| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let Some(output_type) = negotiate(header_value(header::ACCEPT)) else {
+        return HttpResponse::NotAcceptable().json(CloudletErrorResponse::new(
+            ErrorCode::ApiInvalidRequest,
+            format!("The result can only be one of: {}", OUTPUT_TYPES.join(", ")),
+        ));
+    };
+    let input_type = header_value(header::CONTENT_TYPE).unwrap_or_default();
+    let is_json = input_type
+        .split(';')
+        .next()
+        .is_some_and(|media_type| media_type.trim().ends_with("json"));
+    if is_json {
+        if let Err(e) = serde_json::from_slice::<serde::de::IgnoredAny>(&body) {
+            return invalid_request(vec![format!("The body isn't valid JSON: {}", e)]);
+        }
+    }
+
+    let mut client = match VmmClient::new(endpoint).await {
+        Ok(client) => client,
+        Err(e) => return orchestrator_unavailable(e),
+    };
+    // Looked up under the version registered last rather than an empty one, which is invoked
+    // once resolved so that the result cached is the result of this version.
+    let cached = match client.list_workloads(name.clone()).await {
+        Ok(response) => response
+            .workloads
+            .into_iter()
+            .rev()
+            .find(|workload| version.is_empty() || workload.version == version)
+            .and_then(|workload| {
+                let ttl_secs = workload.cache?.ttl_secs;
+                let key = CacheKey::new(&name, &workload.version, input_type, output_type, &body);
+                (ttl_secs > 0).then(|| (key, Duration::from_secs(ttl_secs), workload.version))
+            }),
+        Err(status) => return status_response(&status),
+    };
+    let version = match cached {
+        Some((ref key, _, ref version)) => {
+            if let Some(result) = cache.get(key) {
+                info!(workload = %name, %version, "Returning the cached result");
+                let mut response = result.response();
+                response.headers_mut().insert(
+                    header::HeaderName::from_static(CACHE_HEADER),
+                    header::HeaderValue::from_static("hit"),
+                );
+                return response;
+            }
+            version.clone()
+        }
+        None => version,
+    };
+
+    let invoke = InvokeWorkloadRequest {
+        name,
+        version,
+        inputs: [
+            (INPUT_TYPE_VARIABLE.to_string(), input_type.to_string()),
+            (OUTPUT_TYPE_VARIABLE.to_string(), output_type.to_string()),
+        ]
+        .into(),
+        input: body.to_vec(),
+    };
+    let response = match client.invoke_workload(invoke).await {
+        Ok(response) => response,
+        Err(status) => return status_response(&status),
+    };
+    let start = response
+        .metadata()
+        .get(START_HEADER)
+        .and_then(|start| header::HeaderValue::from_bytes(start.as_bytes()).ok());
+
+    let cacheable = cached.is_some();
+    let mut response = match call_result(response.into_inner(), output_type).await {
+        Ok(result) => {
+            let response = result.response();
+            if let Some((key, ttl, _)) = cached {
+                cache.insert(key, result, ttl);
+            }
+            response
+        }
+        Err(response) => response,
+    };
+    if let Some(start) = start {
+        response
+            .headers_mut()
+            .insert(header::HeaderName::from_static(START_HEADER), start);
+    }
+    if cacheable && response.status().is_success() {
+        response.headers_mut().insert(
+            header::HeaderName::from_static(CACHE_HEADER),
+            header::HeaderValue::from_static("miss"),
+        );
+    }
+    response
+}
+
+/// Result of a call, read from the output of the run of the function, or the response failing
+/// the call.
+async fn call_result(
+    mut response_stream: Streaming<ExecuteResponse>,
+    output_type: &str,
+) -> Result<CallResult, HttpResponse> {
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let mut result = None;
+    let mut truncated = false;
+    while let Some(message) = response_stream.next().await {
+        let response = match message {
+            Ok(response) => response,
+            Err(status) => return Err(status_response(&status)),
+        };
+        truncated |= response.truncated;
+        // The output of the build isn't part of the result.
+        if response.stage() != Stage::Building {
+            stdout.extend(response.stdout.unwrap_or_default());
+            stderr.extend(response.stderr.unwrap_or_default());
+        }
+        if matches!(response.stage(), Stage::Done | Stage::Failed) {
+            result = Some((response.stage(), response.exit_code));
+        }
+    }
+
+    match result {
+        Some((Stage::Done, Some(0))) => {}
+        Some((_, Some(exit_code))) => {
+            return Err(workload_failed(
+                format!("The function exited with code {}", exit_code),
+                &stderr,
+            ))
+        }
+        _ => {
+            return Err(workload_failed(
+                "The function ended without a result".into(),
+                &stderr,
+            ))
+        }
+    }
+    if truncated || stdout.len() > MAX_OUTPUT_BYTES {
+        return Err(invalid_output(format!(
+            "The result is larger than {} bytes",
+            MAX_OUTPUT_BYTES
+        )));
+    }
+
+    let body = match output_type {
+        "application/json" => match serde_json::from_slice::<serde_json::Value>(&stdout) {
+            Ok(result) => result.to_string().into_bytes(),
+            Err(e) => {
+                return Err(invalid_output(format!(
+                    "The result isn't valid JSON: {}",
+                    e
+                )))
+            }
+        },
+        _ => stdout,
+    };
+    Ok(CallResult {
+        content_type: output_type.to_string(),
+        body: body.into(),
+    })
+}
+
+/// Run a registered workload with `inputs`, streaming its output like `/run`.
+#[post("/workloads/{name}/invoke")]
+pub async fn invoke(
+    endpoint: web::Data<VmmEndpoint>,
+    name: web::Path<String>,
+    body: web::Json<CloudletInvokeRequest>,
+) -> impl Responder {
+    let body = body.into_inner();
+    let errors: Vec<String> = cloudlet_spec::validate_env(&body.inputs)
+        .into_iter()
+        .map(|e| e.to_string())
+        .collect();
+    if !errors.is_empty() {
+        return Either::Left(invalid_request(errors));
+    }
+
+    let mut client = match VmmClient::new(&endpoint).await {
+        Ok(client) => client,
+        Err(e) => return Either::Left(orchestrator_unavailable(e)),
+    };
+    let request = InvokeWorkloadRequest {
+        name: name.into_inner(),
+        version: body.version.unwrap_or_default(),
+        inputs: body.inputs.into_iter().collect(),
+        input: Vec::new(),
+    };
+
+    match client.invoke_workload(request).await {
+        Ok(response) => Either::Right(sse::Sse::from_infallible_stream(run_events(
+            response.into_inner(),
+        ))),
+        Err(status) => Either::Left(status_response(&status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(None), Some("application/json"));
+        assert_eq!(negotiate(Some("*/*")), Some("application/json"));
+        assert_eq!(negotiate(Some("text/*")), Some("text/plain"));
+        assert_eq!(
+            negotiate(Some("application/json;q=0.5, application/octet-stream")),
+            Some("application/octet-stream")
+        );
+        assert_eq!(
+            negotiate(Some("text/html, text/plain;q=0.2")),
+            Some("text/plain")
+        );
+        assert_eq!(negotiate(Some("text/plain;q=0")), None);
+        assert_eq!(negotiate(Some("image/png")), None);
+    }
+}
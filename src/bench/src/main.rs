@@ -0,0 +1,277 @@
+//! `cloudlet-bench`: compare the cold starts of the guests booting from an initramfs and from a
+//! root disk image, over the compressions of the images and the languages.
+//!
+//! Each image is built once by `fs-gen` into the work directory, then booted by
+//! `vmm bench-boot`, which needs the capabilities of the VMM (`CAP_NET_ADMIN` for the TAP
+//! device). The report is written as JSON, to be compared against by a later run, and as
+//! markdown.
+
+use clap::{Parser, ValueEnum};
+use report::{BootReport, Case, Report};
+use shared_models::Language;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+mod report;
+
+/// How the rootfs of the guest is built and booted.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    /// CPIO archive compressed with xz, loaded as an initramfs
+    InitramfsXz,
+    /// Uncompressed CPIO archive, as built by tools/rootfs
+    Initramfs,
+    /// Writable ext4 disk image
+    Ext4,
+    /// Read-only erofs disk image compressed with lz4hc
+    ErofsLz4hc,
+    /// Uncompressed read-only erofs disk image
+    Erofs,
+}
+
+impl Variant {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Variant::InitramfsXz => "initramfs-xz",
+            Variant::Initramfs => "initramfs",
+            Variant::Ext4 => "ext4",
+            Variant::ErofsLz4hc => "erofs-lz4hc",
+            Variant::Erofs => "erofs",
+        }
+    }
+
+    /// Format of the image, as given to fs-gen.
+    fn format(&self) -> &'static str {
+        match self {
+            Variant::InitramfsXz | Variant::Initramfs => "cpio",
+            Variant::Ext4 => "ext4",
+            Variant::ErofsLz4hc | Variant::Erofs => "erofs",
+        }
+    }
+
+    fn compression(&self) -> &'static str {
+        match self {
+            Variant::InitramfsXz => "xz",
+            Variant::ErofsLz4hc => "lz4hc",
+            Variant::Initramfs | Variant::Ext4 | Variant::Erofs => "none",
+        }
+    }
+
+    fn is_disk(&self) -> bool {
+        self.format() != "cpio"
+    }
+}
+
+/// Compare the cold starts of the rootfs strategies.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Languages of the images.
+    #[arg(
+        short,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "rust,python,node"
+    )]
+    languages: Vec<Language>,
+
+    /// Rootfs strategies and compressions.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "initramfs-xz,initramfs,ext4,erofs-lz4hc,erofs"
+    )]
+    variants: Vec<Variant>,
+
+    /// Measured boots of each case.
+    #[arg(short = 'n', long, default_value = "10")]
+    iterations: usize,
+
+    /// Boots run before the measured ones of each case.
+    #[arg(long, default_value = "1")]
+    warmup: usize,
+
+    /// Agent binary put in the images.
+    #[arg(long, default_value = "target/x86_64-unknown-linux-musl/release/agent")]
+    agent: PathBuf,
+
+    #[arg(long, default_value = "target/release/fs-gen")]
+    fs_gen: PathBuf,
+
+    #[arg(long, default_value = "target/release/vmm")]
+    vmm: PathBuf,
+
+    /// Kernel booted, by default the one `vmm bench-boot` defaults to.
+    #[arg(short, long)]
+    kernel: Option<PathBuf>,
+
+    /// Where the images are built and kept for the next runs.
+    #[arg(long, default_value = "target/bench")]
+    work_dir: PathBuf,
+
+    /// Build the images again even if they are in the work directory.
+    #[arg(long)]
+    rebuild: bool,
+
+    /// Report to compare the results against.
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+
+    /// Maximum p50 slowdown allowed against the baseline, in percent.
+    #[arg(long, default_value = "10")]
+    max_regression: f64,
+}
+
+/// The image of `language` for `variant`, built by fs-gen unless already in the work directory.
+/// Returns its path and the time spent building it, if it was.
+fn image(
+    args: &Args,
+    language: &Language,
+    variant: Variant,
+) -> Result<(PathBuf, Option<f64>), Box<dyn Error>> {
+    let path = args.work_dir.join("images").join(format!(
+        "{}-{}.img",
+        language.as_str(),
+        variant.as_str()
+    ));
+    if path.exists() && !args.rebuild {
+        return Ok((path, None));
+    }
+    fs::create_dir_all(path.parent().unwrap())?;
+
+    let started = Instant::now();
+    let mut command = Command::new(&args.fs_gen);
+    command
+        .arg(format!("{}:alpine", language.as_str()))
+        .arg(&args.agent)
+        .arg("-o")
+        .arg(&path)
+        .args(["--format", variant.format()]);
+    if variant.compression() == "none" && variant != Variant::Ext4 {
+        command.arg("--no-compression");
+    }
+    let status = command.status()?;
+    if !status.success() {
+        let _ = fs::remove_file(&path);
+        return Err(format!("fs-gen failed: {}", status).into());
+    }
+    Ok((path, Some(started.elapsed().as_secs_f64())))
+}
+
+/// Boot `image` with `vmm bench-boot`.
+fn boot(
+    args: &Args,
+    language: &Language,
+    variant: Variant,
+    image: &Path,
+) -> Result<BootReport, Box<dyn Error>> {
+    let runs = args.work_dir.join("runs");
+    fs::create_dir_all(&runs)?;
+    let name = format!("{}-{}", language.as_str(), variant.as_str());
+    let report_path = runs.join(format!("{}.json", name));
+
+    // The guest changes an ext4 image, each case boots a fresh copy.
+    let image = if variant == Variant::Ext4 {
+        let copy = runs.join(format!("{}.img", name));
+        fs::copy(image, &copy)?;
+        copy
+    } else {
+        image.to_path_buf()
+    };
+
+    let mut command = Command::new(&args.vmm);
+    command
+        .arg("bench-boot")
+        .args(["--iterations", &args.iterations.to_string()])
+        .args(["--warmup", &args.warmup.to_string()])
+        .args(["--language", language.as_str()])
+        .arg(if variant.is_disk() {
+            "--rootfs"
+        } else {
+            "--initramfs"
+        })
+        .arg(&image)
+        .arg("--save-baseline")
+        .arg(&report_path);
+    if let Some(kernel) = &args.kernel {
+        command.arg("--kernel").arg(kernel);
+    }
+    let status = command.status()?;
+    if variant == Variant::Ext4 {
+        let _ = fs::remove_file(&image);
+    }
+    if !status.success() {
+        return Err(format!("vmm bench-boot failed: {}", status).into());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(&report_path)?)?)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+
+    let mut cases = Vec::new();
+    for language in &args.languages {
+        for &variant in &args.variants {
+            println!("==> {} {}", language.as_str(), variant.as_str());
+            let mut case = Case {
+                language: language.as_str().to_string(),
+                variant: variant.as_str().to_string(),
+                rootfs: variant.format().to_string(),
+                compression: variant.compression().to_string(),
+                image_bytes: None,
+                build_seconds: None,
+                boot: None,
+                error: None,
+            };
+            let result = image(&args, language, variant).and_then(|(path, build_seconds)| {
+                case.image_bytes = Some(fs::metadata(&path)?.len());
+                case.build_seconds = build_seconds;
+                boot(&args, language, variant, &path)
+            });
+            match result {
+                Ok(boot) => case.boot = Some(boot),
+                Err(e) => {
+                    eprintln!("{} {}: {}", language.as_str(), variant.as_str(), e);
+                    case.error = Some(e.to_string());
+                }
+            }
+            cases.push(case);
+        }
+    }
+
+    let report = Report {
+        iterations: args.iterations,
+        warmup: args.warmup,
+        cases,
+    };
+    let markdown = report.markdown();
+    print!("{}", markdown);
+    fs::write(args.work_dir.join("report.md"), &markdown)?;
+    fs::write(
+        args.work_dir.join("report.json"),
+        serde_json::to_string_pretty(&report)?,
+    )?;
+    println!(
+        "Report written to {}",
+        args.work_dir.join("report.{json,md}").display()
+    );
+
+    if let Some(path) = &args.baseline {
+        let baseline: Report = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let regressions = report.regressions(&baseline, args.max_regression);
+        if !regressions.is_empty() {
+            for regression in regressions.iter() {
+                eprintln!("Regression: {}", regression);
+            }
+            return Err(format!("{} case(s) regressed", regressions.len()).into());
+        }
+        println!("No regression against {}", path.display());
+    }
+
+    Ok(())
+}
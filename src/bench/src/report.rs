@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// Percentiles of a metric, as reported by `vmm bench-boot`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub min_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Report of `vmm bench-boot`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootReport {
+    pub iterations: usize,
+    pub kernel_boot: Option<Percentiles>,
+    pub agent_ready: Percentiles,
+    pub first_exec: Percentiles,
+}
+
+/// A language booted with a variant of its rootfs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Case {
+    pub language: String,
+    pub variant: String,
+    /// `cpio`, `ext4` or `erofs`.
+    pub rootfs: String,
+    pub compression: String,
+    pub image_bytes: Option<u64>,
+    /// Absent when the image was already built by a previous run.
+    pub build_seconds: Option<f64>,
+    pub boot: Option<BootReport>,
+    /// Why the case couldn't be measured.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub iterations: usize,
+    pub warmup: usize,
+    pub cases: Vec<Case>,
+}
+
+impl Report {
+    pub fn markdown(&self) -> String {
+        let mut markdown = format!(
+            "# Cold starts\n\n{} boots per case, after {} warmup boot(s).\n\n",
+            self.iterations, self.warmup
+        );
+        markdown.push_str(
+            "| Language | Rootfs | Compression | Image (MB) | Kernel p50 (ms) | Agent ready p50 (ms) | Agent ready p90 (ms) | First exec p50 (ms) | First exec p90 (ms) |\n",
+        );
+        markdown.push_str("|---|---|---|---:|---:|---:|---:|---:|---:|\n");
+        for case in &self.cases {
+            let image = case.image_bytes.map_or("-".to_string(), |bytes| {
+                format!("{:.1}", bytes as f64 / (1 << 20) as f64)
+            });
+            let _ = write!(
+                markdown,
+                "| {} | {} | {} | {} |",
+                case.language, case.rootfs, case.compression, image
+            );
+            match &case.boot {
+                Some(boot) => {
+                    let kernel = boot
+                        .kernel_boot
+                        .as_ref()
+                        .map_or("-".to_string(), |kernel| format!("{:.1}", kernel.p50_ms));
+                    let _ = writeln!(
+                        markdown,
+                        " {} | {:.1} | {:.1} | {:.1} | {:.1} |",
+                        kernel,
+                        boot.agent_ready.p50_ms,
+                        boot.agent_ready.p90_ms,
+                        boot.first_exec.p50_ms,
+                        boot.first_exec.p90_ms
+                    );
+                }
+                None => {
+                    let error = case.error.as_deref().unwrap_or("not measured");
+                    let _ = writeln!(markdown, " failed: {} | | | | |", error.replace('|', "/"));
+                }
+            }
+        }
+        markdown
+    }
+
+    /// Cases whose agent ready or first exec p50 is more than `max_regression` percent slower
+    /// than in `baseline`, or which were measured in it and aren't anymore.
+    pub fn regressions(&self, baseline: &Report, max_regression: f64) -> Vec<String> {
+        let factor = 1.0 + max_regression / 100.0;
+        let mut regressions = Vec::new();
+        for before in &baseline.cases {
+            let Some(before_boot) = &before.boot else {
+                continue;
+            };
+            let Some(case) = self
+                .cases
+                .iter()
+                .find(|case| case.language == before.language && case.variant == before.variant)
+            else {
+                continue;
+            };
+            let name = format!("{} {}", case.language, case.variant);
+            let Some(boot) = &case.boot else {
+                regressions.push(format!("{}: not measured anymore", name));
+                continue;
+            };
+            for (metric, now, then) in [
+                ("agent ready", &boot.agent_ready, &before_boot.agent_ready),
+                ("first exec", &boot.first_exec, &before_boot.first_exec),
+            ] {
+                if now.p50_ms > then.p50_ms * factor {
+                    regressions.push(format!(
+                        "{}: {} p50 went from {:.1}ms to {:.1}ms",
+                        name, metric, then.p50_ms, now.p50_ms
+                    ));
+                }
+            }
+        }
+        regressions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(variant: &str, agent_ready_ms: Option<f64>) -> Case {
+        let percentiles = |ms: f64| Percentiles {
+            min_ms: ms,
+            p50_ms: ms,
+            p90_ms: ms,
+            p99_ms: ms,
+            max_ms: ms,
+        };
+        Case {
+            language: "rust".to_string(),
+            variant: variant.to_string(),
+            rootfs: "cpio".to_string(),
+            compression: "none".to_string(),
+            image_bytes: Some(1 << 20),
+            build_seconds: None,
+            boot: agent_ready_ms.map(|ms| BootReport {
+                iterations: 1,
+                kernel_boot: None,
+                agent_ready: percentiles(ms),
+                first_exec: percentiles(100.0),
+            }),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_regressions() {
+        let report = |cases| Report {
+            iterations: 1,
+            warmup: 0,
+            cases,
+        };
+        let baseline = report(vec![
+            case("initramfs", Some(100.0)),
+            case("ext4", Some(100.0)),
+            case("erofs", Some(100.0)),
+            case("erofs-lz4hc", None),
+        ]);
+        let current = report(vec![
+            case("initramfs", Some(105.0)),
+            case("ext4", Some(120.0)),
+            case("erofs", None),
+            case("erofs-lz4hc", Some(500.0)),
+        ]);
+
+        let regressions = current.regressions(&baseline, 10.0);
+        assert_eq!(regressions.len(), 2);
+        assert!(regressions[0].starts_with("rust ext4: agent ready"));
+        assert_eq!(regressions[1], "rust erofs: not measured anymore");
+    }
+}
@@ -0,0 +1,58 @@
+//! Generates the completion scripts and the man pages of the commands into `OUT_DIR`, from the
+//! command tree of `src/args.rs`, for `cli completions` and `cli man`.
+
+use clap::CommandFactory;
+use clap_complete::{generate_to, Shell};
+use std::{env, fs, io, path::PathBuf};
+
+#[allow(dead_code)]
+mod args {
+    include!("src/args.rs");
+}
+
+/// Stand-ins of the values only completed from the API, see `src/complete.rs`.
+mod complete {
+    use clap_complete::engine::CompletionCandidate;
+
+    pub fn run_ids() -> Vec<CompletionCandidate> {
+        Vec::new()
+    }
+
+    pub fn workload_names() -> Vec<CompletionCandidate> {
+        Vec::new()
+    }
+
+    pub fn vms() -> Vec<CompletionCandidate> {
+        Vec::new()
+    }
+}
+
+fn main() -> io::Result<()> {
+    println!("cargo:rerun-if-changed=src/args.rs");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    let mut command = args::CliArgs::command();
+
+    let completions = out_dir.join("completions");
+    fs::create_dir_all(&completions)?;
+    for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+        generate_to(shell, &mut command, "cli", &completions)?;
+    }
+
+    let man = out_dir.join("man");
+    fs::create_dir_all(&man)?;
+    clap_mangen::generate_to(command, &man)?;
+
+    // The pages are embedded in the binary, in the order of their names.
+    let mut pages = fs::read_dir(&man)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    pages.sort();
+    let mut source = String::from("/// Man pages of the commands, by file name.\n");
+    source.push_str("pub const MAN_PAGES: &[(&str, &[u8])] = &[\n");
+    for page in pages {
+        let name = page.file_name().unwrap_or_default().to_string_lossy();
+        source.push_str(&format!("    ({:?}, include_bytes!({:?})),\n", name, page));
+    }
+    source.push_str("];\n");
+    fs::write(out_dir.join("man_pages.rs"), source)
+}
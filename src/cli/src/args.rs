@@ -1,9 +1,30 @@
-use clap::Parser;
+use crate::complete;
+use clap::{Parser, ValueEnum};
+use clap_complete::engine::ArgValueCandidates;
+use shared_models::logging::LogArgs;
+use shared_models::Language;
 use std::path::PathBuf;
+use std::time::Duration;
+
+/// Endpoint of the API, unless `--api` says otherwise.
+pub const DEFAULT_API: &str = "http://127.0.0.1:3000";
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct CliArgs {
+    /// Endpoint of the API: `http://<HOST>:<PORT>`, or `unix://<PATH>` for an API listening on
+    /// a Unix socket.
+    #[arg(long, global = true, env = "CLOUDLET_API", default_value = DEFAULT_API)]
+    pub api: String,
+
+    /// Confirm the destructive commands, e.g. `vm stop --all`, without asking. Required
+    /// outside of a terminal.
+    #[arg(short, long, global = true)]
+    pub yes: bool,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -11,8 +32,458 @@ pub struct CliArgs {
 #[derive(Parser, Debug)]
 pub enum Commands {
     Run {
+        /// Path to a `cloudlet.yaml` workload spec, or to a legacy TOML config file.
+        #[arg(short, long, required_unless_present = "code", conflicts_with = "code")]
+        config_path: Option<PathBuf>,
+        /// Code to run instead of a workload spec, e.g. `-e 'print(1 + 1)'`, or `-` to read
+        /// it from stdin. Requires `--language`.
+        #[arg(short = 'e', long = "eval", requires = "language")]
+        code: Option<String>,
+        /// Language of the code of `--eval`.
+        #[arg(long, requires = "code")]
+        language: Option<Language>,
+        /// Name of the workload of `--eval`, defaults to `inline-<language>`.
+        #[arg(long, requires = "code")]
+        name: Option<String>,
+        /// Validate the workload and print how it would be run, without starting a VM.
+        #[arg(long)]
+        dry_run: bool,
+        /// Keep the VM up when the run fails, to inspect its files with `fs`, until it is shut
+        /// down.
+        #[arg(long)]
+        keep_on_failure: bool,
+        /// Print the request sent to the API, secret variables excepted.
+        #[arg(short, long)]
+        verbose: bool,
+        /// Label of the run, as `KEY=VALUE`, over the labels of the spec. Can be repeated.
+        #[arg(short, long = "label", value_parser = shared_models::labels::parse_label)]
+        labels: Vec<(String, String)>,
+    },
+    /// Fetch the dependencies of a workload and build it, without running it. The VMM caches
+    /// the build, so that the later runs of the same code start from it.
+    Build {
+        /// Path to a `cloudlet.yaml` workload spec, or to a legacy TOML config file.
+        #[arg(short, long)]
+        config_path: PathBuf,
+        /// Keep the VM up when the build fails, to inspect its files with `fs`, until it is
+        /// shut down.
+        #[arg(long)]
+        keep_on_failure: bool,
+        /// Print the request sent to the API, secret variables excepted.
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    Shutdown {
+        /// Id or workload name of the VM, the only running one if not given.
+        #[arg(add = ArgValueCandidates::new(complete::vms))]
+        vm: Option<String>,
+    },
+    /// Print the version of the server and the runtime versions which can be pinned.
+    Info {},
+    /// Check the API, the orchestrator, the host and the local config, printing how to fix what
+    /// fails.
+    Doctor {
+        /// Config to check, `cloudlet.yaml` in the current directory if it exists.
+        #[arg(short, long)]
+        config_path: Option<PathBuf>,
+    },
+    /// Print the lifecycle and scheduling events of the VMs as they happen.
+    Events {
+        /// Only print the events of this workload.
+        #[arg(short, long, add = ArgValueCandidates::new(complete::workload_names))]
+        workload: Option<String>,
+        /// Only print the events of this VM, by id or workload name.
+        #[arg(long, add = ArgValueCandidates::new(complete::vms))]
+        vm: Option<String>,
+    },
+    /// Print the output of a run, e.g. to reattach to it from another terminal.
+    Logs {
+        /// Id of the VM running the workload, logged by the VMM when the run starts, or name
+        /// of the workload for its last run.
+        #[arg(add = ArgValueCandidates::new(complete::run_ids))]
+        vm: String,
+        /// Keep printing the new output until the run is done.
+        #[arg(short, long)]
+        follow: bool,
+        /// Only print the last messages of the output, instead of all of them.
+        #[arg(short = 'n', long, default_value_t = 0)]
+        tail: u32,
+        /// Write the output bytes as produced, instead of replacing invalid UTF-8.
+        #[arg(long)]
+        raw: bool,
+        /// Only print the output produced in the last period, e.g. `30s`, `5m`, `2h` or `1d`.
+        #[arg(long, value_parser = parse_period)]
+        since: Option<Duration>,
+        /// Only print the lines of output matching this regular expression.
+        #[arg(long)]
+        grep: Option<String>,
+        /// Only print one of the output streams.
+        #[arg(long, value_enum)]
+        stream: Option<OutputStream>,
+    },
+    /// List the recent runs of the VMM, the most recent first.
+    Runs {
+        /// Only the runs whose labels match this selector, e.g. `team=infra,env!=staging`.
+        #[arg(short, long)]
+        selector: Option<String>,
+        /// Only the runs of this workload.
+        #[arg(short, long, add = ArgValueCandidates::new(complete::workload_names))]
+        workload: Option<String>,
+        /// Runs listed at most.
+        #[arg(short = 'n', long)]
+        limit: Option<u32>,
+    },
+    /// Run a past workload again with the exact inputs recorded for it by the VMM.
+    Rerun {
+        /// Id of the VM which ran the workload, as printed by `events`.
+        #[arg(add = ArgValueCandidates::new(complete::run_ids))]
+        run_id: String,
+    },
+    /// List the files collected from a run, e.g. the core dump and the backtrace of a crashed
+    /// workload, or download one of them.
+    Artifacts {
+        /// Id of the VM which ran the workload, as printed by `events`.
+        #[arg(add = ArgValueCandidates::new(complete::run_ids))]
+        run_id: String,
+        /// Artifact to download, `core` or `backtrace`.
+        name: Option<String>,
+        /// Where to write it, `-` for stdout; `<run-id>-<name>` by default.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Collect what the API and the VMM know of a run into a `.tar.gz`, to attach to a bug
+    /// report: its request, events, logs, VM metrics, the settings of the VMM and the
+    /// versions, secrets redacted.
+    DebugBundle {
+        /// Id of the VM which ran the workload, as printed by `events` or `runs`.
+        #[arg(add = ArgValueCandidates::new(complete::run_ids))]
+        run_id: String,
+        /// Where to write it, `<run-id>-debug.tar.gz` by default.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Inspect the files of the workload in a running VM, e.g. one kept with
+    /// `--keep-on-failure` after its build failed.
+    Fs {
+        #[command(subcommand)]
+        command: FsCommands,
+    },
+    /// Manage the running VMs.
+    Vm {
+        #[command(subcommand)]
+        command: VmCommands,
+    },
+    /// Keep a workload on the VMM under a version, to run it later with `invoke`.
+    Register {
+        /// Path to a `cloudlet.yaml` workload spec, or to a legacy TOML config file.
         #[arg(short, long)]
         config_path: PathBuf,
+        /// Version of the workload: letters, digits, `.`, `-` and `_`.
+        #[arg(long)]
+        version: String,
+        /// VMs kept warm while the workload is invoked, the default of the VMM if not given.
+        #[arg(long)]
+        min_instances: Option<u32>,
+        /// VMs running the workload at most, the default of the VMM if not given.
+        #[arg(long)]
+        max_instances: Option<u32>,
+        /// For a pure workload, whose result only depends on its input: cache the results of
+        /// its calls in the API for this period, e.g. `30s`, `5m` or `1h`.
+        #[arg(long, value_parser = parse_period)]
+        cache_ttl: Option<Duration>,
+    },
+    /// List the registered workloads and their versions.
+    Workloads {
+        /// Only list the versions of this workload.
+        #[arg(add = ArgValueCandidates::new(complete::workload_names))]
+        name: Option<String>,
+    },
+    /// Show the pools of VMs of the registered workloads and their scaling.
+    Pools {
+        /// Only show the pool of this workload.
+        #[arg(add = ArgValueCandidates::new(complete::workload_names))]
+        name: Option<String>,
+    },
+    /// Replace the VMs of the pools by VMs of the current rootfs image and agent, one at a time
+    /// and without interrupting their runs, e.g. once a new image was built or pushed.
+    UpgradePools {
+        /// Only upgrade the pool of this workload.
+        #[arg(add = ArgValueCandidates::new(complete::workload_names))]
+        name: Option<String>,
+        /// Only upgrade the pools of the workloads of this language.
+        #[arg(long)]
+        language: Option<String>,
+        /// Only upgrade the pools of the workloads pinning this runtime version of `--language`.
+        #[arg(long, requires = "language")]
+        runtime_version: Option<String>,
+    },
+    /// Show the running VMs and the pools of the registered workloads, refreshed until `q` is
+    /// pressed.
+    Top {
+        /// Seconds between two refreshes.
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    /// Show the resources consumed by the tenants, per tenant or per VM.
+    Usage {
+        /// Only show the usage of this tenant.
+        #[arg(long)]
+        tenant: Option<String>,
+        /// Only the VMs which stopped from then, in seconds since the Unix epoch.
+        #[arg(long)]
+        since: Option<u64>,
+        /// Only the VMs which stopped before then, in seconds since the Unix epoch.
+        #[arg(long)]
+        until: Option<u64>,
+        /// Show the usage of each VM instead of the totals per tenant.
+        #[arg(long)]
+        records: bool,
+        /// Print the usage as CSV.
+        #[arg(long)]
+        csv: bool,
+    },
+    /// Run a registered workload, with only its inputs.
+    Invoke {
+        /// Name of the registered workload.
+        #[arg(add = ArgValueCandidates::new(complete::workload_names))]
+        name: String,
+        /// Version to run, the one registered last if not given.
+        #[arg(long)]
+        version: Option<String>,
+        /// Environment variable given to the workload, as `NAME=VALUE`. Can be repeated.
+        #[arg(short, long = "input", value_parser = parse_input)]
+        inputs: Vec<(String, String)>,
+        /// Call the workload as a function: give it this JSON on its stdin, and print the JSON
+        /// result it writes on its stdout instead of its output.
+        #[arg(short, long, conflicts_with = "inputs")]
+        data: Option<String>,
+    },
+    /// Run several workloads together.
+    Batch {
+        #[command(subcommand)]
+        command: BatchCommands,
+    },
+    /// Run workloads on cron schedules, kept by the API.
+    Schedule {
+        #[command(subcommand)]
+        command: ScheduleCommands,
+    },
+    /// Follow the runs queued by the API while the orchestrator was unreachable.
+    Jobs {
+        #[command(subcommand)]
+        command: JobsCommands,
+    },
+    /// Take the node of the VMM out of service for maintenance, and back. The VMM must be
+    /// started with `--enable-admin`.
+    Node {
+        #[command(subcommand)]
+        command: NodeCommands,
+    },
+    /// Create a sample workload (spec, source code and env file) to start from.
+    Init {
+        /// Language of the sample workload.
+        #[arg(short, long)]
+        language: Language,
+        /// Directory to create the workload in.
+        #[arg(short, long, default_value = ".")]
+        directory: PathBuf,
+        /// Name of the workload, defaults to `hello-<language>`.
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+    /// Print the completion script of the commands for a shell, e.g. `cli completions bash >
+    /// ~/.local/share/bash-completion/completions/cli`. To also complete the run ids, the
+    /// workload names and the VMs from the API, source `COMPLETE=<shell> cli` instead, e.g.
+    /// `source <(COMPLETE=bash cli)`.
+    Completions { shell: CompletionShell },
+    /// Write the man pages of the commands into a directory, e.g. `/usr/local/share/man/man1`.
+    Man {
+        #[arg(default_value = ".")]
+        directory: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+fn parse_period(period: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "expected a number followed by s, m, h or d, got `{}`",
+            period
+        )
+    };
+    let unit_len = period.chars().last().map_or(0, char::len_utf8);
+    let (value, unit) = period.split_at(period.len() - unit_len);
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 60 * 60,
+        "d" => value * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+fn parse_input(input: &str) -> Result<(String, String), String> {
+    input
+        .split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got `{}`", input))
+}
+
+#[derive(Parser, Debug)]
+pub enum ScheduleCommands {
+    /// Run a workload on a cron schedule.
+    Create {
+        #[command(flatten)]
+        schedule: ScheduleArgs,
+    },
+    /// Replace the cron expression and the workload of a schedule, keeping its history.
+    Update {
+        #[command(flatten)]
+        schedule: ScheduleArgs,
+    },
+    /// List the schedules and the outcome of their last run.
+    List {},
+    /// Print a schedule and the results of its last runs.
+    Show { name: String },
+    /// Delete a schedule, its running run goes on.
+    Delete { name: String },
+}
+
+#[derive(Parser, Debug)]
+pub enum JobsCommands {
+    /// List the jobs, the oldest first, and the number of them still queued.
+    List {},
+    /// Print a job and the outcome of its run.
+    Show { id: u64 },
+    /// Start the queued jobs now, rather than at the next attempt of the API.
+    Drain {},
+}
+
+#[derive(Parser, Debug)]
+pub enum NodeCommands {
+    /// Stop scheduling new runs onto the node, the running VMs go on.
+    Cordon {
+        /// Why the node is cordoned, shown to the runs it refuses.
+        #[arg(short, long, default_value = "")]
+        reason: String,
+    },
+    /// Schedule new runs onto the node again.
+    Uncordon {},
+    /// Cordon the node and wait for its runs to end and its idle VMs to stop.
+    Drain {
+        #[arg(short, long, default_value = "")]
+        reason: String,
+        /// Seconds to wait for the VMs to stop, 0 to wait for as long as they run.
+        #[arg(short, long, default_value_t = 600)]
+        timeout: u32,
+        /// Kill the VMs still running once the timeout is past, rather than failing.
+        #[arg(short, long)]
+        kill: bool,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ScheduleArgs {
+    /// Name of the schedule.
+    pub name: String,
+    /// When to run the workload, `minute hour day-of-month month day-of-week` in UTC, e.g.
+    /// `*/15 * * * *`, or `@hourly`, `@daily`...
+    #[arg(long)]
+    pub cron: String,
+    /// Path to a `cloudlet.yaml` workload spec, or to a legacy TOML config file.
+    #[arg(short, long)]
+    pub config_path: PathBuf,
+    /// Keep the schedule without running the workload.
+    #[arg(long)]
+    pub disabled: bool,
+}
+
+#[derive(Parser, Debug)]
+pub enum VmCommands {
+    /// List the running VMs.
+    List {
+        /// Only the VMs whose labels match this selector, e.g. `team=infra,env=staging`.
+        #[arg(short, long)]
+        selector: Option<String>,
+    },
+    /// Stop a running VM, or all of them.
+    Stop {
+        /// Id or workload name of the VM.
+        #[arg(required_unless_present = "all", add = ArgValueCandidates::new(complete::vms))]
+        vm: Option<String>,
+        /// Stop every running VM, after confirmation.
+        #[arg(long, conflicts_with = "vm")]
+        all: bool,
+    },
+    /// Open a shell in a running VM, e.g. to debug its rootfs. The VMM must be started with
+    /// `--enable-exec-shell`.
+    Shell {
+        /// Id or workload name of the VM.
+        #[arg(add = ArgValueCandidates::new(complete::vms))]
+        vm: String,
+        /// Command to run instead of `/bin/sh`, after `--`.
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+}
+
+#[derive(Parser, Debug)]
+pub enum FsCommands {
+    /// List a directory of the workload directory of the guest.
+    Ls {
+        /// Id or workload name of the VM.
+        #[arg(add = ArgValueCandidates::new(complete::vms))]
+        vm: String,
+        /// Path relative to the workload directory, the directory itself if not given.
+        path: Option<String>,
+    },
+    /// Print a file of the workload directory of the guest.
+    Cat {
+        /// Id or workload name of the VM.
+        #[arg(add = ArgValueCandidates::new(complete::vms))]
+        vm: String,
+        /// Path relative to the workload directory.
+        path: String,
+        /// Bytes read at most, the cap of the agent (1 MiB) if not given.
+        #[arg(long)]
+        max_bytes: Option<u64>,
+    },
+}
+
+#[derive(Parser, Debug)]
+pub enum BatchCommands {
+    /// Run every job of a manifest concurrently and report their results.
+    Run {
+        /// Path to the `jobs.yaml` manifest.
+        manifest: PathBuf,
+        /// Maximum number of jobs running at the same time, overrides the manifest.
+        #[arg(short, long)]
+        parallelism: Option<usize>,
+        /// Write the summary report as JSON to this file.
+        #[arg(short, long)]
+        report: Option<PathBuf>,
     },
-    Shutdown {},
 }
@@ -0,0 +1,227 @@
+use crate::services::{CloudletClient, RunEvent};
+use cloudlet_spec::WorkloadSpec;
+use serde::{Deserialize, Serialize};
+use shared_models::CloudletDtoRequest;
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+/// Number of jobs running at the same time when the manifest doesn't say.
+const DEFAULT_PARALLELISM: usize = 4;
+
+/// List of workloads to run together (`jobs.yaml`).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BatchManifest {
+    /// Maximum number of jobs running at the same time.
+    #[serde(default)]
+    pub parallelism: Option<usize>,
+    pub jobs: Vec<BatchJob>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BatchJob {
+    /// Name used to prefix the job output, defaults to the workload name.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Path to the `cloudlet.yaml` spec of the job, relative to the manifest.
+    pub spec: PathBuf,
+}
+
+impl BatchManifest {
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read manifest `{}`: {}", path.display(), e))?;
+        let mut manifest: BatchManifest = serde_yaml::from_str(&content)?;
+
+        if let Some(base_dir) = path.parent() {
+            for job in manifest.jobs.iter_mut() {
+                job.spec = base_dir.join(&job.spec);
+            }
+        }
+
+        Ok(manifest)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobReport {
+    pub name: String,
+    pub passed: bool,
+    pub duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether the agent dropped part of the output of the job.
+    pub output_truncated: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchReport {
+    pub passed: usize,
+    pub failed: usize,
+    pub duration_ms: u128,
+    pub jobs: Vec<JobReport>,
+}
+
+/// Load every spec of the manifest, so that an invalid job is reported before anything runs.
+pub fn load_jobs(manifest: &BatchManifest) -> Result<Vec<(String, CloudletDtoRequest)>, String> {
+    manifest
+        .jobs
+        .iter()
+        .map(|job| {
+            let spec = WorkloadSpec::from_file(&job.spec)
+                .map_err(|e| format!("{}: {}", job.spec.display(), e))?;
+            let name = job
+                .name
+                .clone()
+                .unwrap_or_else(|| spec.workload_name.clone());
+            Ok((name, CloudletClient::new_cloudlet_config_from_spec(spec)))
+        })
+        .collect()
+}
+
+/// Run `jobs` with at most `parallelism` of them at the same time, printing their output as it arrives.
+pub async fn run(
+    jobs: Vec<(String, CloudletDtoRequest)>,
+    parallelism: Option<usize>,
+) -> BatchReport {
+    let start = Instant::now();
+    let semaphore = Arc::new(Semaphore::new(
+        parallelism.unwrap_or(DEFAULT_PARALLELISM).max(1),
+    ));
+    let mut set = JoinSet::new();
+
+    for (index, (name, request)) in jobs.into_iter().enumerate() {
+        let semaphore = semaphore.clone();
+        set.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.unwrap();
+            (index, run_job(name, request).await)
+        });
+    }
+
+    let mut reports = Vec::new();
+    while let Some(result) = set.join_next().await {
+        match result {
+            Ok(report) => reports.push(report),
+            Err(e) => eprintln!("A batch job panicked: {}", e),
+        }
+    }
+    reports.sort_by_key(|(index, _)| *index);
+
+    let jobs: Vec<JobReport> = reports.into_iter().map(|(_, report)| report).collect();
+    let passed = jobs.iter().filter(|job| job.passed).count();
+
+    BatchReport {
+        passed,
+        failed: jobs.len() - passed,
+        duration_ms: start.elapsed().as_millis(),
+        jobs,
+    }
+}
+
+async fn run_job(name: String, request: CloudletDtoRequest) -> JobReport {
+    let start = Instant::now();
+    let mut exit_code = None;
+    let mut done = false;
+    let mut output_truncated = false;
+    let mut failure = None;
+
+    let result = async {
+        let mut response = CloudletClient::start_run(&request).await?;
+        if !response.status().is_success() {
+            return Err(CloudletClient::api_error(response).await);
+        }
+
+        CloudletClient::for_each_event(&mut response, |event: RunEvent| {
+            for (output, is_stderr) in [(&event.stdout_text(), false), (&event.stderr_text(), true)]
+            {
+                for line in output.iter().flat_map(|output| output.lines()) {
+                    if is_stderr {
+                        eprintln!("[{}] {}", name, line);
+                    } else {
+                        println!("[{}] {}", name, line);
+                    }
+                }
+            }
+
+            if let Some(output) = event.output.filter(|_| event.truncated) {
+                eprintln!("[{}] {}", name, output.truncation_note());
+                output_truncated = true;
+            }
+
+            match event.stage.as_str() {
+                "Done" => {
+                    done = true;
+                    exit_code = event.exit_code.or(exit_code);
+                }
+                "Failed" => exit_code = event.exit_code.or(exit_code),
+                _ => {}
+            }
+            if let Some(e) = event.error {
+                failure = Some(e.to_string());
+            }
+        })
+        .await?;
+
+        Ok::<(), Box<dyn Error + Send + Sync>>(())
+    }
+    .await;
+
+    let error = match result {
+        Ok(()) if failure.is_some() => failure,
+        Ok(()) if done => None,
+        Ok(()) => Some("the workload did not complete".to_string()),
+        Err(e) => Some(e.to_string()),
+    };
+
+    JobReport {
+        passed: error.is_none() && exit_code.unwrap_or(0) == 0,
+        name,
+        duration_ms: start.elapsed().as_millis(),
+        exit_code,
+        error,
+        output_truncated,
+    }
+}
+
+impl BatchReport {
+    pub fn print(&self) {
+        println!();
+        println!(
+            "{:<24} {:<6} {:>10}  {}",
+            "JOB", "RESULT", "DURATION", "DETAILS"
+        );
+        for job in self.jobs.iter() {
+            let details = match (&job.error, job.exit_code) {
+                (Some(error), _) => error.clone(),
+                (None, Some(code)) => format!("exit code {}", code),
+                (None, None) => String::new(),
+            };
+            let details = if !job.output_truncated {
+                details
+            } else if details.is_empty() {
+                "output truncated".to_string()
+            } else {
+                format!("{}, output truncated", details)
+            };
+            println!(
+                "{:<24} {:<6} {:>8}ms  {}",
+                job.name,
+                if job.passed { "pass" } else { "fail" },
+                job.duration_ms,
+                details
+            );
+        }
+        println!(
+            "{} passed, {} failed in {}ms",
+            self.passed, self.failed, self.duration_ms
+        );
+    }
+}
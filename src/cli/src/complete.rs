@@ -0,0 +1,91 @@
+//! Shell completions of the CLI.
+//!
+//! The scripts of `cli completions` and the pages of `cli man` are generated from the command
+//! tree by the build script. The dynamic completions, registered with `source <(COMPLETE=bash
+//! cli)`, also complete the ids of the runs, the names of the registered workloads and the
+//! running VMs by querying the API of `CLOUDLET_API`, nothing being offered if it doesn't answer
+//! within [`QUERY_TIMEOUT`].
+
+use crate::args::{CompletionShell, DEFAULT_API};
+use crate::services::CloudletClient;
+use clap_complete::engine::CompletionCandidate;
+use std::{
+    collections::BTreeSet, env, error::Error, fs, future::Future, io, path::Path, time::Duration,
+};
+
+/// Time the API has to answer, not to hang the shell.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+include!(concat!(env!("OUT_DIR"), "/man_pages.rs"));
+
+/// Completion script of the commands for `shell`.
+pub fn script(shell: CompletionShell) -> &'static str {
+    match shell {
+        CompletionShell::Bash => include_str!(concat!(env!("OUT_DIR"), "/completions/cli.bash")),
+        CompletionShell::Zsh => include_str!(concat!(env!("OUT_DIR"), "/completions/_cli")),
+        CompletionShell::Fish => include_str!(concat!(env!("OUT_DIR"), "/completions/cli.fish")),
+    }
+}
+
+/// Write the man pages of the commands into `directory`, returning their number.
+pub fn write_man_pages(directory: &Path) -> io::Result<usize> {
+    fs::create_dir_all(directory)?;
+    for (name, page) in MAN_PAGES {
+        fs::write(directory.join(name), page)?;
+    }
+    Ok(MAN_PAGES.len())
+}
+
+/// Result of `request`, empty if the API can't answer it. Called while completing, before any
+/// runtime is started and without the command line parsed.
+fn query<T, F>(request: impl FnOnce() -> F) -> Vec<T>
+where
+    F: Future<Output = Result<Vec<T>, Box<dyn Error>>>,
+{
+    let api = env::var("CLOUDLET_API").unwrap_or_else(|_| DEFAULT_API.to_string());
+    if CloudletClient::use_api(&api).is_err() {
+        return Vec::new();
+    }
+    let Ok(runtime) = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    else {
+        return Vec::new();
+    };
+    runtime.block_on(async {
+        match tokio::time::timeout(QUERY_TIMEOUT, request()).await {
+            Ok(Ok(values)) => values,
+            _ => Vec::new(),
+        }
+    })
+}
+
+/// Ids of the recent runs, the most recent first.
+pub fn run_ids() -> Vec<CompletionCandidate> {
+    query(|| CloudletClient::runs(None, None, Some(100)))
+        .into_iter()
+        .map(|run| {
+            let help = format!("{} {}", run.workload_name, run.outcome);
+            CompletionCandidate::new(run.run_id).help(Some(help.into()))
+        })
+        .collect()
+}
+
+/// Names of the registered workloads.
+pub fn workload_names() -> Vec<CompletionCandidate> {
+    query(|| CloudletClient::workloads(None))
+        .into_iter()
+        .map(|workload| workload.name)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+/// Ids of the running VMs.
+pub fn vms() -> Vec<CompletionCandidate> {
+    query(|| CloudletClient::vms(None))
+        .into_iter()
+        .map(|vm| CompletionCandidate::new(vm.vm_id).help(Some(vm.workload_name.into())))
+        .collect()
+}
@@ -0,0 +1,160 @@
+//! Debug bundle of a run, to attach to a bug report: a `.tar.gz` of what the API and the
+//! orchestrator know of it, under `<run-id>/`:
+//!
+//! - `run.json`: its record in the history of the orchestrator, with its labels and outcome
+//! - `request.txt`: its request, the values of the secret variables redacted
+//! - `events.txt`: the events of its VM
+//! - `logs.jsonl`: the output events of the run, as the API streams them
+//! - `vm.json`: the metrics of its VM, or its usage record once stopped
+//! - `config.txt`: the settings of the orchestrator, secrets redacted
+//! - `console.log`: the console of its guest, when it is logged on this host
+//! - `versions.txt`: the versions of the CLI, the server, the kernels and the runtimes
+//! - `missing.txt`: what couldn't be collected, and why
+
+use crate::services::CloudletClient;
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    error::Error,
+    fs::{self, File},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Files of a bundle, added in order.
+#[derive(Default)]
+struct Bundle {
+    files: Vec<(&'static str, Vec<u8>)>,
+    missing: Vec<String>,
+}
+
+impl Bundle {
+    fn add(&mut self, name: &'static str, content: impl Into<Vec<u8>>) {
+        self.files.push((name, content.into()));
+    }
+
+    /// Add the file made of `part`, or note why it is missing.
+    fn add_part<T>(
+        &mut self,
+        name: &'static str,
+        part: Result<T, Box<dyn Error>>,
+        content: impl FnOnce(T) -> Vec<u8>,
+    ) {
+        match part {
+            Ok(part) => self.add(name, content(part)),
+            Err(e) => self.missing.push(format!("{}: {}", name, e)),
+        }
+    }
+
+    fn write(mut self, run_id: &str, output: &Path) -> Result<(), Box<dyn Error>> {
+        if !self.missing.is_empty() {
+            let missing = self.missing.join("\n") + "\n";
+            self.add("missing.txt", missing);
+        }
+
+        let mtime = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut archive = tar::Builder::new(GzEncoder::new(
+            File::create(output)?,
+            Compression::default(),
+        ));
+        for (name, content) in &self.files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            archive.append_data(
+                &mut header,
+                format!("{}/{}", run_id, name),
+                content.as_slice(),
+            )?;
+        }
+        archive.into_inner()?.finish()?;
+        Ok(())
+    }
+}
+
+/// Collect the bundle of the run `run_id` into `output`. The parts which can't be collected
+/// are left out, and returned.
+pub async fn create(run_id: &str, output: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    // Without it, the orchestrator doesn't know the run.
+    let info = CloudletClient::run_debug_info(run_id).await?;
+
+    let mut bundle = Bundle::default();
+    match &info.run {
+        Some(run) => bundle.add("run.json", serde_json::to_vec_pretty(run)?),
+        None => bundle
+            .missing
+            .push("run.json: the history of the orchestrator forgot the run".to_string()),
+    }
+    if info.request.is_empty() {
+        bundle
+            .missing
+            .push("request.txt: the orchestrator doesn't record the runs".to_string());
+    } else {
+        bundle.add("request.txt", info.request);
+    }
+    let events: String = info
+        .events
+        .iter()
+        .map(|event| {
+            format!(
+                "{} vm={} workload={} {} {}\n",
+                event.timestamp, event.vm_id, event.workload_name, event.kind, event.message
+            )
+        })
+        .collect();
+    bundle.add("events.txt", events);
+    bundle.add_part(
+        "logs.jsonl",
+        CloudletClient::raw_logs(run_id).await,
+        |events| {
+            events
+                .iter()
+                .map(|event| format!("{}\n", event))
+                .collect::<String>()
+                .into()
+        },
+    );
+
+    let vm = match CloudletClient::vms(None).await {
+        Ok(vms) => match vms.into_iter().find(|vm| vm.vm_id == run_id) {
+            Some(vm) => serde_json::to_vec_pretty(&vm).map_err(Into::into),
+            None => CloudletClient::usage(None, None, None, true)
+                .await
+                .and_then(|usage| {
+                    let record = usage
+                        .records
+                        .into_iter()
+                        .find(|record| record.vm_id == run_id)
+                        .ok_or("the VM isn't running, and its usage wasn't recorded")?;
+                    Ok(serde_json::to_vec_pretty(&record)?)
+                }),
+        },
+        Err(e) => Err(e),
+    };
+    bundle.add_part("vm.json", vm, |vm| vm);
+    bundle.add("config.txt", info.config);
+    if !info.console_log.is_empty() {
+        // Only readable when the orchestrator runs on this host.
+        let console: Result<Vec<u8>, Box<dyn Error>> = fs::read(&info.console_log).map_err(|e| {
+            format!(
+                "{} on the host of the orchestrator: {}",
+                info.console_log, e
+            )
+            .into()
+        });
+        bundle.add_part("console.log", console, |content| content);
+    }
+
+    let versions = CloudletClient::server_info().await.and_then(|server| {
+        Ok(format!(
+            "cli {}\n\n{}\n",
+            env!("CARGO_PKG_VERSION"),
+            serde_json::to_string_pretty(&server)?
+        ))
+    });
+    bundle.add_part("versions.txt", versions, String::into_bytes);
+
+    let missing = bundle.missing.clone();
+    bundle.write(run_id, output)?;
+    Ok(missing)
+}
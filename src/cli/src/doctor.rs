@@ -0,0 +1,335 @@
+//! Checks of what the CLI needs to run workloads: the API and the orchestrator behind it, the
+//! capabilities of the server and the local config, each failure printed with how to fix it.
+
+use crate::services::CloudletClient;
+use cloudlet_spec::WorkloadSpec;
+use serde::Deserialize;
+use shared_models::{BuildConfig, CloudletServerInfo, Language};
+use std::{fs, path::Path};
+
+/// Config tried when none is given.
+const DEFAULT_CONFIG: &str = "cloudlet.yaml";
+
+/// What the server needs to know about the local config to run it.
+struct Workload {
+    language: Language,
+    runtime_version: Option<String>,
+    kernel: Option<String>,
+}
+
+/// The fields of a legacy TOML config that the server checks.
+#[derive(Deserialize)]
+struct LegacyConfig {
+    language: Option<Language>,
+    build: BuildConfig,
+}
+
+/// Results of the checks, printed as they are made.
+#[derive(Default)]
+struct Report {
+    warnings: usize,
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&mut self, message: &str) {
+        println!("[ok]   {}", message);
+    }
+
+    fn warn(&mut self, message: &str, fix: &str) {
+        self.warnings += 1;
+        println!("[warn] {}", message);
+        println!("       fix: {}", fix);
+    }
+
+    fn fail(&mut self, message: &str, fix: &str) {
+        self.failures += 1;
+        println!("[fail] {}", message);
+        println!("       fix: {}", fix);
+    }
+}
+
+/// Run every check, returning whether none of them failed.
+pub async fn run(config_path: Option<&Path>) -> bool {
+    let mut report = Report::default();
+
+    let info = check_server(&mut report).await;
+    check_config(&mut report, config_path, info.as_ref());
+    report.ok("auth: the API doesn't authenticate its clients, no credentials are needed");
+
+    println!();
+    println!(
+        "{} warning(s), {} failure(s)",
+        report.warnings, report.failures
+    );
+    report.failures == 0
+}
+
+/// Check the API, the orchestrator and the host, returning the server info if it could be read.
+async fn check_server(report: &mut Report) -> Option<CloudletServerInfo> {
+    match CloudletClient::probe("/healthz").await {
+        Ok(None) => report.ok(&format!(
+            "API: reachable at {}",
+            CloudletClient::api_endpoint()
+        )),
+        Ok(Some(reason)) => {
+            report.fail(
+                &format!("API: unhealthy: {}", reason),
+                "check the logs of the API and restart it",
+            );
+            return None;
+        }
+        Err(e) => {
+            report.fail(
+                &format!(
+                    "API: unreachable at {}: {}",
+                    CloudletClient::api_endpoint(),
+                    e
+                ),
+                "start it with `cargo run --bin api`, or together with the VMM with `cloudlet-server --all-in-one`",
+            );
+            return None;
+        }
+    }
+
+    match CloudletClient::probe("/readyz").await {
+        Ok(None) => report.ok("orchestrator: serving"),
+        Ok(Some(reason)) => report.fail(
+            &format!("orchestrator: {}", reason),
+            "start the VMM (`vmm grpc`, with `cap_net_admin`) and check that the API points at its address",
+        ),
+        Err(e) => report.fail(
+            &format!("orchestrator: could not check it: {}", e),
+            "check the logs of the API",
+        ),
+    }
+
+    let info = match CloudletClient::server_info().await {
+        Ok(info) => info,
+        Err(e) => {
+            report.fail(
+                &format!("server info: could not get it: {}", e),
+                "the capabilities of the server are read from the orchestrator, make it reachable first",
+            );
+            return None;
+        }
+    };
+
+    let version = env!("CARGO_PKG_VERSION");
+    if info.version == version {
+        report.ok(&format!("version: {}, as the CLI", info.version));
+    } else {
+        report.warn(
+            &format!("version: server {}, CLI {}", info.version, version),
+            "build the CLI and the server from the same revision",
+        );
+    }
+
+    let hypervisor = match info.hypervisor.as_str() {
+        "" => "unknown",
+        hypervisor => hypervisor,
+    };
+    if info.problems.is_empty() {
+        report.ok(&format!(
+            "host: can run VMs, with the {} hypervisor",
+            hypervisor
+        ));
+    }
+    for problem in &info.problems {
+        report.fail(&format!("host: {}", problem), problem_fix(problem));
+    }
+
+    if info.runtimes.is_empty() {
+        report.warn(
+            "runtimes: the server doesn't list any",
+            "build the rootfs images of the languages, as described in the README",
+        );
+    }
+    for runtime in &info.runtimes {
+        report.ok(&format!(
+            "runtime {}: {}",
+            runtime.language.as_str(),
+            runtime.versions.join(", ")
+        ));
+    }
+    if !info.kernels.is_empty() {
+        let kernels: Vec<&str> = info.kernels.iter().map(|k| k.name.as_str()).collect();
+        report.ok(&format!(
+            "kernels: {} (default {})",
+            kernels.join(", "),
+            info.default_kernel
+        ));
+    }
+    let capabilities: Vec<&str> = info
+        .capabilities
+        .iter()
+        .map(|capability| capability.as_str())
+        .collect();
+    report.ok(&format!(
+        "capabilities: {}",
+        if capabilities.is_empty() {
+            "none".to_string()
+        } else {
+            capabilities.join(", ")
+        }
+    ));
+    if !info.uplink.is_empty() || info.sriov_vfs > 0 {
+        report.ok(&format!(
+            "network: uplink {}, {} of {} SR-IOV virtual function(s) free",
+            if info.uplink.is_empty() {
+                "of the default route"
+            } else {
+                info.uplink.as_str()
+            },
+            info.sriov_vfs_available,
+            info.sriov_vfs
+        ));
+    }
+    if !info.node_labels.is_empty() {
+        let labels: Vec<String> = info
+            .node_labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        report.ok(&format!("node labels: {}", labels.join(",")));
+    }
+    if !info.cordoned.is_empty() {
+        report.warn(
+            &format!(
+                "the VMM is cordoned for {}, it takes no new run",
+                info.cordoned
+            ),
+            "uncordon it with `cli node uncordon` once its maintenance is over",
+        );
+    }
+    if info.emulated {
+        report.warn(
+            "the VMM emulates the guests without KVM, the runs are much slower",
+            "give the VMM access to /dev/kvm, e.g. on a host with nested virtualization, and restart it",
+        );
+    }
+
+    Some(info)
+}
+
+/// How to fix a failed health check of the host.
+fn problem_fix(problem: &str) -> &'static str {
+    if problem.contains("kvm") {
+        "load the module with `sudo modprobe kvm_intel` (or `kvm_amd`), enable virtualization in the firmware, and add the user of the VMM to the `kvm` group, or start it with --emulation-fallback to emulate the guests, slowly"
+    } else if problem.contains("artifact") {
+        "make the artifact cache directory of the VMM writable by its user, or point it elsewhere"
+    } else {
+        "check the logs of the VMM"
+    }
+}
+
+/// Check that the local config loads and that the server can run it.
+fn check_config(report: &mut Report, path: Option<&Path>, info: Option<&CloudletServerInfo>) {
+    let path = match path {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG).exists() => Path::new(DEFAULT_CONFIG),
+        None => {
+            report.ok(&format!(
+                "config: no --config-path given and no {} here, skipped",
+                DEFAULT_CONFIG
+            ));
+            return;
+        }
+    };
+
+    let workload = match load_workload(path) {
+        Ok(workload) => workload,
+        Err(e) => {
+            report.fail(
+                &format!("config {}: {}", path.display(), e),
+                "fix the fields reported above, `cli init` writes a valid spec to start from",
+            );
+            return;
+        }
+    };
+    report.ok(&format!("config {}: valid", path.display()));
+
+    let Some(info) = info else {
+        return;
+    };
+    let language = workload.language.as_str();
+    match info
+        .runtimes
+        .iter()
+        .find(|runtime| runtime.language.as_str() == language)
+    {
+        None => report.fail(
+            &format!("config: the server has no {} runtime", language),
+            "build the rootfs image of the language on the server",
+        ),
+        Some(runtime) => match &workload.runtime_version {
+            Some(version) if !runtime.versions.contains(version) => report.fail(
+                &format!(
+                    "config: {} {} is not supported by the server",
+                    language, version
+                ),
+                &format!(
+                    "set `build.runtime-version` to one of {}, or remove it",
+                    runtime.versions.join(", ")
+                ),
+            ),
+            _ => report.ok(&format!("config: {} is supported by the server", language)),
+        },
+    }
+
+    if let Some(kernel) = &workload.kernel {
+        if info.kernels.iter().any(|k| &k.name == kernel) {
+            report.ok(&format!("config: kernel {} is available", kernel));
+        } else {
+            report.fail(
+                &format!("config: the server has no kernel {}", kernel),
+                "set `kernel` to one of the kernels listed above, or remove it",
+            );
+        }
+    }
+}
+
+fn load_workload(path: &Path) -> Result<Workload, String> {
+    let is_spec = path
+        .extension()
+        .is_some_and(|ext| ext == "yaml" || ext == "yml");
+
+    if is_spec {
+        let spec = WorkloadSpec::from_file(path).map_err(|e| e.to_string())?;
+        if !spec.code.path.is_file() {
+            return Err(format!(
+                "the code {} can't be read",
+                spec.code.path.display()
+            ));
+        }
+        Ok(Workload {
+            language: spec
+                .language
+                .expect("WorkloadSpec::from_file detects the missing language"),
+            runtime_version: spec.build.runtime_version,
+            kernel: spec.kernel,
+        })
+    } else {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let config: LegacyConfig = toml::from_str(&content).map_err(|e| e.to_string())?;
+        if !config.build.source_code_path.is_file() {
+            return Err(format!(
+                "the code {} can't be read",
+                config.build.source_code_path.display()
+            ));
+        }
+        let language = match config.language {
+            Some(language) => language,
+            None => {
+                cloudlet_spec::detect_language(&config.build.source_code_path)
+                    .map_err(|e| e.to_string())?
+                    .language
+            }
+        };
+        Ok(Workload {
+            language,
+            runtime_version: config.build.runtime_version,
+            kernel: None,
+        })
+    }
+}
@@ -1,28 +1,93 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use clap_complete::CompleteEnv;
 
-use args::{CliArgs, Commands};
+use args::{
+    BatchCommands, CliArgs, Commands, FsCommands, JobsCommands, NodeCommands, ScheduleArgs,
+    ScheduleCommands, VmCommands,
+};
+use batch::BatchManifest;
+use cloudlet_spec::WorkloadSpec;
 
-use services::CloudletClient;
-use std::{fs, io, process::exit};
+use services::{CloudletClient, LogFilter};
+use shared_models::{
+    CloudletDtoRequest, CloudletInvokeRequest, CloudletNodeRequest, CloudletRegisterRequest,
+    CloudletScheduleRequest, CloudletUpgradePoolsRequest, Language,
+};
+use std::{error::Error, fs, io, path::Path, process::exit, time::Duration};
+use tracing::level_filters::LevelFilter;
 
 mod args;
+mod batch;
+mod complete;
+mod debug_bundle;
+mod doctor;
+mod prompt;
+mod scaffold;
 mod services;
+mod shell;
+mod top;
 mod utils;
 
+fn main() -> io::Result<()> {
+    // Answers the shells completing a command line, see the complete module.
+    CompleteEnv::with_factory(CliArgs::command).complete();
+    run()
+}
+
 #[tokio::main]
-async fn main() -> io::Result<()> {
+async fn run() -> io::Result<()> {
     let args = CliArgs::parse();
+    // The output of the commands is on stdout, the logs on stderr, only the warnings by default.
+    args.log.init(LevelFilter::WARN);
+    if let Err(e) = CloudletClient::use_api(&args.api) {
+        eprintln!("Invalid --api: {}", e);
+        exit(2);
+    }
 
     match args.command {
-        Commands::Run { config_path } => {
-            let toml_file = match fs::read_to_string(config_path.clone()) {
-                Ok(c) => c,
-                Err(_) => {
-                    eprintln!("Could not read file `{:?}`", config_path);
-                    exit(1);
+        Commands::Run {
+            config_path,
+            code,
+            language,
+            name,
+            dry_run,
+            keep_on_failure,
+            verbose,
+            labels,
+        } => {
+            let mut body = match config_path {
+                Some(config_path) => {
+                    let spec = is_spec(&config_path).then(|| load_spec(&config_path));
+                    match spec {
+                        Some(mut spec) if !spec.steps.is_empty() => {
+                            spec.labels.extend(labels);
+                            run_pipeline(spec, keep_on_failure, dry_run, verbose).await;
+                            return Ok(());
+                        }
+                        Some(spec) => CloudletClient::new_cloudlet_config_from_spec(spec),
+                        None => load_request(&config_path),
+                    }
                 }
+                None => inline_request(
+                    code.expect("clap requires --eval without --config-path"),
+                    language.expect("clap requires --language with --eval"),
+                    name,
+                ),
             };
-            let body = CloudletClient::new_cloudlet_config(toml_file);
+            body.keep_on_failure = keep_on_failure;
+            body.labels.extend(labels);
+
+            if verbose {
+                // The values of the secret variables are redacted by the `Debug` implementation.
+                println!("Request: {:?}", body);
+            }
+
+            if dry_run {
+                let plan = CloudletClient::plan(&body).await;
+                CloudletClient::print_plan(&body, plan);
+                return Ok(());
+            }
+
             let response = CloudletClient::run(body).await;
 
             match response {
@@ -30,8 +95,577 @@ async fn main() -> io::Result<()> {
                 Err(e) => eprintln!("Error while making the request: {}", e),
             }
         }
-        Commands::Shutdown {} => {
-            let response = CloudletClient::shutdown().await;
+        Commands::Build {
+            config_path,
+            keep_on_failure,
+            verbose,
+        } => {
+            let mut body = load_request(&config_path);
+            body.keep_on_failure = keep_on_failure;
+            if verbose {
+                println!("Request: {:?}", body);
+            }
+
+            match CloudletClient::build(body).await {
+                Ok(Some(build_id)) => println!("Build cached as {}", build_id),
+                Ok(None) => println!("Built, but the VMM doesn't cache the builds"),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Commands::Register {
+            config_path,
+            version,
+            min_instances,
+            max_instances,
+            cache_ttl,
+        } => {
+            let request = CloudletRegisterRequest {
+                request: load_request(&config_path),
+                version,
+                min_instances,
+                max_instances,
+                cache_ttl_secs: cache_ttl.map(|ttl| ttl.as_secs().max(1)),
+            };
+            match CloudletClient::register(&request).await {
+                Ok(workload) => CloudletClient::print_workload(&workload),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Commands::Workloads { name } => match CloudletClient::workloads(name.as_deref()).await {
+            Ok(workloads) => {
+                for workload in &workloads {
+                    CloudletClient::print_workload(workload);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        },
+        Commands::Runs {
+            selector,
+            workload,
+            limit,
+        } => match CloudletClient::runs(selector.as_deref(), workload.as_deref(), limit).await {
+            Ok(runs) => {
+                for run in &runs {
+                    CloudletClient::print_run(run);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        },
+        Commands::Pools { name } => match CloudletClient::pools(name.as_deref()).await {
+            Ok(pools) => {
+                for pool in &pools {
+                    CloudletClient::print_pool(pool);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        },
+        Commands::UpgradePools {
+            name,
+            language,
+            runtime_version,
+        } => {
+            let request = CloudletUpgradePoolsRequest {
+                name: name.unwrap_or_default(),
+                language: language.unwrap_or_default(),
+                runtime_version,
+            };
+            match CloudletClient::upgrade_pools(&request).await {
+                Ok(pools) => {
+                    for pool in &pools {
+                        CloudletClient::print_pool(pool);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Commands::Top { interval } => {
+            if let Err(e) = top::run(Duration::from_secs(interval.max(1))).await {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Commands::Usage {
+            tenant,
+            since,
+            until,
+            records,
+            csv: true,
+        } => match CloudletClient::usage_csv(tenant.as_deref(), since, until, records).await {
+            Ok(csv) => print!("{}", csv),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        },
+        Commands::Usage {
+            tenant,
+            since,
+            until,
+            records,
+            csv: false,
+        } => match CloudletClient::usage(tenant.as_deref(), since, until, records).await {
+            Ok(usage) if records => {
+                for record in &usage.records {
+                    CloudletClient::print_usage_record(record);
+                }
+            }
+            Ok(usage) => {
+                for tenant in &usage.tenants {
+                    CloudletClient::print_tenant_usage(tenant);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        },
+        Commands::Invoke {
+            name,
+            version,
+            data: Some(data),
+            ..
+        } => match CloudletClient::call(&name, version.as_deref(), data).await {
+            Ok(result) => println!("{}", result),
+            Err(e) => {
+                eprintln!("Could not call {}: {}", name, e);
+                exit(1);
+            }
+        },
+        Commands::Invoke {
+            name,
+            version,
+            inputs,
+            data: None,
+        } => {
+            let request = CloudletInvokeRequest {
+                version,
+                inputs: inputs.into_iter().collect(),
+            };
+            if let Err(e) = CloudletClient::invoke(&name, &request).await {
+                eprintln!("Could not invoke {}: {}", name, e);
+                exit(1);
+            }
+        }
+        Commands::Schedule { command } => {
+            let result = match command {
+                ScheduleCommands::Create { schedule } => put_schedule(schedule, false).await,
+                ScheduleCommands::Update { schedule } => put_schedule(schedule, true).await,
+                ScheduleCommands::List {} => CloudletClient::schedules().await.map(|schedules| {
+                    for schedule in &schedules {
+                        CloudletClient::print_schedule(schedule, false);
+                    }
+                }),
+                ScheduleCommands::Show { name } => CloudletClient::schedule(&name)
+                    .await
+                    .map(|schedule| CloudletClient::print_schedule(&schedule, true)),
+                ScheduleCommands::Delete { name } => {
+                    match prompt::confirm(&format!("Delete the schedule {}", name), args.yes) {
+                        Ok(true) => CloudletClient::delete_schedule(&name).await,
+                        Ok(false) => return Ok(()),
+                        Err(e) => Err(e.into()),
+                    }
+                }
+            };
+
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Commands::Jobs { command } => {
+            let result = match command {
+                JobsCommands::List {} => CloudletClient::jobs().await.map(|queue| {
+                    for job in &queue.jobs {
+                        CloudletClient::print_job(job);
+                    }
+                    println!("{} job(s) queued", queue.depth);
+                }),
+                JobsCommands::Show { id } => CloudletClient::job(id)
+                    .await
+                    .map(|job| CloudletClient::print_job(&job)),
+                JobsCommands::Drain {} => CloudletClient::drain_jobs().await.map(|drained| {
+                    println!(
+                        "Started {} job(s), {} left queued",
+                        drained.started, drained.depth
+                    );
+                }),
+            };
+
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Commands::Node { command } => {
+            let (action, request) = match command {
+                NodeCommands::Cordon { reason } => (
+                    "node/cordon",
+                    CloudletNodeRequest {
+                        reason,
+                        ..Default::default()
+                    },
+                ),
+                NodeCommands::Uncordon {} => ("node/uncordon", CloudletNodeRequest::default()),
+                NodeCommands::Drain {
+                    reason,
+                    timeout,
+                    kill,
+                } => {
+                    let action =
+                        format!("Kill the VMs still running on the node after {}s", timeout);
+                    if kill && timeout > 0 {
+                        match prompt::confirm(&action, args.yes) {
+                            Ok(true) => {}
+                            Ok(false) => return Ok(()),
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                exit(1);
+                            }
+                        }
+                    }
+                    (
+                        "node/drain",
+                        CloudletNodeRequest {
+                            reason,
+                            timeout_secs: timeout,
+                            kill,
+                        },
+                    )
+                }
+            };
+
+            match CloudletClient::node(action, &request).await {
+                Ok(status) => {
+                    CloudletClient::print_node(&status);
+                    // A drain which left VMs running didn't let the host be stopped.
+                    if action == "node/drain" && !status.running_vms.is_empty() {
+                        exit(1);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Commands::Init {
+            language,
+            directory,
+            name,
+        } => {
+            let name = name.unwrap_or_else(|| format!("hello-{:?}", language).to_lowercase());
+            if let Some(e) = cloudlet_spec::validate_workload_name(&name) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+
+            match scaffold::scaffold(&directory, &name, &language) {
+                Ok(files) => {
+                    for file in files {
+                        println!("Created {}", file.display());
+                    }
+                    println!(
+                        "Run it with: cli run --config-path {}",
+                        directory.join(scaffold::SPEC_FILE_NAME).display()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Could not create the workload: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        Commands::Batch {
+            command:
+                BatchCommands::Run {
+                    manifest,
+                    parallelism,
+                    report,
+                },
+        } => {
+            let manifest = match BatchManifest::from_file(&manifest) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            };
+            let jobs = match batch::load_jobs(&manifest) {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            };
+
+            let summary = batch::run(jobs, parallelism.or(manifest.parallelism)).await;
+            summary.print();
+
+            if let Some(path) = report {
+                let json = serde_json::to_string_pretty(&summary)?;
+                fs::write(&path, json)?;
+            }
+            if summary.failed > 0 {
+                exit(1);
+            }
+        }
+        Commands::Info {} => match CloudletClient::server_info().await {
+            Ok(info) => {
+                println!("Server version: {}", info.version);
+                if info.emulated {
+                    println!(
+                        "Hypervisor: {} (emulated without KVM, the runs are much slower)",
+                        info.hypervisor
+                    );
+                }
+                for runtime in info.runtimes {
+                    println!(
+                        "{}: {}",
+                        runtime.language.as_str(),
+                        runtime.versions.join(", ")
+                    );
+                }
+                for kernel in info.kernels {
+                    let version = match kernel.version.as_str() {
+                        "" => String::new(),
+                        version => format!(" {}", version),
+                    };
+                    let default = if kernel.name == info.default_kernel {
+                        " (default)"
+                    } else {
+                        ""
+                    };
+                    let features = if kernel.features.is_empty() {
+                        "unknown features".to_string()
+                    } else {
+                        kernel.features.join(", ")
+                    };
+                    println!("kernel {}{}{}: {}", kernel.name, version, default, features);
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not get the server info: {}", e);
+                exit(1);
+            }
+        },
+        Commands::Doctor { config_path } => {
+            if !doctor::run(config_path.as_deref()).await {
+                exit(1);
+            }
+        }
+        Commands::Events { workload, vm } => {
+            if let Err(e) = CloudletClient::watch_events(workload, vm).await {
+                eprintln!("Could not watch the events: {}", e);
+                exit(1);
+            }
+        }
+        Commands::Logs {
+            vm,
+            follow,
+            tail,
+            raw,
+            since,
+            grep,
+            stream,
+        } => {
+            let filter = LogFilter {
+                since,
+                grep,
+                stream: stream.map(|stream| stream.as_str()),
+            };
+            if let Err(e) = CloudletClient::logs(&vm, follow, tail, raw, &filter).await {
+                eprintln!("Could not get the logs: {}", e);
+                exit(1);
+            }
+        }
+        Commands::Rerun { run_id } => {
+            match CloudletClient::run_inputs(&run_id).await {
+                Ok(inputs) => {
+                    println!("Rerunning {} of {}", inputs.run_id, inputs.workload_name);
+                    println!("Code:   {}", inputs.code_digest);
+                    println!("Env:    {}", inputs.env_digest);
+                    println!("Image:  {}", inputs.image_digest);
+                    println!("Kernel: {}", inputs.kernel);
+                }
+                Err(e) => {
+                    eprintln!("Could not get the inputs of the run: {}", e);
+                    exit(1);
+                }
+            }
+
+            if let Err(e) = CloudletClient::rerun(&run_id).await {
+                eprintln!("Could not run it again: {}", e);
+                exit(1);
+            }
+        }
+        Commands::DebugBundle { run_id, output } => {
+            let output = output.unwrap_or_else(|| format!("{}-debug.tar.gz", run_id).into());
+            match debug_bundle::create(&run_id, &output).await {
+                Ok(missing) => {
+                    for part in &missing {
+                        eprintln!("Left out {}", part);
+                    }
+                    println!("Wrote {}", output.display());
+                }
+                Err(e) => {
+                    eprintln!("Could not collect the debug bundle: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        Commands::Artifacts {
+            run_id, name: None, ..
+        } => match CloudletClient::run_artifacts(&run_id).await {
+            Ok(artifacts) => {
+                for artifact in artifacts {
+                    let truncated = if artifact.truncated {
+                        " (truncated)"
+                    } else {
+                        ""
+                    };
+                    println!("{}: {} bytes{}", artifact.name, artifact.size, truncated);
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not list the artifacts of the run: {}", e);
+                exit(1);
+            }
+        },
+        Commands::Artifacts {
+            run_id,
+            name: Some(name),
+            output,
+        } => {
+            let content = match CloudletClient::run_artifact(&run_id, &name).await {
+                Ok(content) => content,
+                Err(e) => {
+                    eprintln!("Could not download the artifact: {}", e);
+                    exit(1);
+                }
+            };
+            let output = output.unwrap_or_else(|| format!("{}-{}", run_id, name).into());
+            let written = if output == Path::new("-") {
+                io::Write::write_all(&mut io::stdout(), &content)
+            } else {
+                fs::write(&output, &content)
+            };
+            match written {
+                Ok(()) if output != Path::new("-") => {
+                    eprintln!("Wrote {} bytes to {}", content.len(), output.display())
+                }
+                Ok(()) => {}
+                Err(e) => {
+                    eprintln!("Could not write the artifact: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        Commands::Fs {
+            command: FsCommands::Ls { vm, path },
+        } => match CloudletClient::guest_dir(&vm, path.as_deref().unwrap_or_default()).await {
+            Ok(listing) => {
+                for entry in listing.entries {
+                    let (kind, suffix) = match entry.kind.as_str() {
+                        "directory" => ('d', "/".to_string()),
+                        "symlink" => ('l', format!(" -> {}", entry.target.unwrap_or_default())),
+                        "file" => ('-', String::new()),
+                        _ => ('?', String::new()),
+                    };
+                    println!(
+                        "{} {:04o} {:>10}  {}{}",
+                        kind, entry.mode, entry.size, entry.name, suffix
+                    );
+                }
+                if listing.truncated {
+                    eprintln!("(more entries not listed)");
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not list the directory: {}", e);
+                exit(1);
+            }
+        },
+        Commands::Fs {
+            command:
+                FsCommands::Cat {
+                    vm,
+                    path,
+                    max_bytes,
+                },
+        } => match CloudletClient::guest_file(&vm, &path, max_bytes).await {
+            Ok(file) => {
+                io::Write::write_all(&mut io::stdout(), &file.content)?;
+                if file.truncated {
+                    eprintln!(
+                        "(truncated: {} of {} bytes printed)",
+                        file.content.len(),
+                        file.size
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not read the file: {}", e);
+                exit(1);
+            }
+        },
+        Commands::Vm {
+            command: VmCommands::List { selector },
+        } => match CloudletClient::vms(selector.as_deref()).await {
+            Ok(vms) => {
+                for vm in &vms {
+                    CloudletClient::print_vm(vm);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        },
+        Commands::Vm {
+            command: VmCommands::Shell { vm, command },
+        } => match shell::run(&vm, command).await {
+            Ok(exit_code) => exit(exit_code),
+            Err(e) => {
+                eprintln!("Could not open the shell: {}", e);
+                exit(1);
+            }
+        },
+        Commands::Vm {
+            command: VmCommands::Stop { vm, all },
+        } => {
+            if let Err(e) = stop_vms(vm, all, args.yes).await {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Commands::Completions { shell } => print!("{}", complete::script(shell)),
+        Commands::Man { directory } => match complete::write_man_pages(&directory) {
+            Ok(pages) => println!("Wrote {} man pages to {}", pages, directory.display()),
+            Err(e) => {
+                eprintln!("Could not write the man pages: {}", e);
+                exit(1);
+            }
+        },
+        Commands::Shutdown { vm } => {
+            let response = CloudletClient::shutdown(vm).await;
             match response {
                 Ok(bool) => {
                     if bool {
@@ -47,3 +681,154 @@ async fn main() -> io::Result<()> {
 
     Ok(())
 }
+
+/// Stop the VM `vm`, or every running VM if `all` is set, once confirmed.
+async fn stop_vms(vm: Option<String>, all: bool, yes: bool) -> Result<(), Box<dyn Error>> {
+    let vms = match vm {
+        Some(vm) if !all => vec![vm],
+        _ => {
+            let vms: Vec<String> = CloudletClient::vms(None)
+                .await?
+                .into_iter()
+                .map(|vm| vm.vm_id)
+                .collect();
+            if vms.is_empty() {
+                println!("No VM is running");
+                return Ok(());
+            }
+            let action = format!("Stop the {} running VM(s): {}", vms.len(), vms.join(", "));
+            if !prompt::confirm(&action, yes)? {
+                return Ok(());
+            }
+            vms
+        }
+    };
+
+    let mut failed = 0;
+    for vm in vms {
+        match CloudletClient::shutdown(Some(vm.clone())).await {
+            Ok(true) => println!("Stopped {}", vm),
+            _ => {
+                eprintln!("Could not stop {}", vm);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} VM(s) couldn't be stopped", failed).into());
+    }
+    Ok(())
+}
+
+/// Create the schedule described by `args`, or replace it if `update` is set.
+/// Run the steps of `spec`, exiting unless they all succeed.
+async fn run_pipeline(spec: WorkloadSpec, keep_on_failure: bool, dry_run: bool, verbose: bool) {
+    let mut pipeline = CloudletClient::new_pipeline_from_spec(spec);
+    for step in &mut pipeline.steps {
+        step.request.keep_on_failure = keep_on_failure;
+    }
+    if verbose {
+        println!("Pipeline: {:?}", pipeline);
+    }
+    if dry_run {
+        CloudletClient::print_pipeline(&pipeline);
+        return;
+    }
+
+    match CloudletClient::run_pipeline(&pipeline).await {
+        Ok(summary) if summary.succeeded => {}
+        Ok(_) => exit(1),
+        Err(e) => {
+            eprintln!("Error while making the request: {}", e);
+            exit(1);
+        }
+    }
+}
+
+async fn put_schedule(args: ScheduleArgs, update: bool) -> Result<(), Box<dyn Error>> {
+    let schedule = CloudletScheduleRequest {
+        request: load_request(&args.config_path),
+        name: args.name,
+        cron: args.cron,
+        enabled: !args.disabled,
+    };
+    let schedule = CloudletClient::put_schedule(&schedule, update).await?;
+    CloudletClient::print_schedule(&schedule, false);
+    Ok(())
+}
+
+/// Whether `config_path` is a spec, rather than a legacy TOML config file.
+fn is_spec(config_path: &Path) -> bool {
+    config_path
+        .extension()
+        .is_some_and(|ext| ext == "yaml" || ext == "yml")
+}
+
+/// Spec at `config_path`, exiting if it is invalid.
+fn load_spec(config_path: &Path) -> WorkloadSpec {
+    // Ask for the fields the spec misses rather than failing, when there is someone to ask.
+    let completed = if prompt::is_interactive() {
+        prompt::complete_spec(config_path).unwrap_or_else(|e| {
+            eprintln!("Could not complete `{}`: {}", config_path.display(), e);
+            exit(1);
+        })
+    } else {
+        None
+    };
+    let spec = match &completed {
+        Some(content) => {
+            eprintln!(
+                "Add the answers to `{}` not to be asked again",
+                config_path.display()
+            );
+            WorkloadSpec::from_content(content, config_path)
+        }
+        None => WorkloadSpec::from_file(config_path),
+    };
+    spec.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    })
+}
+
+/// Request of the workload at `config_path`, a spec or a legacy TOML config file.
+fn load_request(config_path: &Path) -> CloudletDtoRequest {
+    if is_spec(config_path) {
+        CloudletClient::new_cloudlet_config_from_spec(load_spec(config_path))
+    } else {
+        let toml_file = match fs::read_to_string(config_path) {
+            Ok(c) => c,
+            Err(_) => {
+                eprintln!("Could not read file `{:?}`", config_path);
+                exit(1);
+            }
+        };
+        CloudletClient::new_cloudlet_config(toml_file)
+    }
+}
+
+/// Request running the code of `run --eval`, read from stdin if it is `-`, exiting if there is
+/// none.
+fn inline_request(code: String, language: Language, name: Option<String>) -> CloudletDtoRequest {
+    let code = if code == "-" {
+        let mut code = String::new();
+        if let Err(e) = io::Read::read_to_string(&mut io::stdin(), &mut code) {
+            eprintln!("Could not read the code from stdin: {}", e);
+            exit(1);
+        }
+        code
+    } else {
+        code
+    };
+    if code.trim().is_empty() {
+        eprintln!("No code to run");
+        exit(1);
+    }
+
+    let name = name.unwrap_or_else(|| format!("inline-{:?}", language).to_lowercase());
+    if let Some(e) = cloudlet_spec::validate_workload_name(&name) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+    CloudletClient::new_cloudlet_config_from_code(name, language, code)
+}
@@ -0,0 +1,161 @@
+//! Questions asked on the terminal: the required fields a spec misses, and the confirmation of
+//! the destructive commands. Nothing is asked when stdin isn't a terminal, e.g. in scripts,
+//! where a missing field is an error and a destructive command needs `--yes`.
+
+use cloudlet_spec::{detect_language, validate_workload_name, API_VERSION};
+use serde_yaml::{Mapping, Value};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
+/// Source files offered as the code of a spec which doesn't name it.
+const CODE_FILES: &[&str] = &["main.rs", "main.py", "index.js", "main.js", "handler.py"];
+
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal()
+}
+
+/// Ask `question` until the answer passes `validate`, an empty answer taking `default`.
+pub fn ask(
+    question: &str,
+    default: Option<&str>,
+    validate: impl Fn(&str) -> Result<(), String>,
+) -> io::Result<String> {
+    loop {
+        match default {
+            Some(default) => eprint!("{} [{}]: ", question, default),
+            None => eprint!("{}: ", question),
+        }
+        io::stderr().flush()?;
+
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no answer on stdin",
+            ));
+        }
+        let answer = match answer.trim() {
+            "" => match default {
+                Some(default) => default.to_string(),
+                None => continue,
+            },
+            answer => answer.to_string(),
+        };
+        match validate(&answer) {
+            Ok(()) => return Ok(answer),
+            Err(e) => eprintln!("  {}", e),
+        }
+    }
+}
+
+/// Ask to pick one of `choices`.
+pub fn choose(question: &str, choices: &[&str], default: Option<&str>) -> io::Result<String> {
+    let question = format!("{} ({})", question, choices.join("/"));
+    ask(&question, default, |answer| {
+        if choices.contains(&answer) {
+            Ok(())
+        } else {
+            Err(format!("expected one of {}", choices.join(", ")))
+        }
+    })
+}
+
+/// Ask to confirm `action`, unless `yes` is set. Outside of a terminal, the action needs `yes`.
+pub fn confirm(action: &str, yes: bool) -> Result<bool, String> {
+    if yes {
+        return Ok(true);
+    }
+    if !is_interactive() {
+        return Err(format!("{}: pass --yes to confirm", action));
+    }
+    let answer = choose(&format!("{}?", action), &["y", "n"], Some("n"))
+        .map_err(|e| format!("{}: {}", action, e))?;
+    Ok(answer == "y")
+}
+
+fn key(name: &str) -> Value {
+    Value::String(name.to_string())
+}
+
+/// Ask for the required fields the spec at `path` misses, returning the completed spec, or
+/// `None` if it misses none or can't be parsed.
+pub fn complete_spec(path: &Path) -> io::Result<Option<String>> {
+    let content = std::fs::read_to_string(path)?;
+    let Ok(Value::Mapping(mut spec)) = serde_yaml::from_str::<Value>(&content) else {
+        return Ok(None);
+    };
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let mut completed = false;
+
+    if !spec.contains_key("api-version") {
+        let version = ask("api-version", Some(API_VERSION), |version| {
+            if version == API_VERSION {
+                Ok(())
+            } else {
+                Err(format!("only {} is supported", API_VERSION))
+            }
+        })?;
+        spec.insert(key("api-version"), Value::String(version));
+        completed = true;
+    }
+
+    if !spec.contains_key("workload-name") {
+        let directory = std::fs::canonicalize(base_dir)
+            .ok()
+            .and_then(|dir| {
+                dir.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+            .map(|name| name.to_lowercase().replace([' ', '.'], "-"))
+            .filter(|name| validate_workload_name(name).is_none());
+        let name = ask("workload-name", directory.as_deref(), |name| {
+            validate_workload_name(name).map_or(Ok(()), |e| Err(e.message))
+        })?;
+        spec.insert(key("workload-name"), Value::String(name));
+        completed = true;
+    }
+
+    let code_path = spec
+        .get("code")
+        .and_then(|code| code.get("path"))
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let code_path = match code_path {
+        Some(code_path) => code_path,
+        None => {
+            let default = CODE_FILES
+                .iter()
+                .find(|file| base_dir.join(file).is_file())
+                .copied();
+            let code_path = ask("code.path", default, |code_path| {
+                if base_dir.join(code_path).exists() {
+                    Ok(())
+                } else {
+                    Err(format!("{} doesn't exist next to the spec", code_path))
+                }
+            })?;
+            let mut code = Mapping::new();
+            code.insert(key("path"), Value::String(code_path.clone()));
+            spec.insert(key("code"), Value::Mapping(code));
+            completed = true;
+            code_path
+        }
+    };
+
+    // Only a language which can't be detected is asked for.
+    if !spec.contains_key("language") {
+        if let Err(e) = detect_language(&base_dir.join(&code_path)) {
+            eprintln!("{}", e);
+            let language = choose("language", &["rust", "python", "node"], None)?;
+            spec.insert(key("language"), Value::String(language));
+            completed = true;
+        }
+    }
+
+    if !completed {
+        return Ok(None);
+    }
+    serde_yaml::to_string(&Value::Mapping(spec))
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
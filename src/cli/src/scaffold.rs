@@ -0,0 +1,108 @@
+use shared_models::Language;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Name of the spec file created by `cloudlet init`.
+pub const SPEC_FILE_NAME: &str = "cloudlet.yaml";
+/// Name of the env file referenced by the generated spec.
+pub const ENV_FILE_NAME: &str = "cloudlet.env";
+
+const RUST_CODE: &str = r#"fn main() {
+    let name = std::env::var("GREETING_NAME").unwrap_or_else(|_| "world".to_string());
+    println!("Hello, {}!", name);
+}
+"#;
+
+const PYTHON_CODE: &str = r#"import os
+
+name = os.environ.get("GREETING_NAME", "world")
+print(f"Hello, {name}!")
+"#;
+
+const NODE_CODE: &str = r#"const name = process.env.GREETING_NAME || "world";
+console.log(`Hello, ${name}!`);
+"#;
+
+const ENV_FILE: &str = "# Environment variables given to the workload, one `KEY=VALUE` per line.
+GREETING_NAME=cloudlet
+";
+
+/// Name and content of the sample source file for `language`.
+pub fn code_file(language: &Language) -> (&'static str, &'static str) {
+    match language {
+        Language::RUST => ("main.rs", RUST_CODE),
+        Language::PYTHON => ("main.py", PYTHON_CODE),
+        Language::NODE => ("index.js", NODE_CODE),
+    }
+}
+
+fn spec_file(workload_name: &str, language: &Language, code_path: &str) -> String {
+    let language = match language {
+        Language::RUST => "rust",
+        Language::PYTHON => "python",
+        Language::NODE => "node",
+    };
+
+    format!(
+        "api-version: cloudlet/v2
+workload-name: {workload_name}
+language: {language}
+action: prepare-and-run
+
+code:
+  path: {code_path}
+
+build:
+  release: true
+
+resources:
+  cpus: 1
+  memory-mb: 4000
+
+# Maximum duration of the run, in seconds
+timeout: 300
+
+env-file: {ENV_FILE_NAME}
+"
+    )
+}
+
+/// Create a spec, a sample source file and an env file in `directory`.
+///
+/// Existing files are never overwritten: the whole scaffold is aborted if one of them already exists.
+pub fn scaffold(
+    directory: &Path,
+    workload_name: &str,
+    language: &Language,
+) -> io::Result<Vec<PathBuf>> {
+    let (code_name, code) = code_file(language);
+    let files = [
+        (
+            SPEC_FILE_NAME,
+            spec_file(workload_name, language, code_name),
+        ),
+        (code_name, code.to_string()),
+        (ENV_FILE_NAME, ENV_FILE.to_string()),
+    ];
+
+    let files: Vec<(PathBuf, String)> = files
+        .into_iter()
+        .map(|(name, content)| (directory.join(name), content))
+        .collect();
+
+    if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("`{}` already exists", path.display()),
+        ));
+    }
+
+    fs::create_dir_all(directory)?;
+    for (path, content) in files.iter() {
+        fs::write(path, content)?;
+    }
+
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
@@ -1,24 +1,356 @@
+use crate::args::DEFAULT_API;
+use crate::scaffold;
 use crate::utils::ConfigFileHandler;
-use reqwest::Client;
-use serde::Deserialize;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use cloudlet_spec::WorkloadSpec;
+use flate2::{write::GzEncoder, Compression};
+use reqwest::{Client, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use shared_models::unix_socket;
 use shared_models::{
-    BuildConfig, CloudletDtoRequest, CloudletShutdownResponse, Language, ServerConfig,
+    redact_env, ArtifactPlan, BuildConfig, CloudletDirListing, CloudletDrainResponse,
+    CloudletDtoRequest, CloudletErrorResponse, CloudletInvokeRequest, CloudletJob,
+    CloudletJobQueue, CloudletNodeRequest, CloudletNodeStatus, CloudletPipelineRequest,
+    CloudletPipelineStep, CloudletPipelineSummary, CloudletPlanResponse, CloudletPool,
+    CloudletRegisterRequest, CloudletRunArtifact, CloudletRunInputs, CloudletRunRecord,
+    CloudletSchedule, CloudletScheduleRequest, CloudletServerInfo, CloudletShutdownResponse,
+    CloudletTenantUsage, CloudletUpgradePoolsRequest, CloudletUsage, CloudletUsageRecord,
+    CloudletVmExit, CloudletVmMetrics, CloudletWorkload, Language, PipelineStage, ServerConfig,
+    QUEUE_HEADER,
 };
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tungstenite::WebSocketStream;
+use tracing::debug;
 
 #[derive(Deserialize, Debug)]
 struct TomlConfig {
     #[serde(rename = "workload-name")]
     workload_name: String,
-    language: Language,
+    language: Option<Language>,
     action: String,
     server: ServerConfig,
     build: BuildConfig,
 }
 
+/// Output of a run printed by `logs`.
+#[derive(Debug, Default)]
+pub struct LogFilter {
+    /// Only the output produced in this last period.
+    pub since: Option<Duration>,
+    /// Only the lines matching this regular expression.
+    pub grep: Option<String>,
+    /// `stdout` or `stderr`, both if unset.
+    pub stream: Option<&'static str>,
+}
+
+/// Event streamed by the API while a workload runs.
+#[derive(Debug, Deserialize)]
+pub struct RunEvent {
+    pub stage: String,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    #[serde(default)]
+    pub encoding: OutputEncoding,
+    pub exit_code: Option<i32>,
+    /// Whether output was cut at this point, or, on the final event, at all.
+    #[serde(default)]
+    pub truncated: bool,
+    /// Summary of the output, on the final event.
+    #[serde(default)]
+    pub output: Option<OutputSummary>,
+    /// Why the orchestrator ended the run, e.g. when it was preempted.
+    #[serde(default)]
+    pub error: Option<CloudletErrorResponse>,
+    /// Start or end of a stage of the pipeline.
+    #[serde(default)]
+    pub marker: Option<StageMarker>,
+    /// Id of the cached build, on the end of the build stage.
+    #[serde(default)]
+    pub build_id: Option<String>,
+    /// The run reused a cached build, on the final event.
+    #[serde(default)]
+    pub build_cached: bool,
+    /// Attempts the run took when it was retried, on the final event.
+    #[serde(default)]
+    pub attempts: Option<u32>,
+    /// Signal which killed the workload, on the final event.
+    #[serde(default)]
+    pub signal: Option<String>,
+    #[serde(default)]
+    pub core_dumped: bool,
+    /// Files collected to debug the run, on the final event.
+    #[serde(default)]
+    pub artifacts: Vec<CloudletRunArtifact>,
+    /// How the VM stopped, on the final event of a run whose VM doesn't outlive it.
+    #[serde(default)]
+    pub vm_exit: Option<CloudletVmExit>,
+    /// Output dropped before this event, read too slowly.
+    #[serde(default)]
+    pub gap: Option<StreamGap>,
+    /// Step of the pipeline the event is about, on the events of a pipeline.
+    #[serde(default)]
+    pub step: Option<String>,
+    /// Outcome of the steps, on the final event of a pipeline.
+    #[serde(default)]
+    pub pipeline: Option<CloudletPipelineSummary>,
+}
+
+/// Start or end of a stage of the pipeline of a workload.
+#[derive(Debug, Deserialize)]
+pub struct StageMarker {
+    pub stage: String,
+    pub event: String,
+}
+
+/// Encoding of the output in the events.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputEncoding {
+    #[default]
+    Utf8,
+    /// Output which isn't valid UTF-8.
+    Base64,
+}
+
+impl RunEvent {
+    fn decode(&self, output: &Option<String>) -> Option<Vec<u8>> {
+        let output = output.as_ref()?;
+        match self.encoding {
+            OutputEncoding::Utf8 => Some(output.clone().into_bytes()),
+            OutputEncoding::Base64 => BASE64_STANDARD.decode(output).ok(),
+        }
+    }
+
+    /// Bytes written by the workload to its stdout.
+    pub fn stdout_bytes(&self) -> Option<Vec<u8>> {
+        self.decode(&self.stdout)
+    }
+
+    /// Bytes written by the workload to its stderr.
+    pub fn stderr_bytes(&self) -> Option<Vec<u8>> {
+        self.decode(&self.stderr)
+    }
+
+    /// Output of the workload to display, invalid UTF-8 being replaced.
+    pub fn stdout_text(&self) -> Option<String> {
+        self.stdout_bytes()
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Same as [`RunEvent::stdout_text`], for stderr.
+    pub fn stderr_text(&self) -> Option<String> {
+        self.stderr_bytes()
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// Output of a run, as counted by the agent.
+#[derive(Debug, Deserialize)]
+pub struct OutputSummary {
+    pub bytes: u64,
+    pub lines: u64,
+    pub dropped_bytes: u64,
+    pub dropped_lines: u64,
+}
+
+impl OutputSummary {
+    /// Note printed once a run is done if its output was truncated.
+    pub fn truncation_note(&self) -> String {
+        format!(
+            "output truncated: {} lines ({} bytes) dropped",
+            self.dropped_lines, self.dropped_bytes
+        )
+    }
+}
+
+/// Output the orchestrator dropped from a stream read too slowly.
+#[derive(Debug, Deserialize)]
+pub struct StreamGap {
+    pub dropped_bytes: u64,
+    pub dropped_lines: u64,
+}
+
+/// Lifecycle or scheduling event of a VM, streamed by the API.
+#[derive(Debug, Deserialize)]
+pub struct VmEvent {
+    pub vm_id: String,
+    pub workload_name: String,
+    pub kind: String,
+    pub timestamp: u64,
+    pub message: String,
+}
+
+/// What the orchestrator knows of a run, secrets redacted.
+#[derive(Debug, Deserialize)]
+pub struct RunDebugInfo {
+    pub run: Option<CloudletRunRecord>,
+    pub events: Vec<VmEvent>,
+    /// Request of the run, empty if the orchestrator doesn't record them.
+    pub request: String,
+    pub config: String,
+    /// File of the console of the guest on the host of the orchestrator, empty if not logged.
+    #[serde(default)]
+    pub console_log: String,
+}
+
+/// How the API is reached.
+struct Api {
+    endpoint: String,
+    /// URL the paths of the API are appended to.
+    base: String,
+    /// Never set outside of Unix, where the API can't listen on a socket.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    socket: Option<PathBuf>,
+}
+
+static API: OnceLock<Api> = OnceLock::new();
+
+fn api() -> &'static Api {
+    API.get_or_init(|| Api {
+        endpoint: DEFAULT_API.into(),
+        base: DEFAULT_API.into(),
+        socket: None,
+    })
+}
+
+/// Client of the API, connecting to its Unix socket if it listens on one.
+fn api_client() -> Client {
+    #[cfg(unix)]
+    if let Some(path) = &api().socket {
+        return Client::builder()
+            .unix_socket(path.clone())
+            .build()
+            .expect("Failed to create the HTTP client");
+    }
+    Client::new()
+}
+
+/// URL of `path` on the API.
+fn api_url(path: &str) -> String {
+    let url = format!("{}{}", api().base, path);
+    debug!(%url, "request to the API");
+    url
+}
+
+/// Server settings used for specs, which don't describe the server.
+const DEFAULT_SERVER_ADDRESS: &str = "localhost";
+const DEFAULT_SERVER_PORT: u16 = 50051;
+
+/// Size past which the request bodies, e.g. with the code of a workload, are sent compressed.
+const COMPRESSION_THRESHOLD: usize = 64 * 1024;
+
+/// Set `body` as the JSON body of `request`, gzip-compressed when it is large.
+fn json_body<T: Serialize>(request: RequestBuilder, body: &T) -> RequestBuilder {
+    let Ok(json) = serde_json::to_vec(body) else {
+        // reqwest reports the error when the request is sent.
+        return request.json(body);
+    };
+    let request = request.header(reqwest::header::CONTENT_TYPE, "application/json");
+    if json.len() < COMPRESSION_THRESHOLD {
+        return request.body(json);
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    match encoder.write_all(&json).and_then(|()| encoder.finish()) {
+        Ok(compressed) => request
+            .header(reqwest::header::CONTENT_ENCODING, "gzip")
+            .body(compressed),
+        Err(_) => request.body(json),
+    }
+}
+
+/// File read in a running VM.
+#[derive(Debug)]
+pub struct GuestFile {
+    pub content: Vec<u8>,
+    /// Size of the file in the guest.
+    pub size: u64,
+    /// Whether `content` was cut at the cap.
+    pub truncated: bool,
+}
+
+/// Connection a WebSocket of the API runs over.
+pub trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+/// Open the WebSocket at `path` on the API, through its Unix socket if it listens on one.
+async fn api_websocket(path: &str) -> Result<WebSocketStream<Box<dyn Socket>>, Box<dyn Error>> {
+    let api = api();
+    let Some(base) = api.base.strip_prefix("http://") else {
+        return Err(format!(
+            "WebSockets need an http:// or unix:// API endpoint, not {}",
+            api.endpoint
+        )
+        .into());
+    };
+    let url = format!("ws://{}{}", base, path);
+
+    let socket: Box<dyn Socket> = match &api.socket {
+        #[cfg(unix)]
+        Some(socket) => Box::new(tokio::net::UnixStream::connect(socket).await?),
+        _ => {
+            let addresses = reqwest::Url::parse(&api.base)?.socket_addrs(|| None)?;
+            Box::new(TcpStream::connect(&*addresses).await?)
+        }
+    };
+    let (websocket, _) = tokio_tungstenite::client_async(url, socket).await?;
+    Ok(websocket)
+}
+
 pub struct CloudletClient {}
 
 impl CloudletClient {
+    /// Reach the API at `endpoint`, `http://<HOST>:<PORT>` or `unix://<PATH>` for an API
+    /// listening on a Unix socket. Must be called before any request.
+    pub fn use_api(endpoint: &str) -> Result<(), String> {
+        let api = match unix_socket::socket_path(endpoint) {
+            Some(path) if path.as_os_str().is_empty() => {
+                return Err(format!("the API endpoint {} has no path", endpoint))
+            }
+            Some(_) if !cfg!(unix) => {
+                return Err(format!(
+                    "the API endpoint {} is a Unix socket, which this host can't connect to: use http://<HOST>:<PORT>",
+                    endpoint
+                ))
+            }
+            // The host is ignored, every connection goes to the socket.
+            Some(path) => Api {
+                endpoint: endpoint.into(),
+                base: "http://localhost".into(),
+                socket: Some(path.to_path_buf()),
+            },
+            None => {
+                let url = reqwest::Url::parse(endpoint)
+                    .map_err(|e| format!("invalid API endpoint {}: {}", endpoint, e))?;
+                if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+                    return Err(format!(
+                        "the API endpoint {} must be http://<HOST>:<PORT>, https://<HOST>:<PORT> or unix://<PATH>",
+                        endpoint
+                    ));
+                }
+                Api {
+                    endpoint: endpoint.into(),
+                    base: endpoint.trim_end_matches('/').into(),
+                    socket: None,
+                }
+            }
+        };
+        let _ = API.set(api);
+        Ok(())
+    }
+
+    /// Endpoint of the API, as given to `use_api`.
+    pub fn api_endpoint() -> &'static str {
+        &api().endpoint
+    }
+
     pub fn new_cloudlet_config(config: String) -> CloudletDtoRequest {
         let config: TomlConfig =
             toml::from_str(&config).expect("Error while parsing the config file");
@@ -27,7 +359,16 @@ impl CloudletClient {
         let code: String = ConfigFileHandler::read_file(&config.build.source_code_path)
             .expect("Error while reading the code file");
 
-        let language = config.language;
+        let (language, language_detection) = match config.language {
+            Some(language) => (language, None),
+            None => match cloudlet_spec::detect_language(&config.build.source_code_path) {
+                Ok(detection) => (detection.language, Some(detection.reason)),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            },
+        };
         CloudletDtoRequest {
             workload_name,
             language,
@@ -36,26 +377,1188 @@ impl CloudletClient {
             server: config.server,
             build: config.build,
             action: config.action,
+            resources: Default::default(),
+            devices: Vec::new(),
+            capabilities: Vec::new(),
+            node_affinity: Default::default(),
+            kernel: None,
+            priority_class: None,
+            tenant: None,
+            env: Default::default(),
+            secret_env: Vec::new(),
+            stages: Vec::new(),
+            webhooks: Vec::new(),
+            keep_on_failure: false,
+            timeout_secs: None,
+            language_detection,
+            labels: Default::default(),
+        }
+    }
+
+    pub fn new_cloudlet_config_from_spec(spec: WorkloadSpec) -> CloudletDtoRequest {
+        let code: String = ConfigFileHandler::read_file(&spec.code.path)
+            .expect("Error while reading the code file");
+
+        CloudletDtoRequest {
+            workload_name: spec.workload_name,
+            language: spec
+                .language
+                .expect("WorkloadSpec::from_file detects the missing language"),
+            code,
+            log_level: shared_models::LogLevel::INFO,
+            server: ServerConfig {
+                address: DEFAULT_SERVER_ADDRESS.into(),
+                port: DEFAULT_SERVER_PORT,
+            },
+            build: BuildConfig {
+                source_code_path: spec.code.path,
+                release: spec.build.release,
+                features: spec.build.features,
+                compiler_flags: spec.build.compiler_flags,
+                runtime_version: spec.build.runtime_version,
+            },
+            action: spec.action.to_string(),
+            resources: spec.resources,
+            devices: spec.devices,
+            capabilities: spec.capabilities,
+            node_affinity: spec.node_affinity,
+            kernel: spec.kernel,
+            priority_class: spec.priority_class,
+            tenant: spec.tenant,
+            env: spec.env,
+            secret_env: spec.secret_env,
+            stages: Vec::new(),
+            webhooks: spec.webhooks,
+            keep_on_failure: false,
+            timeout_secs: spec.timeout,
+            language_detection: spec.language_detection.map(|detection| detection.reason),
+            labels: spec.labels,
+        }
+    }
+
+    /// Request running `code` as is, without a spec, e.g. for `run --eval`.
+    pub fn new_cloudlet_config_from_code(
+        workload_name: String,
+        language: Language,
+        code: String,
+    ) -> CloudletDtoRequest {
+        let (file_name, _) = scaffold::code_file(&language);
+        CloudletDtoRequest {
+            workload_name,
+            language,
+            code,
+            log_level: shared_models::LogLevel::INFO,
+            server: ServerConfig {
+                address: DEFAULT_SERVER_ADDRESS.into(),
+                port: DEFAULT_SERVER_PORT,
+            },
+            build: BuildConfig {
+                source_code_path: file_name.into(),
+                release: false,
+                features: Vec::new(),
+                compiler_flags: Vec::new(),
+                runtime_version: None,
+            },
+            action: cloudlet_spec::Action::default().to_string(),
+            resources: Default::default(),
+            devices: Vec::new(),
+            capabilities: Vec::new(),
+            node_affinity: Default::default(),
+            kernel: None,
+            priority_class: None,
+            tenant: None,
+            env: Default::default(),
+            secret_env: Vec::new(),
+            stages: Vec::new(),
+            webhooks: Vec::new(),
+            keep_on_failure: false,
+            timeout_secs: None,
+            language_detection: None,
+            labels: Default::default(),
+        }
+    }
+
+    /// Pipeline of the steps of `spec`, each running the workload with what the step overrides.
+    pub fn new_pipeline_from_spec(mut spec: WorkloadSpec) -> CloudletPipelineRequest {
+        let steps = std::mem::take(&mut spec.steps);
+        let name = spec.workload_name.clone();
+        let workload = Self::new_cloudlet_config_from_spec(spec);
+
+        let steps = steps
+            .into_iter()
+            .map(|step| {
+                let mut request = workload.clone();
+                request.workload_name = format!("{}-{}", name, step.name);
+                if let Some(code) = step.code {
+                    request.code = ConfigFileHandler::read_file(&code.path)
+                        .expect("Error while reading the code file");
+                    request.build.source_code_path = code.path;
+                    request.language_detection = None;
+                }
+                if let Some(language) = step.language {
+                    request.language = language;
+                }
+                if let Some(action) = step.action {
+                    request.action = action.to_string();
+                }
+                request.env.extend(step.env);
+                CloudletPipelineStep {
+                    name: step.name,
+                    depends_on: step.depends_on,
+                    outputs: step.outputs,
+                    request,
+                }
+            })
+            .collect();
+        CloudletPipelineRequest { name, steps }
+    }
+
+    /// Describe the language of `request`, and what gave it away if it was detected.
+    pub fn describe_language(request: &CloudletDtoRequest) -> String {
+        match &request.language_detection {
+            Some(reason) => format!("{} (detected from {})", request.language.as_str(), reason),
+            None => request.language.as_str().to_string(),
         }
     }
 
     pub async fn run(request: CloudletDtoRequest) -> Result<(), Box<dyn Error>> {
-        let client = Client::new();
-        let json = serde_json::to_string(&request)?;
-        let res = client
-            .post("http://127.0.0.1:3000/run")
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .body(json)
+        if request.language_detection.is_some() {
+            println!("Language: {}", Self::describe_language(&request));
+        }
+        let client = api_client();
+        let res = json_body(client.post(api_url("/run")), &request)
             .send()
             .await?;
 
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+        // The orchestrator is unreachable, the API queued the run.
+        if res.status() == reqwest::StatusCode::ACCEPTED {
+            let job = res.json::<CloudletJob>().await?;
+            println!(
+                "The orchestrator is unreachable, the run is queued as the job {}: follow it with `cli jobs show {}`",
+                job.id, job.id
+            );
+            return Ok(());
+        }
+
         println!("Response: {:?}", res.text().await?);
         Ok(())
     }
 
-    pub async fn shutdown() -> Result<bool, ()> {
-        let client = Client::new();
-        let response = client.post("http://127.0.0.1:3000/shutdown").send().await;
+    /// Run the steps of `pipeline`, printing their output prefixed with their name, and return
+    /// the outcome of the steps.
+    pub async fn run_pipeline(
+        pipeline: &CloudletPipelineRequest,
+    ) -> Result<CloudletPipelineSummary, Box<dyn Error>> {
+        let mut res = json_body(api_client().post(api_url("/pipelines")), pipeline)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        let mut summary = None;
+        Self::for_each_event(&mut res, |event: RunEvent| match &event.step {
+            Some(step) => Self::print_step_event(step, &event),
+            None => summary = summary.take().or(event.pipeline),
+        })
+        .await?;
+
+        let summary = summary.ok_or("The pipeline ended without the outcome of its steps")?;
+        for step in &summary.steps {
+            let exit_code = step
+                .exit_code
+                .map(|code| format!(" (exit code {})", code))
+                .unwrap_or_default();
+            match step.message.as_str() {
+                "" => println!("{}: {}{}", step.name, step.outcome, exit_code),
+                message => println!("{}: {}{}: {}", step.name, step.outcome, exit_code, message),
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Print an event of the step `step` of a pipeline, each line prefixed with its name.
+    fn print_step_event(step: &str, event: &RunEvent) {
+        if let Some(marker) = &event.marker {
+            eprintln!("[{}] ==> {} {}", step, marker.stage, marker.event);
+        }
+        if let Some(stdout) = event.stdout_text() {
+            for line in stdout.lines() {
+                println!("[{}] {}", step, line);
+            }
+        }
+        if let Some(stderr) = event.stderr_text() {
+            for line in stderr.lines() {
+                eprintln!("[{}] {}", step, line);
+            }
+        }
+        if let Some(error) = &event.error {
+            eprintln!("[{}] {}", step, error);
+        }
+    }
+
+    /// Print the steps of `pipeline`, in the order they are declared.
+    pub fn print_pipeline(pipeline: &CloudletPipelineRequest) {
+        println!("Pipeline:  {}", pipeline.name);
+        for step in &pipeline.steps {
+            println!(
+                "Step:      {} ({}, {:?})",
+                step.name,
+                Self::describe_language(&step.request),
+                step.request.build.source_code_path
+            );
+            if !step.depends_on.is_empty() {
+                println!("  after:   {}", step.depends_on.join(", "));
+            }
+            if !step.outputs.is_empty() {
+                println!("  outputs: {}", step.outputs.join(", "));
+            }
+        }
+    }
+
+    /// Submit a run and return the response as soon as the API starts streaming it. The run
+    /// fails rather than being queued while the orchestrator is unreachable.
+    pub async fn start_run(
+        request: &CloudletDtoRequest,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        json_body(api_client().post(api_url("/run")), request)
+            .header(QUEUE_HEADER, "never")
+            .send()
+            .await
+    }
+
+    /// Call `f` with each event of a response streaming server-sent events.
+    pub async fn for_each_event<T: DeserializeOwned>(
+        response: &mut reqwest::Response,
+        mut f: impl FnMut(T),
+    ) -> Result<(), reqwest::Error> {
+        // Events may be split across chunks, in the middle of a UTF-8 character too: the bytes are
+        // only decoded once their line is complete.
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+
+            while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let bytes: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&bytes);
+                let Some(data) = line.trim_end().strip_prefix("data: ") else {
+                    continue;
+                };
+                if let Ok(event) = serde_json::from_str::<T>(data) {
+                    f(event);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Print the output of the run of `vm`, a VM id or a workload name, following it if
+    /// `follow` is set. With `raw`, the output bytes are written untouched.
+    pub async fn logs(
+        vm: &str,
+        follow: bool,
+        tail: u32,
+        raw: bool,
+        filter: &LogFilter,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut query = vec![
+            ("follow", follow.to_string()),
+            ("tail_lines", tail.to_string()),
+        ];
+        if let Some(since) = filter.since {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+            query.push(("since", now.saturating_sub(since).as_secs().to_string()));
+        }
+        if let Some(grep) = &filter.grep {
+            query.push(("grep", grep.clone()));
+        }
+        if let Some(stream) = filter.stream {
+            query.push(("stream", stream.to_string()));
+        }
+        let mut res = api_client()
+            .get(api_url(&format!("/logs/{}", vm)))
+            .query(&query)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Self::print_output(&mut res, raw).await?;
+
+        Ok(())
+    }
+
+    /// Print the output of a run event, and the stage it starts or ends if any.
+    fn print_event(event: &RunEvent, raw: bool) {
+        if let Some(gap) = &event.gap {
+            eprintln!(
+                "[{} lines ({} bytes) of output skipped, read too slowly: `cli logs` has them]",
+                gap.dropped_lines, gap.dropped_bytes
+            );
+        }
+        if let Some(marker) = &event.marker {
+            eprintln!("==> {} {}", marker.stage, marker.event);
+        }
+        if raw {
+            if let Some(stdout) = event.stdout_bytes() {
+                let _ = std::io::stdout().write_all(&stdout);
+            }
+            if let Some(stderr) = event.stderr_bytes() {
+                let _ = std::io::stderr().write_all(&stderr);
+            }
+        } else {
+            if let Some(stdout) = event.stdout_text() {
+                print!("{}", stdout);
+            }
+            if let Some(stderr) = event.stderr_text() {
+                eprint!("{}", stderr);
+            }
+        }
+        if let Some(output) = event.output.as_ref().filter(|_| event.truncated) {
+            eprintln!("{}", output.truncation_note());
+        }
+        if let Some(error) = &event.error {
+            eprintln!("{}", error);
+        }
+        if event.build_cached {
+            eprintln!("Cache hit: ran the build of an earlier run of the same code and options");
+        }
+        if let Some(attempts) = event.attempts {
+            eprintln!(
+                "Ran on attempt {}, the earlier ones failed on infrastructure errors",
+                attempts
+            );
+        }
+        if let Some(signal) = &event.signal {
+            let core = if event.core_dumped {
+                ", core dumped"
+            } else {
+                ""
+            };
+            eprintln!("The workload was killed by {}{}", signal, core);
+        }
+        if !event.artifacts.is_empty() {
+            let names: Vec<&str> = event.artifacts.iter().map(|a| a.name.as_str()).collect();
+            eprintln!(
+                "Collected {}, downloaded with `cli artifacts <vm-id> <name>`",
+                names.join(", ")
+            );
+        }
+        // A VM which powered off at the end of its run isn't worth a line.
+        if let Some(exit) = event
+            .vm_exit
+            .as_ref()
+            .filter(|exit| exit.reason != "guest-shutdown")
+        {
+            eprintln!("VM exit: {}", exit.message);
+        }
+    }
+
+    /// Print the output streamed in the run events of `response`.
+    async fn print_output(
+        response: &mut reqwest::Response,
+        raw: bool,
+    ) -> Result<(), reqwest::Error> {
+        Self::for_each_event(response, |event: RunEvent| Self::print_event(&event, raw)).await
+    }
+
+    /// Fetch the dependencies of the workload of `request` and build it, printing the output.
+    /// Returns the id of the build, if the VMM cached it.
+    pub async fn build(mut request: CloudletDtoRequest) -> Result<Option<String>, Box<dyn Error>> {
+        request.stages = vec![PipelineStage::FetchDeps, PipelineStage::Build];
+        if request.language_detection.is_some() {
+            println!("Language: {}", Self::describe_language(&request));
+        }
+        let mut res = Self::start_run(&request).await?;
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        let mut build_id = None;
+        let mut failed = false;
+        Self::for_each_event(&mut res, |event: RunEvent| {
+            Self::print_event(&event, false);
+            failed |= event.stage == "Failed";
+            build_id = build_id.take().or(event.build_id);
+        })
+        .await?;
+
+        if failed {
+            return Err(format!("The build of {} failed", request.workload_name).into());
+        }
+        Ok(build_id)
+    }
+
+    pub async fn run_inputs(run_id: &str) -> Result<CloudletRunInputs, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url(&format!("/runs/{}/inputs", run_id)))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletRunInputs>().await?)
+    }
+
+    pub async fn run_debug_info(run_id: &str) -> Result<RunDebugInfo, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url(&format!("/runs/{}/debug", run_id)))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<RunDebugInfo>().await?)
+    }
+
+    /// The events of the output of the run of `vm` kept by the orchestrator, as the API sends
+    /// them.
+    pub async fn raw_logs(vm: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut res = api_client()
+            .get(api_url(&format!("/logs/{}", vm)))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        let mut events = Vec::new();
+        Self::for_each_event(&mut res, |event: serde_json::Value| {
+            events.push(event.to_string())
+        })
+        .await?;
+        Ok(events)
+    }
+
+    pub async fn run_artifacts(run_id: &str) -> Result<Vec<CloudletRunArtifact>, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url(&format!("/runs/{}/artifacts", run_id)))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<Vec<CloudletRunArtifact>>().await?)
+    }
+
+    /// Content of the artifact `name` collected from the run `run_id`.
+    pub async fn run_artifact(run_id: &str, name: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url(&format!("/runs/{}/artifacts/{}", run_id, name)))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.bytes().await?.to_vec())
+    }
+
+    /// Entries of the directory at `path` in the running VM `vm`.
+    pub async fn guest_dir(vm: &str, path: &str) -> Result<CloudletDirListing, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url(&format!("/vms/{}/fs", vm)))
+            .query(&[("path", path)])
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletDirListing>().await?)
+    }
+
+    /// Start of the file at `path` in the running VM `vm`.
+    pub async fn guest_file(
+        vm: &str,
+        path: &str,
+        max_bytes: Option<u64>,
+    ) -> Result<GuestFile, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url(&format!("/vms/{}/fs/content", vm)))
+            .query(&[
+                ("path", path.to_string()),
+                ("max_bytes", max_bytes.unwrap_or_default().to_string()),
+            ])
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        let header = |name: &str| {
+            res.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let truncated = header("x-cloudlet-truncated").as_deref() == Some("true");
+        let size = header("x-cloudlet-file-size").and_then(|size| size.parse().ok());
+        let content = res.bytes().await?.to_vec();
+        Ok(GuestFile {
+            size: size.unwrap_or(content.len() as u64),
+            truncated,
+            content,
+        })
+    }
+
+    /// WebSocket of a shell in the running VM `vm`, see the `shell` module of the API.
+    pub async fn shell_socket(
+        vm: &str,
+    ) -> Result<WebSocketStream<Box<dyn Socket>>, Box<dyn Error>> {
+        api_websocket(&format!("/vms/{}/shell", vm)).await
+    }
+
+    /// Run `run_id` again with its recorded inputs, printing its output.
+    pub async fn rerun(run_id: &str) -> Result<(), Box<dyn Error>> {
+        let mut res = api_client()
+            .post(api_url(&format!("/runs/{}/rerun", run_id)))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Self::print_output(&mut res, false).await?;
+
+        Ok(())
+    }
+
+    pub async fn register(
+        request: &CloudletRegisterRequest,
+    ) -> Result<CloudletWorkload, Box<dyn Error>> {
+        let res = json_body(api_client().post(api_url("/workloads")), request)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletWorkload>().await?)
+    }
+
+    pub async fn workloads(name: Option<&str>) -> Result<Vec<CloudletWorkload>, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url("/workloads"))
+            .query(&[("name", name.unwrap_or_default())])
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<Vec<CloudletWorkload>>().await?)
+    }
+
+    pub async fn vms(selector: Option<&str>) -> Result<Vec<CloudletVmMetrics>, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url("/vms"))
+            .query(&[("selector", selector.unwrap_or_default())])
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<Vec<CloudletVmMetrics>>().await?)
+    }
+
+    pub async fn runs(
+        selector: Option<&str>,
+        workload: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<Vec<CloudletRunRecord>, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url("/runs"))
+            .query(&[
+                ("selector", selector.unwrap_or_default().to_string()),
+                ("workload", workload.unwrap_or_default().to_string()),
+                ("limit", limit.unwrap_or_default().to_string()),
+            ])
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<Vec<CloudletRunRecord>>().await?)
+    }
+
+    pub async fn pools(name: Option<&str>) -> Result<Vec<CloudletPool>, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url("/pools"))
+            .query(&[("name", name.unwrap_or_default())])
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<Vec<CloudletPool>>().await?)
+    }
+
+    pub async fn upgrade_pools(
+        request: &CloudletUpgradePoolsRequest,
+    ) -> Result<Vec<CloudletPool>, Box<dyn Error>> {
+        let res = json_body(api_client().post(api_url("/pools/upgrade")), request)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<Vec<CloudletPool>>().await?)
+    }
+
+    fn usage_request(
+        tenant: Option<&str>,
+        since: Option<u64>,
+        until: Option<u64>,
+        records: bool,
+        format: &str,
+    ) -> reqwest::RequestBuilder {
+        api_client().get(api_url("/usage")).query(&[
+            ("tenant", tenant.unwrap_or_default().to_string()),
+            ("since", since.unwrap_or_default().to_string()),
+            ("until", until.unwrap_or_default().to_string()),
+            ("records", records.to_string()),
+            ("format", format.to_string()),
+        ])
+    }
+
+    pub async fn usage(
+        tenant: Option<&str>,
+        since: Option<u64>,
+        until: Option<u64>,
+        records: bool,
+    ) -> Result<CloudletUsage, Box<dyn Error>> {
+        let res = Self::usage_request(tenant, since, until, records, "json")
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletUsage>().await?)
+    }
+
+    pub async fn usage_csv(
+        tenant: Option<&str>,
+        since: Option<u64>,
+        until: Option<u64>,
+        records: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        let res = Self::usage_request(tenant, since, until, records, "csv")
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.text().await?)
+    }
+
+    /// Run the registered workload `name` with `request`, printing its output.
+    pub async fn invoke(name: &str, request: &CloudletInvokeRequest) -> Result<(), Box<dyn Error>> {
+        let mut res = json_body(
+            api_client().post(api_url(&format!("/workloads/{}/invoke", name))),
+            request,
+        )
+        .send()
+        .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Self::print_output(&mut res, false).await?;
+
+        Ok(())
+    }
+
+    /// Call the registered workload `name` with the JSON `data`, and return its JSON result.
+    pub async fn call(
+        name: &str,
+        version: Option<&str>,
+        data: String,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut request = api_client()
+            .post(api_url(&format!("/workloads/{}/call", name)))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(reqwest::header::ACCEPT, "application/json")
+            .body(data);
+        if let Some(version) = version {
+            request = request.query(&[("version", version)]);
+        }
+        let res = request.send().await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.text().await?)
+    }
+
+    pub fn print_workload(workload: &CloudletWorkload) {
+        let cache = match workload.cache_ttl_secs {
+            Some(ttl_secs) => format!(" cache_ttl={}s", ttl_secs),
+            None => String::new(),
+        };
+        println!(
+            "{} version={} registered_at={} code={}{}",
+            workload.name, workload.version, workload.registered_at, workload.code_digest, cache
+        );
+    }
+
+    fn format_labels(labels: &BTreeMap<String, String>) -> String {
+        labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn print_vm(vm: &CloudletVmMetrics) {
+        println!(
+            "{} workload={} language={} cpus={} memory_mb={} priority_class={} labels={}",
+            vm.vm_id,
+            vm.workload_name,
+            vm.language,
+            vm.cpus,
+            vm.memory_mb,
+            vm.priority_class,
+            Self::format_labels(&vm.labels)
+        );
+    }
+
+    pub fn print_run(run: &CloudletRunRecord) {
+        println!(
+            "{} workload={} tenant={} outcome={} started_at={} finished_at={} labels={}{}{}",
+            run.run_id,
+            run.workload_name,
+            run.tenant,
+            run.outcome,
+            run.started_at,
+            run.finished_at,
+            Self::format_labels(&run.labels),
+            if run.emulated { " emulated=true" } else { "" },
+            if run.message.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", run.message)
+            }
+        );
+    }
+
+    pub fn print_pool(pool: &CloudletPool) {
+        println!(
+            "{} version={} instances={} min={} max={} busy={} in_flight={} queued={} cold_starts={} warm_starts={} scale_ups={} scale_downs={} generation={} outdated={} upgrades={}",
+            pool.name,
+            pool.version,
+            pool.instances,
+            pool.min_instances,
+            pool.max_instances,
+            pool.busy,
+            pool.in_flight,
+            pool.queued,
+            pool.cold_starts,
+            pool.warm_starts,
+            pool.scale_ups,
+            pool.scale_downs,
+            pool.generation,
+            pool.outdated,
+            pool.upgrades
+        );
+    }
+
+    pub fn print_tenant_usage(usage: &CloudletTenantUsage) {
+        println!(
+            "{} vms={} vcpu_seconds={:.1} memory_gb_seconds={:.1} egress_bytes={} build_seconds={:.1}",
+            usage.tenant,
+            usage.vms,
+            usage.vcpu_seconds,
+            usage.memory_gb_seconds,
+            usage.egress_bytes,
+            usage.build_seconds
+        );
+    }
+
+    pub fn print_usage_record(record: &CloudletUsageRecord) {
+        println!(
+            "{} tenant={} workload={} started_at={} finished_at={} vcpu_seconds={:.1} memory_gb_seconds={:.1} egress_bytes={} build_seconds={:.1}",
+            record.vm_id,
+            record.tenant,
+            record.workload_name,
+            record.started_at,
+            record.finished_at,
+            record.vcpu_seconds,
+            record.memory_gb_seconds,
+            record.egress_bytes,
+            record.build_seconds
+        );
+    }
+
+    /// Print the events of the VMs as they happen, until interrupted.
+    pub async fn watch_events(
+        workload_name: Option<String>,
+        vm: Option<String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut query = Vec::new();
+        if let Some(workload_name) = workload_name {
+            query.push(("workload_name", workload_name));
+        }
+        if let Some(vm) = vm {
+            query.push(("vm", vm));
+        }
+
+        let mut res = api_client()
+            .get(api_url("/events"))
+            .query(&query)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Self::for_each_event(&mut res, |event: VmEvent| {
+            println!(
+                "{} vm={} workload={} {} {}",
+                event.timestamp, event.vm_id, event.workload_name, event.kind, event.message
+            );
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Query a health probe of the API, `/healthz` or `/readyz`: the reason it failed, if it did.
+    pub async fn probe(path: &str) -> Result<Option<String>, Box<dyn Error>> {
+        #[derive(Deserialize)]
+        struct Probe {
+            reason: Option<String>,
+        }
+
+        let res = api_client()
+            .get(api_url(path))
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?;
+
+        if res.status().is_success() {
+            return Ok(None);
+        }
+        let status = res.status();
+        Ok(Some(match res.json::<Probe>().await {
+            Ok(Probe {
+                reason: Some(reason),
+            }) => reason,
+            _ => status.to_string(),
+        }))
+    }
+
+    pub async fn server_info() -> Result<CloudletServerInfo, Box<dyn Error>> {
+        let res = api_client().get(api_url("/info")).send().await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletServerInfo>().await?)
+    }
+
+    pub async fn plan(
+        request: &CloudletDtoRequest,
+    ) -> Result<CloudletPlanResponse, Box<dyn Error>> {
+        let client = api_client();
+        let res = json_body(client.post(api_url("/plan")), request)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletPlanResponse>().await?)
+    }
+
+    /// Create `schedule`, or replace the existing one of the same name if `update` is set.
+    pub async fn put_schedule(
+        schedule: &CloudletScheduleRequest,
+        update: bool,
+    ) -> Result<CloudletSchedule, Box<dyn Error>> {
+        let client = api_client();
+        let request = if update {
+            client.put(api_url(&format!("/schedules/{}", schedule.name)))
+        } else {
+            client.post(api_url("/schedules"))
+        };
+        let res = request.json(schedule).send().await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletSchedule>().await?)
+    }
+
+    pub async fn schedules() -> Result<Vec<CloudletSchedule>, Box<dyn Error>> {
+        let res = api_client().get(api_url("/schedules")).send().await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<Vec<CloudletSchedule>>().await?)
+    }
+
+    pub async fn schedule(name: &str) -> Result<CloudletSchedule, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url(&format!("/schedules/{}", name)))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletSchedule>().await?)
+    }
+
+    pub async fn delete_schedule(name: &str) -> Result<(), Box<dyn Error>> {
+        let res = api_client()
+            .delete(api_url(&format!("/schedules/{}", name)))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(())
+    }
+
+    pub async fn jobs() -> Result<CloudletJobQueue, Box<dyn Error>> {
+        let res = api_client().get(api_url("/jobs")).send().await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletJobQueue>().await?)
+    }
+
+    pub async fn job(id: u64) -> Result<CloudletJob, Box<dyn Error>> {
+        let res = api_client()
+            .get(api_url(&format!("/jobs/{}", id)))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletJob>().await?)
+    }
+
+    pub async fn drain_jobs() -> Result<CloudletDrainResponse, Box<dyn Error>> {
+        let res = api_client().post(api_url("/jobs/drain")).send().await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletDrainResponse>().await?)
+    }
+
+    /// Cordon the node of the VMM with `node/cordon`, uncordon it with `node/uncordon`, or
+    /// drain it with `node/drain`.
+    pub async fn node(
+        action: &str,
+        request: &CloudletNodeRequest,
+    ) -> Result<CloudletNodeStatus, Box<dyn Error>> {
+        let res = json_body(api_client().post(api_url(&format!("/{}", action))), request)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Self::api_error(res).await);
+        }
+
+        Ok(res.json::<CloudletNodeStatus>().await?)
+    }
+
+    pub fn print_node(status: &CloudletNodeStatus) {
+        if status.cordoned {
+            println!(
+                "cordoned reason={} cordoned_at={}",
+                status.reason, status.cordoned_at
+            );
+        } else {
+            println!("schedulable");
+        }
+        for vm in &status.running_vms {
+            println!("running {}", vm);
+        }
+        for vm in &status.killed_vms {
+            println!("killed {}", vm);
+        }
+    }
+
+    pub fn print_job(job: &CloudletJob) {
+        let exit_code = job
+            .exit_code
+            .map_or("-".to_string(), |code| code.to_string());
+        println!(
+            "{} {} workload={} queued_at={} attempts={} exit_code={} {}",
+            job.id,
+            job.state,
+            job.workload_name,
+            job.queued_at,
+            job.attempts,
+            exit_code,
+            job.message
+        );
+    }
+
+    /// Print `schedule`, with its last runs if `runs` is set.
+    pub fn print_schedule(schedule: &CloudletSchedule, runs: bool) {
+        let next_run = match schedule.next_run {
+            Some(next_run) => next_run.to_string(),
+            None => "disabled".into(),
+        };
+        let last_run = schedule
+            .runs
+            .first()
+            .map_or("never run", |run| run.outcome.as_str());
+        println!(
+            "{} cron={:?} workload={} next_run={} last_run={}",
+            schedule.name, schedule.cron, schedule.workload_name, next_run, last_run
+        );
+        if !runs {
+            return;
+        }
+
+        for run in &schedule.runs {
+            let exit_code = run
+                .exit_code
+                .map_or("-".to_string(), |code| code.to_string());
+            println!(
+                "  {} {} exit_code={} {}",
+                run.started_at, run.outcome, exit_code, run.message
+            );
+        }
+    }
+
+    /// Turn an error response of the API into an error describing it, with its code and hint.
+    pub async fn api_error(res: reqwest::Response) -> Box<dyn Error + Send + Sync> {
+        let status = res.status();
+        let body = match res.text().await {
+            Ok(body) => body,
+            Err(e) => return e.into(),
+        };
+        debug!(%status, %body, "error answered by the API");
+
+        match serde_json::from_str::<CloudletErrorResponse>(&body) {
+            Ok(error) => error.into(),
+            Err(_) => format!("{}: {}", status, body).into(),
+        }
+    }
+
+    /// Print the plan for `request`, as resolved locally and, if available, by the server.
+    pub fn print_plan(
+        request: &CloudletDtoRequest,
+        plan: Result<CloudletPlanResponse, Box<dyn Error>>,
+    ) {
+        println!("Workload:  {}", request.workload_name);
+        println!("Language:  {}", Self::describe_language(request));
+        println!("Action:    {}", request.action);
+        println!(
+            "Code:      {:?} ({} bytes)",
+            request.build.source_code_path,
+            request.code.len()
+        );
+        println!(
+            "Resources: {} vCPU(s), {} MB",
+            request.resources.cpus, request.resources.memory_mb
+        );
+        let mut env = request.env.clone();
+        redact_env(env.iter_mut(), &request.secret_env);
+        for (name, value) in env {
+            println!("Env:       {}={}", name, value);
+        }
+
+        match plan {
+            Ok(plan) => {
+                println!("Image:     {}", plan.image);
+                println!("Kernel:    {}", Self::describe_artifact(&plan.kernel));
+                println!("Initramfs: {}", Self::describe_artifact(&plan.initramfs));
+                if plan.registry_pull {
+                    println!("Registry:  the rootfs image will be pulled");
+                }
+                println!(
+                    "Build:     {}",
+                    if plan.build_cached {
+                        "cached, fetch and build skipped"
+                    } else {
+                        "not cached"
+                    }
+                );
+                println!(
+                    "Pool:      {}",
+                    if plan.warm_pool_hit {
+                        "an idle VM is warm"
+                    } else {
+                        "no idle VM, cold start"
+                    }
+                );
+            }
+            Err(e) => println!("Server plan unavailable: {}", e),
+        }
+
+        println!("Dry run: no VM was started.");
+    }
+
+    fn describe_artifact(artifact: &ArtifactPlan) -> String {
+        if artifact.cached {
+            format!("{} (cached)", artifact.path)
+        } else {
+            format!("{} (will be built)", artifact.path)
+        }
+    }
+
+    pub async fn shutdown(vm: Option<String>) -> Result<bool, ()> {
+        let client = api_client();
+        let mut request = client.post(api_url("/shutdown"));
+        if let Some(vm) = vm {
+            request = request.json(&serde_json::json!({ "id": vm }));
+        }
+        let response = request.send().await;
 
         let shutdown_response: CloudletShutdownResponse = response
             .unwrap()
@@ -0,0 +1,90 @@
+//! Interactive shell in a running VM, over a WebSocket of the API.
+//!
+//! The terminal is put in raw mode, so that every key, `Ctrl-C` included, goes to the shell;
+//! the session ends when the shell exits, with its exit code.
+
+use crate::services::{CloudletClient, Socket};
+use crossterm::terminal::{self, disable_raw_mode, enable_raw_mode};
+use futures_util::{SinkExt, StreamExt};
+use shared_models::CloudletShellMessage;
+use std::error::Error;
+use std::io::{self, Write};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+/// How often the size of the terminal is checked, to resize the one of the shell.
+const RESIZE_INTERVAL: Duration = Duration::from_millis(250);
+
+fn text(message: &CloudletShellMessage) -> Message {
+    Message::Text(serde_json::to_string(message).unwrap())
+}
+
+/// Run `command`, a shell if empty, in the VM `vm` until it exits, returning its exit code.
+pub async fn run(vm: &str, command: Vec<String>) -> Result<i32, Box<dyn Error>> {
+    let mut socket = CloudletClient::shell_socket(vm).await?;
+    let (cols, rows) = terminal::size().unwrap_or((80, 24));
+    socket
+        .send(text(&CloudletShellMessage::Start {
+            command,
+            rows: rows.into(),
+            cols: cols.into(),
+            term: std::env::var("TERM").unwrap_or_default(),
+        }))
+        .await?;
+
+    enable_raw_mode()?;
+    let result = relay(&mut socket, (cols, rows)).await;
+    disable_raw_mode()?;
+    let _ = socket.close(None).await;
+    result
+}
+
+async fn relay(
+    socket: &mut WebSocketStream<Box<dyn Socket>>,
+    mut size: (u16, u16),
+) -> Result<i32, Box<dyn Error>> {
+    let mut stdin = tokio::io::stdin();
+    let mut stdin_open = true;
+    let mut stdout = io::stdout();
+    let mut buffer = vec![0; 4096];
+    let mut resize = tokio::time::interval(RESIZE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            read = stdin.read(&mut buffer), if stdin_open => match read {
+                Ok(0) | Err(_) => stdin_open = false,
+                Ok(read) => socket.send(Message::Binary(buffer[..read].to_vec())).await?,
+            },
+            message = socket.next() => match message {
+                Some(Ok(Message::Binary(data))) => {
+                    stdout.write_all(&data)?;
+                    stdout.flush()?;
+                }
+                Some(Ok(Message::Text(message))) => match serde_json::from_str(&message) {
+                    Ok(CloudletShellMessage::Exit { exit_code }) => return Ok(exit_code),
+                    Ok(CloudletShellMessage::Error { message }) => return Err(message.into()),
+                    _ => {}
+                },
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err("The API closed the session".into())
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+            _ = resize.tick() => {
+                let new_size = terminal::size().unwrap_or(size);
+                if new_size != size {
+                    size = new_size;
+                    let (cols, rows) = size;
+                    socket
+                        .send(text(&CloudletShellMessage::Resize {
+                            rows: rows.into(),
+                            cols: cols.into(),
+                        }))
+                        .await?;
+                }
+            }
+        }
+    }
+}
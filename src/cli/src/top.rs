@@ -0,0 +1,256 @@
+//! Live view of the running VMs and of the pools of the registered workloads, refreshed from
+//! the API like `top`.
+
+use crate::services::CloudletClient;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout},
+    style::{Modifier, Style},
+    widgets::{Block, Borders, Paragraph, Row, Table},
+    Frame, Terminal,
+};
+use shared_models::{CloudletPool, CloudletVmMetrics};
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{self, Stdout},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+type Backend = CrosstermBackend<Stdout>;
+
+/// What is shown, from the last refresh.
+#[derive(Default)]
+struct View {
+    /// Running VMs, with the share of their vCPUs they used since the previous refresh.
+    vms: Vec<(CloudletVmMetrics, f64)>,
+    pools: Vec<CloudletPool>,
+    /// Why the last refresh failed, the previous data being kept.
+    error: Option<String>,
+}
+
+/// Show the view until `q`, `Esc` or `Ctrl-C` is pressed, refreshing it every `interval`.
+pub async fn run(interval: Duration) -> Result<(), Box<dyn Error>> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let result = refresh_loop(&mut terminal, interval).await;
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+async fn refresh_loop(
+    terminal: &mut Terminal<Backend>,
+    interval: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut view = View::default();
+    // CPU time of each VM at the previous refresh.
+    let mut previous: HashMap<String, (f64, Instant)> = HashMap::new();
+
+    loop {
+        let (vms, pools) = tokio::join!(CloudletClient::vms(None), CloudletClient::pools(None));
+        match (vms, pools) {
+            (Ok(vms), Ok(pools)) => {
+                let now = Instant::now();
+                view.vms = vms
+                    .into_iter()
+                    .map(|vm| {
+                        let usage = match previous.get(&vm.vm_id) {
+                            Some((cpu_seconds, at)) => cpu_share(
+                                vm.cpu_seconds - cpu_seconds,
+                                now.duration_since(*at).as_secs_f64(),
+                                vm.cpus,
+                            ),
+                            None => cpu_share(vm.cpu_seconds, uptime(vm.started_at), vm.cpus),
+                        };
+                        (vm, usage)
+                    })
+                    .collect();
+                previous = view
+                    .vms
+                    .iter()
+                    .map(|(vm, _)| (vm.vm_id.clone(), (vm.cpu_seconds, now)))
+                    .collect();
+                view.pools = pools;
+                view.error = None;
+            }
+            (Err(e), _) | (_, Err(e)) => view.error = Some(e.to_string()),
+        }
+
+        terminal.draw(|frame| draw(frame, &view, interval))?;
+        if tokio::task::block_in_place(|| quit_pressed(interval))? {
+            return Ok(());
+        }
+    }
+}
+
+/// Wait for `timeout`, returning early if a key quitting the view is pressed.
+fn quit_pressed(timeout: Duration) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || !event::poll(remaining)? {
+            return Ok(false);
+        }
+        if let Event::Key(key) = event::read()? {
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.kind == KeyEventKind::Press
+                && (ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc))
+            {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, view: &View, interval: Duration) {
+    let areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(1),
+            Constraint::Min(3),
+            Constraint::Length(view.pools.len().clamp(1, 10) as u16 + 3),
+        ])
+        .split(frame.size());
+
+    let (cpus, memory_mb) = view.vms.iter().fold((0, 0), |(cpus, memory), (vm, _)| {
+        (cpus + vm.cpus, memory + vm.memory_mb)
+    });
+    let queued: u32 = view.pools.iter().map(|pool| pool.queued).sum();
+    let summary = format!(
+        "{} VM(s), {} vCPU(s), {} MB | {} pool(s), {} queued | every {}s, q to quit{}",
+        view.vms.len(),
+        cpus,
+        memory_mb,
+        view.pools.len(),
+        queued,
+        interval.as_secs(),
+        view.error
+            .as_ref()
+            .map(|e| format!(" | {}", e))
+            .unwrap_or_default()
+    );
+    frame.render_widget(Paragraph::new(summary), areas[0]);
+
+    let bold = Style::default().add_modifier(Modifier::BOLD);
+    let vms = Table::new(
+        view.vms.iter().map(|(vm, usage)| {
+            Row::new(vec![
+                vm.vm_id.clone(),
+                vm.language.clone(),
+                vm.cpus.to_string(),
+                format!("{:.1}%", usage * 100.0),
+                format!("{} MB", vm.memory_mb),
+                format_duration(uptime(vm.started_at)),
+                format_bytes(vm.egress_bytes),
+                vm.priority_class.clone(),
+            ])
+        }),
+        [
+            Constraint::Min(24),
+            Constraint::Length(8),
+            Constraint::Length(5),
+            Constraint::Length(7),
+            Constraint::Length(10),
+            Constraint::Length(9),
+            Constraint::Length(10),
+            Constraint::Min(8),
+        ],
+    )
+    .header(
+        Row::new(vec![
+            "VM", "LANGUAGE", "VCPUS", "CPU", "MEMORY", "UPTIME", "EGRESS", "CLASS",
+        ])
+        .style(bold),
+    )
+    .block(Block::default().borders(Borders::ALL).title("VMs"));
+    frame.render_widget(vms, areas[1]);
+
+    let pools = Table::new(
+        view.pools.iter().map(|pool| {
+            Row::new(vec![
+                pool.name.clone(),
+                pool.version.clone(),
+                format!("{}/{}", pool.busy, pool.instances),
+                format!("{}..{}", pool.min_instances, pool.max_instances),
+                pool.in_flight.to_string(),
+                pool.queued.to_string(),
+                format!("{}/{}", pool.cold_starts, pool.warm_starts),
+            ])
+        }),
+        [
+            Constraint::Min(16),
+            Constraint::Length(10),
+            Constraint::Length(9),
+            Constraint::Length(8),
+            Constraint::Length(9),
+            Constraint::Length(7),
+            Constraint::Min(11),
+        ],
+    )
+    .header(
+        Row::new(vec![
+            "WORKLOAD",
+            "VERSION",
+            "BUSY",
+            "BOUNDS",
+            "IN FLIGHT",
+            "QUEUED",
+            "COLD/WARM",
+        ])
+        .style(bold),
+    )
+    .block(Block::default().borders(Borders::ALL).title("Pools"));
+    frame.render_widget(pools, areas[2]);
+}
+
+/// Share of `cpus` vCPUs busy for `cpu_seconds` over `seconds`.
+fn cpu_share(cpu_seconds: f64, seconds: f64, cpus: u32) -> f64 {
+    if seconds <= 0.0 || cpus == 0 {
+        return 0.0;
+    }
+    (cpu_seconds / seconds / f64::from(cpus)).clamp(0.0, 1.0)
+}
+
+/// Seconds since `started_at`, in seconds since the Unix epoch.
+fn uptime(started_at: u64) -> f64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    now.saturating_sub(started_at) as f64
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
@@ -0,0 +1,114 @@
+//! Errors of the clients, carrying the Cloudlet error codes given by the servers.
+
+use shared_models::{CloudletErrorResponse, ErrorCode};
+use std::{error::Error, fmt};
+use tonic::Code;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The endpoint isn't a URL, or is a Unix socket on a host without them.
+    InvalidEndpoint(String),
+    /// The API couldn't be reached, or the connection to it was lost.
+    Http(reqwest::Error),
+    /// The API answered with an error.
+    Api {
+        status: u16,
+        error: CloudletErrorResponse,
+    },
+    /// The orchestrator couldn't be reached.
+    Transport(tonic::transport::Error),
+    /// The orchestrator answered with an error.
+    Grpc(tonic::Status),
+    /// A response of the API couldn't be decoded.
+    Decode(serde_json::Error),
+}
+
+impl ClientError {
+    /// Code of the error, if the server gave one.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            ClientError::Api { error, .. } => error.code.as_deref().and_then(ErrorCode::from_code),
+            ClientError::Grpc(status) => ErrorCode::from_status(status),
+            _ => None,
+        }
+    }
+
+    /// Whether the request may succeed once sent again: the server couldn't be reached, or
+    /// was unavailable or had no room for the run.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::Http(e) => e.is_connect() || e.is_timeout(),
+            ClientError::Api { status, .. } => matches!(status, 429 | 502 | 503 | 504),
+            ClientError::Transport(_) => true,
+            ClientError::Grpc(status) => {
+                status.code() == Code::Unavailable
+                    || ErrorCode::from_status(status) == Some(ErrorCode::VmmHostFull)
+            }
+            ClientError::InvalidEndpoint(_) | ClientError::Decode(_) => false,
+        }
+    }
+
+    /// Whether the request never reached the server, so that sending it again can't run it
+    /// twice.
+    pub fn is_unsent(&self) -> bool {
+        match self {
+            ClientError::Http(e) => e.is_connect(),
+            ClientError::Transport(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidEndpoint(e) => write!(f, "Invalid endpoint: {}", e),
+            ClientError::Http(e) => write!(f, "Could not reach the API: {}", e),
+            ClientError::Api { status, error } => match &error.code {
+                Some(code) => write!(f, "[{}] {} (HTTP {})", code, error.message, status),
+                None => write!(f, "{} (HTTP {})", error.message, status),
+            },
+            ClientError::Transport(e) => write!(f, "Could not reach the orchestrator: {}", e),
+            ClientError::Grpc(status) => match ErrorCode::from_status(status) {
+                Some(code) => write!(f, "[{}] {}", code, status.message()),
+                None => write!(f, "{}", status.message()),
+            },
+            ClientError::Decode(e) => write!(f, "Invalid response: {}", e),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::Http(e) => Some(e),
+            ClientError::Transport(e) => Some(e),
+            ClientError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+impl From<tonic::transport::Error> for ClientError {
+    fn from(e: tonic::transport::Error) -> Self {
+        ClientError::Transport(e)
+    }
+}
+
+impl From<tonic::Status> for ClientError {
+    fn from(status: tonic::Status) -> Self {
+        ClientError::Grpc(status)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> Self {
+        ClientError::Decode(e)
+    }
+}
@@ -0,0 +1,218 @@
+//! Events streamed by the API while a workload runs, one `data: <json>` line each.
+
+use crate::ClientError;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use shared_models::{CloudletErrorResponse, CloudletRunArtifact, CloudletVmExit};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Encoding of the output in the events.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputEncoding {
+    #[default]
+    Utf8,
+    /// Output which isn't valid UTF-8.
+    Base64,
+}
+
+/// Event of a run: output of the workload, or the end of the run.
+#[derive(Debug, Deserialize)]
+pub struct RunEvent {
+    /// `Pending`, `Building`, `Running`, `Done` or `Failed`.
+    pub stage: String,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    #[serde(default)]
+    pub encoding: OutputEncoding,
+    pub exit_code: Option<i32>,
+    /// Whether output was cut at this point, or, on the final event, at all.
+    #[serde(default)]
+    pub truncated: bool,
+    /// Why the orchestrator ended the run, e.g. when it was preempted.
+    #[serde(default)]
+    pub error: Option<CloudletErrorResponse>,
+    /// Id of the cached build, on the end of the build stage.
+    #[serde(default)]
+    pub build_id: Option<String>,
+    /// Attempts the run took when it was retried, on the final event.
+    #[serde(default)]
+    pub attempts: Option<u32>,
+    /// Signal which killed the workload, on the final event.
+    #[serde(default)]
+    pub signal: Option<String>,
+    #[serde(default)]
+    pub core_dumped: bool,
+    /// Files collected to debug the run, on the final event.
+    #[serde(default)]
+    pub artifacts: Vec<CloudletRunArtifact>,
+    /// How the VM stopped, on the final event of a run whose VM doesn't outlive it.
+    #[serde(default)]
+    pub vm_exit: Option<CloudletVmExit>,
+    /// Step of the pipeline the event is about, on the events of a pipeline.
+    #[serde(default)]
+    pub step: Option<String>,
+}
+
+impl RunEvent {
+    fn decode(&self, output: &Option<String>) -> Option<Vec<u8>> {
+        let output = output.as_ref()?;
+        match self.encoding {
+            OutputEncoding::Utf8 => Some(output.clone().into_bytes()),
+            OutputEncoding::Base64 => BASE64_STANDARD.decode(output).ok(),
+        }
+    }
+
+    /// Bytes written by the workload to its stdout.
+    pub fn stdout_bytes(&self) -> Option<Vec<u8>> {
+        self.decode(&self.stdout)
+    }
+
+    /// Bytes written by the workload to its stderr.
+    pub fn stderr_bytes(&self) -> Option<Vec<u8>> {
+        self.decode(&self.stderr)
+    }
+
+    /// Whether the event ends the run.
+    pub fn is_final(&self) -> bool {
+        matches!(self.stage.as_str(), "Done" | "Failed")
+    }
+}
+
+/// Splits the chunks of a response into its `data:` lines, which may span chunks.
+#[derive(Default)]
+struct LineParser {
+    buffer: Vec<u8>,
+}
+
+impl LineParser {
+    /// The events completed by `chunk`.
+    fn push(&mut self, chunk: &[u8]) -> Vec<Result<RunEvent, ClientError>> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(data) = line.trim_end().strip_prefix("data: ") {
+                events.push(serde_json::from_str(data).map_err(ClientError::from));
+            }
+        }
+        events
+    }
+}
+
+type Chunks = Pin<Box<dyn Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Send>>;
+
+/// Events of a run, as the API streams them.
+pub struct RunStream {
+    chunks: Chunks,
+    parser: LineParser,
+    pending: VecDeque<Result<RunEvent, ClientError>>,
+}
+
+impl RunStream {
+    pub(crate) fn new(response: reqwest::Response) -> Self {
+        Self {
+            chunks: Box::pin(response.bytes_stream()),
+            parser: LineParser::default(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl Stream for RunStream {
+    type Item = Result<RunEvent, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            match self.chunks.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    let events = self.parser.push(&chunk);
+                    self.pending.extend(events);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Output of a whole run.
+#[derive(Debug, Default)]
+pub struct RunOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: Option<i32>,
+    /// Whether the run failed, its workload not built or not run to its end.
+    pub failed: bool,
+    /// Why the orchestrator ended the run, if it did.
+    pub error: Option<CloudletErrorResponse>,
+    /// The final event of the run, if the stream reached it.
+    pub last_event: Option<RunEvent>,
+}
+
+impl RunOutput {
+    /// Gather the output of `events` until the end of the run.
+    pub async fn collect(
+        mut events: impl Stream<Item = Result<RunEvent, ClientError>> + Unpin,
+    ) -> Result<Self, ClientError> {
+        let mut output = Self::default();
+        while let Some(event) = events.next().await {
+            let event = event?;
+            output
+                .stdout
+                .extend(event.stdout_bytes().unwrap_or_default());
+            output
+                .stderr
+                .extend(event.stderr_bytes().unwrap_or_default());
+            if event.is_final() {
+                output.exit_code = event.exit_code;
+                output.failed = event.stage == "Failed";
+                output.error = event.error.clone();
+                output.last_event = Some(event);
+                break;
+            }
+        }
+        Ok(output)
+    }
+
+    /// Whether the workload ran to its end and exited with 0.
+    pub fn success(&self) -> bool {
+        !self.failed && self.exit_code == Some(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse_events() {
+        let mut parser = LineParser::default();
+        assert!(parser
+            .push(b"data: {\"stage\":\"Running\",\"stdout\":\"hel")
+            .is_empty());
+        let mut events =
+            parser.push(b"lo\\n\",\"stderr\":null,\"exit_code\":null}\n\n: keep-alive\n");
+        events.extend(parser.push(
+            b"data: {\"stage\":\"Running\",\"stdout\":\"/w==\",\"encoding\":\"base64\"}\ndata: {\"stage\":\"Done\",\"exit_code\":3}\n",
+        ));
+        assert_eq!(events.len(), 3);
+        assert!(parser.push(b"data: {not json}\n")[0].is_err());
+
+        let output = RunOutput::collect(futures_util::stream::iter(events))
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"hello\n\xff");
+        assert_eq!(output.exit_code, Some(3));
+        assert!(!output.failed);
+        assert!(!output.success());
+    }
+}
@@ -0,0 +1,229 @@
+//! Client of the gRPC service of the orchestrator, for the services running next to it.
+
+use crate::{ClientError, RetryPolicy};
+use shared_models::cloudlet::agent::ExecuteResponse;
+use shared_models::vmmorchestrator::{
+    vmm_service_client::VmmServiceClient, GetServerInfoRequest, InvokeWorkloadRequest,
+    ListPoolsRequest, ListRunsRequest, ListVmMetricsRequest, ListWorkloadsRequest, PoolStatus,
+    RegisterWorkloadRequest, RegisteredWorkload, RunPlan, RunRecord, RunVmmRequest, ServerInfo,
+    ShutdownVmRequest, ShutdownVmResponse, StreamLogsRequest, UpgradePoolsRequest, VmEvent,
+    VmMetrics, WatchEventsRequest,
+};
+use shared_models::{unix_socket, AGENT_MAX_MESSAGE_SIZE};
+use std::time::Duration;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Streaming;
+
+/// How long connecting to the orchestrator may take, for each attempt.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Client of the orchestrator.
+///
+/// Connecting and the reads are retried with its [`RetryPolicy`]. The runs and the other
+/// requests changing the orchestrator are sent once, since nothing tells whether a failed one
+/// reached it.
+#[derive(Clone)]
+pub struct GrpcClient {
+    client: VmmServiceClient<Channel>,
+    retry: RetryPolicy,
+}
+
+impl GrpcClient {
+    /// Connect to the orchestrator at `endpoint`, e.g. `http://[::1]:50051`, or on the Unix
+    /// socket of a `unix://<PATH>` one.
+    pub async fn connect(endpoint: &str) -> Result<Self, ClientError> {
+        Self::connect_with_retry(endpoint, RetryPolicy::default()).await
+    }
+
+    /// Connect to the orchestrator at `endpoint`, retrying the requests with `retry`.
+    pub async fn connect_with_retry(
+        endpoint: &str,
+        retry: RetryPolicy,
+    ) -> Result<Self, ClientError> {
+        let channel = retry.run(|| Self::channel(endpoint)).await?;
+        // The artifacts of a run, e.g. a core dump, are sent in one message.
+        let client = shared_models::compressed!(
+            VmmServiceClient::new(channel).max_decoding_message_size(AGENT_MAX_MESSAGE_SIZE)
+        );
+        Ok(Self { client, retry })
+    }
+
+    async fn channel(endpoint: &str) -> Result<Channel, ClientError> {
+        match unix_socket::socket_path(endpoint) {
+            Some(path) if path.as_os_str().is_empty() => Err(ClientError::InvalidEndpoint(
+                format!("{} has no path", endpoint),
+            )),
+            #[cfg(unix)]
+            Some(path) => {
+                let path = path.to_path_buf();
+                // The URI is ignored, every connection goes to the socket.
+                Ok(Endpoint::from_static("http://[::]:50051")
+                    .connect_timeout(CONNECT_TIMEOUT)
+                    .connect_with_connector(tower::service_fn(move |_: tonic::transport::Uri| {
+                        tokio::net::UnixStream::connect(path.clone())
+                    }))
+                    .await?)
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(ClientError::InvalidEndpoint(format!(
+                "{} is a Unix socket, which this host can't connect to",
+                endpoint
+            ))),
+            None => Ok(Endpoint::from_shared(endpoint.to_string())
+                .map_err(|e| ClientError::InvalidEndpoint(format!("{}: {}", endpoint, e)))?
+                .connect_timeout(CONNECT_TIMEOUT)
+                .connect()
+                .await?),
+        }
+    }
+
+    /// Send a read, retried with the policy of the client.
+    async fn read<R: Clone, T, F, Fut>(&self, request: R, call: F) -> Result<T, ClientError>
+    where
+        F: Fn(VmmServiceClient<Channel>, R) -> Fut,
+        Fut: std::future::Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+    {
+        self.retry
+            .run(|| {
+                let response = call(self.client.clone(), request.clone());
+                async move { Ok(response.await?.into_inner()) }
+            })
+            .await
+    }
+
+    /// Start `request`, and return the responses of the agent running it.
+    pub async fn run(
+        &self,
+        request: RunVmmRequest,
+    ) -> Result<Streaming<ExecuteResponse>, ClientError> {
+        Ok(self.client.clone().run(request).await?.into_inner())
+    }
+
+    /// What the orchestrator would do to run `request`, without running it.
+    pub async fn plan(&self, request: RunVmmRequest) -> Result<RunPlan, ClientError> {
+        self.read(request, |mut client, request| async move {
+            client.plan(request).await
+        })
+        .await
+    }
+
+    pub async fn register_workload(
+        &self,
+        request: RegisterWorkloadRequest,
+    ) -> Result<RegisteredWorkload, ClientError> {
+        Ok(self
+            .client
+            .clone()
+            .register_workload(request)
+            .await?
+            .into_inner())
+    }
+
+    /// Run a registered workload, and return the responses of the agent running it.
+    pub async fn invoke_workload(
+        &self,
+        request: InvokeWorkloadRequest,
+    ) -> Result<Streaming<ExecuteResponse>, ClientError> {
+        Ok(self
+            .client
+            .clone()
+            .invoke_workload(request)
+            .await?
+            .into_inner())
+    }
+
+    pub async fn list_workloads(
+        &self,
+        request: ListWorkloadsRequest,
+    ) -> Result<Vec<RegisteredWorkload>, ClientError> {
+        let response = self
+            .read(request, |mut client, request| async move {
+                client.list_workloads(request).await
+            })
+            .await?;
+        Ok(response.workloads)
+    }
+
+    /// The output of a run kept by the orchestrator.
+    pub async fn stream_logs(
+        &self,
+        request: StreamLogsRequest,
+    ) -> Result<Streaming<ExecuteResponse>, ClientError> {
+        self.read(request, |mut client, request| async move {
+            client.stream_logs(request).await
+        })
+        .await
+    }
+
+    /// The lifecycle and scheduling events of the VMs, as they happen.
+    pub async fn watch_events(
+        &self,
+        request: WatchEventsRequest,
+    ) -> Result<Streaming<VmEvent>, ClientError> {
+        self.read(request, |mut client, request| async move {
+            client.watch_events(request).await
+        })
+        .await
+    }
+
+    pub async fn list_pools(
+        &self,
+        request: ListPoolsRequest,
+    ) -> Result<Vec<PoolStatus>, ClientError> {
+        let response = self
+            .read(request, |mut client, request| async move {
+                client.list_pools(request).await
+            })
+            .await?;
+        Ok(response.pools)
+    }
+
+    /// Roll the pools matching `request` over to their current rootfs images, and return them.
+    pub async fn upgrade_pools(
+        &self,
+        request: UpgradePoolsRequest,
+    ) -> Result<Vec<PoolStatus>, ClientError> {
+        Ok(self
+            .client
+            .clone()
+            .upgrade_pools(request)
+            .await?
+            .into_inner()
+            .pools)
+    }
+
+    pub async fn list_runs(&self, request: ListRunsRequest) -> Result<Vec<RunRecord>, ClientError> {
+        let response = self
+            .read(request, |mut client, request| async move {
+                client.list_runs(request).await
+            })
+            .await?;
+        Ok(response.runs)
+    }
+
+    pub async fn list_vms(
+        &self,
+        request: ListVmMetricsRequest,
+    ) -> Result<Vec<VmMetrics>, ClientError> {
+        let response = self
+            .read(request, |mut client, request| async move {
+                client.list_vm_metrics(request).await
+            })
+            .await?;
+        Ok(response.vms)
+    }
+
+    pub async fn server_info(&self) -> Result<ServerInfo, ClientError> {
+        self.read(GetServerInfoRequest {}, |mut client, request| async move {
+            client.get_server_info(request).await
+        })
+        .await
+    }
+
+    pub async fn shutdown(
+        &self,
+        request: ShutdownVmRequest,
+    ) -> Result<ShutdownVmResponse, ClientError> {
+        Ok(self.client.clone().shutdown(request).await?.into_inner())
+    }
+}
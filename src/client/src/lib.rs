@@ -0,0 +1,38 @@
+//! Client of Cloudlet, to submit workloads from other Rust services without writing the HTTP
+//! and gRPC plumbing.
+//!
+//! - [`RestClient`] talks to the HTTP API, like the CLI: runs, registered workloads, pools,
+//!   VMs and run history.
+//! - [`GrpcClient`] talks to the orchestrator directly, for services running next to it.
+//!
+//! Both retry the requests which fail on an unreachable or saturated server with a
+//! [`RetryPolicy`], and return their errors as a [`ClientError`] carrying the Cloudlet error
+//! code, e.g. `CLDT-VMM-019`, when the server gave one. The outputs of the runs are streams of
+//! events, which [`RunOutput::collect`] gathers until the end of the run.
+//!
+//! ```no_run
+//! # use cloudlet_client::models::CloudletDtoRequest;
+//! # async fn example(request: CloudletDtoRequest) -> Result<(), cloudlet_client::ClientError> {
+//! use cloudlet_client::{RestClient, RunOutput};
+//!
+//! let client = RestClient::new("http://127.0.0.1:3000")?;
+//! let output = RunOutput::collect(client.run(&request).await?).await?;
+//! println!("{}", String::from_utf8_lossy(&output.stdout));
+//! # Ok(())
+//! # }
+//! ```
+
+mod error;
+mod events;
+mod grpc;
+mod rest;
+mod retry;
+
+pub use error::ClientError;
+pub use events::{OutputEncoding, RunEvent, RunOutput, RunStream};
+pub use grpc::GrpcClient;
+pub use rest::RestClient;
+pub use retry::RetryPolicy;
+
+/// Types of the requests and the responses, shared with the servers.
+pub use shared_models as models;
@@ -0,0 +1,299 @@
+//! Client of the HTTP API.
+
+use crate::{ClientError, RetryPolicy, RunStream};
+use reqwest::{Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use shared_models::{
+    unix_socket, CloudletDtoRequest, CloudletErrorResponse, CloudletInvokeRequest,
+    CloudletPlanResponse, CloudletPool, CloudletRegisterRequest, CloudletRunRecord,
+    CloudletServerInfo, CloudletShutdownResponse, CloudletUpgradePoolsRequest, CloudletVmMetrics,
+    CloudletWorkload, QUEUE_HEADER,
+};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Header making the retried run requests start a single run.
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Key of a run request, unique to this process.
+fn idempotency_key() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!(
+        "{:x}-{:x}-{:x}",
+        nanos,
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Error of a response whose status isn't a success.
+async fn api_error(response: Response) -> ClientError {
+    let status = response.status();
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(e) => return e.into(),
+    };
+    let error = serde_json::from_str(&body).unwrap_or_else(|_| CloudletErrorResponse {
+        code: None,
+        message: match body.trim() {
+            "" => status.to_string(),
+            body => body.to_string(),
+        },
+        hint: None,
+        details: Vec::new(),
+    });
+    ClientError::Api {
+        status: status.as_u16(),
+        error,
+    }
+}
+
+/// Client of the HTTP API, as used by the CLI.
+///
+/// The runs, which are sent with an idempotency key, and the reads are retried with its
+/// [`RetryPolicy`]. The other requests are only sent again when they couldn't reach the API.
+#[derive(Clone)]
+pub struct RestClient {
+    http: Client,
+    base: String,
+    retry: RetryPolicy,
+}
+
+impl RestClient {
+    /// Client of the API at `endpoint`, `http://<HOST>:<PORT>` or `unix://<PATH>` for an API
+    /// listening on a Unix socket.
+    pub fn new(endpoint: &str) -> Result<Self, ClientError> {
+        let (http, base) = match unix_socket::socket_path(endpoint) {
+            Some(path) if path.as_os_str().is_empty() => {
+                return Err(ClientError::InvalidEndpoint(format!(
+                    "{} has no path",
+                    endpoint
+                )))
+            }
+            #[cfg(unix)]
+            // The host is ignored, every connection goes to the socket.
+            Some(path) => (
+                Client::builder().unix_socket(path.to_path_buf()).build()?,
+                "http://localhost".to_string(),
+            ),
+            #[cfg(not(unix))]
+            Some(_) => {
+                return Err(ClientError::InvalidEndpoint(format!(
+                    "{} is a Unix socket, which this host can't connect to",
+                    endpoint
+                )))
+            }
+            None => {
+                let url = reqwest::Url::parse(endpoint)
+                    .map_err(|e| ClientError::InvalidEndpoint(format!("{}: {}", endpoint, e)))?;
+                if !matches!(url.scheme(), "http" | "https") {
+                    return Err(ClientError::InvalidEndpoint(format!(
+                        "{} isn't an http:// or unix:// endpoint",
+                        endpoint
+                    )));
+                }
+                (Client::new(), endpoint.trim_end_matches('/').to_string())
+            }
+        };
+        Ok(Self {
+            http,
+            base,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// Retry the requests with `retry` rather than the default policy.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    /// Send the request made by `request`, retried on the errors meeting `retryable`, and
+    /// return its response if it succeeded.
+    async fn send(
+        &self,
+        retryable: fn(&ClientError) -> bool,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, ClientError> {
+        self.retry
+            .run_if(retryable, || {
+                let request = request();
+                async move {
+                    let response = request.send().await?;
+                    if !response.status().is_success() {
+                        return Err(api_error(response).await);
+                    }
+                    Ok(response)
+                }
+            })
+            .await
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, ClientError> {
+        let response = self
+            .send(ClientError::is_retryable, || {
+                self.http.get(self.url(path)).query(query)
+            })
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// Start `request`, and return the events of the run. The run fails rather than being
+    /// queued while the orchestrator is unreachable.
+    pub async fn run(&self, request: &CloudletDtoRequest) -> Result<RunStream, ClientError> {
+        let key = idempotency_key();
+        let response = self
+            .send(ClientError::is_retryable, || {
+                self.http
+                    .post(self.url("/run"))
+                    .header(IDEMPOTENCY_KEY_HEADER, &key)
+                    .header(QUEUE_HEADER, "never")
+                    .json(request)
+            })
+            .await?;
+        Ok(RunStream::new(response))
+    }
+
+    /// What the orchestrator would do to run `request`, without running it.
+    pub async fn plan(
+        &self,
+        request: &CloudletDtoRequest,
+    ) -> Result<CloudletPlanResponse, ClientError> {
+        let response = self
+            .send(ClientError::is_retryable, || {
+                self.http.post(self.url("/plan")).json(request)
+            })
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// Run the registered workload `name`, and return the events of the run.
+    pub async fn invoke(
+        &self,
+        name: &str,
+        request: &CloudletInvokeRequest,
+    ) -> Result<RunStream, ClientError> {
+        let response = self
+            .send(ClientError::is_unsent, || {
+                self.http
+                    .post(self.url(&format!("/workloads/{}/invoke", name)))
+                    .json(request)
+            })
+            .await?;
+        Ok(RunStream::new(response))
+    }
+
+    /// The output of the run of `vm`, a VM id or a workload name, followed until its end if
+    /// `follow` is set.
+    pub async fn logs(&self, vm: &str, follow: bool) -> Result<RunStream, ClientError> {
+        let path = format!("/logs/{}", vm);
+        let response = self
+            .send(ClientError::is_retryable, || {
+                self.http.get(self.url(&path)).query(&[("follow", follow)])
+            })
+            .await?;
+        Ok(RunStream::new(response))
+    }
+
+    pub async fn register(
+        &self,
+        request: &CloudletRegisterRequest,
+    ) -> Result<CloudletWorkload, ClientError> {
+        let response = self
+            .send(ClientError::is_unsent, || {
+                self.http.post(self.url("/workloads")).json(request)
+            })
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// The registered workloads, only the versions of `name` if set.
+    pub async fn workloads(
+        &self,
+        name: Option<&str>,
+    ) -> Result<Vec<CloudletWorkload>, ClientError> {
+        self.get(
+            "/workloads",
+            &[("name", name.unwrap_or_default().to_string())],
+        )
+        .await
+    }
+
+    /// The pools of warm VMs, only the one of the workload `name` if set.
+    pub async fn pools(&self, name: Option<&str>) -> Result<Vec<CloudletPool>, ClientError> {
+        self.get("/pools", &[("name", name.unwrap_or_default().to_string())])
+            .await
+    }
+
+    /// Roll the pools matching `request` over to their current rootfs images.
+    pub async fn upgrade_pools(
+        &self,
+        request: &CloudletUpgradePoolsRequest,
+    ) -> Result<Vec<CloudletPool>, ClientError> {
+        let response = self
+            .send(ClientError::is_unsent, || {
+                self.http.post(self.url("/pools/upgrade")).json(request)
+            })
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// The recent runs, the most recent first, matching the label `selector` and of the
+    /// workload `workload` if set. All the runs kept by the orchestrator if `limit` is unset.
+    pub async fn runs(
+        &self,
+        selector: Option<&str>,
+        workload: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<Vec<CloudletRunRecord>, ClientError> {
+        self.get(
+            "/runs",
+            &[
+                ("selector", selector.unwrap_or_default().to_string()),
+                ("workload", workload.unwrap_or_default().to_string()),
+                ("limit", limit.unwrap_or_default().to_string()),
+            ],
+        )
+        .await
+    }
+
+    /// The running VMs, only those whose labels match `selector` if set.
+    pub async fn vms(&self, selector: Option<&str>) -> Result<Vec<CloudletVmMetrics>, ClientError> {
+        self.get(
+            "/vms",
+            &[("selector", selector.unwrap_or_default().to_string())],
+        )
+        .await
+    }
+
+    pub async fn server_info(&self) -> Result<CloudletServerInfo, ClientError> {
+        self.get("/info", &[]).await
+    }
+
+    /// Stop the VM `vm`, or all of them if unset, and return whether they were stopped.
+    pub async fn shutdown(&self, vm: Option<&str>) -> Result<bool, ClientError> {
+        let response = self
+            .send(ClientError::is_unsent, || {
+                let request = self.http.post(self.url("/shutdown"));
+                match vm {
+                    Some(vm) => request.json(&serde_json::json!({ "id": vm })),
+                    None => request,
+                }
+            })
+            .await?;
+        Ok(response.json::<CloudletShutdownResponse>().await?.success)
+    }
+}
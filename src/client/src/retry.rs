@@ -0,0 +1,138 @@
+//! Retries of the requests which failed on an unreachable or saturated server.
+
+use crate::ClientError;
+use std::future::Future;
+use std::time::Duration;
+
+/// How many times, and how far apart, the retryable failures of a request are retried. The
+/// delays double from `initial_backoff` up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts of a request, the first one included.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Send each request once.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before the attempt following the `attempt`th one, counted from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+
+    /// Result of `request`, sent again while it fails with a retryable error.
+    pub async fn run<T, F, Fut>(&self, request: F) -> Result<T, ClientError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        self.run_if(ClientError::is_retryable, request).await
+    }
+
+    /// Result of `request`, sent again while it fails with an error meeting `retryable`.
+    pub(crate) async fn run_if<T, F, Fut>(
+        &self,
+        retryable: fn(&ClientError) -> bool,
+        mut request: F,
+    ) -> Result<T, ClientError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                Err(e) if retryable(&e) && attempt < self.max_attempts => {
+                    tokio::time::sleep(self.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_models::CloudletErrorResponse;
+    use std::cell::Cell;
+
+    fn unavailable() -> ClientError {
+        ClientError::Api {
+            status: 503,
+            error: CloudletErrorResponse {
+                code: None,
+                message: "unavailable".to_string(),
+                hint: None,
+                details: Vec::new(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(1));
+        assert_eq!(policy.backoff(5), Duration::from_millis(2));
+
+        let attempts = Cell::new(0);
+        let result = policy
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                let attempt = attempts.get();
+                async move {
+                    match attempt {
+                        1 => Err(unavailable()),
+                        attempt => Ok(attempt),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+
+        attempts.set(0);
+        let result: Result<(), _> = policy
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err(unavailable()) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        attempts.set(0);
+        let result: Result<(), _> = policy
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err(ClientError::InvalidEndpoint("ftp://".to_string())) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,48 @@
+//! Archive arbitrary entries, which must be read back as they were written, or rejected.
+
+#![no_main]
+
+use fs_gen::archive::cpio::{read_archive, CpioEntry, CpioWriter, EntryMetadata};
+use libfuzzer_sys::{arbitrary::Arbitrary, fuzz_target};
+
+#[derive(Debug, Arbitrary)]
+struct Entry {
+    name: Vec<u8>,
+    mode: u32,
+    mtime: u32,
+    rdev: (u32, u32),
+    data: Vec<u8>,
+}
+
+fuzz_target!(|input: (Vec<Entry>, Vec<u8>)| {
+    let (entries, garbage) = input;
+    let _ = read_archive(&garbage);
+
+    let mut writer = CpioWriter::new(Vec::new());
+    let mut written = Vec::new();
+    for entry in entries {
+        let metadata = EntryMetadata {
+            mode: entry.mode,
+            mtime: entry.mtime,
+            rdev: entry.rdev,
+        };
+        if writer
+            .append(
+                &entry.name,
+                &metadata,
+                entry.data.len() as u64,
+                entry.data.as_slice(),
+            )
+            .is_ok()
+        {
+            written.push(CpioEntry {
+                name: entry.name,
+                metadata,
+                data: entry.data,
+            });
+        }
+    }
+
+    let archive = writer.finish().unwrap();
+    assert_eq!(read_archive(&archive).unwrap(), written);
+});
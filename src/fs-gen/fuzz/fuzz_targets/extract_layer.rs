@@ -0,0 +1,29 @@
+//! Extract arbitrary tarballs, which must never write outside of the layer directory.
+
+#![no_main]
+
+use fs_gen::archive::layer::extract_layer;
+use libfuzzer_sys::fuzz_target;
+use std::{fs, path::Path};
+
+/// Whether every file under `dir` is under `root`.
+fn contained(dir: &Path, root: &Path) -> bool {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .all(|entry| {
+            let path = entry.path();
+            path.starts_with(root)
+                && (!path.is_dir() || path.is_symlink() || contained(&path, root))
+        })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let dir = std::env::temp_dir().join(format!("fs-gen-fuzz-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let layer = dir.join("layer");
+
+    let _ = extract_layer(data, &layer);
+    assert!(contained(&dir, &layer), "the layer escaped its directory");
+});
@@ -0,0 +1,342 @@
+//! Writer of the `newc` cpio archives unpacked by the kernel as initramfs.
+//!
+//! The entries are owned by root, like with `cpio --owner=root:root`. Their names are checked
+//! so that the archive can't make the kernel write outside of the root of the initramfs.
+
+use anyhow::{bail, Context, Result};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::{
+    fs::{self, File},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_LEN: usize = 110;
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+pub(crate) const S_IFDIR: u32 = 0o040000;
+pub(crate) const S_IFREG: u32 = 0o100000;
+pub(crate) const S_IFLNK: u32 = 0o120000;
+
+/// Metadata of an entry, the type bits of `mode` give its type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub mode: u32,
+    pub mtime: u32,
+    /// Device of the character and block special files, as (major, minor).
+    pub rdev: (u32, u32),
+}
+
+/// Entry read back from an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpioEntry {
+    pub name: Vec<u8>,
+    pub metadata: EntryMetadata,
+    pub data: Vec<u8>,
+}
+
+/// Padding after `len` bytes to reach a multiple of 4.
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// Check that `name` designates a path inside the root of the archive.
+fn check_name(name: &[u8]) -> Result<()> {
+    if name.is_empty() || name.contains(&0) {
+        bail!("Invalid entry name {:?}", String::from_utf8_lossy(name));
+    }
+    // Checked as bytes rather than as a host path, which may not use `/` as separator.
+    if name.starts_with(b"/") || name.split(|byte| *byte == b'/').any(|part| part == b"..") {
+        bail!(
+            "Entry name {:?} leaves the root of the archive",
+            String::from_utf8_lossy(name)
+        );
+    }
+    Ok(())
+}
+
+pub struct CpioWriter<W: Write> {
+    out: W,
+    next_ino: u32,
+}
+
+impl<W: Write> CpioWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, next_ino: 1 }
+    }
+
+    fn write_header(
+        &mut self,
+        name: &[u8],
+        metadata: &EntryMetadata,
+        size: u32,
+        nlink: u32,
+    ) -> io::Result<()> {
+        let ino = match name {
+            TRAILER => 0,
+            _ => {
+                self.next_ino += 1;
+                self.next_ino - 1
+            }
+        };
+        let namesize = name.len() + 1;
+        let fields = [
+            ino,
+            metadata.mode,
+            0, // uid
+            0, // gid
+            nlink,
+            metadata.mtime,
+            size,
+            0, // devmajor
+            0, // devminor
+            metadata.rdev.0,
+            metadata.rdev.1,
+            namesize as u32,
+            0, // check
+        ];
+
+        self.out.write_all(MAGIC)?;
+        for field in fields {
+            write!(self.out, "{:08x}", field)?;
+        }
+        self.out.write_all(name)?;
+        self.out
+            .write_all(&[0; 4][..1 + padding(HEADER_LEN + namesize)])
+    }
+
+    /// Append an entry named `name`, with `size` bytes of content read from `data`.
+    pub fn append(
+        &mut self,
+        name: &[u8],
+        metadata: &EntryMetadata,
+        size: u64,
+        data: impl Read,
+    ) -> Result<()> {
+        check_name(name)?;
+        let Ok(size) = u32::try_from(size) else {
+            bail!(
+                "{:?} is too large for a cpio archive ({} bytes)",
+                String::from_utf8_lossy(name),
+                size
+            );
+        };
+        let nlink = if metadata.mode & S_IFMT == S_IFDIR {
+            2
+        } else {
+            1
+        };
+
+        self.write_header(name, metadata, size, nlink)?;
+        let copied = io::copy(&mut data.take(size.into()), &mut self.out)?;
+        if copied != u64::from(size) {
+            bail!(
+                "{:?} changed while it was archived",
+                String::from_utf8_lossy(name)
+            );
+        }
+        self.out.write_all(&[0; 3][..padding(size as usize)])?;
+        Ok(())
+    }
+
+    /// Write the trailer of the archive, and return the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_header(TRAILER, &EntryMetadata::default(), 0, 1)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// (major, minor) of a device number, as encoded by glibc.
+#[cfg(unix)]
+fn split_rdev(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+#[cfg(unix)]
+fn append_dir<W: Write>(writer: &mut CpioWriter<W>, root: &Path, relative: &Path) -> Result<()> {
+    let dir = root.join(relative);
+    let mut entries = fs::read_dir(&dir)
+        .with_context(|| format!("Could not read {}", dir.display()))?
+        .collect::<io::Result<Vec<_>>>()?;
+    // Sorted, so that the same tree always gives the same archive.
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        let path = root.join(&relative);
+        let name = relative.as_os_str().as_bytes();
+        let metadata = fs::symlink_metadata(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let header = EntryMetadata {
+            mode: metadata.mode(),
+            mtime: metadata.mtime().clamp(0, u32::MAX.into()) as u32,
+            rdev: split_rdev(metadata.rdev()),
+        };
+
+        match metadata.mode() & S_IFMT {
+            S_IFREG => {
+                let file = File::open(&path)
+                    .with_context(|| format!("Could not open {}", path.display()))?;
+                writer.append(name, &header, metadata.len(), file)?;
+            }
+            S_IFLNK => {
+                let target = fs::read_link(&path)?;
+                let target = target.as_os_str().as_bytes();
+                writer.append(name, &header, target.len() as u64, target)?;
+            }
+            S_IFDIR => {
+                writer.append(name, &header, 0, io::empty())?;
+                append_dir(writer, root, &relative)?;
+            }
+            // Device nodes, FIFOs and sockets have no content.
+            _ => writer.append(name, &header, 0, io::empty())?,
+        }
+    }
+    Ok(())
+}
+
+/// Write the tree at `root` to `out` as a cpio archive.
+#[cfg(unix)]
+pub fn write_dir<W: Write>(root: &Path, out: W) -> Result<W> {
+    let mut writer = CpioWriter::new(out);
+    append_dir(&mut writer, root, Path::new(""))?;
+    writer.finish()
+}
+
+/// Parse a hex field of a header.
+fn field(header: &[u8], index: usize) -> Result<u32> {
+    let digits = &header[MAGIC.len() + index * 8..MAGIC.len() + (index + 1) * 8];
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+        .with_context(|| format!("Invalid header field {:?}", digits))
+}
+
+/// Read the entries of the archive `data`, up to its trailer.
+pub fn read_archive(data: &[u8]) -> Result<Vec<CpioEntry>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = data
+            .get(offset..offset + HEADER_LEN)
+            .context("Truncated header")?;
+        if &header[..MAGIC.len()] != MAGIC {
+            bail!("Invalid magic at offset {}", offset);
+        }
+        let namesize = field(header, 11)? as usize;
+        let size = field(header, 6)? as usize;
+        let name_start = offset + HEADER_LEN;
+        let name = data
+            .get(name_start..name_start + namesize)
+            .context("Truncated name")?;
+        let Some((&0, name)) = name.split_last() else {
+            bail!("Entry name without its terminating NUL");
+        };
+
+        let data_start = name_start + namesize + padding(HEADER_LEN + namesize);
+        if name == TRAILER {
+            return Ok(entries);
+        }
+        let content = data
+            .get(data_start..data_start + size)
+            .context("Truncated content")?;
+        entries.push(CpioEntry {
+            name: name.to_vec(),
+            metadata: EntryMetadata {
+                mode: field(header, 1)?,
+                mtime: field(header, 5)?,
+                rdev: (field(header, 9)?, field(header, 10)?),
+            },
+            data: content.to_vec(),
+        });
+        offset = data_start + size + padding(size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn entry_strategy() -> impl Strategy<Value = CpioEntry> {
+        (
+            "[a-zA-Z0-9._-]{1,64}(/[a-zA-Z0-9._-]{1,64}){0,4}",
+            prop_oneof![
+                Just(S_IFREG | 0o644),
+                Just(S_IFDIR | 0o755),
+                Just(S_IFLNK | 0o777)
+            ],
+            any::<u32>(),
+            proptest::collection::vec(any::<u8>(), 0..1024),
+        )
+            .prop_filter("`.` and `..` are not entry names", |(name, ..)| {
+                name.split('/').all(|part| part != "." && part != "..")
+            })
+            .prop_map(|(name, mode, mtime, data)| CpioEntry {
+                name: name.into_bytes(),
+                metadata: EntryMetadata {
+                    mode,
+                    mtime,
+                    rdev: (0, 0),
+                },
+                data: if mode & S_IFMT == S_IFDIR {
+                    Vec::new()
+                } else {
+                    data
+                },
+            })
+    }
+
+    fn write(entries: &[CpioEntry]) -> Result<Vec<u8>> {
+        let mut writer = CpioWriter::new(Vec::new());
+        for entry in entries {
+            writer.append(
+                &entry.name,
+                &entry.metadata,
+                entry.data.len() as u64,
+                entry.data.as_slice(),
+            )?;
+        }
+        writer.finish()
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip(entries in proptest::collection::vec(entry_strategy(), 0..16)) {
+            let archive = write(&entries).unwrap();
+            prop_assert_eq!(archive.len() % 4, 0);
+            prop_assert_eq!(read_archive(&archive).unwrap(), entries);
+        }
+
+        #[test]
+        fn test_reject_escaping_names(prefix in "(\\.\\./)+|/", name in "[a-z]{1,16}") {
+            let entry = CpioEntry {
+                name: format!("{}{}", prefix, name).into_bytes(),
+                metadata: EntryMetadata { mode: S_IFREG | 0o644, ..Default::default() },
+                data: Vec::new(),
+            };
+            prop_assert!(write(&[entry]).is_err());
+        }
+
+        #[test]
+        fn test_read_garbage(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = read_archive(&data);
+        }
+    }
+
+    #[test]
+    fn test_reject_truncated_content() {
+        let mut writer = CpioWriter::new(Vec::new());
+        let metadata = EntryMetadata {
+            mode: S_IFREG | 0o644,
+            ..Default::default()
+        };
+        assert!(writer.append(b"file", &metadata, 8, &b"short"[..]).is_err());
+    }
+}
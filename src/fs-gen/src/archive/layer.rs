@@ -0,0 +1,312 @@
+//! Extraction of the tar layers of the container images.
+//!
+//! The layers come from the registries: a malformed or hostile layer must fail the build, or
+//! lose the entries which can't be extracted safely, but can't reach the rest of the host.
+//!
+//! Each path of a layer is checked before anything is written: it must be relative, without
+//! `..`, and can't go through a symlink extracted earlier, which could point anywhere on the
+//! host. The symlinks themselves may point anywhere, they are only resolved in the guest.
+//!
+//! The extraction keeps the Unix modes of the entries, so it is only available on Unix hosts:
+//! the others assemble the rootfs in memory with [`super::tree`].
+
+use std::path::PathBuf;
+use thiserror::Error;
+#[cfg(unix)]
+use {
+    anyhow::{Context, Result},
+    std::fs::{self, Permissions},
+    std::io::{self, Read},
+    std::os::unix::fs::PermissionsExt,
+    std::path::{Component, Path},
+    tar::{Archive, EntryType},
+    tracing::warn,
+};
+
+/// Entry of a layer which would be written outside of the layer directory.
+#[derive(Debug, Error)]
+pub enum UnsafeEntry {
+    #[error("Layer entry `{0}` has an absolute path")]
+    AbsolutePath(PathBuf),
+
+    #[error("Layer entry `{0}` has a `..` component")]
+    ParentDir(PathBuf),
+
+    #[error("Layer entry `{path}` goes through the symlink `{symlink}`")]
+    ThroughSymlink { path: PathBuf, symlink: PathBuf },
+
+    #[error("Layer whiteout `{0}` doesn't name an entry of its directory")]
+    InvalidWhiteout(PathBuf),
+}
+
+/// Check `path`, of an entry of the layer at `root`, and return it relative to `root`.
+/// `None` designates the root itself.
+#[cfg(unix)]
+fn sanitize(root: &Path, path: &Path) -> Result<Option<PathBuf>, UnsafeEntry> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(UnsafeEntry::ParentDir(path.to_path_buf())),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(UnsafeEntry::AbsolutePath(path.to_path_buf()))
+            }
+        }
+    }
+
+    let mut ancestor = root.to_path_buf();
+    for component in relative.parent().into_iter().flat_map(Path::components) {
+        ancestor.push(component);
+        if fs::symlink_metadata(&ancestor).is_ok_and(|metadata| metadata.is_symlink()) {
+            return Err(UnsafeEntry::ThroughSymlink {
+                path: path.to_path_buf(),
+                symlink: ancestor.strip_prefix(root).unwrap().to_path_buf(),
+            });
+        }
+    }
+    Ok((!relative.as_os_str().is_empty()).then_some(relative))
+}
+
+/// Unpack the layer `tarball` to `output_dir`.
+///
+/// Device nodes and FIFOs are skipped: the host would expose its own devices through them
+/// while the image is built, or block reading them. An entry, or the target of a hard link,
+/// whose path leaves `output_dir` fails the extraction with an [`UnsafeEntry`] error.
+#[cfg(unix)]
+pub fn extract_layer(tarball: impl Read, output_dir: &Path) -> Result<()> {
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("Could not create {}", output_dir.display()))?;
+
+    let mut archive = Archive::new(tarball);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_overwrite(true);
+
+    // The permissions of the directories are set once the layer is extracted, so that a
+    // read-only directory can still receive its entries.
+    let mut directories: Vec<(PathBuf, u32)> = Vec::new();
+    for entry in archive
+        .entries()
+        .with_context(|| "Malformed layer".to_string())?
+    {
+        let mut entry = entry.with_context(|| "Malformed layer entry".to_string())?;
+        let path = entry
+            .path()
+            .with_context(|| "Invalid entry path in the layer".to_string())?
+            .into_owned();
+        let entry_type = entry.header().entry_type();
+        if matches!(
+            entry_type,
+            EntryType::Char | EntryType::Block | EntryType::Fifo
+        ) {
+            warn!(path = %path.display(), "Skipping device node of the layer");
+            continue;
+        }
+
+        let Some(relative) = sanitize(output_dir, &path)? else {
+            continue;
+        };
+        let destination = output_dir.join(&relative);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Could not create {}", parent.display()))?;
+        }
+        // The entry replaces a symlink at its path, rather than being written through it.
+        if fs::symlink_metadata(&destination).is_ok_and(|metadata| metadata.is_symlink()) {
+            fs::remove_file(&destination)?;
+        }
+
+        match entry_type {
+            EntryType::Directory => {
+                let mode = entry.header().mode().unwrap_or(0o755);
+                entry
+                    .unpack(&destination)
+                    .with_context(|| format!("Failed to unpack {}", path.display()))?;
+                fs::set_permissions(&destination, Permissions::from_mode(0o755))?;
+                directories.push((destination, mode));
+            }
+            // The tar crate resolves the targets of the hard links against the current
+            // directory, outside of `unpack_in`.
+            EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .with_context(|| format!("Hard link {} without target", path.display()))?
+                    .into_owned();
+                let target = sanitize(output_dir, &target)?
+                    .with_context(|| format!("Hard link {} to the root", path.display()))?;
+                match fs::remove_file(&destination) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                fs::hard_link(output_dir.join(&target), &destination).with_context(|| {
+                    format!("Failed to link {} to {}", path.display(), target.display())
+                })?;
+            }
+            _ => {
+                entry
+                    .unpack(&destination)
+                    .with_context(|| format!("Failed to unpack {}", path.display()))?;
+            }
+        }
+    }
+
+    // The deepest directories first, so that their parents are still writable.
+    for (dir, mode) in directories.into_iter().rev() {
+        fs::set_permissions(&dir, Permissions::from_mode(mode & 0o7777))
+            .with_context(|| format!("Could not set the permissions of {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use cloudlet_test_registry::scratch_dir;
+    use proptest::prelude::*;
+    use tar::{Builder, Header};
+
+    /// Layer holding regular files at the raw `paths`, unchecked by the tar crate.
+    fn raw_layer(paths: &[String]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for path in paths {
+            let mut header = Header::new_old();
+            let name = &mut header.as_old_mut().name;
+            let len = path.len().min(name.len());
+            name[..len].copy_from_slice(&path.as_bytes()[..len]);
+            header.set_mode(0o644);
+            header.set_size(4);
+            header.set_entry_type(EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, &b"data"[..]).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Files under `dir`, recursively.
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                found.extend(files(&path));
+            }
+            found.push(path);
+        }
+        found
+    }
+
+    proptest! {
+        #[test]
+        fn test_extract_garbage(data in proptest::collection::vec(any::<u8>(), 0..4096)) {
+            let dir = scratch_dir("fs-gen-extract-garbage");
+            let _ = extract_layer(data.as_slice(), &dir.join("layer"));
+            for file in files(&dir) {
+                prop_assert!(file.starts_with(dir.join("layer")));
+            }
+        }
+
+        #[test]
+        fn test_extract_hostile_paths(
+            paths in proptest::collection::vec("(/|\\.\\./|\\./|[a-z]{1,8}/){0,6}[a-z]{1,8}", 1..8)
+        ) {
+            let dir = scratch_dir("fs-gen-extract-paths");
+            let result = extract_layer(raw_layer(&paths).as_slice(), &dir.join("layer"));
+            if paths.iter().any(|path| path.starts_with('/') || path.contains("../")) {
+                prop_assert!(result.is_err());
+            }
+            for file in files(&dir) {
+                prop_assert!(file.starts_with(dir.join("layer")));
+            }
+        }
+    }
+
+    #[test]
+    fn test_reject_escaping_links() {
+        let dir = scratch_dir("fs-gen-extract-links");
+        fs::write(dir.join("secret"), "secret").unwrap();
+
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "etc/shadow", "../secret")
+            .unwrap();
+        let error = extract_layer(builder.into_inner().unwrap().as_slice(), &dir.join("layer"))
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<UnsafeEntry>(),
+            Some(UnsafeEntry::ParentDir(_))
+        ));
+        assert!(!dir.join("layer/etc/shadow").exists());
+
+        // A symlink of the layer may point anywhere, but nothing is written through it.
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "etc", &dir).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "shadow", "etc/secret")
+            .unwrap();
+        let error = extract_layer(builder.into_inner().unwrap().as_slice(), &dir.join("layer"))
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<UnsafeEntry>(),
+            Some(UnsafeEntry::ThroughSymlink { .. })
+        ));
+        assert_eq!(fs::read_link(dir.join("layer/etc")).unwrap(), dir);
+        assert!(!dir.join("layer/shadow").exists());
+    }
+
+    #[test]
+    fn test_skip_device_nodes() {
+        let dir = scratch_dir("fs-gen-extract-devices");
+        let mut builder = Builder::new(Vec::new());
+        for (path, entry_type) in [
+            ("dev/sda", EntryType::Block),
+            ("dev/null", EntryType::Char),
+            ("run/fifo", EntryType::Fifo),
+        ] {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(0o600);
+            header.set_size(0);
+            builder
+                .append_data(&mut header, path, std::io::empty())
+                .unwrap();
+        }
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_mode(0o555);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "readonly", std::io::empty())
+            .unwrap();
+        let mut header = Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(4);
+        builder
+            .append_data(&mut header, "readonly/file", &b"data"[..])
+            .unwrap();
+
+        extract_layer(builder.into_inner().unwrap().as_slice(), &dir).unwrap();
+        assert!(!dir.join("dev/sda").exists());
+        assert!(!dir.join("dev/null").exists());
+        assert!(!dir.join("run/fifo").exists());
+        assert_eq!(fs::read(dir.join("readonly/file")).unwrap(), b"data");
+        assert_eq!(
+            fs::metadata(dir.join("readonly"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o555
+        );
+        fs::set_permissions(dir.join("readonly"), Permissions::from_mode(0o755)).unwrap();
+    }
+}
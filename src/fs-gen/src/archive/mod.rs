@@ -0,0 +1,6 @@
+//! Archives read and written while building a rootfs image: the tar layers of the container
+//! images, and the cpio initramfs.
+
+pub mod cpio;
+pub mod layer;
+pub mod tree;
@@ -0,0 +1,479 @@
+//! Rootfs assembled in userland from the tar layers of an image, for the hosts which can't
+//! mount an overlay nor give the extracted files their Linux metadata, e.g. macOS and Windows.
+//!
+//! The layers are applied in order to a tree of their entries, whiteouts included, whose modes
+//! come from the tar headers rather than from the host filesystem. Only the content of the
+//! regular files reaches the host, as numbered files of a scratch directory: the paths of the
+//! layers are never created there, and the tree is written straight into a cpio archive.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tar::{Archive, EntryType};
+use tracing::{debug, warn};
+
+use super::cpio::{CpioWriter, EntryMetadata, S_IFDIR, S_IFLNK, S_IFREG};
+use super::layer::UnsafeEntry;
+
+/// Prefix of the entries hiding a path of the lower layers.
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+/// Entry hiding the content of its directory in the lower layers.
+const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
+
+#[derive(Debug, Clone)]
+enum Content {
+    Directory,
+    /// Regular file of `size` bytes, read from `source` on the host.
+    File {
+        source: PathBuf,
+        size: u64,
+    },
+    Symlink(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// Permission bits, the type of the entry is given by its content.
+    permissions: u32,
+    mtime: u32,
+    content: Content,
+    /// Index of the layer the entry belongs to, an opaque whiteout only hides the lower ones.
+    layer: usize,
+}
+
+impl Node {
+    fn mode(&self) -> u32 {
+        let file_type = match self.content {
+            Content::Directory => S_IFDIR,
+            Content::File { .. } => S_IFREG,
+            Content::Symlink(_) => S_IFLNK,
+        };
+        file_type | self.permissions
+    }
+}
+
+/// Path of an entry as a display name, for the errors.
+fn display(path: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(path).into_owned())
+}
+
+/// Components of the path of an entry, which must stay inside the root.
+fn components(path: &[u8]) -> Result<Vec<&[u8]>, UnsafeEntry> {
+    if path.starts_with(b"/") {
+        return Err(UnsafeEntry::AbsolutePath(display(path)));
+    }
+    let mut parts = Vec::new();
+    for part in path.split(|byte| *byte == b'/') {
+        match part {
+            b"" | b"." => {}
+            b".." => return Err(UnsafeEntry::ParentDir(display(path))),
+            part => parts.push(part),
+        }
+    }
+    Ok(parts)
+}
+
+/// Merged rootfs of the layers applied so far.
+pub struct RootfsTree {
+    /// Entries by path, relative to the root and without trailing `/`. The byte order keeps the
+    /// entries of a directory right after it.
+    nodes: BTreeMap<Vec<u8>, Node>,
+    scratch: PathBuf,
+    files: usize,
+    layers: usize,
+}
+
+impl RootfsTree {
+    /// Empty rootfs, the content of its files being written to `scratch`.
+    pub fn new(scratch: &Path) -> Result<Self> {
+        fs::create_dir_all(scratch)
+            .with_context(|| format!("Could not create {}", scratch.display()))?;
+        Ok(Self {
+            nodes: BTreeMap::new(),
+            scratch: scratch.to_path_buf(),
+            files: 0,
+            layers: 0,
+        })
+    }
+
+    /// Paths of the entries under the directory `dir`, the root being the empty path.
+    fn descendants(&self, dir: &[u8]) -> Vec<Vec<u8>> {
+        let mut prefix = dir.to_vec();
+        if !prefix.is_empty() {
+            prefix.push(b'/');
+        }
+        self.nodes
+            .range(prefix.clone()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Remove the entry at `path`, and its content if it is a directory.
+    fn remove(&mut self, path: &[u8]) {
+        for descendant in self.descendants(path) {
+            self.nodes.remove(&descendant);
+        }
+        self.nodes.remove(path);
+    }
+
+    /// Make the ancestors `parents` of the entry `path` directories of `layer`. A directory
+    /// replaces the file or the symlink of a lower layer, but an entry can't go through a
+    /// symlink of its own layer.
+    fn create_parents(&mut self, parents: &[&[u8]], path: &[u8], layer: usize) -> Result<()> {
+        let mut ancestor = Vec::new();
+        for part in parents {
+            if !ancestor.is_empty() {
+                ancestor.push(b'/');
+            }
+            ancestor.extend_from_slice(part);
+            match self.nodes.get_mut(&ancestor) {
+                Some(node) if matches!(node.content, Content::Directory) => node.layer = layer,
+                Some(node)
+                    if matches!(node.content, Content::Symlink(_)) && node.layer == layer =>
+                {
+                    return Err(UnsafeEntry::ThroughSymlink {
+                        path: display(path),
+                        symlink: display(&ancestor),
+                    }
+                    .into());
+                }
+                _ => {
+                    self.remove(&ancestor);
+                    self.nodes.insert(
+                        ancestor.clone(),
+                        Node {
+                            permissions: 0o755,
+                            mtime: 0,
+                            content: Content::Directory,
+                            layer,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `node` at `path`, keeping the content of the directory it replaces if it is one.
+    fn insert(&mut self, path: Vec<u8>, node: Node) {
+        match self.nodes.get_mut(&path) {
+            Some(existing)
+                if matches!(existing.content, Content::Directory)
+                    && matches!(node.content, Content::Directory) =>
+            {
+                *existing = node;
+            }
+            _ => {
+                self.remove(&path);
+                self.nodes.insert(path, node);
+            }
+        }
+    }
+
+    /// Apply the layer `tarball` over the previous ones.
+    ///
+    /// Device nodes and FIFOs are skipped, as they are when the layers are extracted. An entry,
+    /// or the target of a hard link, whose path leaves the root fails with an [`UnsafeEntry`]
+    /// error. A hard link gets a copy of its target, as the cpio archives only hold copies.
+    pub fn apply_layer(&mut self, tarball: impl Read) -> Result<()> {
+        let layer = self.layers;
+        self.layers += 1;
+
+        let mut archive = Archive::new(tarball);
+        for entry in archive
+            .entries()
+            .with_context(|| "Malformed layer".to_string())?
+        {
+            let mut entry = entry.with_context(|| "Malformed layer entry".to_string())?;
+            let raw = entry.path_bytes().into_owned();
+            let parts = components(&raw)?;
+            let Some((name, parents)) = parts.split_last() else {
+                continue;
+            };
+            let dir = parents.join(&b'/');
+            let path = parts.join(&b'/');
+
+            if *name == OPAQUE_WHITEOUT {
+                for hidden in self.descendants(&dir) {
+                    if self
+                        .nodes
+                        .get(&hidden)
+                        .is_some_and(|node| node.layer < layer)
+                    {
+                        self.nodes.remove(&hidden);
+                    }
+                }
+                continue;
+            }
+            if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                if !matches!(hidden, b"" | b"." | b"..") {
+                    let mut hidden_path = dir.clone();
+                    if !hidden_path.is_empty() {
+                        hidden_path.push(b'/');
+                    }
+                    hidden_path.extend_from_slice(hidden);
+                    self.remove(&hidden_path);
+                }
+                continue;
+            }
+
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            let permissions = header.mode().unwrap_or(0o644) & 0o7777;
+            let mtime = header.mtime().unwrap_or(0).min(u32::MAX.into()) as u32;
+            let content = match entry_type {
+                EntryType::Directory => Content::Directory,
+                EntryType::Symlink => Content::Symlink(
+                    entry
+                        .link_name_bytes()
+                        .with_context(|| format!("Symlink {:?} without target", display(&raw)))?
+                        .into_owned(),
+                ),
+                // The link takes the metadata of its target, like in the extracted layers.
+                EntryType::Link => {
+                    let target = entry
+                        .link_name_bytes()
+                        .with_context(|| format!("Hard link {:?} without target", display(&raw)))?;
+                    let target = components(&target)?.join(&b'/');
+                    let node = match self.nodes.get(&target) {
+                        Some(node) if matches!(node.content, Content::File { .. }) => Node {
+                            layer,
+                            ..node.clone()
+                        },
+                        _ => bail!(
+                            "Hard link {:?} to {:?}, which isn't a file of the layers",
+                            display(&raw),
+                            display(&target)
+                        ),
+                    };
+                    self.create_parents(parents, &raw, layer)?;
+                    self.insert(path, node);
+                    continue;
+                }
+                EntryType::Regular | EntryType::Continuous => {
+                    let source = self.scratch.join(self.files.to_string());
+                    self.files += 1;
+                    let mut file = File::create(&source)
+                        .with_context(|| format!("Could not create {}", source.display()))?;
+                    let size = io::copy(&mut entry, &mut file)
+                        .with_context(|| format!("Failed to unpack {:?}", display(&raw)))?;
+                    Content::File { source, size }
+                }
+                EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                    warn!(path = ?display(&raw), "Skipping device node of the layer");
+                    continue;
+                }
+                other => {
+                    debug!(path = ?display(&raw), entry_type = ?other, "Skipping layer entry");
+                    continue;
+                }
+            };
+
+            self.create_parents(parents, &raw, layer)?;
+            self.insert(
+                path,
+                Node {
+                    permissions,
+                    mtime,
+                    content,
+                    layer,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Add the host file `source` at `path`, replacing the entry of the layers there.
+    pub fn insert_file(&mut self, path: &str, permissions: u32, source: &Path) -> Result<()> {
+        let size = fs::metadata(source)
+            .with_context(|| format!("Could not read {}", source.display()))?
+            .len();
+        let path = components(path.as_bytes())?.join(&b'/');
+        if let Some(parent) = path.iter().rposition(|byte| *byte == b'/') {
+            let parents: Vec<&[u8]> = path[..parent].split(|byte| *byte == b'/').collect();
+            self.create_parents(&parents, &path, self.layers)?;
+        }
+        self.insert(
+            path,
+            Node {
+                permissions,
+                mtime: 0,
+                content: Content::File {
+                    source: source.to_path_buf(),
+                    size,
+                },
+                layer: self.layers,
+            },
+        );
+        Ok(())
+    }
+
+    /// Write the rootfs to `out` as a cpio archive.
+    pub fn write_cpio<W: Write>(&self, out: W) -> Result<W> {
+        let mut writer = CpioWriter::new(out);
+        for (path, node) in &self.nodes {
+            let metadata = EntryMetadata {
+                mode: node.mode(),
+                mtime: node.mtime,
+                rdev: (0, 0),
+            };
+            match &node.content {
+                Content::Directory => writer.append(path, &metadata, 0, io::empty())?,
+                Content::File { source, size } => {
+                    let file = File::open(source)
+                        .with_context(|| format!("Could not open {}", source.display()))?;
+                    writer.append(path, &metadata, *size, file)?;
+                }
+                Content::Symlink(target) => {
+                    writer.append(path, &metadata, target.len() as u64, target.as_slice())?
+                }
+            }
+        }
+        writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::cpio::read_archive;
+    use cloudlet_test_registry::scratch_dir;
+    use tar::{Builder, Header};
+
+    /// Layer of the `entries`, at raw paths unchecked by the tar crate: a path ending with `/`
+    /// is a directory, `@` separates a symlink from its target and `=` a hard link from its
+    /// target, the other files holding their path.
+    fn layer(entries: &[&str]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            let (path, data) = if let Some((path, target)) = entry.split_once('@') {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_link_name(target).unwrap();
+                (path, &b""[..])
+            } else if let Some((path, target)) = entry.split_once('=') {
+                header.set_entry_type(EntryType::Link);
+                header.set_link_name(target).unwrap();
+                (path, &b""[..])
+            } else if entry.ends_with('/') {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o750);
+                (*entry, &b""[..])
+            } else {
+                header.set_mode(0o640);
+                (*entry, entry.as_bytes())
+            };
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mtime(1);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Names and modes of the entries of the cpio archive of `tree`.
+    fn entries(tree: &RootfsTree) -> Vec<(String, u32, Vec<u8>)> {
+        read_archive(&tree.write_cpio(Vec::new()).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                (
+                    String::from_utf8(entry.name).unwrap(),
+                    entry.metadata.mode,
+                    entry.data,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_layers() {
+        let dir = scratch_dir("fs-gen-tree-merge");
+        let mut tree = RootfsTree::new(&dir.join("files")).unwrap();
+        tree.apply_layer(
+            layer(&[
+                "bin/",
+                "bin/busybox",
+                "bin/sh@busybox",
+                "etc/removed",
+                "etc/kept",
+                "var/cache/old",
+                "usr/lib/libc",
+            ])
+            .as_slice(),
+        )
+        .unwrap();
+        tree.apply_layer(
+            layer(&[
+                "etc/.wh.removed",
+                "var/cache/.wh..wh..opq",
+                "var/cache/new",
+                "lib=usr/lib/libc",
+            ])
+            .as_slice(),
+        )
+        .unwrap();
+        tree.insert_file("init", 0o755, &dir.join("files/0"))
+            .unwrap();
+
+        let entries = entries(&tree);
+        let names: Vec<&str> = entries.iter().map(|(name, ..)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "bin",
+                "bin/busybox",
+                "bin/sh",
+                "etc",
+                "etc/kept",
+                "init",
+                "lib",
+                "usr",
+                "usr/lib",
+                "usr/lib/libc",
+                "var",
+                "var/cache",
+                "var/cache/new"
+            ]
+        );
+        assert_eq!(entries[0].1, S_IFDIR | 0o750);
+        assert_eq!(entries[1].2, b"bin/busybox");
+        assert_eq!(entries[2], ("bin/sh".into(), S_IFLNK, b"busybox".to_vec()));
+        assert_eq!(entries[5].1, S_IFREG | 0o755);
+        assert_eq!(
+            entries[6],
+            ("lib".into(), S_IFREG | 0o640, b"usr/lib/libc".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_reject_unsafe_entries() {
+        let dir = scratch_dir("fs-gen-tree-unsafe");
+        for (entries, expected) in [
+            (&["/etc/passwd"][..], "absolute"),
+            (&["../secret"][..], "parent"),
+            (&["etc@/etc", "etc/passwd"][..], "symlink"),
+            (&["shadow=../secret"][..], "parent"),
+        ] {
+            let mut tree = RootfsTree::new(&dir.join(expected)).unwrap();
+            let error = tree.apply_layer(layer(entries).as_slice()).unwrap_err();
+            let error = error.downcast_ref::<UnsafeEntry>();
+            match expected {
+                "absolute" => assert!(matches!(error, Some(UnsafeEntry::AbsolutePath(_)))),
+                "parent" => assert!(matches!(error, Some(UnsafeEntry::ParentDir(_)))),
+                _ => assert!(matches!(error, Some(UnsafeEntry::ThroughSymlink { .. }))),
+            }
+        }
+
+        // A directory of an upper layer replaces the symlink of a lower one.
+        let mut tree = RootfsTree::new(&dir.join("replaced")).unwrap();
+        tree.apply_layer(layer(&["etc@/etc"]).as_slice()).unwrap();
+        tree.apply_layer(layer(&["etc/passwd"]).as_slice()).unwrap();
+        assert_eq!(entries(&tree)[0].1, S_IFDIR | 0o755);
+    }
+}
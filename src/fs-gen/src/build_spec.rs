@@ -0,0 +1,543 @@
+//! Build specs: a subset of the Dockerfile syntax customizing a base image without a Docker
+//! daemon.
+//!
+//! ```text
+//! FROM python:3.12-alpine
+//! COPY requirements.txt /app/
+//! ENV PIP_NO_CACHE_DIR=1
+//! RUN apk add --no-cache gcc musl-dev && pip install -r /app/requirements.txt
+//! ```
+//!
+//! `COPY` takes its sources from the directory of the spec, and its destination is absolute or
+//! relative to `/`. `RUN` only installs packages: its commands, separated by `&&`, are run
+//! without a shell, so quoting, variables and redirections aren't supported.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info};
+
+use crate::cli_args::Runner;
+
+/// File of the rootfs holding the `ENV` of the spec, read by the init for the agent.
+const ENV_FILE: &str = "etc/cloudlet/env";
+
+/// `PATH` of the `RUN` commands until an `ENV` sets it.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Commands accepted by `RUN`, with the subcommands accepted for each, `None` accepting any.
+const PACKAGE_COMMANDS: [(&str, Option<&[&str]>); 12] = [
+    ("apk", Some(&["add", "update", "upgrade"])),
+    ("apt-get", Some(&["update", "install", "upgrade", "clean"])),
+    ("apt", Some(&["update", "install", "upgrade", "clean"])),
+    ("dnf", Some(&["install", "upgrade", "clean"])),
+    ("yum", Some(&["install", "update", "clean"])),
+    ("microdnf", Some(&["install", "update", "clean"])),
+    ("pip", Some(&["install"])),
+    ("pip3", Some(&["install"])),
+    ("npm", Some(&["install", "i"])),
+    ("cargo", Some(&["install"])),
+    ("gem", Some(&["install"])),
+    ("update-ca-certificates", None),
+];
+
+/// Characters which would need a shell.
+const SHELL_CHARACTERS: &[char] = &[
+    ';', '|', '&', '$', '`', '<', '>', '(', ')', '{', '}', '*', '?', '\'', '"', '\\', '!', '#',
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    Copy {
+        sources: Vec<PathBuf>,
+        destination: PathBuf,
+    },
+    Env(Vec<(String, String)>),
+    /// Commands to run one after the other, each one as its arguments.
+    Run(Vec<Vec<String>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildSpec {
+    /// Image customized by the spec.
+    pub from: String,
+    pub instructions: Vec<Instruction>,
+}
+
+impl BuildSpec {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut from = None;
+        let mut instructions = Vec::new();
+
+        for (number, line) in logical_lines(spec) {
+            let (keyword, arguments) = line
+                .split_once(char::is_whitespace)
+                .map(|(keyword, arguments)| (keyword, arguments.trim()))
+                .unwrap_or((line.as_str(), ""));
+            let keyword = keyword.to_ascii_uppercase();
+            if arguments.is_empty() {
+                bail!("line {}: {} without arguments", number, keyword);
+            }
+
+            match (keyword.as_str(), &from) {
+                ("FROM", None) => {
+                    if arguments.split_whitespace().count() != 1 {
+                        bail!("line {}: FROM only takes an image name", number);
+                    }
+                    from = Some(arguments.to_string());
+                }
+                ("FROM", Some(_)) => bail!("line {}: multi-stage builds aren't supported", number),
+                (_, None) => bail!("line {}: the spec must start with FROM", number),
+                ("COPY", _) => {
+                    instructions.push(parse_copy(arguments).with_context(|| line_context(number))?)
+                }
+                ("ENV", _) => {
+                    instructions.push(parse_env(arguments).with_context(|| line_context(number))?)
+                }
+                ("RUN", _) => {
+                    instructions.push(parse_run(arguments).with_context(|| line_context(number))?)
+                }
+                (keyword, _) => bail!(
+                    "line {}: {} isn't supported, only FROM, COPY, ENV and RUN are",
+                    number,
+                    keyword
+                ),
+            }
+        }
+
+        Ok(Self {
+            from: from.context("the spec has no FROM")?,
+            instructions,
+        })
+    }
+
+    /// Environment set by the `ENV` instructions.
+    pub fn env(&self) -> BTreeMap<String, String> {
+        let mut env = BTreeMap::new();
+        for instruction in &self.instructions {
+            if let Instruction::Env(variables) = instruction {
+                env.extend(variables.iter().cloned());
+            }
+        }
+        env
+    }
+}
+
+fn line_context(number: usize) -> String {
+    format!("line {}", number)
+}
+
+/// Lines of the spec with their number, the continuations joined and the comments skipped.
+fn logical_lines(spec: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    for (index, line) in spec.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('#') || (trimmed.is_empty() && current.is_none()) {
+            continue;
+        }
+        let (content, continued) = match trimmed.strip_suffix('\\') {
+            Some(content) => (content.trim_end(), true),
+            None => (trimmed, false),
+        };
+        let (_, logical) = current.get_or_insert_with(|| (index + 1, String::new()));
+        if !logical.is_empty() && !content.is_empty() {
+            logical.push(' ');
+        }
+        logical.push_str(content);
+        if !continued {
+            lines.extend(current.take());
+        }
+    }
+    lines.extend(current);
+    lines
+}
+
+fn parse_copy(arguments: &str) -> Result<Instruction> {
+    let mut paths: Vec<&str> = arguments.split_whitespace().collect();
+    if paths.iter().any(|path| path.starts_with("--")) {
+        bail!("COPY options aren't supported");
+    }
+    if paths.len() < 2 {
+        bail!("COPY takes at least a source and a destination");
+    }
+    let destination = PathBuf::from(paths.pop().unwrap());
+    if destination
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        bail!("the destination of COPY can't contain '..'");
+    }
+    let sources = paths.into_iter().map(PathBuf::from).collect::<Vec<_>>();
+    for source in &sources {
+        if source.is_absolute()
+            || source
+                .components()
+                .any(|component| component == Component::ParentDir)
+        {
+            bail!(
+                "the source {} of COPY must be inside the directory of the spec",
+                source.display()
+            );
+        }
+    }
+
+    Ok(Instruction::Copy {
+        sources,
+        destination,
+    })
+}
+
+fn parse_env(arguments: &str) -> Result<Instruction> {
+    let unquote = |value: &str| {
+        value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value)
+            .to_string()
+    };
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+
+    let variables = if arguments.contains('=') {
+        arguments
+            .split_whitespace()
+            .map(|variable| match variable.split_once('=') {
+                Some((name, value)) if is_name(name) => Ok((name.to_string(), unquote(value))),
+                _ => bail!("invalid variable '{}', expected NAME=VALUE", variable),
+            })
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        // The legacy `ENV NAME VALUE` form.
+        match arguments.split_once(char::is_whitespace) {
+            Some((name, value)) if is_name(name) => {
+                vec![(name.to_string(), unquote(value.trim()))]
+            }
+            _ => bail!("expected ENV NAME=VALUE"),
+        }
+    };
+
+    if let Some((name, _)) = variables
+        .iter()
+        .find(|(_, value)| value.contains('\n') || value.contains('\0'))
+    {
+        bail!("invalid value of {}", name);
+    }
+    Ok(Instruction::Env(variables))
+}
+
+fn parse_run(arguments: &str) -> Result<Instruction> {
+    let mut commands = vec![Vec::new()];
+    for word in arguments.split_whitespace() {
+        if word == "&&" {
+            commands.push(Vec::new());
+            continue;
+        }
+        if word.contains(SHELL_CHARACTERS) {
+            bail!(
+                "'{}' needs a shell: RUN only installs packages, its commands are only separated by '&&'",
+                word
+            );
+        }
+        commands.last_mut().unwrap().push(word.to_string());
+    }
+
+    for command in &commands {
+        let Some(program) = command.first() else {
+            bail!("empty command in RUN");
+        };
+        let subcommands = PACKAGE_COMMANDS
+            .iter()
+            .find(|(name, _)| *name == program.as_str())
+            .map(|(_, subcommands)| subcommands)
+            .with_context(|| {
+                format!("RUN only installs packages, '{}' isn't supported", program)
+            })?;
+        if let Some(subcommands) = subcommands {
+            // The first word which isn't an option, e.g. `apk --no-cache add`.
+            let subcommand = command[1..].iter().find(|word| !word.starts_with('-'));
+            if !subcommand.is_some_and(|subcommand| subcommands.contains(&subcommand.as_str())) {
+                bail!(
+                    "RUN only installs packages, '{}' accepts {}",
+                    program,
+                    subcommands.join(", ")
+                );
+            }
+        }
+    }
+
+    Ok(Instruction::Run(commands))
+}
+
+/// Applies the instructions of a spec to a rootfs.
+pub struct SpecBuilder {
+    /// Directory of the spec, where the sources of `COPY` are.
+    pub context: PathBuf,
+    pub runner: Runner,
+    /// Image of the helper container of the docker and podman runners.
+    pub helper_image: String,
+}
+
+impl SpecBuilder {
+    pub fn apply(&self, spec: &BuildSpec, rootfs: &Path) -> Result<()> {
+        info!("Applying the build spec...");
+
+        let context = self
+            .context
+            .canonicalize()
+            .with_context(|| format!("Failed to open the build context {:?}", self.context))?;
+        let mut env = BTreeMap::from([("PATH".to_string(), DEFAULT_PATH.to_string())]);
+        let mut ran = false;
+        for instruction in &spec.instructions {
+            match instruction {
+                Instruction::Copy {
+                    sources,
+                    destination,
+                } => copy(&context, sources, rootfs, destination)?,
+                Instruction::Env(variables) => env.extend(variables.iter().cloned()),
+                Instruction::Run(commands) => {
+                    for command in commands {
+                        info!(command = command.join(" "), "Running");
+                        self.run(rootfs, &env, command)?;
+                    }
+                    ran = true;
+                }
+            }
+        }
+        if ran && self.runner != Runner::Proot {
+            self.give_back(rootfs)?;
+        }
+
+        let spec_env = spec.env();
+        if !spec_env.is_empty() {
+            write_env(rootfs, &spec_env)?;
+        }
+
+        info!("Build spec applied!");
+        Ok(())
+    }
+
+    fn run(&self, rootfs: &Path, env: &BTreeMap<String, String>, command: &[String]) -> Result<()> {
+        let mut process = match self.runner {
+            Runner::Proot => {
+                let mut process = Command::new("proot");
+                // Faking root for the package managers, with the name servers of the host.
+                process
+                    .arg("-0")
+                    .arg("-r")
+                    .arg(rootfs)
+                    .args(["-w", "/", "-b", "/dev", "-b", "/proc", "-b", "/sys"])
+                    .args(["-b", "/etc/resolv.conf"])
+                    .env_clear()
+                    .envs(env);
+                process
+            }
+            Runner::Docker | Runner::Podman => {
+                let mut process = Command::new(self.runner.as_str());
+                process
+                    .args(["run", "--rm", "--network", "host"])
+                    .arg("-v")
+                    .arg(format!("{}:/rootfs", rootfs.display()));
+                for (name, value) in env {
+                    process.arg("-e").arg(format!("{}={}", name, value));
+                }
+                // The name servers of the host are copied in for the run, then restored.
+                process.arg(&self.helper_image).args([
+                    "sh",
+                    "-c",
+                    "cp -a /rootfs/etc/resolv.conf /tmp/resolv.conf 2>/dev/null; \
+                     cp /etc/resolv.conf /rootfs/etc/resolv.conf; \
+                     chroot /rootfs \"$@\"; status=$?; \
+                     rm -f /rootfs/etc/resolv.conf; \
+                     cp -a /tmp/resolv.conf /rootfs/etc/resolv.conf 2>/dev/null; \
+                     exit $status",
+                    "run",
+                ]);
+                process
+            }
+        };
+        process.args(command);
+
+        debug!(command = ?process, "Running");
+        let status = process
+            .status()
+            .with_context(|| format!("Failed to start {}", self.runner.as_str()))?;
+        if !status.success() {
+            bail!("RUN {} failed: {}", command.join(" "), status);
+        }
+        Ok(())
+    }
+
+    /// Give the files created by the root of the helper container back to the owner of the
+    /// rootfs, for fs-gen to read and remove them.
+    fn give_back(&self, rootfs: &Path) -> Result<()> {
+        let metadata = fs::metadata(rootfs)?;
+        let (uid, gid) = (metadata.uid(), metadata.gid());
+        let status = Command::new(self.runner.as_str())
+            .args(["run", "--rm"])
+            .arg("-v")
+            .arg(format!("{}:/rootfs", rootfs.display()))
+            .arg(&self.helper_image)
+            .args(["chown", "-R", &format!("{}:{}", uid, gid), "/rootfs"])
+            .status()
+            .with_context(|| format!("Failed to start {}", self.runner.as_str()))?;
+        if !status.success() {
+            bail!(
+                "Failed to take back the ownership of the rootfs: {}",
+                status
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Path of `path` in `rootfs`, failing if it goes through a symlink, which could point out of
+/// the rootfs.
+fn rootfs_path(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+    let mut resolved = rootfs.to_path_buf();
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            resolved.push(name);
+            if resolved.is_symlink() {
+                bail!(
+                    "the destination {} goes through a symlink of the image",
+                    path.display()
+                );
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+fn copy(context: &Path, sources: &[PathBuf], rootfs: &Path, destination: &Path) -> Result<()> {
+    let target = rootfs_path(rootfs, destination)?;
+    let into_directory =
+        sources.len() > 1 || destination.to_string_lossy().ends_with('/') || target.is_dir();
+
+    for source in sources {
+        let path = context
+            .join(source)
+            .canonicalize()
+            .with_context(|| format!("COPY source {} not found", source.display()))?;
+        if !path.starts_with(context) {
+            bail!(
+                "the source {} of COPY is outside of the directory of the spec",
+                source.display()
+            );
+        }
+        let target = if into_directory {
+            fs::create_dir_all(&target)?;
+            target.join(path.file_name().unwrap_or_default())
+        } else {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            target.clone()
+        };
+        debug!(source = ?path, target = ?target, "Copying");
+        if path.is_dir() {
+            dircpy::copy_dir(&path, &target)
+                .with_context(|| format!("Failed to copy {}", source.display()))?;
+        } else {
+            fs::copy(&path, &target)
+                .with_context(|| format!("Failed to copy {}", source.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Environment of the image, given to the agent by the init.
+pub fn read_env(rootfs: &Path) -> Result<BTreeMap<String, String>> {
+    let path = rootfs_path(rootfs, Path::new(ENV_FILE))?;
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", ENV_FILE))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect())
+}
+
+pub fn write_env(rootfs: &Path, env: &BTreeMap<String, String>) -> Result<()> {
+    let path = rootfs_path(rootfs, Path::new(ENV_FILE))?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    let content: String = env
+        .iter()
+        .map(|(name, value)| format!("{}={}\n", name, value))
+        .collect();
+    fs::write(&path, content).with_context(|| format!("Failed to write {}", ENV_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let spec = BuildSpec::parse(
+            "# comment\n\
+             FROM python:3.12-alpine\n\
+             \n\
+             COPY requirements.txt src /app/\n\
+             env PIP_NO_CACHE_DIR=1 GREETING=\"hi\"\n\
+             ENV LANG C.UTF-8\n\
+             RUN apk add --no-cache gcc \\\n    musl-dev && pip install -r /app/requirements.txt\n",
+        )
+        .unwrap();
+
+        assert_eq!(spec.from, "python:3.12-alpine");
+        assert_eq!(
+            spec.instructions,
+            vec![
+                Instruction::Copy {
+                    sources: vec!["requirements.txt".into(), "src".into()],
+                    destination: "/app/".into(),
+                },
+                Instruction::Env(vec![
+                    ("PIP_NO_CACHE_DIR".into(), "1".into()),
+                    ("GREETING".into(), "hi".into()),
+                ]),
+                Instruction::Env(vec![("LANG".into(), "C.UTF-8".into())]),
+                Instruction::Run(vec![
+                    ["apk", "add", "--no-cache", "gcc", "musl-dev"]
+                        .map(String::from)
+                        .to_vec(),
+                    ["pip", "install", "-r", "/app/requirements.txt"]
+                        .map(String::from)
+                        .to_vec(),
+                ]),
+            ]
+        );
+        assert_eq!(spec.env().len(), 3);
+    }
+
+    #[test]
+    fn test_parse_rejects_unsupported() {
+        for spec in [
+            "COPY a /a",
+            "FROM alpine\nFROM alpine",
+            "FROM alpine AS build",
+            "FROM alpine\nWORKDIR /app",
+            "FROM alpine\nCOPY ../secret /a",
+            "FROM alpine\nCOPY --from=build /a /a",
+            "FROM alpine\nCOPY a /../a",
+            "FROM alpine\nRUN curl https://example.com/install.sh",
+            "FROM alpine\nRUN apk add gcc; rm -rf /",
+            "FROM alpine\nRUN apk del gcc",
+            "FROM alpine\nRUN apk add $(cat packages)",
+            "FROM alpine\nRUN apk add gcc &&",
+            "FROM alpine\nENV 1A=b",
+        ] {
+            assert!(BuildSpec::parse(spec).is_err(), "{:?}", spec);
+        }
+    }
+}
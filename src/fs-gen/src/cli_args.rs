@@ -1,23 +1,151 @@
 use std::{env, path::PathBuf};
 
-use clap::{command, error::ErrorKind, ArgAction, CommandFactory, Parser};
+use clap::{
+    command, error::ErrorKind, ArgAction, Args, Command, CommandFactory, Parser, ValueEnum,
+};
 use clap_stdin::MaybeStdin;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use shared_models::{logging::LogArgs, Language};
 
 use once_cell::sync::Lazy;
 
+use crate::scan::ScanOptions;
+
 // So, for any of you who may be scared, this is the regex from the OCI Distribution Sepcification for the image name + the tag
 static RE_IMAGE_NAME: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*(/[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*)*(?::[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127})?").unwrap()
 });
 
-/// Convert an OCI image into a CPIO file
+/// Format of the generated rootfs.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// CPIO archive, loaded as an initramfs: the whole rootfs is kept in the guest RAM
+    #[default]
+    Cpio,
+    /// Writable ext4 disk image
+    Ext4,
+    /// Read-only, compressed erofs disk image
+    Erofs,
+}
+
+impl OutputFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Cpio => "cpio",
+            OutputFormat::Ext4 => "ext4",
+            OutputFormat::Erofs => "erofs",
+        }
+    }
+}
+
+/// Format of the software bill of materials of the image.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    /// CycloneDX 1.5 JSON
+    Cyclonedx,
+    /// SPDX 2.3 JSON
+    Spdx,
+}
+
+/// Vulnerability scanner run on the rootfs.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scanner {
+    Trivy,
+    Grype,
+}
+
+impl Scanner {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scanner::Trivy => "trivy",
+            Scanner::Grype => "grype",
+        }
+    }
+}
+
+/// Severity of a vulnerability, from the least to the most severe.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Unknown,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+/// What a scan finding vulnerabilities at or above the threshold does.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScanAction {
+    /// Fail the build
+    #[default]
+    Fail,
+    /// Generate the image, flagged as vulnerable in its scan summary and pushed artifact
+    Flag,
+}
+
+/// How the `RUN` instructions of a build spec are executed.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Runner {
+    /// In the rootfs with proot, without any privilege
+    #[default]
+    Proot,
+    /// Chrooted into the rootfs in a helper Docker container
+    Docker,
+    /// Chrooted into the rootfs in a helper Podman container
+    Podman,
+}
+
+impl Runner {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Runner::Proot => "proot",
+            Runner::Docker => "docker",
+            Runner::Podman => "podman",
+        }
+    }
+}
+
+/// Convert an OCI image into a CPIO file or a disk image
 #[derive(Parser, Debug, Clone)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Run `fs-gen build --help` to customize the image with a build spec, `fs-gen push --help` to push a generated image to a registry."
+)]
 pub struct CliArgs {
     /// The name of the image to download, can include repository and tag: [REPOSITORY/NAME:TAG]
     pub image_name: String,
 
+    #[command(flatten)]
+    pub image: ImageArgs,
+}
+
+/// Build an image from a build spec: a Dockerfile with only `FROM`, `COPY`, `ENV` and `RUN`
+/// limited to package installs
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, long_about = None, bin_name = "fs-gen build")]
+pub struct BuildArgs {
+    /// The build spec, whose directory is the context of its `COPY` instructions
+    pub spec_path: PathBuf,
+
+    #[command(flatten)]
+    pub image: ImageArgs,
+
+    /// How the `RUN` instructions are executed
+    #[arg(long = "runner", value_enum, default_value_t)]
+    pub runner: Runner,
+
+    /// Image of the helper container of the docker and podman runners
+    #[arg(long = "helper-image", default_value = "alpine:3.19")]
+    pub helper_image: String,
+}
+
+/// Options of the generated image, shared by the conversion of an image and `fs-gen build`
+#[derive(Args, Debug, Clone)]
+pub struct ImageArgs {
     /// The host path to the guest agent binary
     pub agent_host_path: PathBuf,
 
@@ -29,15 +157,20 @@ pub struct CliArgs {
     #[arg(short='t', long="tempdir", default_value=get_default_temp_directory().into_os_string())]
     pub temp_directory: PathBuf,
 
+    /// The host path to the guest init binary, by default the `init` next to the agent binary
     #[arg(short='i', long="init", default_value=None)]
     pub initfile_path: Option<PathBuf>,
 
     #[arg(long = "arch", default_value = "amd64")]
     pub architecture: String,
 
+    /// Log from the debug level, unless `--log` says otherwise
     #[arg(short='d', long="debug", action=ArgAction::SetTrue)]
     pub debug: bool,
 
+    #[command(flatten)]
+    pub log: LogArgs,
+
     /// Username to pull image from a private repository
     #[arg(short='u', long="username", default_value=None)]
     pub username: Option<String>,
@@ -53,6 +186,178 @@ pub struct CliArgs {
     /// Disable the compression of the final image
     #[arg(short, long, action=ArgAction::SetTrue)]
     pub no_compression: bool,
+
+    /// Format of the final image
+    #[arg(short = 'f', long = "format", value_enum, default_value_t)]
+    pub format: OutputFormat,
+
+    /// Size of the ext4 image in MB (default: the size of the rootfs, plus some free space)
+    #[arg(long = "size-mb", default_value = None)]
+    pub size_mb: Option<u64>,
+
+    /// Create the ext4 filesystem without a journal
+    #[arg(long="no-journal", action=ArgAction::SetTrue)]
+    pub no_journal: bool,
+
+    /// Size of the ext4 journal in MB (default: chosen by mkfs.ext4)
+    #[arg(long = "journal-size-mb", default_value = None)]
+    pub journal_size_mb: Option<u64>,
+
+    /// Write a bill of the packages found in the rootfs next to the image, as
+    /// `<OUTPUT>.cdx.json` or `<OUTPUT>.spdx.json`
+    #[arg(long = "sbom", value_enum, default_value = None)]
+    pub sbom: Option<SbomFormat>,
+
+    /// Also embed the bill in the image, as `/etc/cloudlet/sbom.cdx.json` or
+    /// `/etc/cloudlet/sbom.spdx.json`
+    #[arg(long = "embed-sbom", action=ArgAction::SetTrue, requires = "sbom")]
+    pub embed_sbom: bool,
+
+    /// Scan the rootfs for vulnerabilities before generating the image, the summary being
+    /// written as `<OUTPUT>.scan.json`
+    #[arg(long = "scan", value_enum, default_value = None)]
+    pub scan: Option<Scanner>,
+
+    /// Use this trivy or grype JSON report of the image instead of running a scanner
+    #[arg(long = "scan-report", default_value = None, conflicts_with = "scan")]
+    pub scan_report: Option<PathBuf>,
+
+    /// Lowest severity of the vulnerabilities failing or flagging the image
+    #[arg(long = "severity-threshold", value_enum, default_value = "high")]
+    pub severity_threshold: Severity,
+
+    /// What vulnerabilities at or above the threshold do
+    #[arg(long = "on-vulnerability", value_enum, default_value_t)]
+    pub on_vulnerability: ScanAction,
+
+    /// Run this command with the /bin/sh of the image inside the rootfs before generating the
+    /// image, e.g. to precompile bytecode or warm caches; can be repeated, a failure aborts the
+    /// build
+    #[arg(long = "run-in-rootfs", value_name = "COMMAND")]
+    pub run_in_rootfs: Vec<String>,
+
+    /// Warm up the runtime of this language in the rootfs before generating the image: the
+    /// Python bytecode, the V8 code cache of Node, the crates.io index of cargo
+    #[arg(long = "warmup", value_enum, value_name = "LANGUAGE")]
+    pub warmup: Option<Language>,
+
+    /// Sign the image with this minisign secret key, the signature being written as
+    /// `<OUTPUT>.minisig` for the orchestrators to check it
+    #[arg(long = "sign-key", default_value = None)]
+    pub sign_key: Option<PathBuf>,
+
+    /// Write the metrics of the generation as JSON to this file: the bytes of the layers
+    /// downloaded, and the seconds spent extracting them and packing the image
+    #[arg(long = "metrics-file", default_value = None)]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Assemble the initramfs from the layers in memory, without extracting nor mounting them:
+    /// only the cpio format is supported, without --sbom nor --scan. Always on outside of Linux
+    #[arg(long="userland", action=ArgAction::SetTrue)]
+    pub userland: bool,
+}
+
+/// Push a generated image to a registry as an OCI artifact, for the orchestrators to pull it
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, long_about = None, bin_name = "fs-gen push")]
+pub struct PushArgs {
+    /// The generated initramfs or disk image
+    pub image_path: PathBuf,
+
+    /// The reference to push the artifact to: [REGISTRY/REPOSITORY/NAME:TAG]
+    pub reference: String,
+
+    /// Format of the image
+    #[arg(short = 'f', long = "format", value_enum, default_value_t)]
+    pub format: OutputFormat,
+
+    /// Log from the debug level, unless `--log` says otherwise
+    #[arg(short='d', long="debug", action=ArgAction::SetTrue)]
+    pub debug: bool,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+
+    /// Username to push to the registry
+    #[arg(short='u', long="username", default_value=None)]
+    pub username: Option<String>,
+
+    /// Password can also be passed via STDIN: [echo <PASSWORD> | fs-gen push ... -p -]
+    #[arg(short='p', long="password", default_value=None)]
+    pub password: Option<MaybeStdin<String>>,
+
+    /// Allow invalid TLS certificates
+    #[arg(long="insecure", action=ArgAction::SetTrue)]
+    pub insecure: bool,
+}
+
+/// Sign artifacts, e.g. the guest kernels, for the orchestrators checking their signatures
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, long_about = None, bin_name = "fs-gen sign")]
+pub struct SignArgs {
+    /// The artifacts to sign, each signature being written as `<ARTIFACT>.minisig`
+    #[arg(required = true)]
+    pub artifacts: Vec<PathBuf>,
+
+    /// The minisign secret key
+    #[arg(short = 'k', long = "key")]
+    pub key: PathBuf,
+
+    /// Log from the debug level, unless `--log` says otherwise
+    #[arg(short='d', long="debug", action=ArgAction::SetTrue)]
+    pub debug: bool,
+
+    #[command(flatten)]
+    pub log: LogArgs,
+}
+
+impl SignArgs {
+    /// Get the arguments following `sign`
+    pub fn get_args() -> Self {
+        SignArgs::parse_from(env::args_os().skip(1))
+    }
+}
+
+impl PushArgs {
+    /// Get the arguments following `push` with additional validation
+    pub fn get_args() -> Self {
+        let args = PushArgs::parse_from(env::args_os().skip(1));
+
+        if !RE_IMAGE_NAME.is_match(&args.reference) {
+            PushArgs::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("Invalid reference: \"{}\"", args.reference),
+                )
+                .exit();
+        }
+        if !args.image_path.is_file() {
+            PushArgs::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!(
+                        "File not found for image: \"{}\"",
+                        args.image_path.to_string_lossy()
+                    ),
+                )
+                .exit();
+        }
+        if args.username.is_some() != args.password.is_some() {
+            PushArgs::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    "Define both username and password to push to a private registry.",
+                )
+                .exit();
+        }
+
+        args
+    }
+}
+
+/// Whether `name` is a valid image name, with an optional tag.
+pub fn is_valid_image_name(name: &str) -> bool {
+    RE_IMAGE_NAME.is_match(name)
 }
 
 impl CliArgs {
@@ -61,14 +366,13 @@ impl CliArgs {
         let args = CliArgs::parse();
 
         args.validate_image();
-        args.validate_host_path();
-        args.validate_auth();
+        args.image.validate(&mut CliArgs::command());
 
         args
     }
 
     fn validate_image(&self) {
-        if !RE_IMAGE_NAME.is_match(&self.image_name) {
+        if !is_valid_image_name(&self.image_name) {
             let mut cmd = CliArgs::command();
             cmd.error(
                 ErrorKind::InvalidValue,
@@ -77,10 +381,83 @@ impl CliArgs {
             .exit();
         }
     }
+}
 
-    fn validate_host_path(&self) {
+impl BuildArgs {
+    /// Get the arguments following `build` with additional validation
+    pub fn get_args() -> Self {
+        let args = BuildArgs::parse_from(env::args_os().skip(1));
+
+        let mut cmd = BuildArgs::command();
+        if !args.spec_path.is_file() {
+            cmd.error(
+                ErrorKind::InvalidValue,
+                format!(
+                    "File not found for build spec: \"{}\"",
+                    args.spec_path.to_string_lossy()
+                ),
+            )
+            .exit();
+        }
+        args.image.validate(&mut cmd);
+
+        args
+    }
+}
+
+impl ImageArgs {
+    fn validate(&self, cmd: &mut Command) {
+        self.validate_host_path(cmd);
+        self.validate_auth(cmd);
+        self.validate_format(cmd);
+        self.validate_userland(cmd);
+    }
+
+    /// Whether the rootfs is assembled in memory, the only way outside of Linux.
+    pub fn userland(&self) -> bool {
+        self.userland || !cfg!(target_os = "linux")
+    }
+
+    fn validate_userland(&self, cmd: &mut Command) {
+        if !self.userland() {
+            return;
+        }
+        let reason = if self.userland {
+            "with --userland"
+        } else {
+            "outside of Linux"
+        };
+        if self.format != OutputFormat::Cpio {
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                format!("Only the cpio format can be generated {}", reason),
+            )
+            .exit();
+        }
+        if self.sbom.is_some() || self.scan.is_some() {
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "--sbom and --scan read the extracted rootfs, which isn't available {}: give the report of a scan with --scan-report instead",
+                    reason
+                ),
+            )
+            .exit();
+        }
+        if !self.run_in_rootfs.is_empty() || self.warmup.is_some() {
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "--run-in-rootfs and --warmup run in the extracted rootfs, which isn't available {}",
+                    reason
+                ),
+            )
+            .exit();
+        }
+    }
+
+    fn validate_host_path(&self, cmd: &mut Command) {
         if !self.agent_host_path.exists() {
-            let mut cmd = CliArgs::command();
             cmd.error(
                 ErrorKind::InvalidValue,
                 format!(
@@ -90,10 +467,45 @@ impl CliArgs {
             )
             .exit();
         }
+        let init_path = self.init_path();
+        if !init_path.exists() {
+            cmd.error(
+                ErrorKind::InvalidValue,
+                format!(
+                    "File not found for init binary: \"{}\", build it with `cargo build --release --bin init --target=x86_64-unknown-linux-musl` or give it with --init",
+                    init_path.to_string_lossy()
+                ),
+            )
+            .exit();
+        }
+    }
+
+    /// Options of the vulnerability scan of the rootfs.
+    pub fn scan_options(&self) -> ScanOptions {
+        ScanOptions {
+            scanner: self.scan,
+            report: self.scan_report.clone(),
+            threshold: self.severity_threshold,
+            action: self.on_vulnerability,
+        }
     }
 
-    fn validate_auth(&self) {
-        let mut cmd = CliArgs::command();
+    /// Where the bill of materials is written, next to the image.
+    pub fn sbom_path(&self, format: SbomFormat) -> PathBuf {
+        let mut path = self.output_file.clone().into_os_string();
+        path.push(".");
+        path.push(format.extension());
+        PathBuf::from(path)
+    }
+
+    /// The host path to the guest init binary.
+    pub fn init_path(&self) -> PathBuf {
+        self.initfile_path
+            .clone()
+            .unwrap_or_else(|| self.agent_host_path.with_file_name("init"))
+    }
+
+    fn validate_auth(&self, cmd: &mut Command) {
         let instruction =
             "Define both username and password to connect to a private image repository.";
         if self.username.is_none() && self.password.is_some() {
@@ -111,11 +523,31 @@ impl CliArgs {
             .exit();
         }
     }
+
+    fn validate_format(&self, cmd: &mut Command) {
+        if self.format != OutputFormat::Ext4
+            && (self.size_mb.is_some() || self.no_journal || self.journal_size_mb.is_some())
+        {
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                "--size-mb, --no-journal and --journal-size-mb only apply to the ext4 format",
+            )
+            .exit();
+        }
+        if self.no_journal && self.journal_size_mb.is_some() {
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                "--journal-size-mb can't be used with --no-journal",
+            )
+            .exit();
+        }
+    }
 }
 
-/// Get the default temporary directory for the current execution.
+/// Get the default temporary directory for the current execution, distinct for each process
+/// so that concurrent builds don't share their layers.
 fn get_default_temp_directory() -> PathBuf {
-    PathBuf::from("/tmp/cloudlet-fs-gen")
+    env::temp_dir().join(format!("cloudlet-fs-gen-{}", std::process::id()))
 }
 
 /// Get the default output file path for the generated initramfs.
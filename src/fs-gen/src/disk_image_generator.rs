@@ -0,0 +1,116 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+use tracing::{debug, info};
+
+/// Free space added to the content of the image when its size isn't given, in MB.
+const DEFAULT_FREE_SPACE_MB: u64 = 64;
+
+/// Options of the ext4 disk images.
+#[derive(Debug, Clone, Copy)]
+pub struct Ext4Options {
+    /// Size of the image, computed from the content of the rootfs when not given.
+    pub size_mb: Option<u64>,
+    /// Create the filesystem without a journal.
+    pub no_journal: bool,
+    /// Size of the journal, chosen by mkfs.ext4 when not given.
+    pub journal_size_mb: Option<u64>,
+}
+
+/// Total size of the files under `path`, without following symlinks.
+fn directory_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path).with_context(|| format!("Failed to read {:?}", path))? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            directory_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    debug!(command = ?command, "Running");
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run {:?}", command.get_program()))?;
+    if !status.success() {
+        bail!("{:?} exited with {}", command.get_program(), status);
+    }
+
+    Ok(())
+}
+
+fn create_output(output: &Path) -> Result<File> {
+    let file = File::create(output)
+        .with_context(|| "Could not open output file to write disk image".to_string())?;
+    file.set_permissions(Permissions::from_mode(0o644))
+        .with_context(|| "Failed to set permissions for output file".to_string())?;
+
+    Ok(file)
+}
+
+/// Generate an ext4 disk image holding the rootfs, with `mkfs.ext4 -d`.
+pub fn generate_ext4(root_directory: &Path, output: &Path, options: Ext4Options) -> Result<()> {
+    info!("Generating ext4 image...");
+
+    let size_mb = match options.size_mb {
+        Some(size_mb) => size_mb,
+        None => {
+            // Leave room for the metadata, the journal and the writes of the workload.
+            let content_mb = directory_size(root_directory)?.div_ceil(1024 * 1024);
+            content_mb + content_mb / 2 + DEFAULT_FREE_SPACE_MB
+        }
+    };
+    debug!(size_mb, "ext4 image size");
+
+    let file = create_output(output)?;
+    file.set_len(size_mb * 1024 * 1024)
+        .with_context(|| "Failed to allocate the disk image".to_string())?;
+
+    let mut command = Command::new("mkfs.ext4");
+    command
+        .args(["-F", "-q", "-L", "cloudlet-root", "-E", "root_owner=0:0"])
+        .arg("-d")
+        .arg(root_directory);
+    if options.no_journal {
+        command.args(["-O", "^has_journal"]);
+    } else if let Some(journal_size_mb) = options.journal_size_mb {
+        command.arg("-J").arg(format!("size={}", journal_size_mb));
+    }
+    command.arg(output);
+    run(&mut command).with_context(|| "Failed to create the ext4 filesystem".to_string())?;
+
+    info!("ext4 image generated!");
+
+    Ok(())
+}
+
+/// Generate a read-only erofs disk image holding the rootfs, with `mkfs.erofs`.
+pub fn generate_erofs(
+    root_directory: &Path,
+    output: &Path,
+    enable_compression: bool,
+) -> Result<()> {
+    info!("Generating erofs image...");
+
+    create_output(output)?;
+
+    let mut command = Command::new("mkfs.erofs");
+    command.args(["--all-root", "-L", "cloudlet-root"]);
+    if enable_compression {
+        command.arg("-zlz4hc");
+    }
+    command.arg(output).arg(root_directory);
+    run(&mut command).with_context(|| "Failed to create the erofs filesystem".to_string())?;
+
+    info!("erofs image generated!");
+
+    Ok(())
+}
@@ -1,36 +1,36 @@
-use anyhow::{Context, Result};
-use std::fs::{copy as fscopy, File, Permissions};
-use std::io::{copy as iocopy, Write};
-use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
 use std::process::{Command, Stdio};
 use tracing::info;
+#[cfg(target_os = "linux")]
+use {fs_gen::archive::cpio::write_dir, std::io::copy as iocopy, std::path::PathBuf};
+#[cfg(unix)]
+use {std::fs::Permissions, std::os::unix::fs::PermissionsExt};
+
+#[cfg(target_os = "linux")]
+pub fn insert_init(destination: &Path, init_path: PathBuf) -> Result<()> {
+    info!("Inserting init into fs...");
+
+    // The image may ship its own, possibly as a link.
+    let _ = std::fs::remove_file(destination.join("init"));
+    let mut file = File::create(destination.join("init"))
+        .with_context(|| "Could not open init file inside initramfs".to_string())?;
+    file.set_permissions(Permissions::from_mode(0o755))
+        .with_context(|| "Failed to set permissions for init file".to_string())?;
 
-const INIT_FILE: &str = include_str!("../resources/initfile");
-
-pub fn create_init_file(path: &Path, initfile: Option<PathBuf>) -> Result<()> {
-    info!("Writing initfile...");
-
-    let destination = path.join("init");
-
-    if let Some(p) = initfile {
-        // if there is a given initfile, we copy it into the folder
-        fscopy(p, destination)
-            .with_context(|| "Failed to copy provided initfile to initramfs".to_string())?;
-    } else {
-        // if there is none, write the default init file
-        let mut file = File::create(destination).unwrap();
-        file.set_permissions(Permissions::from_mode(0o755)).unwrap();
-
-        file.write_all(INIT_FILE.as_bytes())
-            .with_context(|| "Failed to write default initfile to initramfs".to_string())?;
-    }
+    let mut init =
+        File::open(init_path).with_context(|| "Could not open host init file".to_string())?;
+    iocopy(&mut init, &mut file)
+        .with_context(|| "Failed to copy init contents from host to destination".to_string())?;
 
-    info!("Initfile written!");
+    info!("Init inserted!");
 
     Ok(())
 }
 
+#[cfg(target_os = "linux")]
 pub fn insert_agent(destination: &Path, agent_path: PathBuf) -> Result<()> {
     info!("Inserting agent into fs...");
 
@@ -49,36 +49,53 @@ pub fn insert_agent(destination: &Path, agent_path: PathBuf) -> Result<()> {
     Ok(())
 }
 
+#[cfg(target_os = "linux")]
 pub fn generate_initramfs(
     root_directory: &Path,
     output: &Path,
     enable_compression: bool,
+) -> Result<()> {
+    write_initramfs(output, enable_compression, |out| {
+        write_dir(root_directory, out).map(drop)
+    })
+}
+
+/// Write the cpio archive written by `write` to `output`, compressed with xz if enabled.
+pub fn write_initramfs(
+    output: &Path,
+    enable_compression: bool,
+    write: impl FnOnce(Box<dyn Write>) -> Result<()>,
 ) -> Result<()> {
     let file = File::create(output)
         .with_context(|| "Could not open output file to write initramfs".to_string())?;
+    #[cfg(unix)]
     file.set_permissions(Permissions::from_mode(0o644))
         .with_context(|| "Failed to set permissions for output file".to_string())?;
 
     info!("Generating initramfs...");
 
-    let mut command_string: String =
-        "find . -print0 | cpio -0 --create --owner=root:root --format=newc".into();
-
-    if enable_compression {
-        command_string += " | xz -9 -T0 --format=lzma";
+    if !enable_compression {
+        write(Box::new(BufWriter::new(file)))?;
+        info!("Initramfs generated!");
+        return Ok(());
     }
 
-    let mut command = Command::new("sh")
-        .current_dir(root_directory)
+    let mut command = Command::new("xz")
+        .args(["-9", "-T0", "--format=lzma"])
+        .stdin(Stdio::piped())
         .stdout(Stdio::from(file))
-        .arg("-c")
-        .arg(&command_string)
         .spawn()
-        .with_context(|| "Failed to package initramfs into bundle".to_string())?;
+        .with_context(|| "Failed to start xz to compress the initramfs".to_string())?;
+    let stdin = command.stdin.take().unwrap();
+    // Closing the input of xz, when `write` drops it, lets it finish.
+    write(Box::new(BufWriter::new(stdin)))?;
 
-    command.wait().with_context(|| {
+    let status = command.wait().with_context(|| {
         "Encountered exception while waiting for bundling to finish".to_string()
     })?;
+    if !status.success() {
+        bail!("xz failed to compress the initramfs: {}", status);
+    }
 
     info!("Initramfs generated!");
 
@@ -0,0 +1,3 @@
+//! Parts of fs-gen handling untrusted data, shared with its fuzz targets.
+
+pub mod archive;
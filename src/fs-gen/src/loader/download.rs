@@ -1,15 +1,25 @@
 use crate::loader::errors::ImageLoaderError;
 use crate::loader::structs::{Layer, ManifestV2};
-use crate::loader::utils::{get_docker_download_token, unpack_tarball};
+use crate::loader::utils::{decompress, get_docker_download_token, Counted};
 use anyhow::{Context, Result};
 use clap_stdin::MaybeStdin;
-use reqwest::blocking::Client;
-use std::fs::create_dir_all;
-use std::path::{Path, PathBuf};
+use reqwest::blocking::{Client, RequestBuilder};
+use std::cell::Cell;
+use std::io::Read;
+use std::rc::Rc;
 use tracing::{debug, info, warn};
+#[cfg(target_os = "linux")]
+use {
+    crate::loader::utils::apply_whiteouts, fs_gen::archive::layer::extract_layer,
+    std::path::PathBuf,
+};
 
 use super::structs::Image;
 
+/// Download the layers of the image and extract each of them to its own directory under
+/// `output_file`, the whiteouts of the upper layers being applied to the lower ones. Returns
+/// the directories of the layers, and the bytes downloaded.
+#[cfg(target_os = "linux")]
 pub(crate) fn download_image_fs(
     image_name: &str,
     architecture: &str,
@@ -17,7 +27,40 @@ pub(crate) fn download_image_fs(
     username: Option<String>,
     password: Option<MaybeStdin<String>>,
     insecure: bool,
-) -> Result<Vec<PathBuf>, ImageLoaderError> {
+) -> Result<(Vec<PathBuf>, u64), ImageLoaderError> {
+    std::fs::create_dir_all(&output_file)
+        .with_context(|| "Could not create output directory for image downloading")?;
+    let mut layer_paths = Vec::new();
+    let downloaded = download_image_layers(
+        image_name,
+        architecture,
+        username,
+        password,
+        insecure,
+        |layer, tarball| {
+            let output_path = output_file.join(&layer.digest);
+            extract_layer(tarball, &output_path).with_context(|| {
+                format!("Failed to unpack tarball to {}", output_path.display())
+            })?;
+            layer_paths.push(output_path);
+            Ok(())
+        },
+    )?;
+    apply_whiteouts(&layer_paths).map_err(|e| ImageLoaderError::Error { source: e })?;
+
+    Ok((layer_paths, downloaded))
+}
+
+/// Download the layers of the image, from the lowest to the uppermost, giving the decompressed
+/// tarball of each to `unpack`. Returns the bytes downloaded, compressed.
+pub(crate) fn download_image_layers(
+    image_name: &str,
+    architecture: &str,
+    username: Option<String>,
+    password: Option<MaybeStdin<String>>,
+    insecure: bool,
+    mut unpack: impl FnMut(&Layer, Box<dyn Read>) -> Result<()>,
+) -> Result<u64, ImageLoaderError> {
     info!("Downloading image...");
     let image = Image::from_str(image_name);
     debug!(
@@ -34,7 +77,8 @@ pub(crate) fn download_image_fs(
         .build()
         .map_err(|e| ImageLoaderError::Error { source: e.into() })?;
 
-    let token = &get_docker_download_token(&client, &image, username, password)?;
+    let token = get_docker_download_token(&client, &image, username, password)?;
+    let token = token.as_deref();
     let manifest = download_manifest(&client, token, &image, &image.tag)
         .map_err(|e| ImageLoaderError::Error { source: e })?;
 
@@ -45,9 +89,7 @@ pub(crate) fn download_image_fs(
             "{}:{} is not a multi-platform image, the initramfs is not guaranteed to work correctly on the architecture {}",
             image.name, image.tag, architecture
         );
-        create_dir_all(&output_file)
-            .with_context(|| "Could not create output directory for image downloading")?;
-        return download_layers(&m.layers, &client, token, &image, &output_file)
+        return download_layers(&m.layers, &client, token, &image, &mut unpack)
             .map_err(|e| ImageLoaderError::Error { source: e });
     }
 
@@ -84,18 +126,24 @@ pub(crate) fn download_image_fs(
     match submanifest {
         // The submanifest structure doesn't correspond to an image manifest, we throw an error.
         ManifestV2::ImageManifest(m) => {
-            create_dir_all(&output_file)
-                .with_context(|| "Could not create output directory for image downloading")?;
-            download_layers(&m.layers, &client, token, &image, &output_file)
+            download_layers(&m.layers, &client, token, &image, &mut unpack)
                 .map_err(|e| ImageLoaderError::Error { source: e })
         }
         _ => Err(ImageLoaderError::ImageManifestNotFound(image.clone()))?,
     }
 }
 
+/// Authenticate `request` with the token, if the registry requires one.
+fn authorized(request: RequestBuilder, token: Option<&str>) -> RequestBuilder {
+    match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
 fn download_manifest(
     client: &Client,
-    token: &str,
+    token: Option<&str>,
     image: &Image,
     digest: &str,
 ) -> Result<ManifestV2> {
@@ -105,8 +153,7 @@ fn download_manifest(
         image.registry, image.repository, image.name, digest
     );
 
-    let manifest: ManifestV2 = client
-        .get(manifest_url)
+    let manifest: ManifestV2 = authorized(client.get(manifest_url), token)
         .header(
             "Accept",
             "application/vnd.docker.distribution.manifest.v2+json",
@@ -117,7 +164,6 @@ fn download_manifest(
         )
         .header("Accept", "application/vnd.oci.image.manifest.v1+json")
         .header("Accept", "application/vnd.oci.image.index.v1+json")
-        .bearer_auth(token)
         .send()
         .with_context(|| "Could not send request to get manifest data".to_string())?
         .json()
@@ -134,13 +180,12 @@ fn download_manifest(
 fn download_layers(
     layers: &Vec<Layer>,
     client: &Client,
-    token: &str,
+    token: Option<&str>,
     image: &Image,
-    output_dir: &Path,
-) -> Result<Vec<PathBuf>> {
+    unpack: &mut impl FnMut(&Layer, Box<dyn Read>) -> Result<()>,
+) -> Result<u64> {
     info!("Downloading and unpacking layers...");
-
-    let mut layer_paths = Vec::new();
+    let downloaded = Rc::new(Cell::new(0));
 
     // Download and unpack each layer
     for layer in layers {
@@ -150,22 +195,117 @@ fn download_layers(
             image.registry, image.repository, image.name, digest
         );
 
-        let response = client
-            .get(&layer_url)
-            .bearer_auth(token)
+        let response = authorized(client.get(&layer_url), token)
             .send()
-            .with_context(|| format!("Could not send request for layer digest '{digest}'"))?;
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Could not download layer with digest '{digest}'"))?;
 
         debug!("starting to decode layer with digest '{}'", digest);
 
-        let output_path = output_dir.join(digest);
-
-        unpack_tarball(response, &output_path)?;
+        let response = Counted::new(response, downloaded.clone());
+        unpack(layer, decompress(response, &layer.media_type)?)?;
         debug!("layer '{}' unpacked", digest);
-        layer_paths.push(output_path);
     }
 
-    info!("Layers downloaded successfully!");
+    info!(bytes = downloaded.get(), "Layers downloaded successfully!");
+
+    Ok(downloaded.get())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use cloudlet_test_registry::{scratch_dir, TestRegistry};
+    use fs_gen::archive::layer::UnsafeEntry;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_download_manifest_list() {
+        let registry = TestRegistry::start();
+        let dir = scratch_dir("fs-gen-manifest-list");
+        let image = format!("{}/library/hello:latest", registry.url());
+
+        let (layers, downloaded) =
+            download_image_fs(&image, "arm64", dir.join("arm64"), None, None, false).unwrap();
+        assert_eq!(layers.len(), 2);
+        assert!(downloaded > 0);
+        let (lower, upper) = (&layers[0], &layers[1]);
+        assert_eq!(
+            fs::read_to_string(lower.join("etc/hello")).unwrap(),
+            "hello\n"
+        );
+        assert_eq!(
+            fs::read_link(lower.join("bin/sh")).unwrap(),
+            Path::new("busybox")
+        );
+        assert_eq!(
+            fs::read_to_string(upper.join("var/cache/new")).unwrap(),
+            "new\n"
+        );
+
+        // The whiteouts of the zstd layer are applied to the gzip one, then removed.
+        assert!(!lower.join("etc/removed").exists());
+        assert!(!lower.join("var/cache/old").exists());
+        assert!(!upper.join("etc/.wh.removed").exists());
+        assert!(!upper.join("var/cache/.wh..wh..opq").exists());
+
+        let error = download_image_fs(&image, "s390x", dir.join("s390x"), None, None, false);
+        assert!(matches!(
+            error,
+            Err(ImageLoaderError::UnsupportedArchitecture(_))
+        ));
+    }
+
+    #[test]
+    fn test_download_with_credentials() {
+        let registry = TestRegistry::with_credentials("user", "secret");
+        let dir = scratch_dir("fs-gen-credentials");
+        let image = format!("{}/library/single:1.0", registry.url());
 
-    Ok(layer_paths)
+        assert!(
+            download_image_fs(&image, "amd64", dir.join("anonymous"), None, None, false).is_err()
+        );
+        let (layers, _) = download_image_fs(
+            &image,
+            "amd64",
+            dir.join("authenticated"),
+            Some("user".to_string()),
+            "secret".parse().ok(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(layers[0].join("hello.txt")).unwrap(),
+            "single\n"
+        );
+    }
+
+    #[test]
+    fn test_reject_hostile_layers() {
+        let registry = TestRegistry::start();
+        let dir = scratch_dir("fs-gen-hostile");
+        let escaped = Path::new("/tmp/cloudlet-escaped");
+
+        for repository in ["absolute", "parent", "symlink", "whiteout"] {
+            let image = format!("{}/hostile/{}:latest", registry.url(), repository);
+            let Err(ImageLoaderError::Error { source }) =
+                download_image_fs(&image, "amd64", dir.join(repository), None, None, false)
+            else {
+                panic!("the hostile/{} layer was extracted", repository);
+            };
+            let error = source.downcast_ref::<UnsafeEntry>();
+            match repository {
+                "absolute" => assert!(matches!(error, Some(UnsafeEntry::AbsolutePath(_)))),
+                "parent" => assert!(matches!(error, Some(UnsafeEntry::ParentDir(_)))),
+                // The download directory and the lower layer are left alone.
+                "whiteout" => {
+                    assert!(matches!(error, Some(UnsafeEntry::InvalidWhiteout(_))));
+                    assert!(dir.join(repository).is_dir());
+                }
+                _ => assert!(matches!(error, Some(UnsafeEntry::ThroughSymlink { .. }))),
+            }
+            assert!(!escaped.exists());
+        }
+    }
 }
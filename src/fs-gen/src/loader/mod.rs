@@ -1,4 +1,6 @@
 pub(crate) mod download;
 pub(crate) mod errors;
+#[cfg(feature = "push")]
+pub(crate) mod push;
 mod structs;
 mod utils;
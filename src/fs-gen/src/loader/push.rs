@@ -0,0 +1,255 @@
+use crate::loader::structs::Image;
+use crate::loader::utils::get_registry_token;
+use crate::scan::{ScanSummary, FLAGGED_ANNOTATION, SUMMARY_ANNOTATION};
+use crate::signing::{read_signature, SIGNATURE_ANNOTATION};
+use anyhow::{bail, Context, Result};
+use clap_stdin::MaybeStdin;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::StatusCode;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::copy as iocopy;
+use std::path::Path;
+use tracing::{debug, info, warn};
+
+/// Type of the artifacts holding a rootfs image, the media type of the image itself
+/// depends on its format.
+pub(crate) const ROOTFS_ARTIFACT_TYPE: &str = "application/vnd.cloudlet.rootfs.v1";
+
+const OCI_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Empty config of the artifacts, as recommended by the OCI image spec.
+const EMPTY_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const EMPTY_CONFIG: &[u8] = b"{}";
+
+/// Media type of a rootfs image in the given format, e.g. `cpio`.
+pub(crate) fn rootfs_media_type(format: &str) -> String {
+    format!("application/vnd.cloudlet.rootfs.{}.v1", format)
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+fn file_digest(path: &Path) -> Result<(String, u64)> {
+    let mut file = File::open(path).with_context(|| format!("Could not open {:?}", path))?;
+    let mut hasher = Sha256::new();
+    let size = iocopy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to compute the digest of {:?}", path))?;
+
+    Ok((format!("sha256:{:x}", hasher.finalize()), size))
+}
+
+/// Client of the repository of an image, authenticated when the registry requires it.
+struct Repository<'a> {
+    client: &'a Client,
+    image: &'a Image,
+    token: Option<String>,
+}
+
+impl<'a> Repository<'a> {
+    fn connect(
+        client: &'a Client,
+        image: &'a Image,
+        username: Option<String>,
+        password: Option<MaybeStdin<String>>,
+    ) -> Result<Self> {
+        // Registries without authentication, e.g. a local one, answer the version check directly.
+        let response = client
+            .get(format!("{}/v2/", image.registry))
+            .send()
+            .with_context(|| format!("Could not send request to {}", image.registry))?;
+        let token = match response.status() {
+            StatusCode::UNAUTHORIZED => Some(get_registry_token(
+                client,
+                image,
+                username,
+                password,
+                "pull,push",
+            )?),
+            _ => None,
+        };
+
+        Ok(Self {
+            client,
+            image,
+            token,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{}/v2/{}/{}/{}",
+            self.image.registry, self.image.repository, self.image.name, path
+        )
+    }
+
+    fn authorized(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    fn blob_exists(&self, digest: &str) -> Result<bool> {
+        let response = self
+            .authorized(self.client.head(self.url(&format!("blobs/{}", digest))))
+            .send()
+            .with_context(|| format!("Could not check blob '{}'", digest))?;
+
+        Ok(response.status().is_success())
+    }
+
+    /// Upload a blob in a single request, unless the registry already has it.
+    fn upload_blob(&self, digest: &str, body: impl Into<reqwest::blocking::Body>) -> Result<()> {
+        if self.blob_exists(digest)? {
+            debug!(digest, "blob already in the registry");
+            return Ok(());
+        }
+
+        let response = self
+            .authorized(self.client.post(self.url("blobs/uploads/")))
+            .send()
+            .with_context(|| "Could not start the blob upload".to_string())?;
+        if response.status() != StatusCode::ACCEPTED {
+            bail!("Registry refused the blob upload: {}", response.status());
+        }
+        let location = response
+            .headers()
+            .get("location")
+            .and_then(|location| location.to_str().ok())
+            .with_context(|| "Registry didn't return the blob upload location".to_string())?;
+        let mut upload_url = if location.starts_with('/') {
+            format!("{}{}", self.image.registry, location)
+        } else {
+            location.to_string()
+        };
+        upload_url.push(if upload_url.contains('?') { '&' } else { '?' });
+        upload_url.push_str(&format!("digest={}", digest));
+
+        let response = self
+            .authorized(self.client.put(upload_url))
+            .header("Content-Type", "application/octet-stream")
+            .body(body)
+            .send()
+            .with_context(|| format!("Could not upload blob '{}'", digest))?;
+        if response.status() != StatusCode::CREATED {
+            bail!("Failed to upload blob '{}': {}", digest, response.status());
+        }
+
+        Ok(())
+    }
+
+    fn put_manifest(&self, manifest: Vec<u8>) -> Result<()> {
+        let response = self
+            .authorized(
+                self.client
+                    .put(self.url(&format!("manifests/{}", self.image.tag))),
+            )
+            .header("Content-Type", OCI_MANIFEST_MEDIA_TYPE)
+            .body(manifest)
+            .send()
+            .with_context(|| "Could not upload the manifest".to_string())?;
+        if response.status() != StatusCode::CREATED {
+            bail!("Failed to upload the manifest: {}", response.status());
+        }
+
+        Ok(())
+    }
+}
+
+/// Push the rootfs image at `path` as an OCI artifact tagged `reference`.
+///
+/// Returns the reference pinned to the digest of the pushed manifest, e.g.
+/// `registry.example.com/cloudlet/python@sha256:...`.
+pub(crate) fn push_rootfs(
+    path: &Path,
+    format: &str,
+    reference: &str,
+    username: Option<String>,
+    password: Option<MaybeStdin<String>>,
+    insecure: bool,
+) -> Result<String> {
+    let image = Image::from_str(reference);
+    debug!(
+        registry = image.registry,
+        repository = image.repository,
+        name = image.name,
+        tag = image.tag,
+        "image:",
+    );
+
+    let client = Client::builder()
+        .danger_accept_invalid_certs(insecure)
+        .timeout(None)
+        .build()?;
+    let repository = Repository::connect(&client, &image, username, password)?;
+
+    info!("Uploading rootfs image...");
+    let (digest, size) = file_digest(path)?;
+    let file = File::open(path).with_context(|| format!("Could not open {:?}", path))?;
+    repository.upload_blob(&digest, file)?;
+    repository.upload_blob(&sha256_digest(EMPTY_CONFIG), EMPTY_CONFIG)?;
+
+    let title = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST_MEDIA_TYPE,
+        "artifactType": ROOTFS_ARTIFACT_TYPE,
+        "config": {
+            "mediaType": EMPTY_CONFIG_MEDIA_TYPE,
+            "digest": sha256_digest(EMPTY_CONFIG),
+            "size": EMPTY_CONFIG.len(),
+        },
+        "layers": [{
+            "mediaType": rootfs_media_type(format),
+            "digest": digest,
+            "size": size,
+            "annotations": { "org.opencontainers.image.title": title },
+        }],
+    });
+    // The VMMs refuse to boot the images flagged by their scan.
+    let mut annotations = serde_json::Map::new();
+    if let Some(summary) = ScanSummary::read(path)? {
+        annotations.insert(
+            FLAGGED_ANNOTATION.into(),
+            summary.flagged.to_string().into(),
+        );
+        annotations.insert(
+            SUMMARY_ANNOTATION.into(),
+            format!("{}:{}", summary.scanner, summary.counts_string()).into(),
+        );
+        if summary.flagged {
+            warn!(
+                findings = summary.findings.len(),
+                "Pushing an image flagged as vulnerable"
+            );
+        }
+    }
+    // And those not signed by a key they trust, when they check the signatures.
+    if let Some(signature) = read_signature(path)? {
+        annotations.insert(SIGNATURE_ANNOTATION.into(), signature.into());
+    }
+    if !annotations.is_empty() {
+        manifest["annotations"] = annotations.into();
+    }
+    let manifest = serde_json::to_vec(&manifest)?;
+    let manifest_digest = sha256_digest(&manifest);
+    repository.put_manifest(manifest)?;
+    info!(digest = manifest_digest, "Rootfs image pushed!");
+
+    Ok(format!(
+        "{}/{}/{}@{}",
+        image
+            .registry
+            .trim_start_matches("http://")
+            .trim_start_matches("https://"),
+        image.repository,
+        image.name,
+        manifest_digest
+    ))
+}
@@ -20,6 +20,8 @@ pub struct ImageManifest {
 // Image layer
 #[derive(Debug, Deserialize)]
 pub struct Layer {
+    #[serde(rename = "mediaType", default)]
+    pub media_type: String,
     pub digest: String,
 }
 
@@ -72,8 +74,11 @@ impl Image {
             .splitn(3, '/')
             .collect();
 
-        // Get registry link (part of name before the first '/' with dots) or use the default registry
-        let registry = if image_data[0].contains('.') {
+        // Get registry link (part of name before the first '/' with dots, a port or localhost) or use the default registry
+        let host = image_data[0];
+        let registry = if image_data.len() > 1
+            && (host.contains('.') || host.contains(':') || host == "localhost")
+        {
             format!("{}{}", protocol, image_data.remove(0))
         } else {
             DEFAULT_REGISTRY.to_string()
@@ -1,17 +1,138 @@
 use crate::loader::errors::ImageLoaderError;
 use crate::loader::structs::{Image, Registry};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap_stdin::MaybeStdin;
 use flate2::read::GzDecoder;
-use reqwest::blocking::{Client, Response};
-use std::path::Path;
-use tar::Archive;
-
-/// Unpack the tarball to a given directory.
-pub(super) fn unpack_tarball(response: Response, output_dir: &Path) -> Result<()> {
-    Archive::new(GzDecoder::new(response))
-        .unpack(output_dir)
-        .with_context(|| format!("Failed to unpack tarball to {}", output_dir.display()))?;
+use fs_gen::archive::layer::UnsafeEntry;
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Prefix of the files hiding a path of the lower layers.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// File hiding the content of its directory in the lower layers.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Tarball of a downloaded layer, decompressed according to its media type.
+pub(super) fn decompress(response: impl Read + 'static, media_type: &str) -> Result<Box<dyn Read>> {
+    Ok(if media_type.ends_with("+zstd") {
+        Box::new(zstd::Decoder::new(response).with_context(|| "Failed to decode zstd layer")?)
+    } else if media_type.ends_with("+gzip")
+        || media_type.ends_with(".gzip")
+        || media_type.is_empty()
+    {
+        Box::new(GzDecoder::new(response))
+    } else if media_type.ends_with(".tar") {
+        Box::new(response)
+    } else {
+        bail!("Unsupported layer media type '{}'", media_type);
+    })
+}
+
+/// Reader adding the bytes read from the inner one to a counter shared with the caller.
+pub(super) struct Counted<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Counted<R> {
+    pub(super) fn new(inner: R, count: Rc<Cell<u64>>) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read as u64);
+        Ok(read)
+    }
+}
+
+/// Remove a file or a directory, if it exists, without following symlinks.
+fn remove_path(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `relative` is a directory of `root`, reached without going through a symlink.
+fn is_real_dir(root: &Path, relative: &Path) -> bool {
+    let mut path = root.to_path_buf();
+    relative.components().all(|component| {
+        path.push(component);
+        fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir())
+    })
+}
+
+/// Whiteout files of the layer at `root`, relative to it.
+fn find_whiteouts(root: &Path, relative: &Path, whiteouts: &mut Vec<PathBuf>) -> Result<()> {
+    let dir = root.join(relative);
+    for entry in fs::read_dir(&dir).with_context(|| format!("Could not read {}", dir.display()))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            find_whiteouts(root, &path, whiteouts)?;
+        } else if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(WHITEOUT_PREFIX)
+        {
+            whiteouts.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Apply the whiteouts of each layer to the layers below it, then remove them, so that the
+/// merged layers don't show the deleted files.
+pub(super) fn apply_whiteouts(layers: &[PathBuf]) -> Result<()> {
+    for (i, layer) in layers.iter().enumerate() {
+        let mut whiteouts = Vec::new();
+        find_whiteouts(layer, Path::new(""), &mut whiteouts)?;
+
+        for whiteout in whiteouts {
+            let dir = whiteout.parent().unwrap_or(Path::new(""));
+            let name = whiteout.file_name().unwrap_or_default().to_string_lossy();
+            // Like the userland tree, which joins the hidden name to its directory as well.
+            if name != OPAQUE_WHITEOUT {
+                let hidden = &name[WHITEOUT_PREFIX.len()..];
+                if matches!(hidden, "" | "." | "..") || hidden.contains('/') {
+                    return Err(UnsafeEntry::InvalidWhiteout(whiteout).into());
+                }
+            }
+            for lower in &layers[..i] {
+                // A lower layer whose path goes through a symlink or a file has nothing to hide
+                // there, the directory of the upper layer replaces it.
+                if !is_real_dir(lower, dir) {
+                    continue;
+                }
+                let hidden = if name == OPAQUE_WHITEOUT {
+                    lower.join(dir)
+                } else {
+                    lower.join(dir).join(&name[WHITEOUT_PREFIX.len()..])
+                };
+                if hidden == *lower {
+                    // An opaque root hides the whole layer.
+                    for entry in fs::read_dir(lower)? {
+                        remove_path(&entry?.path())?;
+                    }
+                    continue;
+                }
+                remove_path(&hidden)
+                    .with_context(|| format!("Could not remove {}", hidden.display()))?;
+            }
+            fs::remove_file(layer.join(&whiteout))
+                .with_context(|| format!("Could not remove whiteout {}", whiteout.display()))?;
+        }
+    }
     Ok(())
 }
 
@@ -46,18 +167,38 @@ pub(super) fn get_registry_auth_data(
     })
 }
 
-/// Get a token for anonymous authentication to Docker Hub.
+/// Get a token to download the image, `None` if the registry doesn't require authentication.
 pub(super) fn get_docker_download_token(
     client: &Client,
     image: &Image,
     username: Option<String>,
     password: Option<MaybeStdin<String>>,
+) -> Result<Option<String>> {
+    // Registries without authentication, e.g. a local one, answer the version check directly.
+    let response = client
+        .get(format!("{}/v2/", image.registry))
+        .send()
+        .with_context(|| format!("Could not send request to {}", image.registry))?;
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return Ok(None);
+    }
+
+    get_registry_token(client, image, username, password, "pull").map(Some)
+}
+
+/// Get a token allowing the `actions` (e.g. `pull,push`) on the repository of the image.
+pub(super) fn get_registry_token(
+    client: &Client,
+    image: &Image,
+    username: Option<String>,
+    password: Option<MaybeStdin<String>>,
+    actions: &str,
 ) -> Result<String> {
     let registry = get_registry_auth_data(client, image)?;
 
     let mut request = client.get(format!(
-        "{}?service={}&scope=repository:{}/{}:pull",
-        registry.auth_link, registry.auth_service, image.repository, image.name
+        "{}?service={}&scope=repository:{}/{}:{}",
+        registry.auth_link, registry.auth_service, image.repository, image.name, actions
     ));
     let mut auth_type = "";
 
@@ -1,77 +1,301 @@
+// Outside of Linux, only the userland conversion and the push are built, leaving the rest of
+// the helpers of the extracted rootfs unused.
+#![cfg_attr(not(target_os = "linux"), allow(dead_code))]
+// Without the `push` feature, the annotations of the pushed artifacts are unused.
+#![cfg_attr(not(feature = "push"), allow(dead_code))]
+
 use anyhow::{bail, Context, Result};
-use std::{fs::remove_dir_all, path::Path};
+use fs_gen::archive::tree::RootfsTree;
+use shared_models::logging::LogArgs;
+use std::fs::remove_dir_all;
 use tracing::level_filters::LevelFilter;
 use tracing::{debug, error, info};
-use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::prelude::*;
 
-use crate::cli_args::CliArgs;
-use crate::image_builder::merge_layer;
-use crate::initramfs_generator::{create_init_file, generate_initramfs, insert_agent};
-use crate::loader::download::download_image_fs;
+use crate::cli_args::{BuildArgs, CliArgs, ImageArgs, PushArgs, SignArgs};
+use crate::initramfs_generator::write_initramfs;
+use crate::loader::download::download_image_layers;
+#[cfg(feature = "push")]
+use crate::loader::push::push_rootfs;
+use crate::metrics::BuildMetrics;
+use crate::scan::scan_rootfs;
+use crate::signing::sign_artifact;
+#[cfg(target_os = "linux")]
+use {
+    crate::build_spec::{BuildSpec, SpecBuilder},
+    crate::cli_args::{is_valid_image_name, OutputFormat},
+    crate::disk_image_generator::{generate_erofs, generate_ext4, Ext4Options},
+    crate::image_builder::merge_layer,
+    crate::initramfs_generator::{generate_initramfs, insert_agent, insert_init},
+    crate::loader::download::download_image_fs,
+    crate::rootfs_hooks::run_in_rootfs,
+    crate::sbom::generate_sbom,
+    crate::warmup::warm_up,
+    std::{fs, path::Path},
+};
 
+#[cfg(target_os = "linux")]
+mod build_spec;
 mod cli_args;
+#[cfg(target_os = "linux")]
+mod disk_image_generator;
+#[cfg(target_os = "linux")]
 mod image_builder;
 mod initramfs_generator;
 mod loader;
+mod metrics;
+#[cfg(target_os = "linux")]
+mod rootfs_hooks;
+mod sbom;
+mod scan;
+mod signing;
+#[cfg(target_os = "linux")]
+mod warmup;
 
-fn run(args: CliArgs) -> Result<()> {
+/// Generate the image from `image_name`, customized by `spec` if any.
+#[cfg(target_os = "linux")]
+fn run(image_name: &str, args: ImageArgs, spec: Option<(BuildSpec, SpecBuilder)>) -> Result<()> {
     let layers_subdir = args.temp_directory.join("layers/");
     let overlay_subdir = args.temp_directory.join("overlay/");
     let _binding = args.temp_directory.join("output/");
     let output_subdir = _binding.as_path();
 
+    let mut metrics = BuildMetrics::start();
     // image downloading and unpacking
-    let layers_paths = match download_image_fs(
-        &args.image_name,
-        &args.architecture,
-        layers_subdir,
-        args.username,
-        args.password,
-        args.insecure,
-    ) {
+    let (downloaded, extract_secs) = BuildMetrics::timed(|| {
+        download_image_fs(
+            image_name,
+            &args.architecture,
+            layers_subdir,
+            args.username.clone(),
+            args.password.clone(),
+            args.insecure,
+        )
+    });
+    let (layers_paths, download_bytes) = match downloaded {
         Err(e) => bail!(e),
         Ok(e) => e,
     };
     debug!("Layers' paths: {:?}", layers_paths);
+    metrics.report.download_bytes = download_bytes;
+    metrics.report.layers = layers_paths.len() as u32;
+    metrics.report.extract_secs = extract_secs;
 
     // reconstructing image with overlayfs
     merge_layer(&layers_paths, output_subdir, &overlay_subdir)?;
 
-    // building initramfs
-    create_init_file(output_subdir, args.initfile_path)?;
+    if let Some((spec, builder)) = spec {
+        builder.apply(&spec, output_subdir)?;
+    }
+    run_in_rootfs(output_subdir, &args.run_in_rootfs)?;
+    if let Some(language) = &args.warmup {
+        warm_up(output_subdir, &warmup::steps(language))?;
+    }
+
+    if let Some(format) = args.sbom {
+        generate_sbom(
+            output_subdir,
+            image_name,
+            format,
+            &args.sbom_path(format),
+            args.embed_sbom,
+        )?;
+    }
+    scan_rootfs(output_subdir, &args.output_file, &args.scan_options())?;
+
+    // building the rootfs image
+    insert_init(output_subdir, args.init_path())?;
     insert_agent(output_subdir, args.agent_host_path)?;
-    generate_initramfs(
-        output_subdir,
-        Path::new(args.output_file.as_path()),
-        !args.no_compression,
-    )?;
+    let output_file = Path::new(args.output_file.as_path());
+    let (packed, pack_secs) = BuildMetrics::timed(|| match args.format {
+        OutputFormat::Cpio => generate_initramfs(output_subdir, output_file, !args.no_compression),
+        OutputFormat::Ext4 => generate_ext4(
+            output_subdir,
+            output_file,
+            Ext4Options {
+                size_mb: args.size_mb,
+                no_journal: args.no_journal,
+                journal_size_mb: args.journal_size_mb,
+            },
+        ),
+        OutputFormat::Erofs => generate_erofs(output_subdir, output_file, !args.no_compression),
+    });
+    packed?;
+    metrics.report.pack_secs = pack_secs;
+    if let Some(key) = &args.sign_key {
+        sign_artifact(output_file, key)?;
+    }
 
     // cleanup of temporary directory
     remove_dir_all(args.temp_directory.clone())
         .with_context(|| "Failed to remove temporary directory".to_string())?;
 
-    Ok(())
+    metrics.finish(args.metrics_file.as_deref())
 }
 
-fn main() -> Result<()> {
-    let args = CliArgs::get_args();
+/// Generate the initramfs of `image_name` from its layers assembled in memory, on the hosts
+/// which can't extract nor mount them: the only way outside of Linux.
+fn run_userland(image_name: &str, args: ImageArgs) -> Result<()> {
+    let mut metrics = BuildMetrics::start();
+    let mut tree = RootfsTree::new(&args.temp_directory.join("files/"))?;
+    let mut layers = 0;
+    let (downloaded, extract_secs) = BuildMetrics::timed(|| {
+        download_image_layers(
+            image_name,
+            &args.architecture,
+            args.username.clone(),
+            args.password.clone(),
+            args.insecure,
+            |_, tarball| {
+                layers += 1;
+                tree.apply_layer(tarball)
+            },
+        )
+    });
+    match downloaded {
+        Err(e) => bail!(e),
+        Ok(download_bytes) => metrics.report.download_bytes = download_bytes,
+    }
+    metrics.report.layers = layers;
+    metrics.report.extract_secs = extract_secs;
+
+    // Only an existing report can be used, the scanners need the extracted rootfs.
+    scan_rootfs(
+        &args.temp_directory,
+        &args.output_file,
+        &args.scan_options(),
+    )?;
+
+    tree.insert_file("init", 0o755, &args.init_path())?;
+    tree.insert_file("agent", 0o755, &args.agent_host_path)?;
+    let (packed, pack_secs) = BuildMetrics::timed(|| {
+        write_initramfs(&args.output_file, !args.no_compression, |out| {
+            tree.write_cpio(out).map(drop)
+        })
+    });
+    packed?;
+    metrics.report.pack_secs = pack_secs;
+    if let Some(key) = &args.sign_key {
+        sign_artifact(&args.output_file, key)?;
+    }
+
+    remove_dir_all(&args.temp_directory)
+        .with_context(|| "Failed to remove temporary directory".to_string())?;
+
+    metrics.finish(args.metrics_file.as_deref())
+}
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(
-                    (if args.debug {
-                        LevelFilter::DEBUG
-                    } else {
-                        LevelFilter::INFO
-                    })
-                    .into(),
-                )
-                .from_env()?
+/// Log to stderr, stdout being left to the references and the paths printed by the commands.
+fn init_tracing(debug: bool, log: &LogArgs) -> Result<()> {
+    let level = if debug {
+        LevelFilter::DEBUG
+    } else {
+        LevelFilter::INFO
+    };
+    tracing_subscriber::registry()
+        .with(
+            log.filter(level)
                 .add_directive("fuse_backend_rs=warn".parse()?),
         )
+        .with(log.layer())
         .init();
 
+    Ok(())
+}
+
+#[cfg(not(feature = "push"))]
+fn push(_args: PushArgs) -> Result<()> {
+    bail!("This fs-gen was built without the push feature, rebuild it with `--features push`");
+}
+
+#[cfg(feature = "push")]
+fn push(args: PushArgs) -> Result<()> {
+    init_tracing(args.debug, &args.log)?;
+    info!(
+        "Pushing '{}' to '{}'",
+        args.image_path.display(),
+        args.reference
+    );
+
+    let reference = push_rootfs(
+        &args.image_path,
+        args.format.as_str(),
+        &args.reference,
+        args.username,
+        args.password,
+        args.insecure,
+    )
+    .inspect_err(|e| error!(error = ?e, "encountered error while pushing"))?;
+    println!("{}", reference);
+
+    Ok(())
+}
+
+fn sign(args: SignArgs) -> Result<()> {
+    init_tracing(args.debug, &args.log)?;
+    for artifact in &args.artifacts {
+        let signature = sign_artifact(artifact, &args.key)
+            .inspect_err(|e| error!(error = ?e, "encountered error while signing"))?;
+        println!("{}", signature.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn build(_args: BuildArgs) -> Result<()> {
+    bail!("fs-gen build runs the RUN instructions in the extracted rootfs, it is only available on Linux");
+}
+
+#[cfg(target_os = "linux")]
+fn build(args: BuildArgs) -> Result<()> {
+    init_tracing(args.image.debug, &args.image.log)?;
+    if args.image.userland() {
+        bail!("fs-gen build runs the RUN instructions in the extracted rootfs, it can't be used with --userland");
+    }
+
+    let spec = fs::read_to_string(&args.spec_path)
+        .with_context(|| format!("Failed to read the build spec {:?}", args.spec_path))?;
+    let spec = BuildSpec::parse(&spec)
+        .with_context(|| format!("Invalid build spec {:?}", args.spec_path))?;
+    if !is_valid_image_name(&spec.from) {
+        bail!("Invalid image name in FROM: \"{}\"", spec.from);
+    }
+    info!(
+        "Building '{}' from '{}'",
+        args.spec_path.display(),
+        spec.from
+    );
+
+    let builder = SpecBuilder {
+        context: args
+            .spec_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf(),
+        runner: args.runner,
+        helper_image: args.helper_image,
+    };
+    let image_name = spec.from.clone();
+    run(&image_name, args.image, Some((spec, builder)))
+        .inspect_err(|e| error!(error = ?e, "encountered error while building"))?;
+    info!("Finished successfully!");
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    match std::env::args_os().nth(1) {
+        Some(arg) if arg == "push" => return push(PushArgs::get_args()),
+        Some(arg) if arg == "build" => return build(BuildArgs::get_args()),
+        Some(arg) if arg == "sign" => return sign(SignArgs::get_args()),
+        _ => {}
+    }
+
+    let args = CliArgs::get_args();
+    init_tracing(args.image.debug, &args.image.log)?;
+
     info!(
         "Cloudlet initramfs generator: '{}' v{}",
         env!("CARGO_PKG_NAME"),
@@ -81,16 +305,26 @@ fn main() -> Result<()> {
 
     debug!(
         image_name = args.image_name,
-        agent_host_path = ?args.agent_host_path,
-        output_file = ?args.output_file,
-        temp_dir = ?args.temp_directory,
-        initfile_path = ?args.initfile_path,
-        architecture = args.architecture,
-        debug = args.debug,
+        agent_host_path = ?args.image.agent_host_path,
+        output_file = ?args.image.output_file,
+        temp_dir = ?args.image.temp_directory,
+        initfile_path = ?args.image.initfile_path,
+        architecture = args.image.architecture,
+        format = ?args.image.format,
+        debug = args.image.debug,
+        userland = args.image.userland(),
         "arguments:",
     );
 
-    if let Err(e) = run(args) {
+    #[cfg(target_os = "linux")]
+    let result = if args.image.userland() {
+        run_userland(&args.image_name, args.image)
+    } else {
+        run(&args.image_name, args.image, None)
+    };
+    #[cfg(not(target_os = "linux"))]
+    let result = run_userland(&args.image_name, args.image);
+    if let Err(e) = result {
         error!(error = ?e, "encountered error while running");
         Err(e)
     } else {
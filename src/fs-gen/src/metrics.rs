@@ -0,0 +1,51 @@
+//! Metrics of the generation of an image, written as JSON with `--metrics-file <path>`: the
+//! bytes of the layers downloaded, the time spent downloading and extracting them, and packing
+//! the rootfs. The orchestrator passes it to the fs-gen building its images, to publish them.
+
+use anyhow::{Context, Result};
+use shared_models::RootfsBuildMetrics;
+use std::path::Path;
+use std::time::Instant;
+use tracing::info;
+
+/// Metrics of the generation in progress.
+pub struct BuildMetrics {
+    started: Instant,
+    pub report: RootfsBuildMetrics,
+}
+
+impl BuildMetrics {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            report: RootfsBuildMetrics::default(),
+        }
+    }
+
+    /// Run `step`, returning its result and the seconds it took.
+    pub fn timed<T>(step: impl FnOnce() -> T) -> (T, f64) {
+        let started = Instant::now();
+        let result = step();
+        (result, started.elapsed().as_secs_f64())
+    }
+
+    /// Log the metrics of the finished generation, and write them to `path` if any.
+    pub fn finish(mut self, path: Option<&Path>) -> Result<()> {
+        self.report.total_secs = self.started.elapsed().as_secs_f64();
+        let report = &self.report;
+        info!(
+            download_bytes = report.download_bytes,
+            layers = report.layers,
+            extract_secs = report.extract_secs,
+            pack_secs = report.pack_secs,
+            total_secs = report.total_secs,
+            "Image generated"
+        );
+        let Some(path) = path else {
+            return Ok(());
+        };
+        let json = serde_json::to_vec_pretty(report)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Failed to write the metrics to {}", path.display()))
+    }
+}
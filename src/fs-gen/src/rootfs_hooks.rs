@@ -0,0 +1,149 @@
+//! Commands run inside the merged rootfs before the image is generated, e.g. to precompile the
+//! Python bytecode or to warm the caches of the runtime: `--run-in-rootfs "<command>"`.
+//!
+//! Each command is run by the `/bin/sh` of the image, chrooted into the rootfs in a user and
+//! mount namespace created by `unshare`, where the user of fs-gen is root and `/dev`, `/proc`
+//! and `/sys` are bound for the command only. When the host doesn't allow unprivileged user
+//! namespaces, the command is run by proot instead. The output of the commands is captured and
+//! logged, and a command which fails aborts the build with the end of its output.
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::{Command, Output};
+use tracing::{debug, info};
+
+/// `PATH` of the commands.
+const PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Lines of the output of a failed command kept in the error.
+const ERROR_LINES: usize = 20;
+
+/// How the commands get into the rootfs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Isolation {
+    Unshare,
+    Proot,
+}
+
+impl Isolation {
+    /// `unshare` when the host lets the user create user namespaces, proot otherwise.
+    fn detect() -> Result<Self> {
+        let unshare = Command::new("unshare")
+            .args(["--map-root-user", "--mount", "true"])
+            .output();
+        if unshare.is_ok_and(|output| output.status.success()) {
+            return Ok(Isolation::Unshare);
+        }
+        let proot = Command::new("proot").arg("--version").output();
+        if proot.is_ok_and(|output| output.status.success()) {
+            return Ok(Isolation::Proot);
+        }
+        bail!(
+            "--run-in-rootfs needs unshare with unprivileged user namespaces, or proot on the PATH"
+        );
+    }
+
+    fn command(&self, rootfs: &Path, command: &str) -> Command {
+        let mut process = match self {
+            Isolation::Unshare => {
+                let mut process = Command::new("unshare");
+                // The bind mounts vanish with the namespace, once the command is done.
+                process
+                    .args(["--map-root-user", "--mount", "--fork", "sh", "-c"])
+                    .arg(
+                        "for dir in dev proc sys; do \
+                         [ -d \"$1/$dir\" ] && mount --rbind \"/$dir\" \"$1/$dir\"; \
+                         done; \
+                         exec chroot \"$1\" /bin/sh -c \"$2\"",
+                    )
+                    .arg("sh")
+                    .arg(rootfs)
+                    .arg(command);
+                process
+            }
+            Isolation::Proot => {
+                let mut process = Command::new("proot");
+                process
+                    .arg("-0")
+                    .arg("-r")
+                    .arg(rootfs)
+                    .args(["-w", "/", "-b", "/dev", "-b", "/proc", "-b", "/sys"])
+                    .args(["-b", "/etc/resolv.conf"])
+                    .args(["/bin/sh", "-c", command]);
+                process
+            }
+        };
+        process.env_clear().env("PATH", PATH).env("HOME", "/root");
+        process
+    }
+}
+
+/// Run `commands` in order inside `rootfs`, failing on the first one which fails.
+pub fn run_in_rootfs(rootfs: &Path, commands: &[String]) -> Result<()> {
+    if commands.is_empty() {
+        return Ok(());
+    }
+    // Mostly a symlink to a busybox, which can only be resolved in the rootfs.
+    if rootfs.join("bin/sh").symlink_metadata().is_err() {
+        bail!("--run-in-rootfs runs the commands with /bin/sh, which the image doesn't have");
+    }
+
+    let isolation = Isolation::detect()?;
+    for command in commands {
+        info!(command, isolation = ?isolation, "Running in the rootfs");
+        let mut process = isolation.command(rootfs, command);
+        debug!(command = ?process, "Running");
+        let output = process
+            .output()
+            .with_context(|| format!("Failed to run `{}` in the rootfs", command))?;
+        log_output(command, &output);
+        if !output.status.success() {
+            bail!(
+                "`{}` failed in the rootfs: {}\n{}",
+                command,
+                output.status,
+                tail(&output, ERROR_LINES)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn log_output(command: &str, output: &Output) {
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!(command, "{}", line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        info!(command, stderr = true, "{}", line);
+    }
+}
+
+/// The last `lines` lines of the output of a command, its stderr after its stdout.
+fn tail(output: &Output, lines: usize) -> String {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let all: Vec<&str> = stdout.lines().chain(stderr.lines()).collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    #[test]
+    fn test_tail() {
+        let output = Output {
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: b"compiling a.py\ncompiling b.py\n".to_vec(),
+            stderr: b"SyntaxError: invalid syntax\n".to_vec(),
+        };
+        assert_eq!(
+            tail(&output, 2),
+            "compiling b.py\nSyntaxError: invalid syntax"
+        );
+        assert_eq!(tail(&output, 10).lines().count(), 3);
+    }
+}
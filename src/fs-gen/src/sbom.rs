@@ -0,0 +1,629 @@
+//! Software bills of materials of the generated rootfs, listing the packages found in it.
+//!
+//! The packages come from the databases of the distribution package managers (apk, dpkg, and
+//! rpm through the `rpm` tool of the host, which reads any database format), from the installed
+//! Python distributions and Node modules, and from the `Cargo.lock` and `package-lock.json`
+//! lockfiles of the image. The bill is written as CycloneDX 1.5 or SPDX 2.3 JSON.
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::cli_args::SbomFormat;
+
+/// Path of the bill in the rootfs when it is embedded, without its extension.
+pub const EMBEDDED_PATH: &str = "etc/cloudlet/sbom";
+
+/// Directories of the rootfs not searched for Python distributions, Node modules and lockfiles.
+const SKIPPED_DIRECTORIES: [&str; 4] = ["proc", "sys", "dev", "run"];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    /// Package URL, e.g. `pkg:apk/alpine/musl@1.2.4-r2?arch=x86_64`.
+    pub purl: String,
+    pub license: Option<String>,
+}
+
+impl SbomFormat {
+    /// Extension of the bills of the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SbomFormat::Cyclonedx => "cdx.json",
+            SbomFormat::Spdx => "spdx.json",
+        }
+    }
+}
+
+/// Fields of a record of an apk or dpkg database, one `key: value` per line.
+fn fields<'a>(
+    record: &'a str,
+    separator: &'static str,
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    record.lines().filter_map(move |line| {
+        line.split_once(separator)
+            .map(|(key, value)| (key, value.trim()))
+    })
+}
+
+/// Percent-encode a component of a package URL.
+fn purl_encode(component: &str) -> String {
+    component
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// `ID` of the distribution, from `/etc/os-release`.
+fn distribution(rootfs: &Path) -> String {
+    ["etc/os-release", "usr/lib/os-release"]
+        .iter()
+        .find_map(|path| fs::read_to_string(rootfs.join(path)).ok())
+        .and_then(|release| {
+            release.lines().find_map(|line| {
+                line.strip_prefix("ID=")
+                    .map(|id| id.trim_matches('"').to_string())
+            })
+        })
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Packages of the apk database, `/lib/apk/db/installed`.
+pub fn parse_apk(installed: &str, distribution: &str) -> Vec<Package> {
+    installed
+        .split("\n\n")
+        .filter_map(|record| {
+            let (mut name, mut version, mut arch, mut license) = (None, None, None, None);
+            for (key, value) in fields(record, ":") {
+                match key {
+                    "P" => name = Some(value),
+                    "V" => version = Some(value),
+                    "A" => arch = Some(value),
+                    "L" => license = Some(value),
+                    _ => {}
+                }
+            }
+            let (name, version) = (name?, version?);
+            let mut purl = format!(
+                "pkg:apk/{}/{}@{}",
+                distribution,
+                purl_encode(name),
+                purl_encode(version)
+            );
+            if let Some(arch) = arch {
+                purl.push_str(&format!("?arch={}", purl_encode(arch)));
+            }
+            Some(Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                purl,
+                license: license.map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Installed packages of the dpkg database, `/var/lib/dpkg/status`.
+pub fn parse_dpkg(status: &str, distribution: &str) -> Vec<Package> {
+    status
+        .split("\n\n")
+        .filter_map(|record| {
+            let (mut name, mut version, mut arch, mut installed) = (None, None, None, false);
+            for (key, value) in fields(record, ": ") {
+                match key {
+                    "Package" => name = Some(value),
+                    "Version" => version = Some(value),
+                    "Architecture" => arch = Some(value),
+                    "Status" => installed = value.ends_with(" installed"),
+                    _ => {}
+                }
+            }
+            if !installed {
+                return None;
+            }
+            let (name, version) = (name?, version?);
+            let mut purl = format!(
+                "pkg:deb/{}/{}@{}",
+                distribution,
+                purl_encode(name),
+                purl_encode(version)
+            );
+            if let Some(arch) = arch {
+                purl.push_str(&format!("?arch={}", purl_encode(arch)));
+            }
+            Some(Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                purl,
+                license: None,
+            })
+        })
+        .collect()
+}
+
+/// Packages of the rpm database, queried with the `rpm` tool of the host.
+fn rpm_packages(rootfs: &Path, distribution: &str) -> Vec<Package> {
+    let output = Command::new("rpm")
+        .arg("--root")
+        .arg(rootfs)
+        .args([
+            "-qa",
+            "--qf",
+            "%{NAME}\\t%{VERSION}-%{RELEASE}\\t%{ARCH}\\t%{LICENSE}\\n",
+        ])
+        .output();
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            warn!(status = %output.status, "rpm failed, the rpm packages aren't listed");
+            return Vec::new();
+        }
+        Err(e) => {
+            warn!(error = %e, "rpm is needed to list the rpm packages, they aren't listed");
+            return Vec::new();
+        }
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split('\t');
+            let (name, version, arch) = (columns.next()?, columns.next()?, columns.next()?);
+            Some(Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                purl: format!(
+                    "pkg:rpm/{}/{}@{}?arch={}",
+                    distribution,
+                    purl_encode(name),
+                    purl_encode(version),
+                    purl_encode(arch)
+                ),
+                license: columns.next().map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Python distribution of a `METADATA` or `PKG-INFO` file.
+pub fn parse_python_metadata(metadata: &str) -> Option<Package> {
+    // The headers end at the first empty line, the description follows.
+    let headers = metadata.split("\n\n").next()?;
+    let (mut name, mut version, mut license) = (None, None, None);
+    for (key, value) in fields(headers, ": ") {
+        match key {
+            "Name" => name = Some(value),
+            "Version" => version = Some(value),
+            "License-Expression" => license = Some(value),
+            "License" if license.is_none() && !value.is_empty() && value != "UNKNOWN" => {
+                license = Some(value)
+            }
+            _ => {}
+        }
+    }
+    let (name, version) = (name?, version?);
+    Some(Package {
+        name: name.to_string(),
+        version: version.to_string(),
+        purl: format!(
+            "pkg:pypi/{}@{}",
+            purl_encode(&name.to_lowercase().replace('_', "-")),
+            purl_encode(version)
+        ),
+        license: license.map(str::to_string),
+    })
+}
+
+fn npm_package(name: &str, version: &str, license: Option<&str>) -> Package {
+    // The `@` of a scope is encoded, its `/` separates the namespace.
+    let purl_name = match name.strip_prefix('@').and_then(|name| name.split_once('/')) {
+        Some((scope, name)) => format!("%40{}/{}", purl_encode(scope), purl_encode(name)),
+        None => purl_encode(name),
+    };
+    Package {
+        name: name.to_string(),
+        version: version.to_string(),
+        purl: format!("pkg:npm/{}@{}", purl_name, purl_encode(version)),
+        license: license.map(str::to_string),
+    }
+}
+
+/// Node module of a `package.json`.
+fn parse_package_json(package: &str) -> Option<Package> {
+    let package: Value = serde_json::from_str(package).ok()?;
+    Some(npm_package(
+        package["name"].as_str()?,
+        package["version"].as_str()?,
+        package["license"].as_str(),
+    ))
+}
+
+/// Packages locked by a `package-lock.json`, of version 2 or 3.
+pub fn parse_package_lock(lock: &str) -> Vec<Package> {
+    let Ok(lock) = serde_json::from_str::<Value>(lock) else {
+        return Vec::new();
+    };
+    let Some(packages) = lock["packages"].as_object() else {
+        return Vec::new();
+    };
+    packages
+        .iter()
+        .filter_map(|(path, package)| {
+            // The root package has an empty path, the others are under `node_modules/`.
+            let name = path.rsplit_once("node_modules/")?.1;
+            Some(npm_package(
+                name,
+                package["version"].as_str()?,
+                package["license"].as_str(),
+            ))
+        })
+        .collect()
+}
+
+/// Crates locked by a `Cargo.lock`.
+pub fn parse_cargo_lock(lock: &str) -> Vec<Package> {
+    lock.split("[[package]]")
+        .skip(1)
+        .filter_map(|package| {
+            let value = |key: &str| {
+                package.lines().find_map(|line| {
+                    line.strip_prefix(key)
+                        .and_then(|rest| rest.trim_start().strip_prefix('='))
+                        .map(|value| value.trim().trim_matches('"'))
+                })
+            };
+            let (name, version) = (value("name")?, value("version")?);
+            Some(Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                purl: format!("pkg:cargo/{}@{}", purl_encode(name), purl_encode(version)),
+                license: None,
+            })
+        })
+        .collect()
+}
+
+/// Search `directory` for the Python distributions, Node modules and lockfiles.
+fn scan_directory(directory: &Path, packages: &mut BTreeSet<Package>) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if file_type.is_dir() {
+            if name.ends_with(".dist-info") || name.ends_with(".egg-info") {
+                let metadata = ["METADATA", "PKG-INFO"]
+                    .iter()
+                    .find_map(|file| fs::read_to_string(path.join(file)).ok());
+                packages.extend(metadata.as_deref().and_then(parse_python_metadata));
+                continue;
+            }
+            if directory
+                .file_name()
+                .is_some_and(|parent| parent == "node_modules")
+                || (directory
+                    .parent()
+                    .and_then(Path::file_name)
+                    .is_some_and(|parent| parent == "node_modules")
+                    && directory
+                        .file_name()
+                        .is_some_and(|scope| scope.to_string_lossy().starts_with('@')))
+            {
+                if let Ok(package) = fs::read_to_string(path.join("package.json")) {
+                    packages.extend(parse_package_json(&package));
+                }
+            }
+            scan_directory(&path, packages);
+        } else if file_type.is_file() {
+            let parse: fn(&str) -> Vec<Package> = match name.as_ref() {
+                "Cargo.lock" => parse_cargo_lock,
+                "package-lock.json" => parse_package_lock,
+                _ => continue,
+            };
+            if let Ok(lock) = fs::read_to_string(&path) {
+                debug!(lockfile = ?path, "Reading");
+                packages.extend(parse(&lock));
+            }
+        }
+    }
+}
+
+/// Every package found in `rootfs`.
+pub fn detect_packages(rootfs: &Path) -> Vec<Package> {
+    let distribution = distribution(rootfs);
+    let mut packages = BTreeSet::new();
+
+    if let Ok(installed) = fs::read_to_string(rootfs.join("lib/apk/db/installed")) {
+        packages.extend(parse_apk(&installed, &distribution));
+    }
+    if let Ok(status) = fs::read_to_string(rootfs.join("var/lib/dpkg/status")) {
+        packages.extend(parse_dpkg(&status, &distribution));
+    }
+    if ["var/lib/rpm", "usr/lib/sysimage/rpm"]
+        .iter()
+        .any(|path| rootfs.join(path).is_dir())
+    {
+        packages.extend(rpm_packages(rootfs, &distribution));
+    }
+
+    let Ok(entries) = fs::read_dir(rootfs) else {
+        return packages.into_iter().collect();
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        if SKIPPED_DIRECTORIES.iter().any(|skipped| name == *skipped) {
+            continue;
+        }
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            scan_directory(&entry.path(), &mut packages);
+        }
+    }
+
+    packages.into_iter().collect()
+}
+
+/// UUID derived from the content of the bill, so that the same rootfs gets the same one.
+fn content_uuid(image: &str, packages: &[Package]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(image);
+    for package in packages {
+        hasher.update(package.purl.as_bytes());
+    }
+    let digest = hasher.finalize();
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    // Version 5 style, of the RFC 4122 variant.
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The current time as an RFC 3339 UTC timestamp, or `SOURCE_DATE_EPOCH` for reproducible
+/// builds.
+fn timestamp() -> String {
+    let seconds = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        });
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    // Civil date of a day count, after Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+fn tool_name() -> String {
+    format!("{}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// CycloneDX 1.5 bill of the rootfs of `image`.
+pub fn cyclonedx(image: &str, packages: &[Package]) -> Value {
+    let components: Vec<Value> = packages
+        .iter()
+        .map(|package| {
+            let mut component = json!({
+                "type": "library",
+                "bom-ref": package.purl,
+                "name": package.name,
+                "version": package.version,
+                "purl": package.purl,
+            });
+            if let Some(license) = &package.license {
+                component["licenses"] = json!([{ "expression": license }]);
+            }
+            component
+        })
+        .collect();
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", content_uuid(image, packages)),
+        "version": 1,
+        "metadata": {
+            "timestamp": timestamp(),
+            "tools": { "components": [{
+                "type": "application",
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            }] },
+            "component": { "type": "container", "name": image },
+        },
+        "components": components,
+    })
+}
+
+/// SPDX 2.3 bill of the rootfs of `image`.
+pub fn spdx(image: &str, packages: &[Package]) -> Value {
+    let spdx_packages: Vec<Value> = packages
+        .iter()
+        .enumerate()
+        .map(|(index, package)| {
+            json!({
+                "name": package.name,
+                "SPDXID": format!("SPDXRef-Package-{}", index),
+                "versionInfo": package.version,
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "licenseConcluded": "NOASSERTION",
+                "licenseDeclared": package.license.as_deref().unwrap_or("NOASSERTION"),
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": package.purl,
+                }],
+            })
+        })
+        .collect();
+    let relationships: Vec<Value> = (0..packages.len())
+        .map(|index| {
+            json!({
+                "spdxElementId": "SPDXRef-DOCUMENT",
+                "relationshipType": "DESCRIBES",
+                "relatedSpdxElement": format!("SPDXRef-Package-{}", index),
+            })
+        })
+        .collect();
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": image,
+        "documentNamespace": format!(
+            "https://spdx.org/spdxdocs/{}-{}",
+            purl_encode(image),
+            content_uuid(image, packages)
+        ),
+        "creationInfo": {
+            "created": timestamp(),
+            "creators": [format!("Tool: {}", tool_name())],
+        },
+        "packages": spdx_packages,
+        "relationships": relationships,
+    })
+}
+
+/// Generate the bill of the rootfs of `image`, written to `output` and embedded in the rootfs
+/// if `embed`.
+pub fn generate_sbom(
+    rootfs: &Path,
+    image: &str,
+    format: SbomFormat,
+    output: &Path,
+    embed: bool,
+) -> Result<()> {
+    info!("Generating the SBOM...");
+
+    let packages = detect_packages(rootfs);
+    let sbom = match format {
+        SbomFormat::Cyclonedx => cyclonedx(image, &packages),
+        SbomFormat::Spdx => spdx(image, &packages),
+    };
+    let sbom = serde_json::to_string_pretty(&sbom)?;
+    fs::write(output, &sbom)
+        .with_context(|| format!("Failed to write the SBOM to {:?}", output))?;
+    if embed {
+        let embedded = rootfs.join(format!("{}.{}", EMBEDDED_PATH, format.extension()));
+        fs::create_dir_all(embedded.parent().unwrap())?;
+        // The image may have a symlink there, pointing anywhere on the host.
+        let _ = fs::remove_file(&embedded);
+        fs::write(&embedded, &sbom).with_context(|| "Failed to embed the SBOM".to_string())?;
+    }
+
+    info!(packages = packages.len(), output = ?output, "SBOM generated!");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_apk() {
+        let installed = "C:Q1abc=\nP:musl\nV:1.2.4-r2\nA:x86_64\nL:MIT\n\nP:ca-certificates-bundle\nV:20240226-r0\nA:x86_64\nL:MPL-2.0 AND MIT\n\n";
+        assert_eq!(
+            parse_apk(installed, "alpine"),
+            vec![
+                Package {
+                    name: "musl".into(),
+                    version: "1.2.4-r2".into(),
+                    purl: "pkg:apk/alpine/musl@1.2.4-r2?arch=x86_64".into(),
+                    license: Some("MIT".into()),
+                },
+                Package {
+                    name: "ca-certificates-bundle".into(),
+                    version: "20240226-r0".into(),
+                    purl: "pkg:apk/alpine/ca-certificates-bundle@20240226-r0?arch=x86_64".into(),
+                    license: Some("MPL-2.0 AND MIT".into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_dpkg_skips_removed() {
+        let status = "Package: libc6\nStatus: install ok installed\nArchitecture: amd64\nVersion: 2.36-9+deb12u4\n\nPackage: vim\nStatus: deinstall ok config-files\nVersion: 2:9.0\n";
+        let packages = parse_dpkg(status, "debian");
+        assert_eq!(packages.len(), 1);
+        assert_eq!(
+            packages[0].purl,
+            "pkg:deb/debian/libc6@2.36-9%2Bdeb12u4?arch=amd64"
+        );
+    }
+
+    #[test]
+    fn test_parse_language_packages() {
+        let metadata = "Metadata-Version: 2.1\nName: Flask_Cors\nVersion: 4.0.0\nLicense: MIT\n\nLicense: not a header\n";
+        let package = parse_python_metadata(metadata).unwrap();
+        assert_eq!(package.purl, "pkg:pypi/flask-cors@4.0.0");
+        assert_eq!(package.license.as_deref(), Some("MIT"));
+
+        let lock = r#"{"lockfileVersion": 3, "packages": {
+            "": {"name": "app", "version": "1.0.0"},
+            "node_modules/@types/node": {"version": "20.1.0", "license": "MIT"},
+            "node_modules/a/node_modules/b": {"version": "2.0.0"}
+        }}"#;
+        let purls: Vec<String> = parse_package_lock(lock)
+            .into_iter()
+            .map(|package| package.purl)
+            .collect();
+        assert_eq!(purls, ["pkg:npm/%40types/node@20.1.0", "pkg:npm/b@2.0.0"]);
+
+        let lock = "version = 3\n\n[[package]]\nname = \"serde\"\nversion = \"1.0.197\"\nsource = \"registry+https://github.com/rust-lang/crates.io-index\"\n";
+        assert_eq!(parse_cargo_lock(lock)[0].purl, "pkg:cargo/serde@1.0.197");
+    }
+
+    #[test]
+    fn test_timestamp() {
+        std::env::set_var("SOURCE_DATE_EPOCH", "1709251199");
+        assert_eq!(timestamp(), "2024-02-29T23:59:59Z");
+    }
+}
@@ -0,0 +1,331 @@
+//! Vulnerability scan of the merged rootfs, by trivy or grype, before the image is generated.
+//!
+//! The findings at or above the severity threshold either fail the build, or flag the image:
+//! the summary written next to it, `<OUTPUT>.scan.json`, is then marked flagged, and
+//! `fs-gen push` carries the flag in the annotations of the artifact, which the VMMs refuse to
+//! boot.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::cli_args::{ScanAction, Scanner, Severity};
+
+/// Annotations of the artifacts pushed with a scan summary.
+pub const FLAGGED_ANNOTATION: &str = "dev.cloudlet.scan.flagged";
+pub const SUMMARY_ANNOTATION: &str = "dev.cloudlet.scan.summary";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub id: String,
+    pub package: String,
+    pub version: String,
+    pub fixed_version: Option<String>,
+    pub severity: Severity,
+}
+
+/// Summary of a scan, written next to the image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanSummary {
+    pub scanner: String,
+    pub threshold: Severity,
+    /// Findings at or above the threshold.
+    pub flagged: bool,
+    /// Number of findings of each severity.
+    pub counts: BTreeMap<Severity, usize>,
+    /// The findings at or above the threshold.
+    pub findings: Vec<Finding>,
+}
+
+impl ScanSummary {
+    pub fn new(scanner: &str, threshold: Severity, findings: Vec<Finding>) -> Self {
+        let mut counts = BTreeMap::new();
+        for finding in &findings {
+            *counts.entry(finding.severity).or_insert(0) += 1;
+        }
+        let findings: Vec<Finding> = findings
+            .into_iter()
+            .filter(|finding| finding.severity >= threshold)
+            .collect();
+        Self {
+            scanner: scanner.to_string(),
+            threshold,
+            flagged: !findings.is_empty(),
+            counts,
+            findings,
+        }
+    }
+
+    /// Counts of the summary, e.g. `critical=1,high=3`, as annotated on the artifacts.
+    pub fn counts_string(&self) -> String {
+        self.counts
+            .iter()
+            .rev()
+            .map(|(severity, count)| format!("{}={}", severity.as_str(), count))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Summary of the image at `image`, if it was scanned.
+    pub fn read(image: &Path) -> Result<Option<Self>> {
+        let path = summary_path(image);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let summary = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read the scan summary {:?}", path))?;
+        Ok(Some(serde_json::from_str(&summary).with_context(|| {
+            format!("Invalid scan summary {:?}", path)
+        })?))
+    }
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Unknown => "unknown",
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        }
+    }
+
+    /// Severity as named by trivy and grype, e.g. `HIGH` or `Negligible`.
+    fn parse(severity: &str) -> Self {
+        match severity.to_ascii_lowercase().as_str() {
+            "negligible" | "low" => Severity::Low,
+            "medium" | "moderate" => Severity::Medium,
+            "high" => Severity::High,
+            "critical" => Severity::Critical,
+            _ => Severity::Unknown,
+        }
+    }
+}
+
+/// Path of the scan summary of the image at `image`.
+pub fn summary_path(image: &Path) -> PathBuf {
+    let mut path = image.to_path_buf().into_os_string();
+    path.push(".scan.json");
+    PathBuf::from(path)
+}
+
+/// Findings of a `trivy rootfs --format json` report.
+pub fn parse_trivy(report: &Value) -> Vec<Finding> {
+    report["Results"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|result| result["Vulnerabilities"].as_array().into_iter().flatten())
+        .filter_map(|vulnerability| {
+            Some(Finding {
+                id: vulnerability["VulnerabilityID"].as_str()?.to_string(),
+                package: vulnerability["PkgName"].as_str()?.to_string(),
+                version: vulnerability["InstalledVersion"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                fixed_version: vulnerability["FixedVersion"]
+                    .as_str()
+                    .filter(|version| !version.is_empty())
+                    .map(str::to_string),
+                severity: Severity::parse(vulnerability["Severity"].as_str().unwrap_or_default()),
+            })
+        })
+        .collect()
+}
+
+/// Findings of a `grype -o json` report.
+pub fn parse_grype(report: &Value) -> Vec<Finding> {
+    report["matches"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|matched| {
+            let vulnerability = &matched["vulnerability"];
+            let artifact = &matched["artifact"];
+            Some(Finding {
+                id: vulnerability["id"].as_str()?.to_string(),
+                package: artifact["name"].as_str()?.to_string(),
+                version: artifact["version"].as_str().unwrap_or_default().to_string(),
+                fixed_version: vulnerability["fix"]["versions"]
+                    .as_array()
+                    .and_then(|versions| versions.first())
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                severity: Severity::parse(vulnerability["severity"].as_str().unwrap_or_default()),
+            })
+        })
+        .collect()
+}
+
+/// Parse a report of either scanner, telling them apart by their layout.
+fn parse_report(report: &Value) -> Result<(&'static str, Vec<Finding>)> {
+    if report.get("Results").is_some() || report.get("SchemaVersion").is_some() {
+        Ok(("trivy", parse_trivy(report)))
+    } else if report.get("matches").is_some() {
+        Ok(("grype", parse_grype(report)))
+    } else {
+        bail!("Not a trivy nor a grype JSON report")
+    }
+}
+
+fn run_scanner(scanner: Scanner, rootfs: &Path) -> Result<Value> {
+    let mut command = match scanner {
+        Scanner::Trivy => {
+            let mut command = Command::new("trivy");
+            command
+                .args([
+                    "rootfs",
+                    "--quiet",
+                    "--format",
+                    "json",
+                    "--scanners",
+                    "vuln",
+                ])
+                .arg(rootfs);
+            command
+        }
+        Scanner::Grype => {
+            let mut command = Command::new("grype");
+            command
+                .args(["--quiet", "-o", "json"])
+                .arg(format!("dir:{}", rootfs.display()));
+            command
+        }
+    };
+    debug!(command = ?command, "Running");
+    let output = command
+        .output()
+        .with_context(|| format!("Failed to run {}, is it installed?", scanner.as_str()))?;
+    if !output.status.success() {
+        bail!(
+            "{} failed with {}: {}",
+            scanner.as_str(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Invalid JSON report of {}", scanner.as_str()))
+}
+
+/// Options of the scan of the rootfs.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Scanner run on the rootfs.
+    pub scanner: Option<Scanner>,
+    /// Report of a scan already run, used instead of running a scanner.
+    pub report: Option<PathBuf>,
+    pub threshold: Severity,
+    pub action: ScanAction,
+}
+
+/// Scan `rootfs` and write the summary next to `image`, failing if the threshold is reached
+/// and the action is to fail.
+pub fn scan_rootfs(rootfs: &Path, image: &Path, options: &ScanOptions) -> Result<()> {
+    let report: Value = match (&options.report, options.scanner) {
+        (Some(path), _) => {
+            let report = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the scan report {:?}", path))?;
+            serde_json::from_str(&report)
+                .with_context(|| format!("Invalid scan report {:?}", path))?
+        }
+        (None, Some(scanner)) => {
+            info!("Scanning the rootfs with {}...", scanner.as_str());
+            run_scanner(scanner, rootfs)?
+        }
+        (None, None) => return Ok(()),
+    };
+    let (scanner, findings) = parse_report(&report)?;
+    let summary = ScanSummary::new(scanner, options.threshold, findings);
+
+    let path = summary_path(image);
+    fs::write(&path, serde_json::to_string_pretty(&summary)?)
+        .with_context(|| format!("Failed to write the scan summary {:?}", path))?;
+
+    if !summary.flagged {
+        info!(counts = summary.counts_string(), "Scan passed!");
+        return Ok(());
+    }
+    for finding in &summary.findings {
+        warn!(
+            id = finding.id,
+            package = finding.package,
+            version = finding.version,
+            fixed_version = finding.fixed_version,
+            severity = finding.severity.as_str(),
+            "Vulnerability"
+        );
+    }
+    match options.action {
+        ScanAction::Fail => bail!(
+            "{} vulnerabilities at or above {} ({}), see {:?}",
+            summary.findings.len(),
+            options.threshold.as_str(),
+            summary.counts_string(),
+            path
+        ),
+        ScanAction::Flag => {
+            warn!(
+                findings = summary.findings.len(),
+                threshold = options.threshold.as_str(),
+                "The image is flagged as vulnerable"
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_summaries_of_both_scanners() {
+        let trivy = json!({
+            "SchemaVersion": 2,
+            "Results": [
+                { "Target": "alpine 3.19" },
+                { "Vulnerabilities": [
+                    { "VulnerabilityID": "CVE-2024-0001", "PkgName": "openssl",
+                      "InstalledVersion": "3.1.4-r1", "FixedVersion": "3.1.4-r5",
+                      "Severity": "CRITICAL" },
+                    { "VulnerabilityID": "CVE-2024-0002", "PkgName": "busybox",
+                      "InstalledVersion": "1.36.1-r15", "FixedVersion": "",
+                      "Severity": "MEDIUM" }
+                ]}
+            ]
+        });
+        let grype = json!({
+            "matches": [
+                { "vulnerability": { "id": "CVE-2024-0001", "severity": "Critical",
+                                     "fix": { "versions": ["3.1.4-r5"] } },
+                  "artifact": { "name": "openssl", "version": "3.1.4-r1" } },
+                { "vulnerability": { "id": "CVE-2024-0002", "severity": "Medium",
+                                     "fix": { "versions": [] } },
+                  "artifact": { "name": "busybox", "version": "1.36.1-r15" } }
+            ]
+        });
+
+        for (name, report) in [("trivy", trivy), ("grype", grype)] {
+            let (scanner, findings) = parse_report(&report).unwrap();
+            assert_eq!(scanner, name);
+            assert_eq!(findings[0].fixed_version.as_deref(), Some("3.1.4-r5"));
+            assert_eq!(findings[1].fixed_version, None);
+
+            let summary = ScanSummary::new(scanner, Severity::High, findings.clone());
+            assert!(summary.flagged);
+            assert_eq!(summary.findings, vec![findings[0].clone()]);
+            assert_eq!(summary.counts_string(), "critical=1,medium=1");
+
+            assert!(!ScanSummary::new(scanner, Severity::Critical, findings[1..].to_vec()).flagged);
+        }
+    }
+}
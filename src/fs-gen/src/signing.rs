@@ -0,0 +1,119 @@
+//! Minisign signatures of the generated images and of the kernels, checked by the orchestrators
+//! configured with the public key before booting them.
+//!
+//! The signature of an artifact is written next to it as `<ARTIFACT>.minisig`, and pushed with
+//! the image as an annotation of its artifact. An encrypted key is decrypted with the password
+//! of the `FS_GEN_SIGN_KEY_PASSWORD` environment variable.
+
+use anyhow::{Context, Result};
+use minisign::{SecretKey, SecretKeyBox};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Annotation of the pushed artifacts holding the signature of their image.
+pub const SIGNATURE_ANNOTATION: &str = "dev.cloudlet.signature";
+
+/// Environment variable holding the password of an encrypted secret key.
+const PASSWORD_VARIABLE: &str = "FS_GEN_SIGN_KEY_PASSWORD";
+
+/// Path of the signature of the artifact at `path`.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(".minisig");
+    PathBuf::from(path)
+}
+
+fn load_key(key_path: &Path) -> Result<SecretKey> {
+    let key = fs::read_to_string(key_path)
+        .with_context(|| format!("Failed to read the signing key {:?}", key_path))?;
+    let key = SecretKeyBox::from_string(&key)
+        .with_context(|| format!("Invalid minisign secret key {:?}", key_path))?;
+    match std::env::var(PASSWORD_VARIABLE) {
+        Ok(password) => key.into_secret_key(Some(password)),
+        Err(_) => key.into_unencrypted_secret_key(),
+    }
+    .with_context(|| {
+        format!(
+            "Failed to load the signing key {:?}, set {} if it is encrypted",
+            key_path, PASSWORD_VARIABLE
+        )
+    })
+}
+
+/// Sign the artifact at `path` with the minisign secret key at `key_path`, returning the path
+/// of its signature.
+pub fn sign_artifact(path: &Path, key_path: &Path) -> Result<PathBuf> {
+    let key = load_key(key_path)?;
+    let artifact = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let signature = minisign::sign(
+        None,
+        &key,
+        artifact,
+        Some(&format!("file:{}", file_name)),
+        Some("signed by fs-gen"),
+    )
+    .with_context(|| format!("Failed to sign {:?}", path))?;
+
+    let signature_path = signature_path(path);
+    fs::write(&signature_path, signature.to_string())
+        .with_context(|| format!("Failed to write {:?}", signature_path))?;
+    info!(signature = ?signature_path, "Signed {:?}", path);
+    Ok(signature_path)
+}
+
+/// The signature of the artifact at `path`, if it was signed.
+pub fn read_signature(path: &Path) -> Result<Option<String>> {
+    let signature_path = signature_path(path);
+    match fs::read_to_string(&signature_path) {
+        Ok(signature) => Ok(Some(signature)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {:?}", signature_path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minisign::{KeyPair, SignatureBox};
+
+    #[test]
+    fn test_sign_artifact() {
+        let dir = std::env::temp_dir().join(format!("fs-gen-signing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let KeyPair { pk, sk } = KeyPair::generate_unencrypted_keypair().unwrap();
+        let key_path = dir.join("cloudlet.key");
+        fs::write(&key_path, sk.to_box(None).unwrap().to_string()).unwrap();
+        let image = dir.join("initramfs.img");
+        fs::write(&image, b"initramfs").unwrap();
+
+        let signature_path = sign_artifact(&image, &key_path).unwrap();
+        assert_eq!(signature_path, dir.join("initramfs.img.minisig"));
+        let signature = read_signature(&image).unwrap().unwrap();
+        let signature = SignatureBox::from_string(&signature).unwrap();
+        assert_eq!(signature.trusted_comment().unwrap(), "file:initramfs.img");
+        minisign::verify(
+            &pk,
+            &signature,
+            File::open(&image).unwrap(),
+            true,
+            false,
+            false,
+        )
+        .unwrap();
+
+        fs::write(&image, b"tampered").unwrap();
+        assert!(minisign::verify(
+            &pk,
+            &signature,
+            File::open(&image).unwrap(),
+            true,
+            false,
+            false
+        )
+        .is_err());
+        assert!(read_signature(&dir.join("missing.img")).unwrap().is_none());
+    }
+}
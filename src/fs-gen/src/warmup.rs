@@ -0,0 +1,210 @@
+//! Warmup of the language runtimes of the rootfs before the image is generated, so that the
+//! first run in a fresh VM doesn't pay for it: `--warmup <language>`.
+//!
+//! Each step is a [`Warmup`], which applies to the rootfs when it finds its runtime there, runs
+//! its commands inside the rootfs like `--run-in-rootfs` and can add variables to the
+//! environment the init gives to the agent:
+//!
+//! - python: the modules of `sys.path` are compiled to bytecode with `compileall`;
+//! - node: the global modules are loaded once with `NODE_COMPILE_CACHE`, which the images
+//!   keep set for the V8 code cache to be used and extended by the workloads (Node 22 and up);
+//! - rust: the crates.io index and the most used crates are fetched into the cargo home.
+//!
+//! A step which fails only logs a warning: the image works without it, slower to start.
+
+use anyhow::Result;
+use shared_models::Language;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::build_spec::{read_env, write_env};
+use crate::rootfs_hooks::run_in_rootfs;
+
+/// Directory of the V8 code cache of the images.
+const NODE_COMPILE_CACHE: &str = "/var/cache/cloudlet/node-compile-cache";
+
+/// Crates fetched into the cargo home with the index.
+const CRATES: &[&str] = &["serde", "serde_json", "anyhow", "rand", "regex", "tokio"];
+
+/// Step of the warmup of a runtime.
+pub trait Warmup {
+    fn name(&self) -> &'static str;
+
+    /// Whether the step applies to `rootfs`, which has its runtime.
+    fn applies(&self, rootfs: &Path) -> bool;
+
+    /// Commands run inside `rootfs` by its `/bin/sh`.
+    fn commands(&self, rootfs: &Path) -> Vec<String>;
+
+    /// Variables added to the environment of the workloads, unless the image sets them.
+    fn env(&self) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+}
+
+/// Whether `rootfs` has one of `paths`, which may be symlinks resolved in the rootfs only.
+fn has_any(rootfs: &Path, paths: &[&str]) -> bool {
+    paths
+        .iter()
+        .any(|path| rootfs.join(path).symlink_metadata().is_ok())
+}
+
+/// Bytecode of the Python modules.
+pub struct PythonBytecode;
+
+impl Warmup for PythonBytecode {
+    fn name(&self) -> &'static str {
+        "python-bytecode"
+    }
+
+    fn applies(&self, rootfs: &Path) -> bool {
+        has_any(rootfs, &["usr/local/bin/python3", "usr/bin/python3"])
+    }
+
+    fn commands(&self, _rootfs: &Path) -> Vec<String> {
+        vec!["python3 -c \"import compileall, os, sys; \
+             sys.exit(not all([compileall.compile_dir(path, quiet=1, workers=0) \
+             for path in sys.path if os.path.isdir(path)]))\""
+            .to_string()]
+    }
+}
+
+/// V8 code cache of the Node modules.
+pub struct NodeCompileCache;
+
+impl Warmup for NodeCompileCache {
+    fn name(&self) -> &'static str {
+        "node-compile-cache"
+    }
+
+    fn applies(&self, rootfs: &Path) -> bool {
+        has_any(rootfs, &["usr/local/bin/node", "usr/bin/node"])
+    }
+
+    fn commands(&self, _rootfs: &Path) -> Vec<String> {
+        vec![format!(
+            "mkdir -p {cache} && NODE_COMPILE_CACHE={cache} node -e \"\
+             const path = require('path'), fs = require('fs'); \
+             const root = path.join(path.dirname(process.execPath), '..', 'lib', 'node_modules'); \
+             for (const name of fs.existsSync(root) ? fs.readdirSync(root) : []) \
+             {{ try {{ require(path.join(root, name)); }} catch {{}} }}\"",
+            cache = NODE_COMPILE_CACHE
+        )]
+    }
+
+    fn env(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([(
+            "NODE_COMPILE_CACHE".to_string(),
+            NODE_COMPILE_CACHE.to_string(),
+        )])
+    }
+}
+
+/// Index of crates.io and the most used crates, in the cargo home.
+pub struct CargoIndex;
+
+impl Warmup for CargoIndex {
+    fn name(&self) -> &'static str {
+        "cargo-index"
+    }
+
+    fn applies(&self, rootfs: &Path) -> bool {
+        has_any(rootfs, &["usr/local/cargo/bin/cargo", "usr/bin/cargo"])
+    }
+
+    fn commands(&self, rootfs: &Path) -> Vec<String> {
+        // The official images install the toolchain in /usr/local/cargo, set by their config.
+        let cargo_home = if rootfs.join("usr/local/cargo").is_dir() {
+            "export CARGO_HOME=/usr/local/cargo PATH=/usr/local/cargo/bin:$PATH; "
+        } else {
+            ""
+        };
+        let dependencies: String = CRATES
+            .iter()
+            .map(|name| format!("{} = \\\"*\\\"\\n", name))
+            .collect();
+        vec![format!(
+            "{}mkdir -p /tmp/cloudlet-warmup/src && cd /tmp/cloudlet-warmup && \
+             printf \"[package]\\nname = \\\"warmup\\\"\\nversion = \\\"0.1.0\\\"\\n\
+             edition = \\\"2021\\\"\\n\\n[dependencies]\\n{}\" > Cargo.toml && \
+             touch src/main.rs && cargo fetch; status=$?; \
+             cd / && rm -rf /tmp/cloudlet-warmup; exit $status",
+            cargo_home, dependencies
+        )]
+    }
+}
+
+/// Steps of the warmup of the runtime of `language`.
+pub fn steps(language: &Language) -> Vec<Box<dyn Warmup>> {
+    match language {
+        Language::PYTHON => vec![Box::new(PythonBytecode)],
+        Language::NODE => vec![Box::new(NodeCompileCache)],
+        Language::RUST => vec![Box::new(CargoIndex)],
+    }
+}
+
+/// Run the `steps` which apply to `rootfs`.
+pub fn warm_up(rootfs: &Path, steps: &[Box<dyn Warmup>]) -> Result<()> {
+    for step in steps {
+        if !step.applies(rootfs) {
+            info!(
+                step = step.name(),
+                "The runtime isn't in the rootfs, skipping"
+            );
+            continue;
+        }
+
+        info!(step = step.name(), "Warming up");
+        if let Err(e) = run_in_rootfs(rootfs, &step.commands(rootfs)) {
+            warn!(step = step.name(), error = ?e, "Warmup failed, the image is left cold");
+            continue;
+        }
+        add_env(rootfs, step.env())?;
+    }
+
+    Ok(())
+}
+
+/// Add `env` to the environment of the image, its own variables first.
+fn add_env(rootfs: &Path, env: BTreeMap<String, String>) -> Result<()> {
+    if env.is_empty() {
+        return Ok(());
+    }
+    let mut image_env = read_env(rootfs)?;
+    for (name, value) in env {
+        image_env.entry(name).or_insert(value);
+    }
+    write_env(rootfs, &image_env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudlet_test_registry::scratch_dir;
+    use std::fs;
+
+    #[test]
+    fn test_steps() {
+        let rootfs = scratch_dir("fs-gen-warmup");
+        fs::create_dir_all(rootfs.join("usr/local/bin")).unwrap();
+        std::os::unix::fs::symlink("/usr/local/bin/node20", rootfs.join("usr/local/bin/node"))
+            .unwrap();
+
+        // The node of the image is a symlink which only resolves inside it.
+        assert!(NodeCompileCache.applies(&rootfs));
+        assert!(!PythonBytecode.applies(&rootfs));
+        assert!(!CargoIndex.applies(&rootfs));
+        assert!(CargoIndex.commands(&rootfs)[0].contains("serde_json = \\\"*\\\""));
+        assert!(!CargoIndex.commands(&rootfs)[0].contains("CARGO_HOME"));
+        assert_eq!(steps(&Language::NODE)[0].name(), "node-compile-cache");
+
+        // The variables of the image are kept.
+        add_env(&rootfs, BTreeMap::from([("A".into(), "1".into())])).unwrap();
+        add_env(&rootfs, NodeCompileCache.env()).unwrap();
+        add_env(&rootfs, BTreeMap::from([("A".into(), "2".into())])).unwrap();
+        let env = read_env(&rootfs).unwrap();
+        assert_eq!(env["A"], "1");
+        assert_eq!(env["NODE_COMPILE_CACHE"], NODE_COMPILE_CACHE);
+    }
+}
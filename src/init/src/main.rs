@@ -0,0 +1,351 @@
+//! Init of the guests, installed as `/init` in the rootfs images by `fs-gen`.
+//!
+//! It mounts the pseudo filesystems, names the guest and configures its network from the kernel
+//! command line, then runs the agent, restarting it if it crashes. When the agent exits cleanly,
+//! which it does when the VMM shuts the VM down, or when the init gets `SIGTERM`, `SIGUSR2` or
+//! `SIGPWR`, the remaining processes are stopped and the VM is powered off.
+//!
+//! As the first process of the guest, the init also reaps the orphans the agent doesn't adopt.
+
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{sethostname, Pid};
+use std::collections::VecDeque;
+use std::fs;
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+mod net;
+
+const AGENT: &str = "/agent";
+
+/// Name of the guest when the kernel command line doesn't give one.
+const DEFAULT_HOSTNAME: &str = "cloudlet";
+
+/// Environment of the agent, where the rust images install their toolchain.
+const AGENT_ENV: [(&str, &str); 4] = [
+    ("CARGO_HOME", "/usr/local/cargo"),
+    ("RUSTUP_HOME", "/usr/local/rustup"),
+    ("RUST_VERSION", "1.77.2"),
+    (
+        "PATH",
+        "/usr/local/cargo/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    ),
+];
+
+/// Environment given by the build spec of the image, `NAME=VALUE` lines overriding
+/// [`AGENT_ENV`].
+const IMAGE_ENV: &str = "/etc/cloudlet/env";
+
+/// Kernel parameter of the port the agent listens on, set by the VMM when it isn't the default
+/// one of the agent.
+const AGENT_PORT_PARAMETER: &str = "cloudlet.agent_port=";
+
+/// Delay before the first restart of a crashed agent, doubled at each crash up to
+/// [`MAX_RESTART_DELAY`].
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(10);
+
+/// Crashes of the agent within [`CRASH_WINDOW`] after which it isn't restarted anymore.
+const MAX_CRASHES: usize = 5;
+const CRASH_WINDOW: Duration = Duration::from_secs(60);
+
+/// Time given to the processes to exit after `SIGTERM`, before `SIGKILL`.
+const TERMINATION_GRACE: Duration = Duration::from_secs(2);
+
+const POWER_OFF_SIGNALS: [Signal; 3] = [Signal::SIGTERM, Signal::SIGUSR2, Signal::SIGPWR];
+
+struct Mount {
+    source: &'static str,
+    target: &'static str,
+    fstype: &'static str,
+    flags: MsFlags,
+    data: Option<&'static str>,
+}
+
+const MOUNTS: [Mount; 8] = [
+    Mount {
+        source: "devtmpfs",
+        target: "/dev",
+        fstype: "devtmpfs",
+        flags: MsFlags::MS_NOSUID,
+        data: Some("mode=0755"),
+    },
+    Mount {
+        source: "proc",
+        target: "/proc",
+        fstype: "proc",
+        flags: MsFlags::MS_NOSUID
+            .union(MsFlags::MS_NODEV)
+            .union(MsFlags::MS_NOEXEC),
+        data: None,
+    },
+    Mount {
+        source: "sysfs",
+        target: "/sys",
+        fstype: "sysfs",
+        flags: MsFlags::MS_NOSUID
+            .union(MsFlags::MS_NODEV)
+            .union(MsFlags::MS_NOEXEC),
+        data: None,
+    },
+    Mount {
+        source: "cgroup2",
+        target: "/sys/fs/cgroup",
+        fstype: "cgroup2",
+        flags: MsFlags::MS_NOSUID
+            .union(MsFlags::MS_NODEV)
+            .union(MsFlags::MS_NOEXEC),
+        data: None,
+    },
+    Mount {
+        source: "devpts",
+        target: "/dev/pts",
+        fstype: "devpts",
+        flags: MsFlags::MS_NOSUID.union(MsFlags::MS_NOEXEC),
+        data: Some("gid=5,mode=620,ptmxmode=666"),
+    },
+    Mount {
+        source: "tmpfs",
+        target: "/dev/shm",
+        fstype: "tmpfs",
+        flags: MsFlags::MS_NOSUID.union(MsFlags::MS_NODEV),
+        data: Some("mode=1777"),
+    },
+    Mount {
+        source: "tmpfs",
+        target: "/run",
+        fstype: "tmpfs",
+        flags: MsFlags::MS_NOSUID.union(MsFlags::MS_NODEV),
+        data: Some("mode=0755"),
+    },
+    Mount {
+        source: "tmpfs",
+        target: "/tmp",
+        fstype: "tmpfs",
+        flags: MsFlags::MS_NOSUID.union(MsFlags::MS_NODEV),
+        data: Some("mode=1777"),
+    },
+];
+
+fn main() {
+    if process::id() != 1 {
+        eprintln!("init: must run as the first process of the guest");
+        process::exit(1);
+    }
+
+    // Blocked first, so that none is missed: they are waited for with `sigtimedwait`.
+    let signals = handled_signals();
+    if let Err(e) = signals.thread_block() {
+        println!("init: could not block the signals: {}", e);
+    }
+
+    mount_filesystems();
+
+    let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    let ip_config = net::IpConfig::from_cmdline(&cmdline);
+    let hostname = ip_config
+        .as_ref()
+        .and_then(|config| config.hostname.as_deref())
+        .unwrap_or(DEFAULT_HOSTNAME);
+    if let Err(e) = sethostname(hostname) {
+        println!("init: could not set the hostname: {}", e);
+    }
+    if let Err(e) = net::configure(ip_config.as_ref()) {
+        println!("init: could not configure the network: {}", e);
+    }
+
+    supervise_agent(&signals, agent_port(&cmdline));
+    power_off(&signals);
+}
+
+fn agent_port(cmdline: &str) -> Option<String> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|param| param.strip_prefix(AGENT_PORT_PARAMETER))
+        .last()
+        .map(str::to_string)
+}
+
+fn image_env() -> Vec<(String, String)> {
+    fs::read_to_string(IMAGE_ENV)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+fn handled_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGCHLD);
+    for signal in POWER_OFF_SIGNALS {
+        signals.add(signal);
+    }
+    signals
+}
+
+fn mount_filesystems() {
+    for Mount {
+        source,
+        target,
+        fstype,
+        flags,
+        data,
+    } in MOUNTS
+    {
+        if let Err(e) = fs::create_dir_all(target) {
+            println!("init: could not create {}: {}", target, e);
+            continue;
+        }
+        match mount(Some(source), target, Some(fstype), flags, data) {
+            // Already mounted, by the kernel when it boots from a disk.
+            Ok(()) | Err(Errno::EBUSY) => {}
+            Err(e) => println!("init: could not mount {}: {}", target, e),
+        }
+    }
+}
+
+/// Wait for the next of the blocked `signals`, for at most `timeout` if any.
+fn next_signal(signals: &SigSet, timeout: Option<Duration>) -> Option<Signal> {
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timeout = timeout
+        .as_ref()
+        .map_or(std::ptr::null(), |timeout| timeout as *const _);
+    // SAFETY: the set and the timeout live until the call returns.
+    let signal = unsafe { libc::sigtimedwait(signals.as_ref(), std::ptr::null_mut(), timeout) };
+    Signal::try_from(signal).ok()
+}
+
+/// Reap the exited children, returning the status of `agent` if it is one of them.
+fn reap(agent: Option<Pid>) -> Option<WaitStatus> {
+    let mut agent_status = None;
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(_) => return agent_status,
+            Ok(status) if agent.is_some() && status.pid() == agent => agent_status = Some(status),
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Wait for `timeout`, reaping the children meanwhile. Returns `false` if the init is told to
+/// power off.
+fn wait(signals: &SigSet, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        match next_signal(signals, Some(deadline - now)) {
+            Some(signal) if POWER_OFF_SIGNALS.contains(&signal) => {
+                println!("init: {} received, powering off", signal);
+                return false;
+            }
+            _ => {
+                reap(None);
+            }
+        }
+    }
+}
+
+/// Run the agent until it exits cleanly or the init is told to power off.
+fn supervise_agent(signals: &SigSet, port: Option<String>) {
+    let mut env = image_env();
+    env.extend(port.map(|port| ("GRPC_SERVER_PORT".to_string(), port)));
+    let mut crashes: VecDeque<Instant> = VecDeque::new();
+    loop {
+        // The spawned process starts with no signal blocked.
+        let agent = match Command::new(AGENT)
+            .envs(AGENT_ENV)
+            .envs(env.clone())
+            .spawn()
+        {
+            Ok(agent) => Pid::from_raw(agent.id() as i32),
+            Err(e) => {
+                println!("init: could not start {}: {}", AGENT, e);
+                return;
+            }
+        };
+
+        let status = loop {
+            if let Some(status) = reap(Some(agent)) {
+                break status;
+            }
+            if let Some(signal) = next_signal(signals, None) {
+                if POWER_OFF_SIGNALS.contains(&signal) {
+                    println!("init: {} received, powering off", signal);
+                    return;
+                }
+            }
+        };
+        match status {
+            WaitStatus::Exited(_, 0) => {
+                println!("init: the agent exited, powering off");
+                return;
+            }
+            WaitStatus::Exited(_, code) => println!("init: the agent exited with {}", code),
+            WaitStatus::Signaled(_, signal, _) => {
+                println!("init: the agent was killed by {}", signal)
+            }
+            status => println!("init: the agent stopped: {:?}", status),
+        }
+
+        let now = Instant::now();
+        crashes.retain(|crash| now.duration_since(*crash) < CRASH_WINDOW);
+        crashes.push_back(now);
+        if crashes.len() >= MAX_CRASHES {
+            println!(
+                "init: the agent crashed {} times in {:?}, powering off",
+                crashes.len(),
+                CRASH_WINDOW
+            );
+            return;
+        }
+        let delay = RESTART_DELAY
+            .saturating_mul(1 << (crashes.len() - 1))
+            .min(MAX_RESTART_DELAY);
+        println!("init: restarting the agent in {:?}", delay);
+        if !wait(signals, delay) {
+            return;
+        }
+    }
+}
+
+/// Stop every process, write the pending data and unmount the filesystems, then power off the
+/// VM.
+fn power_off(signals: &SigSet) {
+    // `-1` is every process but the init.
+    let everyone = Pid::from_raw(-1);
+    if kill(everyone, Signal::SIGTERM).is_ok() {
+        let deadline = Instant::now() + TERMINATION_GRACE;
+        while Instant::now() < deadline {
+            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Err(Errno::ECHILD) => break,
+                Ok(WaitStatus::StillAlive) => {
+                    next_signal(signals, Some(Duration::from_millis(50)));
+                }
+                _ => {}
+            }
+        }
+        let _ = kill(everyone, Signal::SIGKILL);
+        reap(None);
+    }
+
+    // SAFETY: `sync` has no precondition.
+    unsafe { libc::sync() };
+    for Mount { target, .. } in MOUNTS.into_iter().rev() {
+        let _ = umount2(target, MntFlags::MNT_DETACH);
+    }
+    let Err(e) = reboot(RebootMode::RB_POWER_OFF);
+    // The kernel panics when the init exits, which stops the VM given `panic=1`.
+    println!("init: could not power off: {}", e);
+    process::exit(1);
+}
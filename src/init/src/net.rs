@@ -0,0 +1,257 @@
+//! Network of the guest, from the `ip=` parameter of the kernel command line.
+//!
+//! A kernel built with `CONFIG_IP_PNP` configures the interface itself and reports it in
+//! `/proc/net/pnp`. Otherwise the address, the netmask and the default route are set here.
+
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+/// Written by the kernel when it configured the interface.
+const KERNEL_CONFIG: &str = "/proc/net/pnp";
+
+// From <linux/route.h>.
+const RTF_UP: libc::c_ushort = 0x0001;
+const RTF_GATEWAY: libc::c_ushort = 0x0002;
+
+/// `ioctl` on a socket, whose type of request depends on the libc.
+macro_rules! ioctl {
+    ($socket:expr, $request:expr, $argument:expr) => {
+        check(libc::ioctl($socket.as_raw_fd(), $request as _, $argument))
+    };
+}
+
+/// The `ip=<client>:<server>:<gateway>:<netmask>:<hostname>:<device>:<autoconf>:<dns0>:<dns1>`
+/// parameter, as given by the VMM.
+#[derive(Debug, Default, PartialEq)]
+pub struct IpConfig {
+    pub address: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub netmask: Option<Ipv4Addr>,
+    pub hostname: Option<String>,
+    pub device: Option<String>,
+    pub dns: Vec<Ipv4Addr>,
+}
+
+impl IpConfig {
+    /// The config given by the `ip=` parameter of `cmdline`, `None` if there is none.
+    pub fn from_cmdline(cmdline: &str) -> Option<Self> {
+        let value = cmdline
+            .split_whitespace()
+            .filter_map(|param| param.strip_prefix("ip="))
+            .last()?;
+        let fields: Vec<&str> = value.split(':').collect();
+        let field = |index: usize| fields.get(index).copied().filter(|field| !field.is_empty());
+        let address = |index: usize| field(index).and_then(|field| field.parse().ok());
+
+        Some(Self {
+            address: address(0),
+            gateway: address(2),
+            netmask: address(3),
+            hostname: field(4).map(str::to_string),
+            device: field(5).map(str::to_string),
+            dns: [7, 8].into_iter().filter_map(address).collect(),
+        })
+    }
+}
+
+/// Bring up the loopback and the interface described by `config`, and write the resolver config.
+pub fn configure(config: Option<&IpConfig>) -> io::Result<()> {
+    let socket = socket()?;
+    set_up(&socket, "lo")?;
+
+    let Some(config) = config else {
+        return Ok(());
+    };
+    if !Path::new(KERNEL_CONFIG).exists() {
+        if let (Some(device), Some(address)) = (&config.device, config.address) {
+            let request = IfReq::address(device, address)?;
+            // SAFETY: `request` is a valid `ifreq` holding an address.
+            let result = unsafe { ioctl!(socket, libc::SIOCSIFADDR, &request) };
+            result.map_err(|e| {
+                io::Error::new(e.kind(), format!("Could not set {}: {}", address, e))
+            })?;
+            if let Some(netmask) = config.netmask {
+                let request = IfReq::address(device, netmask)?;
+                // SAFETY: `request` is a valid `ifreq` holding an address.
+                let result = unsafe { ioctl!(socket, libc::SIOCSIFNETMASK, &request) };
+                result.map_err(|e| {
+                    io::Error::new(e.kind(), format!("Could not set {}: {}", netmask, e))
+                })?;
+            }
+            set_up(&socket, device)?;
+            if let Some(gateway) = config.gateway {
+                add_default_route(&socket, device, gateway)?;
+            }
+        }
+    }
+
+    if !config.dns.is_empty() {
+        let resolv_conf: String = config
+            .dns
+            .iter()
+            .map(|dns| format!("nameserver {}\n", dns))
+            .collect();
+        fs::create_dir_all("/etc")?;
+        // The image may ship a link to a resolver it doesn't run.
+        let _ = fs::remove_file("/etc/resolv.conf");
+        fs::write("/etc/resolv.conf", resolv_conf)?;
+    }
+    Ok(())
+}
+
+/// `struct ifreq`, with the members of its union used here.
+#[repr(C)]
+#[allow(dead_code)] // Read by the kernel.
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    data: IfReqData,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+union IfReqData {
+    address: libc::sockaddr_in,
+    flags: libc::c_short,
+    _size: [u8; 24],
+}
+
+impl IfReq {
+    fn new(device: &str, data: IfReqData) -> io::Result<Self> {
+        if device.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid interface name {}", device),
+            ));
+        }
+        let mut name = [0; libc::IFNAMSIZ];
+        for (dst, src) in name.iter_mut().zip(device.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        Ok(Self { name, data })
+    }
+
+    fn address(device: &str, address: Ipv4Addr) -> io::Result<Self> {
+        Self::new(
+            device,
+            IfReqData {
+                address: sockaddr(address),
+            },
+        )
+    }
+}
+
+/// `struct rtentry`.
+#[repr(C)]
+#[allow(dead_code)] // Read by the kernel.
+struct RtEntry {
+    pad1: libc::c_ulong,
+    destination: libc::sockaddr_in,
+    gateway: libc::sockaddr_in,
+    genmask: libc::sockaddr_in,
+    flags: libc::c_ushort,
+    pad2: libc::c_short,
+    pad3: libc::c_ulong,
+    pad4: *mut libc::c_void,
+    metric: libc::c_short,
+    device: *mut libc::c_char,
+    mtu: libc::c_ulong,
+    window: libc::c_ulong,
+    irtt: libc::c_ushort,
+}
+
+fn sockaddr(address: Ipv4Addr) -> libc::sockaddr_in {
+    libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from_ne_bytes(address.octets()),
+        },
+        sin_zero: [0; 8],
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Socket through which the interfaces are configured.
+fn socket() -> io::Result<OwnedFd> {
+    // SAFETY: the returned descriptor is checked, then owned.
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        check(fd)?;
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+fn set_up(socket: &OwnedFd, device: &str) -> io::Result<()> {
+    let mut request = IfReq::new(device, IfReqData { flags: 0 })?;
+    // SAFETY: `request` is a valid `ifreq`, whose flags are read then written by the kernel.
+    let result = unsafe {
+        ioctl!(socket, libc::SIOCGIFFLAGS, &mut request).and_then(|()| {
+            request.data.flags |= libc::IFF_UP as libc::c_short;
+            ioctl!(socket, libc::SIOCSIFFLAGS, &request)
+        })
+    };
+    result.map_err(|e| io::Error::new(e.kind(), format!("Could not bring up {}: {}", device, e)))
+}
+
+fn add_default_route(socket: &OwnedFd, device: &str, gateway: Ipv4Addr) -> io::Result<()> {
+    let mut device_name: Vec<libc::c_char> =
+        device.bytes().map(|byte| byte as libc::c_char).collect();
+    device_name.push(0);
+    // SAFETY: every member of `rtentry` is valid zeroed.
+    let mut route: RtEntry = unsafe { std::mem::zeroed() };
+    route.destination = sockaddr(Ipv4Addr::UNSPECIFIED);
+    route.genmask = sockaddr(Ipv4Addr::UNSPECIFIED);
+    route.gateway = sockaddr(gateway);
+    route.flags = RTF_UP | RTF_GATEWAY;
+    route.device = device_name.as_mut_ptr();
+    // SAFETY: `route` is a valid `rtentry`, its device name living until the call returns.
+    let result = unsafe { ioctl!(socket, libc::SIOCADDRT, &route) };
+    result.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Could not route through {}: {}", gateway, e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vmm_cmdline() {
+        let config = IpConfig::from_cmdline(
+            "console=ttyS0 reboot=k panic=1 ip=172.29.0.2::172.29.0.1:255.255.0.0::eth0:off:1.1.1.1",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            IpConfig {
+                address: Some(Ipv4Addr::new(172, 29, 0, 2)),
+                gateway: Some(Ipv4Addr::new(172, 29, 0, 1)),
+                netmask: Some(Ipv4Addr::new(255, 255, 0, 0)),
+                hostname: None,
+                device: Some("eth0".into()),
+                dns: vec![Ipv4Addr::new(1, 1, 1, 1)],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_hostname_and_missing_ip() {
+        let config =
+            IpConfig::from_cmdline("ip=10.0.0.2::10.0.0.1:255.0.0.0:worker:eth0:off").unwrap();
+        assert_eq!(config.hostname.as_deref(), Some("worker"));
+        assert!(config.dns.is_empty());
+        assert!(IpConfig::from_cmdline("console=ttyS0 quiet").is_none());
+    }
+}
@@ -0,0 +1,275 @@
+//! Translation of an OCI bundle, as prepared by containerd, into a Cloudlet workload.
+//!
+//! The language and the code of the workload are given by annotations, or guessed from the
+//! process of the container: `python3 /app/main.py` runs `/app/main.py` of the container rootfs
+//! with the Python image.
+
+use clap::ValueEnum;
+use cloudlet_spec::{validate_workload_name, Action};
+use serde::Deserialize;
+use shared_models::{BuildConfig, CloudletDtoRequest, Language, LogLevel, Resources, ServerConfig};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Language of the workload, `python`, `node` or `rust`.
+pub const LANGUAGE_ANNOTATION: &str = "io.cloudlet.language";
+/// Path of the code of the workload, in the container rootfs.
+pub const CODE_ANNOTATION: &str = "io.cloudlet.code";
+/// Priority class of the guest, among the classes of the VMM scheduler.
+pub const PRIORITY_CLASS_ANNOTATION: &str = "io.cloudlet.priority-class";
+/// Prefix of the annotations labeling the run, e.g. `io.cloudlet.label/team: infra`.
+pub const LABEL_ANNOTATION_PREFIX: &str = "io.cloudlet.label/";
+/// Set by the CRI plugin of containerd on the pause container of each pod.
+const CRI_CONTAINER_TYPE_ANNOTATION: &str = "io.kubernetes.cri.container-type";
+
+/// Address of the VMM, as seen by the API.
+const SERVER_ADDRESS: &str = "localhost";
+const SERVER_PORT: u16 = 50051;
+
+#[derive(Debug)]
+pub enum BundleError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, serde_json::Error),
+    Unsupported(String),
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::Read(path, e) => write!(f, "Failed to read {:?}: {}", path, e),
+            BundleError::Parse(path, e) => write!(f, "Invalid OCI configuration {:?}: {}", path, e),
+            BundleError::Unsupported(message) => write!(f, "Unsupported container: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+/// The parts of the OCI runtime configuration (`config.json`) mapped to a workload.
+#[derive(Debug, Deserialize)]
+struct OciConfig {
+    process: Option<Process>,
+    root: Option<Root>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+    linux: Option<Linux>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Process {
+    #[serde(default)]
+    terminal: bool,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Root {
+    path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+struct Linux {
+    resources: Option<LinuxResources>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LinuxResources {
+    memory: Option<MemoryResources>,
+    cpu: Option<CpuResources>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MemoryResources {
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CpuResources {
+    quota: Option<i64>,
+    period: Option<u64>,
+}
+
+/// What a container runs.
+#[derive(Debug)]
+pub enum Workload {
+    /// The pause container of a pod, which only holds its namespaces: nothing to run in a VM.
+    Sandbox,
+    Run(CloudletDtoRequest),
+}
+
+/// Workload of the container `id`, from its bundle directory.
+pub fn load(bundle: &Path, id: &str) -> Result<Workload, BundleError> {
+    let path = bundle.join("config.json");
+    let content = fs::read_to_string(&path).map_err(|e| BundleError::Read(path.clone(), e))?;
+    let config: OciConfig =
+        serde_json::from_str(&content).map_err(|e| BundleError::Parse(path, e))?;
+
+    if config
+        .annotations
+        .get(CRI_CONTAINER_TYPE_ANNOTATION)
+        .is_some_and(|container_type| container_type == "sandbox")
+    {
+        return Ok(Workload::Sandbox);
+    }
+
+    let process = config
+        .process
+        .ok_or_else(|| BundleError::Unsupported("the container has no process".into()))?;
+    if process.terminal {
+        return Err(BundleError::Unsupported(
+            "workloads have no terminal, run the container without -t".into(),
+        ));
+    }
+
+    let language = match config.annotations.get(LANGUAGE_ANNOTATION) {
+        Some(language) => Language::from_str(language, true).map_err(|_| {
+            BundleError::Unsupported(format!(
+                "unknown language {} in {}",
+                language, LANGUAGE_ANNOTATION
+            ))
+        })?,
+        None => guess_language(&process.args)?,
+    };
+    let code_path = match config.annotations.get(CODE_ANNOTATION) {
+        Some(code_path) => PathBuf::from(code_path),
+        None => process.args.get(1).map(PathBuf::from).ok_or_else(|| {
+            BundleError::Unsupported(format!(
+                "the process doesn't name its code, set the {} annotation",
+                CODE_ANNOTATION
+            ))
+        })?,
+    };
+
+    let rootfs = match config.root {
+        Some(root) if root.path.is_absolute() => root.path,
+        Some(root) => bundle.join(root.path),
+        None => bundle.join("rootfs"),
+    };
+    let source_code_path = rootfs.join(code_path.strip_prefix("/").unwrap_or(code_path.as_path()));
+    let code = fs::read_to_string(&source_code_path)
+        .map_err(|e| BundleError::Read(source_code_path.clone(), e))?;
+
+    // PATH and HOME are those of the container image, not of the guest.
+    let env: BTreeMap<String, String> = process
+        .env
+        .iter()
+        .filter_map(|variable| variable.split_once('='))
+        .filter(|(name, _)| !matches!(*name, "PATH" | "HOME"))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+    Ok(Workload::Run(CloudletDtoRequest {
+        workload_name: workload_name(id),
+        language,
+        code,
+        log_level: LogLevel::INFO,
+        action: Action::PrepareAndRun.to_string(),
+        server: ServerConfig {
+            address: SERVER_ADDRESS.into(),
+            port: SERVER_PORT,
+        },
+        build: BuildConfig {
+            source_code_path,
+            release: true,
+            features: Vec::new(),
+            compiler_flags: Vec::new(),
+            runtime_version: None,
+        },
+        resources: resources(config.linux.and_then(|linux| linux.resources)),
+        devices: Vec::new(),
+        capabilities: Vec::new(),
+        node_affinity: Default::default(),
+        kernel: None,
+        priority_class: config.annotations.get(PRIORITY_CLASS_ANNOTATION).cloned(),
+        tenant: None,
+        env,
+        secret_env: Vec::new(),
+        stages: Vec::new(),
+        webhooks: Vec::new(),
+        keep_on_failure: false,
+        timeout_secs: None,
+        language_detection: None,
+        labels: config
+            .annotations
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix(LABEL_ANNOTATION_PREFIX)?;
+                Some((key.to_string(), value.clone()))
+            })
+            .collect(),
+    }))
+}
+
+/// Language of a process running an interpreter on a script.
+fn guess_language(args: &[String]) -> Result<Language, BundleError> {
+    let interpreter = args
+        .first()
+        .and_then(|program| Path::new(program).file_name())
+        .and_then(|program| program.to_str())
+        .unwrap_or_default();
+
+    if interpreter.starts_with("python") {
+        Ok(Language::PYTHON)
+    } else if interpreter == "node" || interpreter == "nodejs" {
+        Ok(Language::NODE)
+    } else {
+        Err(BundleError::Unsupported(format!(
+            "can't tell the language of `{}`, set the {} and {} annotations",
+            args.join(" "),
+            LANGUAGE_ANNOTATION,
+            CODE_ANNOTATION
+        )))
+    }
+}
+
+/// Workload name of the container `id`, which containerd lets be anything.
+pub fn workload_name(id: &str) -> String {
+    if validate_workload_name(id).is_none() {
+        return id.to_string();
+    }
+
+    let mut name: String = id
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '-' | '_' => c,
+            _ => '-',
+        })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_lowercase()) {
+        name.insert_str(0, "c-");
+    }
+    name.truncate(64);
+    name
+}
+
+/// Resources of the guest, from the cgroup limits of the container.
+fn resources(limits: Option<LinuxResources>) -> Resources {
+    let mut resources = Resources::default();
+    let Some(limits) = limits else {
+        return resources;
+    };
+
+    if let Some(limit) = limits.memory.and_then(|memory| memory.limit) {
+        if limit > 0 {
+            resources.memory_mb = (limit as u64).div_ceil(1 << 20).min(u32::MAX as u64) as u32;
+        }
+    }
+    if let Some(CpuResources {
+        quota: Some(quota),
+        period: Some(period),
+    }) = limits.cpu
+    {
+        if quota > 0 && period > 0 {
+            resources.cpus = (quota as u64).div_ceil(period).clamp(1, u8::MAX as u64) as u8;
+        }
+    }
+
+    resources
+}
@@ -0,0 +1,377 @@
+//! OCI runtime running containers as Cloudlet workloads, with the command line of runc so that
+//! the runc shim of containerd (`io.containerd.runc.v2`, with `BinaryName` pointing to it) and
+//! nerdctl (`--runtime`) can use it.
+//!
+//! `create` spawns a monitor process, the init process of the container as far as containerd
+//! is concerned, which waits for `start` then runs the workload through the API, writes its
+//! output on the container stdio and exits with its exit code.
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bundle::Workload;
+use clap::{Parser, Subcommand};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use shared_models::{CloudletDtoRequest, CloudletErrorResponse};
+use state::{Container, ContainerState, Status};
+use std::{
+    error::Error,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{exit, Command},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+mod bundle;
+mod state;
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Directory of the state of the containers.
+    #[arg(long, default_value = "/run/cloudlet-runtime")]
+    root: PathBuf,
+
+    /// File to log the errors to, read by containerd when a command fails.
+    #[arg(long)]
+    log: Option<PathBuf>,
+
+    /// Format of the log file, `text` or `json`.
+    #[arg(long, default_value = "text")]
+    log_format: String,
+
+    /// URL of the Cloudlet API.
+    #[arg(
+        long,
+        env = "CLOUDLET_API_URL",
+        default_value = "http://127.0.0.1:3000"
+    )]
+    api_url: String,
+
+    /// Accepted for compatibility with runc, cgroups are left to the VMs.
+    #[arg(long = "systemd-cgroup", hide = true)]
+    _systemd_cgroup: bool,
+
+    /// Accepted for compatibility with runc.
+    #[arg(long = "debug", hide = true)]
+    _debug: bool,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Create a container from an OCI bundle, ready to be started.
+    Create {
+        /// Directory of the bundle.
+        #[arg(short, long, default_value = ".")]
+        bundle: PathBuf,
+        /// File to write the pid of the container process to.
+        #[arg(long)]
+        pid_file: Option<PathBuf>,
+        /// Accepted for compatibility with runc, workloads have no terminal.
+        #[arg(long = "console-socket")]
+        _console_socket: Option<PathBuf>,
+        #[arg(long = "no-pivot", hide = true)]
+        _no_pivot: bool,
+        #[arg(long = "no-new-keyring", hide = true)]
+        _no_new_keyring: bool,
+        #[arg(long = "preserve-fds", hide = true, default_value_t = 0)]
+        _preserve_fds: u32,
+        id: String,
+    },
+    /// Run the workload of a created container.
+    Start { id: String },
+    /// Print the state of a container as JSON.
+    State { id: String },
+    /// Send a signal to a container, SIGTERM and SIGKILL also shut its VM down.
+    Kill {
+        /// Accepted for compatibility with runc, a container has a single process.
+        #[arg(short = 'a', long = "all")]
+        _all: bool,
+        id: String,
+        #[arg(default_value = "SIGTERM")]
+        signal: String,
+    },
+    /// Delete a stopped container, or any container with `--force`.
+    Delete {
+        #[arg(short, long)]
+        force: bool,
+        id: String,
+    },
+    /// Print the processes of a container.
+    Ps {
+        #[arg(short, long, default_value = "table")]
+        format: String,
+        id: String,
+    },
+    /// Process of a container, started by `create`.
+    #[command(hide = true)]
+    Monitor { id: String },
+    #[command(external_subcommand)]
+    Unsupported(Vec<String>),
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if let Err(e) = run(&args) {
+        log_error(&args, &e.to_string());
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Box<dyn Error>> {
+    match &args.command {
+        Commands::Create {
+            bundle,
+            pid_file,
+            id,
+            ..
+        } => {
+            let bundle = bundle.canonicalize()?;
+            let workload = bundle::load(&bundle, id)?;
+
+            let container = Container::new(&args.root, id);
+            container
+                .create()
+                .map_err(|e| format!("Could not create container {}: {}", id, e))?;
+            let workload_name = match &workload {
+                Workload::Run(request) => {
+                    container.save_request(request)?;
+                    Some(request.workload_name.clone())
+                }
+                Workload::Sandbox => None,
+            };
+
+            // The monitor inherits the stdio of the container.
+            let monitor = Command::new(std::env::current_exe()?)
+                .arg("--root")
+                .arg(&args.root)
+                .arg("--api-url")
+                .arg(&args.api_url)
+                .arg("monitor")
+                .arg(id)
+                .spawn()?;
+            let pid = monitor.id() as i32;
+            container.save(&ContainerState {
+                id: id.clone(),
+                bundle,
+                pid,
+                workload_name,
+            })?;
+            if let Some(pid_file) = pid_file {
+                std::fs::write(pid_file, pid.to_string())?;
+            }
+        }
+        Commands::Start { id } => {
+            let container = Container::new(&args.root, id);
+            let state = container.load()?;
+            if container.status(&state) != Status::Created {
+                return Err(format!("Container {} is not created", id).into());
+            }
+            container.start()?;
+        }
+        Commands::State { id } => {
+            let container = Container::new(&args.root, id);
+            let state = container.load()?;
+            let status = container.status(&state);
+            println!(
+                "{}",
+                serde_json::json!({
+                    "ociVersion": "1.0.2",
+                    "id": state.id,
+                    "status": status,
+                    "pid": if status == Status::Stopped { 0 } else { state.pid },
+                    "bundle": state.bundle,
+                })
+            );
+        }
+        Commands::Kill { id, signal, .. } => {
+            let container = Container::new(&args.root, id);
+            let state = container.load()?;
+            let signal = parse_signal(signal)?;
+            if container.status(&state) == Status::Stopped {
+                return Err(format!("Container {} is not running", id).into());
+            }
+
+            if matches!(signal, Signal::SIGTERM | Signal::SIGKILL) {
+                if let Some(workload_name) = &state.workload_name {
+                    shutdown(&args.api_url, workload_name);
+                }
+            }
+            kill(Pid::from_raw(state.pid), signal)?;
+        }
+        Commands::Delete { force, id } => {
+            let container = Container::new(&args.root, id);
+            let state = container.load()?;
+            if container.status(&state) != Status::Stopped {
+                if !force {
+                    return Err(format!("Container {} is still running", id).into());
+                }
+                if let Some(workload_name) = &state.workload_name {
+                    shutdown(&args.api_url, workload_name);
+                }
+                let _ = kill(Pid::from_raw(state.pid), Signal::SIGKILL);
+            }
+            container.remove()?;
+        }
+        Commands::Ps { format, id } => {
+            let container = Container::new(&args.root, id);
+            let state = container.load()?;
+            let pids: Vec<i32> = match container.status(&state) {
+                Status::Stopped => Vec::new(),
+                _ => vec![state.pid],
+            };
+            if format == "json" {
+                println!("{}", serde_json::to_string(&pids)?);
+            } else {
+                println!("PID");
+                for pid in pids {
+                    println!("{}", pid);
+                }
+            }
+        }
+        Commands::Monitor { id } => {
+            let container = Container::new(&args.root, id);
+            let request = container.load_request()?;
+            container.wait_start()?;
+
+            match request {
+                Some(request) => exit(run_workload(&args.api_url, &request)?),
+                // A sandbox only has to stay alive until it's killed.
+                None => loop {
+                    nix::unistd::pause();
+                },
+            }
+        }
+        Commands::Unsupported(command) => {
+            return Err(format!(
+                "{} is not supported, Cloudlet workloads only get a single process",
+                command.first().map(String::as_str).unwrap_or_default()
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Event streamed by the API while a workload runs.
+#[derive(Debug, Deserialize)]
+struct RunEvent {
+    stage: String,
+    stdout: Option<String>,
+    stderr: Option<String>,
+    #[serde(default)]
+    encoding: String,
+    exit_code: Option<i32>,
+    #[serde(default)]
+    error: Option<CloudletErrorResponse>,
+}
+
+impl RunEvent {
+    fn decode(&self, output: &Option<String>) -> Option<Vec<u8>> {
+        let output = output.as_ref()?;
+        match self.encoding.as_str() {
+            "base64" => BASE64_STANDARD.decode(output).ok(),
+            _ => Some(output.clone().into_bytes()),
+        }
+    }
+}
+
+/// Run `request` through the API, writing its output on the stdio. Returns its exit code.
+fn run_workload(api_url: &str, request: &CloudletDtoRequest) -> Result<i32, Box<dyn Error>> {
+    let response = Client::builder()
+        .timeout(None)
+        .build()?
+        .post(format!("{}/run", api_url))
+        .json(request)
+        .send()?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text()?;
+        return Err(match serde_json::from_str::<CloudletErrorResponse>(&body) {
+            Ok(error) => error.into(),
+            Err(_) => format!("{}: {}", status, body).into(),
+        });
+    }
+
+    let mut exit_code = 0;
+    for line in BufReader::new(response).lines() {
+        let line = line?;
+        let Some(data) = line.trim_end().strip_prefix("data: ") else {
+            continue;
+        };
+        let Ok(event) = serde_json::from_str::<RunEvent>(data) else {
+            continue;
+        };
+
+        if let Some(stdout) = event.decode(&event.stdout) {
+            std::io::stdout().write_all(&stdout)?;
+        }
+        if let Some(stderr) = event.decode(&event.stderr) {
+            std::io::stderr().write_all(&stderr)?;
+        }
+        if let Some(error) = &event.error {
+            eprintln!("{}", error);
+        }
+        match event.stage.as_str() {
+            "Done" => exit_code = event.exit_code.unwrap_or_default(),
+            "Failed" => exit_code = event.exit_code.filter(|code| *code != 0).unwrap_or(1),
+            _ => {}
+        }
+    }
+
+    Ok(exit_code)
+}
+
+/// Shut the VM of `workload_name` down, if it's still running.
+fn shutdown(api_url: &str, workload_name: &str) {
+    let _ = Client::new()
+        .post(format!("{}/shutdown", api_url))
+        .json(&serde_json::json!({ "id": workload_name }))
+        .send();
+}
+
+/// Signal named like `SIGTERM`, `TERM` or `15`.
+fn parse_signal(signal: &str) -> Result<Signal, Box<dyn Error>> {
+    if let Ok(number) = signal.parse::<i32>() {
+        return Ok(Signal::try_from(number)?);
+    }
+
+    let name = signal.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Ok(name.parse()?)
+}
+
+/// Append `message` to the log file, where the runc shim looks for the errors.
+fn log_error(args: &Args, message: &str) {
+    let Some(path) = &args.log else {
+        return;
+    };
+    let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) else {
+        return;
+    };
+
+    let line = if args.log_format == "json" {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        serde_json::json!({ "level": "error", "msg": message, "time": time }).to_string()
+    } else {
+        format!("level=error msg={:?}", message)
+    };
+    let _ = writeln!(file, "{}", line);
+}
@@ -0,0 +1,112 @@
+//! State of the containers, kept in `<root>/<id>/`:
+//! * `state.json`, the [`ContainerState`];
+//! * `request.json`, the request of the workload, only readable by root as it holds its env;
+//! * `start.fifo`, which the monitor of the container waits on until `start` opens it;
+//! * `started`, created by `start`.
+
+use nix::{sys::signal::kill, sys::stat::Mode, unistd::Pid};
+use serde::{Deserialize, Serialize};
+use shared_models::CloudletDtoRequest;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContainerState {
+    pub id: String,
+    pub bundle: PathBuf,
+    /// The monitor process, seen by containerd as the init process of the container.
+    pub pid: i32,
+    /// Workload run by the container, `None` for a pod sandbox.
+    pub workload_name: Option<String>,
+}
+
+/// Status of a container, as defined by the OCI runtime specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Created,
+    Running,
+    Stopped,
+}
+
+pub struct Container {
+    dir: PathBuf,
+}
+
+impl Container {
+    pub fn new(root: &Path, id: &str) -> Self {
+        Self { dir: root.join(id) }
+    }
+
+    /// Create the state of a new container, failing if the id is taken.
+    pub fn create(&self) -> io::Result<()> {
+        fs::create_dir_all(self.dir.parent().unwrap_or(&self.dir))?;
+        fs::create_dir(&self.dir)?;
+        nix::unistd::mkfifo(&self.start_fifo(), Mode::S_IRUSR | Mode::S_IWUSR)
+            .map_err(io::Error::from)
+    }
+
+    pub fn load(&self) -> io::Result<ContainerState> {
+        let content = fs::read_to_string(self.dir.join("state.json"))?;
+        serde_json::from_str(&content).map_err(io::Error::other)
+    }
+
+    pub fn save(&self, state: &ContainerState) -> io::Result<()> {
+        fs::write(self.dir.join("state.json"), serde_json::to_vec(state)?)
+    }
+
+    pub fn save_request(&self, request: &CloudletDtoRequest) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(self.dir.join("request.json"))?;
+        file.write_all(&serde_json::to_vec(request)?)
+    }
+
+    /// Request of the workload, `None` for a pod sandbox.
+    pub fn load_request(&self) -> io::Result<Option<CloudletDtoRequest>> {
+        match fs::read_to_string(self.dir.join("request.json")) {
+            Ok(content) => serde_json::from_str(&content)
+                .map(Some)
+                .map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn start_fifo(&self) -> PathBuf {
+        self.dir.join("start.fifo")
+    }
+
+    /// Block until the container is started.
+    pub fn wait_start(&self) -> io::Result<()> {
+        let mut fifo = File::open(self.start_fifo())?;
+        fifo.read_exact(&mut [0])
+    }
+
+    /// Let the monitor of the container run the workload.
+    pub fn start(&self) -> io::Result<()> {
+        File::create(self.dir.join("started"))?;
+        let mut fifo = OpenOptions::new().write(true).open(self.start_fifo())?;
+        fifo.write_all(&[0])
+    }
+
+    pub fn status(&self, state: &ContainerState) -> Status {
+        if kill(Pid::from_raw(state.pid), None).is_err() {
+            Status::Stopped
+        } else if self.dir.join("started").exists() {
+            Status::Running
+        } else {
+            Status::Created
+        }
+    }
+
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_dir_all(&self.dir)
+    }
+}
@@ -0,0 +1,487 @@
+//! The subset of OpenAPI 3 the clients are generated from: JSON request bodies, JSON or
+//! server-sent event responses, path, query and header parameters, and object schemas whose
+//! properties are scalars, arrays, maps, enums of strings or references to other schemas.
+//!
+//! Two extensions describe what OpenAPI can't:
+//! - `x-cloudlet-idempotent: true` on an operation: it is sent with an `Idempotency-Key`, and
+//!   so retried like the reads.
+//! - `x-cloudlet-default: <value>` on a parameter: its value when the caller doesn't give one.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_yaml::{Mapping, Value};
+use std::{fs, path::Path};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// One of these strings.
+    Enum(Vec<String>),
+    Array(Box<Type>),
+    /// Object of arbitrary keys, with values of this type.
+    Map(Box<Type>),
+    /// Schema of `components.schemas`, by name.
+    Ref(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Path,
+    Query,
+    Header,
+}
+
+#[derive(Debug, Clone)]
+pub struct Parameter {
+    pub name: String,
+    pub location: Location,
+    pub required: bool,
+    pub ty: Type,
+    pub description: String,
+    /// Value sent when the caller doesn't give one.
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Response {
+    Json(Type),
+    /// Server-sent events, each a JSON value of this type.
+    Events(Type),
+}
+
+#[derive(Debug, Clone)]
+pub struct Operation {
+    /// `operationId`, in camel case.
+    pub id: String,
+    pub method: Method,
+    /// Path, with `{name}` for the path parameters.
+    pub path: String,
+    pub summary: String,
+    pub parameters: Vec<Parameter>,
+    pub body: Option<Type>,
+    pub response: Response,
+    /// Example of the successful response, a list of the events for the event streams.
+    pub example: serde_json::Value,
+    pub idempotent: bool,
+}
+
+impl Operation {
+    /// Whether a failed attempt can be sent again without running the operation twice.
+    pub fn retryable(&self) -> bool {
+        self.method == Method::Get || self.idempotent
+    }
+
+    /// The parameters at `location`.
+    pub fn parameters(&self, location: Location) -> impl Iterator<Item = &Parameter> {
+        self.parameters
+            .iter()
+            .filter(move |parameter| parameter.location == location)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Property {
+    pub name: String,
+    pub ty: Type,
+    pub required: bool,
+    pub description: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Schema {
+    pub name: String,
+    pub description: String,
+    pub properties: Vec<Property>,
+}
+
+/// API described by an OpenAPI document.
+#[derive(Debug, Clone)]
+pub struct Definition {
+    pub title: String,
+    pub version: String,
+    pub description: String,
+    /// In the order of the document.
+    pub operations: Vec<Operation>,
+    pub schemas: Vec<Schema>,
+}
+
+fn get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    value.as_mapping()?.get(key)
+}
+
+fn string(value: &Value, key: &str) -> String {
+    get(value, key)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn mapping<'a>(value: &'a Value, key: &str) -> Result<&'a Mapping> {
+    get(value, key)
+        .and_then(Value::as_mapping)
+        .ok_or_else(|| anyhow!("`{}` is missing or isn't a mapping", key))
+}
+
+fn parse_type(schema: &Value) -> Result<Type> {
+    if let Some(reference) = get(schema, "$ref").and_then(Value::as_str) {
+        let name = reference
+            .strip_prefix("#/components/schemas/")
+            .ok_or_else(|| anyhow!("unsupported reference `{}`", reference))?;
+        return Ok(Type::Ref(name.to_string()));
+    }
+    if let Some(values) = get(schema, "enum").and_then(Value::as_sequence) {
+        let values = values
+            .iter()
+            .map(|value| {
+                value
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("only enums of strings are supported"))
+            })
+            .collect::<Result<_>>()?;
+        return Ok(Type::Enum(values));
+    }
+    match get(schema, "type").and_then(Value::as_str) {
+        Some("string") => Ok(Type::String),
+        Some("integer") => Ok(Type::Integer),
+        Some("number") => Ok(Type::Number),
+        Some("boolean") => Ok(Type::Boolean),
+        Some("array") => {
+            let items = get(schema, "items").context("an array has no `items`")?;
+            Ok(Type::Array(Box::new(parse_type(items)?)))
+        }
+        Some("object") => {
+            let values = get(schema, "additionalProperties")
+                .context("only the objects of `components.schemas` can have properties")?;
+            Ok(Type::Map(Box::new(parse_type(values)?)))
+        }
+        other => bail!("unsupported schema type {:?}", other),
+    }
+}
+
+fn parse_schema(name: &str, schema: &Value) -> Result<Schema> {
+    let required: Vec<&str> = get(schema, "required")
+        .and_then(Value::as_sequence)
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let mut properties = Vec::new();
+    if let Some(declared) = get(schema, "properties").and_then(Value::as_mapping) {
+        for (property, value) in declared {
+            let property = property
+                .as_str()
+                .ok_or_else(|| anyhow!("a property of {} isn't named by a string", name))?;
+            properties.push(Property {
+                name: property.to_string(),
+                ty: parse_type(value).with_context(|| format!("{}.{}", name, property))?,
+                required: required.contains(&property),
+                description: string(value, "description"),
+            });
+        }
+    }
+    Ok(Schema {
+        name: name.to_string(),
+        description: string(schema, "description"),
+        properties,
+    })
+}
+
+fn parse_parameter(parameter: &Value) -> Result<Parameter> {
+    let name = string(parameter, "name");
+    let location = match string(parameter, "in").as_str() {
+        "path" => Location::Path,
+        "query" => Location::Query,
+        "header" => Location::Header,
+        other => bail!("unsupported location `{}` of the parameter {}", other, name),
+    };
+    let ty = match get(parameter, "schema") {
+        Some(schema) => parse_type(schema).with_context(|| name.clone())?,
+        None => Type::String,
+    };
+    let default = get(parameter, "x-cloudlet-default").map(|value| match value {
+        Value::String(value) => value.clone(),
+        value => serde_yaml::to_string(value)
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+    });
+    Ok(Parameter {
+        required: location == Location::Path
+            || get(parameter, "required").and_then(Value::as_bool) == Some(true),
+        name,
+        location,
+        ty,
+        description: string(parameter, "description"),
+        default,
+    })
+}
+
+fn parse_operation(path: &str, method: Method, operation: &Value) -> Result<Operation> {
+    let id = string(operation, "operationId");
+    if id.is_empty() {
+        bail!("{} {} has no operationId", method.as_str(), path);
+    }
+
+    let parameters = get(operation, "parameters")
+        .and_then(Value::as_sequence)
+        .map(|parameters| {
+            parameters
+                .iter()
+                .map(parse_parameter)
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let body = match get(operation, "requestBody") {
+        Some(body) => {
+            let content = mapping(body, "content")?;
+            let schema = content
+                .get("application/json")
+                .and_then(|json| get(json, "schema"))
+                .ok_or_else(|| anyhow!("only JSON request bodies are supported"))?;
+            Some(parse_type(schema)?)
+        }
+        None => None,
+    };
+
+    let success = get(operation, "responses")
+        .and_then(|responses| get(responses, "200"))
+        .ok_or_else(|| anyhow!("no `200` response"))?;
+    let content = mapping(success, "content")?;
+    let (media_type, content) = content
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("the `200` response has no content"))?;
+    let schema = get(content, "schema").ok_or_else(|| anyhow!("the response has no schema"))?;
+    let response = match media_type.as_str() {
+        Some("application/json") => Response::Json(parse_type(schema)?),
+        Some("text/event-stream") => Response::Events(parse_type(schema)?),
+        other => bail!("unsupported response content {:?}", other),
+    };
+    let example = match get(content, "example") {
+        Some(example) => serde_json::to_value(example)?,
+        None => serde_json::Value::Null,
+    };
+
+    Ok(Operation {
+        id,
+        method,
+        path: path.to_string(),
+        summary: string(operation, "summary"),
+        parameters,
+        body,
+        response,
+        example,
+        idempotent: get(operation, "x-cloudlet-idempotent").and_then(Value::as_bool) == Some(true),
+    })
+}
+
+impl Definition {
+    pub fn load(path: &Path) -> Result<Self> {
+        let document = fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+        Self::parse(&document).with_context(|| format!("parsing {:?}", path))
+    }
+
+    pub fn parse(document: &str) -> Result<Self> {
+        let document: Value = serde_yaml::from_str(document)?;
+        let info = get(&document, "info").context("the document has no `info`")?;
+
+        let mut operations = Vec::new();
+        for (path, item) in mapping(&document, "paths")? {
+            let path = path.as_str().context("a path isn't a string")?;
+            for (method, name) in [
+                (Method::Get, "get"),
+                (Method::Post, "post"),
+                (Method::Put, "put"),
+                (Method::Delete, "delete"),
+            ] {
+                if let Some(operation) = get(item, name) {
+                    operations.push(
+                        parse_operation(path, method, operation)
+                            .with_context(|| format!("{} {}", method.as_str(), path))?,
+                    );
+                }
+            }
+        }
+
+        let schemas = match get(&document, "components") {
+            Some(components) => mapping(components, "schemas")?
+                .iter()
+                .map(|(name, schema)| {
+                    let name = name.as_str().context("a schema isn't named by a string")?;
+                    parse_schema(name, schema)
+                })
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+
+        let definition = Self {
+            title: string(info, "title"),
+            version: string(info, "version"),
+            description: string(info, "description"),
+            operations,
+            schemas,
+        };
+        definition.check()?;
+        Ok(definition)
+    }
+
+    /// Check that the references point to declared schemas, that the path parameters are in
+    /// the paths, and that the schemas the runtimes of the clients rely on are declared.
+    fn check(&self) -> Result<()> {
+        fn references<'a>(ty: &'a Type, found: &mut Vec<&'a str>) {
+            match ty {
+                Type::Ref(name) => found.push(name),
+                Type::Array(items) | Type::Map(items) => references(items, found),
+                _ => {}
+            }
+        }
+
+        let mut found = vec!["ErrorResponse", "RunEvent"];
+        for operation in &self.operations {
+            if let Some(body) = &operation.body {
+                references(body, &mut found);
+            }
+            match &operation.response {
+                Response::Json(ty) | Response::Events(ty) => references(ty, &mut found),
+            }
+            for parameter in operation.parameters(Location::Path) {
+                if !operation.path.contains(&format!("{{{}}}", parameter.name)) {
+                    bail!(
+                        "the path parameter {} isn't in the path {}",
+                        parameter.name,
+                        operation.path
+                    );
+                }
+            }
+        }
+        for schema in &self.schemas {
+            for property in &schema.properties {
+                references(&property.ty, &mut found);
+            }
+        }
+        for name in found {
+            if self.schema(name).is_none() {
+                bail!("the schema {} isn't declared", name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn schema(&self, name: &str) -> Option<&Schema> {
+        self.schemas.iter().find(|schema| schema.name == name)
+    }
+}
+
+/// `snake_case` of a camel case or kebab case name, e.g. `listRuns` or `Cloudlet-Queue`.
+pub fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c == '-' {
+            snake.push('_');
+        } else if c.is_ascii_uppercase() {
+            if i > 0 && !snake.ends_with('_') {
+                snake.push('_');
+            }
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+/// `camelCase` of a snake case or kebab case name.
+pub fn camel_case(name: &str) -> String {
+    let mut camel = String::new();
+    let mut upper = false;
+    for (i, c) in name.chars().enumerate() {
+        if c == '-' || c == '_' {
+            upper = i > 0;
+        } else if upper {
+            camel.push(c.to_ascii_uppercase());
+            upper = false;
+        } else if camel.is_empty() {
+            camel.push(c.to_ascii_lowercase());
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_definition() {
+        let definition = Definition::load(Path::new(crate::DEFINITION)).unwrap();
+        assert_eq!(definition.title, "Cloudlet API");
+
+        let run = definition
+            .operations
+            .iter()
+            .find(|operation| operation.id == "run")
+            .unwrap();
+        assert_eq!(run.method, Method::Post);
+        assert!(run.retryable());
+        assert!(matches!(&run.response, Response::Events(Type::Ref(name)) if name == "RunEvent"));
+        assert_eq!(
+            run.parameters(Location::Header).next().unwrap().default,
+            Some("never".to_string())
+        );
+
+        let invoke = definition
+            .operations
+            .iter()
+            .find(|operation| operation.id == "invokeWorkload")
+            .unwrap();
+        assert!(!invoke.retryable());
+        assert_eq!(invoke.parameters(Location::Path).count(), 1);
+
+        let request = definition.schema("RunRequest").unwrap();
+        let labels = request
+            .properties
+            .iter()
+            .find(|property| property.name == "labels")
+            .unwrap();
+        assert_eq!(labels.ty, Type::Map(Box::new(Type::String)));
+
+        assert!(Definition::parse(
+            "info: {title: t, version: '1'}\npaths:\n  /a:\n    get:\n      operationId: a\n      responses:\n        '200':\n          content:\n            application/json:\n              schema: {$ref: '#/components/schemas/Missing'}\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_case() {
+        assert_eq!(snake_case("listRuns"), "list_runs");
+        assert_eq!(snake_case("Cloudlet-Queue"), "cloudlet_queue");
+        assert_eq!(snake_case("tail_lines"), "tail_lines");
+        assert_eq!(camel_case("tail_lines"), "tailLines");
+        assert_eq!(camel_case("Cloudlet-Queue"), "cloudletQueue");
+        assert_eq!(camel_case("source-code-path"), "sourceCodePath");
+    }
+}
@@ -0,0 +1,183 @@
+//! Generator of the Python and TypeScript clients of the API, from its OpenAPI definition
+//! (`src/api/openapi.yaml`), and mock of the API to exercise them.
+//!
+//! Each client is packaged in a directory, and optionally an archive: a source
+//! distribution `cloudlet-client-<version>.tar.gz` for Python, and a tarball
+//! `cloudlet-client-<version>.tgz` in the layout of `npm pack` for TypeScript. The packages
+//! also carry the protobuf definitions of the orchestrator and the agents, to generate gRPC
+//! stubs from them with the tooling of their language.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use definition::Definition;
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+pub mod definition;
+pub mod mock;
+pub mod python;
+pub mod typescript;
+
+/// Definition of the API of this workspace.
+pub const DEFINITION: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../api/openapi.yaml");
+/// Protobuf definitions of this workspace.
+pub const PROTO_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../proto");
+
+/// Name of the packages, on PyPI and, under the `@cloudlet` scope, on npm.
+pub const PACKAGE_NAME: &str = "cloudlet-client";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Language {
+    Python,
+    Typescript,
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Language::Python => write!(f, "python"),
+            Language::Typescript => write!(f, "typescript"),
+        }
+    }
+}
+
+/// File of a package, at a path relative to its root.
+#[derive(Debug, Clone)]
+pub struct File {
+    pub path: PathBuf,
+    pub content: Vec<u8>,
+}
+
+impl File {
+    pub fn new(path: impl Into<PathBuf>, content: impl Into<Vec<u8>>) -> Self {
+        Self {
+            path: path.into(),
+            content: content.into(),
+        }
+    }
+}
+
+/// The `.proto` files of `dir`, under `proto/`.
+pub fn proto_files(dir: &Path) -> Result<Vec<File>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("reading {:?}", dir))? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "proto")
+        {
+            let name = path.file_name().unwrap();
+            files.push(File::new(
+                Path::new("proto").join(name),
+                fs::read(&path).with_context(|| format!("reading {:?}", path))?,
+            ));
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn readme(definition: &Definition, language: Language) -> String {
+    let usage = match language {
+        Language::Python => {
+            "```python\nfrom cloudlet_client import Client, collect\n\nclient = Client(\"http://127.0.0.1:3000\")\noutput = collect(client.run(request))\nprint(output.stdout.decode())\n```"
+        }
+        Language::Typescript => {
+            "```typescript\nimport { Client, collect } from \"@cloudlet/client\";\n\nconst client = new Client(\"http://127.0.0.1:3000\");\nconst output = await collect(client.run(request));\nconsole.log(new TextDecoder().decode(output.stdout));\n```"
+        }
+    };
+    format!(
+        "# {}\n\nClient of the {}, version {}, generated from its OpenAPI definition.\n\n{}\n\nThe reads and the runs failing on an unreachable or saturated server are retried with an exponential backoff, the runs being sent with an `Idempotency-Key`. The errors of the API are `ApiError`s, with the Cloudlet error code given by the server.\n\nThe protobuf definitions of the orchestrator and the agents are under `proto/`.\n",
+        PACKAGE_NAME, definition.title, definition.version, usage
+    )
+}
+
+/// Files of the package of the client in `language`, with the protobuf definitions `protos`.
+pub fn generate(definition: &Definition, language: Language, protos: &[File]) -> Vec<File> {
+    let readme = readme(definition, language);
+    let mut files = match language {
+        Language::Python => python::package(definition, &readme),
+        Language::Typescript => typescript::package(definition, &readme),
+    };
+    files.extend(protos.iter().cloned());
+    files
+}
+
+/// Write `files` under `dir`, replacing what it held.
+pub fn write(dir: &Path, files: &[File]) -> Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir).with_context(|| format!("removing {:?}", dir))?;
+    }
+    for file in files {
+        let path = dir.join(&file.path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, &file.content).with_context(|| format!("writing {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Archive of the package in `language`, under `dir`, and the path of the archive.
+pub fn archive(
+    definition: &Definition,
+    language: Language,
+    files: &[File],
+    dir: &Path,
+) -> Result<PathBuf> {
+    let (name, root) = match language {
+        Language::Python => (
+            format!("{}-{}.tar.gz", PACKAGE_NAME, definition.version),
+            format!("{}-{}", PACKAGE_NAME, definition.version),
+        ),
+        Language::Typescript => (
+            format!("{}-{}.tgz", PACKAGE_NAME, definition.version),
+            "package".to_string(),
+        ),
+    };
+    let path = dir.join(name);
+    fs::create_dir_all(dir)?;
+
+    let mut archive = tar::Builder::new(GzEncoder::new(
+        fs::File::create(&path).with_context(|| format!("creating {:?}", path))?,
+        Compression::default(),
+    ));
+    for file in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(file.content.len() as u64);
+        header.set_mode(0o644);
+        // The archives of a definition are the same wherever they are built.
+        header.set_mtime(0);
+        archive.append_data(
+            &mut header,
+            Path::new(&root).join(&file.path),
+            file.content.as_slice(),
+        )?;
+    }
+    archive.into_inner()?.finish()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{path::PathBuf, process::Command};
+
+    /// Empty directory for the files of a test, under the temporary directory.
+    pub fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("cloudlet-sdk-gen-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("could not create the scratch directory");
+        dir
+    }
+
+    /// Whether `program` can be run, the smoke tests of its client being skipped otherwise.
+    pub fn available(program: &str) -> bool {
+        let available = Command::new(program).arg("--version").output().is_ok();
+        if !available {
+            eprintln!("{} isn't installed, skipping the test", program);
+        }
+        available
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use cloudlet_sdk_gen::{
+    archive, definition::Definition, generate, mock::MockApi, proto_files, write, Language,
+    DEFINITION, PROTO_DIR,
+};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(about = "Generate the clients of the Cloudlet API, or mock the API to exercise them.")]
+struct Args {
+    /// OpenAPI definition of the API.
+    #[arg(long, default_value = DEFINITION, global = true)]
+    definition: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate the packages of the clients, in `<OUTPUT>/<LANGUAGE>`.
+    Generate {
+        /// Directory of the packages.
+        #[arg(long, default_value = "target/sdk")]
+        output: PathBuf,
+        /// Languages of the clients, all of them if unset.
+        #[arg(long, value_enum)]
+        language: Vec<Language>,
+        /// Directory of the protobuf definitions bundled with the packages.
+        #[arg(long, default_value = PROTO_DIR)]
+        proto: PathBuf,
+        /// Also archive the packages, in `<OUTPUT>/dist`, to publish them.
+        #[arg(long)]
+        archive: bool,
+    },
+    /// Answer the operations of the definition with the examples of their responses.
+    Mock {
+        #[arg(long, default_value = "127.0.0.1:3000")]
+        listen: String,
+    },
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let definition = Definition::load(&args.definition)?;
+
+    match args.command {
+        Command::Generate {
+            output,
+            language,
+            proto,
+            archive: archived,
+        } => {
+            let languages = if language.is_empty() {
+                vec![Language::Python, Language::Typescript]
+            } else {
+                language
+            };
+            let protos = proto_files(&proto)?;
+            for language in languages {
+                let files = generate(&definition, language, &protos);
+                let dir = output.join(language.to_string());
+                write(&dir, &files)?;
+                println!("{} client: {}", language, dir.display());
+                if archived {
+                    let path = archive(&definition, language, &files, &output.join("dist"))?;
+                    println!("{} package: {}", language, path.display());
+                }
+            }
+        }
+        Command::Mock { listen } => {
+            let mock = MockApi::bind(&definition, &listen)?;
+            println!(
+                "Mock of the {} listening on {}",
+                definition.title,
+                mock.url()
+            );
+            mock.wait();
+        }
+    }
+    Ok(())
+}
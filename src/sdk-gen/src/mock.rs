@@ -0,0 +1,350 @@
+//! Mock of the API, answering each operation of a definition with the example of its response,
+//! so that the generated clients can be exercised without an orchestrator.
+//!
+//! The requests are recorded, and the next ones can be made to fail like on a saturated server.
+
+use crate::definition::{Definition, Response};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Request received by the mock.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    /// By lowercase name.
+    pub headers: HashMap<String, String>,
+    pub body: Option<Value>,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Route {
+    method: &'static str,
+    /// Segments of the path, `None` for the path parameters.
+    segments: Vec<Option<String>>,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Route {
+    fn matches(&self, method: &str, path: &str) -> bool {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        self.method == method
+            && segments.len() == self.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(segments)
+                .all(|(expected, segment)| match expected {
+                    Some(expected) => expected == segment,
+                    None => !segment.is_empty(),
+                })
+    }
+}
+
+struct State {
+    routes: Vec<Route>,
+    url: String,
+    requests: Mutex<Vec<RecordedRequest>>,
+    /// Statuses of the next responses, before the examples are answered again.
+    failures: Mutex<VecDeque<u16>>,
+}
+
+pub struct MockApi {
+    state: Arc<State>,
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockApi {
+    /// Mock of the API of `definition`, on a random local port.
+    pub fn start(definition: &Definition) -> io::Result<Self> {
+        Self::bind(definition, "127.0.0.1:0")
+    }
+
+    /// Mock of the API of `definition`, listening on `addr`.
+    pub fn bind(definition: &Definition, addr: &str) -> io::Result<Self> {
+        let routes = definition
+            .operations
+            .iter()
+            .map(|operation| {
+                let segments = operation
+                    .path
+                    .trim_matches('/')
+                    .split('/')
+                    .map(|segment| (!segment.starts_with('{')).then(|| segment.to_string()))
+                    .collect();
+                let (content_type, body) = match &operation.response {
+                    Response::Json(_) => (
+                        "application/json",
+                        serde_json::to_vec(&operation.example).unwrap_or_default(),
+                    ),
+                    Response::Events(_) => {
+                        let events = match &operation.example {
+                            Value::Array(events) => events.clone(),
+                            Value::Null => Vec::new(),
+                            event => vec![event.clone()],
+                        };
+                        let body: String = events
+                            .iter()
+                            .map(|event| format!("data: {}\n\n", event))
+                            .collect();
+                        ("text/event-stream", body.into_bytes())
+                    }
+                };
+                Route {
+                    method: operation.method.as_str(),
+                    segments,
+                    content_type,
+                    body,
+                }
+            })
+            .collect();
+
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(State {
+            routes,
+            url: format!("http://{}", addr),
+            requests: Mutex::new(Vec::new()),
+            failures: Mutex::new(VecDeque::new()),
+        });
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (state, stop) = (state.clone(), stop.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let state = state.clone();
+                    thread::spawn(move || {
+                        let _ = handle(stream, &state);
+                    });
+                }
+            })
+        };
+
+        Ok(Self {
+            state,
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// URL of the mock, e.g. `http://127.0.0.1:41234`.
+    pub fn url(&self) -> &str {
+        &self.state.url
+    }
+
+    /// The requests received so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Answer the next `count` requests with `status`, e.g. 503 like a saturated server.
+    pub fn fail_next(&self, count: usize, status: u16) {
+        self.state
+            .failures
+            .lock()
+            .unwrap()
+            .extend(std::iter::repeat(status).take(count));
+    }
+
+    /// Serve until the process is stopped.
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MockApi {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake the listener up so that it sees it has to stop.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Decode a `application/x-www-form-urlencoded` component.
+fn decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn write_response(
+    mut stream: TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn handle(stream: TcpStream, state: &State) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect();
+    let body = if body.is_empty() {
+        None
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(body) => Some(body),
+            Err(e) => {
+                let error = json!({ "message": format!("invalid JSON body: {}", e) });
+                return write_response(
+                    stream,
+                    400,
+                    "application/json",
+                    error.to_string().as_bytes(),
+                );
+            }
+        }
+    };
+    state.requests.lock().unwrap().push(RecordedRequest {
+        method: method.clone(),
+        path: decode(path),
+        query,
+        headers,
+        body,
+    });
+
+    if let Some(status) = state.failures.lock().unwrap().pop_front() {
+        let error = json!({
+            "code": "CLDT-VMM-019",
+            "message": "The host has no room for the guest",
+            "hint": "retry later",
+        });
+        return write_response(
+            stream,
+            status,
+            "application/json",
+            error.to_string().as_bytes(),
+        );
+    }
+    match state
+        .routes
+        .iter()
+        .find(|route| route.matches(&method, path))
+    {
+        Some(route) => write_response(stream, 200, route.content_type, &route.body),
+        None => {
+            let error = json!({ "message": format!("no operation {} {}", method, path) });
+            write_response(
+                stream,
+                404,
+                "application/json",
+                error.to_string().as_bytes(),
+            )
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Python client: a package of a single module, on the standard library alone.
+//!
+//! The schemas are `TypedDict`s, all of whose keys are optional, and the operations methods of
+//! `Client`, taking the path parameters and the body as positional arguments and the query and
+//! header parameters as keyword ones.
+
+use crate::definition::{snake_case, Definition, Location, Operation, Response, Type};
+use crate::{File, PACKAGE_NAME};
+use std::fmt::Write;
+
+const RUNTIME: &str = include_str!("../templates/python/runtime.py");
+
+/// Name of the module of the package.
+pub const MODULE: &str = "cloudlet_client";
+
+fn quote(text: &str) -> String {
+    serde_json::to_string(text).unwrap()
+}
+
+fn python_type(ty: &Type, quoted: bool) -> String {
+    match ty {
+        Type::String => "str".to_string(),
+        Type::Integer => "int".to_string(),
+        Type::Number => "float".to_string(),
+        Type::Boolean => "bool".to_string(),
+        Type::Enum(values) => format!(
+            "Literal[{}]",
+            values
+                .iter()
+                .map(|value| quote(value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Type::Array(items) => format!("List[{}]", python_type(items, quoted)),
+        Type::Map(values) => format!("Dict[str, {}]", python_type(values, quoted)),
+        // The schemas may refer to the ones declared after them.
+        Type::Ref(name) if quoted => quote(name),
+        Type::Ref(name) => name.clone(),
+    }
+}
+
+/// Docstring of `text`, indented by `indent`.
+fn docstring(text: &str, indent: &str) -> String {
+    format!("{}\"\"\"{}\"\"\"\n", indent, text.replace("\"\"\"", "'''"))
+}
+
+fn render_method(code: &mut String, operation: &Operation) {
+    let mut arguments = vec!["self".to_string()];
+    for parameter in operation.parameters(Location::Path) {
+        arguments.push(format!(
+            "{}: {}",
+            snake_case(&parameter.name),
+            python_type(&parameter.ty, false)
+        ));
+    }
+    if let Some(body) = &operation.body {
+        arguments.push(format!("request: {}", python_type(body, false)));
+    }
+    let options: Vec<_> = operation
+        .parameters(Location::Query)
+        .chain(operation.parameters(Location::Header))
+        .collect();
+    if !options.is_empty() {
+        arguments.push("*".to_string());
+    }
+    for parameter in &options {
+        let default = match &parameter.default {
+            Some(default) => quote(default),
+            None => "None".to_string(),
+        };
+        arguments.push(format!(
+            "{}: Optional[{}] = {}",
+            snake_case(&parameter.name),
+            python_type(&parameter.ty, false),
+            default
+        ));
+    }
+
+    let returns = match &operation.response {
+        Response::Json(ty) => python_type(ty, false),
+        Response::Events(ty) => format!("Iterator[{}]", python_type(ty, false)),
+    };
+    let _ = writeln!(
+        code,
+        "    def {}({}) -> {}:",
+        snake_case(&operation.id),
+        arguments.join(", "),
+        returns
+    );
+
+    let mut doc = operation.summary.clone();
+    for parameter in operation
+        .parameters
+        .iter()
+        .filter(|parameter| !parameter.description.is_empty())
+    {
+        let _ = write!(
+            doc,
+            "\n\n        `{}`: {}",
+            snake_case(&parameter.name),
+            parameter.description
+        );
+    }
+    code.push_str(&docstring(&doc, "        "));
+
+    // The path parameters are substituted in an f-string.
+    let mut path = String::new();
+    for (i, part) in operation.path.split(['{', '}']).enumerate() {
+        if i % 2 == 1 {
+            let _ = write!(
+                path,
+                "{{urllib.parse.quote(str({}), safe='')}}",
+                snake_case(part)
+            );
+        } else {
+            path.push_str(part);
+        }
+    }
+
+    let _ = writeln!(code, "        return self._request(");
+    let _ = writeln!(code, "            {},", quote(operation.method.as_str()));
+    let format = match operation.parameters(Location::Path).next() {
+        Some(_) => "f",
+        None => "",
+    };
+    let _ = writeln!(code, "            {}{},", format, quote(&path));
+    for (location, argument) in [(Location::Query, "query"), (Location::Header, "headers")] {
+        let entries: Vec<String> = operation
+            .parameters(location)
+            .map(|parameter| {
+                format!(
+                    "{}: {}",
+                    quote(&parameter.name),
+                    snake_case(&parameter.name)
+                )
+            })
+            .collect();
+        if !entries.is_empty() {
+            let _ = writeln!(code, "            {}={{{}}},", argument, entries.join(", "));
+        }
+    }
+    if operation.body.is_some() {
+        let _ = writeln!(code, "            body=request,");
+    }
+    if operation.retryable() {
+        let _ = writeln!(code, "            retryable=True,");
+    }
+    if operation.idempotent {
+        let _ = writeln!(code, "            idempotent=True,");
+    }
+    if matches!(operation.response, Response::Events(_)) {
+        let _ = writeln!(code, "            stream=True,");
+    }
+    let _ = writeln!(code, "        )");
+}
+
+/// Source of the module of the client.
+pub fn render(definition: &Definition) -> String {
+    let mut code = String::new();
+    let _ = writeln!(
+        code,
+        "\"\"\"Client of the {}, version {}.\n\n{}\n\nGenerated by cloudlet-sdk-gen from the OpenAPI definition of the API, do not edit.\n\"\"\"\n",
+        definition.title, definition.version, definition.description
+    );
+    code.push_str(RUNTIME);
+    let _ = writeln!(code, "\n__version__ = {}\n", quote(&definition.version));
+
+    for schema in &definition.schemas {
+        code.push('\n');
+        if !schema.description.is_empty() {
+            let _ = writeln!(code, "# {}", schema.description);
+        }
+        let _ = writeln!(code, "{} = TypedDict(", schema.name);
+        let _ = writeln!(code, "    {},", quote(&schema.name));
+        code.push_str("    {\n");
+        for property in &schema.properties {
+            let _ = writeln!(
+                code,
+                "        {}: {},",
+                quote(&property.name),
+                python_type(&property.ty, true)
+            );
+        }
+        code.push_str("    },\n    total=False,\n)\n");
+    }
+
+    let _ = writeln!(code, "\n\nclass Client(_BaseClient):");
+    code.push_str(&docstring(
+        &format!("Client of the {}.", definition.title),
+        "    ",
+    ));
+    for operation in &definition.operations {
+        code.push('\n');
+        render_method(&mut code, operation);
+    }
+    code
+}
+
+/// Files of the package, an sdist layout built with setuptools.
+pub fn package(definition: &Definition, readme: &str) -> Vec<File> {
+    let pyproject = format!(
+        r#"[build-system]
+requires = ["setuptools>=61"]
+build-backend = "setuptools.build_meta"
+
+[project]
+name = "{}"
+version = "{}"
+description = "Client of the {}"
+readme = "README.md"
+license = {{ text = "Apache-2.0" }}
+requires-python = ">=3.8"
+
+[tool.setuptools]
+packages = ["{}"]
+
+[tool.setuptools.package-data]
+{} = ["py.typed"]
+"#,
+        PACKAGE_NAME, definition.version, definition.title, MODULE, MODULE
+    );
+    vec![
+        File::new("pyproject.toml", pyproject),
+        File::new("README.md", readme),
+        File::new(format!("{}/__init__.py", MODULE), render(definition)),
+        File::new(format!("{}/py.typed", MODULE), ""),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockApi;
+    use crate::tests::{available, scratch_dir};
+    use std::path::Path;
+    use std::process::Command;
+
+    const SMOKE_TEST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/smoke.py");
+
+    fn smoke_test(dir: &Path, mock: &MockApi, mode: &str) -> String {
+        let output = Command::new("python3")
+            .args([SMOKE_TEST, mock.url(), mode])
+            .env("PYTHONPATH", dir)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    #[test]
+    fn test_python_client() {
+        let definition = Definition::load(Path::new(crate::DEFINITION)).unwrap();
+        let code = render(&definition);
+        assert!(code.contains("    def invoke_workload(self, name: str, request: InvokeRequest)"));
+        if !available("python3") {
+            return;
+        }
+        let dir = scratch_dir("python");
+        crate::write(&dir, &package(&definition, "")).unwrap();
+        let mock = MockApi::start(&definition).unwrap();
+
+        // The run is retried after the failure, with the same idempotency key.
+        mock.fail_next(1, 503);
+        assert_eq!(smoke_test(&dir, &mock, "retry"), "ok\n");
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0].path, "/run");
+        assert_eq!(requests[1].path, "/run");
+        assert!(requests[0].header("Idempotency-Key").is_some());
+        assert_eq!(
+            requests[0].header("Idempotency-Key"),
+            requests[1].header("Idempotency-Key")
+        );
+        assert_eq!(requests[1].header("Cloudlet-Queue"), Some("never"));
+        assert_eq!(
+            requests[1].body.as_ref().unwrap()["build"]["source-code-path"],
+            "main.py"
+        );
+        assert_eq!(requests[2].query("selector"), Some("team=infra"));
+        assert_eq!(requests[2].query("workload"), None);
+
+        // The invocations aren't idempotent, they aren't retried.
+        mock.fail_next(1, 503);
+        assert_eq!(smoke_test(&dir, &mock, "error"), "ok\n");
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 5);
+        assert_eq!(requests[4].path, "/workloads/hello world/invoke");
+    }
+}
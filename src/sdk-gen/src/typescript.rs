@@ -0,0 +1,331 @@
+//! TypeScript client: an ES module on `fetch`, for Node.js 18 and later, with its type
+//! declarations.
+//!
+//! The schemas are interfaces, and the operations methods of `Client`, taking the path
+//! parameters and the body as positional arguments and the query and header parameters as an
+//! object of options. The event streams are async generators, whose request is sent when the
+//! iteration starts.
+
+use crate::definition::{camel_case, Definition, Location, Operation, Response, Type};
+use crate::{File, PACKAGE_NAME};
+use std::fmt::Write;
+
+const RUNTIME: &str = include_str!("../templates/typescript/runtime.js");
+const RUNTIME_DECLARATIONS: &str = include_str!("../templates/typescript/runtime.d.ts");
+
+const HEADER: &str =
+    "// Generated by cloudlet-sdk-gen from the OpenAPI definition of the API, do not edit.\n\n";
+
+fn quote(text: &str) -> String {
+    serde_json::to_string(text).unwrap()
+}
+
+/// `name` as the key of an object, quoted unless it is an identifier.
+fn key(name: &str) -> String {
+    let identifier = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if identifier {
+        name.to_string()
+    } else {
+        quote(name)
+    }
+}
+
+fn typescript_type(ty: &Type) -> String {
+    match ty {
+        Type::String => "string".to_string(),
+        Type::Integer | Type::Number => "number".to_string(),
+        Type::Boolean => "boolean".to_string(),
+        Type::Enum(values) => values
+            .iter()
+            .map(|value| quote(value))
+            .collect::<Vec<_>>()
+            .join(" | "),
+        Type::Array(items) => match items.as_ref() {
+            Type::Enum(_) => format!("({})[]", typescript_type(items)),
+            items => format!("{}[]", typescript_type(items)),
+        },
+        Type::Map(values) => format!("Record<string, {}>", typescript_type(values)),
+        Type::Ref(name) => name.clone(),
+    }
+}
+
+fn comment(text: &str, indent: &str) -> String {
+    if text.is_empty() {
+        return String::new();
+    }
+    format!("{}/** {} */\n", indent, text.replace("*/", "* /"))
+}
+
+/// Options of `operation`: its query and header parameters.
+fn options(operation: &Operation) -> Vec<(String, &crate::definition::Parameter)> {
+    operation
+        .parameters(Location::Query)
+        .chain(operation.parameters(Location::Header))
+        .map(|parameter| (camel_case(&parameter.name), parameter))
+        .collect()
+}
+
+/// Positional arguments of `operation`: its path parameters and its body.
+fn arguments(operation: &Operation) -> Vec<(String, Type)> {
+    let mut arguments: Vec<_> = operation
+        .parameters(Location::Path)
+        .map(|parameter| (camel_case(&parameter.name), parameter.ty.clone()))
+        .collect();
+    if let Some(body) = &operation.body {
+        arguments.push(("request".to_string(), body.clone()));
+    }
+    arguments
+}
+
+fn render_method(code: &mut String, operation: &Operation) {
+    code.push_str(&comment(&operation.summary, "  "));
+    let mut parameters: Vec<String> = arguments(operation)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    let options = options(operation);
+    if !options.is_empty() {
+        let fields: Vec<String> = options
+            .iter()
+            .map(|(name, parameter)| match &parameter.default {
+                Some(default) => format!("{} = {}", name, quote(default)),
+                None => name.clone(),
+            })
+            .collect();
+        parameters.push(format!("{{ {} }} = {{}}", fields.join(", ")));
+    }
+    let _ = writeln!(
+        code,
+        "  {}({}) {{",
+        camel_case(&operation.id),
+        parameters.join(", ")
+    );
+
+    let mut path = String::new();
+    for (i, part) in operation.path.split(['{', '}']).enumerate() {
+        if i % 2 == 1 {
+            let _ = write!(path, "${{encodeURIComponent({})}}", camel_case(part));
+        } else {
+            path.push_str(&part.replace('`', "\\`"));
+        }
+    }
+
+    let mut request = Vec::new();
+    for (location, field) in [(Location::Query, "query"), (Location::Header, "headers")] {
+        let entries: Vec<String> = options
+            .iter()
+            .filter(|(_, parameter)| parameter.location == location)
+            .map(|(name, parameter)| format!("{}: {}", key(&parameter.name), name))
+            .collect();
+        if !entries.is_empty() {
+            request.push(format!("{}: {{ {} }}", field, entries.join(", ")));
+        }
+    }
+    if operation.body.is_some() {
+        request.push("body: request".to_string());
+    }
+    if operation.retryable() {
+        request.push("retryable: true".to_string());
+    }
+    if operation.idempotent {
+        request.push("idempotent: true".to_string());
+    }
+    let call = match operation.response {
+        Response::Json(_) => "_json",
+        Response::Events(_) => "_events",
+    };
+    let _ = writeln!(
+        code,
+        "    return this.{}({}, `{}`, {{ {} }});",
+        call,
+        quote(operation.method.as_str()),
+        path,
+        request.join(", ")
+    );
+    code.push_str("  }\n");
+}
+
+/// Source of the module of the client, `index.js`.
+pub fn render(definition: &Definition) -> String {
+    let mut code = String::from(HEADER);
+    code.push_str(RUNTIME);
+    let _ = writeln!(
+        code,
+        "\nexport const VERSION = {};",
+        quote(&definition.version)
+    );
+    let _ = writeln!(code, "\n/** Client of the {}. */", definition.title);
+    code.push_str("export class Client extends BaseClient {\n");
+    for (i, operation) in definition.operations.iter().enumerate() {
+        if i > 0 {
+            code.push('\n');
+        }
+        render_method(&mut code, operation);
+    }
+    code.push_str("}\n");
+    code
+}
+
+/// Type declarations of the module, `index.d.ts`.
+pub fn render_declarations(definition: &Definition) -> String {
+    let mut code = String::from(HEADER);
+    code.push_str(RUNTIME_DECLARATIONS);
+    let _ = writeln!(code, "\nexport declare const VERSION: string;");
+
+    for schema in &definition.schemas {
+        code.push('\n');
+        code.push_str(&comment(&schema.description, ""));
+        let _ = writeln!(code, "export interface {} {{", schema.name);
+        for property in &schema.properties {
+            code.push_str(&comment(&property.description, "  "));
+            let _ = writeln!(
+                code,
+                "  {}{}: {};",
+                key(&property.name),
+                if property.required { "" } else { "?" },
+                typescript_type(&property.ty)
+            );
+        }
+        code.push_str("}\n");
+    }
+
+    let _ = writeln!(code, "\n/** Client of the {}. */", definition.title);
+    code.push_str("export declare class Client extends BaseClient {\n");
+    for operation in &definition.operations {
+        let mut summary = operation.summary.clone();
+        for parameter in operation
+            .parameters
+            .iter()
+            .filter(|parameter| !parameter.description.is_empty())
+        {
+            let _ = write!(
+                summary,
+                " `{}`: {}",
+                camel_case(&parameter.name),
+                parameter.description
+            );
+        }
+        code.push_str(&comment(&summary, "  "));
+
+        let mut parameters: Vec<String> = arguments(operation)
+            .into_iter()
+            .map(|(name, ty)| format!("{}: {}", name, typescript_type(&ty)))
+            .collect();
+        let options = options(operation);
+        if !options.is_empty() {
+            let fields: Vec<String> = options
+                .iter()
+                .map(|(name, parameter)| format!("{}?: {}", name, typescript_type(&parameter.ty)))
+                .collect();
+            parameters.push(format!("options?: {{ {} }}", fields.join("; ")));
+        }
+        let returns = match &operation.response {
+            Response::Json(ty) => format!("Promise<{}>", typescript_type(ty)),
+            Response::Events(ty) => {
+                format!("AsyncGenerator<{}, void, undefined>", typescript_type(ty))
+            }
+        };
+        let _ = writeln!(
+            code,
+            "  {}({}): {};",
+            camel_case(&operation.id),
+            parameters.join(", "),
+            returns
+        );
+    }
+    code.push_str("}\n");
+    code
+}
+
+/// Files of the package, in the layout of `npm pack`.
+pub fn package(definition: &Definition, readme: &str) -> Vec<File> {
+    let manifest = serde_json::json!({
+        "name": format!("@cloudlet/{}", PACKAGE_NAME.trim_start_matches("cloudlet-")),
+        "version": definition.version,
+        "description": format!("Client of the {}", definition.title),
+        "license": "Apache-2.0",
+        "type": "module",
+        "main": "index.js",
+        "types": "index.d.ts",
+        "exports": {
+            ".": { "types": "./index.d.ts", "default": "./index.js" }
+        },
+        "engines": { "node": ">=18" },
+    });
+    vec![
+        File::new(
+            "package.json",
+            serde_json::to_string_pretty(&manifest).unwrap() + "\n",
+        ),
+        File::new("README.md", readme),
+        File::new("index.js", render(definition)),
+        File::new("index.d.ts", render_declarations(definition)),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockApi;
+    use crate::tests::{available, scratch_dir};
+    use std::path::Path;
+    use std::process::Command;
+
+    const SMOKE_TEST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/smoke.mjs");
+
+    fn smoke_test(dir: &Path, mock: &MockApi, mode: &str) -> String {
+        let output = Command::new("node")
+            .args([SMOKE_TEST, mock.url(), mode])
+            .env("CLOUDLET_CLIENT", dir.join("index.js"))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    #[test]
+    fn test_typescript_client() {
+        let definition = Definition::load(Path::new(crate::DEFINITION)).unwrap();
+        let declarations = render_declarations(&definition);
+        assert!(declarations.contains("  \"source-code-path\": string;"));
+        assert!(declarations.contains(
+            "  listRuns(options?: { selector?: string; workload?: string; limit?: number }): Promise<RunRecord[]>;"
+        ));
+        if !available("node") {
+            return;
+        }
+        let dir = scratch_dir("typescript");
+        crate::write(&dir, &package(&definition, "")).unwrap();
+        let mock = MockApi::start(&definition).unwrap();
+
+        // The run is retried after the failure, with the same idempotency key.
+        mock.fail_next(1, 503);
+        assert_eq!(smoke_test(&dir, &mock, "retry"), "ok\n");
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[0].header("Idempotency-Key").is_some());
+        assert_eq!(
+            requests[0].header("Idempotency-Key"),
+            requests[1].header("Idempotency-Key")
+        );
+        assert_eq!(requests[1].header("Cloudlet-Queue"), Some("never"));
+        assert_eq!(requests[2].query("limit"), Some("5"));
+
+        // The invocations aren't idempotent, they aren't retried.
+        mock.fail_next(1, 503);
+        assert_eq!(smoke_test(&dir, &mock, "error"), "ok\n");
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 5);
+        assert_eq!(requests[4].path, "/workloads/hello world/invoke");
+    }
+}
@@ -0,0 +1,170 @@
+import base64
+import json
+import socket
+import time
+import urllib.error
+import urllib.parse
+import urllib.request
+import uuid
+from dataclasses import dataclass, field
+from typing import Any, Dict, Iterable, Iterator, List, Literal, Optional, TypedDict
+
+DEFAULT_BASE_URL = "http://127.0.0.1:3000"
+
+# Statuses of the responses of a saturated or restarting server, after which a request is retried.
+RETRYABLE_STATUSES = (429, 502, 503, 504)
+
+
+class ApiError(Exception):
+    """Error answered by the API, with the Cloudlet error code, e.g. `CLDT-VMM-019`, if any."""
+
+    def __init__(self, status: int, error: Dict[str, Any]):
+        self.status = status
+        self.code: Optional[str] = error.get("code")
+        self.message: str = error.get("message") or str(status)
+        self.hint: Optional[str] = error.get("hint")
+        self.details: List[str] = error.get("details") or []
+        super().__init__(f"[{self.code}] {self.message}" if self.code else self.message)
+
+    @property
+    def retryable(self) -> bool:
+        return self.status in RETRYABLE_STATUSES
+
+
+def _error(error: urllib.error.HTTPError) -> ApiError:
+    body = error.read().decode("utf-8", "replace")
+    try:
+        parsed = json.loads(body)
+    except ValueError:
+        parsed = None
+    if not isinstance(parsed, dict) or "message" not in parsed:
+        parsed = {"message": body.strip() or f"HTTP {error.code}"}
+    return ApiError(error.code, parsed)
+
+
+def _query_value(value: Any) -> str:
+    if isinstance(value, bool):
+        return "true" if value else "false"
+    return str(value)
+
+
+def _events(response: Any) -> Iterator[Any]:
+    with response:
+        for line in response:
+            line = line.decode("utf-8", "replace").rstrip("\r\n")
+            if line.startswith("data: "):
+                yield json.loads(line[len("data: "):])
+
+
+def output_bytes(event: Dict[str, Any], stream: str = "stdout") -> bytes:
+    """Bytes written by the workload to `stream`, `stdout` or `stderr`, in `event`."""
+    output = event.get(stream)
+    if output is None:
+        return b""
+    if event.get("encoding") == "base64":
+        return base64.b64decode(output)
+    return output.encode("utf-8")
+
+
+@dataclass
+class RunOutput:
+    """Output of a whole run."""
+
+    stdout: bytes = b""
+    stderr: bytes = b""
+    exit_code: Optional[int] = None
+    # Whether the run failed, its workload not built or not run to its end.
+    failed: bool = False
+    # Why the orchestrator ended the run, if it did.
+    error: Optional[Dict[str, Any]] = None
+    events: List[Dict[str, Any]] = field(default_factory=list)
+
+    @property
+    def success(self) -> bool:
+        return not self.failed and self.exit_code == 0
+
+
+def collect(events: Iterable[Dict[str, Any]]) -> RunOutput:
+    """Gather the output of `events` until the end of the run."""
+    output = RunOutput()
+    for event in events:
+        output.events.append(event)
+        output.stdout += output_bytes(event, "stdout")
+        output.stderr += output_bytes(event, "stderr")
+        if event.get("stage") in ("Done", "Failed"):
+            output.exit_code = event.get("exit_code")
+            output.failed = event.get("stage") == "Failed"
+            output.error = event.get("error")
+            break
+    return output
+
+
+class _BaseClient:
+    def __init__(
+        self,
+        base_url: str = DEFAULT_BASE_URL,
+        timeout: float = 30.0,
+        max_attempts: int = 4,
+        initial_backoff: float = 0.25,
+        max_backoff: float = 5.0,
+    ):
+        """Client of the API at `base_url`. The reads and the idempotent requests failing on an
+        unreachable or saturated server are attempted `max_attempts` times, the delays doubling
+        from `initial_backoff` up to `max_backoff` seconds; the others only when they couldn't
+        reach the server."""
+        self.base_url = base_url.rstrip("/")
+        self.timeout = timeout
+        self.max_attempts = max_attempts
+        self.initial_backoff = initial_backoff
+        self.max_backoff = max_backoff
+
+    def _request(
+        self,
+        method: str,
+        path: str,
+        query: Optional[Dict[str, Any]] = None,
+        headers: Optional[Dict[str, Optional[str]]] = None,
+        body: Any = None,
+        retryable: bool = False,
+        idempotent: bool = False,
+        stream: bool = False,
+    ) -> Any:
+        url = self.base_url + path
+        query = {key: _query_value(value) for key, value in (query or {}).items() if value is not None}
+        if query:
+            url += "?" + urllib.parse.urlencode(query)
+        headers = {key: value for key, value in (headers or {}).items() if value is not None}
+        data = None
+        if body is not None:
+            data = json.dumps(body).encode("utf-8")
+            headers["Content-Type"] = "application/json"
+        if idempotent:
+            headers["Idempotency-Key"] = str(uuid.uuid4())
+
+        attempt = 1
+        while True:
+            request = urllib.request.Request(url, data=data, method=method, headers=headers)
+            try:
+                response = urllib.request.urlopen(request, timeout=self.timeout)
+            except urllib.error.HTTPError as e:
+                failure: Exception = _error(e)
+                retry = retryable and failure.retryable
+            except (urllib.error.URLError, ConnectionError) as e:
+                failure = e
+                # Refused connections never reached the server.
+                reason = getattr(e, "reason", e)
+                retry = retryable or isinstance(reason, ConnectionRefusedError)
+            except socket.timeout as e:
+                failure = e
+                retry = retryable
+            else:
+                if stream:
+                    return _events(response)
+                with response:
+                    content = response.read()
+                return json.loads(content) if content else None
+
+            if not retry or attempt >= self.max_attempts:
+                raise failure
+            time.sleep(min(self.initial_backoff * 2 ** (attempt - 1), self.max_backoff))
+            attempt += 1
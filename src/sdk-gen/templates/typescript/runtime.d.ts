@@ -0,0 +1,52 @@
+export declare const DEFAULT_BASE_URL: string;
+
+/** Statuses of the responses of a saturated or restarting server, after which a request is retried. */
+export declare const RETRYABLE_STATUSES: number[];
+
+/** Error answered by the API, with the Cloudlet error code, e.g. `CLDT-VMM-019`, if any. */
+export declare class ApiError extends Error {
+  constructor(status: number, error: ErrorResponse);
+  readonly status: number;
+  readonly code?: string;
+  readonly hint?: string;
+  readonly details: string[];
+  readonly retryable: boolean;
+}
+
+/** Bytes written by the workload to `stream` in `event`. */
+export declare function outputBytes(event: RunEvent, stream?: "stdout" | "stderr"): Uint8Array;
+
+/** Output of a whole run. */
+export interface RunOutput {
+  stdout: Uint8Array;
+  stderr: Uint8Array;
+  exitCode?: number;
+  /** Whether the run failed, its workload not built or not run to its end. */
+  failed: boolean;
+  /** Why the orchestrator ended the run, if it did. */
+  error?: ErrorResponse;
+  events: RunEvent[];
+  success: boolean;
+}
+
+/** Gather the output of `events` until the end of the run. */
+export declare function collect(events: AsyncIterable<RunEvent>): Promise<RunOutput>;
+
+export interface ClientOptions {
+  /** Attempts of the reads and of the idempotent requests, the first one included. */
+  maxAttempts?: number;
+  initialBackoffMs?: number;
+  maxBackoffMs?: number;
+  /** Implementation of `fetch`, the global one by default. */
+  fetch?: typeof fetch;
+}
+
+export declare class BaseClient {
+  /**
+   * Client of the API at `baseUrl`. The reads and the idempotent requests failing on an
+   * unreachable or saturated server are retried, the others only when they couldn't reach the
+   * server.
+   */
+  constructor(baseUrl?: string, options?: ClientOptions);
+  readonly baseUrl: string;
+}
@@ -0,0 +1,177 @@
+import { randomUUID } from "node:crypto";
+
+export const DEFAULT_BASE_URL = "http://127.0.0.1:3000";
+
+// Statuses of the responses of a saturated or restarting server, after which a request is retried.
+export const RETRYABLE_STATUSES = [429, 502, 503, 504];
+
+/** Error answered by the API, with the Cloudlet error code, e.g. `CLDT-VMM-019`, if any. */
+export class ApiError extends Error {
+  constructor(status, error) {
+    const message = error.message || `HTTP ${status}`;
+    super(error.code ? `[${error.code}] ${message}` : message);
+    this.name = "ApiError";
+    this.status = status;
+    this.code = error.code;
+    this.hint = error.hint;
+    this.details = error.details || [];
+  }
+
+  get retryable() {
+    return RETRYABLE_STATUSES.includes(this.status);
+  }
+}
+
+async function apiError(response) {
+  const body = await response.text();
+  let error;
+  try {
+    error = JSON.parse(body);
+  } catch {
+    error = undefined;
+  }
+  if (typeof error !== "object" || error === null || !("message" in error)) {
+    error = { message: body.trim() || `HTTP ${response.status}` };
+  }
+  return new ApiError(response.status, error);
+}
+
+async function* events(response) {
+  const decoder = new TextDecoder();
+  const reader = response.body.getReader();
+  let buffer = "";
+  for (;;) {
+    const { done, value } = await reader.read();
+    buffer += done ? decoder.decode() : decoder.decode(value, { stream: true });
+    let end;
+    while ((end = buffer.indexOf("\n")) >= 0) {
+      const line = buffer.slice(0, end).replace(/\r$/, "");
+      buffer = buffer.slice(end + 1);
+      if (line.startsWith("data: ")) {
+        yield JSON.parse(line.slice("data: ".length));
+      }
+    }
+    if (done) {
+      return;
+    }
+  }
+}
+
+/** Bytes written by the workload to `stream`, `stdout` or `stderr`, in `event`. */
+export function outputBytes(event, stream = "stdout") {
+  const output = event[stream];
+  if (output === undefined || output === null) {
+    return new Uint8Array();
+  }
+  return event.encoding === "base64"
+    ? Uint8Array.from(Buffer.from(output, "base64"))
+    : new TextEncoder().encode(output);
+}
+
+function concat(a, b) {
+  const joined = new Uint8Array(a.length + b.length);
+  joined.set(a);
+  joined.set(b, a.length);
+  return joined;
+}
+
+/** Gather the output of `events` until the end of the run. */
+export async function collect(events) {
+  const output = {
+    stdout: new Uint8Array(),
+    stderr: new Uint8Array(),
+    exitCode: undefined,
+    failed: false,
+    error: undefined,
+    events: [],
+  };
+  for await (const event of events) {
+    output.events.push(event);
+    output.stdout = concat(output.stdout, outputBytes(event, "stdout"));
+    output.stderr = concat(output.stderr, outputBytes(event, "stderr"));
+    if (event.stage === "Done" || event.stage === "Failed") {
+      output.exitCode = event.exit_code ?? undefined;
+      output.failed = event.stage === "Failed";
+      output.error = event.error;
+      break;
+    }
+  }
+  output.success = !output.failed && output.exitCode === 0;
+  return output;
+}
+
+const sleep = (ms) => new Promise((resolve) => setTimeout(resolve, ms));
+
+export class BaseClient {
+  /**
+   * Client of the API at `baseUrl`. The reads and the idempotent requests failing on an
+   * unreachable or saturated server are attempted `maxAttempts` times, the delays doubling from
+   * `initialBackoffMs` up to `maxBackoffMs`; the others only when they couldn't reach the server.
+   */
+  constructor(
+    baseUrl = DEFAULT_BASE_URL,
+    { maxAttempts = 4, initialBackoffMs = 250, maxBackoffMs = 5000, fetch: fetchImpl } = {},
+  ) {
+    this.baseUrl = baseUrl.replace(/\/+$/, "");
+    this.maxAttempts = maxAttempts;
+    this.initialBackoffMs = initialBackoffMs;
+    this.maxBackoffMs = maxBackoffMs;
+    this.fetch = fetchImpl || globalThis.fetch.bind(globalThis);
+  }
+
+  async _request(method, path, { query, headers, body, retryable, idempotent } = {}) {
+    const url = new URL(this.baseUrl + path);
+    for (const [key, value] of Object.entries(query || {})) {
+      if (value !== undefined && value !== null) {
+        url.searchParams.set(key, String(value));
+      }
+    }
+    const sent = {};
+    for (const [key, value] of Object.entries(headers || {})) {
+      if (value !== undefined && value !== null) {
+        sent[key] = value;
+      }
+    }
+    if (body !== undefined) {
+      sent["Content-Type"] = "application/json";
+    }
+    if (idempotent) {
+      sent["Idempotency-Key"] = randomUUID();
+    }
+
+    for (let attempt = 1; ; attempt++) {
+      let failure;
+      let retry;
+      try {
+        const response = await this.fetch(url, {
+          method,
+          headers: sent,
+          body: body === undefined ? undefined : JSON.stringify(body),
+        });
+        if (response.ok) {
+          return response;
+        }
+        failure = await apiError(response);
+        retry = retryable && failure.retryable;
+      } catch (e) {
+        failure = e;
+        // Refused connections never reached the server.
+        retry = retryable || e?.cause?.code === "ECONNREFUSED";
+      }
+      if (!retry || attempt >= this.maxAttempts) {
+        throw failure;
+      }
+      await sleep(Math.min(this.initialBackoffMs * 2 ** (attempt - 1), this.maxBackoffMs));
+    }
+  }
+
+  async _json(method, path, options) {
+    const response = await this._request(method, path, options);
+    const text = await response.text();
+    return text ? JSON.parse(text) : undefined;
+  }
+
+  async *_events(method, path, options) {
+    yield* events(await this._request(method, path, options));
+  }
+}
@@ -0,0 +1,41 @@
+// Smoke test of the generated TypeScript client, run by `typescript::tests` against the mock of the
+// API: `CLOUDLET_CLIENT=<index.js> node smoke.mjs <URL> retry|error`.
+import { pathToFileURL } from "node:url";
+
+const { ApiError, Client, collect } = await import(pathToFileURL(process.env.CLOUDLET_CLIENT).href);
+const [url, mode] = process.argv.slice(2);
+const client = new Client(url, { initialBackoffMs: 10 });
+if (mode === "retry") {
+  const request = {
+    workload_name: "hello",
+    language: "node",
+    code: "console.log('Hello, world!')",
+    log_level: "info",
+    action: "prepare-and-run",
+    server: { address: "localhost", port: 50051 },
+    build: { "source-code-path": "main.js", release: false },
+  };
+  const output = await collect(client.run(request));
+  if (!output.success || new TextDecoder().decode(output.stdout) !== "Hello, world!\n") {
+    throw new Error(`unexpected output ${JSON.stringify(output)}`);
+  }
+  const runs = await client.listRuns({ selector: "team=infra", limit: 5 });
+  if (runs[0].outcome !== "finished") {
+    throw new Error(`unexpected runs ${JSON.stringify(runs)}`);
+  }
+  if ((await client.serverInfo()).version !== "0.1.0") {
+    throw new Error("unexpected server info");
+  }
+  console.log("ok");
+} else {
+  try {
+    for await (const event of client.invokeWorkload("hello world", { inputs: { NAME: "cloudlet" } })) {
+      throw new Error(`unexpected event ${JSON.stringify(event)}`);
+    }
+  } catch (e) {
+    if (!(e instanceof ApiError) || e.status !== 503 || e.code !== "CLDT-VMM-019" || !e.retryable) {
+      throw e;
+    }
+    console.log("ok");
+  }
+}
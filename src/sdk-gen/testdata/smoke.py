@@ -0,0 +1,30 @@
+# Smoke test of the generated Python client, run by `python::tests` against the mock of the API:
+# `python3 smoke.py <URL> retry|error`.
+import sys
+from cloudlet_client import ApiError, Client, collect
+
+url, mode = sys.argv[1:3]
+client = Client(url, initial_backoff=0.01)
+if mode == "retry":
+    request = {
+        "workload_name": "hello",
+        "language": "python",
+        "code": "print('Hello, world!')",
+        "log_level": "info",
+        "action": "prepare-and-run",
+        "server": {"address": "localhost", "port": 50051},
+        "build": {"source-code-path": "main.py", "release": False},
+    }
+    output = collect(client.run(request))
+    assert output.success, output
+    assert output.stdout == b"Hello, world!\n", output.stdout
+    runs = client.list_runs(selector="team=infra", limit=5)
+    assert runs[0]["outcome"] == "finished", runs
+    assert client.server_info()["version"] == "0.1.0"
+    print("ok")
+else:
+    try:
+        list(client.invoke_workload("hello world", {"inputs": {"NAME": "cloudlet"}}))
+    except ApiError as e:
+        assert e.status == 503 and e.code == "CLDT-VMM-019" and e.retryable, e
+        print("ok")
@@ -0,0 +1,142 @@
+use api::{client::VmmEndpoint, jobs::JobQueue, listen, ListenArgs};
+use clap::Parser;
+use shared_models::logging::LogArgs;
+use std::path::PathBuf;
+use tracing::{info, level_filters::LevelFilter};
+#[cfg(feature = "all-in-one")]
+use {
+    shared_models::vmmorchestrator::vmm_service_server::VmmServiceServer,
+    tokio::sync::mpsc,
+    tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt},
+    tonic::transport::{Channel, Endpoint, Server, Uri},
+    tower::service_fn,
+    tracing::error,
+    vmm::grpc::{
+        config::Settings,
+        startup::{self, OrchestratorArgs},
+    },
+};
+
+/// Size of each in-memory pipe between the HTTP API and the orchestrator.
+#[cfg(feature = "all-in-one")]
+const IN_MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Run the Cloudlet control plane.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Run the HTTP API and the VMM orchestrator in the same process.
+    #[cfg(feature = "all-in-one")]
+    #[arg(long, env)]
+    all_in_one: bool,
+
+    /// Options of the orchestrator in all-in-one mode, those of `vmm grpc`.
+    #[cfg(feature = "all-in-one")]
+    #[command(flatten)]
+    vmm: OrchestratorArgs,
+
+    // The address of the orchestrator is ignored in all-in-one mode.
+    #[command(flatten)]
+    listen: ListenArgs,
+
+    /// Database queueing the runs while the orchestrator is unreachable, created if needed.
+    /// Without it, these runs fail. See the jobs module of the API.
+    #[arg(long, env)]
+    job_queue: Option<PathBuf>,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+/// Start the orchestrator gRPC server on in-memory pipes, configured like `vmm grpc`, and return
+/// a channel connected to it.
+#[cfg(feature = "all-in-one")]
+async fn in_memory_vmm_channel(
+    args: &OrchestratorArgs,
+) -> Result<Channel, Box<dyn std::error::Error>> {
+    // Each connection of the channel, the first one and those after the reconnections, gets a
+    // pipe of its own, whose server end is sent to the orchestrator.
+    let (connections, incoming) = mpsc::unbounded_channel();
+
+    // The orchestrator isn't reached through the network: its listen address is left unset.
+    let config = args.load_config(&args.options(None, None))?;
+    let settings = Settings::load(&config)?;
+    let service = startup::start(args, &config, &settings).await?.service;
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(service.clone().report_health(health_reporter));
+
+    tokio::spawn(async move {
+        let result = Server::builder()
+            .add_service(health_service)
+            .add_service(shared_models::compressed!(VmmServiceServer::from_arc(
+                service
+            )))
+            .serve_with_incoming(
+                UnboundedReceiverStream::new(incoming).map(Ok::<_, std::io::Error>),
+            )
+            .await;
+
+        if let Err(e) = result {
+            error!("In-memory orchestrator stopped: {:?}", e);
+        }
+    });
+
+    let channel = Endpoint::from_static("http://[::]:50051")
+        .connect_with_connector(service_fn(move |_: Uri| {
+            let (client_io, server_io) = tokio::io::duplex(IN_MEMORY_BUFFER_SIZE);
+            let connected = connections.send(server_io).map(|()| client_io);
+            async move {
+                connected.map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "the in-memory orchestrator stopped",
+                    )
+                })
+            }
+        }))
+        .await?;
+    Ok(channel)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    args.log.init(LevelFilter::INFO);
+    #[cfg(feature = "all-in-one")]
+    let all_in_one = args.all_in_one;
+    #[cfg(not(feature = "all-in-one"))]
+    let all_in_one = false;
+
+    info!(
+        app_name = env!("CARGO_PKG_NAME"),
+        app_version = env!("CARGO_PKG_VERSION"),
+        all_in_one,
+        "Starting application",
+    );
+
+    let listener = args.listen.listener();
+    listen::check_addresses(
+        &listener,
+        (!all_in_one).then_some(args.listen.vmm_address.as_str()),
+    )?;
+
+    #[cfg(feature = "all-in-one")]
+    let endpoint = if all_in_one {
+        VmmEndpoint::InMemory(in_memory_vmm_channel(&args.vmm).await?)
+    } else {
+        VmmEndpoint::Remote(args.listen.vmm_address)
+    };
+    #[cfg(not(feature = "all-in-one"))]
+    let endpoint = VmmEndpoint::Remote(args.listen.vmm_address);
+
+    let queue = match &args.job_queue {
+        Some(path) => Some(
+            JobQueue::open(path)
+                .map_err(|e| format!("Failed to open the job queue {:?}: {}", path, e))?,
+        ),
+        None => None,
+    };
+    api::serve(endpoint, listener, queue).await?;
+
+    Ok(())
+}
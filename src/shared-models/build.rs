@@ -0,0 +1,17 @@
+use std::{env, path::PathBuf};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("cloudlet_descriptor.bin"))
+        .compile(
+            &[
+                "../../proto/agent.proto",
+                "../../proto/vmm.proto",
+                "../../proto/admin.proto",
+            ],
+            &["../../proto"],
+        )?;
+    Ok(())
+}
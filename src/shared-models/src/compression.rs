@@ -0,0 +1,51 @@
+//! Compression of the gRPC messages between the API, the orchestrator and the agents.
+//!
+//! The servers accept gzip and zstd, and compress their responses with the encoding of
+//! `CLOUDLET_GRPC_COMPRESSION` (`zstd` by default, `gzip`, or `none`) when the client says it
+//! accepts it. The clients accept both and compress their requests the same way; a client of a
+//! server which doesn't accept the encoding, e.g. an agent of an older image, falls back to
+//! uncompressed requests for the rest of its connection.
+
+use std::sync::OnceLock;
+use tonic::{Code, Status};
+
+pub use tonic::codec::CompressionEncoding;
+
+pub const COMPRESSION_ENV: &str = "CLOUDLET_GRPC_COMPRESSION";
+
+/// Encoding the components compress their messages with, `None` when disabled.
+pub fn configured() -> Option<CompressionEncoding> {
+    static CONFIGURED: OnceLock<Option<CompressionEncoding>> = OnceLock::new();
+    *CONFIGURED.get_or_init(|| match std::env::var(COMPRESSION_ENV).as_deref() {
+        Err(_) | Ok("zstd") => Some(CompressionEncoding::Zstd),
+        Ok("gzip") => Some(CompressionEncoding::Gzip),
+        Ok("none") => None,
+        Ok(other) => {
+            eprintln!(
+                "Unknown {} `{}`, expected zstd, gzip or none: using zstd",
+                COMPRESSION_ENV, other
+            );
+            Some(CompressionEncoding::Zstd)
+        }
+    })
+}
+
+/// Whether the server refused a request because of its compression.
+pub fn is_unsupported_encoding(status: &Status) -> bool {
+    status.code() == Code::Unimplemented && status.message().contains("compressed with")
+}
+
+/// Accept both encodings on a generated gRPC client or server, and compress what it sends with
+/// the configured one.
+#[macro_export]
+macro_rules! compressed {
+    ($service:expr) => {{
+        let service = $service
+            .accept_compressed($crate::compression::CompressionEncoding::Zstd)
+            .accept_compressed($crate::compression::CompressionEncoding::Gzip);
+        match $crate::compression::configured() {
+            Some(encoding) => service.send_compressed(encoding),
+            None => service,
+        }
+    }};
+}
@@ -0,0 +1,180 @@
+//! User-facing error codes, shared by every component so that a failure can be
+//! traced from the agent or the VMM up to the CLI.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tonic::{Code, Status};
+
+macro_rules! error_codes {
+    ($($variant:ident => $code:literal, $hint:literal;)*) => {
+        /// Identifier of an error surfaced to users, e.g. `CLDT-VMM-001`.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum ErrorCode {
+            $($variant,)*
+        }
+
+        impl ErrorCode {
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(ErrorCode::$variant => $code,)*
+                }
+            }
+
+            /// What the user can do to fix the error.
+            pub fn hint(&self) -> &'static str {
+                match self {
+                    $(ErrorCode::$variant => $hint,)*
+                }
+            }
+
+            pub fn from_code(code: &str) -> Option<Self> {
+                match code {
+                    $($code => Some(ErrorCode::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
+    AgentInvalidConfig => "CLDT-AGT-001", "Check the `build` section of the workload.";
+    AgentInvalidLanguage => "CLDT-AGT-002", "Use one of the supported languages: rust, python, node.";
+    AgentBuildFailed => "CLDT-AGT-003", "The workload doesn't compile, see the build output above.";
+    AgentInvalidPath => "CLDT-AGT-004", "Give a path relative to the workload directory, e.g. `cloudlet fs ls <VM> .`.";
+    AgentShell => "CLDT-AGT-005", "Shells are only opened in the guests of a VMM started with --enable-exec-shell, whose image has the shell.";
+    AgentScrubFailed => "CLDT-AGT-006", "The warm VM couldn't be reset for the run and is replaced, invoke the workload again.";
+    VmmTapCreation => "CLDT-VMM-001", "Run the VMM with the CAP_NET_ADMIN capability, see the README.";
+    VmmKvmUnavailable => "CLDT-VMM-002", "Check that /dev/kvm exists and that the VMM user can open it.";
+    VmmConfigure => "CLDT-VMM-003", "Remove tools/kernel and tools/rootfs artifacts so they are rebuilt.";
+    VmmRun => "CLDT-VMM-004", "Check the VMM logs for the guest console output.";
+    VmmArtifactBuild => "CLDT-VMM-005", "Check that the kernel and rootfs build scripts in tools/ can run on the host.";
+    VmmAgentUnreachable => "CLDT-VMM-006", "The guest didn't start its agent, check the VMM logs for the guest console output.";
+    VmmInvalidRequest => "CLDT-VMM-007", "Update the API and the VMM to the same version.";
+    VmmShutdownFailed => "CLDT-VMM-008", "No VM answered the shutdown request, it may already be stopped.";
+    VmmUnknownVm => "CLDT-VMM-009", "Check the VM id or workload name, the output of older runs is not kept.";
+    VmmUnsupportedRuntime => "CLDT-VMM-010", "Pin one of the runtime versions listed by `cli info`, or none to use the default one.";
+    VmmDeviceUnavailable => "CLDT-VMM-011", "Pass the device to the VMM with `--gpu` or `--sriov-vf`, see the README.";
+    VmmDuplicateWorkload => "CLDT-VMM-012", "Wait for the running VM of this workload to stop, or rename the workload.";
+    VmmUnknownKernel => "CLDT-VMM-013", "Select one of the kernels listed by `cli info`, or none to use the default one.";
+    VmmIncompatibleKernel => "CLDT-VMM-014", "Select a kernel providing the missing features, or rebuild it with the matching options.";
+    VmmUnknownRun => "CLDT-VMM-015", "Only the runs started while the VMM records them with `--runs-dir` can be run again.";
+    VmmImageMismatch => "CLDT-VMM-016", "The rootfs image was rebuilt or pulled again since the run, restore it or run the spec instead.";
+    VmmHypervisor => "CLDT-VMM-017", "Check the --cloud-hypervisor binary, or the --qemu one with --emulation-fallback, and its output in the VMM logs; cloud-hypervisor needs a guest kernel with virtio-pci.";
+    VmmAdmissionDenied => "CLDT-VMM-018", "The admission policy of the orchestrator rejected the request, fit it to the policy or ask its operator.";
+    VmmHostFull => "CLDT-VMM-019", "The host has no room for the guest, retry later or with a higher priority class.";
+    VmmPreempted => "CLDT-VMM-020", "A run of a higher priority class needed the host, run the workload again or with a higher priority class.";
+    VmmUnknownPriorityClass => "CLDT-VMM-021", "Select one of the priority classes of the orchestrator scheduler configuration, or none for its default class.";
+    VmmConfigReload => "CLDT-VMM-022", "Start the VMM with --config to reload it, and fix the errors of the file listed in the message.";
+    VmmFaultInjection => "CLDT-VMM-023", "Faults are injected through AdminService/InjectFaults for resilience testing, clear them with an empty request; the VMM must be built with the fault-injection feature to inject them.";
+    VmmBuildNotCached => "CLDT-VMM-024", "Build the workload with `cli build` first, on a VMM caching the builds with `--builds-dir`, or run it with its build stage.";
+    VmmUnknownWorkload => "CLDT-VMM-025", "Register the workload with `cli register` first, on a VMM keeping them with `--workloads-dir`; `cli workloads` lists them.";
+    VmmDuplicateVersion => "CLDT-VMM-026", "A registered version can't be replaced, register the workload under a new version.";
+    VmmUnknownArtifact => "CLDT-VMM-027", "Artifacts are only collected from workloads killed by a signal, and kept for the recent runs or in the storage of --storage; `cli artifacts <vm-id>` lists those of a run.";
+    VmmExecShellDisabled => "CLDT-VMM-028", "Start the VMM with --enable-exec-shell to open shells in the guests, for debugging only.";
+    VmmTenantNetworksExhausted => "CLDT-VMM-029", "Each tenant keeps its isolated network until the VMM restarts, and a VMM has 255 of them: spread the tenants over more VMMs, or restart this one.";
+    VmmTaskPanicked => "CLDT-VMM-030", "A task of the orchestrator panicked, which is a bug: report it with the VMM logs around the panic.";
+    VmmArtifactSignature => "CLDT-VMM-031", "The kernel or the rootfs image isn't signed by a trusted key: sign it with `fs-gen sign`, or prune it through AdminService/PruneArtifacts for the orchestrator to pull or build it again.";
+    VmmInvalidLabels => "CLDT-VMM-032", "Labels are key=value pairs of letters, digits, '-', '_', '.' and '/', and selectors lists of key=value, key!=value, key or !key separated by commas.";
+    VmmAgentIncompatible => "CLDT-VMM-033", "Rebuild the rootfs image of the workload with `fs-gen` to update its agent, or update the VMM to the version of the agent.";
+    VmmDeadlineExceeded => "CLDT-VMM-034", "The run took longer than its timeout, or than the --max-run-duration of the VMM, and its VM was destroyed: raise the timeout of the spec, or find where the workload hangs.";
+    VmmCapabilityUnsupported => "CLDT-VMM-035", "Remove the capabilities listed from the spec, or run it on a VMM which provides them: `network-egress` needs a default route on the host, `shared-volume` the --pmem-dir of the VMM, `gpu` a --gpu, `sriov-nic` a --sriov-vf with --cloud-hypervisor, and the admission policy of the VMM must allow them.";
+    VmmNodeMismatch => "CLDT-VMM-036", "Relax the `node-affinity.required` selector of the spec, or run it on a VMM whose --node-label match it, listed by `cli doctor`.";
+    VmmNodeCordoned => "CLDT-VMM-037", "The VMM is cordoned for maintenance: run the workload on another VMM, or uncordon it with `cli node uncordon`.";
+    VmmGuestAddressesExhausted => "CLDT-VMM-038", "Each running guest has an address of its own on its network, and every address is taken: stop some VMs, or spread the runs over more VMMs.";
+    VmmKernelCorrupted => "CLDT-VMM-039", "The image of the configured kernel changed since its first boot: restore it, or, if it was replaced on purpose, give it a new path or remove its entry from tools/artifacts.json.";
+    ApiInvalidRequest => "CLDT-API-001", "Fix the fields listed in the error and retry.";
+    ApiOrchestratorUnavailable => "CLDT-API-002", "Start the VMM with `vmm grpc`, or the API with `cloudlet-server --all-in-one`.";
+    ApiUnknownSchedule => "CLDT-API-003", "Check the name of the schedule with `cli schedule list`, they are lost when the API restarts.";
+    ApiDuplicateSchedule => "CLDT-API-004", "Update the existing schedule with `cli schedule update`, or delete it first.";
+    ApiWorkloadFailed => "CLDT-API-005", "The function failed, the details of the error hold the end of its stderr.";
+    ApiInvalidOutput => "CLDT-API-006", "Write the result of the function on its stdout, as JSON unless another type is accepted, 1 MiB at most; log on its stderr.";
+    ApiUnknownFunction => "CLDT-API-007", "Call the function through `/fn/{name}` first, its metrics are lost when the API restarts.";
+    ApiUnknownJob => "CLDT-API-008", "Check the id of the job with `cli jobs list`, the API keeps the last 100 finished jobs.";
+    ApiJobQueue => "CLDT-API-009", "Start the API with --job-queue to queue the runs while the orchestrator is unreachable, and check that its file is writable.";
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ErrorCode {
+    /// Build a gRPC status carrying this code in its details.
+    pub fn status(self, code: Code, message: impl Into<String>) -> Status {
+        Status::with_details(
+            code,
+            message,
+            bytes::Bytes::from_static(self.as_str().as_bytes()),
+        )
+    }
+
+    /// Return the code carried by `status`, if it was built by [`ErrorCode::status`].
+    pub fn from_status(status: &Status) -> Option<Self> {
+        std::str::from_utf8(status.details())
+            .ok()
+            .and_then(Self::from_code)
+    }
+}
+
+/// Body of the HTTP API error responses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletErrorResponse {
+    /// Error code such as `CLDT-VMM-001`, absent for unexpected errors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    /// Details about the error, such as the invalid fields of a request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<String>,
+}
+
+impl CloudletErrorResponse {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code: Some(code.as_str().to_string()),
+            message: message.into(),
+            hint: Some(code.hint().to_string()),
+            details: Vec::new(),
+        }
+    }
+
+    pub fn with_details(mut self, details: Vec<String>) -> Self {
+        self.details = details;
+        self
+    }
+
+    /// Convert a gRPC status, keeping its error code when it has one.
+    pub fn from_status(status: &Status) -> Self {
+        match ErrorCode::from_status(status) {
+            Some(code) => Self::new(code, status.message()),
+            None => Self {
+                code: None,
+                message: status.message().to_string(),
+                hint: None,
+                details: Vec::new(),
+            },
+        }
+    }
+}
+
+impl std::error::Error for CloudletErrorResponse {}
+
+impl fmt::Display for CloudletErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(code) = &self.code {
+            write!(f, "[{}] ", code)?;
+        }
+        write!(f, "{}", self.message)?;
+        for detail in self.details.iter() {
+            write!(f, "\n  - {}", detail)?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, "\nhint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
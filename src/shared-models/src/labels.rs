@@ -0,0 +1,136 @@
+//! Labels of the runs, `key=value` pairs set by their clients to manage the fleet, and the
+//! selectors matching them, e.g. `team=infra,env!=staging,gpu`.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+/// Length of the keys and the values of the labels, at most.
+pub const MAX_LABEL_LENGTH: usize = 63;
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/')
+}
+
+/// Check a label: a non-empty key and a value of letters, digits, `-`, `_`, `.` and `/`, of
+/// [`MAX_LABEL_LENGTH`] characters at most.
+pub fn validate_label(key: &str, value: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err("the key of a label can't be empty".to_string());
+    }
+    for (what, text) in [("key", key), ("value", value)] {
+        if text.len() > MAX_LABEL_LENGTH {
+            return Err(format!(
+                "the {} `{}` is longer than {} characters",
+                what, text, MAX_LABEL_LENGTH
+            ));
+        }
+        if !text.chars().all(is_label_char) {
+            return Err(format!(
+                "the {} `{}` can only contain letters, digits, '-', '_', '.' and '/'",
+                what, text
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Parse a `key=value` label, as given on a command line.
+pub fn parse_label(label: &str) -> Result<(String, String), String> {
+    let (key, value) = label
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` isn't a key=value label", label))?;
+    validate_label(key, value)?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    /// `key=value`
+    Equals(String, String),
+    /// `key!=value`, also met by the runs without the label.
+    NotEquals(String, String),
+    /// `key`
+    Exists(String),
+    /// `!key`
+    NotExists(String),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        match self {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::NotExists(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Requirement::Equals(key, value) => write!(f, "{}={}", key, value),
+            Requirement::NotEquals(key, value) => write!(f, "{}!={}", key, value),
+            Requirement::Exists(key) => write!(f, "{}", key),
+            Requirement::NotExists(key) => write!(f, "!{}", key),
+        }
+    }
+}
+
+/// Requirements separated by commas, all of which the labels must meet. The empty selector
+/// matches everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LabelSelector {
+    pub requirements: Vec<Requirement>,
+}
+
+impl LabelSelector {
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.requirements
+            .iter()
+            .all(|requirement| requirement.matches(labels))
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = String;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let requirements = selector
+            .split(',')
+            .map(str::trim)
+            .filter(|requirement| !requirement.is_empty())
+            .map(|requirement| {
+                let parsed = if let Some((key, value)) = requirement.split_once("!=") {
+                    Requirement::NotEquals(key.to_string(), value.to_string())
+                } else if let Some((key, value)) = requirement.split_once('=') {
+                    Requirement::Equals(key.to_string(), value.to_string())
+                } else if let Some(key) = requirement.strip_prefix('!') {
+                    Requirement::NotExists(key.to_string())
+                } else {
+                    Requirement::Exists(requirement.to_string())
+                };
+                let (key, value) = match &parsed {
+                    Requirement::Equals(key, value) | Requirement::NotEquals(key, value) => {
+                        (key, value.as_str())
+                    }
+                    Requirement::Exists(key) | Requirement::NotExists(key) => (key, ""),
+                };
+                validate_label(key, value)
+                    .map(|()| parsed)
+                    .map_err(|e| format!("invalid selector `{}`: {}", requirement, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { requirements })
+    }
+}
+
+impl fmt::Display for LabelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let requirements: Vec<String> = self.requirements.iter().map(|r| r.to_string()).collect();
+        write!(f, "{}", requirements.join(","))
+    }
+}
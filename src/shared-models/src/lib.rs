@@ -1,9 +1,27 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, fmt, path::PathBuf};
 
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, ValueEnum, Deserialize, Serialize)]
+pub mod compression;
+mod errors;
+pub mod labels;
+#[cfg(feature = "logging")]
+pub mod logging;
+mod proto;
+mod redact;
+pub mod unix_socket;
+pub mod validation;
+
+pub use errors::{CloudletErrorResponse, ErrorCode};
+pub use proto::{
+    agent_capabilities, cloudlet, vmmorchestrator, ConversionError, AGENT_MAX_MESSAGE_SIZE,
+    AGENT_PORT_PARAMETER, AGENT_PROTOCOL_VERSION, DEFAULT_AGENT_PORT, EXEC_SHELL_PARAMETER,
+    FILE_DESCRIPTOR_SET, MIN_AGENT_PROTOCOL_VERSION, OUTPUT_ARTIFACT_PREFIX,
+};
+pub use redact::{redact_env, Redactor, REDACTED};
+
+#[derive(Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Language {
     RUST,
@@ -20,6 +38,49 @@ pub enum LogLevel {
     ERROR,
 }
 
+/// Stage of the pipeline of a workload, which can be requested on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PipelineStage {
+    FetchDeps,
+    Build,
+    Run,
+}
+
+/// Host device which can be assigned to a guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    GPU,
+    /// SR-IOV virtual function of a host NIC.
+    #[serde(rename = "sriov-nic")]
+    SriovNic,
+}
+
+/// What a guest may need from the server, checked before its VM is provisioned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    NetworkEgress,
+    ArtifactOutput,
+    SharedVolume,
+    Gpu,
+    SriovNic,
+}
+
+/// Nodes a run is placed on, by their labels, each selector in the syntax of
+/// [`labels::LabelSelector`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NodeAffinity {
+    /// Selector the node must match, e.g. `gpu=a100`.
+    #[serde(default)]
+    pub required: Option<String>,
+    /// Selectors the node would rather match, the first ones first.
+    #[serde(default)]
+    pub preferred: Vec<String>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TomlClientConfigFile {
     pub worklaod_name: String,
@@ -28,7 +89,7 @@ pub struct TomlClientConfigFile {
     pub log_level: LogLevel,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct CloudletDtoRequest {
     pub workload_name: String,
     pub language: Language,
@@ -37,6 +98,152 @@ pub struct CloudletDtoRequest {
     pub action: String,
     pub server: ServerConfig,
     pub build: BuildConfig,
+    #[serde(default)]
+    pub resources: Resources,
+    /// Host devices assigned to the guest.
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    /// Capabilities the run needs, refused before provisioning if the server lacks one.
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// Labels of the nodes the run must, or would rather, run on.
+    #[serde(default)]
+    pub node_affinity: NodeAffinity,
+    /// Name of the guest kernel, the default one of the server if unset.
+    #[serde(default)]
+    pub kernel: Option<String>,
+    /// Priority class of the guest, the default one of the server if unset.
+    #[serde(default)]
+    pub priority_class: Option<String>,
+    /// Tenant the resources consumed by the run are accounted to, `default` if unset.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Labels of the run, to select it among the runs and the VMs.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Environment variables given to the workload.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Names of the variables of `env` whose values are redacted from the output and the logs.
+    #[serde(default)]
+    pub secret_env: Vec<String>,
+    /// Stages of the pipeline to go through, all of them if empty.
+    #[serde(default)]
+    pub stages: Vec<PipelineStage>,
+    /// Notified when the run finishes or fails.
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    /// Keep the VM up when the run fails, to inspect its files with `cloudlet fs`.
+    #[serde(default)]
+    pub keep_on_failure: bool,
+    /// Wall-clock time in seconds the run may take before its VM is destroyed, the limit of the
+    /// server if unset.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// What gave the language away, when the client detected it: the API checks that the code
+    /// doesn't contradict it.
+    #[serde(default)]
+    pub language_detection: Option<String>,
+}
+
+impl fmt::Debug for CloudletDtoRequest {
+    /// Same as a derived implementation, except for the values of the secret variables.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut env = self.env.clone();
+        redact_env(env.iter_mut(), &self.secret_env);
+
+        f.debug_struct("CloudletDtoRequest")
+            .field("workload_name", &self.workload_name)
+            .field("language", &self.language)
+            .field("code", &self.code)
+            .field("log_level", &self.log_level)
+            .field("action", &self.action)
+            .field("server", &self.server)
+            .field("build", &self.build)
+            .field("resources", &self.resources)
+            .field("devices", &self.devices)
+            .field("capabilities", &self.capabilities)
+            .field("node_affinity", &self.node_affinity)
+            .field("kernel", &self.kernel)
+            .field("priority_class", &self.priority_class)
+            .field("tenant", &self.tenant)
+            .field("labels", &self.labels)
+            .field("env", &env)
+            .field("secret_env", &self.secret_env)
+            .field("stages", &self.stages)
+            .field("webhooks", &self.webhooks)
+            .field("keep_on_failure", &self.keep_on_failure)
+            .field("timeout_secs", &self.timeout_secs)
+            .field("language_detection", &self.language_detection)
+            .finish()
+    }
+}
+
+/// Endpoint notified with a JSON payload when a run finishes or fails.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature of the payloads, sent unsigned without one.
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+impl fmt::Debug for Webhook {
+    /// Same as a derived implementation, except for the secret.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url)
+            .field("secret", &self.secret.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+/// Resources assigned to the guest running a workload.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Resources {
+    /// Number of virtual CPUs assigned to the guest.
+    pub cpus: u8,
+    /// Memory amount (in MBytes) assigned to the guest.
+    pub memory_mb: u32,
+    /// Throttling of the IO of the guest.
+    #[serde(default, skip_serializing_if = "IoLimits::is_unset")]
+    pub io: IoLimits,
+    /// Space (in MBytes) the files written by a run under the workload directory of the guest
+    /// may take, a quarter of its memory if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmp_quota_mb: Option<u32>,
+}
+
+impl Default for Resources {
+    fn default() -> Self {
+        Self {
+            cpus: 1,
+            memory_mb: 4000,
+            io: IoLimits::default(),
+            tmp_quota_mb: None,
+        }
+    }
+}
+
+/// Rates the IO of the guest is throttled to, each 0 for the limit of the server, which also
+/// caps them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct IoLimits {
+    /// Bytes per second the guest sends over its network interface, and receives.
+    pub net_bytes_per_sec: u64,
+    /// Frames per second the guest sends, and receives.
+    pub net_packets_per_sec: u64,
+    /// Flushes of the disk per second.
+    pub disk_flushes_per_sec: u64,
+}
+
+impl IoLimits {
+    pub fn is_unset(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -44,17 +251,526 @@ pub struct CloudletShutdownResponse {
     pub success: bool,
 }
 
+/// What the server would do to run a request, returned by a dry run.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletPlanResponse {
+    pub image: String,
+    pub kernel: ArtifactPlan,
+    pub initramfs: ArtifactPlan,
+    pub resources: Resources,
+    /// Whether an invocation of the registered workload would find an idle VM in its pool.
+    #[serde(default)]
+    pub warm_pool_hit: bool,
+    /// Whether the build of the workload is cached, its fetch and build skipped.
+    #[serde(default)]
+    pub build_cached: bool,
+    /// Whether the rootfs image would be pulled from the registry.
+    #[serde(default)]
+    pub registry_pull: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+pub struct ArtifactPlan {
+    pub path: String,
+    /// Whether the artifact is already available or has to be built first.
+    pub cached: bool,
+}
+
+/// Inputs recorded for a past run, which can be run again identically.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletRunInputs {
+    pub run_id: String,
+    pub workload_name: String,
+    /// Seconds since the Unix epoch.
+    pub recorded_at: u64,
+    pub code_digest: String,
+    pub env_digest: String,
+    pub image_digest: String,
+    pub kernel: String,
+}
+
+/// File collected to debug a run, e.g. the core dump of a workload killed by a signal.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletRunArtifact {
+    /// `core` or `backtrace`.
+    pub name: String,
+    /// Size of the file in the guest.
+    pub size: u64,
+    /// Whether the agent cut the file at its cap.
+    pub truncated: bool,
+}
+
+/// Entry of a directory of the workload, listed in a running VM.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletDirEntry {
+    pub name: String,
+    /// `file`, `directory`, `symlink` or `other`.
+    pub kind: String,
+    pub size: u64,
+    /// Permission bits, e.g. 0o755.
+    pub mode: u32,
+    /// Last modification, in seconds since the Unix epoch.
+    pub modified: u64,
+    /// Where a symlink points to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// Directory of the workload, listed in a running VM.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletDirListing {
+    pub entries: Vec<CloudletDirEntry>,
+    /// Whether the directory has more entries than the agent lists.
+    pub truncated: bool,
+}
+
+/// Control message of a shell WebSocket, `/vms/{vm}/shell`, sent as text besides the binary
+/// frames carrying the keystrokes and the output of the terminal.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloudletShellMessage {
+    /// From the client, first: the command to run, `/bin/sh` if empty, and its terminal.
+    Start {
+        #[serde(default)]
+        command: Vec<String>,
+        rows: u32,
+        cols: u32,
+        #[serde(default)]
+        term: String,
+    },
+    /// From the client: the new size of its terminal.
+    Resize { rows: u32, cols: u32 },
+    /// From the API, last: the shell exited.
+    Exit { exit_code: i32 },
+    /// From the API, last: the shell could not be opened, or was lost.
+    Error { message: String },
+}
+
+/// How the VM of a run stopped, on the final event of the runs whose VM doesn't outlive them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletVmExit {
+    /// `guest-shutdown`, `guest-crash`, `killed`, `host-error` or `unknown`.
+    pub reason: String,
+    /// What happened, e.g. `the guest crashed: triple fault`.
+    pub message: String,
+}
+
+/// Workload run by the server on a cron schedule.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletScheduleRequest {
+    /// Name of the schedule, given by the path instead when it is updated.
+    #[serde(default)]
+    pub name: String,
+    /// When to run the workload, `minute hour day-of-month month day-of-week` in UTC.
+    pub cron: String,
+    /// Whether the workload is run, or the schedule only kept.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub request: CloudletDtoRequest,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Schedule of a workload, and the results of its last runs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletSchedule {
+    pub name: String,
+    pub cron: String,
+    pub enabled: bool,
+    pub workload_name: String,
+    /// Seconds since the Unix epoch, none while the schedule is disabled.
+    pub next_run: Option<u64>,
+    /// The most recent first.
+    pub runs: Vec<CloudletScheduledRun>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletScheduledRun {
+    /// Seconds since the Unix epoch.
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    /// `running`, `done`, or `failed` when the run failed or exited with an error.
+    pub outcome: String,
+    pub exit_code: Option<i32>,
+    /// Why the run failed, when it couldn't run to its end.
+    #[serde(default)]
+    pub message: String,
+}
+
+/// Header of the run requests which must fail rather than be queued while the orchestrator is
+/// unreachable, with the value `never`, for the clients which need the output of the run.
+pub const QUEUE_HEADER: &str = "Cloudlet-Queue";
+
+/// Run accepted by the API while the orchestrator was unreachable, started once it is back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CloudletJob {
+    pub id: u64,
+    pub workload_name: String,
+    /// `queued`, `running`, `done`, or `failed` when the run failed or exited with an error.
+    pub state: String,
+    /// Seconds since the Unix epoch.
+    pub queued_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    /// Attempts made to start the run, the orchestrator being unreachable for the failed ones.
+    pub attempts: u32,
+    pub exit_code: Option<i32>,
+    /// Why the run failed, when it couldn't run to its end.
+    #[serde(default)]
+    pub message: String,
+}
+
+/// Jobs of the queue of the API, the oldest first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletJobQueue {
+    /// Jobs waiting for the orchestrator.
+    pub depth: usize,
+    pub jobs: Vec<CloudletJob>,
+}
+
+/// Result of a drain of the queue: the jobs started, and those left queued.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletDrainResponse {
+    pub started: usize,
+    pub depth: usize,
+}
+
+/// Steps run by the API, each on a VM of its own once the steps it depends on succeeded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletPipelineRequest {
+    /// Named like a workload.
+    pub name: String,
+    pub steps: Vec<CloudletPipelineStep>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletPipelineStep {
+    /// Named like a workload, unique in the pipeline.
+    pub name: String,
+    /// Steps which must succeed before this one starts.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Files of the workload directory, e.g. `target/app`, given to the steps depending on this
+    /// one under `<inputs directory>/<step>/<path>`.
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    pub request: CloudletDtoRequest,
+}
+
+/// Outcome of the steps of a pipeline, on the final event of its stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletPipelineSummary {
+    pub succeeded: bool,
+    /// In the order they finished, the skipped steps last.
+    pub steps: Vec<CloudletStepResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletStepResult {
+    pub name: String,
+    /// `succeeded`, `failed`, or `skipped` when a step it depends on didn't succeed.
+    pub outcome: String,
+    pub exit_code: Option<i32>,
+    /// Why the step failed or was skipped.
+    #[serde(default)]
+    pub message: String,
+}
+
+/// Workload kept by the server under a name, its `workload_name`, and a version.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletRegisterRequest {
+    /// Letters, digits, `.`, `-` and `_`. A registered version can't be replaced.
+    pub version: String,
+    pub request: CloudletDtoRequest,
+    /// VMs kept warm while the workload is invoked, the default of the server if unset.
+    #[serde(default)]
+    pub min_instances: Option<u32>,
+    /// VMs running the workload at most, the default of the server if unset.
+    #[serde(default)]
+    pub max_instances: Option<u32>,
+    /// Seconds the result of a call is returned again to the calls with the same input, for a
+    /// pure workload whose result only depends on its input. Not cached if unset.
+    #[serde(default)]
+    pub cache_ttl_secs: Option<u64>,
+}
+
+/// Registered workload, without its code and configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletWorkload {
+    pub name: String,
+    pub version: String,
+    /// Seconds since the Unix epoch.
+    pub registered_at: u64,
+    pub code_digest: String,
+    /// Seconds the results of its calls are cached, if they are.
+    #[serde(default)]
+    pub cache_ttl_secs: Option<u64>,
+}
+
+/// Run of a registered workload.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CloudletInvokeRequest {
+    /// The version registered last if unset.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Variables added to the environment of the workload, replacing the registered ones.
+    #[serde(default)]
+    pub inputs: BTreeMap<String, String>,
+}
+
+/// Pool of VMs of a registered workload, on the orchestrator.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletPool {
+    pub name: String,
+    /// Version started by the scale ups, the one invoked last.
+    pub version: String,
+    pub min_instances: u32,
+    pub max_instances: u32,
+    pub instances: u32,
+    /// Instances running an invocation.
+    pub busy: u32,
+    /// Invocations running or waiting for an instance.
+    pub in_flight: u32,
+    pub queued: u32,
+    pub cold_starts: u64,
+    pub warm_starts: u64,
+    pub scale_ups: u64,
+    pub scale_downs: u64,
+    /// Seconds since the Unix epoch, 0 if never invoked.
+    pub last_invoked_at: u64,
+    /// Generation of the rootfs image the new VMs boot, incremented by each upgrade.
+    pub generation: u64,
+    /// Instances still running a VM of an earlier generation.
+    pub outdated: u32,
+    /// Upgrades finished since the orchestrator started.
+    pub upgrades: u64,
+    /// Seconds since the Unix epoch the upgrade in progress started at, 0 if none is.
+    pub upgrade_started_at: u64,
+}
+
+/// Cordon or drain of the node of the VMM, for the maintenance of its host.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CloudletNodeRequest {
+    /// Why the node is cordoned, `maintenance` if empty.
+    #[serde(default)]
+    pub reason: String,
+    /// Seconds a drain waits for the VMs to stop, 0 to wait for as long as they run.
+    #[serde(default)]
+    pub timeout_secs: u32,
+    /// Kill the VMs still running once the drain timed out.
+    #[serde(default)]
+    pub kill: bool,
+}
+
+/// Whether the node of the VMM is cordoned, and the VMs it still runs.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletNodeStatus {
+    pub cordoned: bool,
+    pub reason: String,
+    /// Seconds since the Unix epoch, 0 if not cordoned.
+    pub cordoned_at: u64,
+    pub running_vms: Vec<String>,
+    /// VMs killed by a drain.
+    #[serde(default)]
+    pub killed_vms: Vec<String>,
+}
+
+/// Pools whose VMs to replace by VMs of the current rootfs image, all of them if nothing is set.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CloudletUpgradePoolsRequest {
+    /// Only the pool of this workload.
+    #[serde(default)]
+    pub name: String,
+    /// Only the pools of the workloads of this language.
+    #[serde(default)]
+    pub language: String,
+    /// Only the pools of the workloads pinning this runtime version, empty for the default one.
+    #[serde(default)]
+    pub runtime_version: Option<String>,
+}
+
+/// Running VM, with its resources and what it consumed so far.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletVmMetrics {
+    pub vm_id: String,
+    pub workload_name: String,
+    pub language: String,
+    pub cpus: u32,
+    pub memory_mb: u32,
+    /// Seconds since the Unix epoch.
+    pub started_at: u64,
+    /// Host CPU time consumed by the vCPUs, 0 if the hypervisor doesn't report it.
+    pub cpu_seconds: f64,
+    pub egress_bytes: u64,
+    pub priority_class: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Run of the history of the orchestrator, the most recent ones being kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletRunRecord {
+    /// Id of the VM of the run.
+    pub run_id: String,
+    pub workload_name: String,
+    pub tenant: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Seconds since the Unix epoch.
+    pub started_at: u64,
+    /// 0 while the run goes on.
+    pub finished_at: u64,
+    /// `running`, `finished`, `failed`, or `retried` when an infrastructure error ended it.
+    pub outcome: String,
+    pub message: String,
+    /// The guest of the run was emulated, without KVM.
+    #[serde(default)]
+    pub emulated: bool,
+}
+
+/// Resources consumed by a VM of a tenant, from its start to its stop.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletUsageRecord {
+    pub vm_id: String,
+    pub tenant: String,
+    pub workload_name: String,
+    /// Seconds since the Unix epoch.
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub cpus: u32,
+    pub memory_mb: u32,
+    pub vcpu_seconds: f64,
+    pub memory_gb_seconds: f64,
+    pub egress_bytes: u64,
+    pub build_seconds: f64,
+}
+
+/// Resources consumed by the VMs of a tenant.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletTenantUsage {
+    pub tenant: String,
+    pub vms: u64,
+    pub vcpu_seconds: f64,
+    pub memory_gb_seconds: f64,
+    pub egress_bytes: u64,
+    pub build_seconds: f64,
+}
+
+/// Export of the usage of the tenants.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloudletUsage {
+    pub tenants: Vec<CloudletTenantUsage>,
+    /// Records of the VMs, only if requested.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub records: Vec<CloudletUsageRecord>,
+}
+
+/// Calls of a registered workload through the gateway of the API, since it started.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CloudletFunctionMetrics {
+    pub name: String,
+    pub invocations: u64,
+    /// Calls which didn't return a result.
+    pub failures: u64,
+    pub in_flight: u64,
+    /// Most calls in flight at the same time.
+    pub peak_in_flight: u64,
+    /// Calls which started a VM, and calls which reused a running one.
+    pub cold_starts: u64,
+    pub warm_starts: u64,
+    pub mean_latency_ms: u64,
+    pub max_latency_ms: u64,
+}
+
+/// Description of the server and of the runtimes it provides.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletServerInfo {
+    pub version: String,
+    pub runtimes: Vec<RuntimeVersions>,
+    /// Kernels which can be selected with `kernel`.
+    #[serde(default)]
+    pub kernels: Vec<KernelInfo>,
+    #[serde(default)]
+    pub default_kernel: String,
+    /// `builtin`, `cloud-hypervisor`, or `qemu-tcg` when the guests are emulated.
+    #[serde(default)]
+    pub hypervisor: String,
+    /// Why the server can't run workloads, empty if it can.
+    #[serde(default)]
+    pub problems: Vec<String>,
+    /// Capabilities the server provides, whatever its admission policy allows.
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// Host interface the guests reach the outside through, empty if any.
+    #[serde(default)]
+    pub uplink: String,
+    /// SR-IOV virtual functions the server passes through, and those not in use.
+    #[serde(default)]
+    pub sriov_vfs: u32,
+    #[serde(default)]
+    pub sriov_vfs_available: u32,
+    /// Labels of the node, matched by the node affinity of the runs.
+    #[serde(default)]
+    pub node_labels: BTreeMap<String, String>,
+    /// Why the node is cordoned for maintenance, empty if it takes runs.
+    #[serde(default)]
+    pub cordoned: String,
+    /// The guests are emulated without KVM: the runs are much slower.
+    #[serde(default)]
+    pub emulated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KernelInfo {
+    pub name: String,
+    pub version: String,
+    /// Drivers and options of the kernel, empty if unknown.
+    pub features: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RuntimeVersions {
+    pub language: Language,
+    /// Versions which can be pinned with `build.runtime-version`.
+    pub versions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerConfig {
     pub address: String,
     pub port: u16,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BuildConfig {
     #[serde(rename = "source-code-path")]
     pub source_code_path: PathBuf,
     pub release: bool,
+    /// Features of the workload to enable.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Extra flags passed to the compiler.
+    #[serde(default, rename = "compiler-flags")]
+    pub compiler_flags: Vec<String>,
+    /// Version of the language runtime or toolchain, the default one of the image if unset.
+    #[serde(default, rename = "runtime-version")]
+    pub runtime_version: Option<String>,
+}
+
+/// Metrics of a rootfs image generated by fs-gen, written to its `--metrics-file`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RootfsBuildMetrics {
+    /// Compressed bytes of the layers downloaded from the registry.
+    pub download_bytes: u64,
+    pub layers: u32,
+    /// Time spent downloading and extracting the layers, streamed into each other.
+    pub extract_secs: f64,
+    /// Time spent packing the rootfs into the image.
+    pub pack_secs: f64,
+    /// Time of the whole generation, the customization of the rootfs included.
+    pub total_secs: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
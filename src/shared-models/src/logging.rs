@@ -0,0 +1,90 @@
+//! Logs of the binaries, on `tracing`. Each writes them to stderr, leaving stdout to what it
+//! outputs, either as text (`--log-format pretty`) or as one JSON object per line (`--log-format
+//! json`), with the fields of the events and of their spans.
+//!
+//! The logs are filtered by the default level of the binary, and by the per-module directives
+//! of `--log`, or else of `RUST_LOG`, e.g. `warn,vmm::grpc::scheduler=debug`.
+
+use clap::{Args, ValueEnum};
+use tracing::Subscriber;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Lines of text, colored on a terminal.
+    #[default]
+    Pretty,
+    /// One JSON object per line.
+    Json,
+}
+
+fn parse_directives(directives: &str) -> Result<String, String> {
+    EnvFilter::builder()
+        .parse(directives)
+        .map(|_| directives.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Options of the logs, flattened into the arguments of each binary.
+#[derive(Args, Debug, Clone, Default)]
+pub struct LogArgs {
+    /// Format of the logs, written to stderr.
+    #[arg(
+        long,
+        env = "CLOUDLET_LOG_FORMAT",
+        value_enum,
+        default_value_t,
+        global = true
+    )]
+    pub log_format: LogFormat,
+
+    /// Directives filtering the logs, e.g. `warn,vmm::grpc::scheduler=debug`, on top of the
+    /// default level. Those of `RUST_LOG` if unset.
+    #[arg(
+        long = "log",
+        env = "CLOUDLET_LOG",
+        value_name = "DIRECTIVES",
+        value_parser = parse_directives,
+        global = true
+    )]
+    pub log_directives: Option<String>,
+}
+
+impl LogArgs {
+    /// Filter of the logs, keeping those from `default` up for the modules without directives.
+    pub fn filter(&self, default: LevelFilter) -> EnvFilter {
+        let builder = EnvFilter::builder().with_default_directive(default.into());
+        match &self.log_directives {
+            Some(directives) => builder.parse_lossy(directives),
+            None => builder.from_env_lossy(),
+        }
+    }
+
+    /// Layer writing the logs to stderr, in the format of `--log-format`.
+    pub fn layer<S>(&self) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+        match self.log_format {
+            LogFormat::Pretty => layer.boxed(),
+            LogFormat::Json => layer
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+                .boxed(),
+        }
+    }
+
+    /// Install the logger of the binary, `default` being its level without directives.
+    pub fn init(&self, default: LevelFilter) {
+        tracing_subscriber::registry()
+            .with(self.filter(default))
+            .with(self.layer())
+            .init();
+    }
+}
@@ -0,0 +1,303 @@
+//! Protobuf definitions shared by every component, and conversions
+//! between them and the types exchanged over the HTTP API.
+
+use crate::{Capability, Device, Language, LogLevel, NodeAffinity, PipelineStage, Webhook};
+use std::fmt;
+
+pub mod cloudlet {
+    pub mod agent {
+        tonic::include_proto!("cloudlet.agent");
+    }
+}
+
+pub mod vmmorchestrator {
+    tonic::include_proto!("vmmorchestrator");
+
+    pub mod admin {
+        tonic::include_proto!("vmmorchestrator.admin");
+    }
+}
+
+/// Encoded descriptors of every service above, served through gRPC reflection.
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("cloudlet_descriptor");
+
+/// Largest message exchanged with the agent, which sends and receives the built workloads
+/// whole.
+pub const AGENT_MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Port the agent listens on in the guest by default.
+pub const DEFAULT_AGENT_PORT: u16 = 50051;
+
+/// Kernel parameter telling the init of the guest the port the agent listens on, when it isn't
+/// the default one.
+pub const AGENT_PORT_PARAMETER: &str = "cloudlet.agent_port";
+
+/// Kernel parameter letting the agent of the guest open shells, set to `1`.
+pub const EXEC_SHELL_PARAMETER: &str = "cloudlet.exec_shell";
+
+/// Version of the protocol between the orchestrator and the agents, raised with the changes
+/// which an older peer would misread.
+pub const AGENT_PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version of the protocol the orchestrator and the agents still speak.
+pub const MIN_AGENT_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features of the agents, announced in their answer to `Hello`.
+pub mod agent_capabilities {
+    /// Accepts compressed requests.
+    pub const COMPRESSION: &str = "compression";
+    /// Writes the `stdin` of the requests to the workloads.
+    pub const STDIN: &str = "stdin";
+    /// Writes the `inputs` of the requests and sends their `outputs` back.
+    pub const PIPELINE_FILES: &str = "pipeline-files";
+    /// Serves `ExecShell`.
+    pub const EXEC_SHELL: &str = "exec-shell";
+    /// Scrubs the workload directory when the requests set `scrub`.
+    pub const SCRUB: &str = "scrub";
+
+    /// Every capability of the agents of this version.
+    pub const ALL: &[&str] = &[COMPRESSION, STDIN, PIPELINE_FILES, EXEC_SHELL, SCRUB];
+}
+
+/// Error returned when a protobuf enum value is not known by this version of Cloudlet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    UnknownLanguage(i32),
+    UnknownLogLevel(i32),
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::UnknownLanguage(v) => write!(f, "Unknown language: {}", v),
+            ConversionError::UnknownLogLevel(v) => write!(f, "Unknown log level: {}", v),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl Language {
+    /// Name of the language, as used for rootfs images and by the agent.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Language::RUST => "rust",
+            Language::PYTHON => "python",
+            Language::NODE => "node",
+        }
+    }
+}
+
+impl Capability {
+    /// Name of the capability, as in the specs and the admission policies.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::NetworkEgress => "network-egress",
+            Capability::ArtifactOutput => "artifact-output",
+            Capability::SharedVolume => "shared-volume",
+            Capability::Gpu => "gpu",
+            Capability::SriovNic => "sriov-nic",
+        }
+    }
+}
+
+/// Prefix of the names of the artifacts holding the outputs of a run, followed by their path.
+pub const OUTPUT_ARTIFACT_PREFIX: &str = "output:";
+
+impl cloudlet::agent::RunArtifact {
+    /// Path of the output of the run held by the artifact, `None` for a debugging artifact.
+    pub fn output_path(&self) -> Option<&str> {
+        self.name.strip_prefix(OUTPUT_ARTIFACT_PREFIX)
+    }
+}
+
+impl cloudlet::agent::ExecuteResponse {
+    /// Name of the signal which killed the workload, e.g. `SIGSEGV`, on the final message.
+    pub fn signal_name(&self) -> Option<String> {
+        const NAMES: [&str; 31] = [
+            "SIGHUP",
+            "SIGINT",
+            "SIGQUIT",
+            "SIGILL",
+            "SIGTRAP",
+            "SIGABRT",
+            "SIGBUS",
+            "SIGFPE",
+            "SIGKILL",
+            "SIGUSR1",
+            "SIGSEGV",
+            "SIGUSR2",
+            "SIGPIPE",
+            "SIGALRM",
+            "SIGTERM",
+            "SIGSTKFLT",
+            "SIGCHLD",
+            "SIGCONT",
+            "SIGSTOP",
+            "SIGTSTP",
+            "SIGTTIN",
+            "SIGTTOU",
+            "SIGURG",
+            "SIGXCPU",
+            "SIGXFSZ",
+            "SIGVTALRM",
+            "SIGPROF",
+            "SIGWINCH",
+            "SIGIO",
+            "SIGPWR",
+            "SIGSYS",
+        ];
+        match self.signal {
+            0 => None,
+            signal => Some(
+                usize::try_from(signal - 1)
+                    .ok()
+                    .and_then(|i| NAMES.get(i))
+                    .map(|name| name.to_string())
+                    .unwrap_or_else(|| format!("signal {}", signal)),
+            ),
+        }
+    }
+}
+
+impl cloudlet::agent::VmExit {
+    /// What happened to the VM, e.g. `the guest crashed: triple fault`.
+    pub fn description(&self) -> String {
+        use cloudlet::agent::vm_exit::Reason;
+
+        let reason = match self.reason() {
+            Reason::Unknown => "the VM stopped",
+            Reason::GuestShutdown => "the guest powered off",
+            Reason::GuestCrash => "the guest crashed",
+            Reason::Killed => "the orchestrator stopped the VM",
+            Reason::HostError => "the hypervisor failed",
+        };
+        match self.message.as_str() {
+            "" => reason.to_string(),
+            message => format!("{}: {}", reason, message),
+        }
+    }
+}
+
+impl From<Language> for vmmorchestrator::Language {
+    fn from(value: Language) -> Self {
+        match value {
+            Language::RUST => vmmorchestrator::Language::Rust,
+            Language::PYTHON => vmmorchestrator::Language::Python,
+            Language::NODE => vmmorchestrator::Language::Node,
+        }
+    }
+}
+
+impl From<vmmorchestrator::Language> for Language {
+    fn from(value: vmmorchestrator::Language) -> Self {
+        match value {
+            vmmorchestrator::Language::Rust => Language::RUST,
+            vmmorchestrator::Language::Python => Language::PYTHON,
+            vmmorchestrator::Language::Node => Language::NODE,
+        }
+    }
+}
+
+impl TryFrom<i32> for Language {
+    type Error = ConversionError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        vmmorchestrator::Language::try_from(value)
+            .map(Language::from)
+            .map_err(|_| ConversionError::UnknownLanguage(value))
+    }
+}
+
+impl From<Device> for vmmorchestrator::Device {
+    fn from(value: Device) -> Self {
+        match value {
+            Device::GPU => vmmorchestrator::Device::Gpu,
+            Device::SriovNic => vmmorchestrator::Device::SriovNic,
+        }
+    }
+}
+
+impl From<Capability> for vmmorchestrator::Capability {
+    fn from(value: Capability) -> Self {
+        match value {
+            Capability::NetworkEgress => vmmorchestrator::Capability::NetworkEgress,
+            Capability::ArtifactOutput => vmmorchestrator::Capability::ArtifactOutput,
+            Capability::SharedVolume => vmmorchestrator::Capability::SharedVolume,
+            Capability::Gpu => vmmorchestrator::Capability::Gpu,
+            Capability::SriovNic => vmmorchestrator::Capability::SriovNic,
+        }
+    }
+}
+
+impl From<NodeAffinity> for vmmorchestrator::NodeAffinity {
+    fn from(value: NodeAffinity) -> Self {
+        Self {
+            required: value.required.unwrap_or_default(),
+            preferred: value.preferred,
+        }
+    }
+}
+
+impl From<vmmorchestrator::Capability> for Capability {
+    fn from(value: vmmorchestrator::Capability) -> Self {
+        match value {
+            vmmorchestrator::Capability::NetworkEgress => Capability::NetworkEgress,
+            vmmorchestrator::Capability::ArtifactOutput => Capability::ArtifactOutput,
+            vmmorchestrator::Capability::SharedVolume => Capability::SharedVolume,
+            vmmorchestrator::Capability::Gpu => Capability::Gpu,
+            vmmorchestrator::Capability::SriovNic => Capability::SriovNic,
+        }
+    }
+}
+
+impl From<PipelineStage> for cloudlet::agent::PipelineStage {
+    fn from(value: PipelineStage) -> Self {
+        match value {
+            PipelineStage::FetchDeps => cloudlet::agent::PipelineStage::FetchDeps,
+            PipelineStage::Build => cloudlet::agent::PipelineStage::Build,
+            PipelineStage::Run => cloudlet::agent::PipelineStage::Run,
+        }
+    }
+}
+
+impl From<Webhook> for vmmorchestrator::Webhook {
+    fn from(value: Webhook) -> Self {
+        Self {
+            url: value.url,
+            secret: value.secret.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<LogLevel> for vmmorchestrator::LogLevel {
+    fn from(value: LogLevel) -> Self {
+        match value {
+            LogLevel::DEBUG => vmmorchestrator::LogLevel::Debug,
+            LogLevel::INFO => vmmorchestrator::LogLevel::Info,
+            LogLevel::WARN => vmmorchestrator::LogLevel::Warn,
+            LogLevel::ERROR => vmmorchestrator::LogLevel::Error,
+        }
+    }
+}
+
+impl From<vmmorchestrator::LogLevel> for LogLevel {
+    fn from(value: vmmorchestrator::LogLevel) -> Self {
+        match value {
+            vmmorchestrator::LogLevel::Debug => LogLevel::DEBUG,
+            vmmorchestrator::LogLevel::Info => LogLevel::INFO,
+            vmmorchestrator::LogLevel::Warn => LogLevel::WARN,
+            vmmorchestrator::LogLevel::Error => LogLevel::ERROR,
+        }
+    }
+}
+
+impl TryFrom<i32> for LogLevel {
+    type Error = ConversionError;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        vmmorchestrator::LogLevel::try_from(value)
+            .map(LogLevel::from)
+            .map_err(|_| ConversionError::UnknownLogLevel(value))
+    }
+}
@@ -0,0 +1,80 @@
+//! Redaction of the secret environment variables of a workload, listed in `secret-env`.
+
+use std::fmt;
+
+/// Replaces a secret value wherever it is printed.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Values of the secret variables of a workload, to hide from its output and from the logs.
+#[derive(Clone, Default)]
+pub struct Redactor {
+    /// Longest first, so that a secret containing another one is redacted as a whole.
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    /// Redactor of the values of the variables named in `secret_names`.
+    pub fn new<'a>(
+        env: impl IntoIterator<Item = (&'a String, &'a String)>,
+        secret_names: &[String],
+    ) -> Self {
+        let mut secrets: Vec<String> = env
+            .into_iter()
+            .filter(|(name, value)| !value.is_empty() && secret_names.contains(name))
+            .map(|(_, value)| value.clone())
+            .collect();
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        secrets.dedup();
+
+        Self { secrets }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        self.secrets.iter().fold(text.to_string(), |text, secret| {
+            text.replace(secret, REDACTED)
+        })
+    }
+
+    pub fn redact_bytes(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut bytes = bytes.to_vec();
+        for secret in &self.secrets {
+            let secret = secret.as_bytes();
+            let mut redacted = Vec::with_capacity(bytes.len());
+            let mut rest = bytes.as_slice();
+            while let Some(position) = rest
+                .windows(secret.len())
+                .position(|window| window == secret)
+            {
+                redacted.extend_from_slice(&rest[..position]);
+                redacted.extend_from_slice(REDACTED.as_bytes());
+                rest = &rest[position + secret.len()..];
+            }
+            redacted.extend_from_slice(rest);
+            bytes = redacted;
+        }
+
+        bytes
+    }
+}
+
+impl fmt::Debug for Redactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Redactor({} secrets)", self.secrets.len())
+    }
+}
+
+/// Replace the values of the variables named in `secret_names` with [`REDACTED`].
+pub fn redact_env<'a>(
+    env: impl IntoIterator<Item = (&'a String, &'a mut String)>,
+    secret_names: &[String],
+) {
+    for (name, value) in env {
+        if secret_names.contains(name) {
+            *value = REDACTED.to_string();
+        }
+    }
+}
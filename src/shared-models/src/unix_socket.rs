@@ -0,0 +1,62 @@
+//! Unix domain sockets the HTTP API and the orchestrator can listen on instead of TCP ports, to
+//! not open any port on a shared machine: the permissions of the socket file decide who can
+//! connect. Only the paths are handled outside of Unix, where nothing listens on them.
+
+use std::path::Path;
+#[cfg(unix)]
+use std::{
+    fs, io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    os::unix::net::{UnixListener, UnixStream},
+};
+
+/// Scheme of the endpoints reached through a Unix socket, e.g. `unix:///run/cloudlet/api.sock`.
+pub const UNIX_SCHEME: &str = "unix://";
+
+/// Permissions of the sockets by default: their owner and group can connect.
+pub const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// Path of the socket of `endpoint`, if it is a `unix://` one.
+pub fn socket_path(endpoint: &str) -> Option<&Path> {
+    endpoint.strip_prefix(UNIX_SCHEME).map(Path::new)
+}
+
+/// Parse octal permissions, e.g. `660` or `0o600`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("invalid permissions `{}`, expected e.g. 660", mode))
+}
+
+/// Listen on a socket at `path` with the permissions `mode`, replacing the socket left by a
+/// previous run. Fails if another process still listens on it.
+#[cfg(unix)]
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("another process listens on {:?}", path),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} exists and is not a socket", path),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
@@ -0,0 +1,118 @@
+//! Findings of the `validate-config` commands of the orchestrator and of the HTTP API, which check
+//! a configuration before it is deployed rather than when the server starts, or reloads it.
+
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// A check which passed.
+    Ok,
+    /// The server would run, but probably not as intended.
+    Warning,
+    /// The server would refuse the configuration, or fail while running with it.
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mark = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{:>7}: {}", mark, self.message)
+    }
+}
+
+/// Findings of the checks of a configuration, in the order they were made.
+#[derive(Clone, Debug, Default)]
+pub struct Findings {
+    pub findings: Vec<Finding>,
+}
+
+impl Findings {
+    fn push(&mut self, severity: Severity, message: impl Into<String>) {
+        self.findings.push(Finding {
+            severity,
+            message: message.into(),
+        });
+    }
+
+    pub fn ok(&mut self, message: impl Into<String>) {
+        self.push(Severity::Ok, message)
+    }
+
+    pub fn warning(&mut self, message: impl Into<String>) {
+        self.push(Severity::Warning, message)
+    }
+
+    pub fn error(&mut self, message: impl Into<String>) {
+        self.push(Severity::Error, message)
+    }
+
+    /// An error for `Err`, `ok` otherwise.
+    pub fn check(&mut self, result: Result<(), String>, ok: impl Into<String>) {
+        match result {
+            Ok(()) => self.ok(ok),
+            Err(message) => self.error(message),
+        }
+    }
+
+    pub fn errors(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == severity)
+            .count()
+    }
+
+    /// Print the findings and their summary to stdout, colored on a terminal.
+    pub fn print(&self, config: &str) -> io::Result<()> {
+        let mut out = io::stdout().lock();
+        let color = out.is_terminal();
+        let paint = |severity: Severity, text: &str| match (color, severity) {
+            (false, _) => text.to_string(),
+            (true, Severity::Ok) => format!("\x1b[32m{}\x1b[0m", text),
+            (true, Severity::Warning) => format!("\x1b[33m{}\x1b[0m", text),
+            (true, Severity::Error) => format!("\x1b[31m{}\x1b[0m", text),
+        };
+
+        for finding in &self.findings {
+            writeln!(out, "{}", paint(finding.severity, &finding.to_string()))?;
+        }
+        let (summary, severity) = match (self.errors(), self.warnings()) {
+            (0, 0) => (format!("{} is valid", config), Severity::Ok),
+            (0, warnings) => (
+                format!("{} is valid, with {} warning(s)", config, warnings),
+                Severity::Warning,
+            ),
+            (errors, warnings) => (
+                format!(
+                    "{} is invalid: {} error(s), {} warning(s)",
+                    config, errors, warnings
+                ),
+                Severity::Error,
+            ),
+        };
+        writeln!(out, "\n{}", paint(severity, &summary))
+    }
+
+    /// Exit code of the command: 1 if any check failed.
+    pub fn exit_code(&self) -> i32 {
+        i32::from(self.errors() > 0)
+    }
+}
@@ -0,0 +1,280 @@
+//! Detection of the language of a workload which doesn't set it.
+//!
+//! The code file decides first, from its extension and its shebang. A code file which says
+//! nothing, e.g. an extension-less script, is decided by the manifests next to it: `Cargo.toml`
+//! for Rust, `package.json` for Node, `pyproject.toml`, `requirements.txt` or `setup.py` for
+//! Python. Signals pointing to different languages are an error rather than a guess.
+
+use crate::{Language, SpecError};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+/// Manifests marking the directory of the code as a project of a language.
+const MANIFESTS: &[(&str, Language)] = &[
+    ("Cargo.toml", Language::RUST),
+    ("package.json", Language::NODE),
+    ("pyproject.toml", Language::PYTHON),
+    ("requirements.txt", Language::PYTHON),
+    ("setup.py", Language::PYTHON),
+];
+
+/// Language detected for a workload, and what gave it away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanguageDetection {
+    pub language: Language,
+    /// E.g. `the .py extension` or `requirements.txt next to the code`.
+    pub reason: String,
+}
+
+impl fmt::Display for LanguageDetection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (from {})", self.language.as_str(), self.reason)
+    }
+}
+
+fn language_of_extension(extension: &str) -> Option<Language> {
+    match extension {
+        "rs" => Some(Language::RUST),
+        "py" => Some(Language::PYTHON),
+        "js" | "mjs" | "cjs" => Some(Language::NODE),
+        _ => None,
+    }
+}
+
+fn language_of_interpreter(interpreter: &str) -> Option<Language> {
+    let interpreter = interpreter.rsplit('/').next().unwrap_or(interpreter);
+    if interpreter == "python" || interpreter.starts_with("python3") {
+        Some(Language::PYTHON)
+    } else if interpreter == "node" || interpreter == "nodejs" {
+        Some(Language::NODE)
+    } else {
+        None
+    }
+}
+
+/// Language named by the shebang of `code`, e.g. `#!/usr/bin/env python3`. The inner
+/// attributes of Rust, `#![...]`, aren't shebangs.
+pub fn shebang_language(code: &str) -> Option<Language> {
+    let line = code.lines().next()?.strip_prefix("#!")?;
+    if line.starts_with('[') {
+        return None;
+    }
+
+    let mut words = line.split_whitespace();
+    let interpreter = words.next()?;
+    if interpreter.rsplit('/').next() == Some("env") {
+        // `env -S python3 -u` passes the flags along.
+        words.find(|word| !word.starts_with('-') && !word.contains('='))
+    } else {
+        Some(interpreter)
+    }
+    .and_then(language_of_interpreter)
+}
+
+/// Language the content of `code` gives away: its shebang, or else the entry points only one
+/// language has, `fn main(` for Rust, `def` functions or `if __name__` for Python, `require(`,
+/// `module.exports` or `console.log(` for Node. `None` when it is unclear.
+pub fn code_language(code: &str) -> Option<Language> {
+    if let Some(language) = shebang_language(code) {
+        return Some(language);
+    }
+
+    let lines = || code.lines().map(str::trim_start);
+    let markers = [
+        (
+            Language::RUST,
+            lines().any(|line| line.starts_with("fn main(") || line.starts_with("pub fn main(")),
+        ),
+        (
+            Language::PYTHON,
+            lines().any(|line| line.starts_with("def ") || line.starts_with("if __name__")),
+        ),
+        (
+            Language::NODE,
+            ["require(", "module.exports", "console.log("]
+                .iter()
+                .any(|marker| code.contains(marker)),
+        ),
+    ];
+    let mut found = markers.into_iter().filter(|(_, found)| *found);
+    match (found.next(), found.next()) {
+        (Some((language, _)), None) => Some(language),
+        _ => None,
+    }
+}
+
+/// Detect the language of the code at `code_path`, a source file or the directory of a project.
+pub fn detect_language(code_path: &Path) -> Result<LanguageDetection, SpecError> {
+    let mut candidates: Vec<LanguageDetection> = Vec::new();
+    let push = |candidates: &mut Vec<LanguageDetection>, language: Language, reason: String| {
+        if !candidates.iter().any(|c| c.language == language) {
+            candidates.push(LanguageDetection { language, reason });
+        }
+    };
+
+    let directory = if code_path.is_dir() {
+        Some(code_path)
+    } else {
+        if let Some(extension) = code_path.extension().and_then(|e| e.to_str()) {
+            if let Some(language) = language_of_extension(extension) {
+                push(
+                    &mut candidates,
+                    language,
+                    format!("the .{} extension", extension),
+                );
+            }
+        }
+        let first_line = File::open(code_path)
+            .ok()
+            .and_then(|file| BufReader::new(file).lines().next()?.ok());
+        if let Some(language) = first_line.as_deref().and_then(shebang_language) {
+            push(&mut candidates, language, "its shebang".to_string());
+        }
+        code_path.parent()
+    };
+
+    // The manifests only decide for a code file which doesn't tell its language.
+    if candidates.is_empty() {
+        for (manifest, language) in MANIFESTS {
+            let found = directory.is_some_and(|directory| directory.join(manifest).is_file());
+            if found {
+                push(
+                    &mut candidates,
+                    language.clone(),
+                    format!("{} next to the code", manifest),
+                );
+            }
+        }
+    }
+
+    match candidates.len() {
+        0 => Err(SpecError::UndetectedLanguage(code_path.to_path_buf())),
+        1 => Ok(candidates.remove(0)),
+        _ => Err(SpecError::AmbiguousLanguage {
+            path: code_path.to_path_buf(),
+            candidates: candidates.iter().map(|c| c.to_string()).collect(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn project(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spec-detect-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_shebang_language() {
+        assert_eq!(
+            shebang_language("#!/usr/bin/env python3\nprint(1)"),
+            Some(Language::PYTHON)
+        );
+        assert_eq!(
+            shebang_language("#!/usr/bin/env -S node --no-warnings"),
+            Some(Language::NODE)
+        );
+        assert_eq!(
+            shebang_language("#!/usr/local/bin/python3.12"),
+            Some(Language::PYTHON)
+        );
+        assert_eq!(shebang_language("#![allow(unused)]\nfn main() {}"), None);
+        assert_eq!(shebang_language("#!/bin/sh"), None);
+    }
+
+    #[test]
+    fn test_code_language() {
+        assert_eq!(
+            code_language("use std::env;\n\nfn main() {}\n"),
+            Some(Language::RUST)
+        );
+        assert_eq!(
+            code_language("def handler():\n    pass\n"),
+            Some(Language::PYTHON)
+        );
+        assert_eq!(
+            code_language("const fs = require('fs');\n"),
+            Some(Language::NODE)
+        );
+        // A Python script printing JavaScript says both.
+        assert_eq!(
+            code_language("def main():\n    print('console.log(1)')\n"),
+            None
+        );
+        assert_eq!(code_language("print('hi')\n"), None);
+    }
+
+    #[test]
+    fn test_detect_language() {
+        let dir = project("files", &[("main.rs", "fn main() {}"), ("Cargo.toml", "")]);
+        assert_eq!(
+            detect_language(&dir.join("main.rs")).unwrap().language,
+            Language::RUST
+        );
+        assert_eq!(detect_language(&dir).unwrap().language, Language::RUST);
+
+        let dir = project(
+            "manifest",
+            &[("handler", "print('hi')"), ("requirements.txt", "numpy")],
+        );
+        let detection = detect_language(&dir.join("handler")).unwrap();
+        assert_eq!(detection.language, Language::PYTHON);
+        assert_eq!(detection.reason, "requirements.txt next to the code");
+
+        // The code file decides over the manifests.
+        let dir = project(
+            "script",
+            &[("build.js", "#!/usr/bin/env node"), ("pyproject.toml", "")],
+        );
+        assert_eq!(
+            detect_language(&dir.join("build.js")).unwrap().language,
+            Language::NODE
+        );
+    }
+
+    #[test]
+    fn test_ambiguous_and_undetected_languages() {
+        let dir = project(
+            "ambiguous",
+            &[
+                ("main.py", "#!/usr/bin/env node"),
+                ("handler", ""),
+                ("package.json", "{}"),
+                ("setup.py", ""),
+            ],
+        );
+        assert!(matches!(
+            detect_language(&dir.join("main.py")),
+            Err(SpecError::AmbiguousLanguage { .. })
+        ));
+        match detect_language(&dir.join("handler")) {
+            Err(SpecError::AmbiguousLanguage { candidates, .. }) => assert_eq!(
+                candidates,
+                [
+                    "node (from package.json next to the code)",
+                    "python (from setup.py next to the code)"
+                ]
+            ),
+            other => panic!("unexpected detection: {:?}", other),
+        }
+
+        let dir = project("undetected", &[("run", "echo hi")]);
+        assert!(matches!(
+            detect_language(&dir.join("run")),
+            Err(SpecError::UndetectedLanguage(_))
+        ));
+    }
+}
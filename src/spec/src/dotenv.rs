@@ -0,0 +1,192 @@
+//! Parser of the env files, in the dotenv format:
+//!
+//! ```text
+//! # Comments take whole lines, or follow a value after a space.
+//! export GREETING=hello world  # `export` is optional
+//! LITERAL='single quotes keep $HOME and \n as is'
+//! ESCAPED="double quotes understand \"\\\", \n, \t, \r and \$"
+//! MESSAGE="quoted values
+//! may span several lines"
+//! ```
+//!
+//! Variables aren't expanded: `$OTHER` is given to the workload as is.
+
+use crate::{is_valid_env_name, ValidationError};
+
+/// Parse the content of an env file into its variables, in order of appearance.
+pub fn parse_env_file(content: &str) -> Result<Vec<(String, String)>, Vec<ValidationError>> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut variables = Vec::new();
+    let mut errors = Vec::new();
+    let mut next = 0;
+
+    while next < lines.len() {
+        let number = next + 1;
+        let line = lines[next].trim_start();
+        next += 1;
+        if line.trim_end().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: String| ValidationError::new(format!("env-file:{}", number), message);
+
+        let line = line
+            .strip_prefix("export ")
+            .map(str::trim_start)
+            .unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            errors.push(error("expected a `KEY=VALUE` line".into()));
+            continue;
+        };
+        let name = name.trim_end();
+        if !is_valid_env_name(name) {
+            errors.push(error(format!(
+                "`{}` is not a valid environment variable name",
+                name
+            )));
+            continue;
+        }
+
+        let trimmed = value.trim_start();
+        let value = match trimmed.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let Some((value, rest, read)) = read_quoted(&trimmed[1..], &lines[next..], quote)
+                else {
+                    errors.push(error(format!("unterminated {} quote", quote)));
+                    // Nothing after the opening quote can be told apart from the value.
+                    break;
+                };
+                next += read;
+                let rest = rest.trim();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    errors.push(error(format!(
+                        "unexpected `{}` after the quoted value",
+                        rest
+                    )));
+                    continue;
+                }
+                value
+            }
+            _ => strip_comment(value).to_string(),
+        };
+
+        variables.push((name.to_string(), value));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(variables)
+}
+
+/// Unquoted value without its trailing comment, which starts with a `#` after a whitespace.
+fn strip_comment(value: &str) -> &str {
+    let end = value
+        .char_indices()
+        .find(|(i, c)| *c == '#' && value[..*i].ends_with(char::is_whitespace))
+        .map(|(i, _)| i)
+        .unwrap_or(value.len());
+
+    value[..end].trim()
+}
+
+/// Read a value opened by `quote`, from `first` then from the `following` lines until the
+/// quote is closed. Returns the value, the text after the closing quote and the number of
+/// following lines read, or `None` if the quote is never closed.
+fn read_quoted<'a>(
+    first: &'a str,
+    following: &[&'a str],
+    quote: char,
+) -> Option<(String, &'a str, usize)> {
+    let mut value = String::new();
+    let mut segment = first;
+    let mut read = 0;
+
+    loop {
+        let mut chars = segment.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                c if c == quote => return Some((value, &segment[i + 1..], read)),
+                '\\' if quote == '"' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, 'r')) => value.push('\r'),
+                    Some((_, c @ ('"' | '\\' | '$'))) => value.push(c),
+                    Some((_, c)) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => value.push('\\'),
+                },
+                c => value.push(c),
+            }
+        }
+
+        segment = following.get(read)?;
+        read += 1;
+        value.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Vec<(String, String)> {
+        parse_env_file(content).unwrap()
+    }
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_unquoted_values_and_comments() {
+        let content = "# comment\n\nexport A=1\nB = two words # comment\nC=#kept\nD=\n";
+
+        assert_eq!(
+            parse(content),
+            [
+                pair("A", "1"),
+                pair("B", "two words"),
+                pair("C", "#kept"),
+                pair("D", "")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quoted_values() {
+        let content = r#"SINGLE='$HOME \n # not a comment'
+DOUBLE="say \"hi\"\n\ttab" # comment
+MULTI="first
+second"
+AFTER=1
+"#;
+
+        assert_eq!(
+            parse(content),
+            [
+                pair("SINGLE", r"$HOME \n # not a comment"),
+                pair("DOUBLE", "say \"hi\"\n\ttab"),
+                pair("MULTI", "first\nsecond"),
+                pair("AFTER", "1")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors_are_reported_with_their_line() {
+        let content = "1BAD=value\nNOVALUE\nOK=1\nQUOTED=\"a\" b\nOPEN='never closed\n";
+
+        let fields: Vec<_> = parse_env_file(content)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.field)
+            .collect();
+        assert_eq!(
+            fields,
+            ["env-file:1", "env-file:2", "env-file:4", "env-file:5"]
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SpecError {
+    /// The spec file could not be read.
+    #[error("Could not read spec file `{path}`: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    /// The spec file is not valid YAML or doesn't match the schema.
+    #[error("Could not parse spec: {0}")]
+    Parse(#[from] serde_yaml::Error),
+
+    /// The spec targets a schema version this crate doesn't know about.
+    #[error("Unsupported api-version `{0}`, expected `cloudlet/v2`")]
+    UnsupportedVersion(String),
+
+    /// The spec doesn't set `language`, and nothing tells the language of its code.
+    #[error("Could not detect the language of `{}`, set `language` in the spec", .0.display())]
+    UndetectedLanguage(PathBuf),
+
+    /// The spec doesn't set `language`, and its code looks like several languages.
+    #[error("The language of `{}` is ambiguous, set `language` in the spec: {}", .path.display(), .candidates.join(", "))]
+    AmbiguousLanguage {
+        path: PathBuf,
+        candidates: Vec<String>,
+    },
+
+    /// The spec was parsed but some of its fields are invalid.
+    #[error("Invalid spec:\n{}", .0.iter().map(|e| format!("  - {}", e)).collect::<Vec<_>>().join("\n"))]
+    Invalid(Vec<ValidationError>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{field}: {message}")]
+pub struct ValidationError {
+    /// Path of the offending field, e.g. `resources.cpus`.
+    pub field: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
@@ -0,0 +1,762 @@
+//! Declarative description of a Cloudlet workload (`cloudlet.yaml`).
+//!
+//! The spec is shared between the CLI, which reads it from disk, and the API,
+//! which validates the parts of it forwarded in run requests.
+
+use serde::{Deserialize, Serialize};
+pub use shared_models::{Capability, Device, Language, NodeAffinity, Resources, Webhook};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+mod detect;
+mod dotenv;
+mod errors;
+
+pub use detect::{code_language, detect_language, shebang_language, LanguageDetection};
+pub use dotenv::parse_env_file;
+pub use errors::{SpecError, ValidationError};
+
+/// Version of the schema described by this crate.
+pub const API_VERSION: &str = "cloudlet/v2";
+
+/// Bounds accepted for the guest resources.
+pub const MAX_CPUS: u8 = 16;
+pub const MIN_MEMORY_MB: u32 = 128;
+pub const MAX_MEMORY_MB: u32 = 32 * 1024;
+/// Maximum wall-clock duration of a run, in seconds.
+pub const MAX_TIMEOUT_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WorkloadSpec {
+    /// Schema version, must be [`API_VERSION`].
+    pub api_version: String,
+    /// Name of the workload, used to identify it.
+    pub workload_name: String,
+    /// Language of the source code, detected from the code by [`WorkloadSpec::from_file`] if
+    /// unset.
+    #[serde(default)]
+    pub language: Option<Language>,
+    /// How the language was detected, when the spec doesn't set it.
+    #[serde(skip)]
+    pub language_detection: Option<LanguageDetection>,
+    /// Action to perform.
+    #[serde(default)]
+    pub action: Action,
+    /// Where to find the source code.
+    pub code: CodeSource,
+    #[serde(default)]
+    pub build: BuildSpec,
+    #[serde(default)]
+    pub resources: Resources,
+    /// Host devices passed through to the guest, e.g. `[sriov-nic]`.
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    /// Capabilities the run needs from the server, e.g. `[network-egress, shared-volume]`: the
+    /// run is refused before any VM is provisioned if it lacks one.
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// Labels of the nodes the run must, or would rather, run on, e.g. `required: gpu=a100`.
+    #[serde(default)]
+    pub node_affinity: NodeAffinity,
+    /// Name of the guest kernel, among the kernels of the server (default: its default kernel).
+    #[serde(default)]
+    pub kernel: Option<String>,
+    /// Priority class of the guest, among the classes of the server (default: its default class).
+    #[serde(default)]
+    pub priority_class: Option<String>,
+    /// Tenant the resources consumed by the run are accounted to (default: `default`).
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Maximum duration of the run, in seconds.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Environment variables given to the workload.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Env file (dotenv format) merged into `env`, relative to the spec file.
+    #[serde(default)]
+    pub env_file: Option<PathBuf>,
+    /// Variables of `env` or of `env-file` whose values are secret, redacted from the output
+    /// and the logs.
+    #[serde(default)]
+    pub secret_env: Vec<String>,
+    /// Additional files copied next to the workload. Not supported yet: the spec is refused
+    /// with any.
+    #[serde(default)]
+    pub files: Vec<FileSpec>,
+    /// Not supported yet: the guests always reach the outside world, and the spec is refused
+    /// without egress.
+    #[serde(default)]
+    pub network: NetworkPolicy,
+    /// Files to collect from the guest once the workload is done. Not supported yet: the spec is
+    /// refused with any.
+    #[serde(default)]
+    pub artifacts: Vec<ArtifactSpec>,
+    /// Endpoints notified when the run finishes or fails.
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    /// Labels of the runs, selecting them in the listings of the runs and of the VMs.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Steps of a pipeline, run by the server instead of the workload itself. Each step runs
+    /// the workload, with the fields it overrides, on a VM of its own.
+    #[serde(default)]
+    pub steps: Vec<StepSpec>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StepSpec {
+    /// Name of the step, unique in the pipeline, named like a workload.
+    pub name: String,
+    /// Steps which must succeed before this one starts.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Code of the step, the code of the workload if unset.
+    #[serde(default)]
+    pub code: Option<CodeSource>,
+    /// Language of the code of the step, detected like the one of the workload if unset.
+    #[serde(default)]
+    pub language: Option<Language>,
+    #[serde(default)]
+    pub action: Option<Action>,
+    /// Environment variables added to those of the workload.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Files of the workload directory given to the steps depending on this one, e.g.
+    /// `target/app`.
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+/// Check the steps of a pipeline, given as their names and the steps they depend on: names
+/// valid and unique, dependencies known, and no cycle.
+pub fn validate_steps(steps: &[(&str, &[String])]) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+
+    for (i, (name, depends_on)) in steps.iter().enumerate() {
+        if let Some(e) = validate_workload_name(name) {
+            errors.push(ValidationError::new(
+                format!("steps[{}].name", i),
+                e.message,
+            ));
+        }
+        if steps[..i].iter().any(|(other, _)| other == name) {
+            errors.push(ValidationError::new(
+                format!("steps[{}].name", i),
+                format!("`{}` names several steps", name),
+            ));
+        }
+        for dependency in depends_on.iter() {
+            if dependency.as_str() == *name
+                || !steps.iter().any(|(other, _)| *other == dependency.as_str())
+            {
+                errors.push(ValidationError::new(
+                    format!("steps[{}].depends-on", i),
+                    format!("`{}` isn't another step of the pipeline", dependency),
+                ));
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return errors;
+    }
+
+    // Take the steps whose dependencies are all taken until none is left, or a cycle is.
+    let mut done: Vec<&str> = Vec::new();
+    while done.len() < steps.len() {
+        let ready: Vec<&str> = steps
+            .iter()
+            .filter(|(name, depends_on)| {
+                !done.contains(name) && depends_on.iter().all(|d| done.contains(&d.as_str()))
+            })
+            .map(|(name, _)| *name)
+            .collect();
+        if ready.is_empty() {
+            let cycle: Vec<&str> = steps
+                .iter()
+                .map(|(name, _)| *name)
+                .filter(|name| !done.contains(name))
+                .collect();
+            errors.push(ValidationError::new(
+                "steps",
+                format!("the dependencies of {} form a cycle", cycle.join(", ")),
+            ));
+            break;
+        }
+        done.extend(ready);
+    }
+
+    errors
+}
+
+/// Check that the outputs of a step are paths relative to the workload directory, without `..`.
+pub fn validate_outputs(step: usize, outputs: &[String]) -> Vec<ValidationError> {
+    outputs
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| {
+            let path = Path::new(output.as_str());
+            output.is_empty()
+                || !path
+                    .components()
+                    .all(|component| matches!(component, std::path::Component::Normal(_)))
+        })
+        .map(|(i, _)| {
+            ValidationError::new(
+                format!("steps[{}].outputs[{}]", step, i),
+                "must be a path relative to the workload directory, without `..`",
+            )
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Prepare,
+    Run,
+    #[default]
+    PrepareAndRun,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Prepare => write!(f, "prepare"),
+            Action::Run => write!(f, "run"),
+            Action::PrepareAndRun => write!(f, "prepare-and-run"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CodeSource {
+    /// Path to the source code, relative to the spec file.
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BuildSpec {
+    /// Build the source code in release mode.
+    #[serde(default)]
+    pub release: bool,
+    /// Features of the workload to enable (cargo features for Rust).
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Extra flags passed to the compiler (`RUSTFLAGS` for Rust).
+    #[serde(default)]
+    pub compiler_flags: Vec<String>,
+    /// Version of the language runtime or toolchain, the default one of the image if unset.
+    #[serde(default)]
+    pub runtime_version: Option<String>,
+}
+
+/// Check that the requested guest resources are within the supported bounds.
+pub fn validate_resources(resources: &Resources) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+
+    if resources.cpus == 0 || resources.cpus > MAX_CPUS {
+        errors.push(ValidationError::new(
+            "resources.cpus",
+            format!("must be between 1 and {}", MAX_CPUS),
+        ));
+    }
+    if !(MIN_MEMORY_MB..=MAX_MEMORY_MB).contains(&resources.memory_mb) {
+        errors.push(ValidationError::new(
+            "resources.memory-mb",
+            format!("must be between {} and {}", MIN_MEMORY_MB, MAX_MEMORY_MB),
+        ));
+    }
+    // The files of the workload directory are kept in the memory of the guest.
+    if let Some(quota) = resources.tmp_quota_mb {
+        if quota == 0 || quota > resources.memory_mb / 2 {
+            errors.push(ValidationError::new(
+                "resources.tmp-quota-mb",
+                "must be between 1 and half of `memory-mb`",
+            ));
+        }
+    }
+
+    errors
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FileSpec {
+    /// Path of the file on the local machine, relative to the spec file.
+    pub source: PathBuf,
+    /// Absolute path of the file inside the guest.
+    pub destination: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NetworkPolicy {
+    /// Allow the workload to reach the outside world.
+    pub egress: bool,
+}
+
+impl Default for NetworkPolicy {
+    fn default() -> Self {
+        Self { egress: true }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ArtifactSpec {
+    /// Absolute path of the file to collect inside the guest.
+    pub path: PathBuf,
+}
+
+/// Check that a workload name can be used as an identifier (and as a binary name by the agent).
+pub fn validate_workload_name(name: &str) -> Option<ValidationError> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        && name.starts_with(|c: char| c.is_ascii_lowercase());
+
+    (!valid).then(|| {
+        ValidationError::new(
+            "workload-name",
+            "must start with a lowercase letter and only contain lowercase letters, digits, '-' or '_' (64 characters max)",
+        )
+    })
+}
+
+/// Check that a tenant name can be used in the usage exports, with the rules of the workload
+/// names.
+pub fn validate_tenant(tenant: &str) -> Option<ValidationError> {
+    validate_workload_name(tenant).map(|e| ValidationError::new("tenant", e.message))
+}
+
+/// Check that the timeout of a run, in seconds, is within the longest run allowed.
+pub fn validate_timeout(timeout: u64) -> Option<ValidationError> {
+    (timeout == 0 || timeout > MAX_TIMEOUT_SECS).then(|| {
+        ValidationError::new(
+            "timeout",
+            format!("must be between 1 and {} seconds", MAX_TIMEOUT_SECS),
+        )
+    })
+}
+
+/// Check that an environment variable name is portable (`[A-Za-z_][A-Za-z0-9_]*`).
+pub fn is_valid_env_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Check the names of the environment variables, and that no value holds a NUL byte, which
+/// can't be passed to a process.
+pub fn validate_env(env: &BTreeMap<String, String>) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+
+    for (name, value) in env {
+        if !is_valid_env_name(name) {
+            errors.push(ValidationError::new(
+                format!("env.{}", name),
+                "is not a valid environment variable name",
+            ));
+        } else if value.contains('\0') {
+            errors.push(ValidationError::new(
+                format!("env.{}", name),
+                "must not contain a NUL byte",
+            ));
+        }
+    }
+
+    errors
+}
+
+/// Check that every secret variable is set.
+pub fn validate_secret_env(
+    env: &BTreeMap<String, String>,
+    secret_env: &[String],
+) -> Vec<ValidationError> {
+    secret_env
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| !env.contains_key(*name))
+        .map(|(i, name)| {
+            ValidationError::new(
+                format!("secret-env[{}]", i),
+                format!("`{}` is not set in env or env-file", name),
+            )
+        })
+        .collect()
+}
+
+/// Check that the webhooks are HTTP URLs. Their hosts are resolved, and the internal
+/// addresses refused, by the orchestrator as it posts to them.
+pub fn validate_webhooks(webhooks: &[Webhook]) -> Vec<ValidationError> {
+    webhooks
+        .iter()
+        .enumerate()
+        .filter(|(_, webhook)| {
+            let host = webhook
+                .url
+                .strip_prefix("https://")
+                .or_else(|| webhook.url.strip_prefix("http://"));
+            !host.is_some_and(|host| !host.is_empty() && !host.starts_with('/'))
+        })
+        .map(|(i, _)| {
+            ValidationError::new(
+                format!("webhooks[{}].url", i),
+                "must be an http:// or https:// URL",
+            )
+        })
+        .collect()
+}
+
+/// Check the keys and the values of the labels.
+pub fn validate_labels(labels: &BTreeMap<String, String>) -> Vec<ValidationError> {
+    labels
+        .iter()
+        .filter_map(|(key, value)| {
+            shared_models::labels::validate_label(key, value)
+                .err()
+                .map(|e| ValidationError::new(format!("labels.{}", key), e))
+        })
+        .collect()
+}
+
+/// Check the selectors of the node affinity.
+pub fn validate_node_affinity(affinity: &NodeAffinity) -> Vec<ValidationError> {
+    let required = affinity
+        .required
+        .iter()
+        .map(|selector| ("node-affinity.required".to_string(), selector));
+    let preferred = affinity
+        .preferred
+        .iter()
+        .enumerate()
+        .map(|(i, selector)| (format!("node-affinity.preferred[{}]", i), selector));
+    required
+        .chain(preferred)
+        .filter_map(|(field, selector)| {
+            selector
+                .parse::<shared_models::labels::LabelSelector>()
+                .err()
+                .map(|e| ValidationError::new(field, e))
+        })
+        .collect()
+}
+
+impl WorkloadSpec {
+    /// Read, parse and validate a spec file. Relative paths are resolved against the spec directory.
+    pub fn from_file(path: &Path) -> Result<Self, SpecError> {
+        let content = std::fs::read_to_string(path).map_err(|source| SpecError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_content(&content, path)
+    }
+
+    /// Parse and validate `content`, the spec of the file at `path`, e.g. completed by the user
+    /// before being read.
+    pub fn from_content(content: &str, path: &Path) -> Result<Self, SpecError> {
+        let mut spec = Self::from_yaml(content)?;
+
+        if let Some(base_dir) = path.parent() {
+            spec.resolve_paths(base_dir);
+        }
+        spec.load_env_file()?;
+        if spec.language.is_none() {
+            let detection = detect_language(&spec.code.path)?;
+            spec.language = Some(detection.language.clone());
+            spec.language_detection = Some(detection);
+        }
+        for step in spec.steps.iter_mut() {
+            if let (Some(code), None) = (&step.code, &step.language) {
+                step.language = Some(detect_language(&code.path)?.language);
+            }
+        }
+
+        let errors = validate_secret_env(&spec.env, &spec.secret_env);
+        if !errors.is_empty() {
+            return Err(SpecError::Invalid(errors));
+        }
+
+        Ok(spec)
+    }
+
+    /// Parse and validate a spec from its YAML representation.
+    pub fn from_yaml(content: &str) -> Result<Self, SpecError> {
+        let spec: WorkloadSpec = serde_yaml::from_str(content)?;
+
+        if spec.api_version != API_VERSION {
+            return Err(SpecError::UnsupportedVersion(spec.api_version));
+        }
+
+        let errors = spec.validate();
+        if !errors.is_empty() {
+            return Err(SpecError::Invalid(errors));
+        }
+
+        Ok(spec)
+    }
+
+    /// Make every local path of the spec relative to `base_dir`.
+    pub fn resolve_paths(&mut self, base_dir: &Path) {
+        self.code.path = base_dir.join(&self.code.path);
+        if let Some(env_file) = self.env_file.as_mut() {
+            *env_file = base_dir.join(&*env_file);
+        }
+        for file in self.files.iter_mut() {
+            file.source = base_dir.join(&file.source);
+        }
+        for code in self.steps.iter_mut().filter_map(|step| step.code.as_mut()) {
+            code.path = base_dir.join(&code.path);
+        }
+    }
+
+    /// Merge the variables of `env-file` into `env`. Variables set inline in the spec take precedence.
+    pub fn load_env_file(&mut self) -> Result<(), SpecError> {
+        let Some(path) = &self.env_file else {
+            return Ok(());
+        };
+        let content = std::fs::read_to_string(path).map_err(|source| SpecError::Read {
+            path: path.clone(),
+            source,
+        })?;
+
+        // A variable set twice in the file takes its last value.
+        let variables: BTreeMap<_, _> = parse_env_file(&content)
+            .map_err(SpecError::Invalid)?
+            .into_iter()
+            .collect();
+        for (name, value) in variables {
+            self.env.entry(name).or_insert(value);
+        }
+
+        let errors = validate_env(&self.env);
+        if !errors.is_empty() {
+            return Err(SpecError::Invalid(errors));
+        }
+
+        Ok(())
+    }
+
+    /// Return every problem found in the spec.
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+
+        errors.extend(validate_workload_name(&self.workload_name));
+        errors.extend(self.tenant.as_deref().and_then(validate_tenant));
+        errors.extend(validate_resources(&self.resources));
+
+        errors.extend(self.timeout.and_then(validate_timeout));
+
+        errors.extend(validate_env(&self.env));
+        errors.extend(validate_webhooks(&self.webhooks));
+        errors.extend(validate_labels(&self.labels));
+        errors.extend(validate_node_affinity(&self.node_affinity));
+
+        for (i, file) in self.files.iter().enumerate() {
+            if !file.destination.is_absolute() {
+                errors.push(ValidationError::new(
+                    format!("files[{}].destination", i),
+                    "must be an absolute path",
+                ));
+            }
+        }
+
+        for (i, artifact) in self.artifacts.iter().enumerate() {
+            if !artifact.path.is_absolute() {
+                errors.push(ValidationError::new(
+                    format!("artifacts[{}].path", i),
+                    "must be an absolute path",
+                ));
+            }
+        }
+
+        // Refused rather than ignored, until the orchestrator and the agent enforce them: a
+        // policy silently dropped would give the run more than it asked for.
+        if !self.files.is_empty() {
+            errors.push(ValidationError::new(
+                "files",
+                "isn't supported yet, the files aren't copied to the guest",
+            ));
+        }
+        if !self.network.egress {
+            errors.push(ValidationError::new(
+                "network.egress",
+                "can't be disabled yet, the guests always reach the outside world",
+            ));
+        }
+        if !self.artifacts.is_empty() {
+            errors.push(ValidationError::new(
+                "artifacts",
+                "isn't supported yet, the files aren't collected from the guest",
+            ));
+        }
+
+        let steps: Vec<(&str, &[String])> = self
+            .steps
+            .iter()
+            .map(|step| (step.name.as_str(), step.depends_on.as_slice()))
+            .collect();
+        errors.extend(validate_steps(&steps));
+        for (i, step) in self.steps.iter().enumerate() {
+            errors.extend(validate_outputs(i, &step.outputs));
+            errors.extend(
+                validate_env(&step.env)
+                    .into_iter()
+                    .map(|e| ValidationError::new(format!("steps[{}].{}", i, e.field), e.message)),
+            );
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL_SPEC: &str = r#"
+api-version: cloudlet/v2
+workload-name: fibonacci
+language: rust
+code:
+  path: main.rs
+"#;
+
+    #[test]
+    fn test_minimal_spec_defaults() {
+        let spec = WorkloadSpec::from_yaml(MINIMAL_SPEC).unwrap();
+
+        assert_eq!(spec.action, Action::PrepareAndRun);
+        assert_eq!(spec.resources, Resources::default());
+        assert!(spec.network.egress);
+        assert!(spec.env.is_empty());
+    }
+
+    #[test]
+    fn test_unenforced_fields_are_rejected() {
+        let content = format!(
+            "{}files:\n  - source: data.csv\n    destination: /data.csv\nnetwork:\n  egress: false\nartifacts:\n  - path: /out.txt\n",
+            MINIMAL_SPEC
+        );
+
+        match WorkloadSpec::from_yaml(&content) {
+            Err(SpecError::Invalid(errors)) => {
+                let fields: Vec<_> = errors.iter().map(|e| e.field.as_str()).collect();
+                assert_eq!(fields, ["files", "network.egress", "artifacts"]);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // The default policy is the one enforced.
+        let content = format!("{}network:\n  egress: true\n", MINIMAL_SPEC);
+        assert!(WorkloadSpec::from_yaml(&content).is_ok());
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let content = MINIMAL_SPEC.replace("cloudlet/v2", "cloudlet/v1");
+
+        assert!(matches!(
+            WorkloadSpec::from_yaml(&content),
+            Err(SpecError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_fields_are_all_reported() {
+        let content = format!(
+            "{}resources:\n  cpus: 0\n  memory-mb: 64\nenv:\n  1BAD: value\n",
+            MINIMAL_SPEC.replace("fibonacci", "Fibonacci")
+        );
+
+        match WorkloadSpec::from_yaml(&content) {
+            Err(SpecError::Invalid(errors)) => {
+                let fields: Vec<_> = errors.iter().map(|e| e.field.as_str()).collect();
+                assert_eq!(
+                    fields,
+                    [
+                        "workload-name",
+                        "resources.cpus",
+                        "resources.memory-mb",
+                        "env.1BAD"
+                    ]
+                );
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tmp_quota_is_within_memory() {
+        let resources = |quota: u32| {
+            format!(
+                "{}resources:\n  cpus: 1\n  memory-mb: 1024\n  tmp-quota-mb: {}\n",
+                MINIMAL_SPEC, quota
+            )
+        };
+        let spec = WorkloadSpec::from_yaml(&resources(512)).unwrap();
+        assert_eq!(spec.resources.tmp_quota_mb, Some(512));
+
+        for quota in [0, 513] {
+            match WorkloadSpec::from_yaml(&resources(quota)) {
+                Err(SpecError::Invalid(errors)) => {
+                    assert_eq!(errors[0].field, "resources.tmp-quota-mb");
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_steps_are_validated() {
+        let steps = |steps: &str| format!("{}steps:\n{}", MINIMAL_SPEC, steps);
+        let pipeline = steps(
+            "  - name: build\n    outputs: [target/app]\n  - name: test\n    depends-on: [build]\n",
+        );
+        let spec = WorkloadSpec::from_yaml(&pipeline).unwrap();
+        assert_eq!(spec.steps[1].depends_on, ["build"]);
+
+        let fields = |content: &str| match WorkloadSpec::from_yaml(content) {
+            Err(SpecError::Invalid(errors)) => errors
+                .into_iter()
+                .map(|e| format!("{}: {}", e.field, e.message))
+                .collect::<Vec<_>>(),
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(
+            fields(&steps(
+                "  - name: build\n    outputs: [../app]\n  - name: build\n    depends-on: [deploy]\n"
+            )),
+            [
+                "steps[1].name: `build` names several steps",
+                "steps[1].depends-on: `deploy` isn't another step of the pipeline",
+                "steps[0].outputs[0]: must be a path relative to the workload directory, without `..`",
+            ]
+        );
+        assert_eq!(
+            fields(&steps(
+                "  - name: a\n    depends-on: [b]\n  - name: b\n    depends-on: [a]\n  - name: c\n"
+            )),
+            ["steps: the dependencies of a, b form a cycle"]
+        );
+    }
+
+    #[test]
+    fn test_unknown_field_is_rejected() {
+        let content = format!("{}unknown: true\n", MINIMAL_SPEC);
+
+        assert!(matches!(
+            WorkloadSpec::from_yaml(&content),
+            Err(SpecError::Parse(_))
+        ));
+    }
+}
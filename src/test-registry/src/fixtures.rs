@@ -0,0 +1,306 @@
+//! Fixture images, described in `testdata/images.toml` and built into blobs and manifests
+//! when the registry starts.
+//!
+//! The layers are built deterministically, so that their digests don't change between runs.
+
+use flate2::{write::GzEncoder, Compression as GzCompression};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, io::Write};
+use tar::{Builder, EntryType, Header};
+
+pub const OCI_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+pub const OCI_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const EMPTY_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+
+/// Type of the rootfs artifacts pushed by `fs-gen push` and pulled by the orchestrator.
+const ROOTFS_ARTIFACT_TYPE: &str = "application/vnd.cloudlet.rootfs.v1";
+const CPIO_MEDIA_TYPE: &str = "application/vnd.cloudlet.rootfs.cpio.v1";
+
+/// Fixtures shipped with the crate.
+pub const IMAGES: &str = include_str!("../testdata/images.toml");
+
+#[derive(Debug, Deserialize)]
+struct Fixtures {
+    #[serde(default, rename = "image")]
+    images: Vec<ImageFixture>,
+    #[serde(default, rename = "artifact")]
+    artifacts: Vec<ArtifactFixture>,
+}
+
+/// Container image, whose layers are applied in order.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImageFixture {
+    repository: String,
+    tag: String,
+    /// Architectures of a manifest list, a single image manifest is served if empty.
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(rename = "layer")]
+    layers: Vec<LayerFixture>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerFixture {
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default, rename = "entry")]
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Compression {
+    #[default]
+    Gzip,
+    Zstd,
+    None,
+}
+
+/// Entry of a layer: a file with `content`, a `symlink`, a `dir`, or a `whiteout` or
+/// `opaque` whiteout of the lower layers.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    path: String,
+    /// Write `path` as is in the header, even absolute or with `..`, like a hostile layer.
+    #[serde(default)]
+    raw: bool,
+    content: Option<String>,
+    symlink: Option<String>,
+    #[serde(default)]
+    dir: bool,
+    #[serde(default)]
+    whiteout: bool,
+    #[serde(default)]
+    opaque: bool,
+}
+
+/// Rootfs image, as pushed by `fs-gen push`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArtifactFixture {
+    repository: String,
+    tag: String,
+    content: String,
+    /// Annotations of the manifest, e.g. the scan summary.
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// Manifest served by the registry.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub media_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+/// Blobs and manifests of the fixtures.
+#[derive(Debug, Default)]
+pub struct Store {
+    pub blobs: HashMap<String, Vec<u8>>,
+    /// By repository, then by tag and by digest.
+    pub manifests: HashMap<String, HashMap<String, Manifest>>,
+}
+
+pub fn digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+impl Store {
+    /// Build the fixtures described by `fixtures`, a TOML document like [`IMAGES`].
+    pub fn build(fixtures: &str) -> Result<Self, toml::de::Error> {
+        let fixtures: Fixtures = toml::from_str(fixtures)?;
+        let mut store = Self::default();
+        for image in &fixtures.images {
+            store.add_image(image);
+        }
+        for artifact in &fixtures.artifacts {
+            store.add_artifact(artifact);
+        }
+
+        Ok(store)
+    }
+
+    fn add_blob(&mut self, data: Vec<u8>) -> Value {
+        let digest = digest(&data);
+        let descriptor = json!({ "digest": digest, "size": data.len() });
+        self.blobs.insert(digest, data);
+        descriptor
+    }
+
+    /// Store `manifest` under its digest, and `tag` if any. Returns its descriptor.
+    fn add_manifest(
+        &mut self,
+        repository: &str,
+        tag: Option<&str>,
+        media_type: &'static str,
+        manifest: Value,
+    ) -> Value {
+        let body = serde_json::to_vec(&manifest).unwrap();
+        let digest = digest(&body);
+        let descriptor = json!({ "mediaType": media_type, "digest": digest, "size": body.len() });
+
+        let manifests = self.manifests.entry(repository.to_string()).or_default();
+        let manifest = Manifest { media_type, body };
+        if let Some(tag) = tag {
+            manifests.insert(tag.to_string(), manifest.clone());
+        }
+        manifests.insert(digest, manifest);
+        descriptor
+    }
+
+    fn add_image(&mut self, image: &ImageFixture) {
+        let mut layers = Vec::new();
+        let mut diff_ids = Vec::new();
+        for layer in &image.layers {
+            let tar = build_tar(&layer.entries);
+            diff_ids.push(digest(&tar));
+            let (media_type, blob) = match layer.compression {
+                Compression::Gzip => {
+                    let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+                    encoder.write_all(&tar).unwrap();
+                    (
+                        "application/vnd.oci.image.layer.v1.tar+gzip",
+                        encoder.finish().unwrap(),
+                    )
+                }
+                Compression::Zstd => (
+                    "application/vnd.oci.image.layer.v1.tar+zstd",
+                    zstd::encode_all(tar.as_slice(), 3).unwrap(),
+                ),
+                Compression::None => ("application/vnd.oci.image.layer.v1.tar", tar),
+            };
+            let mut descriptor = self.add_blob(blob);
+            descriptor["mediaType"] = media_type.into();
+            layers.push(descriptor);
+        }
+
+        let image_manifest = |store: &mut Self, architecture: &str, tag: Option<&str>| {
+            let mut config = store.add_blob(
+                serde_json::to_vec(&json!({
+                    "architecture": architecture,
+                    "os": "linux",
+                    "rootfs": { "type": "layers", "diff_ids": diff_ids },
+                }))
+                .unwrap(),
+            );
+            config["mediaType"] = OCI_CONFIG_MEDIA_TYPE.into();
+            store.add_manifest(
+                &image.repository,
+                tag,
+                OCI_MANIFEST_MEDIA_TYPE,
+                json!({
+                    "schemaVersion": 2,
+                    "mediaType": OCI_MANIFEST_MEDIA_TYPE,
+                    "config": config,
+                    "layers": layers,
+                }),
+            )
+        };
+
+        if image.platforms.is_empty() {
+            image_manifest(self, "amd64", Some(&image.tag));
+            return;
+        }
+        let manifests: Vec<Value> = image
+            .platforms
+            .iter()
+            .map(|architecture| {
+                let mut descriptor = image_manifest(self, architecture, None);
+                descriptor["platform"] = json!({ "architecture": architecture, "os": "linux" });
+                descriptor
+            })
+            .collect();
+        self.add_manifest(
+            &image.repository,
+            Some(&image.tag),
+            OCI_INDEX_MEDIA_TYPE,
+            json!({
+                "schemaVersion": 2,
+                "mediaType": OCI_INDEX_MEDIA_TYPE,
+                "manifests": manifests,
+            }),
+        );
+    }
+
+    fn add_artifact(&mut self, artifact: &ArtifactFixture) {
+        let mut config = self.add_blob(b"{}".to_vec());
+        config["mediaType"] = EMPTY_CONFIG_MEDIA_TYPE.into();
+        let mut layer = self.add_blob(artifact.content.clone().into_bytes());
+        layer["mediaType"] = CPIO_MEDIA_TYPE.into();
+
+        let mut manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_MANIFEST_MEDIA_TYPE,
+            "artifactType": ROOTFS_ARTIFACT_TYPE,
+            "config": config,
+            "layers": [layer],
+        });
+        if !artifact.annotations.is_empty() {
+            manifest["annotations"] = json!(artifact.annotations);
+        }
+        self.add_manifest(
+            &artifact.repository,
+            Some(&artifact.tag),
+            OCI_MANIFEST_MEDIA_TYPE,
+            manifest,
+        );
+    }
+}
+
+/// Uncompressed tarball of `entries`, owned by root and dated from the epoch.
+fn build_tar(entries: &[Entry]) -> Vec<u8> {
+    let mut builder = Builder::new(Vec::new());
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+
+        let (parent, name) = match entry.path.rsplit_once('/') {
+            Some((parent, name)) => (format!("{}/", parent), name),
+            None => (String::new(), entry.path.as_str()),
+        };
+        let path = if entry.whiteout {
+            format!("{}.wh.{}", parent, name)
+        } else if entry.opaque {
+            format!("{}/.wh..wh..opq", entry.path)
+        } else {
+            entry.path.clone()
+        };
+
+        let content: &[u8] = if let Some(target) = &entry.symlink {
+            header.set_entry_type(EntryType::Symlink);
+            header.set_mode(0o777);
+            header.set_link_name(target).unwrap();
+            &[]
+        } else if entry.dir {
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            &[]
+        } else {
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o644);
+            entry.content.as_deref().unwrap_or_default().as_bytes()
+        };
+        header.set_size(content.len() as u64);
+
+        if entry.raw {
+            // Around the checks of `set_path`, which rejects the hostile paths.
+            let name = &mut header.as_old_mut().name;
+            name.fill(0);
+            name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_cksum();
+            builder.append(&header, content).unwrap();
+        } else {
+            builder.append_data(&mut header, &path, content).unwrap();
+        }
+    }
+
+    builder.into_inner().unwrap()
+}
@@ -0,0 +1,298 @@
+//! OCI registry serving fixture images, so that the tests of the image pulls of fs-gen and of
+//! the orchestrator don't depend on Docker Hub.
+//!
+//! The registry implements the pull side of the distribution API over plain HTTP, on a random
+//! local port: `GET`/`HEAD` of the manifests, by tag or digest, and of the blobs. With
+//! credentials, it challenges the clients like Docker Hub does, and hands out a bearer token
+//! to those authenticating with the right username and password on its `/token` endpoint.
+//!
+//! ```ignore
+//! let registry = TestRegistry::start();
+//! let image = format!("{}/library/hello:latest", registry.url());
+//! ```
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use fixtures::{Manifest, Store};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+mod fixtures;
+
+pub use fixtures::{OCI_INDEX_MEDIA_TYPE, OCI_MANIFEST_MEDIA_TYPE};
+
+/// Token handed out to the authenticated clients.
+const TOKEN: &str = "test-registry-token";
+
+/// State shared with the connection threads.
+struct State {
+    store: Store,
+    credentials: Option<(String, String)>,
+    url: String,
+    blob_downloads: AtomicUsize,
+}
+
+pub struct TestRegistry {
+    state: Arc<State>,
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestRegistry {
+    /// Registry serving the fixtures of `testdata/images.toml`, without authentication.
+    pub fn start() -> Self {
+        Self::serve(fixtures::IMAGES, None)
+    }
+
+    /// Registry serving the fixtures of `testdata/images.toml` to the clients authenticated
+    /// with `username` and `password`.
+    pub fn with_credentials(username: &str, password: &str) -> Self {
+        Self::serve(
+            fixtures::IMAGES,
+            Some((username.to_string(), password.to_string())),
+        )
+    }
+
+    /// Registry serving `fixtures`, a TOML document in the format of `testdata/images.toml`.
+    pub fn serve(fixtures: &str, credentials: Option<(String, String)>) -> Self {
+        let store = Store::build(fixtures).expect("invalid fixtures");
+        let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind the registry");
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(State {
+            store,
+            credentials,
+            url: format!("http://{}", addr),
+            blob_downloads: AtomicUsize::new(0),
+        });
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (state, stop) = (state.clone(), stop.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let state = state.clone();
+                    thread::spawn(move || {
+                        let _ = handle(stream, &state);
+                    });
+                }
+            })
+        };
+
+        Self {
+            state,
+            addr,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// URL of the registry, e.g. `http://127.0.0.1:41234`.
+    pub fn url(&self) -> &str {
+        &self.state.url
+    }
+
+    /// Digest of the manifest of `repository` tagged `tag`, e.g. to pin it.
+    pub fn digest(&self, repository: &str, tag: &str) -> Option<String> {
+        let manifest = self.state.store.manifests.get(repository)?.get(tag)?;
+        Some(fixtures::digest(&manifest.body))
+    }
+
+    /// Number of blobs downloaded from the registry so far.
+    pub fn blob_downloads(&self) -> usize {
+        self.state.blob_downloads.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for TestRegistry {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake the listener up so that it sees it has to stop.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Empty directory for the files of a test, under the temporary directory.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cloudlet-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("could not create the scratch directory");
+    dir
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn write(&self, mut stream: TcpStream, head: bool) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "",
+        };
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            reason,
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("\r\n");
+
+        stream.write_all(response.as_bytes())?;
+        if !head {
+            stream.write_all(&self.body)?;
+        }
+        stream.flush()
+    }
+}
+
+fn handle(stream: TcpStream, state: &State) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let response = match method.as_str() {
+        "GET" | "HEAD" => state.respond(&target, &headers),
+        _ => Response::new(405, "only pulls are supported"),
+    };
+    response.write(stream, method == "HEAD")
+}
+
+impl State {
+    fn respond(&self, target: &str, headers: &HashMap<String, String>) -> Response {
+        let path = target.split('?').next().unwrap_or_default();
+        if path == "/token" {
+            return self.token(headers);
+        }
+        let Some(path) = path.strip_prefix("/v2/") else {
+            return Response::new(404, "not found");
+        };
+
+        let route = path
+            .rsplit_once("/manifests/")
+            .map(|(repository, reference)| (repository, reference, true))
+            .or_else(|| {
+                path.rsplit_once("/blobs/")
+                    .map(|(repository, digest)| (repository, digest, false))
+            });
+        if !self.authorized(headers) {
+            let scope = match route {
+                Some((repository, _, _)) => format!(",scope=\"repository:{}:pull\"", repository),
+                None => String::new(),
+            };
+            return Response::new(401, "authentication required").header(
+                "WWW-Authenticate",
+                format!(
+                    "Bearer realm=\"{}/token\",service=\"test-registry\"{}",
+                    self.url, scope
+                ),
+            );
+        }
+
+        match route {
+            None if path.is_empty() => Response::new(200, "{}"),
+            None => Response::new(404, "not found"),
+            Some((repository, reference, true)) => {
+                match self
+                    .store
+                    .manifests
+                    .get(repository)
+                    .and_then(|manifests| manifests.get(reference))
+                {
+                    Some(Manifest { media_type, body }) => Response::new(200, body.clone())
+                        .header("Content-Type", *media_type)
+                        .header("Docker-Content-Digest", fixtures::digest(body)),
+                    None => Response::new(404, "manifest unknown"),
+                }
+            }
+            Some((_, digest, false)) => match self.store.blobs.get(digest) {
+                Some(blob) => {
+                    self.blob_downloads.fetch_add(1, Ordering::Relaxed);
+                    Response::new(200, blob.clone())
+                        .header("Content-Type", "application/octet-stream")
+                        .header("Docker-Content-Digest", digest)
+                }
+                None => Response::new(404, "blob unknown"),
+            },
+        }
+    }
+
+    fn authorized(&self, headers: &HashMap<String, String>) -> bool {
+        self.credentials.is_none()
+            || headers.get("authorization") == Some(&format!("Bearer {}", TOKEN))
+    }
+
+    /// Hand a token out to the clients giving the credentials of the registry.
+    fn token(&self, headers: &HashMap<String, String>) -> Response {
+        let Some((username, password)) = &self.credentials else {
+            return Response::new(200, format!("{{\"token\":\"{}\"}}", TOKEN));
+        };
+        let authenticated = headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|value| BASE64_STANDARD.decode(value).ok())
+            .is_some_and(|value| value == format!("{}:{}", username, password).as_bytes());
+
+        if authenticated {
+            Response::new(200, format!("{{\"token\":\"{}\"}}", TOKEN))
+        } else {
+            Response::new(401, "invalid credentials")
+        }
+    }
+}
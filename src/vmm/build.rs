@@ -1,5 +0,0 @@
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("../../proto/vmm.proto")?;
-    tonic_build::compile_protos("../../proto/agent.proto")?;
-    Ok(())
-}
@@ -1,13 +1,21 @@
 //! Command-line arguments.
-use std::{net::Ipv4Addr, path::PathBuf};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
 
 use clap::Parser;
 use clap_verbosity_flag::{InfoLevel, Verbosity};
+use shared_models::{logging::LogArgs, Language};
 use tracing::level_filters;
+use vmm::grpc::startup::OrchestratorArgs;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 pub struct CliArgs {
+    #[command(flatten)]
+    pub log: LogArgs,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -17,7 +25,42 @@ pub enum Commands {
     #[command(about = "Run a VMM instance.")]
     Cli(CliArguments),
     #[command(about = "Run a GRPC server listening for incoming requests.")]
-    Grpc,
+    Grpc(GrpcArguments),
+    #[command(about = "Boot the reference image repeatedly and report boot latencies.")]
+    BenchBoot(BenchBootArguments),
+    #[command(
+        about = "Replay a session recorded with --record-sessions through the orchestrator."
+    )]
+    Replay(ReplayArguments),
+    #[command(
+        about = "Check a configuration file, and the files it names, against each other and this host."
+    )]
+    ValidateConfig(ValidateConfigArguments),
+}
+
+/// Run a GRPC server listening for incoming requests.
+#[derive(Parser, Debug)]
+pub struct GrpcArguments {
+    #[command(flatten)]
+    pub orchestrator: OrchestratorArgs,
+
+    /// Expose the AdminService, which dumps the internal state of the orchestrator for debugging,
+    /// and cordons or drains it for the maintenance of the host (`cli node`).
+    #[arg(long, env)]
+    pub enable_admin: bool,
+
+    /// Address the gRPC server listens on, `[::1]:50051` by default.
+    #[arg(long, env)]
+    pub listen: Option<SocketAddr>,
+
+    /// Listen on a Unix socket at this path instead of the TCP listen address, for the API of the
+    /// same machine to connect to with `unix://<PATH>`.
+    #[arg(long, env)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Permissions of the Unix socket, in octal: who can connect to the orchestrator.
+    #[arg(long, env, default_value = "660", value_parser = shared_models::unix_socket::parse_mode)]
+    pub socket_mode: u32,
 }
 
 /// Run a VMM instance.
@@ -25,13 +68,24 @@ pub enum Commands {
 #[command(author, version, about)]
 pub struct CliArguments {
     /// Path to the image of the Linux kernel to boot.
-    #[arg(short, long, env, required = true)]
-    pub kernel: PathBuf,
+    #[arg(short, long, env, required_unless_present = "config_file")]
+    pub kernel: Option<PathBuf>,
 
     /// Path to the cpio archive to use as the initramfs.
-    #[arg(short, long, env, required = true)]
+    #[arg(short, long, env, required_unless_present_any = ["config_file", "rootfs"])]
     pub initramfs: Option<PathBuf>,
 
+    /// Path to an ext4 or erofs root disk image built by fs-gen, to boot from instead of an
+    /// initramfs. It is attached as the virtio-pmem device, and an ext4 image is changed by the
+    /// guest.
+    #[arg(long, env, conflicts_with_all = ["initramfs", "config_file"])]
+    pub rootfs: Option<PathBuf>,
+
+    /// Firecracker configuration file to take the kernel, the initramfs, the vCPUs, the memory
+    /// and the drive of the guest from, instead of the options.
+    #[arg(long, env, conflicts_with_all = ["kernel", "initramfs"])]
+    pub config_file: Option<PathBuf>,
+
     /// Number of virtual CPUs assigned to the guest.
     #[clap(short, long, env, default_value = "1")]
     pub cpus: u8,
@@ -57,6 +111,80 @@ pub struct CliArguments {
     pub verbose: Verbosity<InfoLevel>,
 }
 
+/// Boot the reference image repeatedly and report boot latencies.
+#[derive(Parser, Debug)]
+pub struct BenchBootArguments {
+    /// Number of boots to measure.
+    #[arg(short = 'n', long, default_value = "10")]
+    pub iterations: usize,
+
+    /// Language of the reference image.
+    #[arg(short, long, value_enum, default_value = "python")]
+    pub language: Language,
+
+    /// Path to the kernel, defaults to the kernel built in tools/kernel.
+    #[arg(short, long)]
+    pub kernel: Option<PathBuf>,
+
+    /// Path to the initramfs, defaults to the image of `language` built in tools/rootfs.
+    #[arg(short, long)]
+    pub initramfs: Option<PathBuf>,
+
+    /// Path to an ext4 or erofs root disk image to boot from instead of an initramfs.
+    #[arg(long, conflicts_with = "initramfs")]
+    pub rootfs: Option<PathBuf>,
+
+    /// Number of boots run before the measured ones, to warm the host caches up.
+    #[arg(long, default_value = "0")]
+    pub warmup: usize,
+
+    /// Number of virtual CPUs assigned to the guest.
+    #[arg(short, long, default_value = "1")]
+    pub cpus: u8,
+
+    /// Memory amount (in MBytes) assigned to the guest.
+    #[arg(short, long, default_value = "512")]
+    pub memory: u32,
+
+    /// Give up on a boot after this many seconds.
+    #[arg(long, default_value = "60")]
+    pub timeout: u64,
+
+    /// Report to compare the results against.
+    #[arg(long)]
+    pub baseline: Option<PathBuf>,
+
+    /// Maximum p50 slowdown allowed against the baseline, in percent.
+    #[arg(long, default_value = "10")]
+    pub max_regression: f64,
+
+    /// Write the report as JSON to this file, to be used as a baseline later.
+    #[arg(long)]
+    pub save_baseline: Option<PathBuf>,
+}
+
+/// Replay a session recorded with --record-sessions through the orchestrator.
+#[derive(Parser, Debug)]
+pub struct ReplayArguments {
+    /// Session file, `<vm id>-<millis>.session`.
+    pub session: PathBuf,
+
+    /// Wait between the responses as long as the agent did, instead of replaying them at once.
+    #[arg(long)]
+    pub realtime: bool,
+
+    /// Seed of the deterministic mode the replay runs in, which the id of its VM is drawn from.
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+/// Check a configuration file, and the files it names, against each other and this host.
+#[derive(Parser, Debug)]
+pub struct ValidateConfigArguments {
+    /// Configuration file, as given to `vmm grpc --config`.
+    pub file: PathBuf,
+}
+
 impl CliArguments {
     /// Get the log level filter.
     pub fn convert_log_to_tracing(&self) -> level_filters::LevelFilter {
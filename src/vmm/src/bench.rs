@@ -0,0 +1,315 @@
+//! `bench-boot`: boot the reference image repeatedly and measure how long it takes.
+//!
+//! The image is an initramfs or, with `--rootfs`, a root disk image. `cloudlet-bench` runs it
+//! over the rootfs formats and languages.
+use crate::args::BenchBootArguments;
+use serde::{Deserialize, Serialize};
+use shared_models::cloudlet::agent::{
+    workload_runner_client::WorkloadRunnerClient, BuildConfig, ExecuteRequest,
+};
+use std::{
+    env::{current_dir, current_exe},
+    error::Error,
+    fs,
+    path::PathBuf,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+    sync::oneshot,
+};
+use tracing::info;
+use vmm::grpc::server::{initramfs_path, KERNEL_PATH};
+
+/// Addresses of the benchmarked guest, the same as the ones used by the orchestrator.
+const HOST_IP: &str = "172.29.0.1";
+const NETMASK: &str = "255.255.0.0";
+const GUEST_IP: &str = "172.29.0.2";
+const AGENT_PORT: u16 = 50051;
+
+/// Line printed by the guest kernel once it's done booting and starts init.
+const KERNEL_BOOTED_MARKER: &str = "Run /init as init process";
+
+/// Delay between two connection attempts to the agent.
+const AGENT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Workload executed to measure the first exec latency, for each language.
+fn reference_code(language: &shared_models::Language) -> &'static str {
+    match language {
+        shared_models::Language::RUST => "fn main() { println!(\"ready\"); }",
+        shared_models::Language::PYTHON => "print(\"ready\")",
+        shared_models::Language::NODE => "console.log(\"ready\");",
+    }
+}
+
+/// Timings of one boot, measured from the start of the VMM process.
+struct Sample {
+    kernel_boot: Option<Duration>,
+    agent_ready: Duration,
+    first_exec: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub min_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Percentiles {
+    fn from_durations(mut durations: Vec<Duration>) -> Option<Self> {
+        if durations.is_empty() {
+            return None;
+        }
+        durations.sort();
+
+        let ms = |d: &Duration| d.as_secs_f64() * 1000.0;
+        // Nearest-rank percentile.
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0) * durations.len() as f64).ceil() as usize;
+            ms(&durations[rank.clamp(1, durations.len()) - 1])
+        };
+
+        Some(Self {
+            min_ms: ms(&durations[0]),
+            p50_ms: percentile(50.0),
+            p90_ms: percentile(90.0),
+            p99_ms: percentile(99.0),
+            max_ms: ms(&durations[durations.len() - 1]),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchReport {
+    pub iterations: usize,
+    /// Absent when the guest console didn't print the boot marker.
+    pub kernel_boot: Option<Percentiles>,
+    pub agent_ready: Percentiles,
+    pub first_exec: Percentiles,
+}
+
+impl BenchReport {
+    fn metrics(&self) -> Vec<(&'static str, Option<&Percentiles>)> {
+        vec![
+            ("kernel-boot", self.kernel_boot.as_ref()),
+            ("agent-ready", Some(&self.agent_ready)),
+            ("first-exec", Some(&self.first_exec)),
+        ]
+    }
+
+    fn print(&self) {
+        println!("{} boots", self.iterations);
+        println!(
+            "{:<12} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            "METRIC", "MIN", "P50", "P90", "P99", "MAX"
+        );
+        for (name, percentiles) in self.metrics() {
+            match percentiles {
+                Some(p) => println!(
+                    "{:<12} {:>7.1}ms {:>7.1}ms {:>7.1}ms {:>7.1}ms {:>7.1}ms",
+                    name, p.min_ms, p.p50_ms, p.p90_ms, p.p99_ms, p.max_ms
+                ),
+                None => println!("{:<12} {:>9}", name, "n/a"),
+            }
+        }
+    }
+
+    /// Return the metrics whose p50 is more than `max_regression` percent slower than in `baseline`.
+    fn regressions(&self, baseline: &BenchReport, max_regression: f64) -> Vec<String> {
+        self.metrics()
+            .into_iter()
+            .zip(baseline.metrics())
+            .filter_map(|((name, current), (_, baseline))| {
+                let (current, baseline) = (current?, baseline?);
+                let slowdown = (current.p50_ms - baseline.p50_ms) / baseline.p50_ms * 100.0;
+                (slowdown > max_regression).then(|| {
+                    format!(
+                        "{}: p50 {:.1}ms vs {:.1}ms in the baseline (+{:.1}%)",
+                        name, current.p50_ms, baseline.p50_ms, slowdown
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+/// Image the benchmarked guest boots from.
+enum BootImage {
+    Initramfs(PathBuf),
+    RootDisk(PathBuf),
+}
+
+/// Boot a VM in a child VMM process, wait for its agent and run the reference workload.
+async fn boot_once(
+    args: &BenchBootArguments,
+    kernel: &PathBuf,
+    image: &BootImage,
+) -> Result<Sample, Box<dyn Error>> {
+    // The VMM puts its stdin in raw mode, give it a terminal of its own.
+    let pty = nix::pty::openpty(None, None)?;
+
+    let (image_arg, image) = match image {
+        BootImage::Initramfs(path) => ("--initramfs", path),
+        BootImage::RootDisk(path) => ("--rootfs", path),
+    };
+    let start = Instant::now();
+    let mut child = Command::new(current_exe()?)
+        .arg("cli")
+        .arg("--kernel")
+        .arg(kernel)
+        .arg(image_arg)
+        .arg(image)
+        .args(["--cpus", &args.cpus.to_string()])
+        .args(["--memory", &args.memory.to_string()])
+        .args(["--iface-host-addr", HOST_IP])
+        .args(["--netmask", NETMASK])
+        .args(["--iface-guest-addr", GUEST_IP])
+        .stdin(Stdio::from(pty.slave))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    // Watch the guest console for the end of the kernel boot, and keep draining it afterwards.
+    let stdout = child.stdout.take().ok_or("VMM stdout is not captured")?;
+    let (booted_tx, mut booted_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let mut booted_tx = Some(booted_tx);
+        let mut lines = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.contains(KERNEL_BOOTED_MARKER) {
+                if let Some(tx) = booted_tx.take() {
+                    let _ = tx.send(start.elapsed());
+                }
+            }
+        }
+    });
+
+    let timeout = Duration::from_secs(args.timeout);
+    let url = format!("http://{}:{}", GUEST_IP, AGENT_PORT);
+    let mut client = loop {
+        if start.elapsed() > timeout {
+            return Err(format!("the agent wasn't ready after {:?}", timeout).into());
+        }
+        if let Ok(Ok(client)) = tokio::time::timeout(
+            Duration::from_millis(500),
+            WorkloadRunnerClient::connect(url.clone()),
+        )
+        .await
+        {
+            break client;
+        }
+        tokio::time::sleep(AGENT_POLL_INTERVAL).await;
+    };
+    let agent_ready = start.elapsed();
+
+    let mut stream = client
+        .execute(ExecuteRequest {
+            workload_name: "bench-boot".into(),
+            language: args.language.as_str().into(),
+            action: 2, // Prepare and run
+            code: reference_code(&args.language).into(),
+            config_str: String::new(),
+            build: Some(BuildConfig {
+                release: true,
+                ..Default::default()
+            }),
+            output_limits: None,
+            env: Default::default(),
+            secret_env: Vec::new(),
+            stages: Vec::new(),
+            artifact: Vec::new(),
+            return_artifact: false,
+            stdin: Vec::new(),
+            power_off: false,
+            keep_on_failure: false,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            tmp_quota_mb: 0,
+            package_cache_url: String::new(),
+            scrub: false,
+        })
+        .await?
+        .into_inner();
+    stream.message().await?;
+    let first_exec = start.elapsed();
+
+    child.kill().await?;
+
+    Ok(Sample {
+        kernel_boot: booted_rx.try_recv().ok(),
+        agent_ready,
+        first_exec,
+    })
+}
+
+pub async fn bench_boot(args: BenchBootArguments) -> Result<(), Box<dyn Error>> {
+    let curr_dir = current_dir()?.into_os_string();
+    let kernel = args.kernel.clone().unwrap_or_else(|| {
+        let mut path = curr_dir.clone();
+        path.push(KERNEL_PATH);
+        PathBuf::from(path)
+    });
+    let image = match (&args.rootfs, &args.initramfs) {
+        (Some(rootfs), _) => BootImage::RootDisk(rootfs.clone()),
+        (None, Some(initramfs)) => BootImage::Initramfs(initramfs.clone()),
+        (None, None) => {
+            BootImage::Initramfs(initramfs_path(&curr_dir, args.language.as_str(), None))
+        }
+    };
+
+    for iteration in 0..args.warmup {
+        let sample = boot_once(&args, &kernel, &image).await?;
+        info!(iteration, first_exec = ?sample.first_exec, "Warm-up boot");
+    }
+    let mut samples = Vec::with_capacity(args.iterations);
+    for iteration in 0..args.iterations {
+        let sample = boot_once(&args, &kernel, &image).await?;
+        info!(
+            iteration,
+            kernel_boot = ?sample.kernel_boot,
+            agent_ready = ?sample.agent_ready,
+            first_exec = ?sample.first_exec,
+            "Boot measured"
+        );
+        samples.push(sample);
+    }
+
+    let report = BenchReport {
+        iterations: samples.len(),
+        kernel_boot: if samples.iter().all(|s| s.kernel_boot.is_some()) {
+            Percentiles::from_durations(samples.iter().filter_map(|s| s.kernel_boot).collect())
+        } else {
+            None
+        },
+        agent_ready: Percentiles::from_durations(samples.iter().map(|s| s.agent_ready).collect())
+            .ok_or("at least one iteration is required")?,
+        first_exec: Percentiles::from_durations(samples.iter().map(|s| s.first_exec).collect())
+            .ok_or("at least one iteration is required")?,
+    };
+    report.print();
+
+    if let Some(path) = &args.save_baseline {
+        fs::write(path, serde_json::to_string_pretty(&report)?)?;
+        println!("Baseline written to {}", path.display());
+    }
+
+    if let Some(path) = &args.baseline {
+        let baseline: BenchReport = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let regressions = report.regressions(&baseline, args.max_regression);
+        if !regressions.is_empty() {
+            for regression in regressions.iter() {
+                eprintln!("Regression: {}", regression);
+            }
+            return Err(format!("{} metric(s) regressed", regressions.len()).into());
+        }
+        println!("No regression against {}", path.display());
+    }
+
+    Ok(())
+}
@@ -14,11 +14,39 @@ const ECX_EPB_SHIFT: u32 = 3; // "Energy Performance Bias" bit.
 const ECX_TSC_DEADLINE_TIMER_SHIFT: u32 = 24; // TSC deadline mode of APIC timer
 const ECX_HYPERVISOR_SHIFT: u32 = 31; // Flag to be set when the cpu is running on a hypervisor.
 const EDX_HTT_SHIFT: u32 = 28; // Hyper Threading Enabled.
+const ECX_VMX_SHIFT: u32 = 5; // Intel virtual machine extensions.
+const ECX_SVM_SHIFT: u32 = 2; // AMD secure virtual machine, in the 0x8000_0001 leaf.
 
-pub(crate) fn filter_cpuid(kvm: &Kvm, vcpu_id: usize, cpu_count: usize, cpuid: &mut CpuId) {
+/// Module parameters enabling nested virtualization in the host KVM.
+const NESTED_PARAMETERS: [&str; 2] = [
+    "/sys/module/kvm_intel/parameters/nested",
+    "/sys/module/kvm_amd/parameters/nested",
+];
+
+/// Whether the host KVM lets guests run their own virtual machines.
+pub fn host_supports_nested() -> bool {
+    NESTED_PARAMETERS.iter().any(|path| {
+        std::fs::read_to_string(path)
+            .map(|value| matches!(value.trim(), "Y" | "1"))
+            .unwrap_or(false)
+    })
+}
+
+/// Set up the CPUID of a vCPU. The virtualization extensions are only exposed when `nested`
+/// is set, and when the host KVM reports them as supported.
+pub(crate) fn filter_cpuid(
+    kvm: &Kvm,
+    vcpu_id: usize,
+    cpu_count: usize,
+    nested: bool,
+    cpuid: &mut CpuId,
+) {
     for entry in cpuid.as_mut_slice().iter_mut() {
         match entry.function {
             1 => {
+                if !nested {
+                    entry.ecx &= !(1 << ECX_VMX_SHIFT);
+                }
                 // X86 hypervisor feature.
                 if entry.index == 0 {
                     entry.ecx |= 1 << ECX_HYPERVISOR_SHIFT;
@@ -37,6 +65,9 @@ pub(crate) fn filter_cpuid(kvm: &Kvm, vcpu_id: usize, cpu_count: usize, cpuid: &
                 // Clear X86 EPB feature. No frequency selection in the hypervisor.
                 entry.ecx &= !(1 << ECX_EPB_SHIFT);
             }
+            0x8000_0001 if !nested => {
+                entry.ecx &= !(1 << ECX_SVM_SHIFT);
+            }
             _ => (),
         }
     }
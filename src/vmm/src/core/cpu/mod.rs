@@ -2,21 +2,20 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use crate::core::devices::serial::{
-    LumperSerial, SERIAL2_PORT_BASE, SERIAL2_PORT_LAST_REGISTER, SERIAL_PORT_BASE,
+    ConsoleOutput, LumperSerial, SERIAL2_PORT_BASE, SERIAL2_PORT_LAST_REGISTER, SERIAL_PORT_BASE,
     SERIAL_PORT_LAST_REGISTER,
 };
 use kvm_bindings::{kvm_fpu, kvm_regs, CpuId};
 use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
 use std::convert::TryInto;
-use std::io::Stdout;
 use std::sync::{Arc, Mutex};
-use std::{io, process};
 use std::{result, u64};
+#[cfg(feature = "core-tracing")]
+use tracing::trace;
 use tracing::{error, info, warn};
 use vm_device::bus::MmioAddress;
 use vm_device::device_manager::{IoManager, MmioManager};
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
-use vmm_sys_util::terminal::Terminal;
 
 pub(crate) mod cpuid;
 mod gdt;
@@ -24,7 +23,9 @@ use gdt::*;
 mod interrupts;
 use interrupts::*;
 
+use super::exit::{ExitState, VmExit};
 use super::slip_pty::SlipPty;
+use super::stats::VcpuStats;
 pub(crate) mod mpspec;
 pub(crate) mod mptable;
 pub(crate) mod msr_index;
@@ -74,10 +75,13 @@ pub(crate) struct Vcpu {
     pub index: u64,
     /// KVM file descriptor for a vCPU.
     pub vcpu_fd: VcpuFd,
+    /// VM-Exits handled by the vCPU.
+    pub stats: Arc<VcpuStats>,
 
     device_mgr: Arc<Mutex<IoManager>>,
-    serial: Arc<Mutex<LumperSerial<Stdout>>>,
+    serial: Arc<Mutex<LumperSerial<ConsoleOutput>>>,
     slip_pty: Arc<Mutex<SlipPty>>,
+    exit: Arc<ExitState>,
 }
 
 impl Vcpu {
@@ -86,15 +90,18 @@ impl Vcpu {
         vm_fd: &VmFd,
         index: u64,
         device_mgr: Arc<Mutex<IoManager>>,
-        serial: Arc<Mutex<LumperSerial<Stdout>>>,
+        serial: Arc<Mutex<LumperSerial<ConsoleOutput>>>,
         slip_pty: Arc<Mutex<SlipPty>>,
+        exit: Arc<ExitState>,
     ) -> Result<Self> {
         Ok(Vcpu {
             index,
             vcpu_fd: vm_fd.create_vcpu(index).map_err(Error::KvmIoctl)?,
+            stats: Arc::new(VcpuStats::new(index)),
             device_mgr,
             serial,
             slip_pty,
+            exit,
         })
     }
 
@@ -234,112 +241,160 @@ impl Vcpu {
         self.vcpu_fd.set_lapic(&klapic).map_err(Error::KvmIoctl)
     }
 
+    /// Whether the VM stopped, after which the vCPU must not be run anymore.
+    pub fn is_stopped(&self) -> bool {
+        self.exit.is_stopped()
+    }
+
     /// vCPU emulation loop.
     pub fn run(&mut self) {
         // Call into KVM to launch (VMLAUNCH) or resume (VMRESUME) the virtual CPU.
         // This is a blocking function, it only returns for either an error or a
         // VM-Exit. In the latter case, we can inspect the exit reason.
+        let stats = &self.stats;
         match self.vcpu_fd.run() {
-            Ok(exit_reason) => match exit_reason {
-                // The VM stopped (Shutdown ot HLT).
-                VcpuExit::Shutdown | VcpuExit::Hlt => {
-                    info!(?exit_reason, "Guest shutdown. Bye!");
-                    let stdin = io::stdin();
-                    let stdin_lock = stdin.lock();
-                    stdin_lock.set_canon_mode().unwrap();
-
-                    unsafe { libc::exit(0) };
-                }
-
-                // This is a PIO write, i.e. the guest is trying to write
-                // something to an I/O port.
-                VcpuExit::IoOut(addr, data) => match addr {
-                    SERIAL_PORT_BASE..=SERIAL_PORT_LAST_REGISTER => {
-                        self.serial
-                            .lock()
-                            .unwrap()
-                            .serial
-                            .write(
-                                (addr - SERIAL_PORT_BASE)
-                                    .try_into()
-                                    .expect("Invalid serial register offset"),
-                                data[0],
-                            )
-                            .unwrap();
+            Ok(exit_reason) => {
+                #[cfg(feature = "core-tracing")]
+                trace!(vcpu = self.index, ?exit_reason, "VM-Exit");
+                match exit_reason {
+                    // The guest halted its CPU for good.
+                    VcpuExit::Hlt => {
+                        info!(?exit_reason, "Guest shutdown. Bye!");
+                        self.exit.stop(VmExit::Shutdown);
                     }
-                    SERIAL2_PORT_BASE..=SERIAL2_PORT_LAST_REGISTER => {
-                        self.slip_pty
-                            .lock()
-                            .unwrap()
-                            .serial_mut()
-                            .serial
-                            .write(
-                                (addr - SERIAL2_PORT_BASE)
-                                    .try_into()
-                                    .expect("Invalid serial register offset"),
-                                data[0],
-                            )
-                            .unwrap();
+                    // KVM stops the vCPU on a triple fault.
+                    VcpuExit::Shutdown => {
+                        error!(vcpu = self.index, "Guest triple fault");
+                        self.exit.stop(VmExit::Crash("triple fault".into()));
                     }
-                    KBD_CMD_IO_ADDR => {
-                        if data[0] == KBD_RESET_CMD {
-                            info!(?exit_reason, "Guest reset via keyboard controller. Bye!");
-                            process::exit(0);
-                        }
+                    VcpuExit::FailEntry(reason, cpu) => {
+                        error!(vcpu = self.index, reason, cpu, "Guest entry failure");
+                        self.exit.stop(VmExit::Crash(format!(
+                            "KVM could not enter the guest: {:#x}",
+                            reason
+                        )));
                     }
-                    _ => {
-                        warn!(address = addr, "Unsupported device write at {:x?}", addr);
+                    VcpuExit::InternalError => {
+                        error!(vcpu = self.index, "KVM internal error");
+                        self.exit.stop(VmExit::Crash("KVM internal error".into()));
                     }
-                },
-
-                // This is a PIO read, i.e. the guest is trying to read
-                // from an I/O port.
-                VcpuExit::IoIn(addr, data) => match addr {
-                    SERIAL_PORT_BASE..=SERIAL_PORT_LAST_REGISTER => {
-                        data[0] = self.serial.lock().unwrap().serial.read(
-                            (addr - SERIAL_PORT_BASE)
-                                .try_into()
-                                .expect("Invalid serial register offset"),
-                        );
+
+                    // This is a PIO write, i.e. the guest is trying to write
+                    // something to an I/O port.
+                    VcpuExit::IoOut(addr, data) => {
+                        stats.io_out_exits.inc();
+                        stats.last_exit_address.set(addr.into());
+                        match addr {
+                            SERIAL_PORT_BASE..=SERIAL_PORT_LAST_REGISTER => {
+                                self.serial
+                                    .lock()
+                                    .unwrap()
+                                    .serial
+                                    .write(
+                                        (addr - SERIAL_PORT_BASE)
+                                            .try_into()
+                                            .expect("Invalid serial register offset"),
+                                        data[0],
+                                    )
+                                    .unwrap();
+                            }
+                            SERIAL2_PORT_BASE..=SERIAL2_PORT_LAST_REGISTER => {
+                                self.slip_pty
+                                    .lock()
+                                    .unwrap()
+                                    .serial_mut()
+                                    .serial
+                                    .write(
+                                        (addr - SERIAL2_PORT_BASE)
+                                            .try_into()
+                                            .expect("Invalid serial register offset"),
+                                        data[0],
+                                    )
+                                    .unwrap();
+                            }
+                            KBD_CMD_IO_ADDR => {
+                                // Given `reboot=k`, the guests reset through the keyboard
+                                // controller, as they do at the end of a power off without
+                                // ACPI.
+                                if data[0] == KBD_RESET_CMD {
+                                    info!(
+                                        ?exit_reason,
+                                        "Guest reset via keyboard controller. Bye!"
+                                    );
+                                    self.exit.stop(VmExit::Shutdown);
+                                }
+                            }
+                            _ => {
+                                warn!(address = addr, "Unsupported device write at {:x?}", addr);
+                            }
+                        }
                     }
-                    SERIAL2_PORT_BASE..=SERIAL2_PORT_LAST_REGISTER => {
-                        data[0] = self.slip_pty.lock().unwrap().serial_mut().serial.read(
-                            (addr - SERIAL2_PORT_BASE)
-                                .try_into()
-                                .expect("Invalid serial register offset"),
-                        );
+
+                    // This is a PIO read, i.e. the guest is trying to read
+                    // from an I/O port.
+                    VcpuExit::IoIn(addr, data) => {
+                        stats.io_in_exits.inc();
+                        stats.last_exit_address.set(addr.into());
+                        match addr {
+                            SERIAL_PORT_BASE..=SERIAL_PORT_LAST_REGISTER => {
+                                data[0] = self.serial.lock().unwrap().serial.read(
+                                    (addr - SERIAL_PORT_BASE)
+                                        .try_into()
+                                        .expect("Invalid serial register offset"),
+                                );
+                            }
+                            SERIAL2_PORT_BASE..=SERIAL2_PORT_LAST_REGISTER => {
+                                data[0] = self.slip_pty.lock().unwrap().serial_mut().serial.read(
+                                    (addr - SERIAL2_PORT_BASE)
+                                        .try_into()
+                                        .expect("Invalid serial register offset"),
+                                );
+                            }
+                            _ => {
+                                warn!(address = addr, "Unsupported device read at {:x?}", addr);
+                            }
+                        }
                     }
-                    _ => {
-                        warn!(address = addr, "Unsupported device read at {:x?}", addr);
+                    VcpuExit::MmioRead(addr, data) => {
+                        stats.mmio_read_exits.inc();
+                        stats.last_exit_address.set(addr);
+                        if self
+                            .device_mgr
+                            .try_lock()
+                            .unwrap()
+                            .mmio_read(MmioAddress(addr), data)
+                            .is_err()
+                        {
+                            stats.errors.inc();
+                            error!("Failed to read from mmio addr={} data={:#?}", addr, data);
+                        }
                     }
-                },
-                VcpuExit::MmioRead(addr, data) => {
-                    if self
-                        .device_mgr
-                        .try_lock()
-                        .unwrap()
-                        .mmio_read(MmioAddress(addr), data)
-                        .is_err()
-                    {
-                        error!("Failed to read from mmio addr={} data={:#?}", addr, data);
+                    VcpuExit::MmioWrite(addr, data) => {
+                        stats.mmio_write_exits.inc();
+                        stats.last_exit_address.set(addr);
+                        if self
+                            .device_mgr
+                            .try_lock()
+                            .unwrap()
+                            .mmio_write(MmioAddress(addr), data)
+                            .is_err()
+                        {
+                            stats.errors.inc();
+                            error!("Failed to write to mmio");
+                        }
                     }
-                }
-                VcpuExit::MmioWrite(addr, data) => {
-                    if self
-                        .device_mgr
-                        .try_lock()
-                        .unwrap()
-                        .mmio_write(MmioAddress(addr), data)
-                        .is_err()
-                    {
-                        error!("Failed to write to mmio");
+                    _ => {
+                        stats.other_exits.inc();
+                        error!(?exit_reason, "Unhandled VM-Exit");
                     }
                 }
-                _ => {
-                    error!(?exit_reason, "Unhandled VM-Exit");
-                }
-            },
-            Err(e) => error!(?e, "Emulation error"),
+            }
+            // Kicked out of `KVM_RUN` to see the VM stopped.
+            Err(e) if e.errno() == libc::EINTR => {}
+            Err(e) => {
+                stats.errors.inc();
+                error!(?e, "Emulation error");
+            }
         }
     }
 }
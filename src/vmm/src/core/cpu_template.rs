@@ -0,0 +1,372 @@
+//! CPU templates, masking the CPUID the guests see so that it doesn't depend on the host.
+//!
+//! A workload built in a guest, e.g. by a compiler targeting the native CPU, only uses the
+//! features its CPUID advertised. With the same template on every host of a deployment, what is
+//! built on one of them runs on the others, whatever their CPU generation. The templates are:
+//!
+//! * `host`, the CPUID of the host as KVM supports it, the default;
+//! * `baseline`, x86-64-v3 (AVX2, BMI, FMA) without AVX-512, TSX, AMX and the vendor-specific
+//!   extensions, which the CPUs of the last decade all provide;
+//! * a JSON file of custom modifiers, in the format of the custom CPU templates of Firecracker:
+//!
+//! ```json
+//! {"cpuid_modifiers": [{"leaf": "0x7", "subleaf": "0x0", "modifiers": [
+//!     {"register": "ebx", "bitmap": "0b0xxxxxxx_xxxxxxxx_xxxxxxxx_xxxxxxxx"}]}]}
+//! ```
+//!
+//! Each bitmap gives the 32 bits of a register, from bit 31 to bit 0: `0` and `1` force the bit,
+//! `x` keeps the bit of the host. A template can only hide features safely: forcing a feature
+//! the host lacks makes the guest fault when it uses it.
+
+use kvm_bindings::{CpuId, KVM_CPUID_FLAG_SIGNIFCANT_INDEX};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Debug)]
+pub enum CpuTemplateError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, String),
+}
+
+impl fmt::Display for CpuTemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuTemplateError::Read(path, e) => write!(f, "Failed to read {:?}: {}", path, e),
+            CpuTemplateError::Parse(path, e) => {
+                write!(f, "Invalid CPU template {:?}: {}", path, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuTemplateError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Register {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// Bits of a register of a CPUID leaf forced to `value` where `mask` is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuidModifier {
+    pub leaf: u32,
+    /// Index of the leaf, any when unset.
+    pub subleaf: Option<u32>,
+    pub register: Register,
+    pub mask: u32,
+    pub value: u32,
+}
+
+impl CpuidModifier {
+    /// Clear the `bits` of `register` in the leaf `leaf`, index `subleaf`.
+    const fn clear(leaf: u32, subleaf: Option<u32>, register: Register, bits: u32) -> Self {
+        Self {
+            leaf,
+            subleaf,
+            register,
+            mask: bits,
+            value: 0,
+        }
+    }
+}
+
+/// Bit `n` of a register.
+const fn bit(n: u32) -> u32 {
+    1 << n
+}
+
+/// The features the `baseline` template hides.
+const BASELINE: &[CpuidModifier] = &[
+    // Leaf 7: HLE, RTM, MPX, AVX-512 (F, DQ, IFMA, PF, ER, CD, BW, VL), RDSEED and ADX are
+    // kept out of x86-64-v3, as are SHA, CLFLUSHOPT, CLWB and PT.
+    CpuidModifier::clear(
+        0x7,
+        Some(0),
+        Register::Ebx,
+        bit(4)
+            | bit(11)
+            | bit(14)
+            | bit(16)
+            | bit(17)
+            | bit(18)
+            | bit(19)
+            | bit(21)
+            | bit(23)
+            | bit(24)
+            | bit(25)
+            | bit(26)
+            | bit(27)
+            | bit(28)
+            | bit(29)
+            | bit(30)
+            | bit(31),
+    ),
+    // AVX512_VBMI, PKU, OSPKE, WAITPKG, AVX512_VBMI2, GFNI, VAES, VPCLMULQDQ, AVX512_VNNI,
+    // AVX512_BITALG, AVX512_VPOPCNTDQ, LA57, RDPID, MOVDIRI and MOVDIR64B.
+    CpuidModifier::clear(
+        0x7,
+        Some(0),
+        Register::Ecx,
+        bit(1)
+            | bit(3)
+            | bit(4)
+            | bit(5)
+            | bit(6)
+            | bit(8)
+            | bit(9)
+            | bit(10)
+            | bit(11)
+            | bit(12)
+            | bit(14)
+            | bit(16)
+            | bit(22)
+            | bit(27)
+            | bit(28),
+    ),
+    // AVX512_4VNNIW, AVX512_4FMAPS, SERIALIZE, hybrid, AMX-BF16, AVX512_FP16, AMX-TILE and
+    // AMX-INT8.
+    CpuidModifier::clear(
+        0x7,
+        Some(0),
+        Register::Edx,
+        bit(2) | bit(3) | bit(14) | bit(15) | bit(22) | bit(23) | bit(24) | bit(25),
+    ),
+    // AVX-VNNI and AVX512_BF16.
+    CpuidModifier::clear(0x7, Some(1), Register::Eax, bit(4) | bit(5)),
+    // XSAVEC and XSAVES, whose layouts differ between the generations.
+    CpuidModifier::clear(0xd, Some(1), Register::Eax, bit(1) | bit(3)),
+    // SSE4A, XOP, FMA4 and TBM, found on AMD alone.
+    CpuidModifier::clear(
+        0x8000_0001,
+        None,
+        Register::Ecx,
+        bit(6) | bit(11) | bit(16) | bit(21),
+    ),
+    // 1 GB pages.
+    CpuidModifier::clear(0x8000_0001, None, Register::Edx, bit(26)),
+];
+
+/// Custom template, as read from its file.
+#[derive(Debug, Deserialize)]
+struct CustomTemplateFile {
+    cpuid_modifiers: Vec<LeafModifiers>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LeafModifiers {
+    leaf: String,
+    #[serde(default)]
+    subleaf: Option<String>,
+    modifiers: Vec<RegisterModifier>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterModifier {
+    register: Register,
+    bitmap: String,
+}
+
+fn parse_number(value: &str) -> Result<u32, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid leaf {:?}", value))
+}
+
+/// Mask and value of a bitmap, e.g. `0bxxxx0001...`, from bit 31 to bit 0.
+fn parse_bitmap(bitmap: &str) -> Result<(u32, u32), String> {
+    let bits: Vec<char> = bitmap
+        .strip_prefix("0b")
+        .unwrap_or(bitmap)
+        .chars()
+        .filter(|c| *c != '_')
+        .collect();
+    if bits.len() != 32 {
+        return Err(format!("the bitmap {:?} doesn't have 32 bits", bitmap));
+    }
+    let (mut mask, mut value) = (0, 0);
+    for (i, c) in bits.iter().enumerate() {
+        let bit = 1 << (31 - i);
+        match c {
+            '0' => mask |= bit,
+            '1' => {
+                mask |= bit;
+                value |= bit;
+            }
+            'x' | 'X' => {}
+            c => return Err(format!("invalid bit {:?} in the bitmap {:?}", c, bitmap)),
+        }
+    }
+    Ok((mask, value))
+}
+
+/// CPUID presented to the guests.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CpuTemplate {
+    #[default]
+    Host,
+    Baseline,
+    Custom {
+        path: PathBuf,
+        /// Digest of the file, telling the templates apart.
+        sha256: String,
+        modifiers: Vec<CpuidModifier>,
+    },
+}
+
+impl CpuTemplate {
+    /// Read the custom template at `path`.
+    pub fn from_file(path: &Path) -> Result<Self, CpuTemplateError> {
+        let content = fs::read(path).map_err(|e| CpuTemplateError::Read(path.to_path_buf(), e))?;
+        let invalid = |e: String| CpuTemplateError::Parse(path.to_path_buf(), e);
+        let file: CustomTemplateFile =
+            serde_json::from_slice(&content).map_err(|e| invalid(e.to_string()))?;
+
+        let mut modifiers = Vec::new();
+        for leaf in file.cpuid_modifiers {
+            let number = parse_number(&leaf.leaf).map_err(invalid)?;
+            let subleaf = leaf
+                .subleaf
+                .as_deref()
+                .map(parse_number)
+                .transpose()
+                .map_err(invalid)?;
+            for modifier in leaf.modifiers {
+                let (mask, value) = parse_bitmap(&modifier.bitmap).map_err(invalid)?;
+                modifiers.push(CpuidModifier {
+                    leaf: number,
+                    subleaf,
+                    register: modifier.register,
+                    mask,
+                    value,
+                });
+            }
+        }
+        Ok(CpuTemplate::Custom {
+            path: path.to_path_buf(),
+            sha256: format!("{:x}", Sha256::digest(&content)),
+            modifiers,
+        })
+    }
+
+    pub fn modifiers(&self) -> &[CpuidModifier] {
+        match self {
+            CpuTemplate::Host => &[],
+            CpuTemplate::Baseline => BASELINE,
+            CpuTemplate::Custom { modifiers, .. } => modifiers,
+        }
+    }
+
+    /// What the builds made with the template depend on, none for the CPUID of the host.
+    pub fn fingerprint(&self) -> Option<String> {
+        match self {
+            CpuTemplate::Host => None,
+            CpuTemplate::Baseline => Some("baseline".to_string()),
+            CpuTemplate::Custom { sha256, .. } => Some(format!("custom:{}", sha256)),
+        }
+    }
+
+    /// Apply the template to the CPUID of a vCPU.
+    pub fn apply(&self, cpuid: &mut CpuId) {
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            for modifier in self.modifiers() {
+                let indexed = entry.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX != 0;
+                if modifier.leaf != entry.function
+                    || modifier
+                        .subleaf
+                        .is_some_and(|subleaf| indexed && subleaf != entry.index)
+                {
+                    continue;
+                }
+                let register = match modifier.register {
+                    Register::Eax => &mut entry.eax,
+                    Register::Ebx => &mut entry.ebx,
+                    Register::Ecx => &mut entry.ecx,
+                    Register::Edx => &mut entry.edx,
+                };
+                *register = (*register & !modifier.mask) | (modifier.value & modifier.mask);
+            }
+        }
+    }
+}
+
+impl FromStr for CpuTemplate {
+    type Err = String;
+
+    /// `host`, `baseline` or the path of a custom template.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "host" => Ok(CpuTemplate::Host),
+            "baseline" => Ok(CpuTemplate::Baseline),
+            path => CpuTemplate::from_file(Path::new(path)).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CpuTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuTemplate::Host => write!(f, "host"),
+            CpuTemplate::Baseline => write!(f, "baseline"),
+            CpuTemplate::Custom { path, .. } => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_bindings::kvm_cpuid_entry2;
+
+    fn entry(function: u32, index: u32, ebx: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            index,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            ebx,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_apply_cpu_templates() {
+        let avx512f = bit(16);
+        let avx2 = bit(5);
+        let mut cpuid =
+            CpuId::from_entries(&[entry(0x7, 0, avx2 | avx512f), entry(0x7, 1, avx512f)]).unwrap();
+        CpuTemplate::Host.apply(&mut cpuid);
+        assert_eq!(cpuid.as_slice()[0].ebx, avx2 | avx512f);
+
+        CpuTemplate::Baseline.apply(&mut cpuid);
+        assert_eq!(cpuid.as_slice()[0].ebx, avx2);
+        // Only the index 0 of the leaf 7 is masked.
+        assert_eq!(cpuid.as_slice()[1].ebx, avx512f);
+
+        let path =
+            std::env::temp_dir().join(format!("vmm-cpu-template-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"cpuid_modifiers": [{"leaf": "0x7", "subleaf": "0x1", "modifiers": [
+                {"register": "ebx", "bitmap": "0bxxxxxxxx_xxxxxxx0_xxxxxxxx_xx1xxxxx"}]}]}"#,
+        )
+        .unwrap();
+        let template: CpuTemplate = path.to_str().unwrap().parse().unwrap();
+        fs::remove_file(&path).unwrap();
+        template.apply(&mut cpuid);
+        assert_eq!(cpuid.as_slice()[1].ebx, avx2);
+        assert!(template.fingerprint().unwrap().starts_with("custom:"));
+
+        assert!(parse_bitmap("0b0101").is_err());
+        assert!("missing-template.json".parse::<CpuTemplate>().is_err());
+    }
+}
@@ -18,6 +18,9 @@ pub const SERIAL_PORT_LAST_REGISTER: u16 = SERIAL_PORT_BASE + 0x8;
 pub const SERIAL2_PORT_BASE: u16 = 0x2f8;
 pub const SERIAL2_PORT_LAST_REGISTER: u16 = SERIAL2_PORT_BASE + 0x8;
 
+/// Where the console of the guest, on its first serial port, is written.
+pub type ConsoleOutput = Box<dyn Write + Send>;
+
 pub struct EventFdTrigger(EventFd);
 
 impl Trigger for EventFdTrigger {
@@ -1,6 +1,9 @@
 pub mod net;
+pub mod pmem;
 mod register;
 
+use crate::core::stats::DeviceStats;
+
 use event_manager::{
     Error as EvmgrError, MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId,
 };
@@ -73,10 +76,12 @@ pub struct Config {
     endpoint: RemoteEndpoint<Subscriber>,
     vm_fd: Arc<VmFd>,
     pub irqfd: Arc<EventFd>,
+    pub stats: Arc<DeviceStats>,
 }
 
 impl Config {
     pub fn new(
+        name: &'static str,
         virtio: VirtioConfig<Queue>,
         mmio: MmioConfig,
         endpoint: RemoteEndpoint<Subscriber>,
@@ -94,6 +99,7 @@ impl Config {
             endpoint,
             vm_fd,
             irqfd,
+            stats: Arc::new(DeviceStats::new(name)),
         })
     }
 
@@ -175,12 +181,14 @@ pub trait SignalUsedQueue {
 pub struct SingleFdSignalQueue {
     pub irqfd: Arc<EventFd>,
     pub interrupt_status: Arc<AtomicU8>,
+    pub stats: Arc<DeviceStats>,
 }
 
 impl SignalUsedQueue for SingleFdSignalQueue {
     fn signal_used_queue(&self, _index: u16) {
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+        self.stats.interrupts.inc();
 
         self.irqfd
             .write(1)
@@ -1,17 +1,17 @@
 use super::bridge::Bridge;
-use super::iptables::iptables_ip_masq;
 use super::queue_handler::QueueHandler;
+use super::{guest_bridge, BridgeConfig};
 use super::{
     simple_handler::SimpleHandler, tuntap::tap::Tap, Error, Result, NET_DEVICE_ID,
     VIRTIO_NET_HDR_SIZE,
 };
 use crate::core::devices::virtio::features::VIRTIO_F_RING_EVENT_IDX;
 use crate::core::devices::virtio::net::tuntap::open_tap::open_tap;
-use crate::core::devices::virtio::net::BRIDGE_NAME;
 use crate::core::devices::virtio::register::register_mmio_device;
 use crate::core::devices::virtio::{
     self, Config, MmioConfig, SingleFdSignalQueue, Subscriber, QUEUE_MAX_SIZE,
 };
+use crate::core::rate_limiter::{IoLimits, RateLimiter};
 use event_manager::RemoteEndpoint;
 use kvm_ioctls::VmFd;
 use std::net::Ipv4Addr;
@@ -39,6 +39,7 @@ pub struct Net {
     pub config: Config,
     tap: Arc<Mutex<Tap>>,
     _bridge: Bridge,
+    io_limits: IoLimits,
 }
 
 impl Net {
@@ -50,6 +51,8 @@ impl Net {
         iface_host_addr: Ipv4Addr,
         netmask: Ipv4Addr,
         iface_guest_addr: Ipv4Addr,
+        bridge_config: &BridgeConfig,
+        io_limits: IoLimits,
         irq: u32,
         endpoint: RemoteEndpoint<Subscriber>,
         vm_fd: Arc<VmFd>,
@@ -75,7 +78,8 @@ impl Net {
 
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
-        let cfg = Config::new(virtio_cfg, mmio_cfg, endpoint, vm_fd).map_err(Error::Virtio)?;
+        let cfg =
+            Config::new("net", virtio_cfg, mmio_cfg, endpoint, vm_fd).map_err(Error::Virtio)?;
 
         // Set offload flags to match the relevant virtio features of the device (for now,
         // statically set in the constructor.
@@ -86,13 +90,15 @@ impl Net {
         tap.set_vnet_hdr_size(VIRTIO_NET_HDR_SIZE as i32)
             .map_err(Error::Tap)?;
 
-        let bridge_name = BRIDGE_NAME;
-        let bridge = Bridge::new(bridge_name).await.map_err(Error::Bridge)?;
-
-        bridge
-            .set_addr(iface_host_addr, netmask)
-            .await
-            .map_err(Error::Bridge)?;
+        let bridge_name = bridge_config.name.as_str();
+        let bridge = guest_bridge(
+            bridge_name,
+            iface_host_addr,
+            netmask,
+            bridge_config.isolated,
+            bridge_config.uplink.as_deref(),
+        )
+        .await?;
 
         bridge
             .attach_link(tap.get_name().map_err(Error::Tap)?)
@@ -104,17 +110,12 @@ impl Net {
             bridge_name
         );
 
-        bridge.set_up().await.map_err(Error::Bridge)?;
-        info!("bridge {} set UP", bridge_name);
-
-        // Get internet access
-        iptables_ip_masq(iface_host_addr & netmask, netmask, bridge_name.into());
-
         let net = Arc::new(Mutex::new(Net {
             mem,
             config: cfg,
             tap: Arc::new(Mutex::new(tap.clone())),
             _bridge: bridge,
+            io_limits,
         }));
 
         let vmmio_param = register_mmio_device(mmio_cfg, device_mgr, irq, None, net.clone())
@@ -156,18 +157,36 @@ impl VirtioDeviceActions for Net {
         let driver_notify = SingleFdSignalQueue {
             irqfd: self.config.irqfd.clone(),
             interrupt_status: self.config.virtio.interrupt_status.clone(),
+            stats: self.config.stats.clone(),
         };
 
         let mut ioevents = self.config.prepare_activate().map_err(Error::Virtio)?;
 
         let rxq = self.config.virtio.queues.remove(0);
         let txq = self.config.virtio.queues.remove(0);
-        let inner = SimpleHandler::new(driver_notify, rxq, txq, self.tap.clone(), self.mem.clone());
+        // Each way has a limiter of its own: a guest receiving at its limit can still send.
+        let limiter = || {
+            RateLimiter::new(
+                self.io_limits.net_bytes_per_sec,
+                self.io_limits.net_packets_per_sec,
+            )
+            .map_err(Error::RateLimiter)
+        };
+        let inner = SimpleHandler::new(
+            driver_notify,
+            rxq,
+            txq,
+            self.tap.clone(),
+            self.mem.clone(),
+            limiter()?,
+            limiter()?,
+        );
 
         let handler = Arc::new(Mutex::new(QueueHandler {
             inner,
             rx_ioevent: ioevents.remove(0),
             tx_ioevent: ioevents.remove(0),
+            stats: self.config.stats.clone(),
         }));
 
         self.config
@@ -1,16 +1,67 @@
 use std::net::Ipv4Addr;
 
 use super::xx_netmask_width;
+use crate::core::network::GUEST_NETWORKS;
 
-pub fn iptables_ip_masq(network: Ipv4Addr, netmask: Ipv4Addr, link_name: String) {
+/// Masquerade the traffic of the guests of `link_name` going out of the host, only through
+/// `uplink` if set.
+pub fn iptables_ip_masq(
+    network: Ipv4Addr,
+    netmask: Ipv4Addr,
+    link_name: String,
+    uplink: Option<&str>,
+) {
     let prefix_len = xx_netmask_width(netmask.octets());
     let source = format!("{}/{}", network, prefix_len);
 
     let ipt = iptables::new(false).unwrap();
-    let rule = format!("-s {} ! -o {} -j MASQUERADE", source, link_name);
+    let rule = match uplink {
+        Some(uplink) => format!("-s {} -o {} -j MASQUERADE", source, uplink),
+        None => format!("-s {} ! -o {} -j MASQUERADE", source, link_name),
+    };
 
     let exists = ipt.exists("nat", "POSTROUTING", rule.as_str()).unwrap();
     if !exists {
         let _ = ipt.insert_unique("nat", "POSTROUTING", rule.as_str(), 1);
     }
 }
+
+/// Drop the traffic routed from the guests of `link_name` to any other interface than `uplink`,
+/// the traffic between the guests of the bridge being kept.
+pub fn iptables_bind_uplink(link_name: &str, uplink: &str) -> Result<(), String> {
+    let ipt = iptables::new(false).map_err(|e| e.to_string())?;
+    // Each inserted at the top of the chain, the `ACCEPT` within the bridge ends up first.
+    let rules = [
+        format!("-i {} ! -o {} -j DROP", link_name, uplink),
+        format!("-i {} -o {} -j ACCEPT", link_name, link_name),
+    ];
+    for rule in rules {
+        let exists = ipt
+            .exists("filter", "FORWARD", &rule)
+            .map_err(|e| e.to_string())?;
+        if !exists {
+            ipt.insert("filter", "FORWARD", &rule, 1)
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Drop the traffic routed from the guests of `link_name` to the other guest networks.
+pub fn iptables_isolate(link_name: &str) -> Result<(), String> {
+    let (network, prefix_len) = GUEST_NETWORKS;
+    let ipt = iptables::new(false).map_err(|e| e.to_string())?;
+    let rule = format!(
+        "-i {} -d {}/{} ! -o {} -j DROP",
+        link_name, network, prefix_len, link_name
+    );
+
+    let exists = ipt
+        .exists("filter", "FORWARD", &rule)
+        .map_err(|e| e.to_string())?;
+    if !exists {
+        ipt.insert("filter", "FORWARD", &rule, 1)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
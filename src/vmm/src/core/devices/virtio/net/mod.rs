@@ -7,13 +7,38 @@ pub mod tuntap;
 
 use crate::core::devices::virtio;
 
+use self::bridge::Bridge;
+use self::iptables::{iptables_bind_uplink, iptables_ip_masq, iptables_isolate};
 use self::tuntap::{open_tap, tap};
+use std::net::Ipv4Addr;
+use tracing::info;
 
 const NET_DEVICE_ID: u32 = 1;
 const VIRTIO_NET_HDR_SIZE: usize = 12;
 const RXQ_INDEX: u16 = 0;
 const TXQ_INDEX: u16 = 1;
-const BRIDGE_NAME: &str = "br0";
+/// Bridge shared by the guests which aren't isolated.
+pub const BRIDGE_NAME: &str = "br0";
+
+/// Bridge the TAP device of a guest is attached to.
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    pub name: String,
+    /// The traffic routed to the other guest networks is dropped.
+    pub isolated: bool,
+    /// Host interface the traffic to the outside goes through, any interface if unset.
+    pub uplink: Option<String>,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            name: BRIDGE_NAME.to_string(),
+            isolated: false,
+            uplink: None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -21,10 +46,147 @@ pub enum Error {
     TunTap(open_tap::Error),
     Tap(tap::Error),
     Bridge(bridge::Error),
+    Isolate(String),
+    RateLimiter(std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Bridge `name`, up with the host address `addr`, its guests reaching the outside through the
+/// host, only through `uplink` if set, and, if `isolated`, none of the other guest networks.
+pub(crate) async fn guest_bridge(
+    name: &str,
+    addr: Ipv4Addr,
+    netmask: Ipv4Addr,
+    isolated: bool,
+    uplink: Option<&str>,
+) -> Result<Bridge> {
+    let bridge = Bridge::new(name).await.map_err(Error::Bridge)?;
+    bridge
+        .set_addr(addr, netmask)
+        .await
+        .map_err(Error::Bridge)?;
+    bridge.set_up().await.map_err(Error::Bridge)?;
+    info!("bridge {} set UP", name);
+
+    // Get internet access
+    iptables_ip_masq(addr & netmask, netmask, name.into(), uplink);
+    if let Some(uplink) = uplink {
+        iptables_bind_uplink(name, uplink).map_err(Error::Isolate)?;
+        info!("bridge {} bound to the uplink {}", name, uplink);
+    }
+    if isolated {
+        iptables_isolate(name).map_err(Error::Isolate)?;
+        info!("bridge {} isolated from the other guest networks", name);
+    }
+    Ok(bridge)
+}
+
 pub fn xx_netmask_width<const SZ: usize>(netmask: [u8; SZ]) -> u8 {
     netmask.iter().map(|x| x.count_ones() as u8).sum()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn run(program: &str, args: &[&str]) -> bool {
+        Command::new(program)
+            .args(args)
+            .status()
+            .map_or(false, |status| status.success())
+    }
+
+    /// Guest stand-ins: network namespaces attached to the tenant bridges by veth pairs.
+    struct Guests(Vec<(String, String)>);
+
+    impl Guests {
+        async fn attach(&mut self, bridge: &Bridge, index: u8) {
+            let (namespace, link) = (format!("cltn-test-{}", index), format!("cltv{}", index));
+            let (peer, subnet) = (format!("{}g", link), format!("172.29.{}", 200 + index));
+            assert!(run("ip", &["netns", "add", &namespace]));
+            assert!(run(
+                "ip",
+                &["link", "add", &link, "type", "veth", "peer", "name", &peer]
+            ));
+            self.0.push((namespace.clone(), link.clone()));
+            assert!(run("ip", &["link", "set", &peer, "netns", &namespace]));
+            bridge.attach_link(link.clone()).await.unwrap();
+            assert!(run("ip", &["link", "set", &link, "up"]));
+
+            let exec = |args: &[&str]| {
+                let mut command = vec!["netns", "exec", namespace.as_str(), "ip"];
+                command.extend(args);
+                assert!(run("ip", &command));
+            };
+            exec(&["addr", "add", &format!("{}.2/24", subnet), "dev", &peer]);
+            exec(&["link", "set", &peer, "up"]);
+            exec(&["route", "add", "default", "via", &format!("{}.1", subnet)]);
+        }
+
+        fn ping(&self, index: u8, address: &str) -> bool {
+            let namespace = format!("cltn-test-{}", index);
+            run(
+                "ip",
+                &[
+                    "netns", "exec", &namespace, "ping", "-c", "1", "-W", "1", address,
+                ],
+            )
+        }
+    }
+
+    impl Drop for Guests {
+        fn drop(&mut self) {
+            for (namespace, link) in &self.0 {
+                run("ip", &["link", "del", link]);
+                run("ip", &["netns", "del", namespace]);
+            }
+            let ipt = iptables::new(false).unwrap();
+            for index in [1, 2] {
+                let bridge = format!("cltntest{}", index);
+                run("ip", &["link", "del", &bridge]);
+                let isolate = format!("-i {} -d 172.29.0.0/16 ! -o {} -j DROP", bridge, bridge);
+                let masquerade = format!(
+                    "-s 172.29.{}.0/24 ! -o {} -j MASQUERADE",
+                    200 + index,
+                    bridge
+                );
+                let _ = ipt.delete("filter", "FORWARD", &isolate);
+                let _ = ipt.delete("nat", "POSTROUTING", &masquerade);
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs root: creates bridges, network namespaces and iptables rules"]
+    async fn test_tenants_cannot_reach_each_other() {
+        assert!(run("sysctl", &["-qw", "net.ipv4.ip_forward=1"]));
+        let netmask = Ipv4Addr::new(255, 255, 255, 0);
+        let mut guests = Guests(Vec::new());
+        for index in [1, 2] {
+            let name = format!("cltntest{}", index);
+            let bridge = guest_bridge(
+                &name,
+                Ipv4Addr::new(172, 29, 200 + index, 1),
+                netmask,
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+            guests.attach(&bridge, index).await;
+        }
+        // Routed through the host until the bridges are isolated.
+        assert!(guests.ping(1, "172.29.202.2"));
+
+        for bridge in ["cltntest1", "cltntest2"] {
+            iptables_isolate(bridge).unwrap();
+        }
+        assert!(!guests.ping(1, "172.29.202.2"));
+        assert!(!guests.ping(2, "172.29.201.2"));
+        // The guests still reach the host.
+        assert!(guests.ping(1, "172.29.201.1"));
+        assert!(guests.ping(2, "172.29.202.1"));
+    }
+}
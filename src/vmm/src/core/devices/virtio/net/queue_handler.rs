@@ -1,17 +1,22 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use super::simple_handler::SimpleHandler;
+use super::simple_handler::{Error, SimpleHandler};
 use crate::core::devices::virtio::SignalUsedQueue;
+use crate::core::stats::DeviceStats;
 use event_manager::{EventOps, Events, MutEventSubscriber};
 use log::error;
 use std::os::fd::AsRawFd;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 
 const TAPFD_DATA: u32 = 0;
 const RX_IOEVENT_DATA: u32 = 1;
 const TX_IOEVENT_DATA: u32 = 2;
+const RX_RATE_LIMITER_DATA: u32 = 3;
+const TX_RATE_LIMITER_DATA: u32 = 4;
 
 pub struct QueueHandler<S>
 where
@@ -20,6 +25,7 @@ where
     pub inner: SimpleHandler<S>,
     pub rx_ioevent: EventFd,
     pub tx_ioevent: EventFd,
+    pub stats: Arc<DeviceStats>,
 }
 
 impl<S> QueueHandler<S>
@@ -30,10 +36,15 @@ where
     // which is used to unregister all events.
     fn handle_error<A: AsRef<str>>(&self, s: A, ops: &mut EventOps) {
         error!("{}", s.as_ref());
+        self.stats.errors.inc();
         ops.remove(Events::empty(&self.rx_ioevent))
             .expect("Failed to remove rx ioevent");
         ops.remove(Events::empty(&self.tx_ioevent))
             .expect("Failed to remove tx ioevent");
+        ops.remove(Events::empty(&self.inner.rx_rate_limiter))
+            .expect("Failed to remove rx rate limiter event");
+        ops.remove(Events::empty(&self.inner.tx_rate_limiter))
+            .expect("Failed to remove tx rate limiter event");
         ops.remove(Events::empty(
             &self
                 .inner
@@ -61,11 +72,13 @@ where
 
         match events.data() {
             TAPFD_DATA => {
+                self.stats.backend_events.inc();
                 if let Err(e) = self.inner.process_tap() {
                     self.handle_error(format!("Process tap error {:?}", e), ops);
                 }
             }
             RX_IOEVENT_DATA => {
+                self.stats.queue_notifications.inc();
                 if self.rx_ioevent.read().is_err() {
                     self.handle_error("Rx ioevent read", ops);
                 } else if let Err(e) = self.inner.process_rxq() {
@@ -73,10 +86,34 @@ where
                 }
             }
             TX_IOEVENT_DATA => {
+                self.stats.queue_notifications.inc();
                 if self.tx_ioevent.read().is_err() {
                     self.handle_error("Tx ioevent read", ops);
                 }
-                if let Err(e) = self.inner.process_txq() {
+                let result = self.inner.process_txq();
+                self.stats
+                    .tx_bytes
+                    .store(self.inner.tx_bytes, Ordering::Relaxed);
+                if let Err(e) = result {
+                    self.handle_error(format!("Process tx error {:?}", e), ops);
+                }
+            }
+            RX_RATE_LIMITER_DATA => {
+                if let Err(e) = self.inner.rx_rate_limiter.event_handler() {
+                    self.handle_error(format!("Rx rate limiter error {:?}", e), ops);
+                } else if let Err(e) = self.inner.process_tap() {
+                    self.handle_error(format!("Process tap error {:?}", e), ops);
+                }
+            }
+            TX_RATE_LIMITER_DATA => {
+                let result = match self.inner.tx_rate_limiter.event_handler() {
+                    Ok(()) => self.inner.process_txq(),
+                    Err(e) => Err(Error::RateLimiter(e)),
+                };
+                self.stats
+                    .tx_bytes
+                    .store(self.inner.tx_bytes, Ordering::Relaxed);
+                if let Err(e) = result {
                     self.handle_error(format!("Process tx error {:?}", e), ops);
                 }
             }
@@ -110,5 +147,19 @@ where
             EventSet::IN,
         ))
         .expect("Unable to add txfd");
+
+        ops.add(Events::with_data(
+            &self.inner.rx_rate_limiter,
+            RX_RATE_LIMITER_DATA,
+            EventSet::IN,
+        ))
+        .expect("Unable to add rx rate limiter");
+
+        ops.add(Events::with_data(
+            &self.inner.tx_rate_limiter,
+            TX_RATE_LIMITER_DATA,
+            EventSet::IN,
+        ))
+        .expect("Unable to add tx rate limiter");
     }
 }
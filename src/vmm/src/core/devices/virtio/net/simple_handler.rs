@@ -13,6 +13,7 @@ use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryMmap};
 use super::tuntap::tap::Tap;
 use super::{RXQ_INDEX, TXQ_INDEX};
 use crate::core::devices::virtio::SignalUsedQueue;
+use crate::core::rate_limiter::RateLimiter;
 
 // use crate::virtio::net::tap::Tap;
 // use crate::virtio::net::{RXQ_INDEX, TXQ_INDEX};
@@ -27,11 +28,15 @@ use crate::core::devices::virtio::SignalUsedQueue;
 // We assume the TX frame will not exceed this size either.
 const MAX_BUFFER_SIZE: usize = 65562;
 
+// Size of the struct virtio_net_hdr prepended to the frames.
+const VIRTIO_NET_HDR_SIZE: usize = 12;
+
 #[derive(Debug)]
 pub enum Error {
     GuestMemory(vm_memory::GuestMemoryError),
     Queue(virtio_queue::Error),
     Tap(io::Error),
+    RateLimiter(io::Error),
     Mutex,
 }
 
@@ -57,6 +62,12 @@ where
     pub txbuf: [u8; MAX_BUFFER_SIZE],
     pub tap: Arc<Mutex<Tap>>,
     pub mem: Arc<GuestMemoryMmap>,
+    /// Bytes of the frames written to the tap, without their virtio-net header.
+    pub tx_bytes: u64,
+    pub rx_rate_limiter: RateLimiter,
+    pub tx_rate_limiter: RateLimiter,
+    /// Whether the frame in `rxbuf` was taken from the rx rate limiter.
+    rxbuf_charged: bool,
 }
 
 impl<S> SimpleHandler<S>
@@ -69,6 +80,8 @@ where
         txq: Queue,
         tap: Arc<Mutex<Tap>>,
         mem: Arc<GuestMemoryMmap>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
     ) -> Self {
         SimpleHandler {
             driver_notify,
@@ -79,6 +92,10 @@ where
             txbuf: [0u8; MAX_BUFFER_SIZE],
             tap,
             mem,
+            tx_bytes: 0,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            rxbuf_charged: false,
         }
     }
 
@@ -135,7 +152,10 @@ where
                     .map_err(|_| Error::Mutex)?
                     .read(&mut self.rxbuf)
                 {
-                    Ok(n) => self.rxbuf_current = n,
+                    Ok(n) => {
+                        self.rxbuf_current = n;
+                        self.rxbuf_charged = false;
+                    }
                     Err(_) => {
                         // TODO: Do something (logs, metrics, etc.) in response to an error when
                         // reading from tap. EAGAIN means there's nothing available to read anymore
@@ -145,6 +165,18 @@ where
                 }
             }
 
+            // Throttled, the frame is kept until the timer of the limiter fires.
+            if !self.rxbuf_charged {
+                if !self
+                    .rx_rate_limiter
+                    .consume(self.rxbuf_current as u64)
+                    .map_err(Error::RateLimiter)?
+                {
+                    break;
+                }
+                self.rxbuf_charged = true;
+            }
+
             if !self.write_frame_to_guest()? && !self.rxq.enable_notification(self.mem.as_ref())? {
                 break;
             }
@@ -185,6 +217,7 @@ where
             .map_err(|_| Error::Mutex)?
             .write_all(&self.txbuf[..count])
             .map_err(Error::Tap)?;
+        self.tx_bytes += count.saturating_sub(VIRTIO_NET_HDR_SIZE) as u64;
 
         Ok(count as u32)
     }
@@ -194,6 +227,16 @@ where
             self.txq.disable_notification(self.mem.as_ref())?;
 
             while let Some(chain) = self.txq.iter(self.mem.memory())?.next() {
+                let len = chain.clone().map(|desc| u64::from(desc.len())).sum();
+                if !self
+                    .tx_rate_limiter
+                    .consume(len)
+                    .map_err(Error::RateLimiter)?
+                {
+                    // Throttled, the frame is sent once the timer of the limiter fires.
+                    self.txq.go_to_previous_position();
+                    return Ok(());
+                }
                 self.send_frame_from_chain(chain.clone())?;
 
                 self.txq
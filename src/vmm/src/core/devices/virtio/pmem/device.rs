@@ -0,0 +1,178 @@
+use super::queue_handler::QueueHandler;
+use super::{Error, Result, PMEM_ALIGNMENT, PMEM_DEVICE_ID};
+use crate::core::devices::virtio::register::register_mmio_device;
+use crate::core::devices::virtio::{
+    self, Config, MmioConfig, SingleFdSignalQueue, Subscriber, QUEUE_MAX_SIZE,
+};
+use crate::core::rate_limiter::RateLimiter;
+use event_manager::RemoteEndpoint;
+use kvm_bindings::kvm_userspace_memory_region;
+use kvm_ioctls::VmFd;
+use std::{
+    borrow::{Borrow, BorrowMut},
+    fs::{File, OpenOptions},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+use virtio_bindings::virtio_config::VIRTIO_F_VERSION_1;
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
+use virtio_queue::Queue;
+use vm_device::device_manager::IoManager;
+use vm_device::{bus::MmioAddress, MutDeviceMmio};
+use vm_memory::{FileOffset, GuestMemoryMmap, MmapRegion};
+
+/// virtio-pmem device exposing a host file to the guest as directly mapped memory.
+///
+/// The pages of the device are backed by the host page cache, so the host can reclaim
+/// them by writing them back to the file when the guest doesn't use them.
+pub struct Pmem {
+    mem: Arc<GuestMemoryMmap>,
+    pub config: Config,
+    file: File,
+    /// Flushes served per second, 0 for no limit.
+    flushes_per_sec: u64,
+    // Kept alive for as long as the guest can access it through the KVM memory slot.
+    _mapping: MmapRegion,
+}
+
+impl Pmem {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mem: Arc<GuestMemoryMmap>,
+        device_mgr: Arc<Mutex<IoManager>>,
+        mmio_cfg: MmioConfig,
+        irq: u32,
+        endpoint: RemoteEndpoint<Subscriber>,
+        vm_fd: Arc<VmFd>,
+        slot: u32,
+        guest_addr: u64,
+        backing_file: &Path,
+        size: u64,
+        flushes_per_sec: u64,
+        cmdline_extra_parameters: &mut Vec<String>,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let size = size.next_multiple_of(PMEM_ALIGNMENT);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(backing_file)
+            .map_err(Error::BackingFile)?;
+        // Sparse: the host only allocates the blocks written by the guest.
+        file.set_len(size).map_err(Error::BackingFile)?;
+
+        let mapping = MmapRegion::from_file(
+            FileOffset::new(file.try_clone().map_err(Error::BackingFile)?, 0),
+            size as usize,
+        )
+        .map_err(Error::Mmap)?;
+
+        let memory_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: guest_addr,
+            memory_size: size,
+            userspace_addr: mapping.as_ptr() as u64,
+            flags: 0,
+        };
+        // SAFETY: the mapping is owned by the device, which lives as long as the VM.
+        unsafe { vm_fd.set_user_memory_region(memory_region) }.map_err(Error::KvmIoctl)?;
+
+        // struct virtio_pmem_config { le64 start; le64 size; }
+        let mut config_space = guest_addr.to_le_bytes().to_vec();
+        config_space.extend_from_slice(&size.to_le_bytes());
+
+        let queues =
+            vec![Queue::new(QUEUE_MAX_SIZE)
+                .map_err(|_| Error::Virtio(virtio::Error::QueuesNotValid))?];
+        let virtio_cfg = VirtioConfig::new(1 << VIRTIO_F_VERSION_1, queues, config_space);
+        let cfg =
+            Config::new("pmem", virtio_cfg, mmio_cfg, endpoint, vm_fd).map_err(Error::Virtio)?;
+
+        let pmem = Arc::new(Mutex::new(Pmem {
+            mem,
+            config: cfg,
+            file,
+            flushes_per_sec,
+            _mapping: mapping,
+        }));
+
+        let vmmio_param = register_mmio_device(mmio_cfg, device_mgr, irq, None, pmem.clone())
+            .map_err(Error::Virtio)?;
+        cmdline_extra_parameters.push(vmmio_param);
+
+        info!(
+            path = ?backing_file,
+            size,
+            guest_addr = format!("{:#x}", guest_addr),
+            "virtio-pmem device created"
+        );
+
+        Ok(pmem)
+    }
+}
+
+impl VirtioDeviceType for Pmem {
+    fn device_type(&self) -> u32 {
+        PMEM_DEVICE_ID
+    }
+}
+
+impl Borrow<VirtioConfig<Queue>> for Pmem {
+    fn borrow(&self) -> &VirtioConfig<Queue> {
+        &self.config.virtio
+    }
+}
+
+impl BorrowMut<VirtioConfig<Queue>> for Pmem {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<Queue> {
+        &mut self.config.virtio
+    }
+}
+
+impl VirtioDeviceActions for Pmem {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.config.irqfd.clone(),
+            interrupt_status: self.config.virtio.interrupt_status.clone(),
+            stats: self.config.stats.clone(),
+        };
+
+        let mut ioevents = self.config.prepare_activate().map_err(Error::Virtio)?;
+
+        let handler = Arc::new(Mutex::new(QueueHandler {
+            driver_notify,
+            queue: self.config.virtio.queues.remove(0),
+            ioevent: ioevents.remove(0),
+            mem: self.mem.clone(),
+            file: self.file.try_clone().map_err(Error::BackingFile)?,
+            stats: self.config.stats.clone(),
+            rate_limiter: RateLimiter::new(0, self.flushes_per_sec).map_err(Error::RateLimiter)?,
+        }));
+
+        self.config
+            .finalize_activate(handler)
+            .map_err(Error::Virtio)
+    }
+
+    fn reset(&mut self) -> std::result::Result<(), Error> {
+        // Not implemented for now.
+        Ok(())
+    }
+}
+
+impl VirtioMmioDevice for Pmem {}
+
+impl MutDeviceMmio for Pmem {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
@@ -0,0 +1,24 @@
+pub mod device;
+mod queue_handler;
+
+use crate::core::devices::virtio;
+use std::io;
+
+const PMEM_DEVICE_ID: u32 = 27;
+/// Only request defined by the standard: flush the guest writes to the backing file.
+const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
+const VIRTIO_PMEM_RESP_OK: u32 = 0;
+const VIRTIO_PMEM_RESP_EIO: u32 = 1;
+/// Alignment of the device memory in the guest physical address space.
+pub const PMEM_ALIGNMENT: u64 = 2 << 20;
+
+#[derive(Debug)]
+pub enum Error {
+    Virtio(virtio::Error),
+    BackingFile(io::Error),
+    Mmap(vm_memory::mmap::MmapRegionError),
+    KvmIoctl(kvm_ioctls::Error),
+    RateLimiter(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
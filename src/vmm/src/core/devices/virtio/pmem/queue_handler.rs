@@ -0,0 +1,149 @@
+use super::{VIRTIO_PMEM_REQ_TYPE_FLUSH, VIRTIO_PMEM_RESP_EIO, VIRTIO_PMEM_RESP_OK};
+use crate::core::devices::virtio::SignalUsedQueue;
+use crate::core::rate_limiter::RateLimiter;
+use crate::core::stats::DeviceStats;
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::error;
+use std::{fs::File, io, sync::Arc};
+use virtio_queue::{DescriptorChain, Queue, QueueOwnedT, QueueT};
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryMmap};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+const IOEVENT_DATA: u32 = 0;
+const RATE_LIMITER_DATA: u32 = 1;
+/// Size of the status written back to the driver.
+const RESPONSE_SIZE: u32 = 4;
+
+#[derive(Debug)]
+enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+    RateLimiter(io::Error),
+    InvalidRequest,
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+pub struct QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    pub driver_notify: S,
+    pub queue: Queue,
+    pub ioevent: EventFd,
+    pub mem: Arc<GuestMemoryMmap>,
+    pub file: File,
+    pub stats: Arc<DeviceStats>,
+    /// Limiter of the flushes, each an `fsync` of the backing file.
+    pub rate_limiter: RateLimiter,
+}
+
+impl<S> QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    /// Serve a request: a driver-readable request type followed by a device-writable status.
+    fn process_chain(&self, mut chain: DescriptorChain<Arc<GuestMemoryMmap>>) -> Result<(), Error> {
+        let request = chain.next().ok_or(Error::InvalidRequest)?;
+        let request_type: u32 = chain
+            .memory()
+            .read_obj(request.addr())
+            .map_err(Error::GuestMemory)?;
+
+        let response = chain
+            .next()
+            .filter(|desc| desc.is_write_only())
+            .ok_or(Error::InvalidRequest)?;
+
+        let status = match request_type {
+            VIRTIO_PMEM_REQ_TYPE_FLUSH => match self.file.sync_all() {
+                Ok(()) => VIRTIO_PMEM_RESP_OK,
+                Err(e) => {
+                    error!("pmem flush failed: {:?}", e);
+                    VIRTIO_PMEM_RESP_EIO
+                }
+            },
+            _ => VIRTIO_PMEM_RESP_EIO,
+        };
+
+        chain
+            .memory()
+            .write_obj(status, response.addr())
+            .map_err(Error::GuestMemory)
+    }
+
+    fn process_queue(&mut self) -> Result<(), Error> {
+        loop {
+            self.queue.disable_notification(self.mem.as_ref())?;
+
+            while let Some(chain) = self.queue.iter(self.mem.memory())?.next() {
+                if !self.rate_limiter.consume(0).map_err(Error::RateLimiter)? {
+                    // Throttled, the request is served once the timer of the limiter fires.
+                    self.queue.go_to_previous_position();
+                    return Ok(());
+                }
+                self.process_chain(chain.clone())?;
+
+                self.queue
+                    .add_used(self.mem.as_ref(), chain.head_index(), RESPONSE_SIZE)?;
+
+                if self.queue.needs_notification(self.mem.as_ref())? {
+                    self.driver_notify.signal_used_queue(0);
+                }
+            }
+
+            if !self.queue.enable_notification(self.mem.as_ref())? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<S> MutEventSubscriber for QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let ready = match events.data() {
+            _ if events.event_set() != EventSet::IN => None,
+            IOEVENT_DATA => {
+                self.stats.queue_notifications.inc();
+                Some(self.ioevent.read().map(drop))
+            }
+            RATE_LIMITER_DATA => Some(self.rate_limiter.event_handler()),
+            _ => None,
+        };
+
+        let Some(ready) = ready else {
+            error!("Unexpected pmem event");
+            ops.remove(Events::empty(&self.ioevent))
+                .expect("Failed to remove pmem ioevent");
+            ops.remove(Events::empty(&self.rate_limiter))
+                .expect("Failed to remove pmem rate limiter event");
+            return;
+        };
+        if let Err(e) = ready {
+            self.stats.errors.inc();
+            error!("pmem event read {:?}", e);
+        } else if let Err(e) = self.process_queue() {
+            self.stats.errors.inc();
+            error!("Process pmem queue error {:?}", e);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(&self.ioevent, IOEVENT_DATA, EventSet::IN))
+            .expect("Unable to add pmem ioevent");
+        ops.add(Events::with_data(
+            &self.rate_limiter,
+            RATE_LIMITER_DATA,
+            EventSet::IN,
+        ))
+        .expect("Unable to add pmem rate limiter");
+    }
+}
@@ -0,0 +1,116 @@
+//! How a VM stops: the vCPU which sees the guest stop, or the host through a [`VmStopper`],
+//! records the [`VmExit`] and wakes the run loop of the VMM, which stops the other vCPUs and
+//! returns it.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use vmm_sys_util::signal::{register_signal_handler, Killable, SIGRTMIN};
+
+/// Time given to the vCPU threads to leave `KVM_RUN` once kicked.
+const VCPU_STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Why a VM stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmExit {
+    /// The guest powered itself off.
+    Shutdown,
+    /// The guest crashed or reset itself, which a kernel panic does given `panic=`.
+    Crash(String),
+    /// Stopped from the host, for the given reason.
+    Killed(String),
+}
+
+/// Exit of a VM, shared by its vCPUs and its run loop.
+pub(crate) struct ExitState {
+    exit: Mutex<Option<VmExit>>,
+    /// Written once the VM stopped.
+    event: EventFd,
+}
+
+impl ExitState {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            exit: Mutex::new(None),
+            event: EventFd::new(EFD_NONBLOCK)?,
+        })
+    }
+
+    /// Record that the VM stopped, the first reason given being kept.
+    pub fn stop(&self, exit: VmExit) {
+        let mut current = self.exit.lock().unwrap();
+        if current.is_none() {
+            *current = Some(exit);
+            let _ = self.event.write(1);
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.exit.lock().unwrap().is_some()
+    }
+
+    pub fn exit(&self) -> Option<VmExit> {
+        self.exit.lock().unwrap().clone()
+    }
+}
+
+impl AsRawFd for ExitState {
+    fn as_raw_fd(&self) -> RawFd {
+        self.event.as_raw_fd()
+    }
+}
+
+/// Stops a running VM from another thread.
+#[derive(Clone)]
+pub struct VmStopper(pub(crate) Arc<ExitState>);
+
+impl VmStopper {
+    /// Stop the VM, its run returning [`VmExit::Killed`] with `reason`.
+    pub fn stop(&self, reason: &str) {
+        self.0.stop(VmExit::Killed(reason.to_string()));
+    }
+}
+
+/// Signal interrupting the `KVM_RUN` of a vCPU thread.
+fn kick_signal() -> libc::c_int {
+    SIGRTMIN()
+}
+
+extern "C" fn handle_kick(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+
+/// Install the handler of the kick signal, so that it only interrupts the vCPU threads.
+pub(crate) fn register_kick_handler() {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        if let Err(e) = register_signal_handler(kick_signal(), handle_kick) {
+            warn!(error = %e, "Could not register the vCPU kick handler");
+        }
+    });
+}
+
+/// Kick the vCPU threads out of `KVM_RUN` until they see the VM stopped and exit.
+pub(crate) fn stop_vcpus(threads: Vec<JoinHandle<()>>) {
+    let deadline = Instant::now() + VCPU_STOP_TIMEOUT;
+    let mut running = threads;
+    while !running.is_empty() {
+        // A thread may get the signal right before entering `KVM_RUN`, so it's sent again.
+        for thread in &running {
+            let _ = thread.kill(kick_signal());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        let (finished, left): (Vec<_>, Vec<_>) =
+            running.into_iter().partition(|thread| thread.is_finished());
+        for thread in finished {
+            let _ = thread.join();
+        }
+        running = left;
+        if Instant::now() > deadline {
+            warn!(vcpus = running.len(), "vCPU threads didn't stop");
+            return;
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Import of the Firecracker machine configurations, as given to `firecracker --config-file`,
+//! so that existing configurations and the tools generating them can be used with this VMM.
+//!
+//! Only what the VMM can provide is accepted:
+//! * the guest boots from its initramfs, so root drives are rejected, and one other
+//!   read-write drive is exposed as the virtio-pmem device (`/dev/pmem0` rather than `/dev/vdX`);
+//! * the VMM creates the TAP device of the guest itself, so one network interface at most is
+//!   accepted, and its `host_dev_name` and `guest_mac` are ignored;
+//! * `boot_args` are appended to the command line of the VMM instead of replacing it.
+
+use serde::Deserialize;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+#[derive(Debug)]
+pub enum FirecrackerError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, serde_json::Error),
+    Unsupported(String),
+}
+
+impl fmt::Display for FirecrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirecrackerError::Read(path, e) => write!(f, "Failed to read {:?}: {}", path, e),
+            FirecrackerError::Parse(path, e) => {
+                write!(f, "Invalid Firecracker configuration {:?}: {}", path, e)
+            }
+            FirecrackerError::Unsupported(message) => {
+                write!(f, "Unsupported configuration: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FirecrackerError {}
+
+/// A Firecracker configuration file. Unknown sections, e.g. `logger` or `metrics`, are ignored.
+#[derive(Debug, Deserialize)]
+pub struct FirecrackerConfig {
+    #[serde(rename = "boot-source")]
+    pub boot_source: BootSource,
+    #[serde(rename = "machine-config")]
+    pub machine_config: MachineConfig,
+    #[serde(default)]
+    pub drives: Vec<Drive>,
+    #[serde(rename = "network-interfaces", default)]
+    pub network_interfaces: Vec<NetworkInterface>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BootSource {
+    pub kernel_image_path: PathBuf,
+    pub initrd_path: Option<PathBuf>,
+    pub boot_args: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MachineConfig {
+    pub vcpu_count: u8,
+    pub mem_size_mib: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Drive {
+    pub drive_id: String,
+    pub path_on_host: PathBuf,
+    pub is_root_device: bool,
+    #[serde(default)]
+    pub is_read_only: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NetworkInterface {
+    pub iface_id: String,
+    pub host_dev_name: Option<String>,
+    pub guest_mac: Option<String>,
+}
+
+/// What to give to [`VMM::configure`](super::vmm::VMM::configure) and its setters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmConfig {
+    pub cpus: u8,
+    pub memory_mb: u32,
+    pub kernel: PathBuf,
+    pub initramfs: Option<PathBuf>,
+    pub kernel_cmdline: Vec<String>,
+    /// Backing file and size (in MB) of the virtio-pmem device.
+    pub pmem: Option<(PathBuf, u32)>,
+}
+
+impl FirecrackerConfig {
+    pub fn from_file(path: &Path) -> Result<Self, FirecrackerError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| FirecrackerError::Read(path.to_path_buf(), e))?;
+        serde_json::from_str(&content).map_err(|e| FirecrackerError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn to_vm_config(&self) -> Result<VmConfig, FirecrackerError> {
+        if self.machine_config.vcpu_count == 0 || self.machine_config.mem_size_mib == 0 {
+            return Err(FirecrackerError::Unsupported(
+                "the guest needs at least one vCPU and some memory".into(),
+            ));
+        }
+
+        if let [_, _, ..] = self.network_interfaces.as_slice() {
+            return Err(FirecrackerError::Unsupported(format!(
+                "{} network interfaces, the guest has a single one",
+                self.network_interfaces.len()
+            )));
+        }
+        for iface in &self.network_interfaces {
+            if iface.host_dev_name.is_some() || iface.guest_mac.is_some() {
+                warn!(
+                    iface_id = %iface.iface_id,
+                    "Ignoring host_dev_name and guest_mac, the VMM creates its own TAP device"
+                );
+            }
+        }
+
+        let mut pmem = None;
+        for drive in &self.drives {
+            if drive.is_root_device {
+                return Err(FirecrackerError::Unsupported(format!(
+                    "root drive {}, the guest boots from its initramfs",
+                    drive.drive_id
+                )));
+            }
+            if drive.is_read_only {
+                return Err(FirecrackerError::Unsupported(format!(
+                    "read-only drive {}, the pmem device is writable",
+                    drive.drive_id
+                )));
+            }
+            if pmem.is_some() {
+                return Err(FirecrackerError::Unsupported(format!(
+                    "drive {}, the guest has a single pmem device",
+                    drive.drive_id
+                )));
+            }
+
+            let size = fs::metadata(&drive.path_on_host)
+                .map_err(|e| FirecrackerError::Read(drive.path_on_host.clone(), e))?
+                .len();
+            // The device is sized in MB, a file of another size would be extended.
+            if size == 0 || size % (1 << 20) != 0 {
+                return Err(FirecrackerError::Unsupported(format!(
+                    "drive {} is {} bytes, not a whole number of MB",
+                    drive.drive_id, size
+                )));
+            }
+            pmem = Some((drive.path_on_host.clone(), (size >> 20) as u32));
+        }
+
+        Ok(VmConfig {
+            cpus: self.machine_config.vcpu_count,
+            memory_mb: self.machine_config.mem_size_mib,
+            kernel: self.boot_source.kernel_image_path.clone(),
+            initramfs: self.boot_source.initrd_path.clone(),
+            kernel_cmdline: self
+                .boot_source
+                .boot_args
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            pmem,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> FirecrackerConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_machine_config_is_imported() {
+        let config = parse(
+            r#"{
+                "boot-source": {
+                    "kernel_image_path": "vmlinux.bin",
+                    "initrd_path": "initramfs.img",
+                    "boot_args": "console=ttyS0 quiet"
+                },
+                "machine-config": { "vcpu_count": 2, "mem_size_mib": 1024, "smt": false },
+                "network-interfaces": [{ "iface_id": "eth0", "host_dev_name": "tap0" }],
+                "logger": { "log_path": "fc.log" }
+            }"#,
+        );
+
+        assert_eq!(
+            config.to_vm_config().unwrap(),
+            VmConfig {
+                cpus: 2,
+                memory_mb: 1024,
+                kernel: PathBuf::from("vmlinux.bin"),
+                initramfs: Some(PathBuf::from("initramfs.img")),
+                kernel_cmdline: vec!["console=ttyS0".into(), "quiet".into()],
+                pmem: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_root_drives_are_rejected() {
+        let config = parse(
+            r#"{
+                "boot-source": { "kernel_image_path": "vmlinux.bin" },
+                "machine-config": { "vcpu_count": 1, "mem_size_mib": 128 },
+                "drives": [{ "drive_id": "rootfs", "path_on_host": "rootfs.ext4", "is_root_device": true }]
+            }"#,
+        );
+
+        assert!(matches!(
+            config.to_vm_config(),
+            Err(FirecrackerError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,92 @@
+//! Allocation of the guest memory on the host.
+
+use std::{fmt, str::FromStr};
+use tracing::{info, warn};
+use vm_memory::{GuestAddress, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+
+/// Size of the huge pages backing the guest memory.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+/// Size of the regular host pages.
+const PAGE_SIZE: usize = 4 << 10;
+
+/// Whether the guest memory is backed by huge pages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HugePages {
+    /// Use regular pages.
+    #[default]
+    Off,
+    /// Use huge pages when the host has enough of them reserved, regular pages otherwise.
+    On,
+    /// Use huge pages, and fail when the host doesn't have enough of them reserved.
+    Strict,
+}
+
+impl FromStr for HugePages {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(HugePages::Off),
+            "on" => Ok(HugePages::On),
+            "strict" => Ok(HugePages::Strict),
+            other => Err(format!("expected `off`, `on` or `strict`, got `{}`", other)),
+        }
+    }
+}
+
+impl fmt::Display for HugePages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HugePages::Off => write!(f, "off"),
+            HugePages::On => write!(f, "on"),
+            HugePages::Strict => write!(f, "strict"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GuestMemoryConfig {
+    pub huge_pages: HugePages,
+    /// Allocate the whole guest memory when the VM is configured, rather than on first access.
+    pub prealloc: bool,
+}
+
+/// Map `size` bytes of guest memory starting at guest address 0.
+/// The size is rounded up to a multiple of [`HUGE_PAGE_SIZE`] when huge pages are used.
+pub fn map_guest_memory(
+    size: usize,
+    config: &GuestMemoryConfig,
+) -> Result<GuestMemoryMmap, vm_memory::Error> {
+    if config.huge_pages != HugePages::Off {
+        let size = size.next_multiple_of(HUGE_PAGE_SIZE);
+        let flags =
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB;
+
+        // Without MAP_NORESERVE, the huge pages are reserved by the mmap call, which fails
+        // rather than faulting later when the host pool is too small.
+        match MmapRegion::build(None, size, libc::PROT_READ | libc::PROT_WRITE, flags) {
+            Ok(region) => {
+                info!(size, "Guest memory backed by huge pages");
+                let region = GuestRegionMmap::new(region, GuestAddress(0))?;
+                return GuestMemoryMmap::from_regions(vec![region]);
+            }
+            Err(e) if config.huge_pages == HugePages::On => {
+                warn!(reason = ?e, "Not enough huge pages available, using regular pages");
+            }
+            Err(e) => return Err(vm_memory::Error::MmapRegion(e)),
+        }
+    }
+
+    GuestMemoryMmap::from_ranges(&[(GuestAddress(0), size)])
+}
+
+/// Touch every page of the `len` bytes mapped at `addr`, so that they are allocated now.
+///
+/// # Safety
+///
+/// `addr` must point to a writable mapping of at least `len` bytes, which isn't in use yet.
+pub unsafe fn prealloc(addr: *mut u8, len: usize) {
+    for offset in (0..len).step_by(PAGE_SIZE) {
+        addr.add(offset).write_volatile(0);
+    }
+}
@@ -8,14 +8,24 @@ extern crate vm_superio;
 use linux_loader::loader;
 use std::io;
 
-use self::devices::virtio::{self, net::tuntap::open_tap};
+use self::devices::virtio::{self, net::tuntap::open_tap, pmem};
 
 mod cpu;
+pub mod cpu_template;
 mod devices;
 mod epoll_context;
+pub mod exit;
+pub mod firecracker;
 mod irq_allocator;
 mod kernel;
+pub mod memory;
+pub mod network;
+pub mod placement;
+pub mod rate_limiter;
+pub mod root_disk;
 mod slip_pty;
+pub mod stats;
+pub mod vfio;
 pub mod vmm;
 
 #[derive(Debug)]
@@ -73,6 +83,20 @@ pub enum Error {
     MmioRange,
     // Virtio net
     Virtio(virtio::Error),
+    // Virtio pmem
+    Pmem(pmem::Error),
+}
+
+impl Error {
+    /// Whether the error comes from the creation of the guest network interface (TAP device).
+    pub fn is_network(&self) -> bool {
+        matches!(self, Error::OpenTap(_) | Error::Virtio(virtio::Error::Net))
+    }
+
+    /// Whether the error comes from KVM itself rather than from the guest configuration.
+    pub fn is_kvm(&self) -> bool {
+        matches!(self, Error::KvmIoctl(_))
+    }
 }
 
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
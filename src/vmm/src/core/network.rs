@@ -0,0 +1,297 @@
+//! Networks of the guests: the shared bridge, or one isolated bridge per tenant.
+//!
+//! By default every guest is attached to the `br0` bridge. With the tenants isolated, the guests
+//! of each tenant get a bridge and a `/24` of their own, `172.29.<n>.0/24` on `cltn<n>`, and
+//! the traffic routed from a tenant bridge to the other guest networks is dropped: the guests
+//! of a tenant only reach each other, the host and the outside.
+//!
+//! Each guest of a network leases an address of its own, the lowest one free after the host's,
+//! for as long as its VM runs: the concurrent guests of a network, the instances of a pool or
+//! the runs of a batch, are reached at different addresses.
+//!
+//! In the deterministic mode, the networks come from a fake IPAM instead: `198.18.<n>.0/24`,
+//! in the range reserved for benchmarks, on bridges which don't exist. Their addresses only
+//! depend on the order the tenants came in, whatever the networks of the host, for the replays
+//! and the tests which don't boot guests.
+
+use super::devices::virtio::net::{
+    guest_bridge,
+    tuntap::{open_tap::open_tap, tap::Tap},
+    BRIDGE_NAME,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+/// Addresses of every guest network.
+pub const GUEST_NETWORKS: (Ipv4Addr, u8) = (Ipv4Addr::new(172, 29, 0, 0), 16);
+
+/// Tenant networks available, `172.29.1.0/24` to `172.29.255.0/24`.
+pub const MAX_TENANT_NETWORKS: usize = 255;
+
+/// Addresses of the networks of the fake IPAM.
+pub const FAKE_NETWORKS: (Ipv4Addr, u8) = (Ipv4Addr::new(198, 18, 0, 0), 16);
+
+/// Network a guest is attached to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestNetwork {
+    pub bridge: String,
+    pub host_ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// Address of the guest, the first one of the network until a [`GuestAddresses`] leases
+    /// one.
+    pub guest_ip: Ipv4Addr,
+    /// The traffic to the other guest networks is dropped.
+    pub isolated: bool,
+}
+
+impl GuestNetwork {
+    /// The `br0` bridge shared by every guest.
+    pub fn shared() -> Self {
+        Self {
+            bridge: BRIDGE_NAME.to_string(),
+            host_ip: Ipv4Addr::new(172, 29, 0, 1),
+            netmask: Ipv4Addr::new(255, 255, 0, 0),
+            guest_ip: Ipv4Addr::new(172, 29, 0, 2),
+            isolated: false,
+        }
+    }
+
+    /// The isolated network `index`, from 1 to [`MAX_TENANT_NETWORKS`].
+    fn tenant(index: u8) -> Self {
+        Self {
+            bridge: format!("cltn{}", index),
+            host_ip: Ipv4Addr::new(172, 29, index, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            guest_ip: Ipv4Addr::new(172, 29, index, 2),
+            isolated: true,
+        }
+    }
+
+    /// The network `index` of the fake IPAM.
+    fn fake(index: u8) -> Self {
+        let [a, b, ..] = FAKE_NETWORKS.0.octets();
+        Self {
+            bridge: format!("fake{}", index),
+            host_ip: Ipv4Addr::new(a, b, index, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            guest_ip: Ipv4Addr::new(a, b, index, 2),
+            isolated: true,
+        }
+    }
+}
+
+/// Addresses leased to the running guests, by bridge.
+type Leased = Arc<Mutex<BTreeMap<String, BTreeSet<Ipv4Addr>>>>;
+
+/// Addresses of the guests on each network.
+#[derive(Debug, Default, Clone)]
+pub struct GuestAddresses {
+    leased: Leased,
+}
+
+impl GuestAddresses {
+    /// `network` with an address no other running guest of its bridge has, the lowest one free
+    /// after the address of the host, `None` if every address is taken. The address is free
+    /// again once the lease is dropped.
+    pub fn lease(&self, network: &GuestNetwork) -> Option<AddressLease> {
+        let host = u32::from(network.host_ip);
+        let mask = u32::from(network.netmask);
+        let broadcast = (host & mask) | !mask;
+        let mut leased = self.leased.lock().unwrap();
+        let taken = leased.entry(network.bridge.clone()).or_default();
+        let guest_ip = (host + 1..broadcast)
+            .map(Ipv4Addr::from)
+            .find(|address| !taken.contains(address))?;
+        taken.insert(guest_ip);
+        Some(AddressLease {
+            network: GuestNetwork {
+                guest_ip,
+                ..network.clone()
+            },
+            leased: self.leased.clone(),
+        })
+    }
+
+    /// The addresses leased, by bridge.
+    pub fn leases(&self) -> Vec<(String, Ipv4Addr)> {
+        let leased = self.leased.lock().unwrap();
+        leased
+            .iter()
+            .flat_map(|(bridge, addresses)| {
+                addresses
+                    .iter()
+                    .map(move |address| (bridge.clone(), *address))
+            })
+            .collect()
+    }
+}
+
+/// A network with the address of a guest, given back when dropped.
+#[derive(Debug)]
+pub struct AddressLease {
+    network: GuestNetwork,
+    leased: Leased,
+}
+
+impl AddressLease {
+    pub fn network(&self) -> &GuestNetwork {
+        &self.network
+    }
+}
+
+impl Drop for AddressLease {
+    fn drop(&mut self) {
+        let mut leased = self.leased.lock().unwrap();
+        if let Some(taken) = leased.get_mut(&self.network.bridge) {
+            taken.remove(&self.network.guest_ip);
+            if taken.is_empty() {
+                leased.remove(&self.network.bridge);
+            }
+        }
+    }
+}
+
+/// TAP device attached to the bridge of a guest run by an external emulator, which inherits its
+/// file descriptor. The device goes away once both are closed.
+pub struct GuestTap {
+    tap: Tap,
+}
+
+impl GuestTap {
+    /// Open a TAP device on the bridge of `network`, set up like the built-in VMM does.
+    pub async fn open(network: &GuestNetwork, uplink: Option<&str>) -> io::Result<Self> {
+        let tap = open_tap(None, None, None, &mut None, None, None)
+            .map_err(|e| io::Error::other(format!("Could not open a TAP device: {:?}", e)))?;
+        let name = tap
+            .get_name()
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        let bridge = guest_bridge(
+            &network.bridge,
+            network.host_ip,
+            network.netmask,
+            network.isolated,
+            uplink,
+        )
+        .await
+        .map_err(|e| io::Error::other(format!("Could not set the guest bridge up: {:?}", e)))?;
+        bridge.attach_link(name).await.map_err(|e| {
+            io::Error::other(format!(
+                "Could not attach the TAP device to the bridge: {:?}",
+                e
+            ))
+        })?;
+        Ok(Self { tap })
+    }
+
+    pub fn name(&self) -> String {
+        self.tap.get_name().unwrap_or_default()
+    }
+}
+
+impl AsRawFd for GuestTap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tap.as_raw_fd()
+    }
+}
+
+/// Networks given to the tenants, each keeping its network until the orchestrator restarts.
+#[derive(Debug, Default)]
+pub struct TenantNetworks {
+    tenants: Mutex<HashMap<String, u8>>,
+    fake: bool,
+}
+
+impl TenantNetworks {
+    /// Networks of the fake IPAM.
+    pub fn fake() -> Self {
+        Self {
+            fake: true,
+            ..Default::default()
+        }
+    }
+
+    /// Whether every network is taken, the guests of the new tenants then having none.
+    pub fn exhausted(&self) -> bool {
+        self.tenants.lock().unwrap().len() >= MAX_TENANT_NETWORKS
+    }
+
+    /// Network of the guests of `tenant`, `None` if every network is taken.
+    pub fn network(&self, tenant: &str) -> Option<GuestNetwork> {
+        let mut tenants = self.tenants.lock().unwrap();
+        let index = match tenants.get(tenant) {
+            Some(index) => *index,
+            None => {
+                let index = u8::try_from(tenants.len() + 1).ok()?;
+                tenants.insert(tenant.to_string(), index);
+                index
+            }
+        };
+        Some(if self.fake {
+            GuestNetwork::fake(index)
+        } else {
+            GuestNetwork::tenant(index)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_addresses() {
+        let addresses = GuestAddresses::default();
+        let shared = GuestNetwork::shared();
+        let first = addresses.lease(&shared).unwrap();
+        let second = addresses.lease(&shared).unwrap();
+        assert_eq!(first.network().guest_ip, Ipv4Addr::new(172, 29, 0, 2));
+        assert_eq!(second.network().guest_ip, Ipv4Addr::new(172, 29, 0, 3));
+        assert_eq!(second.network().bridge, shared.bridge);
+        // The networks have addresses of their own.
+        let tenant = addresses.lease(&GuestNetwork::tenant(1)).unwrap();
+        assert_eq!(tenant.network().guest_ip, Ipv4Addr::new(172, 29, 1, 2));
+        assert_eq!(addresses.leases().len(), 3);
+
+        drop(first);
+        let third = addresses.lease(&shared).unwrap();
+        assert_eq!(third.network().guest_ip, Ipv4Addr::new(172, 29, 0, 2));
+
+        // A /24 has 253 addresses for the guests, after the one of the host.
+        let network = GuestNetwork::tenant(2);
+        let leases: Vec<_> = std::iter::from_fn(|| addresses.lease(&network)).collect();
+        assert_eq!(leases.len(), 253);
+        assert_eq!(
+            leases.last().unwrap().network().guest_ip,
+            Ipv4Addr::new(172, 29, 2, 254)
+        );
+        drop(leases);
+        assert!(addresses.lease(&network).is_some());
+    }
+
+    #[test]
+    fn test_tenant_networks() {
+        let networks = TenantNetworks::default();
+        assert!(!networks.exhausted());
+        let first = networks.network("a").unwrap();
+        assert_eq!(first.bridge, "cltn1");
+        assert_eq!(first.guest_ip, Ipv4Addr::new(172, 29, 1, 2));
+        assert_eq!(networks.network("b").unwrap().bridge, "cltn2");
+        assert_eq!(networks.network("a").unwrap(), first);
+
+        for tenant in 3..=MAX_TENANT_NETWORKS {
+            assert!(networks.network(&tenant.to_string()).is_some());
+        }
+        assert!(networks.network("full").is_none());
+        assert!(networks.exhausted());
+        assert_eq!(networks.network("b").unwrap().bridge, "cltn2");
+
+        let fake = TenantNetworks::fake();
+        assert_eq!(
+            fake.network("b").unwrap().guest_ip,
+            Ipv4Addr::new(198, 18, 1, 2)
+        );
+    }
+}
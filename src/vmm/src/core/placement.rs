@@ -0,0 +1,312 @@
+//! Placement of the vCPU threads and guest memory on the host CPUs and NUMA nodes.
+
+use std::{cmp::Reverse, collections::BTreeMap, fmt, fs, io, str::FromStr, sync::Mutex};
+use tracing::warn;
+
+/// Where the NUMA topology of the host is exposed.
+const NUMA_NODES_DIR: &str = "/sys/devices/system/node";
+
+/// `mbind` memory policy binding allocations to a set of nodes.
+const MPOL_BIND: libc::c_int = 2;
+/// `mbind` flag moving the pages already allocated to the requested node.
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// Parse a CPU list such as `0-3,8,10-11`, as used by the kernel and `taskset`.
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+                if start > end {
+                    return None;
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+
+    Some(cpus)
+}
+
+/// How the vCPUs of a guest are placed on the host CPUs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CpuPolicy {
+    /// Let the host scheduler place the vCPU threads.
+    #[default]
+    None,
+    /// Place each guest on the least loaded NUMA node.
+    Spread,
+    /// Fill a NUMA node before using the next one.
+    Pack,
+    /// Run every guest on the given host CPUs.
+    Explicit(Vec<usize>),
+}
+
+impl FromStr for CpuPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CpuPolicy::None),
+            "spread" => Ok(CpuPolicy::Spread),
+            "pack" => Ok(CpuPolicy::Pack),
+            list => parse_cpu_list(list)
+                .filter(|cpus| !cpus.is_empty())
+                .map(CpuPolicy::Explicit)
+                .ok_or_else(|| {
+                    format!(
+                        "expected `none`, `spread`, `pack` or a CPU list such as `0-3,8`, got `{}`",
+                        list
+                    )
+                }),
+        }
+    }
+}
+
+impl fmt::Display for CpuPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuPolicy::None => write!(f, "none"),
+            CpuPolicy::Spread => write!(f, "spread"),
+            CpuPolicy::Pack => write!(f, "pack"),
+            CpuPolicy::Explicit(cpus) => {
+                let cpus: Vec<String> = cpus.iter().map(|cpu| cpu.to_string()).collect();
+                write!(f, "{}", cpus.join(","))
+            }
+        }
+    }
+}
+
+/// Host CPUs of each NUMA node.
+#[derive(Debug, Clone)]
+pub struct HostTopology {
+    nodes: BTreeMap<u32, Vec<usize>>,
+}
+
+impl HostTopology {
+    /// Read the topology of the host, which is seen as a single node when NUMA isn't available.
+    pub fn detect() -> Self {
+        let nodes = Self::read_nodes().unwrap_or_default();
+        if !nodes.is_empty() {
+            return Self { nodes };
+        }
+
+        let cpus = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Self {
+            nodes: BTreeMap::from([(0, (0..cpus).collect())]),
+        }
+    }
+
+    fn read_nodes() -> io::Result<BTreeMap<u32, Vec<usize>>> {
+        let mut nodes = BTreeMap::new();
+
+        for entry in fs::read_dir(NUMA_NODES_DIR)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(id) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("node"))
+                .and_then(|id| id.parse().ok())
+            else {
+                continue;
+            };
+
+            let cpu_list = fs::read_to_string(entry.path().join("cpulist"))?;
+            if let Some(cpus) = parse_cpu_list(&cpu_list).filter(|cpus| !cpus.is_empty()) {
+                nodes.insert(id, cpus);
+            }
+        }
+
+        Ok(nodes)
+    }
+
+    fn node_of(&self, cpu: usize) -> Option<u32> {
+        self.nodes
+            .iter()
+            .find(|(_, cpus)| cpus.contains(&cpu))
+            .map(|(id, _)| *id)
+    }
+}
+
+/// Host CPUs and NUMA node assigned to a guest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CpuPlacement {
+    /// vCPU `i` runs on `cpus[i % cpus.len()]`.
+    pub cpus: Vec<usize>,
+    /// Node the guest memory is allocated from, when every CPU belongs to the same node.
+    pub numa_node: Option<u32>,
+}
+
+impl CpuPlacement {
+    /// Host CPU of the vCPU `index`.
+    pub fn cpu_for(&self, index: usize) -> Option<usize> {
+        (!self.cpus.is_empty()).then(|| self.cpus[index % self.cpus.len()])
+    }
+}
+
+/// Assigns host CPUs to guests according to a [`CpuPolicy`], keeping track of the CPUs in use.
+#[derive(Debug)]
+pub struct CpuAllocator {
+    policy: CpuPolicy,
+    topology: HostTopology,
+    /// Number of vCPUs pinned to each host CPU.
+    load: Mutex<BTreeMap<usize, usize>>,
+}
+
+impl CpuAllocator {
+    pub fn new(policy: CpuPolicy, topology: HostTopology) -> Self {
+        Self {
+            policy,
+            topology,
+            load: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn policy(&self) -> &CpuPolicy {
+        &self.policy
+    }
+
+    /// Pick the host CPUs for a guest with `num_vcpus` vCPUs, `None` if the guest shouldn't be pinned.
+    pub fn allocate(&self, num_vcpus: usize) -> Option<CpuPlacement> {
+        let mut load = self.load.lock().unwrap();
+        let cpu_load = |cpu: &usize| load.get(cpu).copied().unwrap_or(0);
+
+        let cpus: Vec<usize> = match &self.policy {
+            CpuPolicy::None => return None,
+            CpuPolicy::Explicit(cpus) => cpus.clone(),
+            CpuPolicy::Spread | CpuPolicy::Pack => {
+                let idle = |cpus: &Vec<usize>| cpus.iter().filter(|cpu| cpu_load(cpu) == 0).count();
+                let fitting = self
+                    .topology
+                    .nodes
+                    .values()
+                    .filter(|cpus| idle(cpus) >= num_vcpus);
+
+                // Pack on the busiest node the guest fits in, spread on the least busy one.
+                let node = match self.policy {
+                    CpuPolicy::Pack => fitting.min_by_key(|cpus| idle(cpus)),
+                    _ => fitting.min_by_key(|cpus| Reverse(idle(cpus))),
+                };
+
+                // Share the least loaded CPUs of the host when no node is idle enough.
+                let mut candidates = node
+                    .cloned()
+                    .unwrap_or_else(|| self.topology.nodes.values().flatten().copied().collect());
+                candidates.sort_by_key(|cpu| (cpu_load(cpu), *cpu));
+                candidates.truncate(num_vcpus);
+                candidates
+            }
+        };
+
+        let nodes: Vec<Option<u32>> = cpus.iter().map(|cpu| self.topology.node_of(*cpu)).collect();
+        let numa_node = match nodes.first() {
+            Some(Some(node)) if nodes.iter().all(|n| *n == Some(*node)) => Some(*node),
+            _ => None,
+        };
+
+        for cpu in cpus.iter().take(num_vcpus) {
+            *load.entry(*cpu).or_default() += 1;
+        }
+
+        Some(CpuPlacement { cpus, numa_node })
+    }
+
+    /// Give back the CPUs of a guest which stopped.
+    pub fn release(&self, placement: &CpuPlacement, num_vcpus: usize) {
+        let mut load = self.load.lock().unwrap();
+        for cpu in placement.cpus.iter().take(num_vcpus) {
+            if let Some(count) = load.get_mut(cpu) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Pin the calling thread to the host CPU `cpu`.
+pub fn pin_current_thread(cpu: usize) {
+    let mut cpu_set = nix::sched::CpuSet::new();
+    let result = cpu_set
+        .set(cpu)
+        .and_then(|_| nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), &cpu_set));
+
+    if let Err(e) = result {
+        warn!(cpu, reason = %e, "Could not pin vCPU thread");
+    }
+}
+
+/// Bind the memory mapped at `addr` to the NUMA node `node`.
+pub fn bind_memory(addr: *mut u8, len: usize, node: u32) -> io::Result<()> {
+    let bits = libc::c_ulong::BITS;
+    let mut node_mask = vec![0 as libc::c_ulong; node as usize / bits as usize + 1];
+    node_mask[node as usize / bits as usize] |= 1 << (node % bits);
+
+    // SAFETY: the range is a mapping owned by the guest memory, and the node mask outlives the call.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr,
+            len,
+            MPOL_BIND,
+            node_mask.as_ptr(),
+            node_mask.len() as libc::c_ulong * bits as libc::c_ulong,
+            MPOL_MF_MOVE,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology() -> HostTopology {
+        HostTopology {
+            nodes: BTreeMap::from([(0, vec![0, 1, 2, 3]), (1, vec![4, 5, 6, 7])]),
+        }
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-2,8,10-11"),
+            Some(vec![0, 1, 2, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+    }
+
+    #[test]
+    fn test_pack_fills_a_node_first() {
+        let allocator = CpuAllocator::new(CpuPolicy::Pack, topology());
+
+        let first = allocator.allocate(2).unwrap();
+        let second = allocator.allocate(2).unwrap();
+
+        assert_eq!(first.cpus, [0, 1]);
+        assert_eq!(second.cpus, [2, 3]);
+        assert_eq!(second.numa_node, Some(0));
+    }
+
+    #[test]
+    fn test_spread_uses_the_least_loaded_node() {
+        let allocator = CpuAllocator::new(CpuPolicy::Spread, topology());
+
+        let first = allocator.allocate(2).unwrap();
+        let second = allocator.allocate(2).unwrap();
+
+        assert_eq!(first.numa_node, Some(0));
+        assert_eq!(second.numa_node, Some(1));
+
+        allocator.release(&first, 2);
+        assert_eq!(allocator.allocate(2).unwrap().numa_node, Some(0));
+    }
+}
@@ -0,0 +1,239 @@
+//! Throttling of the IO of the guests, with token buckets like the rate limiters of Firecracker.
+//!
+//! A device takes the tokens of a request from its [`RateLimiter`] before serving it: one
+//! operation, and its size in bytes. Once a bucket is short of tokens, the request is left in
+//! its queue and the timer of the limiter is armed for when the bucket will have refilled
+//! enough, the device serving its queue again when the timer fires.
+//!
+//! The virtio-net device throttles the frames sent and received by the guest, each way with a
+//! limiter of its own. The guest accesses the virtio-pmem device through the host page cache,
+//! which writes it back at its own pace, so only its flushes, each an `fsync` of the backing
+//! file, are throttled.
+
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+use vmm_sys_util::timerfd::TimerFd;
+
+/// Bytes per second each guest sends or receives, unless configured otherwise: 1 Gbit/s.
+pub const DEFAULT_NET_BYTES_PER_SEC: u64 = 125_000_000;
+/// Frames per second each guest sends or receives, unless configured otherwise.
+pub const DEFAULT_NET_PACKETS_PER_SEC: u64 = 100_000;
+/// Flushes of the virtio-pmem device per second, unless configured otherwise.
+pub const DEFAULT_DISK_FLUSHES_PER_SEC: u64 = 100;
+
+/// Time a bucket takes to refill from empty, its size being the rate: bursts last a second.
+const REFILL_TIME: Duration = Duration::from_secs(1);
+
+/// Limits of the IO of a guest, each 0 for no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoLimits {
+    /// Bytes per second the guest sends through its network interface, and receives.
+    pub net_bytes_per_sec: u64,
+    pub net_packets_per_sec: u64,
+    pub disk_flushes_per_sec: u64,
+}
+
+impl Default for IoLimits {
+    fn default() -> Self {
+        Self {
+            net_bytes_per_sec: DEFAULT_NET_BYTES_PER_SEC,
+            net_packets_per_sec: DEFAULT_NET_PACKETS_PER_SEC,
+            disk_flushes_per_sec: DEFAULT_DISK_FLUSHES_PER_SEC,
+        }
+    }
+}
+
+impl IoLimits {
+    /// No limit at all.
+    pub const UNLIMITED: Self = Self {
+        net_bytes_per_sec: 0,
+        net_packets_per_sec: 0,
+        disk_flushes_per_sec: 0,
+    };
+
+    /// These limits, the ones of `ceiling` where they are higher or unset.
+    pub fn within(self, ceiling: Self) -> Self {
+        let min = |limit: u64, ceiling: u64| match (limit, ceiling) {
+            (0, _) => ceiling,
+            (_, 0) => limit,
+            _ => limit.min(ceiling),
+        };
+        Self {
+            net_bytes_per_sec: min(self.net_bytes_per_sec, ceiling.net_bytes_per_sec),
+            net_packets_per_sec: min(self.net_packets_per_sec, ceiling.net_packets_per_sec),
+            disk_flushes_per_sec: min(self.disk_flushes_per_sec, ceiling.disk_flushes_per_sec),
+        }
+    }
+}
+
+/// Bucket of `size` tokens, refilled continuously over [`REFILL_TIME`].
+#[derive(Debug)]
+struct TokenBucket {
+    size: u64,
+    budget: u64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn new(size: u64) -> Self {
+        Self {
+            size,
+            budget: size,
+            last_update: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_update).as_nanos();
+        let tokens = elapsed * u128::from(self.size) / REFILL_TIME.as_nanos();
+        // The time of a fraction of a token is kept for the next refill.
+        if tokens > 0 {
+            self.budget = (u128::from(self.budget) + tokens).min(self.size.into()) as u64;
+            self.last_update = now;
+        }
+    }
+
+    /// Time until the bucket holds `tokens`, capped at its size for the larger requests.
+    fn wait_for(&self, tokens: u64) -> Duration {
+        let missing = tokens.min(self.size).saturating_sub(self.budget);
+        let nanos = u128::from(missing) * REFILL_TIME.as_nanos() / u128::from(self.size);
+        // At least a millisecond, not to spin on a fraction of token.
+        Duration::from_nanos(nanos as u64).max(Duration::from_millis(1))
+    }
+
+    fn take(&mut self, tokens: u64) {
+        self.budget = self.budget.saturating_sub(tokens);
+    }
+}
+
+/// Limiter of the bytes and operations of a device queue.
+pub struct RateLimiter {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    timer: TimerFd,
+    blocked: bool,
+}
+
+impl RateLimiter {
+    /// Limiter of `bytes_per_sec` and `ops_per_sec`, each 0 for no limit.
+    pub fn new(bytes_per_sec: u64, ops_per_sec: u64) -> io::Result<Self> {
+        let bucket = |rate| (rate > 0).then(|| TokenBucket::new(rate));
+        Ok(Self {
+            bandwidth: bucket(bytes_per_sec),
+            ops: bucket(ops_per_sec),
+            timer: TimerFd::new()?,
+            blocked: false,
+        })
+    }
+
+    /// Take an operation of `bytes`. If a bucket is short of tokens, nothing is taken and the
+    /// timer is armed for when they will be available.
+    pub fn consume(&mut self, bytes: u64) -> io::Result<bool> {
+        if self.blocked {
+            return Ok(false);
+        }
+
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        for (bucket, tokens) in [(&mut self.bandwidth, bytes), (&mut self.ops, 1)] {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                if bucket.budget < tokens.min(bucket.size) {
+                    wait = wait.max(bucket.wait_for(tokens));
+                }
+            }
+        }
+        if !wait.is_zero() {
+            self.timer.reset(wait, None)?;
+            self.blocked = true;
+            return Ok(false);
+        }
+
+        for (bucket, tokens) in [(&mut self.bandwidth, bytes), (&mut self.ops, 1)] {
+            if let Some(bucket) = bucket {
+                bucket.take(tokens);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether the queue waits for the timer to fire.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Handle the expiration of the timer, the queue can be served again.
+    pub fn event_handler(&mut self) -> io::Result<()> {
+        self.timer.wait()?;
+        self.blocked = false;
+        Ok(())
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    /// Timer to watch for the queue to be served again.
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(1000, 2).unwrap();
+        assert!(limiter.consume(600).unwrap());
+        // Not enough bytes left: nothing is taken, and the limiter waits for its timer.
+        assert!(!limiter.consume(600).unwrap());
+        assert!(limiter.is_blocked());
+        assert!(!limiter.consume(1).unwrap());
+
+        std::thread::sleep(Duration::from_millis(200));
+        limiter.event_handler().unwrap();
+        assert!(limiter.consume(400).unwrap());
+        // The operations ran out before the bytes.
+        assert!(!limiter.consume(1).unwrap());
+
+        let mut unlimited = RateLimiter::new(0, 0).unwrap();
+        for _ in 0..1000 {
+            assert!(unlimited.consume(u64::MAX).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_larger_than_bucket() {
+        let mut limiter = RateLimiter::new(100, 0).unwrap();
+        // A frame larger than the bucket takes it whole rather than waiting forever.
+        assert!(limiter.consume(1500).unwrap());
+        assert!(!limiter.consume(1).unwrap());
+    }
+
+    #[test]
+    fn test_limits_within() {
+        let requested = IoLimits {
+            net_bytes_per_sec: 1000,
+            net_packets_per_sec: 0,
+            disk_flushes_per_sec: 500,
+        };
+        let ceiling = IoLimits {
+            net_bytes_per_sec: 2000,
+            net_packets_per_sec: 100,
+            disk_flushes_per_sec: 0,
+        };
+        assert_eq!(
+            requested.within(ceiling),
+            IoLimits {
+                net_bytes_per_sec: 1000,
+                net_packets_per_sec: 100,
+                disk_flushes_per_sec: 500,
+            }
+        );
+        assert_eq!(
+            IoLimits::default().within(IoLimits::UNLIMITED),
+            IoLimits::default()
+        );
+    }
+}
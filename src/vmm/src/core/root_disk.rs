@@ -0,0 +1,83 @@
+//! Root disk images built by `fs-gen --format ext4|erofs`, which the guest boots from through the
+//! virtio-pmem device, the VMM having no virtio-blk device: the kernel sees it as `/dev/pmem0`.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Offset and value of the magic number of the superblock of each format.
+const EXT4_MAGIC: (u64, &[u8]) = (1080, &[0x53, 0xef]);
+const EROFS_MAGIC: (u64, &[u8]) = (1024, &[0xe2, 0xe1, 0xf5, 0xe0]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootDiskFormat {
+    Ext4,
+    Erofs,
+}
+
+impl RootDiskFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RootDiskFormat::Ext4 => "ext4",
+            RootDiskFormat::Erofs => "erofs",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RootDisk {
+    pub path: PathBuf,
+    pub format: RootDiskFormat,
+    /// Size of the image, rounded up to a whole number of MB.
+    pub size_mb: u32,
+}
+
+impl RootDisk {
+    /// Open the image at `path`, recognizing its format from its superblock.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let has_magic = |file: &mut File, (offset, magic): (u64, &[u8])| -> io::Result<bool> {
+            let mut read = vec![0; magic.len()];
+            file.seek(SeekFrom::Start(offset))?;
+            match file.read_exact(&mut read) {
+                Ok(()) => Ok(read == magic),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+                Err(e) => Err(e),
+            }
+        };
+        let format = if has_magic(&mut file, EXT4_MAGIC)? {
+            RootDiskFormat::Ext4
+        } else if has_magic(&mut file, EROFS_MAGIC)? {
+            RootDiskFormat::Erofs
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is neither an ext4 nor an erofs image", path.display()),
+            ));
+        };
+        let size_mb = file.metadata()?.len().div_ceil(1 << 20);
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+            size_mb: size_mb.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "The image is too large")
+            })?,
+        })
+    }
+
+    /// Parameters of the kernel command line booting from the image. An erofs image is
+    /// read-only, an ext4 one is mounted writable, and changed by the guest.
+    pub fn kernel_cmdline(&self) -> Vec<String> {
+        vec![
+            "root=/dev/pmem0".into(),
+            format!("rootfstype={}", self.format.as_str()),
+            match self.format {
+                RootDiskFormat::Ext4 => "rw".into(),
+                RootDiskFormat::Erofs => "ro".into(),
+            },
+            "rootwait".into(),
+            "init=/init".into(),
+        ]
+    }
+}
@@ -0,0 +1,174 @@
+//! Activity counters of the vCPUs and devices of a guest, to diagnose slow or stuck VMs.
+//!
+//! The counters are only maintained when the `core-tracing` feature is enabled, they are
+//! no-ops otherwise so that the vCPU loop and the device handlers don't pay for them. The
+//! bytes sent by the guest are always counted, they are billed, and the CPU time of the vCPUs
+//! is read from the kernel on demand.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Whether the counters are maintained in this build.
+pub const ENABLED: bool = cfg!(feature = "core-tracing");
+
+/// Monotonic counter, or gauge through [`Counter::set`].
+#[derive(Debug, Default)]
+pub struct Counter {
+    #[cfg(feature = "core-tracing")]
+    value: AtomicU64,
+}
+
+#[cfg(feature = "core-tracing")]
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(not(feature = "core-tracing"))]
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {}
+
+    #[inline]
+    pub fn set(&self, _value: u64) {}
+
+    pub fn get(&self) -> u64 {
+        0
+    }
+}
+
+/// VM-Exits handled by a vCPU.
+#[derive(Debug, Default)]
+pub struct VcpuStats {
+    pub index: u64,
+    pub io_in_exits: Counter,
+    pub io_out_exits: Counter,
+    pub mmio_read_exits: Counter,
+    pub mmio_write_exits: Counter,
+    pub other_exits: Counter,
+    /// Failed KVM_RUN calls and device accesses.
+    pub errors: Counter,
+    /// Address of the last port or MMIO access, to see where a vCPU spins.
+    pub last_exit_address: Counter,
+    /// Id of the host thread running the vCPU, 0 until it starts.
+    pub thread_id: AtomicU64,
+}
+
+impl VcpuStats {
+    pub fn new(index: u64) -> Self {
+        Self {
+            index,
+            ..Default::default()
+        }
+    }
+
+    /// Host CPU time consumed by the thread of the vCPU, in the guest included, if it started.
+    pub fn cpu_time(&self) -> Option<Duration> {
+        let tid = self.thread_id.load(Ordering::Relaxed);
+        if tid == 0 {
+            return None;
+        }
+        let stat = std::fs::read_to_string(format!("/proc/self/task/{}/stat", tid)).ok()?;
+        thread_cpu_time(&stat)
+    }
+}
+
+/// `utime` + `stime` of a `/proc/<pid>/task/<tid>/stat` file.
+fn thread_cpu_time(stat: &str) -> Option<Duration> {
+    // The fields following the command, which may contain spaces and parentheses, start with
+    // the state, the third field; utime and stime are the 14th and 15th.
+    let mut fields = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    let ticks_per_second = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as u64,
+        _ => 100,
+    };
+    Some(Duration::from_millis(
+        (utime + stime) * 1000 / ticks_per_second,
+    ))
+}
+
+/// Activity of a virtio device.
+#[derive(Debug, Default)]
+pub struct DeviceStats {
+    pub name: &'static str,
+    /// Queue notifications from the driver, received through the ioeventfds.
+    pub queue_notifications: Counter,
+    /// Interrupts injected into the guest through the irqfd.
+    pub interrupts: Counter,
+    /// Events of the host backend of the device, e.g. the TAP interface.
+    pub backend_events: Counter,
+    pub errors: Counter,
+    /// Bytes sent by the guest through the device, counted in every build.
+    pub tx_bytes: AtomicU64,
+}
+
+impl DeviceStats {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+}
+
+/// Counters of every vCPU and device of a guest.
+#[derive(Debug, Default)]
+pub struct VmStats {
+    vcpus: Mutex<Vec<Arc<VcpuStats>>>,
+    devices: Mutex<Vec<Arc<DeviceStats>>>,
+}
+
+impl VmStats {
+    pub(crate) fn add_vcpu(&self, stats: Arc<VcpuStats>) {
+        self.vcpus.lock().unwrap().push(stats);
+    }
+
+    pub(crate) fn add_device(&self, stats: Arc<DeviceStats>) {
+        self.devices.lock().unwrap().push(stats);
+    }
+
+    pub fn vcpus(&self) -> Vec<Arc<VcpuStats>> {
+        self.vcpus.lock().unwrap().clone()
+    }
+
+    pub fn devices(&self) -> Vec<Arc<DeviceStats>> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    /// Host CPU time consumed by all the vCPUs of the guest.
+    pub fn cpu_time(&self) -> Duration {
+        self.vcpus
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|vcpu| vcpu.cpu_time())
+            .sum()
+    }
+
+    /// Bytes sent by the guest through all its devices.
+    pub fn tx_bytes(&self) -> u64 {
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|device| device.tx_bytes.load(Ordering::Relaxed))
+            .sum()
+    }
+}
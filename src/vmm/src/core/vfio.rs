@@ -0,0 +1,236 @@
+//! Host PCI devices which can be passed through to guests with VFIO.
+//!
+//! The SR-IOV virtual functions of the host NICs are kept in a [`VfPool`], each given to a
+//! single guest at a time and reset before the next one gets it.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::error;
+
+const PCI_DEVICES: &str = "/sys/bus/pci/devices";
+const VFIO_DRIVER: &str = "vfio-pci";
+
+#[derive(Debug)]
+pub enum VfioError {
+    /// The PCI device doesn't exist.
+    NotFound(String),
+    /// The device isn't in an IOMMU group, the IOMMU is probably disabled.
+    NoIommuGroup(String),
+    /// Another device of the IOMMU group isn't bound to vfio-pci, so the group can't be
+    /// assigned to a guest.
+    GroupNotViable {
+        device: String,
+        other: String,
+    },
+    /// The VFIO group device node is missing.
+    NoGroupDevice(PathBuf),
+    /// The device isn't the virtual function of an SR-IOV physical function.
+    NotVirtualFunction(String),
+    Reset(io::Error),
+}
+
+impl fmt::Display for VfioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VfioError::NotFound(device) => write!(f, "PCI device {} not found", device),
+            VfioError::NoIommuGroup(device) => write!(
+                f,
+                "PCI device {} has no IOMMU group, enable the IOMMU (e.g. intel_iommu=on)",
+                device
+            ),
+            VfioError::GroupNotViable { device, other } => write!(
+                f,
+                "IOMMU group of {} also contains {}, which isn't bound to {}",
+                device, other, VFIO_DRIVER
+            ),
+            VfioError::NoGroupDevice(path) => write!(f, "VFIO group {:?} not found", path),
+            VfioError::NotVirtualFunction(device) => write!(
+                f,
+                "PCI device {} isn't an SR-IOV virtual function, create them with sriov_numvfs",
+                device
+            ),
+            VfioError::Reset(e) => write!(f, "Failed to reset the device: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VfioError {}
+
+/// A host PCI device ready to be assigned to a guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VfioDevice {
+    /// PCI address, e.g. `0000:01:00.0`.
+    pub bdf: String,
+    pub iommu_group: u32,
+}
+
+impl VfioDevice {
+    /// Check that the device at `bdf` and every device sharing its IOMMU group are bound
+    /// to vfio-pci, which is required to assign them to a guest.
+    pub fn probe(bdf: &str) -> Result<Self, VfioError> {
+        let device_dir = Path::new(PCI_DEVICES).join(bdf);
+        if !device_dir.exists() {
+            return Err(VfioError::NotFound(bdf.to_string()));
+        }
+
+        let group_dir = fs::read_link(device_dir.join("iommu_group"))
+            .map_err(|_| VfioError::NoIommuGroup(bdf.to_string()))?;
+        let iommu_group = group_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse().ok())
+            .ok_or_else(|| VfioError::NoIommuGroup(bdf.to_string()))?;
+
+        let group_devices = fs::read_dir(device_dir.join("iommu_group/devices"))
+            .map_err(|_| VfioError::NoIommuGroup(bdf.to_string()))?;
+        for other in group_devices.flatten() {
+            let other = other.file_name().to_string_lossy().into_owned();
+            if driver(&other).as_deref() != Some(VFIO_DRIVER) {
+                return Err(VfioError::GroupNotViable {
+                    device: bdf.to_string(),
+                    other,
+                });
+            }
+        }
+
+        let group_device = PathBuf::from(format!("/dev/vfio/{}", iommu_group));
+        if !group_device.exists() {
+            return Err(VfioError::NoGroupDevice(group_device));
+        }
+
+        Ok(Self {
+            bdf: bdf.to_string(),
+            iommu_group,
+        })
+    }
+
+    /// Like [`VfioDevice::probe`], for a virtual function of an SR-IOV NIC.
+    pub fn probe_virtual_function(bdf: &str) -> Result<Self, VfioError> {
+        let device = Self::probe(bdf)?;
+        if !device.sysfs_path().join("physfn").exists() {
+            return Err(VfioError::NotVirtualFunction(bdf.to_string()));
+        }
+        Ok(device)
+    }
+
+    /// Directory of the device in sysfs, as given to cloud-hypervisor.
+    pub fn sysfs_path(&self) -> PathBuf {
+        Path::new(PCI_DEVICES).join(&self.bdf)
+    }
+
+    /// Reset the device, so that a guest doesn't see the state left by the previous one.
+    /// Devices without a reset method are left as is.
+    pub fn reset(&self) -> Result<(), VfioError> {
+        let reset = Path::new(PCI_DEVICES).join(&self.bdf).join("reset");
+        if !reset.exists() {
+            return Ok(());
+        }
+        fs::write(reset, "1").map_err(VfioError::Reset)
+    }
+}
+
+/// Name of the driver bound to the PCI device `bdf`, if any.
+fn driver(bdf: &str) -> Option<String> {
+    fs::read_link(Path::new(PCI_DEVICES).join(bdf).join("driver"))
+        .ok()
+        .and_then(|driver| {
+            driver
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+}
+
+/// Virtual functions given to the guests one at a time.
+#[derive(Debug, Clone, Default)]
+pub struct VfPool {
+    free: Arc<Mutex<Vec<VfioDevice>>>,
+    total: usize,
+}
+
+impl VfPool {
+    pub fn new(functions: Vec<VfioDevice>) -> Self {
+        Self {
+            total: functions.len(),
+            free: Arc::new(Mutex::new(functions)),
+        }
+    }
+
+    /// Virtual functions of the pool, in use or not.
+    pub fn len(&self) -> usize {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// Virtual functions not given to a guest.
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    /// A free virtual function, if any, back in the pool once the lease is dropped.
+    pub fn acquire(&self) -> Option<VfLease> {
+        let device = self.free.lock().unwrap().pop()?;
+        Some(VfLease {
+            device: Some(device),
+            pool: self.free.clone(),
+        })
+    }
+}
+
+/// A virtual function given to a guest, reset and put back in its pool when dropped.
+#[derive(Debug)]
+pub struct VfLease {
+    device: Option<VfioDevice>,
+    pool: Arc<Mutex<Vec<VfioDevice>>>,
+}
+
+impl VfLease {
+    pub fn device(&self) -> &VfioDevice {
+        self.device
+            .as_ref()
+            .expect("the device is only taken on drop")
+    }
+}
+
+impl Drop for VfLease {
+    fn drop(&mut self) {
+        let Some(device) = self.device.take() else {
+            return;
+        };
+        // A function which can't be reset would leak the state of a guest to the next one.
+        match device.reset() {
+            Ok(()) => self.pool.lock().unwrap().push(device),
+            Err(e) => {
+                error!(bdf = %device.bdf, error = %e, "Could not reset the virtual function, retiring it")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vf_pool() {
+        let function = |bdf: &str| VfioDevice {
+            bdf: bdf.to_string(),
+            iommu_group: 0,
+        };
+        let pool = VfPool::new(vec![function("0000:00:00.9")]);
+        let lease = pool.acquire().unwrap();
+        assert_eq!(lease.device().bdf, "0000:00:00.9");
+        assert_eq!((pool.len(), pool.available()), (1, 0));
+        assert!(pool.acquire().is_none());
+
+        // A device without a reset method in sysfs is put back as is.
+        drop(lease);
+        assert_eq!(pool.available(), 1);
+        assert!(pool.acquire().is_some());
+    }
+}
@@ -1,32 +1,42 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use crate::core::cpu::{self, cpuid, mptable, Vcpu};
-use crate::core::devices::serial::LumperSerial;
+use crate::core::devices::serial::{ConsoleOutput, LumperSerial};
 use crate::core::epoll_context::{EpollContext, EPOLL_EVENTS_LEN};
+use crate::core::exit::{self, ExitState, VmExit, VmStopper};
 use crate::core::kernel;
 use crate::core::{Error, Result};
 use event_manager::{EventManager, MutEventSubscriber};
 use kvm_bindings::{kvm_userspace_memory_region, KVM_MAX_CPUID_ENTRIES};
 use kvm_ioctls::{Kvm, VmFd};
 use linux_loader::loader::KernelLoaderResult;
-use std::io::{self, stdout, Stdout};
+use std::io::{self, stdout};
 use std::net::Ipv4Addr;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use vm_allocator::{AddressAllocator, AllocPolicy};
 use vm_device::bus::{MmioAddress, MmioRange};
 use vm_device::device_manager::IoManager;
-use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 use vmm_sys_util::terminal::Terminal;
 
-use super::devices::virtio::net::device::Net;
+use super::cpu_template::CpuTemplate;
+use super::devices::virtio::net::{device::Net, BridgeConfig};
+use super::devices::virtio::pmem::{device::Pmem, PMEM_ALIGNMENT};
 use super::devices::virtio::{self, MmioConfig};
 use super::irq_allocator::IrqAllocator;
+use super::memory::{self, GuestMemoryConfig};
+use super::placement::{self, CpuPlacement};
+use super::rate_limiter::IoLimits;
 use super::slip_pty::SlipPty;
+use super::stats::VmStats;
+
+pub use crate::core::cpu::cpuid::host_supports_nested;
 
 #[cfg(target_arch = "x86_64")]
 pub(crate) const MMIO_GAP_END: u64 = 1 << 34;
@@ -45,6 +55,15 @@ pub const DEFAULT_ALLOC_POLICY: AllocPolicy = AllocPolicy::FirstMatch;
 const SERIAL_IRQ: u32 = 4;
 /// Last usable IRQ ID for virtio device interrupts on x86_64.
 const IRQ_MAX: u8 = 23;
+/// Time after which the device event loop checks whether the VM stopped.
+const EVENT_MGR_TIMEOUT_MS: i32 = 100;
+
+/// Host file backing the virtio-pmem device of the guest.
+#[derive(Clone)]
+struct PmemBacking {
+    path: PathBuf,
+    size_mb: u32,
+}
 
 type EventMgr = Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>;
 
@@ -61,10 +80,21 @@ pub struct VMM {
     iface_host_addr: Ipv4Addr,
     netmask: Ipv4Addr,
     iface_guest_addr: Ipv4Addr,
+    bridge: BridgeConfig,
+    io_limits: IoLimits,
     net_devices: Vec<Arc<Mutex<Net>>>,
-    serial: Arc<Mutex<LumperSerial<Stdout>>>,
+    pmem: Option<PmemBacking>,
+    pmem_devices: Vec<Arc<Mutex<Pmem>>>,
+    serial: Arc<Mutex<LumperSerial<ConsoleOutput>>>,
     slip_pty: Arc<Mutex<SlipPty>>,
     epoll: EpollContext,
+    exit: Arc<ExitState>,
+    placement: Option<CpuPlacement>,
+    memory_config: GuestMemoryConfig,
+    nested_virtualization: bool,
+    cpu_template: CpuTemplate,
+    kernel_cmdline: Vec<String>,
+    stats: Arc<VmStats>,
 }
 
 impl VMM {
@@ -97,6 +127,10 @@ impl VMM {
                 epoll::Events::EPOLLIN | epoll::Events::EPOLLET,
             )
             .map_err(Error::EpollError)?;
+        let exit = Arc::new(ExitState::new().map_err(Error::IO)?);
+        epoll
+            .add_fd(exit.as_raw_fd(), epoll::Events::EPOLLIN)
+            .map_err(Error::EpollError)?;
 
         let irq_allocator = IrqAllocator::new(SERIAL_IRQ, IRQ_MAX.into()).unwrap();
         let device_mgr = Arc::new(Mutex::new(IoManager::new()));
@@ -111,14 +145,25 @@ impl VMM {
             event_mgr: Arc::new(Mutex::new(EventManager::new().unwrap())),
             vcpus: vec![],
             serial: Arc::new(Mutex::new(
-                LumperSerial::new(stdout()).map_err(Error::SerialCreation)?,
+                LumperSerial::new(Box::new(stdout())).map_err(Error::SerialCreation)?,
             )),
             slip_pty: Arc::new(Mutex::new(slip_pty)),
             epoll,
+            exit,
             iface_host_addr,
             netmask,
             iface_guest_addr,
+            bridge: BridgeConfig::default(),
+            io_limits: IoLimits::default(),
             net_devices: Vec::new(),
+            pmem: None,
+            pmem_devices: Vec::new(),
+            placement: None,
+            memory_config: GuestMemoryConfig::default(),
+            nested_virtualization: false,
+            cpu_template: CpuTemplate::default(),
+            kernel_cmdline: Vec::new(),
+            stats: Arc::new(VmStats::default()),
         };
 
         Ok(vmm)
@@ -128,11 +173,28 @@ impl VMM {
         // Convert memory size from MBytes to bytes.
         let mem_size = ((mem_size_mb as u64) << 20) as usize;
 
-        // Create one single memory region, from zero to mem_size.
-        let mem_regions = vec![(GuestAddress(0), mem_size)];
+        // Allocate one single memory region, from zero to mem_size.
+        let guest_memory =
+            memory::map_guest_memory(mem_size, &self.memory_config).map_err(Error::Memory)?;
+
+        // Keep the guest memory close to its vCPUs. Pages are only allocated when first touched,
+        // so the policy applies to the whole guest memory.
+        if let Some(node) = self.placement.as_ref().and_then(|p| p.numa_node) {
+            for region in guest_memory.iter() {
+                let addr = guest_memory.get_host_address(region.start_addr()).unwrap();
+                if let Err(e) = placement::bind_memory(addr, region.len() as usize, node) {
+                    warn!(node, reason = %e, "Could not bind guest memory to NUMA node");
+                }
+            }
+        }
 
-        // Allocate the guest memory from the memory region.
-        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions).map_err(Error::Memory)?;
+        if self.memory_config.prealloc {
+            for region in guest_memory.iter() {
+                let addr = guest_memory.get_host_address(region.start_addr()).unwrap();
+                // SAFETY: the region was just mapped, writable, and isn't used by the guest yet.
+                unsafe { memory::prealloc(addr, region.len() as usize) };
+            }
+        }
 
         // For each memory region in guest_memory:
         // 1. Create a KVM memory region mapping the memory region guest physical address to the host virtual address.
@@ -211,6 +273,14 @@ impl VMM {
                 .map_err(Error::KvmIoctl)?;
         }
 
+        for pmem in self.pmem_devices.iter() {
+            let pmem_cfg = &pmem.lock().unwrap().config;
+
+            self.vm_fd
+                .register_irqfd(&pmem_cfg.irqfd, pmem_cfg.mmio.gsi)
+                .map_err(Error::KvmIoctl)?;
+        }
+
         Ok(())
     }
 
@@ -230,6 +300,7 @@ impl VMM {
                 self.device_mgr.clone(),
                 Arc::clone(&self.serial),
                 Arc::clone(&self.slip_pty),
+                Arc::clone(&self.exit),
             )
             .map_err(Error::Vcpu)?;
 
@@ -239,8 +310,10 @@ impl VMM {
                 &self.kvm,
                 index as usize,
                 num_vcpus as usize,
+                self.nested_virtualization,
                 &mut vcpu_cpuid,
             );
+            self.cpu_template.apply(&mut vcpu_cpuid);
             vcpu.configure_cpuid(&vcpu_cpuid).map_err(Error::Vcpu)?;
 
             // Configure MSRs (model specific registers).
@@ -256,19 +329,110 @@ impl VMM {
             // Configure LAPICs.
             vcpu.configure_lapic().map_err(Error::Vcpu)?;
 
+            self.stats.add_vcpu(vcpu.stats.clone());
             self.vcpus.push(vcpu);
         }
 
         Ok(())
     }
 
-    /// Run all virtual CPUs.
-    pub fn run(&mut self) -> Result<()> {
+    /// Pin the vCPUs to host CPUs and allocate the guest memory from their NUMA node.
+    /// Must be called before [`VMM::configure`].
+    pub fn set_cpu_placement(&mut self, placement: CpuPlacement) {
+        self.placement = Some(placement);
+    }
+
+    /// Set how the guest memory is allocated. Must be called before [`VMM::configure`].
+    pub fn set_memory_config(&mut self, memory_config: GuestMemoryConfig) {
+        self.memory_config = memory_config;
+    }
+
+    /// Activity counters of the vCPUs and devices, maintained with the `core-tracing` feature.
+    pub fn stats(&self) -> Arc<VmStats> {
+        self.stats.clone()
+    }
+
+    /// Handle stopping the VM from another thread, its run returning once it stopped.
+    pub fn stopper(&self) -> VmStopper {
+        VmStopper(self.exit.clone())
+    }
+
+    /// Expose the virtualization extensions to the guest, so that it can run its own VMs.
+    /// Has no effect when the host KVM doesn't support nested virtualization.
+    pub fn set_nested_virtualization(&mut self, enabled: bool) {
+        self.nested_virtualization = enabled;
+    }
+
+    /// Mask the CPUID of the vCPUs with `template`.
+    pub fn set_cpu_template(&mut self, template: CpuTemplate) {
+        self.cpu_template = template;
+    }
+
+    /// Append `parameters` to the command line of the guest kernel.
+    /// Must be called before `configure`.
+    pub fn set_kernel_cmdline(&mut self, parameters: Vec<String>) {
+        self.kernel_cmdline = parameters;
+    }
+
+    /// Attach the guest to the bridge `name` instead of the shared one, `isolated` dropping the
+    /// traffic routed from it to the other guest networks. Must be called before `configure`.
+    pub fn set_bridge(&mut self, name: String, isolated: bool) {
+        self.bridge = BridgeConfig {
+            name,
+            isolated,
+            uplink: self.bridge.uplink.take(),
+        };
+    }
+
+    /// Route the traffic of the guest to the outside through the host interface `uplink` only.
+    /// Must be called before `configure`.
+    pub fn set_uplink(&mut self, uplink: Option<String>) {
+        self.bridge.uplink = uplink;
+    }
+
+    /// Write the console of the guest to `out` rather than to the standard output. Must be
+    /// called before `configure`.
+    pub fn set_console(&mut self, out: Box<dyn io::Write + Send>) -> Result<()> {
+        self.serial = Arc::new(Mutex::new(
+            LumperSerial::new(out).map_err(Error::SerialCreation)?,
+        ));
+        Ok(())
+    }
+
+    /// Throttle the network and disk IO of the guest. Must be called before `configure`.
+    pub fn set_io_limits(&mut self, limits: IoLimits) {
+        self.io_limits = limits;
+    }
+
+    /// Expose `size_mb` of the host file at `path` to the guest as a virtio-pmem device.
+    /// Must be called before `configure`.
+    pub fn set_pmem(&mut self, path: PathBuf, size_mb: u32) {
+        self.pmem = Some(PmemBacking { path, size_mb });
+    }
+
+    /// Run all virtual CPUs, until the guest stops or is stopped by a [`VmStopper`].
+    pub fn run(&mut self) -> Result<VmExit> {
+        exit::register_kick_handler();
+        let mut vcpu_threads = Vec::new();
         for mut vcpu in self.vcpus.drain(..) {
             info!(vcpu_index = vcpu.index, "Starting vCPU");
-            let _ = thread::Builder::new().spawn(move || loop {
-                vcpu.run();
-            });
+            let host_cpu = self
+                .placement
+                .as_ref()
+                .and_then(|p| p.cpu_for(vcpu.index as usize));
+            let thread = thread::Builder::new()
+                .spawn(move || {
+                    if let Some(cpu) = host_cpu {
+                        placement::pin_current_thread(cpu);
+                    }
+                    let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+                    vcpu.stats.thread_id.store(tid as u64, Ordering::Relaxed);
+                    while !vcpu.is_stopped() {
+                        vcpu.run();
+                    }
+                })
+                .map_err(Error::IO)?;
+            vcpu_threads.push(thread);
         }
 
         let stdin = io::stdin();
@@ -280,10 +444,17 @@ impl VMM {
         let epoll_fd = self.epoll.as_raw_fd();
 
         let event_mgr = self.event_mgr.clone();
-        let _ = thread::Builder::new().spawn(move || loop {
-            match event_mgr.lock().unwrap().run() {
-                Ok(_) => (),
-                Err(e) => eprintln!("Failed to handle events: {:?}", e),
+        let exit = self.exit.clone();
+        let _ = thread::Builder::new().spawn(move || {
+            while !exit.is_stopped() {
+                match event_mgr
+                    .lock()
+                    .unwrap()
+                    .run_with_timeout(EVENT_MGR_TIMEOUT_MS)
+                {
+                    Ok(_) => (),
+                    Err(e) => eprintln!("Failed to handle events: {:?}", e),
+                }
             }
         });
 
@@ -296,7 +467,15 @@ impl VMM {
                 let event_evts = epoll::Events::from_bits_truncate(event.events);
                 let event_data = event.data as RawFd;
 
-                if let libc::STDIN_FILENO = event_data {
+                if event_data == self.exit.as_raw_fd() {
+                    let exit = self.exit.exit().unwrap_or(VmExit::Shutdown);
+                    info!(?exit, "VM stopped");
+                    exit::stop_vcpus(vcpu_threads);
+                    stdin_lock
+                        .set_canon_mode()
+                        .map_err(Error::TerminalConfigure)?;
+                    return Ok(exit);
+                } else if let libc::STDIN_FILENO = event_data {
                     let mut out = [0u8; 64];
 
                     let count = stdin_lock.read_raw(&mut out).map_err(Error::StdinRead)?;
@@ -343,11 +522,12 @@ impl VMM {
         kernel_path: PathBuf,
         initramfs_path: &Option<PathBuf>,
     ) -> Result<()> {
-        let cmdline_extra_parameters = &mut Vec::new();
+        let cmdline_extra_parameters = &mut self.kernel_cmdline.clone();
 
         self.configure_memory(mem_size_mb)?;
         self.configure_allocators(mem_size_mb)?;
         self.configure_net_device(cmdline_extra_parameters).await?;
+        self.configure_pmem_device(mem_size_mb, cmdline_extra_parameters)?;
 
         let kernel_load = kernel::kernel_setup(
             &self.guest_memory,
@@ -366,9 +546,8 @@ impl VMM {
         cmdline_extra_parameters: &mut Vec<String>,
     ) -> Result<()> {
         let mem = Arc::new(self.guest_memory.clone());
-        let range = if let Some(allocator) = &self.address_allocator {
+        let range = if let Some(allocator) = self.address_allocator.as_mut() {
             allocator
-                .to_owned()
                 .allocate(0x1000, DEFAULT_ADDRESS_ALIGNEMNT, DEFAULT_ALLOC_POLICY)
                 .map_err(Error::Allocate)?
         } else {
@@ -392,6 +571,8 @@ impl VMM {
             self.iface_host_addr,
             self.netmask,
             self.iface_guest_addr,
+            &self.bridge,
+            self.io_limits,
             irq,
             remote_endpoint,
             self.vm_fd.clone(),
@@ -405,8 +586,63 @@ impl VMM {
             Error::Virtio(virtio::Error::Net)
         })?;
 
+        self.stats
+            .add_device(net.lock().unwrap().config.stats.clone());
         self.net_devices.push(net);
 
         Ok(())
     }
+
+    fn configure_pmem_device(
+        &mut self,
+        mem_size_mb: u32,
+        cmdline_extra_parameters: &mut Vec<String>,
+    ) -> Result<()> {
+        let Some(backing) = self.pmem.clone() else {
+            return Ok(());
+        };
+
+        let range = self
+            .address_allocator
+            .as_mut()
+            .expect("Address allocator is not initialized")
+            .allocate(0x1000, DEFAULT_ADDRESS_ALIGNEMNT, DEFAULT_ALLOC_POLICY)
+            .map_err(Error::Allocate)?;
+        let mmio_range = MmioRange::new(MmioAddress(range.start()), range.len())
+            .map_err(|_| Error::MmioRange)?;
+        let irq = self.irq_allocator.next_irq().map_err(Error::IrqAllocator)?;
+        let mmio_cfg = MmioConfig {
+            range: mmio_range,
+            gsi: irq,
+        };
+
+        // The device memory sits above the guest RAM and the MMIO gap, in its own KVM slot.
+        let mem_size = (mem_size_mb as u64) << 20;
+        let guest_addr = mem_size.max(MMIO_GAP_END).next_multiple_of(PMEM_ALIGNMENT);
+        let slot = self.guest_memory.num_regions() as u32;
+
+        let remote_endpoint = { self.event_mgr.lock().unwrap().remote_endpoint() };
+
+        let pmem = Pmem::new(
+            Arc::new(self.guest_memory.clone()),
+            self.device_mgr.clone(),
+            mmio_cfg,
+            irq,
+            remote_endpoint,
+            self.vm_fd.clone(),
+            slot,
+            guest_addr,
+            &backing.path,
+            (backing.size_mb as u64) << 20,
+            self.io_limits.disk_flushes_per_sec,
+            cmdline_extra_parameters,
+        )
+        .map_err(Error::Pmem)?;
+
+        self.stats
+            .add_device(pmem.lock().unwrap().config.stats.clone());
+        self.pmem_devices.push(pmem);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,526 @@
+use super::hypervisor::GuestStopper;
+use super::{
+    client::WorkloadClient,
+    config::ConfigReloader,
+    deterministic::{seeded_random, system_clock, Deterministic, SharedClock},
+    faults::{self, FaultSettings, Faults},
+    janitor::Janitor,
+    maintenance::Maintenance,
+    pool::FunctionPool,
+    tasks::VmTasks,
+};
+use crate::core::network::GuestAddresses;
+use crate::core::stats::{self, VmStats};
+use shared_models::labels::LabelSelector;
+use shared_models::vmmorchestrator::admin::{
+    admin_service_server::AdminService as AdminServiceTrait, AddressLease, CordonNodeRequest,
+    DeviceDebugInfo, DrainNodeRequest, DumpStateRequest, DumpVmDebugInfoRequest,
+    InjectFaultsRequest, InjectFaultsResponse, ListVmsRequest, ListVmsResponse, NodeStatus,
+    PruneArtifactsRequest, PruneArtifactsResponse, PrunedArtifact, ReloadConfigRequest,
+    ReloadConfigResponse, StateDump, UncordonNodeRequest, VcpuDebugInfo, VmDebugInfo, VmInfo,
+};
+use shared_models::{vmmorchestrator::ShutdownVmRequest, ErrorCode, DEFAULT_AGENT_PORT};
+use std::str::FromStr;
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    hash::{BuildHasher, Hasher},
+    net::Ipv4Addr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tonic::{Code, Request, Response, Status};
+use tracing::warn;
+
+/// Port the agent of `vm` listens on.
+pub fn agent_port(vm: &VmInfo) -> u16 {
+    match u16::try_from(vm.agent_port) {
+        Ok(0) | Err(_) => DEFAULT_AGENT_PORT,
+        Ok(port) => port,
+    }
+}
+
+/// VMs started by the orchestrator and still running, shared with the admin service.
+#[derive(Clone)]
+pub struct VmTable {
+    ids: Arc<IdGenerator>,
+    vms: Arc<Mutex<BTreeMap<String, RunningVm>>>,
+    clock: SharedClock,
+}
+
+impl Default for VmTable {
+    fn default() -> Self {
+        Self {
+            ids: Arc::default(),
+            vms: Arc::default(),
+            clock: system_clock(),
+        }
+    }
+}
+
+struct RunningVm {
+    info: VmInfo,
+    stats: Arc<VmStats>,
+    /// Set once the guest runs.
+    stopper: Option<GuestStopper>,
+    /// Tasks of the runs of the VM.
+    tasks: Option<VmTasks>,
+    /// Why the orchestrator is stopping the VM, if it is.
+    stop_reason: Option<String>,
+}
+
+/// Generator of the short suffixes of the VM ids.
+#[derive(Default)]
+struct IdGenerator {
+    // Randomly seeded, so that ids aren't reused across restarts of the orchestrator.
+    state: RandomState,
+    /// Seed of the deterministic mode, which the ids are drawn from instead.
+    seed: Option<u64>,
+    counter: AtomicU64,
+}
+
+impl IdGenerator {
+    fn next(&self) -> String {
+        let index = self.counter.fetch_add(1, Ordering::Relaxed);
+        let random = match self.seed {
+            Some(seed) => seeded_random(seed, index),
+            None => {
+                let mut hasher = self.state.build_hasher();
+                hasher.write_u64(index);
+                hasher.finish()
+            }
+        };
+        format!("{:08x}", random as u32)
+    }
+}
+
+impl VmTable {
+    /// A table whose ids are drawn from the seed of `mode` and whose VMs are timestamped by
+    /// its clock.
+    pub fn deterministic(mode: &Deterministic) -> Self {
+        Self {
+            ids: Arc::new(IdGenerator {
+                seed: Some(mode.seed),
+                ..Default::default()
+            }),
+            vms: Arc::default(),
+            clock: mode.clock(),
+        }
+    }
+
+    /// Record a new VM and return its id, `<workload name>-<8 hex digits>`, which is filled
+    /// in `vm`. Returns `None` if a VM of the same workload is already running, so that
+    /// workload names designate a single running VM.
+    pub fn insert(&self, mut vm: VmInfo, stats: Arc<VmStats>) -> Option<String> {
+        let mut vms = self.vms.lock().unwrap();
+        if vms
+            .values()
+            .any(|other| other.info.workload_name == vm.workload_name)
+        {
+            return None;
+        }
+
+        let id = loop {
+            let id = format!("{}-{}", vm.workload_name, self.ids.next());
+            if !vms.contains_key(&id) {
+                break id;
+            }
+        };
+        vm.id = id.clone();
+        vm.started_at = self.clock.unix_secs();
+
+        vms.insert(
+            id.clone(),
+            RunningVm {
+                info: vm,
+                stats,
+                stopper: None,
+                tasks: None,
+                stop_reason: None,
+            },
+        );
+        Some(id)
+    }
+
+    /// Remove the stopped VM `id`, returning why the orchestrator stopped it if it did.
+    pub fn remove(&self, id: &str) -> Option<String> {
+        self.vms.lock().unwrap().remove(id)?.stop_reason
+    }
+
+    pub fn set_stopper(&self, id: &str, stopper: GuestStopper) {
+        if let Some(vm) = self.vms.lock().unwrap().get_mut(id) {
+            vm.stopper = Some(stopper);
+        }
+    }
+
+    pub fn set_console_log(&self, id: &str, path: &Path) {
+        if let Some(vm) = self.vms.lock().unwrap().get_mut(id) {
+            vm.info.console_log = path.to_string_lossy().into_owned();
+        }
+    }
+
+    pub fn set_tasks(&self, id: &str, tasks: VmTasks) {
+        if let Some(vm) = self.vms.lock().unwrap().get_mut(id) {
+            vm.tasks = Some(tasks);
+        }
+    }
+
+    /// Tasks of the running VM `id`, for the runs made in it after the first one.
+    pub fn tasks(&self, id: &str) -> Option<VmTasks> {
+        self.vms.lock().unwrap().get(id)?.tasks.clone()
+    }
+
+    /// Record that the orchestrator is stopping the VM `id` for `reason`, the first reason
+    /// given being kept.
+    pub fn mark_stopping(&self, id: &str, reason: &str) {
+        if let Some(vm) = self.vms.lock().unwrap().get_mut(id) {
+            vm.stop_reason.get_or_insert_with(|| reason.to_string());
+        }
+    }
+
+    /// Stop the VM `id` from the host, without going through its agent.
+    pub fn kill(&self, id: &str, reason: &str) {
+        let stopper = match self.vms.lock().unwrap().get_mut(id) {
+            Some(vm) => {
+                vm.stop_reason.get_or_insert_with(|| reason.to_string());
+                vm.stopper.clone()
+            }
+            None => return,
+        };
+        if let Some(stopper) = stopper {
+            warn!(vm_id = id, reason, "Killing VM");
+            stopper.stop(reason);
+        }
+    }
+
+    /// Whether a VM of `workload_name` is running.
+    pub fn is_running(&self, workload_name: &str) -> bool {
+        self.vms
+            .lock()
+            .unwrap()
+            .values()
+            .any(|vm| vm.info.workload_name == workload_name)
+    }
+
+    /// Find a running VM by id or by workload name. An empty `vm` designates the only
+    /// running VM, if there is a single one.
+    pub fn resolve(&self, vm: &str) -> Option<VmInfo> {
+        self.resolve_with_stats(vm).map(|(info, _)| info)
+    }
+
+    fn resolve_with_stats(&self, vm: &str) -> Option<(VmInfo, Arc<VmStats>)> {
+        let vms = self.vms.lock().unwrap();
+        let running = if vm.is_empty() {
+            match vms.len() {
+                1 => vms.values().next(),
+                _ => None,
+            }
+        } else {
+            vms.get(vm).or_else(|| {
+                vms.values()
+                    .find(|running| running.info.workload_name == vm)
+            })
+        };
+
+        running.map(|running| (running.info.clone(), running.stats.clone()))
+    }
+
+    /// Running VMs, with their activity counters.
+    pub fn list_with_stats(&self) -> Vec<(VmInfo, Arc<VmStats>)> {
+        self.vms
+            .lock()
+            .unwrap()
+            .values()
+            .map(|vm| (vm.info.clone(), vm.stats.clone()))
+            .collect()
+    }
+
+    pub fn list(&self) -> Vec<VmInfo> {
+        self.vms
+            .lock()
+            .unwrap()
+            .values()
+            .map(|vm| vm.info.clone())
+            .collect()
+    }
+}
+
+/// Debug service exposing the internal state of the orchestrator.
+pub struct AdminService {
+    vms: VmTable,
+    janitor: Arc<Janitor>,
+    reloader: Option<Arc<ConfigReloader>>,
+    faults: Arc<Faults>,
+    maintenance: Arc<Maintenance>,
+    addresses: GuestAddresses,
+    pool: Arc<FunctionPool>,
+}
+
+impl AdminService {
+    /// `reloader` reloads the configuration file, if the orchestrator has one. `faults` are
+    /// those the orchestrator injects, and `maintenance` its cordon. `addresses` and `pool` are
+    /// the addresses leased to the guests and the pools of the orchestrator, as dumped.
+    pub fn new(
+        vms: VmTable,
+        janitor: Arc<Janitor>,
+        reloader: Option<Arc<ConfigReloader>>,
+        faults: Arc<Faults>,
+        maintenance: Arc<Maintenance>,
+        addresses: GuestAddresses,
+        pool: Arc<FunctionPool>,
+    ) -> Self {
+        Self {
+            vms,
+            janitor,
+            reloader,
+            faults,
+            maintenance,
+            addresses,
+            pool,
+        }
+    }
+
+    fn node_status(&self, killed_vms: Vec<String>) -> NodeStatus {
+        let cordon = self.maintenance.cordoned();
+        NodeStatus {
+            cordoned: cordon.is_some(),
+            reason: cordon
+                .as_ref()
+                .map(|cordon| cordon.reason.clone())
+                .unwrap_or_default(),
+            cordoned_at: cordon.map_or(0, |cordon| cordon.since),
+            running_vms: self
+                .vms
+                .list()
+                .into_iter()
+                .map(|vm| vm.id)
+                // Not yet stopped, the killed VMs are only listed as such.
+                .filter(|id| !killed_vms.contains(id))
+                .collect(),
+            killed_vms,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AdminServiceTrait for AdminService {
+    async fn list_vms(
+        &self,
+        request: Request<ListVmsRequest>,
+    ) -> Result<Response<ListVmsResponse>, Status> {
+        let selector = LabelSelector::from_str(&request.into_inner().selector)
+            .map_err(|e| ErrorCode::VmmInvalidLabels.status(Code::InvalidArgument, e))?;
+        Ok(Response::new(ListVmsResponse {
+            vms: self
+                .vms
+                .list()
+                .into_iter()
+                .filter(|vm| selector.matches(&vm.labels))
+                .collect(),
+        }))
+    }
+
+    async fn prune_artifacts(
+        &self,
+        request: Request<PruneArtifactsRequest>,
+    ) -> Result<Response<PruneArtifactsResponse>, Status> {
+        let all = request.into_inner().all;
+        let janitor = self.janitor.clone();
+        let report = tokio::task::spawn_blocking(move || janitor.prune(all))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let stats = self.janitor.stats();
+        Ok(Response::new(PruneArtifactsResponse {
+            pruned: report
+                .pruned
+                .into_iter()
+                .map(|(path, size)| PrunedArtifact {
+                    path: path.to_string_lossy().into_owned(),
+                    size,
+                })
+                .collect(),
+            freed_bytes: report.freed_bytes,
+            disk_usage: report.disk_usage.unwrap_or_default(),
+            total_runs: stats.runs.load(Ordering::Relaxed),
+            total_pruned: stats.pruned.load(Ordering::Relaxed),
+            total_freed_bytes: stats.freed_bytes.load(Ordering::Relaxed),
+        }))
+    }
+
+    async fn dump_vm_debug_info(
+        &self,
+        request: Request<DumpVmDebugInfoRequest>,
+    ) -> Result<Response<VmDebugInfo>, Status> {
+        let vm = request.into_inner().vm;
+        let (info, stats) = self.vms.resolve_with_stats(&vm).ok_or_else(|| {
+            ErrorCode::VmmUnknownVm.status(
+                Code::NotFound,
+                format!("No VM is running with the id or workload name {}", vm),
+            )
+        })?;
+
+        Ok(Response::new(VmDebugInfo {
+            vm: Some(info),
+            counters_enabled: stats::ENABLED,
+            vcpus: stats
+                .vcpus()
+                .iter()
+                .map(|vcpu| VcpuDebugInfo {
+                    index: vcpu.index,
+                    io_in_exits: vcpu.io_in_exits.get(),
+                    io_out_exits: vcpu.io_out_exits.get(),
+                    mmio_read_exits: vcpu.mmio_read_exits.get(),
+                    mmio_write_exits: vcpu.mmio_write_exits.get(),
+                    other_exits: vcpu.other_exits.get(),
+                    errors: vcpu.errors.get(),
+                    last_exit_address: vcpu.last_exit_address.get(),
+                })
+                .collect(),
+            devices: stats
+                .devices()
+                .iter()
+                .map(|device| DeviceDebugInfo {
+                    name: device.name.to_string(),
+                    queue_notifications: device.queue_notifications.get(),
+                    interrupts: device.interrupts.get(),
+                    backend_events: device.backend_events.get(),
+                    errors: device.errors.get(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn reload_config(
+        &self,
+        _request: Request<ReloadConfigRequest>,
+    ) -> Result<Response<ReloadConfigResponse>, Status> {
+        let reloader = self.reloader.clone().ok_or_else(|| {
+            ErrorCode::VmmConfigReload.status(
+                Code::FailedPrecondition,
+                "The orchestrator was started without a configuration file",
+            )
+        })?;
+        let report = tokio::task::spawn_blocking(move || reloader.reload())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| ErrorCode::VmmConfigReload.status(Code::InvalidArgument, e.to_string()))?;
+
+        Ok(Response::new(ReloadConfigResponse {
+            applied: report.applied,
+            rejected: report.rejected,
+        }))
+    }
+
+    async fn inject_faults(
+        &self,
+        request: Request<InjectFaultsRequest>,
+    ) -> Result<Response<InjectFaultsResponse>, Status> {
+        if !faults::ENABLED {
+            return Err(ErrorCode::VmmFaultInjection.status(
+                Code::Unimplemented,
+                "The orchestrator was built without the fault-injection feature",
+            ));
+        }
+        let request = request.into_inner();
+        for percent in [
+            request.drop_agent_connections_percent,
+            request.fail_downloads_percent,
+        ] {
+            if percent > 100 {
+                return Err(ErrorCode::VmmFaultInjection.status(
+                    Code::InvalidArgument,
+                    format!("{} is not a percentage", percent),
+                ));
+            }
+        }
+
+        let settings = FaultSettings {
+            drop_agent_connections_percent: request.drop_agent_connections_percent,
+            boot_delay: Duration::from_millis(request.boot_delay_ms.into()),
+            fail_downloads_percent: request.fail_downloads_percent,
+        };
+        warn!(settings = ?settings, "Injecting faults");
+        self.faults.set(settings);
+
+        let mut vms = self.vms.list();
+        let mut killed_vms = Vec::new();
+        while killed_vms.len() < request.kill_vms as usize && !vms.is_empty() {
+            let vm = vms.swap_remove(self.faults.pick(vms.len()));
+            let Ok(guest_ip) = vm.guest_ip.parse::<Ipv4Addr>() else {
+                continue;
+            };
+            warn!(vm_id = %vm.id, "Injected fault: killing the VM");
+
+            let vm_id = vm.id.clone();
+            let port = agent_port(&vm);
+            tokio::spawn(async move {
+                let shutdown = match WorkloadClient::new(guest_ip, port).await {
+                    Ok(mut client) => client
+                        .shutdown(ShutdownVmRequest { vm: vm_id.clone() })
+                        .await
+                        .map(|_| ())
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(e) = shutdown {
+                    warn!(vm_id = %vm_id, error = %e, "Could not kill the VM");
+                }
+            });
+            killed_vms.push(vm.id);
+        }
+
+        Ok(Response::new(InjectFaultsResponse { killed_vms }))
+    }
+
+    async fn cordon_node(
+        &self,
+        request: Request<CordonNodeRequest>,
+    ) -> Result<Response<NodeStatus>, Status> {
+        self.maintenance.cordon(&request.into_inner().reason);
+        Ok(Response::new(self.node_status(Vec::new())))
+    }
+
+    async fn uncordon_node(
+        &self,
+        _request: Request<UncordonNodeRequest>,
+    ) -> Result<Response<NodeStatus>, Status> {
+        self.maintenance.uncordon();
+        Ok(Response::new(self.node_status(Vec::new())))
+    }
+
+    async fn drain_node(
+        &self,
+        request: Request<DrainNodeRequest>,
+    ) -> Result<Response<NodeStatus>, Status> {
+        let request = request.into_inner();
+        let timeout =
+            (request.timeout_secs > 0).then(|| Duration::from_secs(request.timeout_secs.into()));
+        let drained = self
+            .maintenance
+            .drain(&self.vms, &request.reason, timeout, request.kill)
+            .await;
+        Ok(Response::new(self.node_status(drained.killed)))
+    }
+
+    async fn dump_state(
+        &self,
+        _request: Request<DumpStateRequest>,
+    ) -> Result<Response<StateDump>, Status> {
+        Ok(Response::new(StateDump {
+            address_leases: self
+                .addresses
+                .leases()
+                .into_iter()
+                .map(|(bridge, address)| AddressLease {
+                    bridge,
+                    address: address.to_string(),
+                })
+                .collect(),
+            pools: self.pool.dump(),
+        }))
+    }
+}
@@ -0,0 +1,334 @@
+//! Admission control of the run requests, before any VM is created for them.
+//!
+//! Requests are checked against the rules of a TOML policy file, then sent to an optional
+//! webhook. Both can reject a request, or change it: lower its resources, add variables to
+//! its environment.
+//!
+//! ```toml
+//! [rules]
+//! max-cpus = 4
+//! max-memory-mb = 8192
+//! clamp-resources = true      # lower oversized requests instead of rejecting them
+//! languages = ["python", "node"]
+//! kernels = ["builtin"]
+//! devices = []                # host devices the requests may ask for, e.g. "gpu"
+//! capabilities = ["artifact-output"] # capabilities the requests may need, all if unset
+//! require-image-digest = true # only run pinned rootfs images
+//! env = { TZ = "UTC" }        # added to the environment, unless the request sets them
+//!
+//! # Rules of the workloads named `batch-...`, instead of the ones above.
+//! [[tenant]]
+//! prefix = "batch-"
+//! max-memory-mb = 32768
+//!
+//! [webhook]
+//! url = "http://policy.internal/admit"
+//! timeout-ms = 2000
+//! fail-open = false           # admit the requests when the webhook can't be reached
+//! ```
+//!
+//! The webhook receives the request as JSON (see [`WebhookRequest`]), secret values excepted,
+//! and answers `{"allowed": true}`, or `{"allowed": false, "reason": "..."}`, optionally with
+//! `cpus`, `memory_mb` and `env` to change the request.
+//!
+//! The capabilities are negotiated rather than denied: a request needing one the rules don't
+//! allow fails with `FAILED_PRECONDITION` along with those the host lacks, see
+//! [`AdmissionPolicy::allows_capability`].
+
+use serde::{Deserialize, Serialize};
+use shared_models::{
+    redact_env,
+    vmmorchestrator::{Capability, Device, RunVmmRequest},
+    ErrorCode, Language, REDACTED,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tonic::{Code, Status};
+use tracing::{info, warn};
+
+#[derive(Debug)]
+pub enum AdmissionError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for AdmissionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdmissionError::Read(path, e) => write!(f, "Failed to read {:?}: {}", path, e),
+            AdmissionError::Parse(path, e) => {
+                write!(f, "Invalid admission policy {:?}: {}", path, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AdmissionError {}
+
+/// Rules applied to the requests, all optional.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Rules {
+    pub max_cpus: Option<u32>,
+    pub max_memory_mb: Option<u32>,
+    #[serde(default)]
+    pub clamp_resources: bool,
+    pub languages: Option<Vec<String>>,
+    pub kernels: Option<Vec<String>>,
+    pub devices: Option<Vec<String>>,
+    pub capabilities: Option<Vec<String>>,
+    #[serde(default)]
+    pub require_image_digest: bool,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TenantRules {
+    prefix: String,
+    #[serde(flatten)]
+    rules: Rules,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Webhook {
+    url: String,
+    #[serde(default = "default_webhook_timeout_ms")]
+    timeout_ms: u64,
+    #[serde(default)]
+    fail_open: bool,
+}
+
+fn default_webhook_timeout_ms() -> u64 {
+    2000
+}
+
+/// Policy the requests must comply with.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AdmissionPolicy {
+    #[serde(default)]
+    rules: Rules,
+    #[serde(default, rename = "tenant")]
+    tenants: Vec<TenantRules>,
+    webhook: Option<Webhook>,
+}
+
+/// What the webhook is sent.
+#[derive(Debug, Serialize)]
+pub struct WebhookRequest {
+    pub workload_name: String,
+    pub language: String,
+    pub cpus: u32,
+    pub memory_mb: u32,
+    pub kernel: String,
+    pub devices: Vec<String>,
+    pub capabilities: Vec<String>,
+    /// Secret values are replaced with `[REDACTED]`.
+    pub env: HashMap<String, String>,
+    pub image_digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookResponse {
+    allowed: bool,
+    #[serde(default)]
+    reason: String,
+    cpus: Option<u32>,
+    memory_mb: Option<u32>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+fn denied(message: impl Into<String>) -> Status {
+    ErrorCode::VmmAdmissionDenied.status(Code::PermissionDenied, message)
+}
+
+fn device_name(device: Device) -> String {
+    device.as_str_name().to_ascii_lowercase()
+}
+
+/// Name of `capability` in the policies and the errors, e.g. `network-egress`.
+pub fn capability_name(capability: Capability) -> String {
+    capability
+        .as_str_name()
+        .to_ascii_lowercase()
+        .replace('_', "-")
+}
+
+impl AdmissionPolicy {
+    pub fn load(path: &Path) -> Result<Self, AdmissionError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| AdmissionError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&content).map_err(|e| AdmissionError::Parse(path.to_path_buf(), e))
+    }
+
+    /// The policy, with the values it adds to the environments redacted.
+    pub fn redacted(&self) -> Self {
+        let mut policy = self.clone();
+        for rules in std::iter::once(&mut policy.rules)
+            .chain(policy.tenants.iter_mut().map(|tenant| &mut tenant.rules))
+        {
+            for value in rules.env.values_mut() {
+                *value = REDACTED.to_string();
+            }
+        }
+        policy
+    }
+
+    /// Every set of rules of the policy, with the workload name prefix it applies to, `None`
+    /// for the default rules.
+    pub fn rules(&self) -> Vec<(Option<&str>, &Rules)> {
+        std::iter::once((None, &self.rules))
+            .chain(
+                self.tenants
+                    .iter()
+                    .map(|tenant| (Some(tenant.prefix.as_str()), &tenant.rules)),
+            )
+            .collect()
+    }
+
+    /// Rules of the workload `workload_name`: those of the tenant with the longest matching
+    /// prefix, the default ones otherwise.
+    fn rules_for(&self, workload_name: &str) -> &Rules {
+        self.tenants
+            .iter()
+            .filter(|tenant| workload_name.starts_with(&tenant.prefix))
+            .max_by_key(|tenant| tenant.prefix.len())
+            .map(|tenant| &tenant.rules)
+            .unwrap_or(&self.rules)
+    }
+
+    /// Whether the rules of the workload `workload_name` let it use `capability`.
+    pub fn allows_capability(&self, workload_name: &str, capability: Capability) -> bool {
+        self.rules_for(workload_name)
+            .capabilities
+            .as_ref()
+            .map_or(true, |allowed| {
+                allowed.contains(&capability_name(capability))
+            })
+    }
+
+    /// Admit `request`, whose guest gets `cpus` and `memory_mb`, changing it if the policy
+    /// says so. The request is rejected with a `PermissionDenied` status.
+    pub async fn admit(
+        &self,
+        request: &mut RunVmmRequest,
+        cpus: u32,
+        memory_mb: u32,
+    ) -> Result<(), Status> {
+        let rules = self.rules_for(&request.workload_name);
+        let language = Language::try_from(request.language)
+            .map(|language| language.as_str().to_string())
+            .unwrap_or_default();
+        let (mut cpus, mut memory_mb) = (cpus, memory_mb);
+
+        if let Some(max_cpus) = rules.max_cpus.filter(|max| cpus > *max) {
+            if !rules.clamp_resources {
+                return Err(denied(format!(
+                    "{} vCPUs requested, the policy allows {}",
+                    cpus, max_cpus
+                )));
+            }
+            cpus = max_cpus;
+        }
+        if let Some(max_memory_mb) = rules.max_memory_mb.filter(|max| memory_mb > *max) {
+            if !rules.clamp_resources {
+                return Err(denied(format!(
+                    "{} MB of memory requested, the policy allows {}",
+                    memory_mb, max_memory_mb
+                )));
+            }
+            memory_mb = max_memory_mb;
+        }
+        if let Some(languages) = &rules.languages {
+            if !languages.contains(&language) {
+                return Err(denied(format!("Language {} is not allowed", language)));
+            }
+        }
+        if let Some(kernels) = &rules.kernels {
+            // The default kernel is always allowed.
+            if !request.kernel.is_empty() && !kernels.contains(&request.kernel) {
+                return Err(denied(format!("Kernel {} is not allowed", request.kernel)));
+            }
+        }
+        if let Some(devices) = &rules.devices {
+            if let Some(device) = request
+                .devices()
+                .map(device_name)
+                .find(|device| !devices.contains(device))
+            {
+                return Err(denied(format!("Device {} is not allowed", device)));
+            }
+        }
+        if rules.require_image_digest && request.image_digest.is_empty() {
+            return Err(denied("The policy only runs pinned rootfs images"));
+        }
+        for (name, value) in &rules.env {
+            request
+                .env
+                .entry(name.clone())
+                .or_insert_with(|| value.clone());
+        }
+
+        if let Some(webhook) = &self.webhook {
+            let mut env = request.env.clone();
+            redact_env(env.iter_mut(), &request.secret_env);
+            let body = WebhookRequest {
+                workload_name: request.workload_name.clone(),
+                language,
+                cpus,
+                memory_mb,
+                kernel: request.kernel.clone(),
+                devices: request.devices().map(device_name).collect(),
+                capabilities: request.capabilities().map(capability_name).collect(),
+                env,
+                image_digest: request.image_digest.clone(),
+            };
+
+            match call_webhook(webhook, &body).await {
+                Ok(response) if !response.allowed => {
+                    info!(workload = %request.workload_name, reason = %response.reason, "Request denied by the admission webhook");
+                    return Err(denied(format!("Denied by the policy: {}", response.reason)));
+                }
+                Ok(response) => {
+                    cpus = response.cpus.unwrap_or(cpus);
+                    memory_mb = response.memory_mb.unwrap_or(memory_mb);
+                    request.env.extend(response.env);
+                }
+                Err(e) if webhook.fail_open => {
+                    warn!(error = %e, "Admission webhook failed, admitting the request")
+                }
+                Err(e) => {
+                    return Err(ErrorCode::VmmAdmissionDenied.status(
+                        Code::Unavailable,
+                        format!("Admission webhook failed: {}", e),
+                    ))
+                }
+            }
+        }
+
+        request.cpus = cpus;
+        request.memory_mb = memory_mb;
+        Ok(())
+    }
+}
+
+async fn call_webhook(
+    webhook: &Webhook,
+    body: &WebhookRequest,
+) -> Result<WebhookResponse, reqwest::Error> {
+    reqwest::Client::new()
+        .post(&webhook.url)
+        .timeout(Duration::from_millis(webhook.timeout_ms))
+        .json(body)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
@@ -0,0 +1,92 @@
+//! Node affinity of the runs: the labels of the node a run must, or would rather, run on, e.g.
+//! to target the GPU nodes or to keep the runs of a tenant next to its persistent volumes.
+//!
+//! The orchestrator is a node labelled with `--node-label key=value`, the labels it gives in
+//! `GetServerInfo`. A run whose `required` selector doesn't match them is refused before its VM
+//! is provisioned, with `CLDT-VMM-036`. The `preferred` selectors rank the nodes which can run
+//! it: an orchestrator being a single node, the run stays on it whatever they match, and those
+//! it misses are logged, until a scheduler spreads the runs over several orchestrators.
+
+use shared_models::{labels::LabelSelector, vmmorchestrator::NodeAffinity, ErrorCode};
+use std::collections::HashMap;
+use tonic::{Code, Status};
+
+/// Check that the node labelled `labels` can run a run of `affinity`, returning the preferred
+/// selectors it doesn't match.
+pub fn check_affinity(
+    affinity: &NodeAffinity,
+    labels: &HashMap<String, String>,
+) -> Result<Vec<LabelSelector>, Status> {
+    let parse = |selector: &str| {
+        selector.parse::<LabelSelector>().map_err(|e| {
+            ErrorCode::VmmInvalidLabels.status(
+                Code::InvalidArgument,
+                format!("Invalid node affinity: {}", e),
+            )
+        })
+    };
+    let required = parse(&affinity.required)?;
+    let preferred = affinity
+        .preferred
+        .iter()
+        .map(|selector| parse(selector))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if !required.matches(labels) {
+        return Err(ErrorCode::VmmNodeMismatch.status(
+            Code::FailedPrecondition,
+            format!(
+                "The node doesn't match the required selector `{}` of the run, its labels: {}",
+                required,
+                describe(labels)
+            ),
+        ));
+    }
+    Ok(preferred
+        .into_iter()
+        .filter(|selector| !selector.matches(labels))
+        .collect())
+}
+
+/// `key=value` labels separated by commas, sorted, `none` if there are none.
+pub fn describe(labels: &HashMap<String, String>) -> String {
+    let mut labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    if labels.is_empty() {
+        return "none".to_string();
+    }
+    labels.sort();
+    labels.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_affinity() {
+        let labels = HashMap::from([
+            ("gpu".to_string(), "a100".to_string()),
+            ("zone".to_string(), "eu-1".to_string()),
+        ]);
+        let affinity = |required: &str, preferred: &[&str]| NodeAffinity {
+            required: required.to_string(),
+            preferred: preferred.iter().map(|s| s.to_string()).collect(),
+        };
+
+        // Any node runs the runs without affinity.
+        assert!(check_affinity(&NodeAffinity::default(), &HashMap::new())
+            .unwrap()
+            .is_empty());
+        let missed = check_affinity(&affinity("gpu=a100", &["zone=eu-1", "volume"]), &labels);
+        assert_eq!(missed.unwrap(), vec!["volume".parse().unwrap()]);
+
+        let status = check_affinity(&affinity("gpu,zone!=eu-1", &[]), &labels).unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert!(status.message().contains("gpu=a100,zone=eu-1"));
+        let status = check_affinity(&affinity("", &["a=b=c"]), &labels).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,121 @@
+//! Crash-safe creation of the kernel and initramfs artifacts.
+//!
+//! An artifact is built into a temporary file next to it, then renamed once complete, so an
+//! artifact that exists is never a partial one left by a crash. Builds of the same artifact are
+//! serialized with a lock file, so concurrent orchestrators don't build it twice or overwrite it
+//! while another one boots from it.
+
+use nix::fcntl::{Flock, FlockArg};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+/// Suffix of the temporary files, followed by the id of the building process.
+const TMP_SUFFIX: &str = ".tmp-";
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Path of the lock file of the artifact at `path`.
+pub fn lock_path(path: &Path) -> PathBuf {
+    sibling(path, ".lock")
+}
+
+/// Whether `path` is a temporary file or a lock file of an artifact.
+pub fn is_build_file(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(".lock") || name.contains(TMP_SUFFIX)
+}
+
+/// Remove the temporary files left by builds of `path` which didn't complete.
+/// Must be called with the lock of the artifact held.
+fn remove_stale_files(path: &Path) {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    let mut prefix = name.to_os_string();
+    prefix.push(TMP_SUFFIX);
+    let prefix = prefix.to_string_lossy().into_owned();
+
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            warn!(path = ?entry.path(), "Removing the leftovers of an interrupted build");
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Make sure the artifact at `path` exists, building it with `build` if it doesn't.
+///
+/// `build` writes the artifact to the path it's given, which is renamed to `path` once the
+/// build succeeded. Returns whether the artifact was built.
+pub fn ensure<F>(path: &Path, build: F) -> io::Result<bool>
+where
+    F: FnOnce(&Path) -> io::Result<()>,
+{
+    ensure_current(path, |_| true, build)
+}
+
+/// Like [`ensure`], but also rebuilds the artifact when `is_current` tells it's outdated.
+pub fn ensure_current<C, F>(path: &Path, is_current: C, build: F) -> io::Result<bool>
+where
+    C: Fn(&Path) -> bool,
+    F: FnOnce(&Path) -> io::Result<()>,
+{
+    if path.try_exists()? && is_current(path) {
+        return Ok(false);
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let lock_file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(path))?;
+    info!(path = ?path, "Waiting for the artifact lock");
+    let _lock = Flock::lock(lock_file, FlockArg::LockExclusive)
+        .map_err(|(_, errno)| io::Error::from(errno))?;
+
+    // Another orchestrator may have built it while we were waiting.
+    if path.try_exists()? && is_current(path) {
+        return Ok(false);
+    }
+    remove_stale_files(path);
+
+    let tmp_path = sibling(path, &format!("{}{}", TMP_SUFFIX, std::process::id()));
+    let result = build(&tmp_path).and_then(|()| {
+        let file = File::open(&tmp_path)?;
+        if file.metadata()?.len() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the build of {:?} produced an empty file", path),
+            ));
+        }
+        file.sync_all()
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        // Persist the rename itself.
+        File::open(dir)?.sync_all()?;
+    }
+    info!(path = ?path, "Artifact built");
+
+    Ok(true)
+}
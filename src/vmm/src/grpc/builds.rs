@@ -0,0 +1,128 @@
+//! Workloads built by the agents, cached so that the later runs of the same inputs skip
+//! their build, e.g. a `cli build` followed by `cli run`.
+//!
+//! A build is identified by the digest of everything it depends on: the language and its
+//! runtime version, the code and the build options, and by its tenant, whose runs only reuse
+//! the builds of their own. It is kept in the object `<hex digest>.bin` of a storage.
+//!
+//! The CPU template of the guests is part of the digest too, unless it's the host CPUID: a
+//! build tuned to the CPU it ran on isn't reused on another, while the builds of the hosts
+//! sharing a template are.
+
+use crate::core::cpu_template::CpuTemplate;
+use crate::grpc::metering;
+use crate::grpc::storage::{Storage, StorageError};
+use sha2::{Digest, Sha256};
+use shared_models::vmmorchestrator::RunVmmRequest;
+use std::sync::Arc;
+
+/// Where the built workloads are cached.
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+    storage: Arc<dyn Storage>,
+}
+
+impl BuildCache {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    fn key(build_id: &str) -> String {
+        format!("{}.bin", build_id.trim_start_matches("sha256:"))
+    }
+
+    /// Workload built as `build_id`, `None` if it isn't cached.
+    pub fn get(&self, build_id: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        self.storage.read(&Self::key(build_id))
+    }
+
+    /// Whether a workload built as `build_id` is cached.
+    pub fn contains(&self, build_id: &str) -> Result<bool, StorageError> {
+        self.get(build_id).map(|artifact| artifact.is_some())
+    }
+
+    pub fn put(&self, build_id: &str, artifact: &[u8]) -> Result<(), StorageError> {
+        self.storage.write(&Self::key(build_id), artifact)
+    }
+}
+
+/// Id of the build of the workload of `request` in guests of `cpu_template`, `sha256:...`.
+pub fn build_id(request: &RunVmmRequest, cpu_template: &CpuTemplate) -> String {
+    let build = request.build.clone().unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    hasher.update(metering::tenant(&request.tenant));
+    hasher.update("\0");
+    hasher.update(request.language().as_str_name());
+    hasher.update("\0");
+    hasher.update(&build.runtime_version);
+    hasher.update("\0");
+    hasher.update(if build.release { "release" } else { "debug" });
+    for feature in &build.features {
+        hasher.update("\0feature=");
+        hasher.update(feature);
+    }
+    for flag in &build.compiler_flags {
+        hasher.update("\0flag=");
+        hasher.update(flag);
+    }
+    if let Some(fingerprint) = cpu_template.fingerprint() {
+        hasher.update("\0cpu-template=");
+        hasher.update(fingerprint);
+    }
+    hasher.update("\0\0");
+    hasher.update(&request.code);
+    format!("sha256:{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_models::cloudlet::agent::BuildConfig;
+
+    fn build_id(request: &RunVmmRequest) -> String {
+        super::build_id(request, &CpuTemplate::Host)
+    }
+
+    #[test]
+    fn test_build_id() {
+        let request = RunVmmRequest {
+            code: "fn main() {}".into(),
+            build: Some(BuildConfig {
+                features: vec!["a".into()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(build_id(&request), build_id(&request.clone()));
+
+        // The environment and the resources of the guest don't change the build.
+        let mut other = request.clone();
+        other.env.insert("NAME".into(), "value".into());
+        other.memory_mb = 512;
+        assert_eq!(build_id(&request), build_id(&other));
+
+        // The tenants don't share their builds.
+        let mut other = request.clone();
+        other.tenant = "other".into();
+        assert_ne!(build_id(&request), build_id(&other));
+        other.tenant = metering::DEFAULT_TENANT.into();
+        assert_eq!(build_id(&request), build_id(&other));
+
+        let mut other = request.clone();
+        other.build.as_mut().unwrap().release = true;
+        assert_ne!(build_id(&request), build_id(&other));
+
+        // A feature named like a flag is another build.
+        let mut other = request.clone();
+        let build = other.build.as_mut().unwrap();
+        build.features.clear();
+        build.compiler_flags.push("a".into());
+        assert_ne!(build_id(&request), build_id(&other));
+
+        // Nor are the builds of another CPU template.
+        let baseline = super::build_id(&request, &CpuTemplate::Baseline);
+        assert_ne!(build_id(&request), baseline);
+        assert_eq!(baseline, super::build_id(&request, &CpuTemplate::Baseline));
+    }
+}
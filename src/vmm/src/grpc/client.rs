@@ -1,25 +1,111 @@
-use self::agent::{workload_runner_client::WorkloadRunnerClient, ExecuteRequest, SignalRequest};
-use super::server::vmmorchestrator::{ShutdownVmRequest, ShutdownVmResponse};
-use log::error;
+use log::{error, info, warn};
+use shared_models::cloudlet::agent::{
+    self, workload_runner_client::WorkloadRunnerClient, ExecuteRequest, HelloRequest,
+    ListDirRequest, ListDirResponse, ReadFileRequest, ReadFileResponse, ShellInput, ShellOutput,
+    SignalRequest,
+};
+use shared_models::vmmorchestrator::{ShutdownVmRequest, ShutdownVmResponse};
+use shared_models::{
+    agent_capabilities, compression, ErrorCode, AGENT_MAX_MESSAGE_SIZE, AGENT_PROTOCOL_VERSION,
+    MIN_AGENT_PROTOCOL_VERSION,
+};
 use std::{error::Error, net::Ipv4Addr, time::Duration};
-use tonic::{transport::Channel, Streaming};
+use tokio_stream::Stream;
+use tonic::{transport::Channel, Code, Status, Streaming};
 
-pub mod agent {
-    tonic::include_proto!("cloudlet.agent");
+/// What an agent told of itself in its answer to `Hello`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentInfo {
+    pub protocol_version: u32,
+    pub version: String,
+    pub capabilities: Vec<String>,
+}
+
+impl AgentInfo {
+    /// Agent which predates `Hello`.
+    fn legacy() -> Self {
+        Self {
+            protocol_version: 1,
+            version: "unknown".to_string(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Check that the orchestrator and an agent speaking the versions `protocol_version` down
+    /// to `min_protocol_version` of the protocol have one in common.
+    fn compatible(protocol_version: u32, min_protocol_version: u32) -> Result<(), String> {
+        if protocol_version < MIN_AGENT_PROTOCOL_VERSION {
+            return Err(format!(
+                "The agent of the guest speaks version {} of the protocol, the orchestrator version {} at least",
+                protocol_version, MIN_AGENT_PROTOCOL_VERSION
+            ));
+        }
+        if min_protocol_version > AGENT_PROTOCOL_VERSION {
+            return Err(format!(
+                "The agent of the guest speaks version {} of the protocol at least, the orchestrator version {} at most",
+                min_protocol_version, AGENT_PROTOCOL_VERSION
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Refuse what the agent doesn't support, rather than letting it misread the request.
+    pub fn require(&self, capability: &str, feature: &str) -> Result<(), Status> {
+        if self.supports(capability) {
+            return Ok(());
+        }
+        Err(ErrorCode::VmmAgentIncompatible.status(
+            Code::FailedPrecondition,
+            format!(
+                "The agent of the guest (version {}) doesn't support {}",
+                self.version, feature
+            ),
+        ))
+    }
+
+    /// Check that the agent supports what `request` needs.
+    fn check(&self, request: &ExecuteRequest) -> Result<(), Status> {
+        if !request.stdin.is_empty() {
+            self.require(
+                agent_capabilities::STDIN,
+                "the standard input of the workloads",
+            )?;
+        }
+        if !request.inputs.is_empty() || !request.outputs.is_empty() {
+            self.require(
+                agent_capabilities::PIPELINE_FILES,
+                "the inputs and the outputs of the steps of a pipeline",
+            )?;
+        }
+        if request.scrub {
+            self.require(
+                agent_capabilities::SCRUB,
+                "the scrubbing of the guests reused from a pool",
+            )?;
+        }
+        Ok(())
+    }
 }
 
 pub struct WorkloadClient {
+    /// Sending uncompressed requests, and accepting compressed responses.
     client: WorkloadRunnerClient<Channel>,
+    /// Whether the requests are compressed, until the agent refuses one.
+    compress_requests: bool,
+    /// What the agent told of itself, once asked.
+    agent: Option<AgentInfo>,
 }
 
 impl WorkloadClient {
     pub async fn new(ip: Ipv4Addr, port: u16) -> Result<Self, tonic::transport::Error> {
         let delay = Duration::from_secs(2); // Setting initial delay to 2 seconds
         loop {
-            match WorkloadRunnerClient::connect(format!("http://[{}]:{}", ip, port)).await {
-                Ok(client) => {
-                    return Ok(WorkloadClient { client });
-                }
+            match Self::connect(ip, port).await {
+                Ok(client) => return Ok(client),
                 Err(err) => {
                     error!("Failed to connect to Agent service: {}", err);
                     error!("Retrying in {:?}...", delay);
@@ -29,14 +115,138 @@ impl WorkloadClient {
         }
     }
 
+    /// Connect to the agent of a guest booting, retrying until `timeout`: past it, the agent
+    /// which never came up fails the run on an infrastructure error, retried on a fresh VM.
+    pub async fn connect_within(
+        ip: Ipv4Addr,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<Self, Status> {
+        match tokio::time::timeout(timeout, Self::new(ip, port)).await {
+            Ok(Ok(client)) => Ok(client),
+            Ok(Err(e)) => Err(ErrorCode::VmmAgentUnreachable.status(
+                Code::Unavailable,
+                format!("Could not connect to the agent: {:?}", e),
+            )),
+            Err(_) => Err(ErrorCode::VmmAgentUnreachable.status(
+                Code::Unavailable,
+                format!("The agent didn't come up within {:?}", timeout),
+            )),
+        }
+    }
+
+    /// Connect to the agent of a guest already up, without retrying.
+    pub async fn connect(ip: Ipv4Addr, port: u16) -> Result<Self, tonic::transport::Error> {
+        let client = WorkloadRunnerClient::connect(format!("http://[{}]:{}", ip, port))
+            .await?
+            .max_decoding_message_size(AGENT_MAX_MESSAGE_SIZE)
+            .max_encoding_message_size(AGENT_MAX_MESSAGE_SIZE)
+            .accept_compressed(compression::CompressionEncoding::Zstd)
+            .accept_compressed(compression::CompressionEncoding::Gzip);
+        Ok(WorkloadClient {
+            client,
+            compress_requests: true,
+            agent: None,
+        })
+    }
+
+    /// Exchange the versions of the protocol with the agent, on the first call, refusing an
+    /// agent with which the orchestrator has none in common.
+    pub async fn handshake(&mut self) -> Result<AgentInfo, Status> {
+        if let Some(agent) = &self.agent {
+            return Ok(agent.clone());
+        }
+
+        let request = HelloRequest {
+            protocol_version: AGENT_PROTOCOL_VERSION,
+            min_protocol_version: MIN_AGENT_PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+        let agent = match self.client.hello(request).await {
+            Ok(response) => {
+                let response = response.into_inner();
+                AgentInfo::compatible(response.protocol_version, response.min_protocol_version)
+                    .map_err(|e| {
+                        ErrorCode::VmmAgentIncompatible.status(Code::FailedPrecondition, e)
+                    })?;
+                if !response
+                    .capabilities
+                    .iter()
+                    .any(|c| c == agent_capabilities::COMPRESSION)
+                {
+                    self.compress_requests = false;
+                }
+                AgentInfo {
+                    protocol_version: response.protocol_version,
+                    version: response.version,
+                    capabilities: response.capabilities,
+                }
+            }
+            Err(status) if status.code() == Code::Unimplemented => {
+                warn!("The agent predates the protocol versions, only serving what it always did");
+                AgentInfo::legacy()
+            }
+            Err(status) => return Err(status),
+        };
+        info!(
+            "Agent {} speaks version {} of the protocol, with {:?}",
+            agent.version, agent.protocol_version, agent.capabilities
+        );
+        self.agent = Some(agent.clone());
+        Ok(agent)
+    }
+
+    pub async fn list_dir(&mut self, path: String) -> Result<ListDirResponse, tonic::Status> {
+        Ok(self
+            .client
+            .list_dir(ListDirRequest { path })
+            .await?
+            .into_inner())
+    }
+
+    pub async fn read_file(
+        &mut self,
+        path: String,
+        max_bytes: u64,
+    ) -> Result<ReadFileResponse, tonic::Status> {
+        Ok(self
+            .client
+            .read_file(ReadFileRequest { path, max_bytes })
+            .await?
+            .into_inner())
+    }
+
+    pub async fn exec_shell(
+        &mut self,
+        inputs: impl Stream<Item = ShellInput> + Send + 'static,
+    ) -> Result<Streaming<ShellOutput>, tonic::Status> {
+        self.handshake()
+            .await?
+            .require(agent_capabilities::EXEC_SHELL, "shells")?;
+        Ok(self.client.exec_shell(inputs).await?.into_inner())
+    }
+
     pub async fn execute(
         &mut self,
         request: ExecuteRequest,
     ) -> Result<Streaming<agent::ExecuteResponse>, tonic::Status> {
-        let request = tonic::Request::new(request);
-        let response_stream = self.client.execute(request).await?.into_inner();
+        self.handshake().await?.check(&request)?;
+        let mut client = self.client.clone();
+        if let Some(encoding) = compression::configured().filter(|_| self.compress_requests) {
+            client = client.send_compressed(encoding);
+        }
+        let response = match client.execute(request.clone()).await {
+            Err(status)
+                if self.compress_requests && compression::is_unsupported_encoding(&status) =>
+            {
+                warn!("The agent doesn't accept compressed requests, sending them uncompressed");
+                self.compress_requests = false;
+                self.client.execute(request).await
+            }
+            response => response,
+        };
 
-        Ok(response_stream)
+        Ok(response?.into_inner())
     }
 
     pub async fn shutdown(
@@ -58,3 +268,58 @@ impl WorkloadClient {
         Ok(ShutdownVmResponse { success: false })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::retries::is_infra_failure;
+
+    #[tokio::test]
+    async fn test_agent_connect_timeout() {
+        // A port nothing listens on, the connections being refused like by a guest still
+        // booting.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let timeout = Duration::from_millis(200);
+        let status = WorkloadClient::connect_within(Ipv4Addr::LOCALHOST, port, timeout)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            ErrorCode::from_status(&status),
+            Some(ErrorCode::VmmAgentUnreachable)
+        );
+        assert!(is_infra_failure(&status));
+    }
+
+    #[test]
+    fn test_agent_compatibility() {
+        assert!(AgentInfo::compatible(AGENT_PROTOCOL_VERSION, MIN_AGENT_PROTOCOL_VERSION).is_ok());
+        assert!(AgentInfo::compatible(MIN_AGENT_PROTOCOL_VERSION, 1).is_ok());
+        assert!(AgentInfo::compatible(MIN_AGENT_PROTOCOL_VERSION - 1, 0).is_err());
+        assert!(
+            AgentInfo::compatible(AGENT_PROTOCOL_VERSION + 2, AGENT_PROTOCOL_VERSION + 1).is_err()
+        );
+
+        let legacy = AgentInfo::legacy();
+        let request = ExecuteRequest {
+            stdin: b"input".to_vec(),
+            ..Default::default()
+        };
+        let status = legacy.check(&request).unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert!(legacy.check(&ExecuteRequest::default()).is_ok());
+
+        let current = AgentInfo {
+            capabilities: agent_capabilities::ALL
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            ..legacy
+        };
+        assert!(current.check(&request).is_ok());
+    }
+}
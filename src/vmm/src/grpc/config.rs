@@ -0,0 +1,404 @@
+//! Configuration file of the orchestrator, reloaded while it runs.
+//!
+//! The file sets the same settings as the options of `vmm grpc`, which it overrides:
+//!
+//! ```toml
+//! listen = "[::1]:50051"         # only read at startup, like unix-socket and agent-port
+//! # unix-socket = "/run/cloudlet/vmm.sock"   # instead of listen
+//! agent-port = 50051             # port of the agents in the guests
+//! log-level = "info"
+//! kernels = "kernels.toml"       # relative to the configuration file
+//! admission-policy = "admission.toml"
+//! scheduler = "scheduler.toml"
+//! max-output-bytes = 16777216
+//! max-output-lines = 100000
+//! rootfs-pins = ["python:3.12@sha256:..."]
+//! artifact-public-keys = ["cloudlet.pub"]      # see the signatures module
+//! artifact-signing-key = "cloudlet.key"
+//! ```
+//!
+//! The file and the files it names are watched, and reloaded when they change or when
+//! `AdminService/ReloadConfig` is called. Settings which can't change while the orchestrator
+//! runs are left as they are, with a warning.
+
+use super::{
+    admission::{AdmissionError, AdmissionPolicy},
+    kernels::{KernelRegistry, KernelsError, BUILTIN_KERNEL_CONFIG},
+    registry::RootfsPin,
+    scheduler::{SchedulerConfig, SchedulerError},
+    server::VmmService,
+    signatures::{ArtifactSignatures, SignatureError},
+};
+use serde::Deserialize;
+use shared_models::cloudlet::agent::OutputLimits;
+use shared_models::logging::LogArgs;
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::{filter::LevelFilter, reload, EnvFilter, Registry};
+
+/// Address the orchestrator listens on by default.
+pub const DEFAULT_LISTEN: &str = "[::1]:50051";
+
+/// How often the configuration files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+    Kernels(KernelsError),
+    Admission(AdmissionError),
+    Scheduler(SchedulerError),
+    Signatures(SignatureError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Failed to read {:?}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid configuration {:?}: {}", path, e),
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {}", message),
+            ConfigError::Kernels(e) => write!(f, "{}", e),
+            ConfigError::Admission(e) => write!(f, "{}", e),
+            ConfigError::Scheduler(e) => write!(f, "{}", e),
+            ConfigError::Signatures(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Settings of the orchestrator, from its options or its configuration file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OrchestratorConfig {
+    pub listen: Option<SocketAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub agent_port: Option<u16>,
+    pub log_level: Option<String>,
+    pub kernels: Option<PathBuf>,
+    pub admission_policy: Option<PathBuf>,
+    pub scheduler: Option<PathBuf>,
+    pub max_output_bytes: Option<u64>,
+    pub max_output_lines: Option<u64>,
+    pub rootfs_pins: Option<Vec<RootfsPin>>,
+    pub artifact_public_keys: Option<Vec<PathBuf>>,
+    pub artifact_signing_key: Option<PathBuf>,
+}
+
+impl OrchestratorConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        let mut config: Self =
+            toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for file in [
+            &mut config.kernels,
+            &mut config.admission_policy,
+            &mut config.scheduler,
+            &mut config.artifact_signing_key,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(config.artifact_public_keys.iter_mut().flatten())
+        {
+            *file = dir.join(&*file);
+        }
+
+        Ok(config)
+    }
+
+    /// `self`, with the settings it lacks taken from `defaults`.
+    pub fn or(self, defaults: &Self) -> Self {
+        let defaults = defaults.clone();
+        Self {
+            listen: self.listen.or(defaults.listen),
+            unix_socket: self.unix_socket.or(defaults.unix_socket),
+            agent_port: self.agent_port.or(defaults.agent_port),
+            log_level: self.log_level.or(defaults.log_level),
+            kernels: self.kernels.or(defaults.kernels),
+            admission_policy: self.admission_policy.or(defaults.admission_policy),
+            scheduler: self.scheduler.or(defaults.scheduler),
+            max_output_bytes: self.max_output_bytes.or(defaults.max_output_bytes),
+            max_output_lines: self.max_output_lines.or(defaults.max_output_lines),
+            rootfs_pins: self.rootfs_pins.or(defaults.rootfs_pins),
+            artifact_public_keys: self.artifact_public_keys.or(defaults.artifact_public_keys),
+            artifact_signing_key: self.artifact_signing_key.or(defaults.artifact_signing_key),
+        }
+    }
+
+    pub fn listen(&self) -> SocketAddr {
+        self.listen
+            .unwrap_or_else(|| DEFAULT_LISTEN.parse().unwrap())
+    }
+
+    /// Check the addresses, which the orchestrator would otherwise only fail to use later.
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_some() && self.unix_socket.is_some() {
+            return Err(ConfigError::Invalid(
+                "listen and unix-socket are both set, the orchestrator listens on only one of them"
+                    .to_string(),
+            ));
+        }
+        if self.listen().port() == 0 {
+            return Err(ConfigError::Invalid(format!(
+                "the listen address {} has no port",
+                self.listen()
+            )));
+        }
+        if self.agent_port == Some(0) {
+            return Err(ConfigError::Invalid(
+                "the agent port can't be 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Names of the settings only read at startup which differ between `self` and `other`.
+    fn startup_changes(&self, other: &Self) -> Vec<String> {
+        [
+            ("listen", self.listen() != other.listen()),
+            ("unix-socket", self.unix_socket != other.unix_socket),
+            ("agent-port", self.agent_port != other.agent_port),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name.to_string())
+        .collect()
+    }
+
+    /// Files whose changes are applied while the orchestrator runs.
+    fn watched_files(&self) -> Vec<PathBuf> {
+        [
+            &self.kernels,
+            &self.admission_policy,
+            &self.scheduler,
+            &self.artifact_signing_key,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.artifact_public_keys.iter().flatten())
+        .cloned()
+        .collect()
+    }
+}
+
+/// Settings which can change while the orchestrator runs, loaded from an [`OrchestratorConfig`].
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub log_level: LevelFilter,
+    pub kernels: KernelRegistry,
+    pub admission: Option<AdmissionPolicy>,
+    pub scheduler: SchedulerConfig,
+    pub output_limits: OutputLimits,
+    pub rootfs_pins: Vec<RootfsPin>,
+    pub signatures: Option<ArtifactSignatures>,
+}
+
+impl Settings {
+    pub fn load(config: &OrchestratorConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        let log_level = match &config.log_level {
+            Some(level) => level
+                .parse()
+                .map_err(|_| ConfigError::Invalid(format!("unknown log level {}", level)))?,
+            None => LevelFilter::INFO,
+        };
+
+        Ok(Self {
+            log_level,
+            kernels: KernelRegistry::load(
+                Path::new(BUILTIN_KERNEL_CONFIG),
+                config.kernels.as_deref(),
+            )
+            .map_err(ConfigError::Kernels)?,
+            admission: config
+                .admission_policy
+                .as_deref()
+                .map(AdmissionPolicy::load)
+                .transpose()
+                .map_err(ConfigError::Admission)?,
+            scheduler: match &config.scheduler {
+                Some(path) => SchedulerConfig::load(path).map_err(ConfigError::Scheduler)?,
+                None => SchedulerConfig::default(),
+            },
+            output_limits: OutputLimits {
+                max_bytes: config.max_output_bytes.unwrap_or_default(),
+                max_lines: config.max_output_lines.unwrap_or_default(),
+            },
+            rootfs_pins: config.rootfs_pins.clone().unwrap_or_default(),
+            signatures: ArtifactSignatures::load(
+                config.artifact_public_keys.as_deref().unwrap_or_default(),
+                config.artifact_signing_key.as_deref(),
+            )
+            .map_err(ConfigError::Signatures)?,
+        })
+    }
+
+    /// The settings, for the debug bundles: the values the admission policy adds to the
+    /// environments are redacted, and the signing key is only named by its path.
+    pub fn snapshot(&self) -> String {
+        [
+            ("log-level", format!("{}", self.log_level)),
+            ("kernels", format!("{:?}", self.kernels)),
+            (
+                "admission-policy",
+                format!(
+                    "{:?}",
+                    self.admission.as_ref().map(AdmissionPolicy::redacted)
+                ),
+            ),
+            ("scheduler", format!("{:?}", self.scheduler)),
+            ("max-output", format!("{:?}", self.output_limits)),
+            ("rootfs-pins", format!("{:?}", self.rootfs_pins)),
+            ("artifact-signatures", format!("{:?}", self.signatures)),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{} = {}\n", name, value))
+        .collect()
+    }
+
+    /// Names of the settings which differ between `self` and `other`.
+    fn changes(&self, other: &Self) -> Vec<String> {
+        let differ =
+            |a: &dyn fmt::Debug, b: &dyn fmt::Debug| format!("{:?}", a) != format!("{:?}", b);
+        [
+            ("log-level", differ(&self.log_level, &other.log_level)),
+            ("kernels", differ(&self.kernels, &other.kernels)),
+            (
+                "admission-policy",
+                differ(&self.admission, &other.admission),
+            ),
+            ("scheduler", differ(&self.scheduler, &other.scheduler)),
+            ("max-output", self.output_limits != other.output_limits),
+            ("rootfs-pins", self.rootfs_pins != other.rootfs_pins),
+            (
+                "artifact-signatures",
+                differ(&self.signatures, &other.signatures),
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name.to_string())
+        .collect()
+    }
+}
+
+/// What a reload changed.
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+    pub applied: Vec<String>,
+    /// Changes left for the next start of the orchestrator.
+    pub rejected: Vec<String>,
+}
+
+/// Reloads the configuration file of a running orchestrator into its service.
+pub struct ConfigReloader {
+    path: PathBuf,
+    /// Settings of the options, overridden by the file.
+    options: OrchestratorConfig,
+    service: Arc<VmmService>,
+    /// Filter of the logs, from the level of the settings and the directives of `log`.
+    log_filter: reload::Handle<EnvFilter, Registry>,
+    log: LogArgs,
+    /// The configuration and the settings in use.
+    current: Mutex<(OrchestratorConfig, Settings)>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        path: PathBuf,
+        options: OrchestratorConfig,
+        config: OrchestratorConfig,
+        settings: Settings,
+        service: Arc<VmmService>,
+        log_filter: reload::Handle<EnvFilter, Registry>,
+        log: LogArgs,
+    ) -> Self {
+        Self {
+            path,
+            options,
+            service,
+            log_filter,
+            log,
+            current: Mutex::new((config, settings)),
+        }
+    }
+
+    /// Load the configuration again and apply its changes. The settings in use are kept if it
+    /// is invalid.
+    pub fn reload(&self) -> Result<ReloadReport, ConfigError> {
+        let config = OrchestratorConfig::load(&self.path)?.or(&self.options);
+        let settings = Settings::load(&config)?;
+
+        let mut current = self.current.lock().unwrap();
+        let mut report = ReloadReport::default();
+        report.rejected = config.startup_changes(&current.0);
+        if !report.rejected.is_empty() {
+            warn!(
+                settings = ?report.rejected,
+                "These settings can't change while the orchestrator runs, restart it to apply the change"
+            );
+        }
+
+        report.applied = settings.changes(&current.1);
+        if !report.applied.is_empty() {
+            self.log_filter
+                .reload(self.log.filter(settings.log_level))
+                .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+            self.service.apply_settings(&settings);
+        }
+        info!(applied = ?report.applied, rejected = ?report.rejected, "Reloaded the configuration");
+
+        let (listen, unix_socket, agent_port) = (
+            current.0.listen,
+            current.0.unix_socket.clone(),
+            current.0.agent_port,
+        );
+        *current = (
+            OrchestratorConfig {
+                listen,
+                unix_socket,
+                agent_port,
+                ..config
+            },
+            settings,
+        );
+        Ok(report)
+    }
+
+    /// Reload the configuration whenever its files change.
+    pub async fn watch(self: Arc<Self>) {
+        let mut last = self.modification_times();
+        loop {
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            let times = self.modification_times();
+            if times == last {
+                continue;
+            }
+            last = times;
+
+            if let Err(e) = self.reload() {
+                error!(error = %e, "Could not reload the configuration, keeping the current one");
+            }
+        }
+    }
+
+    fn modification_times(&self) -> Vec<Option<SystemTime>> {
+        let mut files = vec![self.path.clone()];
+        files.extend(self.current.lock().unwrap().0.watched_files());
+        files
+            .iter()
+            .map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
@@ -0,0 +1,233 @@
+//! Logs of the guest consoles, one file per VM, kept for post-mortem debugging whether or not a
+//! client was streaming the run.
+//!
+//! The console of a VM is written to `<dir>/<vm id>.log`, rotated to `<vm id>.log.1`,
+//! `<vm id>.log.2`... once it reaches the maximum size, the oldest rotation being dropped past
+//! the maximum count. The guest may write to its console before its VM is given an id: the
+//! output is buffered until the log is opened. The logs not written to for longer than the
+//! maximum age are removed when another one is opened.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+/// Output of the guest kept while its log isn't opened yet.
+const PENDING_LIMIT: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ConsoleLogConfig {
+    pub dir: PathBuf,
+    /// Size in bytes past which the log of a VM is rotated.
+    pub max_size: u64,
+    /// Rotated logs kept per VM, besides the current one.
+    pub max_files: u32,
+    /// Logs not written to for this long are removed.
+    pub max_age: Duration,
+}
+
+impl ConsoleLogConfig {
+    /// Path of the current log of the VM `vm_id`.
+    pub fn path(&self, vm_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", vm_id))
+    }
+
+    /// Remove the logs, rotated or not, which haven't been written to for longer than the
+    /// maximum age.
+    pub fn prune(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let now = SystemTime::now();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if !path.to_string_lossy().contains(".log") {
+                continue;
+            }
+            let expired = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| {
+                    now.duration_since(modified)
+                        .is_ok_and(|age| age > self.max_age)
+                });
+            if expired {
+                match fs::remove_file(&path) {
+                    Ok(()) => info!(path = ?path, "Pruned console log"),
+                    Err(e) => warn!(path = ?path, reason = %e, "Could not prune console log"),
+                }
+            }
+        }
+    }
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", index));
+    path.with_file_name(name)
+}
+
+#[derive(Default)]
+struct State {
+    file: Option<(PathBuf, LineWriter<File>)>,
+    /// Bytes in the current file.
+    size: u64,
+    pending: Vec<u8>,
+    /// The log couldn't be written, the rest of the console is dropped.
+    failed: bool,
+}
+
+impl State {
+    fn write(&mut self, config: &ConsoleLogConfig, buf: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            let room = PENDING_LIMIT.saturating_sub(self.pending.len());
+            self.pending.extend_from_slice(&buf[..buf.len().min(room)]);
+            return Ok(());
+        }
+        if self.size > 0 && self.size + buf.len() as u64 > config.max_size {
+            self.rotate(config)?;
+        }
+        if let Some((_, file)) = &mut self.file {
+            file.write_all(buf)?;
+            self.size += buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Move the current file aside, dropping the oldest rotation, and start a new one.
+    fn rotate(&mut self, config: &ConsoleLogConfig) -> io::Result<()> {
+        let Some((path, mut file)) = self.file.take() else {
+            return Ok(());
+        };
+        file.flush()?;
+        drop(file);
+        if config.max_files > 0 {
+            let _ = fs::remove_file(rotated_path(&path, config.max_files));
+            for index in (1..config.max_files).rev() {
+                let _ = fs::rename(rotated_path(&path, index), rotated_path(&path, index + 1));
+            }
+            fs::rename(&path, rotated_path(&path, 1))?;
+        }
+        let file = File::create(&path)?;
+        self.file = Some((path, LineWriter::new(file)));
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Console of a guest, written to the log of its VM once [`ConsoleLog::open`]ed.
+#[derive(Clone)]
+pub struct ConsoleLog {
+    config: Arc<ConsoleLogConfig>,
+    state: Arc<Mutex<State>>,
+}
+
+impl fmt::Debug for ConsoleLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConsoleLog")
+            .field("dir", &self.config.dir)
+            .field("path", &self.path())
+            .finish()
+    }
+}
+
+impl ConsoleLog {
+    pub fn new(config: Arc<ConsoleLogConfig>) -> Self {
+        Self {
+            config,
+            state: Arc::default(),
+        }
+    }
+
+    /// Start writing to the log of the VM `vm_id`, beginning with what the guest wrote so far.
+    /// Returns the path of the log.
+    pub fn open(&self, vm_id: &str) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.config.dir)?;
+        self.config.prune();
+        let path = self.config.path(vm_id);
+        let file = File::options().create(true).append(true).open(&path)?;
+
+        let mut state = self.state.lock().unwrap();
+        state.size = file.metadata()?.len();
+        state.file = Some((path.clone(), LineWriter::new(file)));
+        let pending = std::mem::take(&mut state.pending);
+        state.write(&self.config, &pending)?;
+        Ok(path)
+    }
+
+    /// Path of the current log, once opened.
+    pub fn path(&self) -> Option<PathBuf> {
+        let state = self.state.lock().unwrap();
+        state.file.as_ref().map(|(path, _)| path.clone())
+    }
+}
+
+impl Write for ConsoleLog {
+    /// Never fails, so as not to disturb the serial port of the guest: the console is dropped
+    /// once the log can't be written.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if !state.failed {
+            if let Err(e) = state.write(&self.config, buf) {
+                let path = state.file.as_ref().map(|(path, _)| path.clone());
+                warn!(path = ?path, reason = %e, "Could not write the console log, dropping the console");
+                state.failed = true;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match &mut state.file {
+            Some((_, file)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudlet_test_registry::scratch_dir;
+
+    #[test]
+    fn test_rotate_console_log() {
+        let config = Arc::new(ConsoleLogConfig {
+            dir: scratch_dir("vmm-console"),
+            max_size: 16,
+            max_files: 2,
+            max_age: Duration::from_secs(3600),
+        });
+        let mut console = ConsoleLog::new(config.clone());
+
+        // Written before the VM has an id.
+        console.write_all(b"booting\n").unwrap();
+        let path = console.open("hello-0123abcd").unwrap();
+        assert_eq!(path, config.path("hello-0123abcd"));
+        console.write_all(b"started\n").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"booting\nstarted\n");
+
+        for line in [b"line 1\n", b"line 2\n", b"line 3\n", b"line 4\n"] {
+            console.write_all(line).unwrap();
+        }
+        console.write_all(b"last\n").unwrap();
+        console.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"last\n");
+        assert_eq!(
+            fs::read(rotated_path(&path, 1)).unwrap(),
+            b"line 3\nline 4\n"
+        );
+        assert_eq!(
+            fs::read(rotated_path(&path, 2)).unwrap(),
+            b"line 1\nline 2\n"
+        );
+        // The first file was dropped past the two rotations.
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
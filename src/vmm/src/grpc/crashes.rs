@@ -0,0 +1,182 @@
+//! Files collected by the agents to debug the runs: the core dump and the backtrace of a
+//! workload killed by a signal.
+//!
+//! The agent sends them with the final message of the run, which the orchestrator forwards
+//! with their description only. Their content is kept in memory, the most recent runs first up
+//! to [`MAX_BYTES`], and in the shared storage if there is one, to be fetched by
+//! `GetRunArtifact`.
+
+use super::storage::Storage;
+use prost::Message;
+use shared_models::cloudlet::agent::{ExecuteResponse, RunArtifact};
+use shared_models::vmmorchestrator::ListRunArtifactsResponse;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tracing::{error, warn};
+
+/// Content kept in memory, the artifacts of the oldest runs being dropped beyond it.
+pub const MAX_BYTES: usize = 512 * 1024 * 1024;
+
+/// Key of the artifact `name` of the run `vm_id` in the shared storage.
+pub fn key(vm_id: &str, name: &str) -> String {
+    format!("artifacts/{}/{}", vm_id, name)
+}
+
+/// Key of the description of the artifacts of the run `vm_id` in the shared storage.
+pub fn index_key(vm_id: &str) -> String {
+    format!("artifacts/{}.pb", vm_id)
+}
+
+/// The same artifact, without its content.
+pub fn description(artifact: &RunArtifact) -> RunArtifact {
+    RunArtifact {
+        name: artifact.name.clone(),
+        content: Vec::new(),
+        size: artifact.size,
+        truncated: artifact.truncated,
+    }
+}
+
+/// Keep the artifacts of the final `response` of the run `vm_id` in `store`, and in the shared
+/// `storage` if any, leaving only their description in the response. The outputs of the run
+/// are left in the response as they are, for the client.
+pub async fn keep(
+    store: &RunArtifacts,
+    storage: Option<Arc<dyn Storage>>,
+    vm_id: &str,
+    response: &mut ExecuteResponse,
+) {
+    let (outputs, artifacts): (Vec<RunArtifact>, Vec<RunArtifact>) =
+        std::mem::take(&mut response.artifacts)
+            .into_iter()
+            .partition(|artifact| artifact.output_path().is_some());
+    response.artifacts = outputs;
+    if artifacts.is_empty() {
+        return;
+    }
+    response.artifacts.extend(artifacts.iter().map(description));
+
+    let artifacts = match storage {
+        Some(storage) => {
+            let id = vm_id.to_string();
+            let index = ListRunArtifactsResponse {
+                artifacts: artifacts.iter().map(description).collect(),
+            }
+            .encode_to_vec();
+            let stored = tokio::task::spawn_blocking(move || {
+                let result = artifacts
+                    .iter()
+                    .try_for_each(|artifact| {
+                        storage.write(&key(&id, &artifact.name), &artifact.content)
+                    })
+                    .and_then(|()| storage.write(&index_key(&id), &index));
+                (artifacts, result)
+            })
+            .await;
+            match stored {
+                Ok((artifacts, Ok(()))) => artifacts,
+                Ok((artifacts, Err(e))) => {
+                    warn!(vm_id, error = %e, "Could not store the run artifacts");
+                    artifacts
+                }
+                Err(e) => {
+                    error!(error = %e, "Run artifacts storage task failed");
+                    return;
+                }
+            }
+        }
+        None => artifacts,
+    };
+    store.put(vm_id, artifacts);
+}
+
+/// Artifacts of the recent runs.
+#[derive(Debug)]
+pub struct RunArtifacts {
+    /// Content kept in memory.
+    max_bytes: usize,
+    runs: Mutex<VecDeque<(String, Vec<RunArtifact>)>>,
+}
+
+impl Default for RunArtifacts {
+    fn default() -> Self {
+        Self::new(MAX_BYTES)
+    }
+}
+
+impl RunArtifacts {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            runs: Mutex::default(),
+        }
+    }
+
+    /// Keep the `artifacts` of the run `vm_id`, dropping those of the oldest runs if needed.
+    pub fn put(&self, vm_id: &str, artifacts: Vec<RunArtifact>) {
+        let mut runs = self.runs.lock().unwrap();
+        runs.retain(|(id, _)| id != vm_id);
+        runs.push_back((vm_id.to_string(), artifacts));
+
+        let size = |artifacts: &[RunArtifact]| -> usize {
+            artifacts
+                .iter()
+                .map(|artifact| artifact.content.len())
+                .sum()
+        };
+        let mut total: usize = runs.iter().map(|(_, artifacts)| size(artifacts)).sum();
+        while total > self.max_bytes && runs.len() > 1 {
+            let (dropped, artifacts) = runs.pop_front().unwrap();
+            total -= size(&artifacts);
+            warn!(vm_id = %dropped, "Dropped the run artifacts from memory");
+        }
+    }
+
+    /// Description of the artifacts of the run `vm_id`, `None` if none are kept.
+    pub fn list(&self, vm_id: &str) -> Option<Vec<RunArtifact>> {
+        let runs = self.runs.lock().unwrap();
+        let (_, artifacts) = runs.iter().find(|(id, _)| id == vm_id)?;
+        Some(artifacts.iter().map(description).collect())
+    }
+
+    /// The artifact `name` of the run `vm_id`.
+    pub fn get(&self, vm_id: &str, name: &str) -> Option<RunArtifact> {
+        let runs = self.runs.lock().unwrap();
+        let (_, artifacts) = runs.iter().find(|(id, _)| id == vm_id)?;
+        artifacts
+            .iter()
+            .find(|artifact| artifact.name == name)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn core(size: usize) -> RunArtifact {
+        RunArtifact {
+            name: "core".into(),
+            content: vec![0; size],
+            size: size as u64,
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn test_put_drops_oldest_runs() {
+        let store = RunArtifacts::new(100);
+        store.put("a-1", vec![core(50)]);
+        store.put("b-1", vec![core(50)]);
+        assert!(store.get("a-1", "core").is_some());
+
+        store.put("c-1", vec![core(1)]);
+        assert!(store.list("a-1").is_none());
+        assert_eq!(store.get("c-1", "core").unwrap().content.len(), 1);
+        assert!(store.get("c-1", "backtrace").is_none());
+
+        let listed = store.list("b-1").unwrap();
+        assert_eq!(listed[0].size, 50);
+        assert!(listed[0].content.is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+//! Hard deadlines of the runs, enforced by the orchestrator rather than by the agent.
+//!
+//! A run may take its own timeout, or `--max-run-duration` if it has none or a longer one, from
+//! the boot of its VM or, on a warm VM, from the start of the invocation. Past it, the
+//! orchestrator destroys the VM through its stopper instead of asking the agent, which may be
+//! the one which hangs or misbehaves: the run fails with `CLDT-VMM-034` and a
+//! `DEADLINE_EXCEEDED` event, rather than with the outcome of the workload.
+
+use super::{admin::VmTable, deterministic::Clock, events::EventBus};
+use shared_models::{vmmorchestrator::VmEventKind, ErrorCode};
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::{Code, Status};
+use tracing::warn;
+
+/// Longest run by default, in seconds, also the longest timeout of a spec.
+pub const DEFAULT_MAX_RUN_SECS: u64 = 60 * 60;
+
+/// Time a run asking for `timeout_secs`, 0 if it doesn't, may take within `max`, the limit of
+/// the orchestrator if any.
+pub fn run_limit(timeout_secs: u64, max: Option<Duration>) -> Option<Duration> {
+    let requested = (timeout_secs > 0).then(|| Duration::from_secs(timeout_secs));
+    match (requested, max) {
+        (Some(requested), Some(max)) => Some(requested.min(max)),
+        (requested, max) => requested.or(max),
+    }
+}
+
+/// Hard deadline of a run, from when it's created.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    limit: Duration,
+    at: Instant,
+}
+
+impl Deadline {
+    /// A deadline `limit` from now on `clock`, none without a limit.
+    pub fn new(limit: Option<Duration>, clock: &dyn Clock) -> Option<Self> {
+        limit.map(|limit| Self {
+            limit,
+            at: Instant::from_std(clock.instant()) + limit,
+        })
+    }
+
+    pub fn limit(&self) -> Duration {
+        self.limit
+    }
+
+    /// Destroy the VM `vm_id` whose run went past the deadline, returning the status ending
+    /// the run.
+    pub fn enforce(
+        &self,
+        vms: &VmTable,
+        events: &EventBus,
+        vm_id: &str,
+        workload_name: &str,
+    ) -> Status {
+        let message = format!("The run went past its hard deadline of {:?}", self.limit);
+        warn!(vm_id, limit = ?self.limit, "The run went past its hard deadline, destroying its VM");
+        events.publish(
+            vm_id,
+            workload_name,
+            VmEventKind::DeadlineExceeded,
+            format!("{:?}", self.limit),
+        );
+        vms.kill(vm_id, "hard deadline exceeded");
+        ErrorCode::VmmDeadlineExceeded.status(Code::DeadlineExceeded, message)
+    }
+}
+
+/// Resolve once `deadline` is past, never if there is none.
+pub async fn expired(deadline: Option<Deadline>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.at).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_limit() {
+        let hour = Some(Duration::from_secs(3600));
+        assert_eq!(run_limit(0, hour), hour);
+        assert_eq!(run_limit(60, hour), Some(Duration::from_secs(60)));
+        // The requests can't run for longer than the orchestrator allows.
+        assert_eq!(run_limit(7200, hour), hour);
+        assert_eq!(run_limit(7200, None), Some(Duration::from_secs(7200)));
+        assert_eq!(run_limit(0, None), None);
+    }
+}
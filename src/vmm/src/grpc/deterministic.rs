@@ -0,0 +1,172 @@
+//! Deterministic mode of the orchestrator, for the integration tests and the replays of the
+//! recorded sessions: two runs of the same inputs give the same ids, timestamps and addresses.
+//!
+//! The ids of the VMs and the faults injected are drawn from a seed rather than from the entropy
+//! of the host. The time of the VM table, the events, the pools, the history of the runs, the
+//! queue of the scheduler, the deadlines and the recorded sessions is read from a [`Clock`], a
+//! [`FixedClock`] in this mode, which only moves when the test advances it: an idle timeout
+//! expires when the test says so, not when the machine running it is slow. The guests get
+//! their addresses from a fake IPAM, [`TenantNetworks::fake`], whatever the networks of the
+//! host.
+//!
+//! [`TenantNetworks::fake`]: crate::core::network::TenantNetworks::fake
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Time the fixed clocks start at, 2024-01-01T00:00:00Z.
+pub const FIXED_EPOCH_SECS: u64 = 1_704_067_200;
+
+/// Source of the time of the orchestrator.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Wall-clock time, for the timestamps.
+    fn now(&self) -> SystemTime;
+
+    /// Monotonic time, for the durations.
+    fn instant(&self) -> Instant;
+
+    /// Seconds since the Unix epoch.
+    fn unix_secs(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The clock of the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A clock which stands still until it is [`FixedClock::advance`]d.
+#[derive(Debug)]
+pub struct FixedClock {
+    start: SystemTime,
+    origin: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for FixedClock {
+    fn default() -> Self {
+        Self::new(UNIX_EPOCH + Duration::from_secs(FIXED_EPOCH_SECS))
+    }
+}
+
+impl FixedClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            start,
+            origin: Instant::now(),
+            elapsed: Mutex::default(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn instant(&self) -> Instant {
+        self.origin + *self.elapsed.lock().unwrap()
+    }
+}
+
+/// Pseudo-random number `index` of the sequence of `seed`, splitmix64, stable across the
+/// versions of Rust unlike the hashers of the standard library.
+pub fn seeded_random(seed: u64, index: u64) -> u64 {
+    let mut z = seed.wrapping_add(index.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Settings of the deterministic mode.
+#[derive(Debug, Clone)]
+pub struct Deterministic {
+    pub seed: u64,
+    pub clock: Arc<FixedClock>,
+}
+
+impl Deterministic {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            clock: Arc::default(),
+        }
+    }
+
+    pub fn clock(&self) -> SharedClock {
+        self.clock.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::admin::VmTable;
+    use shared_models::vmmorchestrator::admin::VmInfo;
+
+    fn vm_ids(mode: &Deterministic) -> Vec<(String, u64)> {
+        let vms = VmTable::deterministic(mode);
+        ["a", "b"]
+            .into_iter()
+            .map(|workload| {
+                let vm = VmInfo {
+                    workload_name: workload.into(),
+                    ..Default::default()
+                };
+                let id = vms.insert(vm, Arc::default()).unwrap();
+                (id.clone(), vms.resolve(&id).unwrap().started_at)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_seeded_vm_ids() {
+        let ids = vm_ids(&Deterministic::new(42));
+        assert_eq!(ids, vm_ids(&Deterministic::new(42)));
+        assert_ne!(ids, vm_ids(&Deterministic::new(43)));
+        assert!(ids[0].0.starts_with("a-"));
+        assert_eq!(ids[1].1, FIXED_EPOCH_SECS);
+    }
+
+    #[test]
+    fn test_fixed_clock() {
+        let clock = FixedClock::default();
+        let (now, instant) = (clock.now(), clock.instant());
+        assert_eq!(clock.unix_secs(), FIXED_EPOCH_SECS);
+        assert_eq!((clock.now(), clock.instant()), (now, instant));
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.unix_secs(), FIXED_EPOCH_SECS + 90);
+        assert_eq!(clock.instant() - instant, Duration::from_secs(90));
+
+        assert_eq!(seeded_random(7, 0), seeded_random(7, 0));
+        assert_ne!(seeded_random(7, 0), seeded_random(7, 1));
+        assert_ne!(seeded_random(7, 0), seeded_random(8, 0));
+    }
+}
@@ -0,0 +1,83 @@
+use super::deterministic::{system_clock, SharedClock};
+use shared_models::vmmorchestrator::{VmEvent, VmEventKind, WatchEventsRequest};
+use tokio::sync::{broadcast, mpsc};
+use tonic::Status;
+use tracing::warn;
+
+/// Events kept for watchers which are slower than the orchestrator.
+const EVENT_BUFFER: usize = 256;
+
+/// Lifecycle and scheduling events of the VMs, published to every watcher.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<VmEvent>,
+    clock: SharedClock,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(system_clock())
+    }
+}
+
+impl EventBus {
+    /// A bus timestamping the events with `clock`.
+    pub fn new(clock: SharedClock) -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender, clock }
+    }
+
+    pub fn publish(
+        &self,
+        vm_id: &str,
+        workload_name: &str,
+        kind: VmEventKind,
+        message: impl Into<String>,
+    ) {
+        let event = VmEvent {
+            vm_id: vm_id.to_string(),
+            workload_name: workload_name.to_string(),
+            kind: kind as i32,
+            timestamp: self.clock.unix_secs(),
+            message: message.into(),
+        };
+        // Nobody may be watching.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<VmEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Send the events matching `filter` to `tx`, until the watcher leaves.
+    pub async fn watch(
+        &self,
+        filter: WatchEventsRequest,
+        tx: mpsc::Sender<Result<VmEvent, Status>>,
+    ) {
+        let mut receiver = self.sender.subscribe();
+
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!(missed, "Watcher is too slow, dropping events");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+
+            if !matches(&filter, &event) {
+                continue;
+            }
+            if tx.send(Ok(event)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn matches(filter: &WatchEventsRequest, event: &VmEvent) -> bool {
+    (filter.workload_name.is_empty() || filter.workload_name == event.workload_name)
+        && (filter.vm.is_empty() || filter.vm == event.vm_id || filter.vm == event.workload_name)
+}
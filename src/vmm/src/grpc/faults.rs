@@ -0,0 +1,129 @@
+//! Faults injected into the orchestrator for resilience testing, to check that it and its
+//! clients degrade gracefully: dropped agent connections, slow boots, failed downloads and
+//! killed VMs.
+//!
+//! The faults are set through `AdminService/InjectFaults`, and only injected when the
+//! `fault-injection` feature is enabled: the hooks are no-ops otherwise.
+
+#[cfg(feature = "fault-injection")]
+use super::deterministic::seeded_random;
+use std::time::Duration;
+#[cfg(feature = "fault-injection")]
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+/// Whether the faults are injected in this build.
+pub const ENABLED: bool = cfg!(feature = "fault-injection");
+
+/// Faults to inject, none by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultSettings {
+    /// Percentage of the runs whose agent connection is dropped after their first message.
+    pub drop_agent_connections_percent: u32,
+    /// Delay before booting each guest.
+    pub boot_delay: Duration,
+    /// Percentage of the rootfs pulls and storage fetches which fail.
+    pub fail_downloads_percent: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Faults {
+    #[cfg(feature = "fault-injection")]
+    settings: RwLock<FaultSettings>,
+    #[cfg(feature = "fault-injection")]
+    state: RandomState,
+    /// Seed of the deterministic mode, which the faults are drawn from instead.
+    #[cfg(feature = "fault-injection")]
+    seed: Option<u64>,
+    #[cfg(feature = "fault-injection")]
+    counter: AtomicU64,
+}
+
+#[cfg(feature = "fault-injection")]
+impl Faults {
+    /// Faults drawn from `seed`, the same ones from a run to the next.
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            seed: Some(seed),
+            ..Default::default()
+        }
+    }
+
+    pub fn set(&self, settings: FaultSettings) {
+        *self.settings.write().unwrap() = settings;
+    }
+
+    pub fn settings(&self) -> FaultSettings {
+        self.settings.read().unwrap().clone()
+    }
+
+    pub fn drop_agent_connection(&self) -> bool {
+        self.roll(self.settings().drop_agent_connections_percent)
+    }
+
+    pub fn boot_delay(&self) -> Duration {
+        self.settings().boot_delay
+    }
+
+    pub fn fail_download(&self) -> bool {
+        self.roll(self.settings().fail_downloads_percent)
+    }
+
+    /// Random index below `len`.
+    pub fn pick(&self, len: usize) -> usize {
+        (self.random() % len.max(1) as u64) as usize
+    }
+
+    /// Whether an event happening `percent`% of the time happens.
+    fn roll(&self, percent: u32) -> bool {
+        percent > 0 && self.random() % 100 < percent as u64
+    }
+
+    fn random(&self) -> u64 {
+        let index = self.counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(seed) = self.seed {
+            return seeded_random(seed, index);
+        }
+        let mut hasher = self.state.build_hasher();
+        hasher.write_u64(index);
+        hasher.finish()
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+impl Faults {
+    pub fn seeded(_seed: u64) -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, _settings: FaultSettings) {}
+
+    pub fn settings(&self) -> FaultSettings {
+        FaultSettings::default()
+    }
+
+    #[inline]
+    pub fn drop_agent_connection(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    pub fn boot_delay(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    #[inline]
+    pub fn fail_download(&self) -> bool {
+        false
+    }
+
+    pub fn pick(&self, _len: usize) -> usize {
+        0
+    }
+}
@@ -0,0 +1,125 @@
+use super::server::VmmService;
+use crate::core::network::{TenantNetworks, MAX_TENANT_NETWORKS};
+use kvm_ioctls::Kvm;
+use shared_models::vmmorchestrator::vmm_service_server::VmmServiceServer;
+use std::{env::current_dir, time::Duration};
+use tonic_health::server::HealthReporter;
+use tracing::{info, warn};
+
+/// Delay between two evaluations of the orchestrator health.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Directories where the kernel and rootfs artifacts are cached.
+const ARTIFACT_DIRS: [&str; 2] = ["tools/kernel", "tools/rootfs"];
+
+/// Check that the host can create virtual machines.
+pub fn check_kvm() -> Result<(), String> {
+    Kvm::new()
+        .map(|_| ())
+        .map_err(|e| format!("unable to open /dev/kvm: {}", e))
+}
+
+/// Check that the artifact cache directories exist and are writable.
+fn check_artifact_cache() -> Result<(), String> {
+    let curr_dir = current_dir().map_err(|e| format!("unable to get current dir: {}", e))?;
+
+    for dir in ARTIFACT_DIRS {
+        let path = curr_dir.join(dir);
+        let metadata = std::fs::metadata(&path)
+            .map_err(|e| format!("artifact directory {:?} is unavailable: {}", path, e))?;
+
+        if metadata.permissions().readonly() {
+            return Err(format!("artifact directory {:?} is read-only", path));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a new tenant can still get an isolated network, when the tenants are isolated.
+fn check_tenant_networks(networks: Option<&TenantNetworks>) -> Result<(), String> {
+    match networks {
+        Some(networks) if networks.exhausted() => Err(format!(
+            "all the {} tenant networks are taken",
+            MAX_TENANT_NETWORKS
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Run every health check and return the first failure, if any. The guests which are `emulated`
+/// don't need KVM.
+pub fn check(emulated: bool, networks: Option<&TenantNetworks>) -> Result<(), String> {
+    if !emulated {
+        check_kvm()?;
+    }
+    check_artifact_cache()?;
+    check_tenant_networks(networks)
+}
+
+/// Run every health check and return all their failures.
+pub fn problems(emulated: bool, networks: Option<&TenantNetworks>) -> Vec<String> {
+    [
+        (!emulated).then(check_kvm),
+        Some(check_artifact_cache()),
+        Some(check_tenant_networks(networks)),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(Result::err)
+    .collect()
+}
+
+/// Periodically evaluate the orchestrator health and publish it through the
+/// standard `grpc.health.v1.Health` service.
+pub async fn report_health(
+    mut reporter: HealthReporter,
+    emulated: bool,
+    networks: Option<&TenantNetworks>,
+) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut last_result: Option<Result<(), String>> = None;
+
+    loop {
+        interval.tick().await;
+        let result = check(emulated, networks);
+
+        if last_result.as_ref() != Some(&result) {
+            match &result {
+                Ok(_) => {
+                    info!("Orchestrator is serving");
+                    reporter.set_serving::<VmmServiceServer<VmmService>>().await;
+                }
+                Err(e) => {
+                    warn!(reason = %e, "Orchestrator is not serving");
+                    reporter
+                        .set_not_serving::<VmmServiceServer<VmmService>>()
+                        .await;
+                }
+            }
+        }
+
+        last_result = Some(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_tenant_networks() {
+        assert!(check_tenant_networks(None).is_ok());
+        let networks = TenantNetworks::fake();
+        for tenant in 1..MAX_TENANT_NETWORKS {
+            networks.network(&tenant.to_string()).unwrap();
+        }
+        assert!(check_tenant_networks(Some(&networks)).is_ok());
+
+        // The orchestrator isn't serving once a new tenant can't get a network.
+        networks.network("last").unwrap();
+        let problem = check_tenant_networks(Some(&networks)).unwrap_err();
+        assert!(problem.contains("tenant networks"));
+        assert!(problems(true, Some(&networks)).contains(&problem));
+    }
+}
@@ -0,0 +1,220 @@
+//! History of the recent runs of the orchestrator, with their labels, listed by `ListRuns`.
+//!
+//! A run is recorded when its VM is scheduled, or when a warm VM serves it, and ended by the
+//! `RUN_FINISHED`, `RUN_FAILED` or `RUN_RETRIED` event of its VM. The events of its VM are kept
+//! with it, for the debug bundles. Only the last [`MAX_RUNS`] runs are kept, in memory.
+
+use crate::grpc::{
+    deterministic::{system_clock, SharedClock},
+    events::EventBus,
+};
+use shared_models::labels::LabelSelector;
+use shared_models::vmmorchestrator::{ListRunsRequest, RunRecord, VmEvent, VmEventKind};
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Runs kept in the history, the oldest dropped first.
+pub const MAX_RUNS: usize = 1000;
+/// Events kept for each run, the first ones.
+pub const MAX_EVENTS_PER_RUN: usize = 100;
+
+struct Entry {
+    record: RunRecord,
+    events: Vec<VmEvent>,
+}
+
+pub struct RunHistory {
+    runs: Mutex<VecDeque<Entry>>,
+    clock: SharedClock,
+}
+
+impl Default for RunHistory {
+    fn default() -> Self {
+        Self::new(system_clock())
+    }
+}
+
+impl RunHistory {
+    /// A history timestamping the runs with `clock`.
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            runs: Mutex::default(),
+            clock,
+        }
+    }
+
+    /// Record the start of a run on the VM `run_id`.
+    pub fn start(
+        &self,
+        run_id: &str,
+        workload_name: &str,
+        tenant: &str,
+        labels: &HashMap<String, String>,
+    ) {
+        let mut runs = self.runs.lock().unwrap();
+        if runs.len() == MAX_RUNS {
+            runs.pop_front();
+        }
+        runs.push_back(Entry {
+            record: RunRecord {
+                run_id: run_id.to_string(),
+                workload_name: workload_name.to_string(),
+                tenant: tenant.to_string(),
+                labels: labels.clone(),
+                started_at: self.clock.unix_secs(),
+                finished_at: 0,
+                outcome: "running".to_string(),
+                message: String::new(),
+                emulated: false,
+            },
+            events: Vec::new(),
+        });
+    }
+
+    /// Keep `event` with the last run of its VM, ending the run if the event does.
+    pub fn record(&self, event: &VmEvent) {
+        let mut runs = self.runs.lock().unwrap();
+        let Some(entry) = runs
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.record.run_id == event.vm_id)
+        else {
+            return;
+        };
+        if entry.events.len() < MAX_EVENTS_PER_RUN {
+            entry.events.push(event.clone());
+        }
+
+        let outcome = match event.kind() {
+            VmEventKind::RunFinished => "finished",
+            VmEventKind::RunFailed => "failed",
+            VmEventKind::RunRetried => "retried",
+            _ => return,
+        };
+        let run = &mut entry.record;
+        if run.finished_at == 0 {
+            run.finished_at = self.clock.unix_secs();
+            run.outcome = outcome.to_string();
+            run.message = event.message.clone();
+        }
+    }
+
+    /// Keep the events of the runs, forever.
+    pub async fn track(&self, events: &EventBus) {
+        let mut receiver = events.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(event) => self.record(&event),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+
+    /// The last run of the VM `run_id`, and the events of its VM.
+    pub fn get(&self, run_id: &str) -> Option<(RunRecord, Vec<VmEvent>)> {
+        let runs = self.runs.lock().unwrap();
+        runs.iter()
+            .rev()
+            .find(|entry| entry.record.run_id == run_id)
+            .map(|entry| (entry.record.clone(), entry.events.clone()))
+    }
+
+    /// The runs matching `request`, the most recent first.
+    pub fn list(&self, request: &ListRunsRequest) -> Result<Vec<RunRecord>, String> {
+        let selector = LabelSelector::from_str(&request.selector)?;
+        let limit = match request.limit {
+            0 => MAX_RUNS,
+            limit => limit as usize,
+        };
+        let runs = self.runs.lock().unwrap();
+        Ok(runs
+            .iter()
+            .rev()
+            .map(|entry| &entry.record)
+            .filter(|run| {
+                request.workload_name.is_empty() || run.workload_name == request.workload_name
+            })
+            .filter(|run| selector.matches(&run.labels))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::deterministic::{FixedClock, FIXED_EPOCH_SECS};
+    use std::{sync::Arc, time::Duration};
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_select_runs() {
+        let history = RunHistory::default();
+        history.start(
+            "vm-1",
+            "build",
+            "",
+            &labels(&[("team", "infra"), ("env", "staging")]),
+        );
+        history.start("vm-2", "build", "", &labels(&[("team", "infra")]));
+        history.start("vm-3", "train", "", &labels(&[("team", "ml"), ("gpu", "")]));
+        history.record(&VmEvent {
+            vm_id: "vm-1".to_string(),
+            kind: VmEventKind::RunFailed as i32,
+            message: "exit code 1".to_string(),
+            ..Default::default()
+        });
+
+        let list = |selector: &str, workload_name: &str, limit: u32| {
+            history
+                .list(&ListRunsRequest {
+                    selector: selector.to_string(),
+                    workload_name: workload_name.to_string(),
+                    limit,
+                })
+                .map(|runs| runs.into_iter().map(|run| run.run_id).collect::<Vec<_>>())
+        };
+        assert_eq!(list("", "", 0).unwrap(), ["vm-3", "vm-2", "vm-1"]);
+        assert_eq!(list("team=infra,env=staging", "", 0).unwrap(), ["vm-1"]);
+        assert_eq!(list("team=infra,env!=staging", "", 0).unwrap(), ["vm-2"]);
+        assert_eq!(list("gpu", "", 0).unwrap(), ["vm-3"]);
+        assert_eq!(list("!gpu", "build", 1).unwrap(), ["vm-2"]);
+        assert!(list("team=in fra", "", 0).is_err());
+
+        let (run, events) = history.get("vm-1").unwrap();
+        assert_eq!(run.outcome, "failed");
+        assert_eq!(run.message, "exit code 1");
+        assert_eq!(events.len(), 1);
+        assert_eq!(history.get("vm-2").unwrap().0.outcome, "running");
+        assert!(history.get("vm-4").is_none());
+    }
+
+    #[test]
+    fn test_fixed_clock_timestamps() {
+        let clock = Arc::new(FixedClock::default());
+        let history = RunHistory::new(clock.clone());
+        history.start("vm-1", "build", "", &HashMap::new());
+        clock.advance(Duration::from_secs(30));
+        history.record(&VmEvent {
+            vm_id: "vm-1".to_string(),
+            kind: VmEventKind::RunFinished as i32,
+            ..Default::default()
+        });
+
+        let run = history.get("vm-1").unwrap().0;
+        assert_eq!(
+            (run.started_at, run.finished_at),
+            (FIXED_EPOCH_SECS, FIXED_EPOCH_SECS + 30)
+        );
+    }
+}
@@ -0,0 +1,633 @@
+//! Hypervisors the orchestrator can start the guests with: the built-in VMM, an external
+//! cloud-hypervisor binary driven through its REST API, for guests needing a device the
+//! built-in VMM lacks, or QEMU emulating the guests without KVM.
+//!
+//! cloud-hypervisor attaches the devices over PCI, so the guest kernel needs virtio-pci and
+//! PVH boot support. It creates the TAP device of the guest and prints the guest console on
+//! its standard output, like the built-in VMM, unless the console is logged to a file.
+//!
+//! With `--emulation-fallback`, an orchestrator which can't open `/dev/kvm`, e.g. on a machine
+//! without nested virtualization, emulates the guests with the TCG of QEMU instead: slowly, but
+//! with the same kernel and initramfs. QEMU runs a `microvm` machine with the virtio-mmio
+//! devices of the built-in VMM, its network through a TAP device the orchestrator attaches to
+//! the bridge of the guest. The runs are labeled as emulated in the status of the orchestrator,
+//! of its VMs and of their history, and in its metrics.
+
+use super::console::ConsoleLog;
+use crate::{
+    core::{
+        cpu_template::CpuTemplate,
+        exit::{VmExit, VmStopper},
+        memory::{GuestMemoryConfig, HugePages},
+        network::{GuestNetwork, GuestTap},
+        placement::CpuPlacement,
+        rate_limiter::IoLimits,
+        stats::VmStats,
+        vmm::VMM,
+    },
+    VmmErrors,
+};
+use serde_json::json;
+use std::{
+    ffi::OsString,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+/// Interval between the checks of the API socket of cloud-hypervisor.
+const API_SOCKET_POLL: Duration = Duration::from_millis(20);
+/// Checks of the API socket before giving up on cloud-hypervisor, for 5s: counted rather than
+/// timed, for the wait not to depend on the clock of the orchestrator.
+const API_SOCKET_POLLS: u32 = 250;
+
+/// QEMU binary emulating the guests, looked up in the `PATH`.
+pub const DEFAULT_QEMU: &str = "qemu-system-x86_64";
+
+/// File descriptor of the TAP device in the QEMU process.
+const QEMU_TAP_FD: i32 = 3;
+
+/// Hypervisor starting the guests.
+#[derive(Debug, Clone, Default)]
+pub enum Hypervisor {
+    #[default]
+    Builtin,
+    CloudHypervisor(CloudHypervisorConfig),
+    Emulated(EmulatorConfig),
+}
+
+impl Hypervisor {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hypervisor::Builtin => "builtin",
+            Hypervisor::CloudHypervisor(_) => "cloud-hypervisor",
+            Hypervisor::Emulated(_) => "qemu-tcg",
+        }
+    }
+
+    /// Whether the guests are emulated rather than run by KVM.
+    pub fn emulated(&self) -> bool {
+        matches!(self, Hypervisor::Emulated(_))
+    }
+
+    /// Whether the guests have a PCI bus, for the devices passed through.
+    pub fn has_pci(&self) -> bool {
+        matches!(self, Hypervisor::CloudHypervisor(_))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CloudHypervisorConfig {
+    /// The cloud-hypervisor binary.
+    pub binary: PathBuf,
+    /// Directory of the API sockets of the cloud-hypervisor processes.
+    pub socket_dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct EmulatorConfig {
+    /// The `qemu-system-x86_64` binary.
+    pub binary: PathBuf,
+}
+
+/// Settings of a guest, whatever its hypervisor.
+#[derive(Debug, Clone)]
+pub struct GuestConfig {
+    pub cpus: u8,
+    pub memory_mb: u32,
+    pub kernel: PathBuf,
+    pub initramfs: PathBuf,
+    /// Appended to the command line of the guest kernel.
+    pub kernel_cmdline: Vec<String>,
+    pub network: GuestNetwork,
+    /// Host interface the traffic to the outside goes through, any interface if unset.
+    pub uplink: Option<String>,
+    /// Host PCI devices passed through, by their sysfs directory. Only cloud-hypervisor has a
+    /// PCI bus for them.
+    pub vfio_devices: Vec<PathBuf>,
+    pub placement: Option<CpuPlacement>,
+    pub memory: GuestMemoryConfig,
+    pub nested_virtualization: bool,
+    pub cpu_template: CpuTemplate,
+    /// Backing file and size (in MB) of the virtio-pmem device.
+    pub pmem: Option<(PathBuf, u32)>,
+    pub io_limits: IoLimits,
+    /// Log the console of the guest is written to, rather than the standard output.
+    pub console: Option<ConsoleLog>,
+}
+
+/// A configured guest, ready to run.
+pub enum Guest {
+    Builtin(VMM),
+    CloudHypervisor(CloudHypervisorVm),
+    Emulated(EmulatedVm),
+}
+
+impl Guest {
+    pub async fn create(hypervisor: &Hypervisor, config: GuestConfig) -> Result<Self, VmmErrors> {
+        match hypervisor {
+            Hypervisor::Builtin => {
+                let network = config.network;
+                let mut vmm = VMM::new(network.host_ip, network.netmask, network.guest_ip)
+                    .map_err(VmmErrors::VmmNew)?;
+                vmm.set_bridge(network.bridge, network.isolated);
+                vmm.set_uplink(config.uplink);
+                if !config.vfio_devices.is_empty() {
+                    warn!(devices = ?config.vfio_devices, "The built-in VMM has no PCI bus, the devices aren't passed through");
+                }
+                if let Some(placement) = config.placement {
+                    vmm.set_cpu_placement(placement);
+                }
+                vmm.set_memory_config(config.memory);
+                vmm.set_nested_virtualization(config.nested_virtualization);
+                vmm.set_cpu_template(config.cpu_template);
+                vmm.set_kernel_cmdline(config.kernel_cmdline);
+                vmm.set_io_limits(config.io_limits);
+                if let Some(console) = config.console {
+                    vmm.set_console(Box::new(console))
+                        .map_err(VmmErrors::VmmNew)?;
+                }
+                if let Some((path, size_mb)) = config.pmem {
+                    vmm.set_pmem(path, size_mb);
+                }
+
+                vmm.configure(
+                    config.cpus,
+                    config.memory_mb,
+                    config.kernel,
+                    &Some(config.initramfs),
+                )
+                .await
+                .map_err(VmmErrors::VmmConfigure)?;
+
+                Ok(Guest::Builtin(vmm))
+            }
+            Hypervisor::CloudHypervisor(ch) => {
+                let ch = ch.clone();
+                tokio::task::spawn_blocking(move || CloudHypervisorVm::create(&ch, &config))
+                    .await
+                    .map_err(|e| VmmErrors::VmmHypervisor(io::Error::other(e)))?
+                    .map(Guest::CloudHypervisor)
+                    .map_err(VmmErrors::VmmHypervisor)
+            }
+            Hypervisor::Emulated(emulator) => {
+                let tap = GuestTap::open(&config.network, config.uplink.as_deref())
+                    .await
+                    .map_err(VmmErrors::VmmHypervisor)?;
+                EmulatedVm::create(emulator, &config, tap)
+                    .map(Guest::Emulated)
+                    .map_err(VmmErrors::VmmHypervisor)
+            }
+        }
+    }
+
+    /// Activity counters of the guest, only maintained by the built-in VMM.
+    pub fn stats(&self) -> Arc<VmStats> {
+        match self {
+            Guest::Builtin(vmm) => vmm.stats(),
+            Guest::CloudHypervisor(_) | Guest::Emulated(_) => Arc::default(),
+        }
+    }
+
+    /// Handle stopping the guest from another thread.
+    pub fn stopper(&self) -> GuestStopper {
+        match self {
+            Guest::Builtin(vmm) => GuestStopper::Builtin(vmm.stopper()),
+            Guest::CloudHypervisor(vm) => GuestStopper::Process {
+                pid: vm.process.id(),
+                state: vm.state.clone(),
+            },
+            Guest::Emulated(vm) => GuestStopper::Process {
+                pid: vm.process.id(),
+                state: vm.state.clone(),
+            },
+        }
+    }
+
+    /// Run the guest until it stops, returning why it did.
+    pub fn run(&mut self) -> Result<VmExit, VmmErrors> {
+        match self {
+            Guest::Builtin(vmm) => vmm.run().map_err(VmmErrors::VmmRun),
+            Guest::CloudHypervisor(vm) => vm.run().map_err(VmmErrors::VmmHypervisor),
+            Guest::Emulated(vm) => vm.run().map_err(VmmErrors::VmmHypervisor),
+        }
+    }
+}
+
+/// Stops a running [`Guest`], its run returning [`VmExit::Killed`].
+#[derive(Clone)]
+pub enum GuestStopper {
+    Builtin(VmStopper),
+    /// A cloud-hypervisor or QEMU process.
+    Process {
+        pid: u32,
+        state: Arc<Mutex<ProcessState>>,
+    },
+}
+
+/// State of a cloud-hypervisor or QEMU process, shared with its [`GuestStopper`].
+#[derive(Debug, Default)]
+pub enum ProcessState {
+    #[default]
+    Running,
+    /// Killed for the given reason.
+    Killed(String),
+    /// Reaped, its pid may be reused.
+    Exited,
+}
+
+impl GuestStopper {
+    pub fn stop(&self, reason: &str) {
+        match self {
+            GuestStopper::Builtin(stopper) => stopper.stop(reason),
+            GuestStopper::Process { pid, state } => {
+                let mut state = state.lock().unwrap();
+                if let ProcessState::Running = *state {
+                    *state = ProcessState::Killed(reason.to_string());
+                    // SAFETY: `kill` has no precondition, the process not being reaped yet.
+                    unsafe { libc::kill(*pid as libc::pid_t, libc::SIGKILL) };
+                }
+            }
+        }
+    }
+}
+
+/// A guest of a cloud-hypervisor process, killed when dropped.
+pub struct CloudHypervisorVm {
+    process: Child,
+    socket: PathBuf,
+    state: Arc<Mutex<ProcessState>>,
+}
+
+impl CloudHypervisorVm {
+    fn create(ch: &CloudHypervisorConfig, config: &GuestConfig) -> io::Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let socket = ch
+            .socket_dir
+            .join(format!("cloud-hypervisor-{}.sock", nanos));
+        let mut process = Command::new(&ch.binary)
+            .arg("--api-socket")
+            .arg(format!("path={}", socket.display()))
+            .stdout(match config.console {
+                Some(_) => Stdio::piped(),
+                None => Stdio::inherit(),
+            })
+            .spawn()
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("Could not start {:?}: {}", ch.binary, e))
+            })?;
+        if let (Some(mut stdout), Some(mut console)) =
+            (process.stdout.take(), config.console.clone())
+        {
+            // Until the process exits, closing its standard output.
+            std::thread::spawn(move || io::copy(&mut stdout, &mut console));
+        }
+        let mut vm = Self {
+            process,
+            socket,
+            state: Arc::default(),
+        };
+        info!(pid = vm.process.id(), socket = ?vm.socket, "Started cloud-hypervisor");
+
+        let mut polls = 0;
+        while !vm.socket.exists() {
+            if let Some(status) = vm.process.try_wait()? {
+                return Err(io::Error::other(format!(
+                    "cloud-hypervisor exited: {}",
+                    status
+                )));
+            }
+            if polls == API_SOCKET_POLLS {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "cloud-hypervisor didn't open its API socket",
+                ));
+            }
+            polls += 1;
+            std::thread::sleep(API_SOCKET_POLL);
+        }
+
+        if config.nested_virtualization {
+            warn!("Nested virtualization is left to the cloud-hypervisor defaults");
+        }
+        if let Some(uplink) = &config.uplink {
+            warn!(
+                uplink = uplink.as_str(),
+                "The uplink is left to the routing of the host with cloud-hypervisor"
+            );
+        }
+        if config.cpu_template != CpuTemplate::Host {
+            warn!(template = %config.cpu_template, "The CPU template is left to the cloud-hypervisor defaults");
+        }
+        // Created like the built-in VMM does, cloud-hypervisor only maps existing files.
+        if let Some((path, size_mb)) = &config.pmem {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?
+                .set_len((*size_mb as u64) << 20)?;
+        }
+        vm.put("vm.create", Some(vm_config(config).to_string()))?;
+        vm.put("vm.boot", None)?;
+
+        Ok(vm)
+    }
+
+    fn run(&mut self) -> io::Result<VmExit> {
+        let status = self.process.wait()?;
+        let state = std::mem::replace(&mut *self.state.lock().unwrap(), ProcessState::Exited);
+        if let ProcessState::Killed(reason) = state {
+            return Ok(VmExit::Killed(reason));
+        }
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "cloud-hypervisor exited: {}",
+                status
+            )));
+        }
+
+        // It exits once the guest powered off.
+        Ok(VmExit::Shutdown)
+    }
+
+    /// Send `PUT /api/v1/<endpoint>` to the API socket.
+    fn put(&self, endpoint: &str, body: Option<String>) -> io::Result<()> {
+        let mut stream = UnixStream::connect(&self.socket)?;
+        let body = body.unwrap_or_default();
+        write!(
+            stream,
+            "PUT /api/v1/{} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            endpoint,
+            body.len(),
+            body
+        )?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or_default();
+                }
+            }
+        }
+
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            let mut response = vec![0; content_length];
+            reader.read_exact(&mut response)?;
+            return Err(io::Error::other(format!(
+                "cloud-hypervisor answered {} to {}: {}",
+                status_line.trim(),
+                endpoint,
+                String::from_utf8_lossy(&response)
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for CloudHypervisorVm {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+/// A guest emulated by a QEMU process, killed when dropped.
+pub struct EmulatedVm {
+    process: Child,
+    state: Arc<Mutex<ProcessState>>,
+    /// Closed with the process, for the device to go away.
+    _tap: GuestTap,
+}
+
+impl EmulatedVm {
+    fn create(emulator: &EmulatorConfig, config: &GuestConfig, tap: GuestTap) -> io::Result<Self> {
+        if !config.vfio_devices.is_empty() {
+            warn!(devices = ?config.vfio_devices, "The emulated guests have no PCI bus, the devices aren't passed through");
+        }
+        if config.placement.is_some() {
+            warn!("The vCPUs of the emulated guests aren't pinned to host CPUs");
+        }
+        if config.nested_virtualization {
+            warn!("The emulated guests can't run VMs of their own");
+        }
+        if config.pmem.is_some() {
+            warn!("The emulated guests have no virtio-pmem volume");
+        }
+
+        let mut command = Command::new(&emulator.binary);
+        command
+            .args(qemu_args(config))
+            .stdin(Stdio::null())
+            .stdout(match config.console {
+                Some(_) => Stdio::piped(),
+                None => Stdio::inherit(),
+            });
+        let tap_fd = tap.as_raw_fd();
+        // SAFETY: `dup2` and `fcntl` are async-signal-safe, and the TAP device outlives the
+        // spawn.
+        unsafe {
+            command.pre_exec(move || {
+                // The descriptor left by `dup2` is inherited, unlike the TAP device's own.
+                let ret = match tap_fd {
+                    QEMU_TAP_FD => libc::fcntl(tap_fd, libc::F_SETFD, 0),
+                    _ => libc::dup2(tap_fd, QEMU_TAP_FD),
+                };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut process = command.spawn().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Could not start {:?}: {}", emulator.binary, e),
+            )
+        })?;
+        if let (Some(mut stdout), Some(mut console)) =
+            (process.stdout.take(), config.console.clone())
+        {
+            // Until the process exits, closing its standard output.
+            std::thread::spawn(move || io::copy(&mut stdout, &mut console));
+        }
+        info!(pid = process.id(), tap = %tap.name(), "Started QEMU, emulating the guest");
+
+        Ok(Self {
+            process,
+            state: Arc::default(),
+            _tap: tap,
+        })
+    }
+
+    fn run(&mut self) -> io::Result<VmExit> {
+        let status = self.process.wait()?;
+        let state = std::mem::replace(&mut *self.state.lock().unwrap(), ProcessState::Exited);
+        if let ProcessState::Killed(reason) = state {
+            return Ok(VmExit::Killed(reason));
+        }
+        if !status.success() {
+            return Err(io::Error::other(format!("QEMU exited: {}", status)));
+        }
+
+        // With `-no-reboot`, it exits once the guest powered off or rebooted.
+        Ok(VmExit::Shutdown)
+    }
+}
+
+impl Drop for EmulatedVm {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Command line of QEMU emulating the guest: a `microvm` machine, with the serial console and
+/// the virtio-mmio network device of the built-in VMM. QEMU doesn't enforce its I/O limits.
+fn qemu_args(config: &GuestConfig) -> Vec<OsString> {
+    let mut cmdline = vec![
+        "console=ttyS0".to_string(),
+        "reboot=k".to_string(),
+        "panic=1".to_string(),
+        format!(
+            "ip={}::{}:{}::eth0:off:1.1.1.1",
+            config.network.guest_ip, config.network.host_ip, config.network.netmask
+        ),
+    ];
+    cmdline.extend(config.kernel_cmdline.iter().cloned());
+
+    let mut args: Vec<OsString> = [
+        "-machine",
+        "microvm,accel=tcg,rtc=on",
+        "-cpu",
+        "max",
+        "-nodefaults",
+        "-no-user-config",
+        "-no-reboot",
+        "-display",
+        "none",
+        "-serial",
+        "stdio",
+    ]
+    .into_iter()
+    .map(OsString::from)
+    .collect();
+    args.extend([
+        "-smp".into(),
+        config.cpus.to_string().into(),
+        "-m".into(),
+        format!("{}M", config.memory_mb).into(),
+        "-kernel".into(),
+        config.kernel.clone().into(),
+        "-initrd".into(),
+        config.initramfs.clone().into(),
+        "-append".into(),
+        cmdline.join(" ").into(),
+        "-netdev".into(),
+        format!("tap,id=net0,fd={}", QEMU_TAP_FD).into(),
+        "-device".into(),
+        "virtio-net-device,netdev=net0".into(),
+    ]);
+    // QEMU can't fall back to regular pages, like `on` does.
+    if config.memory.huge_pages == HugePages::Strict {
+        args.extend(["-mem-path".into(), "/dev/hugepages".into()]);
+    }
+    if config.memory.prealloc {
+        args.push("-mem-prealloc".into());
+    }
+
+    args
+}
+
+/// Body of the `vm.create` request.
+fn vm_config(config: &GuestConfig) -> serde_json::Value {
+    let mut cmdline = vec![
+        "console=ttyS0".to_string(),
+        "reboot=k".to_string(),
+        "panic=1".to_string(),
+        format!(
+            "ip={}::{}:{}::eth0:off:1.1.1.1",
+            config.network.guest_ip, config.network.host_ip, config.network.netmask
+        ),
+    ];
+    cmdline.extend(config.kernel_cmdline.iter().cloned());
+
+    let affinity: Vec<_> = config
+        .placement
+        .iter()
+        .flat_map(|placement| {
+            (0..config.cpus).filter_map(|vcpu| {
+                placement
+                    .cpu_for(vcpu as usize)
+                    .map(|cpu| json!({ "vcpu": vcpu, "host_cpus": [cpu] }))
+            })
+        })
+        .collect();
+
+    let mut vm = json!({
+        "cpus": { "boot_vcpus": config.cpus, "max_vcpus": config.cpus },
+        "memory": {
+            "size": (config.memory_mb as u64) << 20,
+            "hugepages": config.memory.huge_pages != HugePages::Off,
+            "prefault": config.memory.prealloc,
+        },
+        "payload": {
+            "kernel": config.kernel,
+            "initramfs": config.initramfs,
+            "cmdline": cmdline.join(" "),
+        },
+        "net": [{
+            "ip": config.network.host_ip.to_string(),
+            "mask": config.network.netmask.to_string(),
+        }],
+        "serial": { "mode": "Tty" },
+        "console": { "mode": "Off" },
+    });
+    if !affinity.is_empty() {
+        vm["cpus"]["affinity"] = affinity.into();
+    }
+    if !config.vfio_devices.is_empty() {
+        vm["devices"] = config
+            .vfio_devices
+            .iter()
+            .map(|path| json!({ "path": path }))
+            .collect();
+    }
+    if let Some((file, size_mb)) = &config.pmem {
+        vm["pmem"] = json!([{ "file": file, "size": (*size_mb as u64) << 20 }]);
+    }
+    // Buckets refilled in a second, like the ones of the built-in VMM. cloud-hypervisor has no
+    // limiter on its pmem devices, the flushes of the disk aren't throttled.
+    let bucket = |rate: u64| (rate > 0).then(|| json!({ "size": rate, "refill_time": 1000 }));
+    let limits = &config.io_limits;
+    let (bandwidth, ops) = (
+        bucket(limits.net_bytes_per_sec),
+        bucket(limits.net_packets_per_sec),
+    );
+    if bandwidth.is_some() || ops.is_some() {
+        vm["net"][0]["rate_limiter_config"] = json!({ "bandwidth": bandwidth, "ops": ops });
+    }
+
+    vm
+}
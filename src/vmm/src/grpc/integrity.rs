@@ -0,0 +1,269 @@
+//! Manifest of the checksums of the kernel and rootfs artifacts, checked before each boot.
+//!
+//! `tools/artifacts.json` records the sha256, the size and the source of every artifact the
+//! orchestrators of the host built, fetched or pulled, by its path under `tools`, e.g.
+//! `rootfs/python.img`. An artifact which no longer matches its entry, e.g. a cache corrupted by
+//! a full disk or a bad sector, is discarded to be fetched or built again rather than booted.
+//! The artifacts which predate the manifest are adopted: their checksum is recorded as is.
+
+use super::artifacts;
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Path of the manifest, relative to the working directory.
+pub const MANIFEST_PATH: &str = "tools/artifacts.json";
+
+/// Directory the names of the artifacts are relative to.
+const ARTIFACT_ROOT: &str = "tools";
+
+/// Where an artifact comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArtifactSource {
+    /// Built by an orchestrator of the host.
+    Built,
+    /// Fetched from the shared storage.
+    Storage,
+    /// Pulled from the rootfs registry, with the digest of its manifest.
+    Registry(String),
+    /// Already in the cache when the manifest didn't know it.
+    Adopted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Hex digest of the content of the artifact.
+    pub sha256: String,
+    pub size: u64,
+    pub source: ArtifactSource,
+}
+
+#[derive(Debug)]
+pub enum IntegrityError {
+    Io(PathBuf, io::Error),
+    Manifest(PathBuf, serde_json::Error),
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::Io(path, e) => write!(f, "Failed to access {:?}: {}", path, e),
+            IntegrityError::Manifest(path, e) => {
+                write!(f, "Invalid artifact manifest {:?}: {}", path, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Outcome of the check of an artifact against the manifest.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Checked {
+    /// There is no artifact to check.
+    Missing,
+    /// It matches its entry.
+    Intact,
+    /// The manifest didn't know it, its checksum was recorded.
+    Adopted,
+    /// It doesn't match `expected`, being of `size` bytes and of digest `sha256`.
+    Corrupted {
+        expected: ManifestEntry,
+        sha256: String,
+        size: u64,
+    },
+}
+
+/// Digest and size of the file at `path`.
+fn checksum(path: &Path) -> io::Result<(String, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+/// Manifest of the artifacts under a working directory.
+#[derive(Debug, Clone)]
+pub struct ArtifactManifest {
+    root: PathBuf,
+}
+
+impl ArtifactManifest {
+    /// Manifest of the artifacts of the working directory `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.root.join(MANIFEST_PATH)
+    }
+
+    /// Name of the artifact at `path` in the manifest, its path under `tools`.
+    pub fn name(&self, path: &Path) -> String {
+        path.strip_prefix(self.root.join(ARTIFACT_ROOT))
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Entries of the manifest, by name, none while it doesn't exist.
+    pub fn entries(&self) -> Result<BTreeMap<String, ManifestEntry>, IntegrityError> {
+        let path = self.path();
+        match fs::read(&path) {
+            Ok(content) => {
+                serde_json::from_slice(&content).map_err(|e| IntegrityError::Manifest(path, e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(IntegrityError::Io(path, e)),
+        }
+    }
+
+    /// Change the entries with `change`, holding the lock of the manifest so that concurrent
+    /// orchestrators don't lose each other's changes.
+    fn update<T>(
+        &self,
+        change: impl FnOnce(&mut BTreeMap<String, ManifestEntry>) -> T,
+    ) -> Result<T, IntegrityError> {
+        let path = self.path();
+        let io_error = |e| IntegrityError::Io(path.clone(), e);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        let lock_file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(artifacts::lock_path(&path))
+            .map_err(io_error)?;
+        let _lock = Flock::lock(lock_file, FlockArg::LockExclusive)
+            .map_err(|(_, errno)| io_error(io::Error::from(errno)))?;
+
+        let mut entries = self.entries()?;
+        let result = change(&mut entries);
+        let content = serde_json::to_vec_pretty(&entries)
+            .map_err(|e| IntegrityError::Manifest(path.clone(), e))?;
+        // Written aside then renamed, so that a crash never leaves half a manifest.
+        let tmp_path = path.with_extension(format!("json.tmp-{}", std::process::id()));
+        fs::write(&tmp_path, content)
+            .and_then(|()| fs::rename(&tmp_path, &path))
+            .map_err(io_error)?;
+
+        Ok(result)
+    }
+
+    /// Record the checksum of the artifact at `path`, which comes from `source`.
+    pub fn record(
+        &self,
+        path: &Path,
+        source: ArtifactSource,
+    ) -> Result<ManifestEntry, IntegrityError> {
+        let (sha256, size) = checksum(path).map_err(|e| IntegrityError::Io(path.into(), e))?;
+        let entry = ManifestEntry {
+            sha256,
+            size,
+            source,
+        };
+        self.update(|entries| entries.insert(self.name(path), entry.clone()))?;
+        Ok(entry)
+    }
+
+    /// Forget the artifact at `path`, e.g. once it's pruned.
+    pub fn forget(&self, path: &Path) -> Result<(), IntegrityError> {
+        let name = self.name(path);
+        if !self.entries()?.contains_key(&name) {
+            return Ok(());
+        }
+        self.update(|entries| {
+            entries.remove(&name);
+        })
+    }
+
+    /// Check the artifact at `path` against its entry, adopting it if it has none.
+    pub fn check(&self, path: &Path) -> Result<Checked, IntegrityError> {
+        let (sha256, size) = match checksum(path) {
+            Ok(checksum) => checksum,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Checked::Missing),
+            Err(e) => return Err(IntegrityError::Io(path.into(), e)),
+        };
+        let name = self.name(path);
+        match self.entries()?.remove(&name) {
+            Some(expected) if expected.sha256 == sha256 && expected.size == size => {
+                Ok(Checked::Intact)
+            }
+            Some(expected) => Ok(Checked::Corrupted {
+                expected,
+                sha256,
+                size,
+            }),
+            None => {
+                let entry = ManifestEntry {
+                    sha256,
+                    size,
+                    source: ArtifactSource::Adopted,
+                };
+                // Another orchestrator may have recorded it meanwhile, its entry is kept then.
+                self.update(|entries| {
+                    entries.entry(name).or_insert(entry);
+                })?;
+                Ok(Checked::Adopted)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_artifacts() {
+        let root = std::env::temp_dir().join(format!("vmm-integrity-{}", std::process::id()));
+        fs::create_dir_all(root.join("tools/rootfs")).unwrap();
+        let manifest = ArtifactManifest::new(&root);
+        let artifact = root.join("tools/rootfs/python.img");
+        assert_eq!(manifest.check(&artifact).unwrap(), Checked::Missing);
+
+        fs::write(&artifact, b"initramfs").unwrap();
+        assert_eq!(manifest.check(&artifact).unwrap(), Checked::Adopted);
+        assert_eq!(manifest.check(&artifact).unwrap(), Checked::Intact);
+        let entry = manifest
+            .record(
+                &artifact,
+                ArtifactSource::Registry("sha256:1234".to_string()),
+            )
+            .unwrap();
+        assert_eq!(entry.size, 9);
+        assert_eq!(
+            manifest.entries().unwrap().get("rootfs/python.img"),
+            Some(&entry)
+        );
+
+        // A single flipped byte is caught, even though the size didn't change.
+        fs::write(&artifact, b"initramfS").unwrap();
+        match manifest.check(&artifact).unwrap() {
+            Checked::Corrupted {
+                expected,
+                sha256,
+                size,
+            } => {
+                assert_eq!(expected, entry);
+                assert_eq!(size, 9);
+                assert_ne!(sha256, entry.sha256);
+            }
+            checked => panic!("expected the artifact to be corrupted, got {:?}", checked),
+        }
+
+        manifest.forget(&artifact).unwrap();
+        assert!(manifest.entries().unwrap().is_empty());
+        assert_eq!(manifest.check(&artifact).unwrap(), Checked::Adopted);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use super::admin::VmTable;
+use super::integrity::ArtifactManifest;
+use super::kernels::BUILTIN_KERNEL;
+use super::registry;
+use super::server::{initramfs_path, KERNEL_PATH};
+use super::signatures;
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info, warn};
+
+/// Delay between two automatic prunes.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Directory holding the initramfs images, relative to the working directory.
+const ROOTFS_DIR: &str = "tools/rootfs";
+
+#[derive(Debug, Clone)]
+pub struct JanitorConfig {
+    /// Artifacts which haven't been used for this long are pruned.
+    pub max_age: Duration,
+    /// Disk usage ratio above which the least recently used artifacts are pruned...
+    pub high_watermark: f64,
+    /// ...until the usage goes back below this ratio.
+    pub low_watermark: f64,
+}
+
+impl Default for JanitorConfig {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(7 * 24 * 60 * 60),
+            high_watermark: 0.90,
+            low_watermark: 0.80,
+        }
+    }
+}
+
+/// Counters of everything the janitor did since the orchestrator started.
+#[derive(Debug, Default)]
+pub struct JanitorStats {
+    pub runs: AtomicU64,
+    pub pruned: AtomicU64,
+    pub freed_bytes: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Artifact {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub pruned: Vec<(PathBuf, u64)>,
+    pub freed_bytes: u64,
+    /// Disk usage ratio once the prune is done, if it could be measured.
+    pub disk_usage: Option<f64>,
+}
+
+/// Removes the kernel and initramfs artifacts that are no longer used, to keep the host disk from filling up.
+pub struct Janitor {
+    root: PathBuf,
+    vms: VmTable,
+    config: JanitorConfig,
+    stats: JanitorStats,
+}
+
+/// Record that `path` was just used, so that it's pruned last.
+pub fn mark_used(path: &Path) {
+    let result = File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+
+    if let Err(e) = result {
+        warn!(path = ?path, reason = %e, "Could not mark artifact as used");
+    }
+}
+
+/// Ratio of the space used on the filesystem holding `path`.
+fn disk_usage(path: &Path) -> io::Result<f64> {
+    let stat = nix::sys::statvfs::statvfs(path).map_err(io::Error::from)?;
+    let total = stat.blocks() as f64;
+    if total == 0.0 {
+        return Ok(0.0);
+    }
+
+    Ok(1.0 - stat.blocks_available() as f64 / total)
+}
+
+impl Janitor {
+    pub fn new(root: PathBuf, vms: VmTable, config: JanitorConfig) -> Self {
+        Self {
+            root,
+            vms,
+            config,
+            stats: JanitorStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &JanitorStats {
+        &self.stats
+    }
+
+    fn kernel_path(&self) -> PathBuf {
+        let mut path = self.root.clone().into_os_string();
+        path.push(KERNEL_PATH);
+        PathBuf::from(path)
+    }
+
+    /// List the artifacts, least recently used first.
+    fn artifacts(&self) -> Vec<Artifact> {
+        let mut paths = vec![self.kernel_path()];
+        if let Ok(entries) = fs::read_dir(self.root.join(ROOTFS_DIR)) {
+            paths.extend(
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "img")),
+            );
+        }
+
+        let mut artifacts: Vec<Artifact> = paths
+            .into_iter()
+            .filter_map(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+                Some(Artifact {
+                    size: metadata.len(),
+                    last_used: metadata.modified().ok()?,
+                    path,
+                })
+            })
+            .collect();
+
+        artifacts.sort_by_key(|artifact| artifact.last_used);
+        artifacts
+    }
+
+    /// Artifacts needed by the VMs currently running, which must not be pruned.
+    fn in_use(&self) -> Vec<PathBuf> {
+        let vms = self.vms.list();
+        let mut paths: Vec<PathBuf> = vms
+            .iter()
+            .map(|vm| {
+                let version = Some(vm.runtime_version.as_str()).filter(|v| !v.is_empty());
+                initramfs_path(self.root.as_os_str(), &vm.language, version)
+            })
+            .collect();
+        if vms.iter().any(|vm| vm.kernel == BUILTIN_KERNEL) {
+            paths.push(self.kernel_path());
+        }
+
+        paths
+    }
+
+    /// Prune the artifacts unused for longer than the configured age, then the least recently
+    /// used ones while the disk usage is above the high watermark. With `all`, every unused artifact is pruned.
+    pub fn prune(&self, all: bool) -> PruneReport {
+        let in_use = self.in_use();
+        let manifest = ArtifactManifest::new(&self.root);
+        let now = SystemTime::now();
+        let mut report = PruneReport::default();
+        let mut over_watermark = disk_usage(&self.root)
+            .map(|usage| usage > self.config.high_watermark)
+            .unwrap_or(false);
+
+        for artifact in self.artifacts() {
+            if in_use.contains(&artifact.path) {
+                continue;
+            }
+
+            let expired = now
+                .duration_since(artifact.last_used)
+                .is_ok_and(|age| age > self.config.max_age);
+            if !(all || expired || over_watermark) {
+                continue;
+            }
+
+            match fs::remove_file(&artifact.path) {
+                Ok(()) => {
+                    // Forget where a pulled image came from, its signature and its checksum,
+                    // along with it.
+                    let _ = fs::remove_file(registry::source_path(&artifact.path));
+                    let _ = fs::remove_file(signatures::signature_path(&artifact.path));
+                    if let Err(e) = manifest.forget(&artifact.path) {
+                        warn!(path = ?artifact.path, reason = %e, "Could not update the artifact manifest");
+                    }
+                    info!(path = ?artifact.path, size = artifact.size, "Pruned artifact");
+                    report.freed_bytes += artifact.size;
+                    report.pruned.push((artifact.path, artifact.size));
+                }
+                Err(e) => error!(path = ?artifact.path, reason = %e, "Could not prune artifact"),
+            }
+
+            if over_watermark {
+                over_watermark = disk_usage(&self.root)
+                    .map(|usage| usage > self.config.low_watermark)
+                    .unwrap_or(false);
+            }
+        }
+
+        report.disk_usage = disk_usage(&self.root).ok();
+
+        self.stats.runs.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .pruned
+            .fetch_add(report.pruned.len() as u64, Ordering::Relaxed);
+        self.stats
+            .freed_bytes
+            .fetch_add(report.freed_bytes, Ordering::Relaxed);
+
+        report
+    }
+
+    /// Periodically prune the artifacts.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let janitor = self.clone();
+            match tokio::task::spawn_blocking(move || janitor.prune(false)).await {
+                Ok(report) => info!(
+                    pruned = report.pruned.len(),
+                    freed_bytes = report.freed_bytes,
+                    disk_usage = ?report.disk_usage,
+                    "Artifacts pruned"
+                ),
+                Err(e) => error!("Janitor task failed: {:?}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Guest kernels the orchestrator can boot, selected by name in the run requests.
+//!
+//! Besides the kernel built from `tools/kernel`, kernels are registered in a TOML file:
+//!
+//! ```toml
+//! default = "lts"
+//!
+//! [[kernel]]
+//! name = "lts"
+//! version = "6.1"
+//! path = "/var/lib/cloudlet/kernels/vmlinux-6.1"
+//! cmdline = ["mitigations=off"]
+//! # Either list the features of the kernel, or give the `.config` it was built from.
+//! config = "/var/lib/cloudlet/kernels/config-6.1"
+//! ```
+
+use serde::Deserialize;
+use std::{
+    collections::BTreeSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Name of the kernel built from `tools/kernel`.
+pub const BUILTIN_KERNEL: &str = "builtin";
+
+/// Configuration of the built-in kernel, relative to the working directory.
+pub const BUILTIN_KERNEL_CONFIG: &str = "tools/kernel/linux-config-x86_64";
+
+/// Features a guest kernel may provide, with the kernel option enabling each of them.
+pub const FEATURES: [(&str, &str); 6] = [
+    ("virtio-mmio", "CONFIG_VIRTIO_MMIO"),
+    ("virtio-net", "CONFIG_VIRTIO_NET"),
+    ("virtio-pmem", "CONFIG_VIRTIO_PMEM"),
+    ("initramfs", "CONFIG_BLK_DEV_INITRD"),
+    ("kvm", "CONFIG_KVM"),
+    ("modules", "CONFIG_MODULES"),
+];
+
+/// Features every guest needs: the agent is loaded from an initramfs and reached over
+/// a virtio-net interface.
+pub const REQUIRED_FEATURES: [&str; 3] = ["virtio-mmio", "virtio-net", "initramfs"];
+
+#[derive(Debug)]
+pub enum KernelsError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    UnknownFeature { kernel: String, feature: String },
+    DuplicateName(String),
+    UnknownDefault(String),
+}
+
+impl fmt::Display for KernelsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelsError::Read(path, e) => write!(f, "Failed to read {:?}: {}", path, e),
+            KernelsError::Parse(path, e) => write!(f, "Invalid kernels file {:?}: {}", path, e),
+            KernelsError::UnknownFeature { kernel, feature } => write!(
+                f,
+                "Unknown feature {} of kernel {}, expected one of: {}",
+                feature,
+                kernel,
+                FEATURES.map(|(name, _)| name).join(", ")
+            ),
+            KernelsError::DuplicateName(name) => write!(f, "Kernel {} is registered twice", name),
+            KernelsError::UnknownDefault(name) => {
+                write!(f, "The default kernel {} isn't registered", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KernelsError {}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct KernelsFile {
+    default: Option<String>,
+    #[serde(default, rename = "kernel")]
+    kernels: Vec<KernelEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct KernelEntry {
+    name: String,
+    #[serde(default)]
+    version: String,
+    path: PathBuf,
+    #[serde(default)]
+    cmdline: Vec<String>,
+    features: Option<Vec<String>>,
+    config: Option<PathBuf>,
+}
+
+/// A kernel guests can boot.
+#[derive(Debug, Clone)]
+pub struct Kernel {
+    pub name: String,
+    pub version: String,
+    /// Path of the image, `None` for the built-in kernel, which is built on first use.
+    pub path: Option<PathBuf>,
+    /// Parameters appended to the command line of the guests.
+    pub cmdline: Vec<String>,
+    /// Features of the kernel, `None` if they are unknown and can't be checked.
+    pub features: Option<BTreeSet<String>>,
+}
+
+impl Kernel {
+    /// Features among `required` that the kernel is known to lack.
+    pub fn missing_features<'a>(&self, required: &[&'a str]) -> Vec<&'a str> {
+        match &self.features {
+            Some(features) => required
+                .iter()
+                .filter(|feature| !features.contains(**feature))
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Features enabled by a kernel `.config` file.
+pub fn features_from_config(config: &str) -> BTreeSet<String> {
+    let enabled: BTreeSet<&str> = config
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(_, value)| matches!(value.trim(), "y" | "m"))
+        .map(|(option, _)| option.trim())
+        .collect();
+
+    FEATURES
+        .iter()
+        .filter(|(_, option)| enabled.contains(option))
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// The kernels registered in the orchestrator.
+#[derive(Debug, Clone)]
+pub struct KernelRegistry {
+    kernels: Vec<Kernel>,
+    default: String,
+}
+
+impl Default for KernelRegistry {
+    fn default() -> Self {
+        Self {
+            kernels: vec![Kernel {
+                name: BUILTIN_KERNEL.to_string(),
+                version: String::new(),
+                path: None,
+                cmdline: Vec::new(),
+                features: None,
+            }],
+            default: BUILTIN_KERNEL.to_string(),
+        }
+    }
+}
+
+impl KernelRegistry {
+    /// Register the built-in kernel, whose features are read from `builtin_config` if it
+    /// exists, and the kernels of the `kernels_file`, if any.
+    pub fn load(builtin_config: &Path, kernels_file: Option<&Path>) -> Result<Self, KernelsError> {
+        let mut registry = Self::default();
+        if let Ok(config) = fs::read_to_string(builtin_config) {
+            registry.kernels[0].features = Some(features_from_config(&config));
+        }
+
+        let Some(kernels_file) = kernels_file else {
+            return Ok(registry);
+        };
+        let content = fs::read_to_string(kernels_file)
+            .map_err(|e| KernelsError::Read(kernels_file.to_path_buf(), e))?;
+        let file: KernelsFile = toml::from_str(&content)
+            .map_err(|e| KernelsError::Parse(kernels_file.to_path_buf(), e))?;
+
+        for entry in file.kernels {
+            if registry.get(&entry.name).is_some() {
+                return Err(KernelsError::DuplicateName(entry.name));
+            }
+
+            let features = match (entry.features, entry.config) {
+                (Some(features), _) => {
+                    if let Some(feature) = features
+                        .iter()
+                        .find(|feature| !FEATURES.iter().any(|(name, _)| name == *feature))
+                    {
+                        return Err(KernelsError::UnknownFeature {
+                            kernel: entry.name,
+                            feature: feature.clone(),
+                        });
+                    }
+                    Some(features.into_iter().collect())
+                }
+                (None, Some(config)) => {
+                    let config =
+                        fs::read_to_string(&config).map_err(|e| KernelsError::Read(config, e))?;
+                    Some(features_from_config(&config))
+                }
+                (None, None) => None,
+            };
+
+            registry.kernels.push(Kernel {
+                name: entry.name,
+                version: entry.version,
+                path: Some(entry.path),
+                cmdline: entry.cmdline,
+                features,
+            });
+        }
+
+        if let Some(default) = file.default {
+            if registry.get(&default).is_none() {
+                return Err(KernelsError::UnknownDefault(default));
+            }
+            registry.default = default;
+        }
+
+        Ok(registry)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Kernel> {
+        self.kernels.iter().find(|kernel| kernel.name == name)
+    }
+
+    /// The kernel named `name`, or the default one if `name` is empty.
+    pub fn select(&self, name: &str) -> Option<&Kernel> {
+        match name {
+            "" => self.get(&self.default),
+            name => self.get(name),
+        }
+    }
+
+    pub fn kernels(&self) -> &[Kernel] {
+        &self.kernels
+    }
+
+    pub fn default_name(&self) -> &str {
+        &self.default
+    }
+}
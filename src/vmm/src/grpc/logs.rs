@@ -0,0 +1,281 @@
+use prost::Message;
+use regex::bytes::Regex;
+use shared_models::cloudlet::agent::execute_response::Stage;
+use shared_models::cloudlet::agent::ExecuteResponse;
+use shared_models::vmmorchestrator::LogStream;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{broadcast, mpsc};
+use tonic::Status;
+
+/// Output messages kept for each run, older ones are dropped first.
+const MAX_BUFFERED_MESSAGES: usize = 10_000;
+
+/// Finished runs whose output is kept, so it can still be read once the VM stopped.
+const MAX_FINISHED_RUNS: usize = 32;
+
+/// Output of the runs started by the orchestrator, oldest first.
+#[derive(Clone, Default)]
+pub struct LogStore {
+    runs: Arc<Mutex<VecDeque<Arc<RunLogs>>>>,
+}
+
+impl LogStore {
+    /// Start buffering the output of the VM `id`, running `workload_name`.
+    pub fn insert(&self, id: &str, workload_name: &str) -> Arc<RunLogs> {
+        let logs = Arc::new(RunLogs::new(id, workload_name));
+        let mut runs = self.runs.lock().unwrap();
+        runs.push_back(logs.clone());
+
+        let mut finished = runs.iter().filter(|logs| logs.is_done()).count();
+        runs.retain(|logs| {
+            if finished > MAX_FINISHED_RUNS && logs.is_done() {
+                finished -= 1;
+                return false;
+            }
+            true
+        });
+
+        logs
+    }
+
+    /// Find a run by VM id, or the last run of a workload by its name.
+    pub fn get(&self, vm: &str) -> Option<Arc<RunLogs>> {
+        let runs = self.runs.lock().unwrap();
+        runs.iter()
+            .find(|logs| logs.vm_id == vm)
+            .or_else(|| runs.iter().rev().find(|logs| logs.workload_name == vm))
+            .cloned()
+    }
+}
+
+/// Messages of a run streamed to a client.
+#[derive(Debug, Default)]
+pub struct LogFilter {
+    /// Only the messages received from then, in milliseconds since the Unix epoch. The
+    /// messages of older orchestrators, without timestamp, are kept.
+    pub since_ms: u64,
+    /// Only the lines of output matching it.
+    pub grep: Option<Regex>,
+    pub stream: LogStream,
+}
+
+impl LogFilter {
+    /// `message` with only the output matching the filter, `None` if none of it does.
+    pub fn apply(&self, mut message: ExecuteResponse) -> Option<ExecuteResponse> {
+        if message.timestamp_ms != 0 && message.timestamp_ms < self.since_ms {
+            return None;
+        }
+        match self.stream {
+            LogStream::AllStreams => {}
+            LogStream::Stdout => message.stderr = None,
+            LogStream::Stderr => message.stdout = None,
+        }
+        if let Some(grep) = &self.grep {
+            let matching = |output: Vec<u8>| {
+                let lines: Vec<u8> = output
+                    .split_inclusive(|b| *b == b'\n')
+                    .filter(|line| grep.is_match(line))
+                    .flatten()
+                    .copied()
+                    .collect();
+                (!lines.is_empty()).then_some(lines)
+            };
+            message.stdout = message.stdout.and_then(matching);
+            message.stderr = message.stderr.and_then(matching);
+        }
+
+        let has_output = [&message.stdout, &message.stderr]
+            .into_iter()
+            .any(|output| output.as_ref().is_some_and(|output| !output.is_empty()));
+        let is_event = message.marker.is_some()
+            || message.exit_code.is_some()
+            || matches!(message.stage(), Stage::Done | Stage::Failed);
+        (has_output || is_event).then_some(message)
+    }
+}
+
+/// Output of a run, buffered as it is produced so that clients can attach to it later.
+pub struct RunLogs {
+    vm_id: String,
+    workload_name: String,
+    state: Mutex<LogState>,
+    // `None` marks the end of the run.
+    sender: broadcast::Sender<Option<ExecuteResponse>>,
+}
+
+struct LogState {
+    messages: VecDeque<ExecuteResponse>,
+    done: bool,
+}
+
+impl RunLogs {
+    fn new(vm_id: &str, workload_name: &str) -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self {
+            vm_id: vm_id.to_string(),
+            workload_name: workload_name.to_string(),
+            state: Mutex::new(LogState {
+                messages: VecDeque::new(),
+                done: false,
+            }),
+            sender,
+        }
+    }
+
+    /// Buffer `message`, stamped with the time it was received if it isn't yet.
+    pub fn push(&self, mut message: ExecuteResponse) {
+        if message.timestamp_ms == 0 {
+            message.timestamp_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default();
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.messages.len() == MAX_BUFFERED_MESSAGES {
+            state.messages.pop_front();
+        }
+        state.messages.push_back(message.clone());
+        let _ = self.sender.send(Some(message));
+    }
+
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.done = true;
+        let _ = self.sender.send(None);
+    }
+
+    fn is_done(&self) -> bool {
+        self.state.lock().unwrap().done
+    }
+
+    /// Logs of a finished run read back from [`Self::archive`], e.g. by another orchestrator.
+    pub fn from_archive(vm_id: &str, mut archive: &[u8]) -> Result<Self, prost::DecodeError> {
+        let logs = Self::new(vm_id, "");
+        {
+            let mut state = logs.state.lock().unwrap();
+            while !archive.is_empty() {
+                state
+                    .messages
+                    .push_back(ExecuteResponse::decode_length_delimited(&mut archive)?);
+            }
+            state.done = true;
+        }
+
+        Ok(logs)
+    }
+
+    /// The buffered messages, length-delimited, to keep them once the run is forgotten.
+    pub fn archive(&self) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+        let mut archive = Vec::new();
+        for message in &state.messages {
+            message
+                .encode_length_delimited(&mut archive)
+                .expect("a Vec grows as needed");
+        }
+
+        archive
+    }
+
+    /// Send the last `tail` buffered messages matching `filter` (all of them if `tail` is 0)
+    /// to `tx`, then the new ones until the run is done if `follow` is set.
+    pub async fn stream(
+        &self,
+        tail: usize,
+        follow: bool,
+        filter: LogFilter,
+        tx: mpsc::Sender<Result<ExecuteResponse, Status>>,
+    ) {
+        let (messages, done, mut receiver) = {
+            let state = self.state.lock().unwrap();
+            let mut messages: Vec<ExecuteResponse> = state
+                .messages
+                .iter()
+                .cloned()
+                .filter_map(|message| filter.apply(message))
+                .collect();
+            if tail != 0 {
+                messages.drain(..messages.len().saturating_sub(tail));
+            }
+            (messages, state.done, self.sender.subscribe())
+        };
+
+        for message in messages {
+            if tx.send(Ok(message)).await.is_err() {
+                return;
+            }
+        }
+        if done || !follow {
+            return;
+        }
+
+        loop {
+            match receiver.recv().await {
+                Ok(Some(message)) => {
+                    let Some(message) = filter.apply(message) else {
+                        continue;
+                    };
+                    if tx.send(Ok(message)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) | Err(broadcast::error::RecvError::Closed) => return,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_models::cloudlet::agent::StageMarker;
+
+    fn output(stdout: &str, stderr: &str) -> ExecuteResponse {
+        ExecuteResponse {
+            stage: Stage::Running as i32,
+            stdout: Some(stdout.as_bytes().to_vec()),
+            stderr: Some(stderr.as_bytes().to_vec()),
+            timestamp_ms: 2_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = LogFilter {
+            grep: Some(Regex::new("err").unwrap()),
+            ..Default::default()
+        };
+        let message = filter
+            .apply(output("ok\nerror 1\nok\n", "stderr line\n"))
+            .unwrap();
+        assert_eq!(message.stdout.unwrap(), b"error 1\n");
+        assert_eq!(message.stderr.unwrap(), b"stderr line\n");
+        assert!(filter.apply(output("ok\n", "")).is_none());
+        // Without output, the markers are kept.
+        let marker = ExecuteResponse {
+            marker: Some(StageMarker::default()),
+            ..Default::default()
+        };
+        assert!(filter.apply(marker).is_some());
+
+        let filter = LogFilter {
+            stream: LogStream::Stderr,
+            ..Default::default()
+        };
+        let message = filter.apply(output("out\n", "err\n")).unwrap();
+        assert!(message.stdout.is_none());
+        assert!(filter.apply(output("out\n", "")).is_none());
+
+        let filter = LogFilter {
+            since_ms: 3_000,
+            ..Default::default()
+        };
+        assert!(filter.apply(output("out\n", "")).is_none());
+    }
+}
@@ -0,0 +1,192 @@
+//! Maintenance of the host of the orchestrator, through the cordon and the drain of the
+//! `AdminService`.
+//!
+//! A cordoned orchestrator takes no new run nor invocation, which fail with `CLDT-VMM-037`
+//! (`UNAVAILABLE`) for the client to go to another VMM, while the runs in progress go on. The
+//! pools stop their idle VMs rather than keeping them warm, and their busy ones at the end of
+//! their run. A drain cordons the orchestrator and waits for its VM table to be empty, the host
+//! can then be restarted without cutting a run short. The VMs aren't migrated to another host:
+//! a drain which times out can only leave them running, or kill them.
+
+use super::{
+    admin::VmTable,
+    deterministic::{system_clock, SharedClock},
+};
+use shared_models::ErrorCode;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tonic::{Code, Status};
+use tracing::{info, warn};
+
+/// Reason of a cordon which doesn't give one.
+pub const DEFAULT_REASON: &str = "maintenance";
+
+/// Delay between two checks of the VMs left by a drain.
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cordon {
+    pub reason: String,
+    /// Seconds since the Unix epoch.
+    pub since: u64,
+}
+
+/// Outcome of a drain.
+#[derive(Debug, Default)]
+pub struct Drained {
+    /// The VMs still running, past the timeout.
+    pub running: Vec<String>,
+    pub killed: Vec<String>,
+}
+
+/// Whether the orchestrator is cordoned, shared by the services.
+#[derive(Debug)]
+pub struct Maintenance {
+    cordon: Mutex<Option<Cordon>>,
+    /// Wakes the scaling of the pools up when the orchestrator is cordoned.
+    cordoned: Notify,
+    clock: SharedClock,
+}
+
+impl Maintenance {
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            cordon: Mutex::default(),
+            cordoned: Notify::new(),
+            clock,
+        }
+    }
+
+    /// Stop taking new runs for `reason`, keeping the reason and the time of an earlier cordon.
+    pub fn cordon(&self, reason: &str) -> Cordon {
+        let reason = match reason.trim() {
+            "" => DEFAULT_REASON,
+            reason => reason,
+        };
+        let cordon = self
+            .cordon
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| {
+                warn!(reason, "Cordoning the orchestrator, it takes no new run");
+                Cordon {
+                    reason: reason.to_string(),
+                    since: self.clock.unix_secs(),
+                }
+            })
+            .clone();
+        self.cordoned.notify_waiters();
+        cordon
+    }
+
+    pub fn uncordon(&self) {
+        if let Some(cordon) = self.cordon.lock().unwrap().take() {
+            info!(reason = %cordon.reason, "Uncordoned the orchestrator");
+        }
+    }
+
+    pub fn cordoned(&self) -> Option<Cordon> {
+        self.cordon.lock().unwrap().clone()
+    }
+
+    /// Resolve once the orchestrator is cordoned, or cordoned again.
+    pub async fn wait_cordoned(&self) {
+        self.cordoned.notified().await
+    }
+
+    /// Check that the orchestrator takes new runs.
+    pub fn check(&self) -> Result<(), Status> {
+        match self.cordoned() {
+            Some(cordon) => Err(ErrorCode::VmmNodeCordoned.status(
+                Code::Unavailable,
+                format!(
+                    "The orchestrator is cordoned for {}, it takes no new run",
+                    cordon.reason
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Cordon the orchestrator for `reason` and wait for the VMs of `vms` to stop, for at most
+    /// `timeout` if any, then kill those left if `kill`.
+    pub async fn drain(
+        &self,
+        vms: &VmTable,
+        reason: &str,
+        timeout: Option<Duration>,
+        kill: bool,
+    ) -> Drained {
+        let cordon = self.cordon(reason);
+        let started = Instant::now();
+        info!(reason = %cordon.reason, timeout = ?timeout, "Draining the orchestrator");
+        let mut running = vms.list();
+        while !running.is_empty() && !timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            tokio::time::sleep(DRAIN_INTERVAL).await;
+            running = vms.list();
+        }
+
+        let mut drained = Drained::default();
+        for vm in running {
+            if kill {
+                vms.kill(&vm.id, "the orchestrator was drained");
+                drained.killed.push(vm.id);
+            } else {
+                drained.running.push(vm.id);
+            }
+        }
+        info!(
+            elapsed = ?started.elapsed(),
+            running = drained.running.len(),
+            killed = drained.killed.len(),
+            "Drained the orchestrator"
+        );
+        drained
+    }
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self::new(system_clock())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::deterministic::{Deterministic, FIXED_EPOCH_SECS};
+    use shared_models::vmmorchestrator::admin::VmInfo;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_cordon_and_drain() {
+        let mode = Deterministic::new(1);
+        let maintenance = Maintenance::new(mode.clock());
+        assert!(maintenance.check().is_ok());
+
+        let cordon = maintenance.cordon("");
+        assert_eq!(cordon.reason, DEFAULT_REASON);
+        assert_eq!(cordon.since, FIXED_EPOCH_SECS);
+        // Cordoned again, the first reason is kept.
+        assert_eq!(maintenance.cordon("kernel upgrade"), cordon);
+        assert_eq!(maintenance.check().unwrap_err().code(), Code::Unavailable);
+        maintenance.uncordon();
+        assert!(maintenance.check().is_ok());
+
+        let vms = VmTable::deterministic(&mode);
+        let vm = VmInfo {
+            workload_name: "fib".into(),
+            ..Default::default()
+        };
+        let vm_id = vms.insert(vm, Arc::default()).unwrap();
+        let drained = maintenance
+            .drain(&vms, "reboot", Some(Duration::ZERO), false)
+            .await;
+        assert_eq!(drained.running, vec![vm_id]);
+        assert!(drained.killed.is_empty());
+        assert_eq!(maintenance.cordoned().unwrap().reason, "reboot");
+    }
+}
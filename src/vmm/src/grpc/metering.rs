@@ -0,0 +1,136 @@
+//! Resources consumed by the VMs, accounted to the tenants of their runs so that operators can
+//! charge or budget them.
+//!
+//! A VM is recorded when it stops, for its whole lifetime: the warm invocations of a registered
+//! workload are included in the record of the VM which served them. The vCPU-seconds and the
+//! GB-seconds are the resources of the VM multiplied by its uptime, its egress the bytes its
+//! guest sent through its network interface, and its build seconds the time spent in the
+//! `BUILDING` stage of its runs.
+//!
+//! The records are only kept in memory, the last [`MAX_RECORDS`] of them: export them
+//! periodically, e.g. with `since` set to the end of the previous export.
+
+use shared_models::vmmorchestrator::{
+    ExportUsageRequest, ExportUsageResponse, TenantUsage, UsageRecord,
+};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Records kept in memory, the oldest ones being dropped beyond it.
+pub const MAX_RECORDS: usize = 100_000;
+
+/// Tenant of the runs which don't name one.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Tenant of a request, [`DEFAULT_TENANT`] if it doesn't name one.
+pub fn tenant(tenant: &str) -> &str {
+    if tenant.is_empty() {
+        DEFAULT_TENANT
+    } else {
+        tenant
+    }
+}
+
+/// Usage of the VMs which stopped.
+#[derive(Debug, Default)]
+pub struct UsageMeter {
+    records: Mutex<VecDeque<UsageRecord>>,
+}
+
+impl UsageMeter {
+    /// Record the usage of a VM which stopped.
+    pub fn record(&self, record: UsageRecord) {
+        let mut records = self.records.lock().unwrap();
+        if records.len() == MAX_RECORDS {
+            let dropped = records.pop_front().unwrap();
+            warn!(vm_id = %dropped.vm_id, "Dropped the oldest usage record, export them more often");
+        }
+        records.push_back(record);
+    }
+
+    /// Usage matching `request`, aggregated per tenant.
+    pub fn export(&self, request: &ExportUsageRequest) -> ExportUsageResponse {
+        let records: Vec<UsageRecord> = self
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| request.tenant.is_empty() || record.tenant == request.tenant)
+            .filter(|record| record.finished_at >= request.since)
+            .filter(|record| request.until == 0 || record.finished_at < request.until)
+            .cloned()
+            .collect();
+
+        let mut tenants: BTreeMap<&str, TenantUsage> = BTreeMap::new();
+        for record in &records {
+            let usage = tenants
+                .entry(&record.tenant)
+                .or_insert_with(|| TenantUsage {
+                    tenant: record.tenant.clone(),
+                    ..Default::default()
+                });
+            usage.vms += 1;
+            usage.vcpu_seconds += record.vcpu_seconds;
+            usage.memory_gb_seconds += record.memory_gb_seconds;
+            usage.egress_bytes += record.egress_bytes;
+            usage.build_seconds += record.build_seconds;
+        }
+
+        ExportUsageResponse {
+            tenants: tenants.into_values().collect(),
+            records: if request.records { records } else { Vec::new() },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(vm_id: &str, tenant: &str, finished_at: u64) -> UsageRecord {
+        UsageRecord {
+            vm_id: vm_id.into(),
+            tenant: tenant.into(),
+            finished_at,
+            vcpu_seconds: 2.0,
+            memory_gb_seconds: 1.5,
+            egress_bytes: 100,
+            build_seconds: 0.5,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_export_per_tenant() {
+        let meter = UsageMeter::default();
+        meter.record(record("a-1", "acme", 10));
+        meter.record(record("b-1", "default", 20));
+        meter.record(record("a-2", "acme", 30));
+
+        let usage = meter.export(&ExportUsageRequest::default());
+        assert_eq!(usage.tenants.len(), 2);
+        assert_eq!(usage.tenants[0].tenant, "acme");
+        assert_eq!(usage.tenants[0].vms, 2);
+        assert_eq!(usage.tenants[0].vcpu_seconds, 4.0);
+        assert_eq!(usage.tenants[0].egress_bytes, 200);
+        assert!(usage.records.is_empty());
+
+        let usage = meter.export(&ExportUsageRequest {
+            tenant: "acme".into(),
+            since: 20,
+            until: 0,
+            records: true,
+        });
+        assert_eq!(usage.tenants.len(), 1);
+        assert_eq!(usage.tenants[0].vms, 1);
+        assert_eq!(usage.records.len(), 1);
+        assert_eq!(usage.records[0].vm_id, "a-2");
+
+        let usage = meter.export(&ExportUsageRequest {
+            until: 30,
+            ..Default::default()
+        });
+        assert_eq!(usage.tenants.iter().map(|t| t.vms).sum::<u64>(), 2);
+    }
+}
@@ -0,0 +1,588 @@
+//! Caching proxy of the language package registries, so that the dependencies fetched by the
+//! workloads come from a warm cache on the host rather than from the internet.
+//!
+//! The proxy serves the registries under a prefix each:
+//!
+//! - `/crates-io/index/` the sparse index of crates.io, `/crates-io/dl/` its crates;
+//! - `/pypi/simple/` the simple index of PyPI, `/pypi/files/` its distributions;
+//! - `/npm/` the npm registry, packages and tarballs.
+//!
+//! The packages themselves never change once published, so they are kept until evicted; the
+//! indexes are fetched again once older than the TTL, and served stale while the upstream
+//! can't be reached. The links of the indexes to the packages are rewritten to the proxy. The
+//! cache takes at most its maximum size, the least recently used files being evicted first.
+
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, FileTimes},
+    io,
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpSocket, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+/// How long the proxy waits for an upstream.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The registries proxied, by the URL they are fetched from.
+#[derive(Debug, Clone)]
+pub struct Upstreams {
+    /// Sparse index of crates.io.
+    pub crates_index: String,
+    /// Where crates.io serves the crates, `<crates_dl>/<name>/<name>-<version>.crate`.
+    pub crates_dl: String,
+    /// Simple index of PyPI.
+    pub pypi: String,
+    /// Where PyPI serves the distributions, linked from its index.
+    pub pypi_files: String,
+    pub npm: String,
+}
+
+impl Default for Upstreams {
+    fn default() -> Self {
+        Self {
+            crates_index: "https://index.crates.io".to_string(),
+            crates_dl: "https://static.crates.io/crates".to_string(),
+            pypi: "https://pypi.org/simple".to_string(),
+            pypi_files: "https://files.pythonhosted.org".to_string(),
+            npm: "https://registry.npmjs.org".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PackageCacheConfig {
+    pub dir: PathBuf,
+    pub listen: SocketAddr,
+    /// Age past which an index is fetched again.
+    pub ttl: Duration,
+    /// Size in bytes of the cache, past which the least recently used files are evicted.
+    pub max_size: u64,
+    pub upstreams: Upstreams,
+}
+
+/// Where a request of a guest is fetched from, and how it is served.
+#[derive(Debug, PartialEq, Eq)]
+struct Route {
+    upstream: String,
+    /// A package, which doesn't change once published, rather than an index.
+    immutable: bool,
+    /// Links of the upstream replaced by links to the proxy, in the served content.
+    rewrite: Option<(String, String)>,
+    content_type: &'static str,
+}
+
+impl Upstreams {
+    /// Route of `path`, for an index served to the guests at `base`, e.g.
+    /// `http://172.29.0.1:3142`.
+    fn route(&self, path: &str, base: &str) -> Option<Route> {
+        if path.split('/').any(|segment| segment == "..") {
+            return None;
+        }
+        if let Some(path) = path.strip_prefix("/crates-io/index/") {
+            return Some(Route {
+                upstream: format!("{}/{}", self.crates_index, path),
+                immutable: false,
+                // cargo downloads the crates from the `dl` of the `config.json` of the index.
+                rewrite: (path == "config.json")
+                    .then(|| (self.crates_dl.clone(), format!("{}/crates-io/dl", base))),
+                content_type: "application/json",
+            });
+        }
+        if let Some(path) = path.strip_prefix("/crates-io/dl/") {
+            // `<name>/<version>/download`, as cargo appends to a `dl` without placeholders.
+            let mut segments = path.split('/');
+            let (Some(name), Some(version), Some("download"), None) = (
+                segments.next(),
+                segments.next(),
+                segments.next(),
+                segments.next(),
+            ) else {
+                return None;
+            };
+            return Some(Route {
+                upstream: format!("{}/{}/{}-{}.crate", self.crates_dl, name, name, version),
+                immutable: true,
+                rewrite: None,
+                content_type: "application/x-tar",
+            });
+        }
+        if let Some(path) = path.strip_prefix("/pypi/simple/") {
+            return Some(Route {
+                upstream: format!("{}/{}", self.pypi, path),
+                immutable: false,
+                rewrite: Some((self.pypi_files.clone(), format!("{}/pypi/files", base))),
+                content_type: "text/html",
+            });
+        }
+        if let Some(path) = path.strip_prefix("/pypi/files/") {
+            return Some(Route {
+                upstream: format!("{}/{}", self.pypi_files, path),
+                immutable: true,
+                rewrite: None,
+                content_type: "application/octet-stream",
+            });
+        }
+        if let Some(path) = path.strip_prefix("/npm/") {
+            // The tarballs are at `<package>/-/<package>-<version>.tgz`.
+            let tarball = path.contains("/-/");
+            return Some(Route {
+                upstream: format!("{}/{}", self.npm, path),
+                immutable: tarball,
+                rewrite: (!tarball).then(|| (self.npm.clone(), format!("{}/npm", base))),
+                content_type: if tarball {
+                    "application/octet-stream"
+                } else {
+                    "application/json"
+                },
+            });
+        }
+        None
+    }
+}
+
+/// Answer of the proxy to a guest.
+enum Served {
+    File(PathBuf),
+    Status(u16),
+}
+
+/// Listen on `addr`, which may not be an address of the host yet: the address of the guest
+/// bridge, by default, is set with its first guest.
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    let enable: libc::c_int = 1;
+    // SAFETY: the option is an int, read from a valid pointer for its size.
+    let freebind = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_IP,
+            libc::IP_FREEBIND,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if freebind != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// The caching proxy, bound to its address.
+pub struct PackageCache {
+    config: PackageCacheConfig,
+    listener: TcpListener,
+    client: reqwest::Client,
+}
+
+impl PackageCache {
+    pub async fn bind(config: PackageCacheConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let listener = listen(config.listen)?;
+        let client = reqwest::Client::builder()
+            .timeout(UPSTREAM_TIMEOUT)
+            .user_agent(concat!("cloudlet-vmm/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(io::Error::other)?;
+        Ok(Self {
+            config,
+            listener,
+            client,
+        })
+    }
+
+    /// Port the proxy listens on, which the guests reach on the host side of their network.
+    pub fn port(&self) -> io::Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    /// Answer the guests until the VMM stops.
+    pub async fn serve(self) {
+        let PackageCache {
+            config,
+            listener,
+            client,
+        } = self;
+        let proxy = Arc::new(Proxy { config, client });
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!(error = %e, "Could not accept a package cache connection");
+                    continue;
+                }
+            };
+            let proxy = proxy.clone();
+            tokio::spawn(async move {
+                if let Err(e) = proxy.handle(stream).await {
+                    debug!(peer = %peer, error = %e, "Package cache connection failed");
+                }
+            });
+        }
+    }
+}
+
+struct Proxy {
+    config: PackageCacheConfig,
+    client: reqwest::Client,
+}
+
+/// Path of the cached copy of `url` under `dir`.
+fn cache_path(dir: &Path, url: &str) -> PathBuf {
+    dir.join(format!("{:x}", Sha256::digest(url.as_bytes())))
+}
+
+/// Mark the file at `path` as used now, for the eviction.
+fn touch(path: &Path) {
+    let _ = File::options()
+        .append(true)
+        .open(path)
+        .and_then(|file| file.set_times(FileTimes::new().set_accessed(SystemTime::now())));
+}
+
+impl Proxy {
+    async fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let mut request_line = line.split_whitespace();
+        let method = request_line.next().unwrap_or_default().to_string();
+        let target = request_line.next().unwrap_or_default().to_string();
+        let mut host = None;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                break;
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("host") {
+                    host = Some(value.trim().to_string());
+                }
+            }
+        }
+        let mut stream = reader.into_inner();
+
+        if method != "GET" && method != "HEAD" {
+            return write_head(&mut stream, 405, "text/plain", 0).await;
+        }
+        let base = match &host {
+            Some(host) => format!("http://{}", host),
+            None => format!("http://{}", stream.local_addr()?),
+        };
+        let path = target.split('?').next().unwrap_or_default();
+        let Some(route) = self.config.upstreams.route(path, &base) else {
+            return write_head(&mut stream, 404, "text/plain", 0).await;
+        };
+
+        match self.fetch(&route).await {
+            Served::Status(status) => write_head(&mut stream, status, "text/plain", 0).await,
+            Served::File(path) => {
+                match &route.rewrite {
+                    Some((from, to)) => {
+                        let content = tokio::fs::read_to_string(&path).await?;
+                        let body = content.replace(from.as_str(), to);
+                        write_head(&mut stream, 200, route.content_type, body.len() as u64).await?;
+                        if method == "GET" {
+                            stream.write_all(body.as_bytes()).await?;
+                        }
+                    }
+                    None => {
+                        let mut file = tokio::fs::File::open(&path).await?;
+                        let length = file.metadata().await?.len();
+                        write_head(&mut stream, 200, route.content_type, length).await?;
+                        if method == "GET" {
+                            tokio::io::copy(&mut file, &mut stream).await?;
+                        }
+                    }
+                }
+                stream.flush().await
+            }
+        }
+    }
+
+    /// The cached copy of `route`, fetched if it's missing or stale.
+    async fn fetch(&self, route: &Route) -> Served {
+        let path = cache_path(&self.config.dir, &route.upstream);
+        let fetched_at = fs::metadata(&path).and_then(|metadata| metadata.modified());
+        let fresh = fetched_at.as_ref().is_ok_and(|fetched_at| {
+            route.immutable
+                || SystemTime::now()
+                    .duration_since(*fetched_at)
+                    .is_ok_and(|age| age < self.config.ttl)
+        });
+        if fresh {
+            debug!(url = %route.upstream, "Package cache hit");
+            touch(&path);
+            return Served::File(path);
+        }
+
+        match self.download(&route.upstream, &path).await {
+            Ok(StatusCode::OK) => {
+                debug!(url = %route.upstream, "Package cache miss");
+                let (dir, max_size, kept) =
+                    (self.config.dir.clone(), self.config.max_size, path.clone());
+                let _ = tokio::task::spawn_blocking(move || evict(&dir, max_size, &kept)).await;
+                Served::File(path)
+            }
+            Ok(status) if status.is_client_error() => Served::Status(status.as_u16()),
+            result => {
+                let reason = match result {
+                    Ok(status) => status.to_string(),
+                    Err(e) => e.to_string(),
+                };
+                if fetched_at.is_ok() {
+                    warn!(url = %route.upstream, reason = %reason, "Could not refresh a package index, serving it stale");
+                    touch(&path);
+                    Served::File(path)
+                } else {
+                    warn!(url = %route.upstream, reason = %reason, "Could not fetch a package");
+                    Served::Status(502)
+                }
+            }
+        }
+    }
+
+    /// Download `url` to `path` when it answers with a success, written aside then renamed so
+    /// that the guests never get a partial file. Returns the status of the upstream.
+    async fn download(&self, url: &str, path: &Path) -> Result<StatusCode, reqwest::Error> {
+        let mut response = self.client.get(url).send().await?;
+        if response.status() != StatusCode::OK {
+            return Ok(response.status());
+        }
+        let tmp_path = path.with_extension(format!("tmp-{:x}", rand_suffix()));
+        let write = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            while let Some(chunk) = response.chunk().await.map_err(io::Error::other)? {
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            drop(file);
+            tokio::fs::rename(&tmp_path, path).await?;
+            touch(path);
+            io::Result::Ok(())
+        };
+        if let Err(e) = write.await {
+            warn!(url, error = %e, "Could not cache a package");
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Ok(StatusCode::BAD_GATEWAY);
+        }
+        Ok(StatusCode::OK)
+    }
+}
+
+/// Suffix of the temporary files, distinct between concurrent downloads.
+fn rand_suffix() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    (u64::from(std::process::id()) << 32) | COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Remove the least recently used files of `dir` until they take at most `max_size` bytes,
+/// `kept` aside, which is about to be served.
+fn evict(dir: &Path, max_size: u64, kept: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.file_name().to_string_lossy().contains(".tmp-"))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let used_at = metadata.accessed().or_else(|_| metadata.modified()).ok()?;
+            Some((used_at, metadata.len(), entry.path()))
+        })
+        .collect();
+    let mut size: u64 = files.iter().map(|(_, size, _)| size).sum();
+    files.sort();
+    for (_, file_size, path) in files {
+        if size <= max_size {
+            break;
+        }
+        if path == kept {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                info!(path = ?path, size = file_size, "Evicted from the package cache");
+                size -= file_size;
+            }
+            Err(e) => warn!(path = ?path, error = %e, "Could not evict from the package cache"),
+        }
+    }
+}
+
+async fn write_head(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    length: u64,
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        502 => "Bad Gateway",
+        _ => "",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, reason, content_type, length
+    );
+    stream.write_all(head.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudlet_test_registry::scratch_dir;
+    use std::{
+        io::{BufRead, Read, Write},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    /// Upstream answering `/simple/hello/` with a link to its files, and counting its requests.
+    fn upstream(requests: Arc<AtomicUsize>, up: Arc<Mutex<bool>>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let files = format!("{}/files", url);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_string();
+                requests.fetch_add(1, Ordering::SeqCst);
+                let (status, body) = match path.as_str() {
+                    _ if !*up.lock().unwrap() => ("503 Service Unavailable", String::new()),
+                    "/simple/hello/" => (
+                        "200 OK",
+                        format!("<a href=\"{}/ab/hello-1.0.tar.gz\">hello</a>", files),
+                    ),
+                    "/files/ab/hello-1.0.tar.gz" => ("200 OK", "tarball".to_string()),
+                    _ => ("404 Not Found", String::new()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        url
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cache_packages() {
+        let (requests, up) = (Arc::new(AtomicUsize::new(0)), Arc::new(Mutex::new(true)));
+        let url = upstream(requests.clone(), up.clone());
+        let cache = PackageCache::bind(PackageCacheConfig {
+            dir: scratch_dir("vmm-package-cache"),
+            listen: "127.0.0.1:0".parse().unwrap(),
+            ttl: Duration::ZERO,
+            max_size: 1 << 20,
+            upstreams: Upstreams {
+                pypi: format!("{}/simple", url),
+                pypi_files: format!("{}/files", url),
+                ..Default::default()
+            },
+        })
+        .await
+        .unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], cache.port().unwrap()));
+        tokio::spawn(cache.serve());
+
+        let (status, index) = tokio::task::spawn_blocking(move || get(addr, "/pypi/simple/hello/"))
+            .await
+            .unwrap();
+        assert_eq!(status, 200);
+        // The link to the distribution goes through the proxy.
+        let link = format!("http://{}/pypi/files/ab/hello-1.0.tar.gz", addr);
+        assert_eq!(index, format!("<a href=\"{}\">hello</a>", link));
+
+        let fetch = move |path: &'static str| tokio::task::spawn_blocking(move || get(addr, path));
+        for _ in 0..2 {
+            let (status, body) = fetch("/pypi/files/ab/hello-1.0.tar.gz").await.unwrap();
+            assert_eq!((status, body.as_str()), (200, "tarball"));
+        }
+        // The distribution was only fetched once.
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // The index is stale at once with a TTL of zero, but still served with the upstream down.
+        *up.lock().unwrap() = false;
+        let (status, _) = fetch("/pypi/simple/hello/").await.unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(fetch("/pypi/simple/missing/").await.unwrap().0, 502);
+        assert_eq!(fetch("/pypi/simple/../secret").await.unwrap().0, 404);
+    }
+
+    #[test]
+    fn test_route_crates() {
+        let upstreams = Upstreams::default();
+        let base = "http://172.29.0.1:3142";
+        assert_eq!(
+            upstreams.route("/crates-io/index/config.json", base),
+            Some(Route {
+                upstream: "https://index.crates.io/config.json".to_string(),
+                immutable: false,
+                rewrite: Some((
+                    "https://static.crates.io/crates".to_string(),
+                    "http://172.29.0.1:3142/crates-io/dl".to_string()
+                )),
+                content_type: "application/json",
+            })
+        );
+        let route = upstreams
+            .route("/crates-io/dl/serde/1.0.197/download", base)
+            .unwrap();
+        assert_eq!(
+            route.upstream,
+            "https://static.crates.io/crates/serde/serde-1.0.197.crate"
+        );
+        assert!(route.immutable);
+        assert!(
+            upstreams
+                .route("/npm/left-pad/-/left-pad-1.3.0.tgz", base)
+                .unwrap()
+                .immutable
+        );
+        assert_eq!(upstreams.route("/crates-io/dl/serde", base), None);
+    }
+}
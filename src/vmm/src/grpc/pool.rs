@@ -0,0 +1,1144 @@
+//! VMs of the registered workloads kept warm between their invocations, and scaled with them.
+//!
+//! The VM started by an invocation keeps running once it is over, its agent keeping the built
+//! workload: the next invocation of the same version only runs it again. An invocation which
+//! finds every VM of its workload busy starts another one, up to the maximum of the workload,
+//! then waits for one of them to be released.
+//!
+//! [`FunctionPool::scale`] is called periodically to bring each pool back to its target, the
+//! number of invocations in flight within the bounds of the workload: idle VMs beyond it are
+//! stopped once idle for `idle_timeout`, and VMs are started ahead of the invocations, with
+//! only their build, up to the minimum. A workload without invocations for `idle_window`
+//! scales down to no VM at all, even below its minimum. The scaling decisions are published as
+//! `POOL_SCALED_UP` and `POOL_SCALED_DOWN` events.
+//!
+//! The orchestrator runs a single VM per workload name, so the instances beyond the first one
+//! run under the names `<name>--<n>`.
+//!
+//! A VM only serves the invocations of the tenant it started for, and the files the previous
+//! invocation left in it are removed before the next one runs (see `scrub` in the agent
+//! protocol): an invocation never sees the data of another tenant, nor of an earlier run.
+//!
+//! [`FunctionPool::upgrade`] starts the rolling upgrade of pools to a new generation of their
+//! rootfs image and agent, e.g. once a new image was pushed: the VMs of the earlier generations
+//! are replaced one at a time by [`FunctionPool::scale`], each idle one once the replacement of
+//! the previous one is built, and the busy ones are retired at the end of their invocation
+//! instead of being reused, so that no run is interrupted. An idle VM of an earlier generation
+//! still serves an invocation when no VM of the current one is free. The progress is published
+//! as `POOL_UPGRADE_STARTED`, `POOL_UPGRADE_PROGRESS` and `POOL_UPGRADE_FINISHED` events.
+
+use crate::grpc::admin::VmTable;
+use crate::grpc::deterministic::{system_clock, SharedClock};
+use crate::grpc::events::EventBus;
+use shared_models::vmmorchestrator::admin::{PoolDump, PoolInstance};
+use shared_models::vmmorchestrator::{PoolStatus, ScalingPolicy, VmEventKind};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::info;
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// VMs kept warm for each workload while it is invoked, unless its policy sets it.
+    pub min_instances: usize,
+    /// VMs running each workload at most, unless its policy sets it.
+    pub max_instances: usize,
+    /// Idle VMs beyond the target of their pool are stopped after this long.
+    pub idle_timeout: Duration,
+    /// Pools without invocations for this long are scaled down to no VM.
+    pub idle_window: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_instances: 0,
+            max_instances: 4,
+            idle_timeout: Duration::from_secs(5 * 60),
+            idle_window: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Minimum and maximum instances of a pool, from the policy of its workload and the
+    /// defaults.
+    pub fn bounds(&self, policy: Option<&ScalingPolicy>) -> (usize, usize) {
+        let min = policy
+            .and_then(|policy| policy.min_instances)
+            .map_or(self.min_instances, |min| min as usize);
+        let max = policy
+            .and_then(|policy| policy.max_instances)
+            .map_or(self.max_instances, |max| max as usize);
+        (min, max.max(min).max(1))
+    }
+}
+
+#[derive(Debug)]
+struct Instance {
+    /// Workload name of the VM.
+    name: String,
+    version: String,
+    /// `None` until the VM of the instance started.
+    vm_id: Option<String>,
+    /// Tenant whose invocations the VM serves, and only them.
+    tenant: String,
+    busy: bool,
+    idle_since: Instant,
+    /// Generation of the rootfs image of the pool the VM booted.
+    generation: u64,
+}
+
+/// An instance reserved for an invocation, or for a scale up, to give back with
+/// [`FunctionPool::release`]. Dropped without being given back, by a cancelled invocation, the
+/// instance is removed from its pool and its VM, in an unknown state, is killed.
+pub struct Lease {
+    workload: String,
+    version: String,
+    instance: String,
+    vm_id: Option<String>,
+    warm: bool,
+    /// Counted in the invocations in flight of the pool.
+    invocation: bool,
+    /// Idle VM of another version, replaced by this instance, to stop.
+    retired: Option<String>,
+    pool: Arc<FunctionPool>,
+    vms: VmTable,
+    released: bool,
+}
+
+impl fmt::Debug for Lease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lease")
+            .field("workload", &self.workload)
+            .field("version", &self.version)
+            .field("instance", &self.instance)
+            .field("vm_id", &self.vm_id)
+            .field("warm", &self.warm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        let pool = self.pool.clone();
+        if let Some(vm_id) = pool.give_back(self, false) {
+            self.vms.kill(&vm_id, "its invocation was cancelled");
+        }
+    }
+}
+
+impl Lease {
+    pub fn workload(&self) -> &str {
+        &self.workload
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Workload name to run the invocation under.
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// VM to run the invocation in, `None` to start a new one.
+    pub fn warm_vm(&self) -> Option<&str> {
+        self.vm_id.as_deref().filter(|_| self.warm)
+    }
+
+    /// VM to stop to make room for this instance, if any.
+    pub fn take_retired(&mut self) -> Option<String> {
+        self.retired.take()
+    }
+}
+
+/// Rolling upgrade of a pool in progress.
+#[derive(Debug)]
+struct Upgrade {
+    started: Instant,
+    started_at: u64,
+    /// Instances of an earlier generation when it started.
+    outdated: usize,
+    /// Instance started in place of the last VM retired, until it is built.
+    replacement: Option<String>,
+}
+
+#[derive(Debug)]
+struct Pool {
+    instances: Vec<Instance>,
+    min_instances: usize,
+    max_instances: usize,
+    /// Version started by the scale ups, the one invoked last.
+    version: String,
+    /// Tenant of the invocation of `version`.
+    tenant: String,
+    /// Invocations holding or waiting for an instance.
+    in_flight: usize,
+    queued: usize,
+    last_invoked: Instant,
+    last_invoked_at: u64,
+    cold_starts: u64,
+    warm_starts: u64,
+    scale_ups: u64,
+    scale_downs: u64,
+    /// Generation of the rootfs image the new instances boot.
+    generation: u64,
+    /// Digest of the rootfs image booted by the instances of the generation, once one booted.
+    image: Option<String>,
+    upgrade: Option<Upgrade>,
+    upgrades: u64,
+}
+
+impl Pool {
+    fn new(now: Instant) -> Self {
+        Self {
+            instances: Vec::new(),
+            min_instances: 0,
+            max_instances: 0,
+            version: String::new(),
+            tenant: String::new(),
+            in_flight: 0,
+            queued: 0,
+            last_invoked: now,
+            last_invoked_at: 0,
+            cold_starts: 0,
+            warm_starts: 0,
+            scale_ups: 0,
+            scale_downs: 0,
+            generation: 0,
+            image: None,
+            upgrade: None,
+            upgrades: 0,
+        }
+    }
+
+    /// Instances running a VM of an earlier generation, or starting one.
+    fn outdated(&self) -> usize {
+        self.instances
+            .iter()
+            .filter(|instance| instance.generation < self.generation)
+            .count()
+    }
+
+    /// Publish the progress of the upgrade in progress once `vm_id` was retired, and end it if
+    /// every outdated instance is gone.
+    fn upgrade_progress(
+        &mut self,
+        workload: &str,
+        vm_id: Option<&str>,
+        events: &EventBus,
+        now: Instant,
+    ) {
+        let outdated = self.outdated();
+        let Some(upgrade) = &self.upgrade else {
+            return;
+        };
+        let total = upgrade.outdated.max(outdated);
+        if let Some(vm_id) = vm_id {
+            events.publish(
+                vm_id,
+                workload,
+                VmEventKind::PoolUpgradeProgress,
+                format!(
+                    "{}/{} instance(s) replaced by generation {}",
+                    total - outdated,
+                    total,
+                    self.generation
+                ),
+            );
+        }
+        if outdated == 0 {
+            let elapsed = now.saturating_duration_since(upgrade.started);
+            events.publish(
+                "",
+                workload,
+                VmEventKind::PoolUpgradeFinished,
+                format!(
+                    "{} instance(s) upgraded to generation {} in {}s",
+                    total,
+                    self.generation,
+                    elapsed.as_secs()
+                ),
+            );
+            info!(workload = %workload, generation = self.generation, "Upgraded the pool");
+            self.upgrade = None;
+            self.upgrades += 1;
+        }
+    }
+
+    /// Forget the instances whose VM stopped, e.g. shut down by hand.
+    fn prune(&mut self, vms: &VmTable) {
+        self.instances.retain(|instance| {
+            instance.busy
+                || instance
+                    .vm_id
+                    .as_ref()
+                    .is_some_and(|vm_id| vms.resolve(vm_id).is_some())
+        });
+    }
+
+    /// Add a busy instance of `version` for `tenant` without a VM yet, and return its name.
+    fn add(
+        &mut self,
+        workload: &str,
+        (version, tenant): (&str, &str),
+        vms: &VmTable,
+        now: Instant,
+    ) -> String {
+        // The name of a stopped instance can be reused once its VM is gone.
+        let name = (0..)
+            .map(|n| match n {
+                0 => workload.to_string(),
+                n => format!("{}--{}", workload, n),
+            })
+            .find(|name| {
+                !self.instances.iter().any(|instance| instance.name == *name)
+                    && !vms.is_running(name)
+            })
+            .unwrap();
+        self.instances.push(Instance {
+            name: name.clone(),
+            version: version.to_string(),
+            vm_id: None,
+            tenant: tenant.to_string(),
+            busy: true,
+            idle_since: now,
+            generation: self.generation,
+        });
+        name
+    }
+
+    fn to_status(&self, name: &str) -> PoolStatus {
+        PoolStatus {
+            name: name.to_string(),
+            version: self.version.clone(),
+            min_instances: self.min_instances as u32,
+            max_instances: self.max_instances as u32,
+            instances: self.instances.len() as u32,
+            busy: self
+                .instances
+                .iter()
+                .filter(|instance| instance.busy)
+                .count() as u32,
+            in_flight: self.in_flight as u32,
+            queued: self.queued as u32,
+            cold_starts: self.cold_starts,
+            warm_starts: self.warm_starts,
+            scale_ups: self.scale_ups,
+            scale_downs: self.scale_downs,
+            last_invoked_at: self.last_invoked_at,
+            generation: self.generation,
+            outdated: self.outdated() as u32,
+            upgrades: self.upgrades,
+            upgrade_started_at: self
+                .upgrade
+                .as_ref()
+                .map_or(0, |upgrade| upgrade.started_at),
+        }
+    }
+}
+
+/// VMs to stop and instances to start to bring the pools back to their targets.
+#[derive(Debug, Default)]
+pub struct Scaling {
+    pub stop: Vec<String>,
+    pub start: Vec<Lease>,
+    /// VMs of an earlier generation, replaced by instances of `start`.
+    pub retire: Vec<String>,
+}
+
+/// Pools of VMs of the registered workloads, by workload.
+pub struct FunctionPool {
+    config: PoolConfig,
+    pools: Mutex<BTreeMap<String, Pool>>,
+    released: Notify,
+    events: EventBus,
+    clock: SharedClock,
+}
+
+/// Counts an invocation as queued while it waits for an instance, and no longer in flight if
+/// it is cancelled meanwhile.
+struct Waiting<'a> {
+    pools: &'a Mutex<BTreeMap<String, Pool>>,
+    workload: &'a str,
+    acquired: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pools.lock().unwrap().get_mut(self.workload) {
+            pool.queued -= 1;
+            if !self.acquired {
+                pool.in_flight -= 1;
+            }
+        }
+    }
+}
+
+impl FunctionPool {
+    pub fn new(config: PoolConfig, events: EventBus) -> Self {
+        Self {
+            config,
+            pools: Mutex::default(),
+            released: Notify::new(),
+            events,
+            clock: system_clock(),
+        }
+    }
+
+    /// Time the idleness of the pools with `clock`.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    /// Reserve an instance for an invocation of `version` of `workload` by `tenant`, whose pool
+    /// has `bounds` instances: an idle VM of this version and tenant, else a new instance if the
+    /// pool isn't at its maximum. Waits for an instance to be released otherwise.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        workload: &str,
+        version: &str,
+        tenant: &str,
+        bounds: (usize, usize),
+        vms: &VmTable,
+    ) -> Lease {
+        {
+            let mut pools = self.pools.lock().unwrap();
+            let now = self.clock.instant();
+            let pool = pools
+                .entry(workload.to_string())
+                .or_insert_with(|| Pool::new(now));
+            (pool.min_instances, pool.max_instances) = bounds;
+            pool.version = version.to_string();
+            pool.tenant = tenant.to_string();
+            pool.in_flight += 1;
+            pool.queued += 1;
+            pool.last_invoked = now;
+            pool.last_invoked_at = self.clock.unix_secs();
+        }
+
+        let mut waiting = Waiting {
+            pools: &self.pools,
+            workload,
+            acquired: false,
+        };
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // Registered before looking at the instances, so that no release is missed.
+            released.as_mut().enable();
+            if let Some(lease) = self.try_acquire(workload, version, tenant, vms) {
+                waiting.acquired = true;
+                return lease;
+            }
+            released.await;
+        }
+    }
+
+    fn try_acquire(
+        self: &Arc<Self>,
+        workload: &str,
+        version: &str,
+        tenant: &str,
+        vms: &VmTable,
+    ) -> Option<Lease> {
+        let mut pools = self.pools.lock().unwrap();
+        let pool = pools.get_mut(workload)?;
+        pool.prune(vms);
+
+        // A VM of the current generation, else one of an earlier generation still running.
+        let generation = pool.generation;
+        let idle = |instance: &Instance| {
+            !instance.busy && instance.version == version && instance.tenant == tenant
+        };
+        let warm = pool
+            .instances
+            .iter()
+            .position(|instance| idle(instance) && instance.generation == generation)
+            .or_else(|| pool.instances.iter().position(idle));
+        if let Some(instance) = warm {
+            let instance = &mut pool.instances[instance];
+            instance.busy = true;
+            let lease = Lease {
+                workload: workload.to_string(),
+                version: version.to_string(),
+                instance: instance.name.clone(),
+                vm_id: instance.vm_id.clone(),
+                warm: true,
+                invocation: true,
+                retired: None,
+                pool: self.clone(),
+                vms: vms.clone(),
+                released: false,
+            };
+            pool.warm_starts += 1;
+            return Some(lease);
+        }
+
+        // At its maximum, the pool makes room by stopping an idle VM of another version or
+        // tenant.
+        let before = pool.instances.len();
+        let mut retired = None;
+        if before >= pool.max_instances {
+            let idle = pool.instances.iter().position(|instance| !instance.busy)?;
+            retired = pool.instances.remove(idle).vm_id;
+            pool.scale_downs += 1;
+        }
+
+        let instance = pool.add(workload, (version, tenant), vms, self.clock.instant());
+        pool.scale_ups += 1;
+        pool.cold_starts += 1;
+        self.events.publish(
+            "",
+            workload,
+            VmEventKind::PoolScaledUp,
+            format!(
+                "{} -> {} instance(s): {} invocation(s) in flight{}",
+                before,
+                pool.instances.len(),
+                pool.in_flight,
+                match retired {
+                    Some(_) => ", replacing an idle VM of another version or tenant",
+                    None => "",
+                }
+            ),
+        );
+        Some(Lease {
+            workload: workload.to_string(),
+            version: version.to_string(),
+            instance,
+            vm_id: None,
+            warm: false,
+            invocation: true,
+            retired,
+            pool: self.clone(),
+            vms: vms.clone(),
+            released: false,
+        })
+    }
+
+    /// Record that the VM of the new instance of `lease` is `vm_id`.
+    pub fn started(&self, lease: &mut Lease, vm_id: &str) {
+        lease.vm_id = Some(vm_id.to_string());
+        let mut pools = self.pools.lock().unwrap();
+        let instance = pools.get_mut(&lease.workload).and_then(|pool| {
+            pool.instances
+                .iter_mut()
+                .find(|instance| instance.name == lease.instance)
+        });
+        if let Some(instance) = instance {
+            instance.vm_id = Some(vm_id.to_string());
+        }
+    }
+
+    /// Give the instance of `lease` back, idle if its VM can run the next invocations. Returns
+    /// the VM to stop otherwise, or if an upgrade retires it.
+    pub fn release(&self, mut lease: Lease, reusable: bool) -> Option<String> {
+        self.give_back(&mut lease, reusable)
+    }
+
+    fn give_back(&self, lease: &mut Lease, reusable: bool) -> Option<String> {
+        lease.released = true;
+        let mut retired = false;
+        if let Some(pool) = self.pools.lock().unwrap().get_mut(&lease.workload) {
+            if lease.invocation {
+                pool.in_flight -= 1;
+            }
+            if let Some(upgrade) = pool.upgrade.as_mut() {
+                if upgrade.replacement.as_ref() == Some(&lease.instance) {
+                    upgrade.replacement = None;
+                }
+            }
+            let instance = pool
+                .instances
+                .iter()
+                .position(|instance| instance.name == lease.instance);
+            match instance {
+                Some(instance)
+                    if reusable
+                        && lease.vm_id.is_some()
+                        && pool.instances[instance].generation < pool.generation =>
+                {
+                    pool.instances.remove(instance);
+                    pool.upgrade_progress(
+                        &lease.workload,
+                        lease.vm_id.as_deref(),
+                        &self.events,
+                        self.clock.instant(),
+                    );
+                    retired = true;
+                }
+                Some(instance) if reusable && lease.vm_id.is_some() => {
+                    let instance = &mut pool.instances[instance];
+                    instance.busy = false;
+                    instance.idle_since = self.clock.instant();
+                }
+                Some(instance) => {
+                    pool.instances.remove(instance);
+                }
+                None => {}
+            }
+        }
+        self.released.notify_waiters();
+
+        lease.vm_id.clone().filter(|_| !reusable || retired)
+    }
+
+    /// Start the rolling upgrade of the pools of `workloads` to a new generation of their
+    /// rootfs image, for `reason`. Returns the state of these pools.
+    pub fn upgrade(&self, workloads: &[String], reason: &str) -> Vec<PoolStatus> {
+        let mut pools = self.pools.lock().unwrap();
+        let mut upgraded = Vec::new();
+        for workload in workloads {
+            let Some(pool) = pools.get_mut(workload) else {
+                continue;
+            };
+            self.start_upgrade(workload, pool, reason);
+            upgraded.push(pool.to_status(workload));
+        }
+        upgraded
+    }
+
+    fn start_upgrade(&self, workload: &str, pool: &mut Pool, reason: &str) {
+        pool.generation += 1;
+        // Known once an instance of the new generation boots.
+        pool.image = None;
+        let outdated = pool.outdated();
+        if outdated == 0 {
+            return;
+        }
+        let started = match pool.upgrade.take() {
+            // Restarted, the VMs replaced so far are of an earlier generation too.
+            Some(upgrade) => (upgrade.started, upgrade.started_at),
+            None => (self.clock.instant(), self.clock.unix_secs()),
+        };
+        pool.upgrade = Some(Upgrade {
+            started: started.0,
+            started_at: started.1,
+            outdated,
+            replacement: None,
+        });
+        self.events.publish(
+            "",
+            workload,
+            VmEventKind::PoolUpgradeStarted,
+            format!(
+                "replacing {} instance(s) by generation {}: {}",
+                outdated, pool.generation, reason
+            ),
+        );
+        info!(workload = %workload, generation = pool.generation, outdated, reason, "Upgrading the pool");
+    }
+
+    /// Record that the VM of `instance`, if it's an instance of a pool, boots the rootfs image
+    /// of digest `image`. Another image than the one of the instances of its generation, e.g.
+    /// rebuilt or pulled again since, starts the rolling upgrade of the pool, this instance
+    /// being of the new generation.
+    pub fn booted(&self, instance: &str, image: &str) {
+        let mut pools = self.pools.lock().unwrap();
+        let Some((workload, pool)) = pools
+            .iter_mut()
+            .find(|(_, pool)| pool.instances.iter().any(|other| other.name == instance))
+        else {
+            return;
+        };
+        match pool.image.as_deref().map(|booted| booted != image) {
+            Some(true) => {
+                let generation = pool.generation + 1;
+                if let Some(booting) = pool
+                    .instances
+                    .iter_mut()
+                    .find(|other| other.name == instance)
+                {
+                    booting.generation = generation;
+                }
+                self.start_upgrade(workload, pool, "the rootfs image changed");
+            }
+            Some(false) => return,
+            None => {}
+        }
+        pool.image = Some(image.to_string());
+    }
+
+    /// Bring the pools back to their targets: the VMs to stop are removed from the pools, and
+    /// the instances to start are reserved.
+    pub fn scale(self: &Arc<Self>, vms: &VmTable) -> Scaling {
+        let mut scaling = Scaling::default();
+        let now = self.clock.instant();
+        let mut pools = self.pools.lock().unwrap();
+        for (workload, pool) in pools.iter_mut() {
+            pool.prune(vms);
+            let before = pool.instances.len();
+
+            let idle_for = now.saturating_duration_since(pool.last_invoked);
+            let scale_to_zero = pool.in_flight == 0 && idle_for >= self.config.idle_window;
+            let (target, reason) = if scale_to_zero {
+                (0, format!("no invocation for {}s", idle_for.as_secs()))
+            } else {
+                (
+                    pool.in_flight.clamp(pool.min_instances, pool.max_instances),
+                    format!(
+                        "{} invocation(s) in flight, between {} and {} instance(s)",
+                        pool.in_flight, pool.min_instances, pool.max_instances
+                    ),
+                )
+            };
+
+            // Down to the target, the VMs idle for the longest first.
+            pool.instances.sort_by_key(|instance| instance.idle_since);
+            while pool.instances.len() > target {
+                let Some(oldest) = pool.instances.iter().position(|instance| {
+                    !instance.busy
+                        && (scale_to_zero
+                            || now.saturating_duration_since(instance.idle_since)
+                                >= self.config.idle_timeout)
+                }) else {
+                    break;
+                };
+                let instance = pool.instances.remove(oldest);
+                pool.scale_downs += 1;
+                self.events.publish(
+                    instance.vm_id.as_deref().unwrap_or_default(),
+                    workload,
+                    VmEventKind::PoolScaledDown,
+                    format!(
+                        "{} -> {} instance(s): {}",
+                        pool.instances.len() + 1,
+                        pool.instances.len(),
+                        reason
+                    ),
+                );
+                scaling.stop.extend(instance.vm_id);
+            }
+
+            // Up to the minimum, ahead of the invocations.
+            while pool.instances.len() < target && !pool.version.is_empty() {
+                let (version, tenant) = (pool.version.clone(), pool.tenant.clone());
+                let instance = pool.add(workload, (&version, &tenant), vms, now);
+                pool.scale_ups += 1;
+                self.events.publish(
+                    "",
+                    workload,
+                    VmEventKind::PoolScaledUp,
+                    format!(
+                        "{} -> {} instance(s): {}",
+                        pool.instances.len() - 1,
+                        pool.instances.len(),
+                        reason
+                    ),
+                );
+                scaling.start.push(Lease {
+                    workload: workload.clone(),
+                    version,
+                    instance,
+                    vm_id: None,
+                    warm: false,
+                    invocation: false,
+                    retired: None,
+                    pool: self.clone(),
+                    vms: vms.clone(),
+                    released: false,
+                });
+            }
+
+            if before != pool.instances.len() {
+                info!(workload = %workload, before, after = pool.instances.len(), "Scaled the pool");
+            }
+
+            self.roll(workload, pool, vms, &mut scaling);
+        }
+        scaling
+    }
+
+    /// Remove the idle instances of every pool for `reason`, e.g. the orchestrator being
+    /// drained, and return their VMs to stop. The busy ones are removed at their next scaling
+    /// once their run is over.
+    pub fn drain_idle(&self, vms: &VmTable, reason: &str) -> Vec<String> {
+        let mut stop = Vec::new();
+        let mut pools = self.pools.lock().unwrap();
+        for (workload, pool) in pools.iter_mut() {
+            pool.prune(vms);
+            while let Some(idle) = pool.instances.iter().position(|instance| !instance.busy) {
+                let instance = pool.instances.remove(idle);
+                pool.scale_downs += 1;
+                self.events.publish(
+                    instance.vm_id.as_deref().unwrap_or_default(),
+                    workload,
+                    VmEventKind::PoolScaledDown,
+                    format!(
+                        "{} -> {} instance(s): {}",
+                        pool.instances.len() + 1,
+                        pool.instances.len(),
+                        reason
+                    ),
+                );
+                stop.extend(instance.vm_id);
+            }
+        }
+        stop
+    }
+
+    /// Replace the idle VM of an earlier generation idle for the longest by a new instance,
+    /// once the replacement of the previous one is built.
+    fn roll(
+        self: &Arc<Self>,
+        workload: &str,
+        pool: &mut Pool,
+        vms: &VmTable,
+        scaling: &mut Scaling,
+    ) {
+        let Some(upgrade) = &pool.upgrade else {
+            return;
+        };
+        let building = upgrade.replacement.as_ref().is_some_and(|replacement| {
+            pool.instances
+                .iter()
+                .any(|instance| instance.name == *replacement && instance.busy)
+        });
+        if building {
+            return;
+        }
+
+        let generation = pool.generation;
+        let Some(oldest) = pool
+            .instances
+            .iter()
+            .position(|instance| !instance.busy && instance.generation < generation)
+        else {
+            // The outdated VMs left, if any, are busy and retired at the end of their run.
+            pool.upgrade_progress(workload, None, &self.events, self.clock.instant());
+            return;
+        };
+        let retired = pool.instances.remove(oldest);
+        pool.upgrade_progress(
+            workload,
+            retired.vm_id.as_deref(),
+            &self.events,
+            self.clock.instant(),
+        );
+        scaling.retire.extend(retired.vm_id);
+        if pool.version.is_empty() {
+            return;
+        }
+
+        let (version, tenant) = (pool.version.clone(), pool.tenant.clone());
+        // Under another name while the retired VM stops.
+        let instance = pool.add(workload, (&version, &tenant), vms, self.clock.instant());
+        if let Some(upgrade) = pool.upgrade.as_mut() {
+            upgrade.replacement = Some(instance.clone());
+        }
+        scaling.start.push(Lease {
+            workload: workload.to_string(),
+            version,
+            instance,
+            vm_id: None,
+            warm: false,
+            invocation: false,
+            retired: None,
+            pool: self.clone(),
+            vms: vms.clone(),
+            released: false,
+        });
+    }
+
+    /// Whether an invocation of `workload` by `tenant`, of the version invoked last, would find
+    /// an idle VM of its pool, without reserving it.
+    pub fn has_warm(&self, workload: &str, tenant: &str, vms: &VmTable) -> bool {
+        let pools = self.pools.lock().unwrap();
+        pools.get(workload).is_some_and(|pool| {
+            pool.instances.iter().any(|instance| {
+                !instance.busy
+                    && instance.version == pool.version
+                    && instance.tenant == tenant
+                    && instance
+                        .vm_id
+                        .as_ref()
+                        .is_some_and(|vm_id| vms.resolve(vm_id).is_some())
+            })
+        })
+    }
+
+    /// Instances of every pool, as they are.
+    pub fn dump(&self) -> Vec<PoolDump> {
+        let now = self.clock.instant();
+        self.pools
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(workload, pool)| PoolDump {
+                workload: workload.clone(),
+                generation: pool.generation,
+                image: pool.image.clone().unwrap_or_default(),
+                instances: pool
+                    .instances
+                    .iter()
+                    .map(|instance| PoolInstance {
+                        name: instance.name.clone(),
+                        version: instance.version.clone(),
+                        tenant: instance.tenant.clone(),
+                        vm_id: instance.vm_id.clone().unwrap_or_default(),
+                        busy: instance.busy,
+                        generation: instance.generation,
+                        idle_secs: if instance.busy {
+                            0
+                        } else {
+                            now.saturating_duration_since(instance.idle_since).as_secs()
+                        },
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// State of the pools, only the one of `workload` if not empty.
+    pub fn status(&self, workload: &str) -> Vec<PoolStatus> {
+        self.pools
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| workload.is_empty() || *name == workload)
+            .map(|(name, pool)| pool.to_status(name))
+            .collect()
+    }
+
+    /// Delay between two scalings of the pools.
+    pub fn check_interval(&self) -> Duration {
+        (self.config.idle_timeout.min(self.config.idle_window) / 4)
+            .clamp(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::deterministic::{FixedClock, FIXED_EPOCH_SECS};
+    use shared_models::vmmorchestrator::admin::VmInfo;
+
+    fn start(vms: &VmTable, pool: &FunctionPool, lease: &mut Lease) -> String {
+        let vm = VmInfo {
+            workload_name: lease.instance().to_string(),
+            ..Default::default()
+        };
+        let vm_id = vms.insert(vm, Arc::default()).unwrap();
+        pool.started(lease, &vm_id);
+        vm_id
+    }
+
+    fn pool(idle_window: Duration) -> Arc<FunctionPool> {
+        let config = PoolConfig {
+            idle_timeout: Duration::ZERO,
+            idle_window,
+            ..Default::default()
+        };
+        Arc::new(FunctionPool::new(config, EventBus::default()))
+    }
+
+    #[tokio::test]
+    async fn test_scale_with_concurrency() {
+        let vms = VmTable::default();
+        let pool = pool(Duration::from_secs(60));
+
+        let mut first = pool.acquire("fib", "1", "default", (0, 2), &vms).await;
+        assert_eq!((first.instance(), first.warm_vm()), ("fib", None));
+        let first_vm = start(&vms, &pool, &mut first);
+        let mut second = pool.acquire("fib", "1", "default", (0, 2), &vms).await;
+        assert_eq!(second.instance(), "fib--1");
+        let second_vm = start(&vms, &pool, &mut second);
+        assert!(pool.try_acquire("fib", "1", "default", &vms).is_none());
+
+        assert_eq!(pool.release(first, true), None);
+        let warm = pool.acquire("fib", "1", "default", (0, 2), &vms).await;
+        assert_eq!(warm.warm_vm(), Some(first_vm.as_str()));
+
+        // The instance is replaced, under another name while its VM stops.
+        assert_eq!(pool.release(second, false), Some(second_vm));
+        let replacement = pool.acquire("fib", "1", "default", (0, 2), &vms).await;
+        assert_eq!(replacement.instance(), "fib--2");
+
+        // Idle beyond the single invocation in flight.
+        assert_eq!(pool.release(warm, true), None);
+        assert_eq!(pool.scale(&vms).stop, vec![first_vm]);
+        let status = &pool.status("fib")[0];
+        assert_eq!((status.instances, status.in_flight), (1, 1));
+        assert_eq!((status.cold_starts, status.warm_starts), (3, 1));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_lease() {
+        let vms = VmTable::default();
+        let pool = pool(Duration::from_secs(60));
+
+        let mut lease = pool.acquire("fib", "1", "default", (0, 1), &vms).await;
+        start(&vms, &pool, &mut lease);
+        let status = &pool.status("fib")[0];
+        assert_eq!((status.instances, status.in_flight), (1, 1));
+
+        // A cancelled invocation gives its instance back, and lets the next one start.
+        drop(lease);
+        let status = &pool.status("fib")[0];
+        assert_eq!((status.instances, status.in_flight), (0, 0));
+        let next = pool.acquire("fib", "1", "default", (0, 1), &vms).await;
+        assert_eq!(next.warm_vm(), None);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_on_image_change() {
+        let vms = VmTable::default();
+        let pool = pool(Duration::from_secs(60));
+
+        let mut first = pool.acquire("fib", "1", "default", (0, 3), &vms).await;
+        pool.booted(first.instance(), "sha256:aaaa");
+        start(&vms, &pool, &mut first);
+        let mut second = pool.acquire("fib", "1", "default", (0, 3), &vms).await;
+        pool.booted(second.instance(), "sha256:aaaa");
+        start(&vms, &pool, &mut second);
+        let status = &pool.status("fib")[0];
+        assert_eq!((status.generation, status.outdated), (0, 0));
+
+        // Booting another image, the instance is of a new generation and the others outdated.
+        let mut third = pool.acquire("fib", "1", "default", (0, 3), &vms).await;
+        pool.booted(third.instance(), "sha256:bbbb");
+        start(&vms, &pool, &mut third);
+        let status = &pool.status("fib")[0];
+        assert_eq!((status.generation, status.outdated), (1, 2));
+        assert_ne!(status.upgrade_started_at, 0);
+
+        // The instances of the new generation boot it too.
+        pool.booted(third.instance(), "sha256:bbbb");
+        assert_eq!(pool.status("fib")[0].generation, 1);
+    }
+
+    #[tokio::test]
+    async fn test_has_warm() {
+        let vms = VmTable::default();
+        let pool = pool(Duration::from_secs(60));
+        assert!(!pool.has_warm("fib", "default", &vms));
+
+        let mut lease = pool.acquire("fib", "1", "default", (0, 1), &vms).await;
+        start(&vms, &pool, &mut lease);
+        assert!(!pool.has_warm("fib", "default", &vms));
+        assert_eq!(pool.release(lease, true), None);
+        assert!(pool.has_warm("fib", "default", &vms));
+        assert!(!pool.has_warm("fib", "other", &vms));
+        // Still idle, the check reserves nothing.
+        assert!(pool.try_acquire("fib", "1", "default", &vms).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_scale_to_min_then_zero() {
+        let vms = VmTable::default();
+        let pool = pool(Duration::ZERO);
+
+        let mut invocation = pool.acquire("fib", "1", "default", (2, 4), &vms).await;
+        start(&vms, &pool, &mut invocation);
+        // Kept up to the minimum while invoked.
+        let scaling = pool.scale(&vms);
+        assert_eq!(scaling.start.len(), 1);
+        for mut lease in scaling.start {
+            assert_eq!((lease.instance(), lease.version()), ("fib--1", "1"));
+            start(&vms, &pool, &mut lease);
+            pool.release(lease, true);
+        }
+        pool.release(invocation, true);
+
+        assert_eq!(pool.scale(&vms).stop.len(), 2);
+        assert_eq!(pool.status("fib")[0].instances, 0);
+        assert!(pool.scale(&vms).start.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_no_reuse_across_tenants() {
+        let vms = VmTable::default();
+        let pool = pool(Duration::from_secs(60));
+
+        let mut first = pool.acquire("fib", "1", "a", (0, 1), &vms).await;
+        let first_vm = start(&vms, &pool, &mut first);
+        assert_eq!(pool.release(first, true), None);
+        // At its maximum, the pool stops the idle VM of the other tenant rather than reuse it.
+        let mut other = pool.acquire("fib", "1", "b", (0, 1), &vms).await;
+        assert_eq!(other.warm_vm(), None);
+        assert_eq!(other.take_retired(), Some(first_vm));
+    }
+
+    #[tokio::test]
+    async fn test_stop_after_idle_timeout() {
+        let clock = Arc::new(FixedClock::default());
+        let vms = VmTable::default();
+        let config = PoolConfig {
+            idle_timeout: Duration::from_secs(60),
+            idle_window: Duration::from_secs(600),
+            ..Default::default()
+        };
+        let pool =
+            Arc::new(FunctionPool::new(config, EventBus::default()).with_clock(clock.clone()));
+
+        let mut lease = pool.acquire("fib", "1", "default", (0, 2), &vms).await;
+        let vm_id = start(&vms, &pool, &mut lease);
+        pool.release(lease, true);
+        clock.advance(Duration::from_secs(59));
+        assert!(pool.scale(&vms).stop.is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(pool.scale(&vms).stop, vec![vm_id]);
+        assert_eq!(pool.status("fib")[0].last_invoked_at, FIXED_EPOCH_SECS);
+    }
+
+    #[tokio::test]
+    async fn test_drain_idle() {
+        let vms = VmTable::default();
+        let pool = pool(Duration::from_secs(60));
+
+        let mut idle = pool.acquire("fib", "1", "default", (1, 2), &vms).await;
+        let idle_vm = start(&vms, &pool, &mut idle);
+        let mut busy = pool.acquire("fib", "1", "default", (1, 2), &vms).await;
+        start(&vms, &pool, &mut busy);
+        pool.release(idle, true);
+
+        // The busy instance is left to its run.
+        assert_eq!(pool.drain_idle(&vms, "draining"), vec![idle_vm]);
+        assert_eq!(pool.status("fib")[0].instances, 1);
+        assert!(pool.drain_idle(&vms, "draining").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rolling_upgrade() {
+        let vms = VmTable::default();
+        let pool = pool(Duration::from_secs(60));
+
+        let mut idle = pool.acquire("fib", "1", "default", (2, 4), &vms).await;
+        let idle_vm = start(&vms, &pool, &mut idle);
+        let mut busy = pool.acquire("fib", "1", "default", (2, 4), &vms).await;
+        let busy_vm = start(&vms, &pool, &mut busy);
+        pool.release(idle, true);
+
+        let status = &pool.upgrade(&["fib".to_string()], "new image")[0];
+        assert_eq!((status.generation, status.outdated), (1, 2));
+
+        // The idle VM is replaced first, the next one waiting for its replacement to be built.
+        let scaling = pool.scale(&vms);
+        assert_eq!(scaling.retire, vec![idle_vm]);
+        assert_eq!(scaling.start.len(), 1);
+        assert!(pool.scale(&vms).retire.is_empty());
+        for mut lease in scaling.start {
+            assert_eq!(lease.instance(), "fib--2");
+            start(&vms, &pool, &mut lease);
+            assert_eq!(pool.release(lease, true), None);
+        }
+
+        // The busy VM is retired once its run is over.
+        assert_eq!(pool.release(busy, true), Some(busy_vm));
+        let status = &pool.status("fib")[0];
+        assert_eq!((status.instances, status.outdated), (1, 0));
+        assert_eq!((status.upgrades, status.upgrade_started_at), (1, 0));
+    }
+}
@@ -0,0 +1,535 @@
+//! Prebuilt rootfs images pulled from an OCI registry, where `fs-gen push` uploads them.
+//!
+//! The image of a language is the artifact `<registry>/<language>:<version>`, `latest` when
+//! no version is pinned by the request. The orchestrator can be told the digest of the
+//! artifact to use instead of trusting the tag, in which case a cached image matching the
+//! digest is used without contacting the registry at all.
+
+use super::{artifacts, signatures};
+use reqwest::{
+    blocking::{Client, Response},
+    header, StatusCode,
+};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::{debug, info};
+
+/// Type of the artifacts holding a rootfs image, see `fs-gen push`.
+const ROOTFS_ARTIFACT_TYPE: &str = "application/vnd.cloudlet.rootfs.v1";
+
+/// Media type of the initramfs images in these artifacts.
+const CPIO_MEDIA_TYPE: &str = "application/vnd.cloudlet.rootfs.cpio.v1";
+
+const OCI_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Annotation of the artifacts whose vulnerability scan failed, see `fs-gen --on-vulnerability
+/// flag`.
+const SCAN_FLAGGED_ANNOTATION: &str = "dev.cloudlet.scan.flagged";
+const SCAN_SUMMARY_ANNOTATION: &str = "dev.cloudlet.scan.summary";
+
+/// Annotation holding the minisign signature of the image, see the signatures module.
+const SIGNATURE_ANNOTATION: &str = "dev.cloudlet.signature";
+
+#[derive(Debug)]
+pub enum RegistryError {
+    InvalidPin(String),
+    Request(reqwest::Error),
+    Status(String, StatusCode),
+    Authentication(String),
+    InvalidManifest(String),
+    DigestMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// The artifact was flagged as vulnerable by its scan, with its summary if any.
+    Flagged(String, Option<String>),
+    Io(io::Error),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::InvalidPin(pin) => write!(
+                f,
+                "Invalid rootfs pin {}, expected LANGUAGE[:VERSION]@sha256:DIGEST",
+                pin
+            ),
+            RegistryError::Request(e) => write!(f, "Registry request failed: {}", e),
+            RegistryError::Status(url, status) => write!(f, "{} answered {}", url, status),
+            RegistryError::Authentication(reason) => {
+                write!(f, "Registry authentication failed: {}", reason)
+            }
+            RegistryError::InvalidManifest(reason) => {
+                write!(f, "Not a rootfs artifact: {}", reason)
+            }
+            RegistryError::DigestMismatch { expected, actual } => {
+                write!(f, "Expected digest {}, got {}", expected, actual)
+            }
+            RegistryError::Flagged(artifact, summary) => {
+                write!(f, "{} is flagged as vulnerable by its scan", artifact)?;
+                if let Some(summary) = summary {
+                    write!(f, " ({})", summary)?;
+                }
+                Ok(())
+            }
+            RegistryError::Io(e) => write!(f, "Failed to store the rootfs image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<reqwest::Error> for RegistryError {
+    fn from(e: reqwest::Error) -> Self {
+        RegistryError::Request(e)
+    }
+}
+
+impl From<io::Error> for RegistryError {
+    fn from(e: io::Error) -> Self {
+        RegistryError::Io(e)
+    }
+}
+
+/// Digest of the artifact to use for a language, and optionally a runtime version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootfsPin {
+    pub language: String,
+    pub version: Option<String>,
+    pub digest: String,
+}
+
+impl FromStr for RootfsPin {
+    type Err = RegistryError;
+
+    /// Parse `LANGUAGE[:VERSION]@sha256:DIGEST`.
+    fn from_str(pin: &str) -> Result<Self, Self::Err> {
+        let invalid = || RegistryError::InvalidPin(pin.to_string());
+        let (name, digest) = pin.split_once('@').ok_or_else(invalid)?;
+        let hex = digest.strip_prefix("sha256:").ok_or_else(invalid)?;
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let (language, version) = match name.split_once(':') {
+            Some((language, version)) => (language, Some(version.to_string())),
+            None => (name, None),
+        };
+        if language.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            language: language.to_string(),
+            version,
+            digest: digest.to_ascii_lowercase(),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for RootfsPin {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Result of a pull.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Pulled {
+    /// The cached image already was the one of the artifact.
+    Cached(String),
+    /// The image was downloaded.
+    Downloaded(String),
+}
+
+/// Registry holding the prebuilt rootfs images.
+#[derive(Debug, Clone)]
+pub struct RootfsRegistry {
+    /// URL of the registry, e.g. `https://registry.example.com`.
+    url: String,
+    /// Namespace of the artifacts in the registry, e.g. `cloudlet`.
+    namespace: String,
+    pins: Vec<RootfsPin>,
+    credentials: Option<(String, String)>,
+}
+
+/// File next to a pulled image recording the digest of the artifact it comes from.
+pub fn source_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".source");
+    path.with_file_name(name)
+}
+
+fn cached_digest(path: &Path) -> Option<String> {
+    if !path.is_file() {
+        return None;
+    }
+    fs::read_to_string(source_path(path))
+        .ok()
+        .map(|digest| digest.trim().to_string())
+}
+
+/// Value of `key` in a `WWW-Authenticate: Bearer realm="...",service="..."` challenge.
+fn challenge_param<'a>(challenge: &'a str, key: &str) -> Option<&'a str> {
+    challenge
+        .trim_start_matches("Bearer ")
+        .split(',')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+/// Client of a repository for the duration of a pull.
+struct Session<'a> {
+    client: Client,
+    credentials: &'a Option<(String, String)>,
+    token: Option<String>,
+}
+
+impl Session<'_> {
+    fn send(&self, url: &str, accept: &str) -> reqwest::Result<Response> {
+        let request = self.client.get(url).header(header::ACCEPT, accept);
+        match &self.token {
+            Some(token) => request.bearer_auth(token).send(),
+            None => request.send(),
+        }
+    }
+
+    /// GET `url`, authenticating first if the registry asks for it.
+    fn get(&mut self, url: &str, accept: &str) -> Result<Response, RegistryError> {
+        let mut response = self.send(url, accept)?;
+        if response.status() == StatusCode::UNAUTHORIZED && self.token.is_none() {
+            let challenge = response
+                .headers()
+                .get(header::WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            self.token = Some(self.authenticate(&challenge)?);
+            response = self.send(url, accept)?;
+        }
+        if !response.status().is_success() {
+            return Err(RegistryError::Status(url.to_string(), response.status()));
+        }
+
+        Ok(response)
+    }
+
+    fn authenticate(&self, challenge: &str) -> Result<String, RegistryError> {
+        let realm = challenge_param(challenge, "realm").ok_or_else(|| {
+            RegistryError::Authentication(format!("unsupported challenge {:?}", challenge))
+        })?;
+        let mut query = Vec::new();
+        for key in ["service", "scope"] {
+            if let Some(value) = challenge_param(challenge, key) {
+                query.push((key, value));
+            }
+        }
+
+        let mut request = self.client.get(realm).query(&query);
+        if let Some((username, password)) = self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request.send()?;
+        if !response.status().is_success() {
+            return Err(RegistryError::Status(realm.to_string(), response.status()));
+        }
+        let body: Value = response.json()?;
+
+        body["token"]
+            .as_str()
+            .or(body["access_token"].as_str())
+            .map(str::to_string)
+            .ok_or_else(|| RegistryError::Authentication("no token in the response".into()))
+    }
+}
+
+impl RootfsRegistry {
+    /// Registry at `location`, e.g. `registry.example.com/cloudlet` (HTTPS unless it starts
+    /// with `http://`).
+    pub fn new(
+        location: &str,
+        pins: Vec<RootfsPin>,
+        credentials: Option<(String, String)>,
+    ) -> Self {
+        let (scheme, location) = match location.strip_prefix("http://") {
+            Some(location) => ("http://", location),
+            None => ("https://", location.trim_start_matches("https://")),
+        };
+        let location = location.trim_end_matches('/');
+        let (host, namespace) = location.split_once('/').unwrap_or((location, ""));
+
+        Self {
+            url: format!("{}{}", scheme, host),
+            namespace: namespace.to_string(),
+            pins,
+            credentials,
+        }
+    }
+
+    pub fn set_pins(&mut self, pins: Vec<RootfsPin>) {
+        self.pins = pins;
+    }
+
+    /// Languages and versions whose pin differs in `pins`, added, removed or of another digest.
+    pub fn changed_pins(&self, pins: &[RootfsPin]) -> Vec<(String, Option<String>)> {
+        let mut changed: Vec<(String, Option<String>)> = Vec::new();
+        for pin in self.pins.iter().chain(pins) {
+            let (language, version) = (pin.language.as_str(), pin.version.as_deref());
+            let before = self.pin(language, version).map(|pin| &pin.digest);
+            let after = pins
+                .iter()
+                .find(|pin| pin.language == language && pin.version.as_deref() == version)
+                .map(|pin| &pin.digest);
+            let key = (language.to_string(), version.map(str::to_string));
+            if before != after && !changed.contains(&key) {
+                changed.push(key);
+            }
+        }
+        changed
+    }
+
+    fn pin(&self, language: &str, version: Option<&str>) -> Option<&RootfsPin> {
+        self.pins
+            .iter()
+            .find(|pin| pin.language == language && pin.version.as_deref() == version)
+    }
+
+    /// Whether [`Self::pull`] would download the image at `path`: if it isn't the pinned one,
+    /// or without a pin if none was pulled. An unpinned image already pulled is downloaded again
+    /// only if the registry has a newer one, which isn't asked.
+    pub fn would_pull(&self, language: &str, version: Option<&str>, path: &Path) -> bool {
+        let cached = cached_digest(path);
+        match self.pin(language, version) {
+            Some(pin) => cached.as_ref() != Some(&pin.digest),
+            None => cached.is_none(),
+        }
+    }
+
+    /// Make sure the image at `path` is the one of the artifact for `language` and `version`,
+    /// pulling it if needed.
+    pub fn pull(
+        &self,
+        language: &str,
+        version: Option<&str>,
+        path: &Path,
+    ) -> Result<Pulled, RegistryError> {
+        let pinned = self.pin(language, version).map(|pin| pin.digest.clone());
+        if let Some(digest) = &pinned {
+            if cached_digest(path).as_ref() == Some(digest) {
+                return Ok(Pulled::Cached(digest.clone()));
+            }
+        }
+
+        let repository = match self.namespace.as_str() {
+            "" => format!("{}/v2/{}", self.url, language),
+            namespace => format!("{}/v2/{}/{}", self.url, namespace, language),
+        };
+        let reference = pinned
+            .clone()
+            .unwrap_or_else(|| version.unwrap_or("latest").to_string());
+        let mut session = Session {
+            client: Client::builder().timeout(None).build()?,
+            credentials: &self.credentials,
+            token: None,
+        };
+
+        let manifest_url = format!("{}/manifests/{}", repository, reference);
+        let manifest = session
+            .get(&manifest_url, OCI_MANIFEST_MEDIA_TYPE)?
+            .bytes()?;
+        let digest = format!("sha256:{:x}", Sha256::digest(&manifest));
+        if let Some(expected) = pinned {
+            if expected != digest {
+                return Err(RegistryError::DigestMismatch {
+                    expected,
+                    actual: digest,
+                });
+            }
+        }
+        if cached_digest(path).as_ref() == Some(&digest) {
+            return Ok(Pulled::Cached(digest));
+        }
+
+        let manifest: Value = serde_json::from_slice(&manifest)
+            .map_err(|e| RegistryError::InvalidManifest(e.to_string()))?;
+        if manifest["artifactType"] != ROOTFS_ARTIFACT_TYPE {
+            return Err(RegistryError::InvalidManifest(format!(
+                "{} is of type {}",
+                manifest_url, manifest["artifactType"]
+            )));
+        }
+        if manifest["annotations"][SCAN_FLAGGED_ANNOTATION] == "true" {
+            return Err(RegistryError::Flagged(
+                manifest_url,
+                manifest["annotations"][SCAN_SUMMARY_ANNOTATION]
+                    .as_str()
+                    .map(str::to_string),
+            ));
+        }
+        let layer_digest = manifest["layers"]
+            .as_array()
+            .and_then(|layers| {
+                layers
+                    .iter()
+                    .find(|layer| layer["mediaType"] == CPIO_MEDIA_TYPE)
+            })
+            .and_then(|layer| layer["digest"].as_str())
+            .ok_or_else(|| {
+                RegistryError::InvalidManifest(format!("{} has no initramfs image", manifest_url))
+            })?
+            .to_string();
+
+        info!(artifact = %manifest_url, digest = %digest, "Pulling rootfs image");
+        let mut download_error = None;
+        let result = artifacts::ensure_current(
+            path,
+            |path| cached_digest(path).as_ref() == Some(&digest),
+            |tmp_path| {
+                let blob_url = format!("{}/blobs/{}", repository, layer_digest);
+                match session
+                    .get(&blob_url, "application/octet-stream")
+                    .and_then(|response| download(response, tmp_path, &layer_digest))
+                {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        let message = e.to_string();
+                        download_error = Some(e);
+                        Err(io::Error::other(message))
+                    }
+                }
+            },
+        );
+        if let Some(e) = download_error {
+            return Err(e);
+        }
+        let downloaded = result?;
+        let signature_path = signatures::signature_path(path);
+        match manifest["annotations"][SIGNATURE_ANNOTATION].as_str() {
+            Some(signature) => fs::write(signature_path, signature)?,
+            None => match fs::remove_file(signature_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+        }
+        fs::write(source_path(path), &digest)?;
+        debug!(path = ?path, downloaded, "Rootfs image up to date");
+
+        Ok(if downloaded {
+            Pulled::Downloaded(digest)
+        } else {
+            Pulled::Cached(digest)
+        })
+    }
+}
+
+/// Write the blob of `response` to `path`, checking it matches `digest`.
+fn download(mut response: Response, path: &Path, digest: &str) -> Result<(), RegistryError> {
+    let mut file = fs::File::create(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = response.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        file.write_all(&buffer[..read])?;
+    }
+
+    let actual = format!("sha256:{:x}", hasher.finalize());
+    if actual != digest {
+        return Err(RegistryError::DigestMismatch {
+            expected: digest.to_string(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudlet_test_registry::{scratch_dir, TestRegistry};
+
+    #[test]
+    fn test_pull_with_credentials() {
+        let registry = TestRegistry::with_credentials("user", "secret");
+        let path = scratch_dir("vmm-registry-pull").join("python.img");
+        let rootfs = RootfsRegistry::new(
+            &format!("{}/cloudlet", registry.url()),
+            Vec::new(),
+            Some(("user".to_string(), "secret".to_string())),
+        );
+        let digest = registry.digest("cloudlet/python", "latest").unwrap();
+
+        assert_eq!(
+            rootfs.pull("python", None, &path).unwrap(),
+            Pulled::Downloaded(digest.clone())
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "python rootfs\n");
+        assert_eq!(
+            rootfs.pull("python", None, &path).unwrap(),
+            Pulled::Cached(digest)
+        );
+        assert_eq!(registry.blob_downloads(), 1);
+    }
+
+    #[test]
+    fn test_pull_pinned() {
+        let registry = TestRegistry::start();
+        let path = scratch_dir("vmm-registry-pin").join("python.img");
+        let pin = |digest: &str| RootfsPin {
+            language: "python".to_string(),
+            version: None,
+            digest: digest.to_string(),
+        };
+
+        let location = format!("{}/cloudlet", registry.url());
+
+        // The pinned artifact is pulled by digest, whatever `latest` is.
+        let unknown = format!("sha256:{}", "0".repeat(64));
+        let rootfs = RootfsRegistry::new(&location, vec![pin(&unknown)], None);
+        assert!(matches!(
+            rootfs.pull("python", None, &path),
+            Err(RegistryError::Status(_, StatusCode::NOT_FOUND))
+        ));
+
+        let digest = registry.digest("cloudlet/python", "latest").unwrap();
+        let rootfs = RootfsRegistry::new(&location, vec![pin(&digest)], None);
+        assert!(rootfs.would_pull("python", None, &path));
+        assert_eq!(
+            rootfs.pull("python", None, &path).unwrap(),
+            Pulled::Downloaded(digest)
+        );
+        assert!(!rootfs.would_pull("python", None, &path));
+        // Repinned, the image is pulled again.
+        let rootfs = RootfsRegistry::new(&location, vec![pin(&unknown)], None);
+        assert!(rootfs.would_pull("python", None, &path));
+    }
+
+    #[test]
+    fn test_pull_flagged() {
+        let registry = TestRegistry::start();
+        let path = scratch_dir("vmm-registry-flagged").join("node.img");
+        let rootfs = RootfsRegistry::new(&format!("{}/cloudlet", registry.url()), Vec::new(), None);
+
+        match rootfs.pull("node", None, &path) {
+            Err(RegistryError::Flagged(_, summary)) => {
+                assert_eq!(summary.as_deref(), Some("trivy:critical=1"))
+            }
+            result => panic!("expected the artifact to be refused, got {:?}", result),
+        }
+        assert!(!path.exists());
+        assert_eq!(registry.blob_downloads(), 0);
+    }
+}
@@ -0,0 +1,124 @@
+//! Retries, on a fresh VM, of the runs failing on an infrastructure error.
+//!
+//! A failure is the infrastructure's when the guest didn't get to run the workload: its VM
+//! couldn't be created or booted, e.g. because the host was out of memory, or its agent couldn't
+//! be reached. These runs are started again, up to `--infra-retries` times, each attempt
+//! recorded with the previous one. Once the agent started the workload, a failure is the
+//! workload's: its output was streamed and it may have had side effects, so it isn't retried.
+
+use super::admin::VmTable;
+use shared_models::ErrorCode;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+/// Attempts made by default after the first one.
+pub const DEFAULT_INFRA_RETRIES: u32 = 2;
+
+/// Wait before the first retry, doubled before each of the next ones.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Time given to the VM of a failed attempt to stop, before the next attempt.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether the run failing with `status` failed on an infrastructure error.
+pub fn is_infra_failure(status: &Status) -> bool {
+    match ErrorCode::from_status(status) {
+        Some(code) => matches!(
+            code,
+            ErrorCode::VmmConfigure
+                | ErrorCode::VmmRun
+                | ErrorCode::VmmHypervisor
+                | ErrorCode::VmmAgentUnreachable
+        ),
+        // The connection to the agent dropped before it answered.
+        None => status.code() == Code::Unavailable,
+    }
+}
+
+/// Attempt of a run, from 1.
+#[derive(Debug, Clone, Default)]
+pub struct Attempt {
+    pub number: u32,
+    /// Attempts left after this one.
+    pub retries: u32,
+    /// VM of the previous attempt, if it got one.
+    pub retry_of: Option<String>,
+}
+
+impl Attempt {
+    pub fn first(retries: u32) -> Self {
+        Self {
+            number: 1,
+            retries,
+            retry_of: None,
+        }
+    }
+
+    /// Whether the run is attempted again if this attempt fails with `status`.
+    pub fn retried_on(&self, status: &Status) -> bool {
+        self.retries > 0 && is_infra_failure(status)
+    }
+
+    /// Attempt following this one, which failed in the VM `vm_id` if it got one.
+    pub fn next(&self, vm_id: Option<String>) -> Self {
+        Self {
+            number: self.number + 1,
+            retries: self.retries.saturating_sub(1),
+            retry_of: vm_id,
+        }
+    }
+
+    /// Wait before the attempt following this one.
+    pub fn delay(&self) -> Duration {
+        RETRY_DELAY * 2u32.saturating_pow(self.number.saturating_sub(1))
+    }
+}
+
+/// Kill the VM `vm_id` of a failed attempt of a run of `workload_name`, and wait for it to be
+/// gone, for the next attempt not to be rejected as a duplicate.
+pub async fn discard_vm(vms: &VmTable, vm_id: &str, workload_name: &str, reason: &str) {
+    vms.kill(vm_id, reason);
+    let _ = tokio::time::timeout(STOP_TIMEOUT, async {
+        while vms.is_running(workload_name) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_infra_failures() {
+        let infra = [
+            ErrorCode::VmmConfigure.status(Code::Internal, "out of memory"),
+            ErrorCode::VmmAgentUnreachable.status(Code::Unavailable, "timed out"),
+            Status::unavailable("connection reset"),
+        ];
+        for status in &infra {
+            assert!(is_infra_failure(status), "{:?}", status);
+        }
+        let workload = [
+            ErrorCode::VmmInvalidRequest.status(Code::InvalidArgument, "no code"),
+            ErrorCode::VmmTapCreation.status(Code::Internal, "no CAP_NET_ADMIN"),
+            Status::invalid_argument("unknown language"),
+        ];
+        for status in &workload {
+            assert!(!is_infra_failure(status), "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn test_attempts() {
+        let first = Attempt::first(2);
+        let failure = ErrorCode::VmmRun.status(Code::Internal, "KVM_RUN failed");
+        assert!(first.retried_on(&failure));
+        let second = first.next(Some("hello-0123abcd".into()));
+        assert_eq!(second.number, 2);
+        assert_eq!(second.retry_of.as_deref(), Some("hello-0123abcd"));
+        assert_eq!(second.delay(), 2 * first.delay());
+        assert!(!second.next(None).retried_on(&failure));
+    }
+}
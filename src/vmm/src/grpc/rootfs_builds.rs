@@ -0,0 +1,207 @@
+//! Metrics of the rootfs images the runs need, by language, returned by `GetServerInfo` and
+//! exposed by the `/metrics` endpoint of the API for the slow builds to be diagnosed centrally.
+//!
+//! An image is a cache hit when it was already built: on disk, in the shared storage or pulled
+//! from the rootfs registry. Otherwise fs-gen builds it, with a `--metrics-file` next to the
+//! image it writes: the bytes of the layers it downloaded, and the time it spent extracting
+//! them and packing the rootfs. Each build is announced by a `ROOTFS_BUILD_STARTED` event, and
+//! ends with a `ROOTFS_BUILD_FINISHED` event giving its metrics, or a `ROOTFS_BUILD_FAILED` one.
+
+use super::events::EventBus;
+use shared_models::{
+    vmmorchestrator::{RootfsBuildStats, VmEventKind},
+    RootfsBuildMetrics,
+};
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+use tracing::{info, warn};
+
+/// File next to the image built at `tmp_path` where fs-gen writes its metrics.
+pub fn metrics_path(tmp_path: &Path) -> PathBuf {
+    let mut path = tmp_path.as_os_str().to_owned();
+    path.push(".metrics.json");
+    path.into()
+}
+
+/// Metrics of the rootfs images of each language, since the orchestrator started.
+#[derive(Debug, Default)]
+pub struct RootfsBuilds {
+    stats: Mutex<BTreeMap<String, RootfsBuildStats>>,
+}
+
+impl RootfsBuilds {
+    fn update(&self, language: &str, update: impl FnOnce(&mut RootfsBuildStats)) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats
+            .entry(language.to_string())
+            .or_insert_with(|| RootfsBuildStats {
+                language: language.to_string(),
+                ..Default::default()
+            });
+        update(stats);
+    }
+
+    /// Count an image of `language` needed by a run, `cached` if it was already built.
+    pub fn requested(&self, language: &str, cached: bool) {
+        self.update(language, |stats| {
+            stats.requests += 1;
+            if cached {
+                stats.cache_hits += 1;
+            }
+        });
+    }
+
+    /// Build `image` of `language` for a run of `workload_name` with `build`, which is given
+    /// the path of the metrics fs-gen writes, and publish its progress to `events`.
+    pub fn build(
+        &self,
+        events: &EventBus,
+        workload_name: &str,
+        (language, image): (&str, &str),
+        tmp_path: &Path,
+        build: impl FnOnce(&Path) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let metrics_path = metrics_path(tmp_path);
+        let _ = std::fs::remove_file(&metrics_path);
+        self.update(language, |stats| stats.in_progress += 1);
+        events.publish(
+            "",
+            workload_name,
+            VmEventKind::RootfsBuildStarted,
+            format!("{}: building the rootfs of {}", language, image),
+        );
+
+        let started = Instant::now();
+        let built = build(&metrics_path);
+        let elapsed = started.elapsed().as_secs_f64();
+        let metrics = read_metrics(&metrics_path).unwrap_or_else(|| RootfsBuildMetrics {
+            total_secs: elapsed,
+            ..Default::default()
+        });
+        let _ = std::fs::remove_file(&metrics_path);
+
+        self.update(language, |stats| {
+            stats.in_progress -= 1;
+            match &built {
+                Ok(()) => {
+                    stats.builds += 1;
+                    stats.download_bytes += metrics.download_bytes;
+                    stats.extract_seconds += metrics.extract_secs;
+                    stats.pack_seconds += metrics.pack_secs;
+                    stats.build_seconds += elapsed;
+                }
+                Err(_) => stats.failed_builds += 1,
+            }
+        });
+        match &built {
+            Ok(()) => {
+                info!(language, image, metrics = ?metrics, "Built the rootfs image");
+                events.publish(
+                    "",
+                    workload_name,
+                    VmEventKind::RootfsBuildFinished,
+                    format!("{}: {}", language, describe(&metrics, elapsed)),
+                );
+            }
+            Err(e) => events.publish(
+                "",
+                workload_name,
+                VmEventKind::RootfsBuildFailed,
+                format!("{}: {} after {:.1}s", language, e, elapsed),
+            ),
+        }
+        built
+    }
+
+    pub fn stats(&self) -> Vec<RootfsBuildStats> {
+        self.stats.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// The metrics written by fs-gen at `path`, if it could write them.
+fn read_metrics(path: &Path) -> Option<RootfsBuildMetrics> {
+    let json = std::fs::read(path).ok()?;
+    match serde_json::from_slice(&json) {
+        Ok(metrics) => Some(metrics),
+        Err(e) => {
+            warn!(path = ?path, error = %e, "Could not read the metrics of the rootfs build");
+            None
+        }
+    }
+}
+
+fn describe(metrics: &RootfsBuildMetrics, elapsed: f64) -> String {
+    format!(
+        "downloaded {} bytes in {} layer(s), extracted in {:.1}s, packed in {:.1}s, built in {:.1}s",
+        metrics.download_bytes, metrics.layers, metrics.extract_secs, metrics.pack_secs, elapsed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudlet_test_registry::scratch_dir;
+
+    #[test]
+    fn test_rootfs_builds() {
+        let dir = scratch_dir("vmm-rootfs-builds");
+        let (builds, events) = (RootfsBuilds::default(), EventBus::default());
+        let mut watcher = events.subscribe();
+
+        builds.requested("python", true);
+        builds.requested("python", false);
+        let tmp_path = dir.join("python.img.tmp");
+        builds
+            .build(
+                &events,
+                "fib",
+                ("python", "python:3.12-alpine"),
+                &tmp_path,
+                |metrics_path| {
+                    let metrics = RootfsBuildMetrics {
+                        download_bytes: 1024,
+                        layers: 2,
+                        extract_secs: 1.5,
+                        pack_secs: 0.5,
+                        total_secs: 2.5,
+                    };
+                    std::fs::write(metrics_path, serde_json::to_vec(&metrics).unwrap())
+                },
+            )
+            .unwrap();
+        // Without metrics, the build is still counted.
+        builds.requested("python", false);
+        let failed = builds.build(&events, "fib", ("python", "python"), &tmp_path, |_| {
+            Err(io::Error::other("no network"))
+        });
+        assert!(failed.is_err());
+
+        let stats = &builds.stats()[0];
+        assert_eq!((stats.requests, stats.cache_hits), (3, 1));
+        assert_eq!(
+            (stats.builds, stats.failed_builds, stats.in_progress),
+            (1, 1, 0)
+        );
+        assert_eq!(stats.download_bytes, 1024);
+        assert_eq!(stats.extract_seconds, 1.5);
+        assert!(!metrics_path(&tmp_path).exists());
+
+        let kinds: Vec<VmEventKind> = std::iter::from_fn(|| watcher.try_recv().ok())
+            .map(|event| event.kind())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                VmEventKind::RootfsBuildStarted,
+                VmEventKind::RootfsBuildFinished,
+                VmEventKind::RootfsBuildStarted,
+                VmEventKind::RootfsBuildFailed,
+            ]
+        );
+    }
+}
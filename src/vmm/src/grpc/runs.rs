@@ -0,0 +1,83 @@
+//! Inputs of the runs, recorded so that a past run can be started again identically.
+//!
+//! Each run is recorded in the object `<run id>.pb` of a storage, a `RunInputs` message.
+//! The records hold the code and the environment of the runs, secret variables included:
+//! local records are only readable by the orchestrator user, a shared bucket must be
+//! protected likewise.
+
+use crate::grpc::storage::{Storage, StorageError};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use shared_models::vmmorchestrator::RunInputs;
+use std::{collections::HashMap, fs, io, path::Path, sync::Arc};
+
+/// Where the inputs of the runs are recorded.
+#[derive(Debug, Clone)]
+pub struct RunStore {
+    storage: Arc<dyn Storage>,
+}
+
+impl RunStore {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    fn key(run_id: &str) -> String {
+        format!("{}.pb", run_id)
+    }
+
+    pub fn record(&self, inputs: &RunInputs) -> Result<(), StorageError> {
+        self.storage
+            .write(&Self::key(&inputs.run_id), &inputs.encode_to_vec())
+    }
+
+    /// Inputs of the run `run_id`, `None` if it wasn't recorded.
+    pub fn get(&self, run_id: &str) -> Result<Option<RunInputs>, StorageError> {
+        if !is_run_id(run_id) {
+            return Ok(None);
+        }
+
+        match self.storage.read(&Self::key(run_id))? {
+            Some(bytes) => RunInputs::decode(bytes.as_slice())
+                .map(Some)
+                .map_err(|e| StorageError::Io(io::Error::other(e))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Run ids are VM ids, `<workload name>-<8 hex digits>`: anything else can't name an
+/// object of a storage.
+pub fn is_run_id(run_id: &str) -> bool {
+    !run_id.is_empty()
+        && run_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+pub fn code_digest(code: &str) -> String {
+    format!("sha256:{:x}", Sha256::digest(code))
+}
+
+/// Digest of `env`, independent of the order of the variables.
+pub fn env_digest(env: &HashMap<String, String>) -> String {
+    let mut variables: Vec<_> = env.iter().collect();
+    variables.sort();
+
+    let mut hasher = Sha256::new();
+    for (name, value) in variables {
+        hasher.update(name);
+        hasher.update("=");
+        hasher.update(value);
+        hasher.update("\0");
+    }
+    format!("sha256:{:x}", hasher.finalize())
+}
+
+pub fn file_digest(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
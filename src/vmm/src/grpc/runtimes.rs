@@ -0,0 +1,48 @@
+use shared_models::Language;
+
+/// Runtime versions which can be pinned for each language, each one with its own rootfs image.
+/// Workloads which don't pin a version run on the latest image of their language.
+const SUPPORTED_VERSIONS: [(Language, &[&str]); 3] = [
+    (Language::RUST, &["1.75", "1.76", "1.77"]),
+    (Language::PYTHON, &["3.10", "3.11", "3.12"]),
+    (Language::NODE, &["18", "20", "22"]),
+];
+
+pub fn supported_versions(language: &Language) -> &'static [&'static str] {
+    SUPPORTED_VERSIONS
+        .iter()
+        .find(|(supported, _)| supported.as_str() == language.as_str())
+        .map(|(_, versions)| *versions)
+        .unwrap_or_default()
+}
+
+/// Check that `version` of the runtime of `language` can be provided.
+pub fn validate(language: &Language, version: &str) -> Result<(), String> {
+    let versions = supported_versions(language);
+    if versions.contains(&version) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} {} is not supported, supported versions: {}",
+            language.as_str(),
+            version,
+            versions.join(", ")
+        ))
+    }
+}
+
+/// Container image the rootfs of `language` is built from.
+pub fn image(language: &str, version: Option<&str>) -> String {
+    match version {
+        Some(version) => format!("{language}:{version}-alpine"),
+        None => format!("{language}:alpine"),
+    }
+}
+
+/// Name of the rootfs image of `language`, in tools/rootfs.
+pub fn rootfs_name(language: &str, version: Option<&str>) -> String {
+    match version {
+        Some(version) => format!("{language}-{version}"),
+        None => language.to_string(),
+    }
+}
@@ -0,0 +1,749 @@
+//! Scheduling of the guests on the host by priority class, and between tenants by fair share.
+//!
+//! The capacity of the host is capped by the scheduler configuration, a TOML file:
+//!
+//! ```toml
+//! max-vms = 8
+//! max-memory-mb = 65536
+//! preemption = true           # let higher priority runs stop lower priority guests
+//! default-class = "normal"    # class of the requests naming none
+//! queue-timeout-secs = 600    # let the requests which don't fit wait for room
+//! reserved-memory-mb = 2048   # kept for the orchestrator and the API, never given to guests
+//! reserved-cpus = 2           # the vCPUs of the guests add up to the other CPUs at most
+//!
+//! [[class]]
+//! name = "batch"
+//! priority = 0
+//!
+//! [[class]]
+//! name = "normal"
+//! priority = 50
+//!
+//! [[class]]
+//! name = "interactive"
+//! priority = 100
+//! preemptible = false
+//!
+//! [[tenant]]
+//! name = "infra"
+//! weight = 3                  # 1 for the tenants not listed
+//! ```
+//!
+//! When a guest doesn't fit, the preemptible guests of lower priority are preempted, lowest
+//! priority and most recent first, until it does: their clients get a `CLDT-VMM-020` error,
+//! their agent is asked to stop the workload and their resources are reused once their VM
+//! stopped. Otherwise the request fails like when the host is full, or waits in the queue
+//! when `queue-timeout-secs` is set.
+//!
+//! The memory and CPUs reserved are taken from those of the host, so that the control plane
+//! isn't OOM-killed or starved under load: a guest which would eat into them waits for room
+//! like on a full host, and one which doesn't fit even on an empty host is refused at once.
+//!
+//! The queued requests get the room released in the order of their priority, then of the share
+//! of the host their tenant uses, the memory of its guests divided by its weight, so that a
+//! tenant submitting many runs doesn't starve the others. Each decision is published as a
+//! `RUN_QUEUED` or `RUN_DEQUEUED` event.
+
+use super::{
+    deterministic::{system_clock, SharedClock},
+    events::EventBus,
+    validate::Host,
+};
+use serde::Deserialize;
+use shared_models::{vmmorchestrator::VmEventKind, ErrorCode};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{oneshot, Notify},
+    time::Instant,
+};
+use tonic::{Code, Status};
+use tracing::info;
+
+/// How long the preempted guests may take to stop.
+const PREEMPTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Class of the requests naming none, if the configuration doesn't set one.
+const DEFAULT_CLASS: &str = "default";
+
+#[derive(Debug)]
+pub enum SchedulerError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedulerError::Read(path, e) => write!(f, "Failed to read {:?}: {}", path, e),
+            SchedulerError::Parse(path, e) => {
+                write!(f, "Invalid scheduler configuration {:?}: {}", path, e)
+            }
+            SchedulerError::Invalid(message) => {
+                write!(f, "Invalid scheduler configuration: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SchedulerError {}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PriorityClass {
+    pub name: String,
+    /// Guests of a higher priority can preempt this class.
+    pub priority: i32,
+    #[serde(default = "default_preemptible")]
+    pub preemptible: bool,
+}
+
+fn default_preemptible() -> bool {
+    true
+}
+
+/// Weight of a tenant in the fair sharing of the host.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantShare {
+    pub name: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SchedulerConfig {
+    /// Number of guests the host runs at once, unlimited if unset.
+    pub max_vms: Option<usize>,
+    /// Memory the guests of the host get in total, unlimited if unset.
+    pub max_memory_mb: Option<u64>,
+    #[serde(default)]
+    pub preemption: bool,
+    pub default_class: Option<String>,
+    #[serde(default, rename = "class")]
+    pub classes: Vec<PriorityClass>,
+    /// How long a request which doesn't fit waits for room, failing at once if unset.
+    pub queue_timeout_secs: Option<u64>,
+    #[serde(default, rename = "tenant")]
+    pub tenants: Vec<TenantShare>,
+    /// Memory of the host kept for the orchestrator and the API.
+    pub reserved_memory_mb: Option<u64>,
+    /// CPUs of the host kept for the orchestrator and the API.
+    pub reserved_cpus: Option<usize>,
+}
+
+/// Guests, vCPUs and memory taken on the host.
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    vms: usize,
+    cpus: u64,
+    memory_mb: u64,
+}
+
+impl SchedulerConfig {
+    pub fn load(path: &Path) -> Result<Self, SchedulerError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| SchedulerError::Read(path.to_path_buf(), e))?;
+        let config: Self =
+            toml::from_str(&content).map_err(|e| SchedulerError::Parse(path.to_path_buf(), e))?;
+
+        for (i, class) in config.classes.iter().enumerate() {
+            if config.classes[..i].iter().any(|c| c.name == class.name) {
+                return Err(SchedulerError::Invalid(format!(
+                    "priority class {} is defined twice",
+                    class.name
+                )));
+            }
+        }
+        for (i, tenant) in config.tenants.iter().enumerate() {
+            if config.tenants[..i].iter().any(|t| t.name == tenant.name) {
+                return Err(SchedulerError::Invalid(format!(
+                    "the weight of the tenant {} is defined twice",
+                    tenant.name
+                )));
+            }
+            if tenant.weight == 0 {
+                return Err(SchedulerError::Invalid(format!(
+                    "the weight of the tenant {} must be at least 1",
+                    tenant.name
+                )));
+            }
+        }
+        if let Some(default) = &config.default_class {
+            if !config.classes.iter().any(|class| &class.name == default) {
+                return Err(SchedulerError::Invalid(format!(
+                    "the default class {} is not defined",
+                    default
+                )));
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn fits(&self, host: &Host, usage: Usage) -> bool {
+        self.max_vms.map_or(true, |max| usage.vms <= max)
+            && self
+                .max_memory_mb
+                .map_or(true, |max| usage.memory_mb <= max)
+            && self.headroom_violation(host, usage).is_none()
+    }
+
+    /// How `usage` eats into the resources of `host` reserved for the orchestrator, if it does.
+    fn headroom_violation(&self, host: &Host, usage: Usage) -> Option<String> {
+        if let (Some(reserved), Some(total)) = (self.reserved_memory_mb, host.memory_mb) {
+            if usage.memory_mb > total.saturating_sub(reserved) {
+                return Some(format!(
+                    "the guests would take {} MB of the {} MB of the host, {} MB of which are reserved for the orchestrator",
+                    usage.memory_mb, total, reserved
+                ));
+            }
+        }
+        if let (Some(reserved), Some(total)) = (self.reserved_cpus, host.cpus) {
+            if usage.cpus > total.saturating_sub(reserved) as u64 {
+                return Some(format!(
+                    "the guests would take {} vCPUs of the {} CPUs of the host, {} of which are reserved for the orchestrator",
+                    usage.cpus, total, reserved
+                ));
+            }
+        }
+        None
+    }
+
+    fn weight(&self, tenant: &str) -> u32 {
+        self.tenants
+            .iter()
+            .find(|t| t.name == tenant)
+            .map_or(1, |t| t.weight)
+    }
+}
+
+/// Guest admitted on the host, from its reservation until its VM stops.
+struct Guest {
+    id: u64,
+    workload_name: String,
+    tenant: String,
+    priority: i32,
+    preemptible: bool,
+    cpus: u8,
+    memory_mb: u32,
+    /// Taken when the guest is preempted.
+    preempt: Option<oneshot::Sender<String>>,
+}
+
+/// Request waiting in the queue for room on the host.
+struct Waiter {
+    id: u64,
+    tenant: String,
+    priority: i32,
+}
+
+/// Resolves with the reason of the preemption of a guest.
+pub type Preemption = oneshot::Receiver<String>;
+
+pub struct Scheduler {
+    config: RwLock<SchedulerConfig>,
+    guests: Mutex<Vec<Guest>>,
+    /// Locked after `guests` when both are.
+    queue: Mutex<Vec<Waiter>>,
+    /// Resources the reserved ones are taken from.
+    host: Host,
+    next_id: AtomicU64,
+    released: Notify,
+    clock: SharedClock,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            config: RwLock::default(),
+            guests: Mutex::default(),
+            queue: Mutex::default(),
+            host: Host::default(),
+            next_id: AtomicU64::default(),
+            released: Notify::default(),
+            clock: system_clock(),
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            ..Default::default()
+        }
+    }
+
+    /// Check the reservations of the configuration against the resources of `host`.
+    pub fn with_host(mut self, host: Host) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// Time the waits of the requests with `clock`.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Replace the configuration, the guests already admitted are kept even if they no longer
+    /// fit.
+    pub fn set_config(&self, config: SchedulerConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Time spent waiting since `start`, on the clock of the scheduler.
+    fn waited(&self, start: Instant) -> Duration {
+        Instant::from_std(self.clock.instant()).saturating_duration_since(start)
+    }
+
+    /// Priority class named `name`, the default class if empty.
+    pub fn class(&self, name: &str) -> Result<PriorityClass, Status> {
+        let config = self.config.read().unwrap();
+        let name = match name {
+            "" => config.default_class.as_deref().unwrap_or(DEFAULT_CLASS),
+            name => name,
+        };
+
+        match config.classes.iter().find(|class| class.name == name) {
+            Some(class) => Ok(class.clone()),
+            None if name == DEFAULT_CLASS => Ok(PriorityClass {
+                name: DEFAULT_CLASS.to_string(),
+                priority: 0,
+                preemptible: true,
+            }),
+            None => Err(ErrorCode::VmmUnknownPriorityClass.status(
+                Code::InvalidArgument,
+                format!("Unknown priority class {}", name),
+            )),
+        }
+    }
+
+    /// Reserve room for a guest of `class` with `cpus` and `memory_mb` of memory for `tenant`,
+    /// preempting guests of lower priority if needed, or waiting in the queue if it is enabled.
+    /// The room is released when the reservation is dropped.
+    pub async fn reserve(
+        self: &Arc<Self>,
+        class: &PriorityClass,
+        workload_name: &str,
+        tenant: &str,
+        (cpus, memory_mb): (u8, u32),
+        events: &EventBus,
+    ) -> Result<(Reservation, Preemption), Status> {
+        // A guest which doesn't fit on an empty host would wait for nothing.
+        let alone = usage(std::iter::empty(), cpus, memory_mb);
+        let violation = self
+            .config
+            .read()
+            .unwrap()
+            .headroom_violation(&self.host, alone);
+        if let Some(violation) = violation {
+            return Err(host_full(format!(
+                "The host can't run the guest: {}",
+                violation
+            )));
+        }
+
+        let start = Instant::from_std(self.clock.instant());
+        let mut deadline = start + PREEMPTION_TIMEOUT;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // Removes the request from the queue however the reservation ends.
+        let mut queued: Option<Queued> = None;
+        loop {
+            // Created before looking at the guests, so that no release is missed.
+            let released = self.released.notified();
+            {
+                let config = self.config.read().unwrap().clone();
+                let mut guests = self.guests.lock().unwrap();
+                let mut queue = self.queue.lock().unwrap();
+                // The queued requests go first, unless the queue was disabled since.
+                let turn = config.queue_timeout_secs.is_none()
+                    || next_in_queue(&config, &guests, &queue).map_or(true, |next| next.id == id);
+
+                if turn && config.fits(&self.host, usage(guests.iter(), cpus, memory_mb)) {
+                    if queued.is_some() {
+                        queue.retain(|waiter| waiter.id != id);
+                        let message = format!(
+                            "given room after {}s in the queue, {}",
+                            self.waited(start).as_secs(),
+                            describe_share(&config, &guests, tenant)
+                        );
+                        info!(workload = %workload_name, "Dequeued: {}", message);
+                        events.publish("", workload_name, VmEventKind::RunDequeued, message);
+                    }
+                    let (preempt, preemption) = oneshot::channel();
+                    guests.push(Guest {
+                        id,
+                        workload_name: workload_name.to_string(),
+                        tenant: tenant.to_string(),
+                        priority: class.priority,
+                        preemptible: class.preemptible,
+                        cpus,
+                        memory_mb,
+                        preempt: Some(preempt),
+                    });
+                    // The next queued request may fit too.
+                    if !queue.is_empty() {
+                        self.released.notify_waiters();
+                    }
+                    let reservation = Reservation {
+                        scheduler: self.clone(),
+                        id,
+                    };
+                    return Ok((reservation, preemption));
+                }
+
+                // The guests already preempted are on their way out.
+                let mut remaining = usage(
+                    guests.iter().filter(|guest| guest.preempt.is_some()),
+                    cpus,
+                    memory_mb,
+                );
+                if turn && !config.fits(&self.host, remaining) {
+                    let mut candidates: Vec<usize> = (0..guests.len())
+                        .filter(|i| {
+                            let guest = &guests[*i];
+                            config.preemption
+                                && guest.preempt.is_some()
+                                && guest.preemptible
+                                && guest.priority < class.priority
+                        })
+                        .collect();
+                    candidates.sort_by_key(|i| (guests[*i].priority, u64::MAX - guests[*i].id));
+
+                    let mut victims = Vec::new();
+                    for i in candidates {
+                        if config.fits(&self.host, remaining) {
+                            break;
+                        }
+                        remaining.vms -= 1;
+                        remaining.cpus -= guests[i].cpus as u64;
+                        remaining.memory_mb -= guests[i].memory_mb as u64;
+                        victims.push(i);
+                    }
+                    let fits = config.fits(&self.host, remaining);
+                    if !fits && config.queue_timeout_secs.is_none() {
+                        let reserved = config
+                            .headroom_violation(&self.host, remaining)
+                            .map(|violation| format!(": {}", violation))
+                            .unwrap_or_default();
+                        return Err(host_full(format!(
+                            "The host has no room for {} MB more in its capacity ({} VMs, {} MB){}",
+                            memory_mb,
+                            config
+                                .max_vms
+                                .map_or("unlimited".to_string(), |max| max.to_string()),
+                            config
+                                .max_memory_mb
+                                .map_or("unlimited".to_string(), |max| max.to_string()),
+                            reserved,
+                        )));
+                    }
+
+                    let reason = format!("{} of priority class {}", workload_name, class.name);
+                    // Without enough victims, the request waits in the queue instead.
+                    if !fits {
+                        victims.clear();
+                    }
+                    for i in victims {
+                        let guest = &mut guests[i];
+                        info!(workload = %guest.workload_name, by = %reason, "Preempting guest");
+                        if let Some(preempt) = guest.preempt.take() {
+                            let _ = preempt.send(reason.clone());
+                        }
+                    }
+                }
+
+                if let Some(timeout) = config.queue_timeout_secs.filter(|_| queued.is_none()) {
+                    queue.push(Waiter {
+                        id,
+                        tenant: tenant.to_string(),
+                        priority: class.priority,
+                    });
+                    queued = Some(Queued {
+                        scheduler: self.clone(),
+                        id,
+                    });
+                    deadline = deadline.max(start + Duration::from_secs(timeout));
+                    let message = format!(
+                        "waiting for room with {} other requests queued, {}",
+                        queue.len() - 1,
+                        describe_share(&config, &guests, tenant)
+                    );
+                    info!(workload = %workload_name, "Queued: {}", message);
+                    events.publish("", workload_name, VmEventKind::RunQueued, message);
+                }
+            }
+
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(host_full(match queued {
+                    Some(_) => format!(
+                        "The request waited {}s in the queue without the host having room for it",
+                        self.waited(start).as_secs()
+                    ),
+                    None => "The preempted guests didn't stop in time, retry later".to_string(),
+                }));
+            }
+        }
+    }
+}
+
+/// Memory the guests of `tenant` use.
+fn tenant_memory(guests: &[Guest], tenant: &str) -> u64 {
+    guests
+        .iter()
+        .filter(|guest| guest.tenant == tenant)
+        .map(|guest| guest.memory_mb as u64)
+        .sum()
+}
+
+/// Memory the guests of `tenant` use, divided by its weight.
+fn share(config: &SchedulerConfig, guests: &[Guest], tenant: &str) -> f64 {
+    tenant_memory(guests, tenant) as f64 / config.weight(tenant) as f64
+}
+
+fn describe_share(config: &SchedulerConfig, guests: &[Guest], tenant: &str) -> String {
+    format!(
+        "the tenant {} uses {} MB of the host for a weight of {}",
+        tenant,
+        tenant_memory(guests, tenant),
+        config.weight(tenant)
+    )
+}
+
+/// Next queued request to get room: of the highest priority, then of the tenant using the least
+/// of the host for its weight, then the oldest.
+fn next_in_queue<'a>(
+    config: &SchedulerConfig,
+    guests: &[Guest],
+    queue: &'a [Waiter],
+) -> Option<&'a Waiter> {
+    queue.iter().min_by(|a, b| {
+        b.priority
+            .cmp(&a.priority)
+            .then(share(config, guests, &a.tenant).total_cmp(&share(config, guests, &b.tenant)))
+            .then(a.id.cmp(&b.id))
+    })
+}
+
+/// Place of a request in the queue, left when it is dropped.
+struct Queued {
+    scheduler: Arc<Scheduler>,
+    id: u64,
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.scheduler
+            .queue
+            .lock()
+            .unwrap()
+            .retain(|waiter| waiter.id != self.id);
+        // The request may have been the next one, blocking the others.
+        self.scheduler.released.notify_waiters();
+    }
+}
+
+/// Resources used by `guests` plus a new guest with `cpus` and `memory_mb`.
+fn usage<'a>(guests: impl Iterator<Item = &'a Guest>, cpus: u8, memory_mb: u32) -> Usage {
+    let new = Usage {
+        vms: 1,
+        cpus: cpus as u64,
+        memory_mb: memory_mb as u64,
+    };
+    guests.fold(new, |usage, guest| Usage {
+        vms: usage.vms + 1,
+        cpus: usage.cpus + guest.cpus as u64,
+        memory_mb: usage.memory_mb + guest.memory_mb as u64,
+    })
+}
+
+fn host_full(message: impl Into<String>) -> Status {
+    ErrorCode::VmmHostFull.status(Code::ResourceExhausted, message)
+}
+
+/// Room of a guest on the host.
+pub struct Reservation {
+    scheduler: Arc<Scheduler>,
+    id: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.scheduler
+            .guests
+            .lock()
+            .unwrap()
+            .retain(|guest| guest.id != self.id);
+        self.scheduler.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler() -> Arc<Scheduler> {
+        Arc::new(Scheduler::new(
+            toml::from_str(
+                r#"
+                max-vms = 1
+                preemption = true
+
+                [[class]]
+                name = "batch"
+                priority = 0
+
+                [[class]]
+                name = "interactive"
+                priority = 100
+                "#,
+            )
+            .unwrap(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_preempt_lower_priority() {
+        let scheduler = scheduler();
+        let events = EventBus::default();
+        let batch = scheduler.class("batch").unwrap();
+        let interactive = scheduler.class("interactive").unwrap();
+
+        let (reservation, preemption) = scheduler
+            .reserve(&batch, "job", "", (1, 512), &events)
+            .await
+            .unwrap();
+        let waiting = {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move {
+                scheduler
+                    .reserve(&interactive, "shell", "", (1, 512), &EventBus::default())
+                    .await
+            })
+        };
+
+        assert_eq!(
+            preemption.await.unwrap(),
+            "shell of priority class interactive"
+        );
+        drop(reservation);
+        assert!(waiting.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_host_full() {
+        let scheduler = scheduler();
+        let events = EventBus::default();
+        let batch = scheduler.class("batch").unwrap();
+
+        let _reservation = scheduler
+            .reserve(&batch, "job", "", (1, 512), &events)
+            .await
+            .unwrap();
+        let status = scheduler
+            .reserve(&batch, "other", "", (1, 512), &events)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(scheduler.class("unknown").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reserved_headroom() {
+        let config = toml::from_str("reserved-memory-mb = 2048\nreserved-cpus = 1\n").unwrap();
+        let scheduler = Arc::new(Scheduler::new(config).with_host(Host {
+            memory_mb: Some(4096),
+            cpus: Some(4),
+            ..Default::default()
+        }));
+        let class = scheduler.class("").unwrap();
+        let events = EventBus::default();
+
+        let status = scheduler
+            .reserve(&class, "huge", "", (1, 3072), &events)
+            .await
+            .err()
+            .unwrap();
+        assert!(status.message().contains("2048 MB of which are reserved"));
+
+        let _reservation = scheduler
+            .reserve(&class, "job", "", (2, 1024), &events)
+            .await
+            .unwrap();
+        let status = scheduler
+            .reserve(&class, "other", "", (2, 512), &events)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(status.message().contains("4 vCPUs of the 4 CPUs"));
+    }
+
+    #[tokio::test]
+    async fn test_fair_share_queue() {
+        let scheduler = Arc::new(Scheduler::new(
+            toml::from_str(
+                r#"
+                max-vms = 3
+                queue-timeout-secs = 60
+
+                [[tenant]]
+                name = "infra"
+                weight = 2
+                "#,
+            )
+            .unwrap(),
+        ));
+        let class = scheduler.class("").unwrap();
+        let events = EventBus::default();
+        let reserve = |workload: &'static str, tenant: &'static str| {
+            let (scheduler, class, events) = (scheduler.clone(), class.clone(), events.clone());
+            tokio::spawn(async move {
+                scheduler
+                    .reserve(&class, workload, tenant, (1, 512), &events)
+                    .await
+                    .map(|(reservation, _)| reservation)
+            })
+        };
+        let queued = |len: usize| {
+            let scheduler = scheduler.clone();
+            async move {
+                while scheduler.queue.lock().unwrap().len() != len {
+                    tokio::task::yield_now().await;
+                }
+            }
+        };
+
+        let _web = reserve("web-1", "web").await.unwrap().unwrap();
+        let _infra = reserve("infra-1", "infra").await.unwrap().unwrap();
+        let batch = reserve("batch-1", "batch").await.unwrap().unwrap();
+        let web = reserve("web-2", "web");
+        queued(1).await;
+        let infra = reserve("infra-2", "infra");
+        queued(2).await;
+
+        // Both tenants use 512 MB, which is half the share for the weight of infra.
+        drop(batch);
+        let infra = infra.await.unwrap().unwrap();
+        assert!(!web.is_finished());
+        drop(infra);
+        web.await.unwrap().unwrap();
+        assert!(scheduler.queue.lock().unwrap().is_empty());
+    }
+}
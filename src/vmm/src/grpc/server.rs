@@ -1,12 +1,85 @@
-use self::vmmorchestrator::{
-    vmm_service_server::VmmService as VmmServiceTrait, Language, RunVmmRequest, ShutdownVmRequest,
-    ShutdownVmResponse,
-};
-use crate::grpc::client::agent::ExecuteRequest;
 use crate::VmmErrors;
-use crate::{core::vmm::VMM, grpc::client::WorkloadClient};
+use crate::{
+    core::{
+        cpu_template::CpuTemplate,
+        exit::VmExit,
+        memory::GuestMemoryConfig,
+        network::{AddressLease, GuestAddresses, GuestNetwork, TenantNetworks},
+        placement::{CpuAllocator, CpuPlacement, CpuPolicy, HostTopology},
+        rate_limiter::IoLimits,
+        vfio::{VfLease, VfPool, VfioDevice},
+    },
+    grpc::{
+        admin::{agent_port, VmTable},
+        admission::{self, AdmissionPolicy},
+        affinity, artifacts,
+        builds::{self, BuildCache},
+        client::WorkloadClient,
+        config::Settings,
+        console::{ConsoleLog, ConsoleLogConfig},
+        crashes::{self, RunArtifacts},
+        deadline::{self, Deadline},
+        deterministic::{system_clock, Deterministic, SharedClock},
+        events::EventBus,
+        faults::Faults,
+        health,
+        history::RunHistory,
+        hypervisor::{Guest, GuestConfig, Hypervisor},
+        integrity::{ArtifactManifest, ArtifactSource, Checked},
+        janitor,
+        kernels::{Kernel, KernelRegistry, REQUIRED_FEATURES},
+        logs::{LogFilter, LogStore, RunLogs},
+        maintenance::Maintenance,
+        metering::{self, UsageMeter},
+        pool::{FunctionPool, Lease, PoolConfig},
+        registry::{self, Pulled, RootfsRegistry},
+        retries::{self, Attempt},
+        rootfs_builds::RootfsBuilds,
+        runs::{self, RunStore},
+        runtimes,
+        scheduler::{Scheduler, SchedulerConfig},
+        sessions::{AgentStream, Recorder, ReplayAgent, Session},
+        signatures::{self, ArtifactSignatures, SIGNATURE_SUFFIX},
+        storage::Storage,
+        stream::{self, RunMessage},
+        tasks::VmTasks,
+        validate::{self, Host},
+        webhooks::{Payload, WebhookNotifier},
+        workloads::{self, WorkloadStore},
+    },
+};
+use prost::Message;
+use regex::bytes::RegexBuilder;
+use shared_models::cloudlet::agent::{
+    execute_response::Stage, shell_input::Input as ShellInputKind, vm_exit::Reason as VmExitReason,
+    ExecuteRequest, ExecuteResponse, ListDirResponse, OutputLimits, PipelineStage,
+    ReadFileResponse, RunArtifact, ShellInput, ShellOutput, VmExit as AgentVmExit,
+};
+use shared_models::labels::{self, LabelSelector};
+use shared_models::vmmorchestrator::admin::VmInfo;
+use shared_models::vmmorchestrator::{
+    vmm_service_server::VmmService as VmmServiceTrait, ArtifactPlan, Capability, Device,
+    ExportUsageRequest, ExportUsageResponse, GetRunArtifactRequest, GetRunDebugInfoRequest,
+    GetRunInputsRequest, GetServerInfoRequest, InvokeWorkloadRequest, KernelInfo,
+    ListGuestDirRequest, ListPoolsRequest, ListPoolsResponse, ListRunArtifactsRequest,
+    ListRunArtifactsResponse, ListRunsRequest, ListRunsResponse, ListVmMetricsRequest,
+    ListVmMetricsResponse, ListWorkloadsRequest, ListWorkloadsResponse, PoolStatus,
+    ReadGuestFileRequest, RegisterWorkloadRequest, RegisteredWorkload, RunDebugInfo, RunInputs,
+    RunPlan, RunVmmRequest, RuntimeInfo, ServerInfo, ShutdownVmRequest, ShutdownVmResponse,
+    StreamLogsRequest, UpgradePoolsRequest, UpgradePoolsResponse, UsageRecord, VmEvent,
+    VmEventKind, VmMetrics, WatchEventsRequest, Webhook,
+};
+use shared_models::{
+    redact_env, ErrorCode, Language, Redactor, AGENT_PORT_PARAMETER, DEFAULT_AGENT_PORT,
+    EXEC_SHELL_PARAMETER, REDACTED,
+};
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::time::Duration;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{
     convert::From,
     env::current_dir,
@@ -14,18 +87,275 @@ use std::{
     path::{Path, PathBuf},
     process::{Command, Stdio},
 };
-use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response, Status};
-use tracing::{error, info};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::{Code, Request, Response, Status, Streaming};
+use tonic_health::server::HealthReporter;
+use tracing::{error, info, warn};
 
 type Result<T> = std::result::Result<Response<T>, tonic::Status>;
 
-pub mod vmmorchestrator {
-    tonic::include_proto!("vmmorchestrator");
+/// Resources given to a guest when the request doesn't specify them.
+const DEFAULT_CPUS: u8 = 1;
+const DEFAULT_MEMORY_MB: u32 = 4000;
+
+/// Metadata of the Run response carrying the id of the VM, to attach to its logs later.
+pub const VM_ID_METADATA: &str = "x-cloudlet-vm-id";
+
+/// Metadata of the invocations of the registered workloads, `warm` if they reused a running VM
+/// and `cold` if they started one.
+pub const START_METADATA: &str = "x-cloudlet-start";
+
+/// Time given to the agent of a running VM to accept a connection, and to the one of a VM just
+/// booted, after a first delay, to come up.
+const AGENT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time given to a guest to power itself off at the end of its run, before it is stopped.
+const POWER_OFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time given to the VM to stop once its agent is gone or asked to stop it, for its exit to be
+/// reported at the end of the run.
+const VM_EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Extension of the Run requests of the pool instances, whose VM is kept for the next runs.
+#[derive(Clone, Copy)]
+struct KeepVm;
+
+/// Host CPUs the vCPUs of a guest are pinned to, and the backing file of its pmem device,
+/// released when dropped: by a run whose guest couldn't be created, or once the VM stopped.
+struct GuestResources {
+    cpus: Arc<CpuAllocator>,
+    vcpus: usize,
+    placement: Option<CpuPlacement>,
+    /// Backing file of the pmem device, and whether it's kept after the VM.
+    pmem_file: Option<(PathBuf, bool)>,
+}
+
+impl Drop for GuestResources {
+    fn drop(&mut self) {
+        if let Some(placement) = &self.placement {
+            self.cpus.release(placement, self.vcpus);
+        }
+        if let Some((path, false)) = &self.pmem_file {
+            match std::fs::remove_file(path) {
+                Ok(()) => {}
+                // The guest didn't get as far as creating it.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => error!("Could not remove pmem backing file {:?}: {:?}", path, e),
+            }
+        }
+    }
+}
+
+/// Location of the guest kernel, relative to the working directory.
+pub const KERNEL_PATH: &str = "/tools/kernel/vmlinux.bin";
+
+/// Kernel produced by `mkkernel.sh` in its build tree, copied to [`KERNEL_PATH`] once built.
+const KERNEL_BUILD_OUTPUT: &str =
+    "/tools/kernel/linux-cloud-hypervisor/arch/x86/boot/compressed/vmlinux.bin";
+
+/// Resources requested by the client, falling back on the defaults for clients which don't send them.
+fn requested_resources(request: &RunVmmRequest) -> (u8, u32) {
+    let cpus = u8::try_from(request.cpus)
+        .ok()
+        .filter(|cpus| *cpus > 0)
+        .unwrap_or(DEFAULT_CPUS);
+    let memory_mb = match request.memory_mb {
+        0 => DEFAULT_MEMORY_MB,
+        memory_mb => memory_mb,
+    };
+
+    (cpus, memory_mb)
+}
+
+/// Runtime version pinned by the client, if it is available.
+fn requested_runtime_version(
+    request: &RunVmmRequest,
+    language: &Language,
+) -> std::result::Result<Option<String>, Status> {
+    let version = request
+        .build
+        .as_ref()
+        .map(|build| build.runtime_version.clone())
+        .filter(|version| !version.is_empty());
+
+    if let Some(version) = &version {
+        runtimes::validate(language, version)
+            .map_err(|e| ErrorCode::VmmUnsupportedRuntime.status(Code::InvalidArgument, e))?;
+    }
+
+    Ok(version)
+}
+
+/// Path of the built-in kernel.
+pub fn builtin_kernel_path(curr_dir: &OsStr) -> PathBuf {
+    let mut path = curr_dir.to_os_string();
+    path.push(KERNEL_PATH);
+    PathBuf::from(path)
+}
+
+/// Path of the initramfs image built for `language`, at `version` if pinned.
+pub fn initramfs_path(curr_dir: &OsStr, language: &str, version: Option<&str>) -> PathBuf {
+    let mut path = curr_dir.to_os_string();
+    path.push(format!(
+        "/tools/rootfs/{}.img",
+        runtimes::rootfs_name(language, version)
+    ));
+    PathBuf::from(path)
+}
+
+/// Key of the logs of the VM `vm_id` in the shared storage.
+fn logs_key(vm_id: &str) -> String {
+    format!("logs/{}.pb", vm_id)
+}
+
+/// Summary of the end of a run, from its final message.
+fn run_summary(response: &ExecuteResponse) -> String {
+    let end = match response.signal_name() {
+        None => format!("exit code {}", response.exit_code.unwrap_or_default()),
+        Some(signal) => format!(
+            "killed by {}{}",
+            signal,
+            if response.core_dumped {
+                ", core dumped"
+            } else {
+                ""
+            }
+        ),
+    };
+    let truncated = if response.truncated {
+        ", output truncated"
+    } else {
+        ""
+    };
+    let cached = if response.build_cached {
+        ", cached build"
+    } else {
+        ""
+    };
+    format!("{}{}{}", end, truncated, cached)
+}
+
+fn duplicate_workload(workload_name: &str) -> Status {
+    ErrorCode::VmmDuplicateWorkload.status(
+        Code::AlreadyExists,
+        format!("A VM of the workload {} is already running", workload_name),
+    )
+}
+
+fn unknown_workload(name: &str, version: &str) -> Status {
+    ErrorCode::VmmUnknownWorkload.status(
+        Code::NotFound,
+        match version {
+            "" => format!("No workload {} is registered", name),
+            version => format!(
+                "No version {} of the workload {} is registered",
+                version, name
+            ),
+        },
+    )
+}
+
+/// Ask the agent of the running VM `vm_id` to stop it for `reason`, killing the VM if the agent
+/// can't.
+async fn stop_vm(vms: &VmTable, vm_id: &str, reason: &str) {
+    let Some((guest_ip, port)) = vms.resolve(vm_id).and_then(|vm| {
+        let guest_ip = vm.guest_ip.parse::<Ipv4Addr>().ok()?;
+        Some((guest_ip, agent_port(&vm)))
+    }) else {
+        return;
+    };
+    vms.mark_stopping(vm_id, reason);
+    match tokio::time::timeout(AGENT_CONNECT_TIMEOUT, WorkloadClient::new(guest_ip, port)).await {
+        Ok(Ok(mut client)) => {
+            info!(vm_id, reason, "Stopping VM");
+            let request = ShutdownVmRequest {
+                vm: vm_id.to_string(),
+            };
+            match client.shutdown(request).await {
+                Ok(response) if response.success => {}
+                Ok(_) => {
+                    warn!(vm_id, "The agent could not stop the VM");
+                    vms.kill(vm_id, reason);
+                }
+                Err(e) => {
+                    warn!(vm_id, error = %e, "Could not stop the VM");
+                    vms.kill(vm_id, reason);
+                }
+            }
+        }
+        _ => {
+            warn!(vm_id, "Could not connect to the agent to stop the VM");
+            vms.kill(vm_id, reason);
+        }
+    }
+}
+
+/// Why the orchestrator ended a run before its agent did.
+enum Interruption {
+    /// By a run of a higher priority class, named.
+    Preempted(String),
+    DeadlineExceeded(Deadline),
+}
+
+impl fmt::Display for Interruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interruption::Preempted(by) => write!(f, "preempted by {}", by),
+            Interruption::DeadlineExceeded(deadline) => {
+                write!(f, "hard deadline of {:?} exceeded", deadline.limit())
+            }
+        }
+    }
+}
+
+/// How the VM of a run stopped, from the result of its run and why the orchestrator stopped it
+/// if it did.
+fn vm_exit(
+    result: &std::result::Result<VmExit, VmmErrors>,
+    stop_reason: Option<String>,
+) -> AgentVmExit {
+    let (reason, message) = match (result, stop_reason) {
+        // Asked to its agent, the guest powers off.
+        (Ok(VmExit::Shutdown), Some(stop_reason)) => (VmExitReason::Killed, stop_reason),
+        (Ok(VmExit::Shutdown), None) => (VmExitReason::GuestShutdown, String::new()),
+        (Ok(VmExit::Crash(message)), _) => (VmExitReason::GuestCrash, message.clone()),
+        (Ok(VmExit::Killed(message)), _) => (VmExitReason::Killed, message.clone()),
+        (Err(e), _) => (VmExitReason::HostError, format!("{:?}", e)),
+    };
+    AgentVmExit {
+        reason: reason as i32,
+        message,
+    }
+}
+
+fn artifact_plan(path: PathBuf) -> ArtifactPlan {
+    ArtifactPlan {
+        cached: path.exists(),
+        path: path.to_string_lossy().into_owned(),
+    }
+}
+
+/// Record the checksum of the artifact at `path`, just built, fetched or pulled, in the
+/// manifest the next boots check it against.
+fn record_artifact(curr_dir: &OsStr, path: &Path, source: ArtifactSource) {
+    match ArtifactManifest::new(curr_dir).record(path, source) {
+        Ok(entry) => {
+            info!(artifact = ?path, sha256 = %entry.sha256, "Recorded the checksum of the artifact")
+        }
+        Err(e) => warn!(artifact = ?path, error = %e, "Could not update the artifact manifest"),
+    }
 }
 
-pub mod agent {
-    tonic::include_proto!("cloudlet.agent");
+/// Digest of the artifact at `path` in the manifest, recorded when it was built, fetched or
+/// pulled, or checked before this boot.
+fn recorded_digest(curr_dir: &OsStr, path: &Path) -> Option<String> {
+    let manifest = ArtifactManifest::new(curr_dir);
+    let entries = tokio::task::block_in_place(|| manifest.entries())
+        .map_err(|e| warn!(artifact = ?path, error = %e, "Could not read the artifact manifest"))
+        .ok()?;
+    entries
+        .get(&manifest.name(path))
+        .map(|entry| entry.sha256.clone())
 }
 
 // Implement the From trait for VmmErrors into Status
@@ -34,40 +364,749 @@ impl From<VmmErrors> for Status {
         // log the gRPC error before sending it
         error!("VMM error: {:?}", error);
 
-        // You can create a custom Status variant based on the error
-        match error {
-            VmmErrors::VmmNew(_) => Status::internal("Error creating VMM"),
-            VmmErrors::VmmConfigure(_) => Status::internal("Error configuring VMM"),
-            VmmErrors::VmmRun(_) => Status::internal("Error running VMM"),
-            VmmErrors::VmmBuildEnvironment(_) => {
-                Status::internal("Error while compiling the necessary files for the VMM")
-            }
-        }
+        let (code, message) = match &error {
+            VmmErrors::VmmNew(e) | VmmErrors::VmmConfigure(e) if e.is_network() => (
+                ErrorCode::VmmTapCreation,
+                format!("TAP creation failed: {:?}", e),
+            ),
+            VmmErrors::VmmNew(e) if e.is_kvm() => (
+                ErrorCode::VmmKvmUnavailable,
+                format!("Error creating VMM, KVM is unavailable: {:?}", e),
+            ),
+            VmmErrors::VmmNew(e) | VmmErrors::VmmConfigure(e) => (
+                ErrorCode::VmmConfigure,
+                format!("Error configuring VMM: {:?}", e),
+            ),
+            VmmErrors::VmmRun(e) => (ErrorCode::VmmRun, format!("Error running VMM: {:?}", e)),
+            VmmErrors::VmmHypervisor(e) => (
+                ErrorCode::VmmHypervisor,
+                format!("Error driving the external hypervisor: {}", e),
+            ),
+            VmmErrors::VmmBuildEnvironment(e) => (
+                ErrorCode::VmmArtifactBuild,
+                format!(
+                    "Error while compiling the necessary files for the VMM: {}",
+                    e
+                ),
+            ),
+        };
+
+        code.status(Code::Internal, message)
+    }
+}
+
+/// Settings of the orchestrator, applied to every guest it starts.
+#[derive(Debug, Clone, Default)]
+pub struct VmmServiceConfig {
+    /// Placement of the vCPUs on the host CPUs.
+    pub cpu_policy: CpuPolicy,
+    /// Allocation of the guest memory.
+    pub memory: GuestMemoryConfig,
+    /// Scratch space given to each guest as a virtio-pmem device, if any.
+    pub pmem: Option<PmemConfig>,
+    /// Host GPUs which can be passed through to guests.
+    pub gpus: Vec<VfioDevice>,
+    /// Host interface the guests reach the outside through, any the host routes to if unset.
+    pub uplink: Option<String>,
+    /// SR-IOV virtual functions which can be passed through to guests.
+    pub sriov_vfs: VfPool,
+    /// Labels of the node, matched by the node affinity of the runs.
+    pub node_labels: HashMap<String, String>,
+    /// Expose the virtualization extensions to the guests.
+    pub nested_virtualization: bool,
+    /// CPUID presented to the guests.
+    pub cpu_template: CpuTemplate,
+    /// Kernels the guests can boot.
+    pub kernels: KernelRegistry,
+    /// Keys the kernels and the rootfs images must be signed with, if they are checked.
+    pub signatures: Option<ArtifactSignatures>,
+    /// Registry to pull the rootfs images from, instead of building them.
+    pub rootfs_registry: Option<RootfsRegistry>,
+    /// Caps on the output the agents stream back, 0 for their defaults.
+    pub output_limits: OutputLimits,
+    /// Where to record the inputs of the runs, if they are recorded.
+    pub runs: Option<RunStore>,
+    /// Where to cache the workloads built by the runs, if they are cached.
+    pub builds: Option<BuildCache>,
+    /// Where to keep the registered workloads, if they can be registered.
+    pub workloads: Option<WorkloadStore>,
+    /// VMs kept running between the invocations of the registered workloads.
+    pub pool: PoolConfig,
+    /// Storage shared with the other orchestrators, for the built images and the run logs.
+    pub storage: Option<Arc<dyn Storage>>,
+    /// Notified of the end of every run.
+    pub webhooks: Vec<Webhook>,
+    /// Hosts the webhooks may be posted to though they are internal addresses or resolve to
+    /// some.
+    pub webhook_internal_hosts: Vec<String>,
+    /// Hypervisor starting the guests.
+    pub hypervisor: Hypervisor,
+    /// Policy the requests must comply with, checked before creating their VM.
+    pub admission: Option<AdmissionPolicy>,
+    /// Capacity of the host and priority classes of the guests.
+    pub scheduler: SchedulerConfig,
+    /// Faults to inject, shared with the admin service.
+    pub faults: Arc<Faults>,
+    /// Cordon of the orchestrator, shared with the admin service.
+    pub maintenance: Arc<Maintenance>,
+    /// Port the agents listen on in the guests, 0 for the default one.
+    pub agent_port: u16,
+    /// Let the clients open shells in the guests.
+    pub exec_shell: bool,
+    /// Where to record the sessions with the agents, if they are recorded.
+    pub sessions: Option<PathBuf>,
+    /// Where to write the consoles of the guests, the standard output otherwise.
+    pub console_logs: Option<ConsoleLogConfig>,
+    /// Port of the caching proxy of the package registries, which the guests are pointed to.
+    pub package_cache_port: Option<u16>,
+    /// Give the guests of each tenant a network of their own, isolated from the others.
+    pub isolate_tenants: bool,
+    /// Throttling of the IO of the guests, also the highest limits the requests can set.
+    pub io_limits: IoLimits,
+    /// Attempts made again on a fresh VM when a run fails on an infrastructure error.
+    pub infra_retries: u32,
+    /// Settings in use, as given in the debug information of the runs (see
+    /// [`Settings::snapshot`]).
+    pub settings_snapshot: String,
+    /// Seed and clock of the deterministic mode, if it's enabled. Its VM table is to be
+    /// created with [`VmTable::deterministic`].
+    pub deterministic: Option<Deterministic>,
+    /// Longest run, the runs asking for longer being cut short, see the deadline module. No
+    /// limit if unset.
+    pub max_run_duration: Option<Duration>,
+}
+
+/// Host files backing the virtio-pmem device of each guest.
+///
+/// The guest accesses the file through the host page cache, so the host can write
+/// the pages back and reclaim them under memory pressure instead of keeping them
+/// resident like the guest RAM.
+#[derive(Debug, Clone)]
+pub struct PmemConfig {
+    /// Directory where the backing files are created.
+    pub dir: PathBuf,
+    /// Size of the device of each guest.
+    pub size_mb: u32,
+    /// Keep the backing file once the guest stops, instead of deleting it.
+    pub keep: bool,
+}
+
+impl PmemConfig {
+    /// Path of a new backing file for a guest running `workload_name`.
+    fn backing_file(&self, workload_name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        self.dir.join(format!("{}-{}.pmem", workload_name, nanos))
     }
 }
 
-#[derive(Default)]
-pub struct VmmService;
+pub struct VmmService {
+    vms: VmTable,
+    cpus: Arc<CpuAllocator>,
+    memory: GuestMemoryConfig,
+    pmem: Option<PmemConfig>,
+    logs: LogStore,
+    events: EventBus,
+    history: Arc<RunHistory>,
+    gpus: Vec<VfioDevice>,
+    /// Interface of the default route of the host, through which the guests reach the outside
+    /// world, if it has one.
+    default_route: Option<String>,
+    /// Interface the guests are bound to, `--uplink`, if set.
+    uplink: Option<String>,
+    sriov_vfs: VfPool,
+    node_labels: HashMap<String, String>,
+    nested_virtualization: bool,
+    cpu_template: CpuTemplate,
+    kernels: RwLock<KernelRegistry>,
+    signatures: RwLock<Option<ArtifactSignatures>>,
+    rootfs_registry: RwLock<Option<RootfsRegistry>>,
+    rootfs_builds: RootfsBuilds,
+    output_limits: RwLock<OutputLimits>,
+    runs: Option<RunStore>,
+    builds: Option<BuildCache>,
+    workloads: Option<WorkloadStore>,
+    pool: Arc<FunctionPool>,
+    usage: Arc<UsageMeter>,
+    artifacts: Arc<RunArtifacts>,
+    storage: Option<Arc<dyn Storage>>,
+    webhooks: WebhookNotifier,
+    hypervisor: Hypervisor,
+    admission: RwLock<Option<Arc<AdmissionPolicy>>>,
+    scheduler: Arc<Scheduler>,
+    faults: Arc<Faults>,
+    maintenance: Arc<Maintenance>,
+    agent_port: u16,
+    exec_shell: bool,
+    sessions: Option<PathBuf>,
+    console_logs: Option<Arc<ConsoleLogConfig>>,
+    package_cache_port: Option<u16>,
+    tenant_networks: Option<TenantNetworks>,
+    addresses: GuestAddresses,
+    io_limits: IoLimits,
+    infra_retries: u32,
+    max_run_duration: Option<Duration>,
+    settings_snapshot: RwLock<String>,
+    clock: SharedClock,
+}
+
+impl Default for VmmService {
+    fn default() -> Self {
+        Self::new(VmTable::default(), VmmServiceConfig::default())
+    }
+}
 
 impl VmmService {
+    /// Create a service recording the VMs it starts in `vms`.
+    pub fn new(vms: VmTable, config: VmmServiceConfig) -> Self {
+        let clock = config
+            .deterministic
+            .as_ref()
+            .map_or_else(system_clock, Deterministic::clock);
+        let events = EventBus::new(clock.clone());
+        // The deterministic mode doesn't depend on the resources of the host either.
+        let (host, default_route) = match config.deterministic {
+            Some(_) => (Host::default(), Some("deterministic".to_string())),
+            None => (Host::probe(), validate::probe_default_route()),
+        };
+        Self {
+            vms,
+            cpus: Arc::new(CpuAllocator::new(config.cpu_policy, HostTopology::detect())),
+            memory: config.memory,
+            pmem: config.pmem,
+            logs: LogStore::default(),
+            webhooks: WebhookNotifier::new(
+                config.webhooks,
+                config.webhook_internal_hosts,
+                events.clone(),
+            ),
+            pool: Arc::new(
+                FunctionPool::new(config.pool, events.clone()).with_clock(clock.clone()),
+            ),
+            usage: Arc::default(),
+            artifacts: Arc::default(),
+            events,
+            history: Arc::new(RunHistory::new(clock.clone())),
+            gpus: config.gpus,
+            default_route,
+            uplink: config.uplink,
+            sriov_vfs: config.sriov_vfs,
+            node_labels: config.node_labels,
+            nested_virtualization: config.nested_virtualization,
+            cpu_template: config.cpu_template,
+            kernels: RwLock::new(config.kernels),
+            signatures: RwLock::new(config.signatures),
+            rootfs_registry: RwLock::new(config.rootfs_registry),
+            rootfs_builds: RootfsBuilds::default(),
+            output_limits: RwLock::new(config.output_limits),
+            runs: config.runs,
+            builds: config.builds,
+            workloads: config.workloads,
+            storage: config.storage,
+            hypervisor: config.hypervisor,
+            admission: RwLock::new(config.admission.map(Arc::new)),
+            scheduler: Arc::new(
+                Scheduler::new(config.scheduler)
+                    .with_host(host)
+                    .with_clock(clock.clone()),
+            ),
+            faults: config.faults,
+            maintenance: config.maintenance,
+            agent_port: match config.agent_port {
+                0 => DEFAULT_AGENT_PORT,
+                port => port,
+            },
+            exec_shell: config.exec_shell,
+            sessions: config.sessions,
+            console_logs: config.console_logs.map(Arc::new),
+            package_cache_port: config.package_cache_port,
+            tenant_networks: match config.deterministic {
+                Some(_) => Some(TenantNetworks::fake()),
+                None => config.isolate_tenants.then(TenantNetworks::default),
+            },
+            addresses: GuestAddresses::default(),
+            io_limits: config.io_limits,
+            infra_retries: config.infra_retries,
+            max_run_duration: config.max_run_duration,
+            settings_snapshot: RwLock::new(config.settings_snapshot),
+            clock,
+        }
+    }
+
+    /// Time `request` may run for, its timeout within the limit of the orchestrator.
+    fn run_limit(&self, request: &RunVmmRequest) -> Option<Duration> {
+        deadline::run_limit(request.timeout_secs, self.max_run_duration)
+    }
+
+    /// Throttling of the IO of the guest of `request`, its limits within the ones of the
+    /// orchestrator.
+    fn io_limits(&self, request: &RunVmmRequest) -> IoLimits {
+        let requested = request.io_limits.clone().unwrap_or_default();
+        IoLimits {
+            net_bytes_per_sec: requested.net_bytes_per_sec,
+            net_packets_per_sec: requested.net_packets_per_sec,
+            disk_flushes_per_sec: requested.disk_flushes_per_sec,
+        }
+        .within(self.io_limits)
+    }
+
+    /// Kernel command line of the guests booting a kernel with `cmdline`.
+    fn kernel_cmdline(&self, cmdline: &[String]) -> Vec<String> {
+        let mut cmdline = cmdline.to_vec();
+        if self.agent_port != DEFAULT_AGENT_PORT {
+            cmdline.push(format!("{}={}", AGENT_PORT_PARAMETER, self.agent_port));
+        }
+        if self.exec_shell {
+            cmdline.push(format!("{}=1", EXEC_SHELL_PARAMETER));
+        }
+        cmdline
+    }
+
+    /// Addresses leased to the guests, shared with the admin service.
+    pub fn guest_addresses(&self) -> GuestAddresses {
+        self.addresses.clone()
+    }
+
+    /// Pools of the registered workloads, shared with the admin service.
+    pub fn pool(&self) -> Arc<FunctionPool> {
+        self.pool.clone()
+    }
+
+    /// Apply settings reloaded from the configuration, to the requests received from now on.
+    pub fn apply_settings(&self, settings: &Settings) {
+        *self.kernels.write().unwrap() = settings.kernels.clone();
+        *self.signatures.write().unwrap() = settings.signatures.clone();
+        *self.admission.write().unwrap() = settings.admission.clone().map(Arc::new);
+        self.scheduler.set_config(settings.scheduler.clone());
+        *self.output_limits.write().unwrap() = settings.output_limits.clone();
+        let repinned = match self.rootfs_registry.write().unwrap().as_mut() {
+            Some(registry) => {
+                let repinned = registry.changed_pins(&settings.rootfs_pins);
+                registry.set_pins(settings.rootfs_pins.clone());
+                repinned
+            }
+            None => Vec::new(),
+        };
+        *self.settings_snapshot.write().unwrap() = settings.snapshot();
+
+        // The VMs of the pools booted the images of the previous pins.
+        for (language, version) in repinned {
+            let request = UpgradePoolsRequest {
+                name: String::new(),
+                language: language.clone(),
+                runtime_version: Some(version.unwrap_or_default()),
+            };
+            if let Err(e) = self.upgrade_pools_matching(&request, "the rootfs image was repinned") {
+                warn!(language = %language, error = %e.message(), "Could not upgrade the pools");
+            }
+        }
+    }
+
+    /// Start the rolling upgrade of the pools of the workloads matching `request`, for
+    /// `reason`. Returns the state of these pools.
+    fn upgrade_pools_matching(
+        &self,
+        request: &UpgradePoolsRequest,
+        reason: &str,
+    ) -> std::result::Result<Vec<PoolStatus>, Status> {
+        let mut workloads = Vec::new();
+        for pool in self.pool.status(&request.name) {
+            if !request.language.is_empty() {
+                let workload = self
+                    .workload_store()?
+                    .get(&pool.name, &pool.version)
+                    .map_err(|e| {
+                        Status::internal(format!("Could not read the workloads: {}", e))
+                    })?;
+                let Some(run) = workload.and_then(|workload| workload.request) else {
+                    continue;
+                };
+                let language = Language::try_from(run.language).map(|language| language.as_str());
+                let pinned = run
+                    .build
+                    .as_ref()
+                    .map(|build| build.runtime_version.as_str())
+                    .unwrap_or_default();
+                if language.ok() != Some(request.language.as_str())
+                    || request
+                        .runtime_version
+                        .as_ref()
+                        .is_some_and(|version| *version != pinned)
+                {
+                    continue;
+                }
+            }
+            workloads.push(pool.name);
+        }
+        Ok(self.pool.upgrade(&workloads, reason))
+    }
+
+    /// The settings in use, those of the options included, the secrets they hold redacted.
+    fn config_snapshot(&self) -> String {
+        let webhooks: Vec<&str> = self.webhooks.urls().collect();
+        format!(
+            "{}hypervisor = {}\nagent-port = {}\nexec-shell = {}\nisolate-tenants = {}\ninfra-retries = {}\nio-limits = {:?}\nwebhooks = {:?}\n",
+            self.settings_snapshot.read().unwrap(),
+            self.hypervisor.name(),
+            self.agent_port,
+            self.exec_shell,
+            self.tenant_networks.is_some(),
+            self.infra_retries,
+            self.io_limits,
+            webhooks,
+        )
+    }
+
+    fn workload_store(&self) -> std::result::Result<&WorkloadStore, Status> {
+        self.workloads.as_ref().ok_or_else(|| {
+            ErrorCode::VmmUnknownWorkload.status(
+                Code::FailedPrecondition,
+                "The orchestrator doesn't keep registered workloads",
+            )
+        })
+    }
+
+    /// Apply the admission policy to `request`, which it may change.
+    async fn admit(&self, request: &mut RunVmmRequest) -> std::result::Result<(), Status> {
+        let Some(admission) = self.admission.read().unwrap().clone() else {
+            return Ok(());
+        };
+
+        let (cpus, memory_mb) = requested_resources(request);
+        admission.admit(request, cpus.into(), memory_mb).await
+    }
+
+    /// Check that the devices requested for the guest can be assigned to it.
+    fn check_devices(&self, request: &RunVmmRequest) -> std::result::Result<(), Status> {
+        for device in request.devices() {
+            match device {
+                Device::Gpu if self.gpus.is_empty() => {
+                    return Err(ErrorCode::VmmDeviceUnavailable.status(
+                        Code::FailedPrecondition,
+                        "No GPU was assigned to the orchestrator",
+                    ));
+                }
+                // The GPUs are validated and reset, but attaching them to a guest needs a PCI
+                // transport: the guests only have virtio-mmio devices for now.
+                Device::Gpu => {
+                    return Err(ErrorCode::VmmDeviceUnavailable.status(
+                        Code::Unimplemented,
+                        format!(
+                            "GPU passthrough is not supported by this VMM yet ({} GPU(s) available)",
+                            self.gpus.len()
+                        ),
+                    ));
+                }
+                Device::SriovNic if self.sriov_vfs.is_empty() => {
+                    return Err(ErrorCode::VmmDeviceUnavailable.status(
+                        Code::FailedPrecondition,
+                        "No SR-IOV virtual function was assigned to the orchestrator",
+                    ));
+                }
+                Device::SriovNic if !self.hypervisor.has_pci() => {
+                    return Err(ErrorCode::VmmDeviceUnavailable.status(
+                        Code::Unimplemented,
+                        "Passing an SR-IOV virtual function through needs a PCI bus, start the \
+                         orchestrator with --cloud-hypervisor",
+                    ));
+                }
+                Device::SriovNic => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the node takes new runs, and that the node affinity of the guest lets it run
+    /// on it.
+    fn check_node(&self, request: &RunVmmRequest) -> std::result::Result<(), Status> {
+        self.maintenance.check()?;
+        let affinity = request.node_affinity.clone().unwrap_or_default();
+        let missed = affinity::check_affinity(&affinity, &self.node_labels)?;
+        if !missed.is_empty() {
+            let missed: Vec<String> = missed.iter().map(|s| s.to_string()).collect();
+            info!(
+                workload_name = %request.workload_name,
+                labels = %affinity::describe(&self.node_labels),
+                missed = ?missed,
+                "The node doesn't match the preferred selectors of the run, running it as the only node"
+            );
+        }
+        Ok(())
+    }
+
+    /// Virtual functions for the `sriov-nic` devices requested for the guest, back in the pool
+    /// once the leases are dropped.
+    fn lease_virtual_functions(
+        &self,
+        request: &RunVmmRequest,
+    ) -> std::result::Result<Vec<VfLease>, Status> {
+        let requested = request
+            .devices()
+            .filter(|device| *device == Device::SriovNic)
+            .count();
+        let leases: Vec<VfLease> = std::iter::from_fn(|| self.sriov_vfs.acquire())
+            .take(requested)
+            .collect();
+        if leases.len() < requested {
+            return Err(ErrorCode::VmmDeviceUnavailable.status(
+                Code::ResourceExhausted,
+                format!(
+                    "{} SR-IOV virtual function(s) requested, {} of {} free",
+                    requested,
+                    leases.len(),
+                    self.sriov_vfs.len()
+                ),
+            ));
+        }
+        for lease in &leases {
+            info!(bdf = %lease.device().bdf, "Passing an SR-IOV virtual function through");
+        }
+        Ok(leases)
+    }
+
+    /// Why the host can't provide `capability` to the guests, whatever the policy allows.
+    fn missing_capability(&self, capability: Capability) -> Option<String> {
+        match capability {
+            Capability::NetworkEgress if self.uplink.is_none() && self.default_route.is_none() => {
+                Some("the host has no default route".to_string())
+            }
+            Capability::NetworkEgress | Capability::ArtifactOutput => None,
+            Capability::SharedVolume => self
+                .pmem
+                .is_none()
+                .then(|| "the orchestrator gives the guests no virtio-pmem volume".to_string()),
+            Capability::Gpu if self.gpus.is_empty() => {
+                Some("no GPU was assigned to the orchestrator".to_string())
+            }
+            // See `check_devices`.
+            Capability::Gpu => Some("GPU passthrough is not supported yet".to_string()),
+            Capability::SriovNic if self.sriov_vfs.is_empty() => {
+                Some("no SR-IOV virtual function was assigned to the orchestrator".to_string())
+            }
+            Capability::SriovNic if !self.hypervisor.has_pci() => {
+                Some("SR-IOV passthrough needs cloud-hypervisor".to_string())
+            }
+            Capability::SriovNic => None,
+        }
+    }
+
+    /// Check that the policy allows the capabilities the guest needs, and that the host provides
+    /// them, listing all those missing in a single error.
+    fn check_capabilities(&self, request: &RunVmmRequest) -> std::result::Result<(), Status> {
+        let policy = self.admission.read().unwrap().clone();
+        let mut unsupported = Vec::new();
+        for capability in request.capabilities() {
+            let allowed = policy.as_ref().map_or(true, |policy| {
+                policy.allows_capability(&request.workload_name, capability)
+            });
+            let missing = if allowed {
+                self.missing_capability(capability)
+            } else {
+                Some("not allowed by the admission policy".to_string())
+            };
+            if let Some(reason) = missing {
+                let name = admission::capability_name(capability);
+                if !unsupported.iter().any(|(other, _)| *other == name) {
+                    unsupported.push((name, reason));
+                }
+            }
+        }
+
+        if unsupported.is_empty() {
+            return Ok(());
+        }
+        Err(ErrorCode::VmmCapabilityUnsupported.status(
+            Code::FailedPrecondition,
+            format!(
+                "Unsupported capabilities: {}",
+                unsupported
+                    .iter()
+                    .map(|(name, reason)| format!("{} ({})", name, reason))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ))
+    }
+
+    /// The kernel requested for the guest, checked against the features the guest needs.
+    fn select_kernel(&self, request: &RunVmmRequest) -> std::result::Result<Kernel, Status> {
+        let kernels = self.kernels.read().unwrap();
+        let kernel = kernels.select(&request.kernel).ok_or_else(|| {
+            ErrorCode::VmmUnknownKernel.status(
+                Code::InvalidArgument,
+                format!(
+                    "Unknown kernel {}, expected one of: {}",
+                    request.kernel,
+                    kernels
+                        .kernels()
+                        .iter()
+                        .map(|kernel| kernel.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )
+        })?;
+
+        let mut required = REQUIRED_FEATURES.to_vec();
+        if self.pmem.is_some() {
+            required.push("virtio-pmem");
+        }
+        if self.nested_virtualization {
+            required.push("kvm");
+        }
+        let missing = kernel.missing_features(&required);
+        if !missing.is_empty() {
+            return Err(ErrorCode::VmmIncompatibleKernel.status(
+                Code::FailedPrecondition,
+                format!(
+                    "Kernel {} lacks features needed by the guest: {}",
+                    kernel.name,
+                    missing.join(", ")
+                ),
+            ));
+        }
+
+        Ok(kernel.clone())
+    }
+
+    /// Path of the image of `kernel`, building the built-in kernel first if needed.
+    fn kernel_path(
+        &self,
+        kernel: &Kernel,
+        curr_dir: &OsStr,
+    ) -> std::result::Result<PathBuf, Status> {
+        let Some(path) = &kernel.path else {
+            return Ok(self.get_kernel(curr_dir)?);
+        };
+        if !path.is_file() {
+            return Err(ErrorCode::VmmUnknownKernel.status(
+                Code::FailedPrecondition,
+                format!("Image {:?} of kernel {} doesn't exist", path, kernel.name),
+            ));
+        }
+
+        Ok(path.clone())
+    }
+
+    /// Path of the guest kernel, built first if it doesn't exist.
+    pub fn get_kernel(&self, curr_dir: &OsStr) -> std::result::Result<PathBuf, VmmErrors> {
+        let kernel_path = builtin_kernel_path(curr_dir);
+
+        let mut source = ArtifactSource::Built;
+        let built = artifacts::ensure(&kernel_path, |tmp_path| {
+            source =
+                self.build_shared("kernel/vmlinux.bin", &kernel_path, tmp_path, |tmp_path| {
+                    info!("Building kernel");
+                    self.run_command("sh", vec!["./tools/kernel/mkkernel.sh"])?;
+
+                    let mut build_output = curr_dir.to_os_string();
+                    build_output.push(KERNEL_BUILD_OUTPUT);
+                    std::fs::copy(build_output, tmp_path).map(|_| ())
+                })?;
+            Ok(())
+        })
+        .map_err(VmmErrors::VmmBuildEnvironment)?;
+        if built {
+            record_artifact(curr_dir, &kernel_path, source);
+        }
+
+        Ok(kernel_path)
+    }
+
+    /// Pull the initramfs from the rootfs registry, if any. On failure, the image is built
+    /// locally by [`Self::get_initramfs`] instead.
+    async fn pull_initramfs(&self, language: &str, version: Option<&str>, curr_dir: &OsStr) {
+        let Some(registry) = self.rootfs_registry.read().unwrap().clone() else {
+            return;
+        };
+        let path = initramfs_path(curr_dir, language, version);
+        if self.faults.fail_download() {
+            warn!("Injected fault: failing the rootfs pull, building it locally");
+            return;
+        }
+        let (language, version) = (language.to_string(), version.map(str::to_string));
+
+        let pulled_path = path.clone();
+        match tokio::task::spawn_blocking(move || {
+            registry.pull(&language, version.as_deref(), &pulled_path)
+        })
+        .await
+        {
+            Ok(Ok(Pulled::Cached(digest))) => info!(digest, "Using the cached rootfs image"),
+            Ok(Ok(Pulled::Downloaded(digest))) => {
+                info!(digest, "Pulled the rootfs image");
+                tokio::task::block_in_place(|| {
+                    record_artifact(curr_dir, &path, ArtifactSource::Registry(digest))
+                });
+            }
+            Ok(Err(e)) => warn!(error = %e, "Could not pull the rootfs image, building it locally"),
+            Err(e) => error!(error = %e, "Rootfs pull task failed"),
+        }
+    }
+
+    /// Path of the initramfs of `language` needed by a run of `workload_name`, built if no
+    /// orchestrator did.
     pub fn get_initramfs(
         &self,
+        workload_name: &str,
         language: &str,
+        version: Option<&str>,
         curr_dir: &OsStr,
     ) -> std::result::Result<PathBuf, VmmErrors> {
-        // define initramfs file placement
-        let mut initramfs_entire_file_path = curr_dir.to_os_string();
-        initramfs_entire_file_path.push(&format!("/tools/rootfs/{language}.img"));
+        // define initramfs file placement, each pinned version has its own image
+        let initramfs_entire_file_path = initramfs_path(curr_dir, language, version);
         // set image name
-        let image = format!("{language}:alpine");
+        let image = runtimes::image(language, version);
+        let key = format!(
+            "rootfs/{}",
+            initramfs_entire_file_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+        );
 
-        // check if an initramfs already exists
-        let rootfs_exists = Path::new(&initramfs_entire_file_path)
-            .try_exists()
-            .map_err(VmmErrors::VmmBuildEnvironment)?;
-        if !rootfs_exists {
-            // build the agent
-            let agent_file_name = self.get_path(
+        let mut source = ArtifactSource::Built;
+        let built = artifacts::ensure(&initramfs_entire_file_path, |tmp_path| {
+            source =
+                self.build_shared(&key, &initramfs_entire_file_path, tmp_path, |tmp_path| {
+                    self.rootfs_builds.build(
+                        &self.events,
+                        workload_name,
+                        (language, &image),
+                        tmp_path,
+                        |metrics_path| {
+                            self.build_initramfs(&image, language, curr_dir, tmp_path, metrics_path)
+                        },
+                    )
+                })?;
+            Ok(())
+        })
+        .map_err(VmmErrors::VmmBuildEnvironment)?;
+        // Pulled or found in the shared storage, the image wasn't built here.
+        self.rootfs_builds
+            .requested(language, !built || source != ArtifactSource::Built);
+        if built {
+            record_artifact(curr_dir, &initramfs_entire_file_path, source);
+        }
+
+        Ok(initramfs_entire_file_path)
+    }
+
+    /// Build the initramfs of `image` with the init and the agent in `tmp_path`, its runtime
+    /// of `language` warmed up, fs-gen writing the metrics of the build to `metrics_path`.
+    fn build_initramfs(
+        &self,
+        image: &str,
+        language: &str,
+        curr_dir: &OsStr,
+        tmp_path: &Path,
+        metrics_path: &Path,
+    ) -> std::result::Result<(), std::io::Error> {
+        // build the agent
+        let agent_file_name = self
+            .get_path(
                 curr_dir,
                 "/target/x86_64-unknown-linux-musl/release/agent",
                 "cargo",
@@ -78,22 +1117,268 @@ impl VmmService {
                     "agent",
                     "--target=x86_64-unknown-linux-musl",
                 ],
-            )?;
-            // build initramfs
-            info!("Building initramfs");
-            let _ = self
-                .run_command(
-                    "sh",
-                    vec![
-                        "./tools/rootfs/mkrootfs.sh",
-                        &image,
-                        &agent_file_name.to_str().unwrap(),
-                        &initramfs_entire_file_path.to_str().unwrap(),
-                    ],
-                )
-                .map_err(VmmErrors::VmmBuildEnvironment);
+            )
+            .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+        // build the init, which fs-gen takes next to the agent
+        self.get_path(
+            curr_dir,
+            "/target/x86_64-unknown-linux-musl/release/init",
+            "cargo",
+            vec![
+                "build",
+                "--release",
+                "--bin",
+                "init",
+                "--target=x86_64-unknown-linux-musl",
+            ],
+        )
+        .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+        // build initramfs
+        info!("Building initramfs");
+        self.run_command(
+            "sh",
+            vec![
+                "./tools/rootfs/mkrootfs.sh",
+                image,
+                &agent_file_name.to_string_lossy(),
+                &tmp_path.to_string_lossy(),
+                language,
+                &metrics_path.to_string_lossy(),
+            ],
+        )
+    }
+
+    /// Logs of the VM `vm_id` kept in the shared storage, by this orchestrator once they left
+    /// the [`LogStore`] or by another one.
+    async fn stored_logs(&self, vm_id: &str) -> std::result::Result<Option<Arc<RunLogs>>, Status> {
+        let Some(storage) = self.storage.clone() else {
+            return Ok(None);
+        };
+        if !runs::is_run_id(vm_id) {
+            return Ok(None);
         }
-        Ok(PathBuf::from(&initramfs_entire_file_path))
+
+        let key = logs_key(vm_id);
+        let archive = tokio::task::spawn_blocking(move || storage.read(&key))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| {
+                Status::internal(format!("Could not read the logs of {}: {}", vm_id, e))
+            })?;
+
+        archive
+            .map(|archive| RunLogs::from_archive(vm_id, &archive).map(Arc::new))
+            .transpose()
+            .map_err(|e| Status::internal(format!("Could not decode the logs of {}: {}", vm_id, e)))
+    }
+
+    /// Content of the object `key` of the shared storage, `None` if there is no storage or no
+    /// such object.
+    async fn read_stored(&self, key: String) -> std::result::Result<Option<Vec<u8>>, Status> {
+        let Some(storage) = self.storage.clone() else {
+            return Ok(None);
+        };
+        let read_key = key.clone();
+        tokio::task::spawn_blocking(move || storage.read(&read_key))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::internal(format!("Could not read {}: {}", key, e)))
+    }
+
+    /// Fetch the artifact `key` from the shared storage to `tmp_path`, or build it with
+    /// `build` and store it for the other orchestrators. Its signature, if any, is fetched or
+    /// made for the artifact at `path`. Returns where the artifact comes from.
+    fn build_shared(
+        &self,
+        key: &str,
+        path: &Path,
+        tmp_path: &Path,
+        build: impl FnOnce(&Path) -> std::io::Result<()>,
+    ) -> std::io::Result<ArtifactSource> {
+        let signature_key = format!("{}{}", key, SIGNATURE_SUFFIX);
+        let signature_path = signatures::signature_path(path);
+        let build = |tmp_path: &Path| {
+            build(tmp_path)?;
+            self.sign_built(tmp_path, &signature_path)
+        };
+        let Some(storage) = &self.storage else {
+            build(tmp_path)?;
+            return Ok(ArtifactSource::Built);
+        };
+
+        if self.faults.fail_download() {
+            warn!(key, "Injected fault: failing the fetch from the storage");
+            build(tmp_path)?;
+            return Ok(ArtifactSource::Built);
+        }
+        match tokio::task::block_in_place(|| storage.fetch(key, tmp_path)) {
+            Ok(true) => {
+                info!(key, "Fetched the artifact from the storage");
+                let _ = std::fs::remove_file(&signature_path);
+                if let Err(e) =
+                    tokio::task::block_in_place(|| storage.fetch(&signature_key, &signature_path))
+                {
+                    warn!(key, error = %e, "Could not fetch the signature of the artifact");
+                }
+                return Ok(ArtifactSource::Storage);
+            }
+            Ok(false) => {}
+            Err(e) => warn!(key, error = %e, "Could not fetch the artifact from the storage"),
+        }
+
+        build(tmp_path)?;
+        if let Err(e) = tokio::task::block_in_place(|| storage.store(key, tmp_path)) {
+            warn!(key, error = %e, "Could not store the artifact");
+        }
+        if signature_path.is_file() {
+            if let Err(e) =
+                tokio::task::block_in_place(|| storage.store(&signature_key, &signature_path))
+            {
+                warn!(key, error = %e, "Could not store the signature of the artifact");
+            }
+        }
+
+        Ok(ArtifactSource::Built)
+    }
+
+    /// Sign the artifact just built at `tmp_path` to `signature_path`, if the orchestrator has
+    /// a signing key. The signature of the artifact it replaces is removed otherwise.
+    fn sign_built(&self, tmp_path: &Path, signature_path: &Path) -> std::io::Result<()> {
+        let signatures = self.signatures.read().unwrap().clone();
+        let signed = match signatures {
+            Some(signatures) => signatures
+                .sign(tmp_path, signature_path)
+                .map_err(|e| std::io::Error::other(e.to_string()))?,
+            None => false,
+        };
+        if signed {
+            info!(signature = ?signature_path, "Signed the built artifact");
+        } else {
+            let _ = std::fs::remove_file(signature_path);
+        }
+        Ok(())
+    }
+
+    /// Check the image at `path` of the configured `kernel` against the manifest before a guest
+    /// of `workload_name` boots it, its checksum being recorded on its first boot. Unlike the
+    /// built-in kernel, it can't be fetched or built again: a corrupted image is refused rather
+    /// than discarded.
+    fn check_kernel_integrity(
+        &self,
+        curr_dir: &OsStr,
+        kernel: &Kernel,
+        path: &Path,
+        workload_name: &str,
+    ) -> std::result::Result<(), Status> {
+        let manifest = ArtifactManifest::new(curr_dir);
+        match tokio::task::block_in_place(|| manifest.check(path)) {
+            Ok(Checked::Missing | Checked::Intact) => Ok(()),
+            Ok(Checked::Adopted) => {
+                info!(kernel = %kernel.name, artifact = ?path, "Recorded the checksum of the kernel");
+                Ok(())
+            }
+            Ok(Checked::Corrupted {
+                expected,
+                sha256,
+                size,
+            }) => {
+                error!(
+                    kernel = %kernel.name,
+                    artifact = ?path,
+                    expected_sha256 = %expected.sha256,
+                    sha256 = %sha256,
+                    "The kernel doesn't match its checksum, refusing to boot it"
+                );
+                let message = format!(
+                    "{}: expected sha256 {} ({} bytes), got {} ({} bytes)",
+                    manifest.name(path),
+                    expected.sha256,
+                    expected.size,
+                    sha256,
+                    size
+                );
+                self.events
+                    .publish("", workload_name, VmEventKind::ArtifactCorrupted, &message);
+                Err(ErrorCode::VmmKernelCorrupted.status(
+                    Code::FailedPrecondition,
+                    format!(
+                        "The image of the kernel {} is corrupted, {}",
+                        kernel.name, message
+                    ),
+                ))
+            }
+            // Like for the other artifacts, an unreadable manifest doesn't stop the runs.
+            Err(e) => {
+                warn!(artifact = ?path, error = %e, "Could not check the integrity of the kernel");
+                Ok(())
+            }
+        }
+    }
+
+    /// Check the artifact at `path` against the manifest before a guest of `workload_name`
+    /// boots it. A corrupted artifact is discarded, along with its signature and the record of
+    /// its pull, so that it's fetched or built again.
+    fn check_integrity(&self, curr_dir: &OsStr, path: &Path, workload_name: &str) {
+        let manifest = ArtifactManifest::new(curr_dir);
+        match tokio::task::block_in_place(|| manifest.check(path)) {
+            Ok(Checked::Missing | Checked::Intact) => {}
+            Ok(Checked::Adopted) => {
+                info!(artifact = ?path, "Recorded the checksum of the artifact")
+            }
+            Ok(Checked::Corrupted {
+                expected,
+                sha256,
+                size,
+            }) => {
+                error!(
+                    artifact = ?path,
+                    expected_sha256 = %expected.sha256,
+                    expected_size = expected.size,
+                    sha256 = %sha256,
+                    size,
+                    source = ?expected.source,
+                    "The artifact doesn't match its checksum, discarding it"
+                );
+                self.events.publish(
+                    "",
+                    workload_name,
+                    VmEventKind::ArtifactCorrupted,
+                    format!(
+                        "{}: expected sha256 {} ({} bytes), got {} ({} bytes), fetching or building it again",
+                        manifest.name(path),
+                        expected.sha256,
+                        expected.size,
+                        sha256,
+                        size
+                    ),
+                );
+                let _ = std::fs::remove_file(path);
+                let _ = std::fs::remove_file(registry::source_path(path));
+                let _ = std::fs::remove_file(signatures::signature_path(path));
+                if let Err(e) = manifest.forget(path) {
+                    warn!(artifact = ?path, error = %e, "Could not update the artifact manifest");
+                }
+            }
+            Err(e) => {
+                warn!(artifact = ?path, error = %e, "Could not check the integrity of the artifact")
+            }
+        }
+    }
+
+    /// Check that the artifacts a guest boots are signed, when the orchestrator checks them.
+    fn verify_signatures(&self, artifacts: &[&Path]) -> std::result::Result<(), Status> {
+        let Some(signatures) = self.signatures.read().unwrap().clone() else {
+            return Ok(());
+        };
+        for artifact in artifacts {
+            if let Err(e) = signatures.verify(artifact) {
+                error!(artifact = ?artifact, error = %e, "Refusing to boot an artifact");
+                return Err(
+                    ErrorCode::VmmArtifactSignature.status(Code::FailedPrecondition, e.to_string())
+                );
+            }
+        }
+        Ok(())
     }
 
     pub fn run_command(
@@ -102,12 +1387,20 @@ impl VmmService {
         args: Vec<&str>,
     ) -> std::result::Result<(), std::io::Error> {
         // Execute the script using sh and capture output and error streams
-        Command::new(command_type)
-            .args(args)
+        let output = Command::new(command_type)
+            .args(&args)
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
-            .output()
-            .expect("Failed to execute the script");
+            .output()?;
+
+        if !output.status.success() {
+            return Err(std::io::Error::other(format!(
+                "`{} {}` failed: {}",
+                command_type,
+                args.join(" "),
+                output.status
+            )));
+        }
         Ok(())
     }
 
@@ -129,142 +1422,1787 @@ impl VmmService {
 
         if !exists {
             info!("File {:?} not found, building it", &entire_path);
-            let _ = self
-                .run_command(command_type, args)
-                .map_err(VmmErrors::VmmBuildEnvironment);
+            self.run_command(command_type, args)
+                .map_err(VmmErrors::VmmBuildEnvironment)?;
             info!("File {:?} successfully build", &entire_path);
         };
         Ok(PathBuf::from(&entire_path))
     }
 
-    pub fn get_agent_request(
+    /// Stages the agent goes through for `request`, and the workload it runs if built by an
+    /// earlier run of the same inputs: its fetch and build stages are then skipped.
+    fn pipeline(
         &self,
-        vmm_request: RunVmmRequest,
-        language: String,
-    ) -> ExecuteRequest {
-        // Send the grpc request to start the agent
-        ExecuteRequest {
-            workload_name: vmm_request.workload_name,
-            language,
-            action: 2, // Prepare and run
-            code: vmm_request.code,
-            config_str: "[build]\nrelease = true".to_string(),
+        request: &RunVmmRequest,
+    ) -> std::result::Result<(Vec<PipelineStage>, Option<Vec<u8>>), Status> {
+        let mut stages: Vec<PipelineStage> = request.stages().collect();
+        if stages.is_empty() {
+            stages = vec![
+                PipelineStage::FetchDeps,
+                PipelineStage::Build,
+                PipelineStage::Run,
+            ];
         }
-    }
-}
+        let Some(run) = stages.iter().position(|&stage| stage == PipelineStage::Run) else {
+            return Ok((stages, None));
+        };
 
-#[tonic::async_trait]
-impl VmmServiceTrait for VmmService {
-    type RunStream =
-        ReceiverStream<std::result::Result<vmmorchestrator::ExecuteResponse, tonic::Status>>;
+        let build_id = builds::build_id(request, &self.cpu_template);
+        let cached = self.builds.as_ref().and_then(|builds| {
+            tokio::task::block_in_place(|| builds.get(&build_id)).unwrap_or_else(|e| {
+                warn!(build_id, error = %e, "Could not read the build cache");
+                None
+            })
+        });
+        match cached {
+            Some(artifact) => {
+                info!(build_id, "Running the cached build of the workload");
+                stages.retain(|&stage| stage == PipelineStage::Run);
+                Ok((stages, Some(artifact)))
+            }
+            None if !stages[..run].contains(&PipelineStage::Build) => {
+                Err(ErrorCode::VmmBuildNotCached.status(
+                    Code::FailedPrecondition,
+                    format!(
+                        "No build of {} is cached for its code and build options",
+                        request.workload_name
+                    ),
+                ))
+            }
+            None => Ok((stages, None)),
+        }
+    }
 
-    async fn shutdown(&self, request: Request<ShutdownVmRequest>) -> Result<ShutdownVmResponse> {
-        const GUEST_IP: Ipv4Addr = Ipv4Addr::new(172, 29, 0, 2);
+    /// Publish the health of the orchestrator to `reporter`, forever.
+    pub async fn report_health(self: Arc<Self>, reporter: HealthReporter) {
+        health::report_health(
+            reporter,
+            self.hypervisor.emulated(),
+            self.tenant_networks.as_ref(),
+        )
+        .await
+    }
 
-        let grpc_client = tokio::spawn(async move {
-            // Wait 2 seconds
-            tokio::time::sleep(Duration::from_secs(2)).await;
-            println!("Connecting to Agent service");
+    /// End the runs of the history on the events of their VMs, forever.
+    pub async fn track_runs(self: Arc<Self>) {
+        self.history.track(&self.events).await
+    }
+
+    /// Scale the pools of VMs of the registered workloads to their targets, forever.
+    pub async fn autoscale(self: Arc<Self>) {
+        loop {
+            // Right away once cordoned, not to keep the idle VMs warm any longer.
+            tokio::select! {
+                _ = tokio::time::sleep(self.pool.check_interval()) => {}
+                _ = self.maintenance.wait_cordoned() => {}
+            }
+            if let Some(cordon) = self.maintenance.cordoned() {
+                let reason = format!("the orchestrator is cordoned for {}", cordon.reason);
+                for vm_id in self.pool.drain_idle(&self.vms, &reason) {
+                    stop_vm(&self.vms, &vm_id, "the orchestrator is cordoned").await;
+                }
+                continue;
+            }
+            let scaling = self.pool.scale(&self.vms);
+            for vm_id in scaling.stop {
+                stop_vm(&self.vms, &vm_id, "the pool scaled down").await;
+            }
+            for vm_id in scaling.retire {
+                stop_vm(&self.vms, &vm_id, "replaced by an upgrade of its pool").await;
+            }
+            for lease in scaling.start {
+                self.prewarm(lease).await;
+            }
+        }
+    }
+
+    /// Start the VM of the instance of `lease` ahead of the invocations, only building the
+    /// workload in it.
+    async fn prewarm(&self, lease: Lease) {
+        let workload = self.workload_store().ok().and_then(|store| {
+            tokio::task::block_in_place(|| store.get(lease.workload(), lease.version()))
+                .ok()
+                .flatten()
+        });
+        let Some(mut run) = workload.and_then(|workload| workload.request) else {
+            self.pool.release(lease, false);
+            return;
+        };
+        run.stages = vec![PipelineStage::FetchDeps as i32, PipelineStage::Build as i32];
+        run.stdin.clear();
+
+        let instance = lease.instance().to_string();
+        match self.run_instance(lease, run).await {
+            // The instance is released at the end of the build.
+            Ok(response) => {
+                tokio::spawn(async move {
+                    let mut stream = response.into_inner().into_inner();
+                    while stream.recv().await.is_some() {}
+                });
+            }
+            Err(status) => {
+                warn!(instance = %instance, error = %status.message(), "Could not start a warm VM")
+            }
+        }
+    }
+
+    /// Run the workload built in the running VM `vm_id` again, with the inputs of `request`,
+    /// once what the earlier runs left in the guest is removed if `scrub`.
+    async fn run_warm(
+        &self,
+        vm_id: &str,
+        request: RunVmmRequest,
+        scrub: bool,
+    ) -> Result<ReceiverStream<RunMessage>> {
+        let unreachable = || {
+            ErrorCode::VmmAgentUnreachable.status(
+                Code::Unavailable,
+                format!("Could not connect to the agent of the VM {}", vm_id),
+            )
+        };
+        let (guest_ip, port, host_ip) = self
+            .vms
+            .resolve(vm_id)
+            .and_then(|vm| {
+                let guest_ip = vm.guest_ip.parse::<Ipv4Addr>().ok()?;
+                Some((guest_ip, agent_port(&vm), vm.host_ip))
+            })
+            .ok_or_else(unreachable)?;
+        let mut client =
+            tokio::time::timeout(AGENT_CONNECT_TIMEOUT, WorkloadClient::new(guest_ip, port))
+                .await
+                .ok()
+                .and_then(|client| client.ok())
+                .ok_or_else(unreachable)?;
+
+        let language = Language::try_from(request.language)
+            .map_err(|e| ErrorCode::VmmInvalidRequest.status(Code::InvalidArgument, e.to_string()))?
+            .as_str()
+            .to_string();
+        let workload_name = request.workload_name.clone();
+        let (tenant, run_labels) = (request.tenant.clone(), request.labels.clone());
+        let redactor = Redactor::new(&request.env, &request.secret_env);
+        let run_webhooks = request.webhooks.clone();
+        let deadline = Deadline::new(self.run_limit(&request), self.clock.as_ref());
+        let agent_request = ExecuteRequest {
+            scrub,
+            ..self.get_agent_request(
+                request,
+                &host_ip,
+                language,
+                vec![PipelineStage::Run],
+                None,
+                false,
+            )
+        };
+        let mut expired = Box::pin(deadline::expired(deadline));
+        let mut response_stream = tokio::select! {
+            execution = self.execute(&mut client, vm_id, agent_request) => execution?,
+            () = &mut expired => {
+                return Err(self.past_deadline(deadline, vm_id, &workload_name, &run_webhooks));
+            }
+        };
+        self.history.start(
+            vm_id,
+            &workload_name,
+            metering::tenant(&tenant),
+            &run_labels,
+        );
+        self.events
+            .publish(vm_id, &workload_name, VmEventKind::RunStarted, "warm VM");
 
-            WorkloadClient::new(GUEST_IP, 50051).await
+        let (tx, rx) = stream::channel();
+        let (events, webhooks) = (self.events.clone(), self.webhooks.clone());
+        let (artifacts, storage) = (self.artifacts.clone(), self.storage.clone());
+        let vms = self.vms.clone();
+        let run_vm_id = vm_id.to_string();
+        let tasks = self.vms.tasks(vm_id).unwrap_or_else(|| VmTasks::new(vm_id));
+        tasks.spawn_relay("relay", tx.clone(), async move {
+            let mut outcome = None;
+            let interrupted = loop {
+                tokio::select! {
+                    message = response_stream.message() => {
+                        let Ok(Some(mut response)) = message else {
+                            break None;
+                        };
+                        if !redactor.is_empty() {
+                            response.stdout =
+                                response.stdout.map(|stdout| redactor.redact_bytes(&stdout));
+                            response.stderr =
+                                response.stderr.map(|stderr| redactor.redact_bytes(&stderr));
+                        }
+                        crashes::keep(&artifacts, storage.clone(), &run_vm_id, &mut response)
+                            .await;
+                        if matches!(response.stage(), Stage::Done | Stage::Failed) {
+                            outcome =
+                                Some((response.stage(), response.exit_code, run_summary(&response)));
+                        }
+                        tx.send(Ok(response));
+                    }
+                    () = &mut expired => break deadline.map(Interruption::DeadlineExceeded),
+                }
+            };
+            if let Some(Interruption::DeadlineExceeded(deadline)) = &interrupted {
+                tx.send(Err(deadline.enforce(&vms, &events, &run_vm_id, &workload_name)));
+            }
+
+            let exit_code = outcome.as_ref().and_then(|(_, exit_code, _)| *exit_code);
+            let (kind, message) = match (outcome, interrupted) {
+                (_, Some(interruption)) => (VmEventKind::RunFailed, interruption.to_string()),
+                (Some((Stage::Done, _, summary)), None) => (VmEventKind::RunFinished, summary),
+                (Some((_, _, summary)), None) => (VmEventKind::RunFailed, summary),
+                (None, None) => (
+                    VmEventKind::RunFailed,
+                    "the agent stopped before the end of the run".to_string(),
+                ),
+            };
+            events.publish(&run_vm_id, &workload_name, kind, &message);
+            webhooks.notify(
+                Payload::new(&run_vm_id, &workload_name, kind, exit_code, &message),
+                &run_webhooks,
+            );
+        });
+
+        let mut response = Response::new(rx);
+        response
+            .metadata_mut()
+            .insert(VM_ID_METADATA, vm_id.parse().unwrap());
+        Ok(response)
+    }
+
+    /// Run `request` in the instance of `lease`, and give it back to the pool at the end of
+    /// the run.
+    async fn run_instance(
+        &self,
+        mut lease: Lease,
+        mut request: RunVmmRequest,
+    ) -> Result<ReceiverStream<RunMessage>> {
+        request.workload_name = lease.instance().to_string();
+        if let Some(vm_id) = lease.take_retired() {
+            stop_vm(&self.vms, &vm_id, "retired from its pool").await;
+        }
+        let warm = lease.warm_vm().map(str::to_string);
+        let started = match &warm {
+            // Without what the earlier invocations left in the guest.
+            Some(vm_id) => self.run_warm(vm_id, request, true).await,
+            None => {
+                let mut request = Request::new(request);
+                request.extensions_mut().insert(KeepVm);
+                self.run(request).await
+            }
+        };
+        let response = match started {
+            Ok(response) => response,
+            Err(status) => {
+                if let Some(vm_id) = self.pool.release(lease, false) {
+                    let vms = self.vms.clone();
+                    tokio::spawn(async move {
+                        stop_vm(&vms, &vm_id, "the run could not start in it").await
+                    });
+                }
+                return Err(status);
+            }
+        };
+
+        let (mut metadata, stream, extensions) = response.into_parts();
+        if let Some(vm_id) = metadata
+            .get(VM_ID_METADATA)
+            .and_then(|vm_id| vm_id.to_str().ok())
+        {
+            self.pool.started(&mut lease, vm_id);
+        }
+        let start = if warm.is_some() { "warm" } else { "cold" };
+        metadata.insert(START_METADATA, start.parse().unwrap());
+
+        let (tx, rx) = stream::channel();
+        let (pool, vms) = (self.pool.clone(), self.vms.clone());
+        let maintenance = self.maintenance.clone();
+        tokio::spawn(async move {
+            let mut stream = stream.into_inner();
+            // The VM is reused if the workload ran to its end, even if it failed, or if it was
+            // only built.
+            let mut reusable = false;
+            while let Some(message) = stream.recv().await {
+                reusable = match &message {
+                    Ok(response) => {
+                        reusable || matches!(response.stage(), Stage::Running | Stage::Done)
+                    }
+                    Err(_) => false,
+                };
+                tx.send(message);
+            }
+            // Not kept warm once the orchestrator is cordoned, for it to drain.
+            let reusable = reusable && maintenance.cordoned().is_none();
+            if let Some(vm_id) = pool.release(lease, reusable) {
+                stop_vm(&vms, &vm_id, "not reusable after its run").await;
+            }
+        });
+
+        Ok(Response::from_parts(metadata, rx, extensions))
+    }
+
+    /// Network of the guests of `tenant`: its own when the tenants are isolated.
+    fn network(&self, tenant: &str) -> std::result::Result<GuestNetwork, Status> {
+        let Some(networks) = &self.tenant_networks else {
+            return Ok(GuestNetwork::shared());
+        };
+        let tenant = metering::tenant(tenant);
+        networks.network(tenant).ok_or_else(|| {
+            ErrorCode::VmmTenantNetworksExhausted.status(
+                Code::ResourceExhausted,
+                format!("No isolated network is left for the tenant {}", tenant),
+            )
         })
-        .await
-        .unwrap();
+    }
 
-        if let Ok(mut client) = grpc_client {
-            info!("Attempting to shutdown the VM...");
+    /// An address of its own on the network of a guest of `tenant`, until the lease is dropped.
+    fn lease_address(&self, tenant: &str) -> std::result::Result<AddressLease, Status> {
+        let network = self.network(tenant)?;
+        self.addresses.lease(&network).ok_or_else(|| {
+            ErrorCode::VmmGuestAddressesExhausted.status(
+                Code::ResourceExhausted,
+                format!("No address is left for a guest on {}", network.bridge),
+            )
+        })
+    }
 
-            let response = client.shutdown(request.into_inner()).await.unwrap();
+    /// Send `request` to the agent of `vm_id`, recording the session if they are.
+    /// End the run of `vm_id` which went past `deadline` before its relay started, destroying
+    /// its VM.
+    fn past_deadline(
+        &self,
+        deadline: Option<Deadline>,
+        vm_id: &str,
+        workload_name: &str,
+        webhooks: &[Webhook],
+    ) -> Status {
+        let deadline = deadline.expect("only a deadline expires");
+        let status = deadline.enforce(&self.vms, &self.events, vm_id, workload_name);
+        let message = Interruption::DeadlineExceeded(deadline).to_string();
+        self.events
+            .publish(vm_id, workload_name, VmEventKind::RunFailed, &message);
+        self.webhooks.notify(
+            Payload::new(vm_id, workload_name, VmEventKind::RunFailed, None, &message),
+            webhooks,
+        );
+        status
+    }
 
-            return Ok(Response::new(response));
-        } else if let Err(e) = grpc_client {
-            error!("ERROR {:?}", e);
+    async fn execute(
+        &self,
+        client: &mut WorkloadClient,
+        vm_id: &str,
+        request: ExecuteRequest,
+    ) -> std::result::Result<AgentStream, Status> {
+        let mut recorder = self.sessions.as_ref().and_then(|dir| {
+            Recorder::create(dir, vm_id, &request, self.clock.clone())
+                .map_err(|e| warn!(vm_id, error = %e, "Could not record the session"))
+                .ok()
+        });
+        match client.execute(request).await {
+            Ok(stream) => Ok(AgentStream::new(stream, recorder)),
+            Err(status) => {
+                if let Some(recorder) = &mut recorder {
+                    let _ = recorder.error(&status);
+                }
+                Err(status)
+            }
         }
-        return Err(Status::internal("Failed to shutdown the VM"));
     }
 
-    async fn run(&self, request: Request<RunVmmRequest>) -> Result<Self::RunStream> {
-        let (tx, rx) = tokio::sync::mpsc::channel(4);
+    /// Run the session recorded at `path` through the orchestrator, the run of a VM whose agent
+    /// replays it, `realtime` keeping the delays between its responses.
+    pub async fn replay(
+        &self,
+        path: &Path,
+        realtime: bool,
+    ) -> std::result::Result<ReceiverStream<RunMessage>, Status> {
+        let session = Session::load(path).map_err(|e| {
+            ErrorCode::VmmInvalidRequest.status(Code::InvalidArgument, e.to_string())
+        })?;
+        let request = session.run_request();
+        let (addr, agent) = ReplayAgent::new(session, realtime, self.clock.clone())
+            .serve()
+            .await
+            .map_err(|e| Status::internal(format!("Could not start the replay agent: {}", e)))?;
+        let workload_name = format!("replay-{}", request.workload_name);
+        let vm = VmInfo {
+            workload_name: workload_name.clone(),
+            guest_ip: Ipv4Addr::LOCALHOST.to_string(),
+            agent_port: addr.port().into(),
+            ..Default::default()
+        };
+        let vm_id = self.vms.insert(vm, Arc::default()).ok_or_else(|| {
+            agent.abort();
+            duplicate_workload(&workload_name)
+        })?;
+
+        let started = self.run_warm(&vm_id, request, false).await;
+        let stream = match started {
+            Ok(response) => response.into_inner().into_inner(),
+            Err(status) => {
+                agent.abort();
+                self.vms.remove(&vm_id);
+                return Err(status);
+            }
+        };
+        let (tx, rx) = stream::channel();
+        let vms = self.vms.clone();
+        tokio::spawn(async move {
+            let mut stream = stream;
+            while let Some(message) = stream.recv().await {
+                tx.send(message);
+            }
+            // The replayed VM is gone with its agent.
+            agent.abort();
+            vms.remove(&vm_id);
+        });
+        Ok(rx)
+    }
+
+    /// Client of the agent of the running VM `vm`, an id or a workload name.
+    async fn guest_agent(&self, vm: &str) -> std::result::Result<WorkloadClient, Status> {
+        let vm = self.vms.resolve(vm).ok_or_else(|| {
+            ErrorCode::VmmUnknownVm.status(
+                Code::NotFound,
+                match vm {
+                    "" => {
+                        "No single running VM to inspect, give its id or workload name".to_string()
+                    }
+                    vm => format!("No VM is running with the id or workload name {}", vm),
+                },
+            )
+        })?;
+        let guest_ip: Ipv4Addr = vm
+            .guest_ip
+            .parse()
+            .map_err(|_| Status::internal(format!("Invalid guest address {}", vm.guest_ip)))?;
+        WorkloadClient::connect(guest_ip, agent_port(&vm))
+            .await
+            .map_err(|e| {
+                ErrorCode::VmmAgentUnreachable.status(
+                    Code::Unavailable,
+                    format!("Could not connect to the agent of {}: {}", vm.id, e),
+                )
+            })
+    }
+
+    pub fn get_agent_request(
+        &self,
+        vmm_request: RunVmmRequest,
+        host_ip: &str,
+        language: String,
+        stages: Vec<PipelineStage>,
+        artifact: Option<Vec<u8>>,
+        power_off: bool,
+    ) -> ExecuteRequest {
+        let return_artifact = self.builds.is_some() && stages.contains(&PipelineStage::Build);
+        let keep_on_failure = vmm_request.keep_on_failure;
+        // Send the grpc request to start the agent
+        ExecuteRequest {
+            workload_name: vmm_request.workload_name,
+            language,
+            action: 2, // Prepare and run, for the agents which predate `stages`
+            code: vmm_request.code,
+            config_str: String::new(),
+            build: vmm_request.build,
+            env: vmm_request.env,
+            secret_env: vmm_request.secret_env,
+            output_limits: Some(self.output_limits.read().unwrap().clone()),
+            stages: stages.into_iter().map(|stage| stage as i32).collect(),
+            artifact: artifact.unwrap_or_default(),
+            return_artifact,
+            stdin: vmm_request.stdin,
+            power_off,
+            keep_on_failure,
+            inputs: vmm_request.inputs,
+            outputs: vmm_request.outputs,
+            tmp_quota_mb: vmm_request.tmp_quota_mb,
+            package_cache_url: self
+                .package_cache_port
+                .map(|port| format!("http://{}:{}", host_ip, port))
+                .unwrap_or_default(),
+            scrub: false,
+        }
+    }
 
-        const HOST_IP: Ipv4Addr = Ipv4Addr::new(172, 29, 0, 1);
-        const HOST_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 0, 0);
-        const GUEST_IP: Ipv4Addr = Ipv4Addr::new(172, 29, 0, 2);
+    /// Run the attempt `attempt` of `request` on a new VM.
+    async fn run_attempt(
+        &self,
+        request: Request<RunVmmRequest>,
+        attempt: &Attempt,
+    ) -> Result<ReceiverStream<RunMessage>> {
+        let (tx, rx) = stream::channel();
 
         // get current directory
         let curr_dir = current_dir()
             .map_err(VmmErrors::VmmBuildEnvironment)?
             .into_os_string();
 
+        let power_off = request.extensions().get::<KeepVm>().is_none();
+        // get request with the language
+        let mut vmm_request = request.into_inner();
+        let language = Language::try_from(vmm_request.language).map_err(|e| {
+            ErrorCode::VmmInvalidRequest.status(Code::InvalidArgument, e.to_string())
+        })?;
+        let runtime_version = requested_runtime_version(&vmm_request, &language)?;
+        let language = language.as_str().to_string();
+        if !vmm_request.tenant.is_empty() && !workloads::is_name(&vmm_request.tenant) {
+            return Err(ErrorCode::VmmInvalidRequest.status(
+                Code::InvalidArgument,
+                "The tenant can only contain letters, digits, '-' and '_'",
+            ));
+        }
+        for (key, value) in &vmm_request.labels {
+            labels::validate_label(key, value)
+                .map_err(|e| ErrorCode::VmmInvalidLabels.status(Code::InvalidArgument, e))?;
+        }
+        self.admit(&mut vmm_request).await?;
+        self.check_capabilities(&vmm_request)?;
+        self.check_node(&vmm_request)?;
+        self.check_devices(&vmm_request)?;
+        if self.vms.is_running(&vmm_request.workload_name) {
+            return Err(duplicate_workload(&vmm_request.workload_name));
+        }
+        let virtual_functions = self.lease_virtual_functions(&vmm_request)?;
+        let (stages, artifact) = self.pipeline(&vmm_request)?;
+        let build_id = builds::build_id(&vmm_request, &self.cpu_template);
+        let build_cached = artifact.is_some();
+        let address = self.lease_address(&vmm_request.tenant)?;
+        let network = address.network().clone();
+        let guest_ip = network.guest_ip;
+
         // build kernel if necessary
-        let kernel_path: PathBuf = self.get_path(
+        let kernel = self.select_kernel(&vmm_request)?;
+        if kernel.path.is_none() {
+            self.check_integrity(
+                &curr_dir,
+                &builtin_kernel_path(&curr_dir),
+                &vmm_request.workload_name,
+            );
+        }
+        let kernel_path = self.kernel_path(&kernel, &curr_dir)?;
+        if kernel.path.is_some() {
+            self.check_kernel_integrity(
+                &curr_dir,
+                &kernel,
+                &kernel_path,
+                &vmm_request.workload_name,
+            )?;
+        }
+        info!(kernel = %kernel.name, path = ?kernel_path, "Selected kernel");
+
+        self.check_integrity(
             &curr_dir,
-            "/tools/kernel/linux-cloud-hypervisor/arch/x86/boot/compressed/vmlinux.bin",
-            "sh",
-            vec!["./tools/kernel/mkkernel.sh"],
+            &initramfs_path(&curr_dir, &language, runtime_version.as_deref()),
+            &vmm_request.workload_name,
+        );
+        self.pull_initramfs(&language, runtime_version.as_deref(), &curr_dir)
+            .await;
+        let initramfs_path = self.get_initramfs(
+            &vmm_request.workload_name,
+            &language,
+            runtime_version.as_deref(),
+            curr_dir.as_os_str(),
         )?;
+        if kernel.path.is_none() {
+            janitor::mark_used(&kernel_path);
+        }
+        janitor::mark_used(&initramfs_path);
+        self.verify_signatures(&[&kernel_path, &initramfs_path])?;
+        // The pool of an instance compares the image with the one of its other VMs.
+        if let Some(digest) = recorded_digest(&curr_dir, &initramfs_path) {
+            self.pool.booted(&vmm_request.workload_name, &digest);
+        }
 
-        // get request with the language
-        let vmm_request = request.into_inner();
-        let language: String = Language::from_i32(vmm_request.language)
-            .expect("Unknown language")
-            .as_str_name()
-            .to_lowercase();
+        let image_digest = if self.runs.is_some() || !vmm_request.image_digest.is_empty() {
+            runs::file_digest(&initramfs_path).map_err(VmmErrors::VmmBuildEnvironment)?
+        } else {
+            String::new()
+        };
+        if !vmm_request.image_digest.is_empty() && vmm_request.image_digest != image_digest {
+            return Err(ErrorCode::VmmImageMismatch.status(
+                Code::FailedPrecondition,
+                format!(
+                    "The rootfs image is {}, the run expects {}",
+                    image_digest, vmm_request.image_digest
+                ),
+            ));
+        }
 
-        let initramfs_path = self.get_initramfs(&language, curr_dir.as_os_str())?;
+        let (cpus, memory_mb) = requested_resources(&vmm_request);
+        let priority_class = self.scheduler.class(&vmm_request.priority_class)?;
+        let (reservation, preemption) = self
+            .scheduler
+            .reserve(
+                &priority_class,
+                &vmm_request.workload_name,
+                metering::tenant(&vmm_request.tenant),
+                (cpus, memory_mb),
+                &self.events,
+            )
+            .await?;
 
-        let mut vmm = VMM::new(HOST_IP, HOST_NETMASK, GUEST_IP).map_err(VmmErrors::VmmNew)?;
+        let resources = GuestResources {
+            cpus: self.cpus.clone(),
+            vcpus: cpus.into(),
+            placement: self.cpus.allocate(cpus.into()),
+            pmem_file: self.pmem.as_ref().map(|pmem| {
+                let path = pmem.backing_file(&vmm_request.workload_name);
+                info!(path = ?path, size_mb = pmem.size_mb, "Attaching virtio-pmem device");
+                (path, pmem.keep)
+            }),
+        };
+        if let Some(placement) = &resources.placement {
+            info!(host_cpus = ?placement.cpus, numa_node = ?placement.numa_node, "Pinning vCPUs");
+        }
 
-        // Configure the VMM parameters might need to be calculated rather than hardcoded
-        vmm.configure(1, 4000, kernel_path, &Some(initramfs_path))
-            .await
-            .map_err(VmmErrors::VmmConfigure)?;
+        let boot_delay = self.faults.boot_delay();
+        if !boot_delay.is_zero() {
+            warn!(delay = ?boot_delay, "Injected fault: delaying the boot");
+            tokio::time::sleep(boot_delay).await;
+        }
 
-        // Run the VMM in a separate task
-        tokio::spawn(async move {
-            info!("Running VMM");
-            if let Err(err) = vmm.run().map_err(VmmErrors::VmmRun) {
-                error!("Error running VMM: {:?}", err);
+        let console = self.console_logs.clone().map(ConsoleLog::new);
+        let mut vmm = Guest::create(
+            &self.hypervisor,
+            GuestConfig {
+                cpus,
+                memory_mb,
+                kernel: kernel_path,
+                initramfs: initramfs_path,
+                kernel_cmdline: self.kernel_cmdline(&kernel.cmdline),
+                network: network.clone(),
+                uplink: self.uplink.clone(),
+                vfio_devices: virtual_functions
+                    .iter()
+                    .map(|lease| lease.device().sysfs_path())
+                    .collect(),
+                placement: resources.placement.clone(),
+                memory: self.memory,
+                nested_virtualization: self.nested_virtualization,
+                cpu_template: self.cpu_template.clone(),
+                pmem: self
+                    .pmem
+                    .as_ref()
+                    .zip(resources.pmem_file.as_ref())
+                    .map(|(pmem, (path, _))| (path.clone(), pmem.size_mb)),
+                io_limits: self.io_limits(&vmm_request),
+                console: console.clone(),
+            },
+        )
+        .await?;
+
+        let vm_id = match self.vms.insert(
+            VmInfo {
+                workload_name: vmm_request.workload_name.clone(),
+                language: language.clone(),
+                runtime_version: runtime_version.clone().unwrap_or_default(),
+                cpus: cpus.into(),
+                memory_mb,
+                host_ip: network.host_ip.to_string(),
+                guest_ip: guest_ip.to_string(),
+                kernel: kernel.name.clone(),
+                priority_class: priority_class.name.clone(),
+                agent_port: self.agent_port.into(),
+                labels: vmm_request.labels.clone(),
+                emulated: self.hypervisor.emulated(),
+                ..Default::default()
+            },
+            vmm.stats(),
+        ) {
+            Some(vm_id) => vm_id,
+            // Another run of the workload started in the meantime.
+            None => return Err(duplicate_workload(&vmm_request.workload_name)),
+        };
+        info!(vm_id = %vm_id, "VM started");
+        self.vms.set_stopper(&vm_id, vmm.stopper());
+        let deadline = Deadline::new(self.run_limit(&vmm_request), self.clock.as_ref());
+        if let Some(console) = &console {
+            match tokio::task::block_in_place(|| console.open(&vm_id)) {
+                Ok(path) => self.vms.set_console_log(&vm_id, &path),
+                Err(e) => warn!(vm_id = %vm_id, error = %e, "Could not open the console log"),
             }
+        }
+        if let Some(store) = &self.runs {
+            let mut request = vmm_request.clone();
+            request.kernel = kernel.name.clone();
+            request.image_digest = image_digest.clone();
+            let inputs = RunInputs {
+                run_id: vm_id.clone(),
+                recorded_at: self.clock.unix_secs(),
+                code_digest: runs::code_digest(&request.code),
+                env_digest: runs::env_digest(&request.env),
+                image_digest,
+                request: Some(request),
+                attempt: attempt.number,
+                retry_of: attempt.retry_of.clone().unwrap_or_default(),
+            };
+            if let Err(e) = tokio::task::block_in_place(|| store.record(&inputs)) {
+                warn!(vm_id = %vm_id, error = %e, "Could not record the inputs of the run");
+            }
+        }
+        let workload_name = vmm_request.workload_name.clone();
+        self.history.start(
+            &vm_id,
+            &workload_name,
+            metering::tenant(&vmm_request.tenant),
+            &vmm_request.labels,
+        );
+        let emulated = match self.hypervisor.emulated() {
+            true => ", emulated without KVM",
+            false => "",
+        };
+        self.events.publish(
+            &vm_id,
+            &workload_name,
+            VmEventKind::VmScheduled,
+            match &resources.placement {
+                Some(placement) => format!(
+                    "{} vCPU(s) on host CPUs {:?}{}, {} MB, priority class {}{}",
+                    cpus,
+                    placement.cpus,
+                    placement
+                        .numa_node
+                        .map(|node| format!(" of NUMA node {}", node))
+                        .unwrap_or_default(),
+                    memory_mb,
+                    priority_class.name,
+                    emulated
+                ),
+                None => format!(
+                    "{} vCPU(s), {} MB, priority class {}{}",
+                    cpus, memory_mb, priority_class.name, emulated
+                ),
+            },
+        );
+        let logs = self.logs.insert(&vm_id, &workload_name);
+        let vms = self.vms.clone();
+        let events = self.events.clone();
+        let vm_workload_name = workload_name.clone();
+        let run_vm_id = vm_id.clone();
+        let usage = self.usage.clone();
+        let stats = vmm.stats();
+        let tenant = metering::tenant(&vmm_request.tenant).to_string();
+        // Time spent building the workload, in milliseconds.
+        let build_ms = Arc::new(AtomicU64::new(0));
+        let vm_build_ms = build_ms.clone();
+        let clock = self.clock.clone();
+        let (started, started_at) = (clock.instant(), clock.unix_secs());
+
+        let (stopped_tx, mut stopped) = tokio::sync::oneshot::channel::<AgentVmExit>();
+
+        // Run the VMM on a thread of its own, the tasks of the run ending with it
+        let tasks = VmTasks::new(&vm_id);
+        self.vms.set_tasks(&vm_id, tasks.clone());
+        let vm_tasks = tasks.clone();
+        let _ = tasks.spawn("vm", async move {
+            info!("Running VMM");
+            events.publish(&run_vm_id, &vm_workload_name, VmEventKind::VmStarted, "");
+            let exit = match vm_tasks.spawn_blocking("vmm", move || vmm.run()).await {
+                Ok(result) => {
+                    if let Err(err) = &result {
+                        error!("Error running VMM: {:?}", err);
+                    }
+                    vm_exit(&result, vms.remove(&run_vm_id))
+                }
+                Err(status) => {
+                    vms.remove(&run_vm_id);
+                    AgentVmExit {
+                        reason: VmExitReason::HostError as i32,
+                        message: status.message().to_string(),
+                    }
+                }
+            };
+            let kind = match exit.reason() {
+                VmExitReason::GuestShutdown | VmExitReason::Killed => VmEventKind::VmStopped,
+                _ => VmEventKind::VmFailed,
+            };
+            events.publish(&run_vm_id, &vm_workload_name, kind, exit.description());
+            let uptime = clock
+                .instant()
+                .saturating_duration_since(started)
+                .as_secs_f64();
+            usage.record(UsageRecord {
+                vm_id: run_vm_id.clone(),
+                tenant,
+                workload_name: vm_workload_name.clone(),
+                started_at,
+                finished_at: clock.unix_secs(),
+                cpus: cpus.into(),
+                memory_mb,
+                vcpu_seconds: f64::from(cpus) * uptime,
+                memory_gb_seconds: f64::from(memory_mb) / 1024.0 * uptime,
+                egress_bytes: stats.tx_bytes(),
+                build_seconds: vm_build_ms.load(Ordering::Relaxed) as f64 / 1000.0,
+            });
+            drop(resources);
+            drop(reservation);
+            drop(virtual_functions);
+            drop(address);
+            let _ = stopped_tx.send(exit);
+            vm_tasks.vm_stopped();
         });
 
-        // run the grpc client
-        let grpc_client = tokio::spawn(async move {
+        // run the grpc client, the deadline covering the connection, the start of the execution
+        // and its stream
+        let run_webhooks = vmm_request.webhooks.clone();
+        let mut expired = Box::pin(deadline::expired(deadline));
+        let agent_port = self.agent_port;
+        let connect = tasks.spawn("agent-connect", async move {
             // Wait 2 seconds
             tokio::time::sleep(Duration::from_secs(2)).await;
             info!("Connecting to Agent service");
 
-            WorkloadClient::new(GUEST_IP, 50051).await
-        })
-        .await
-        .unwrap();
+            WorkloadClient::connect_within(guest_ip, agent_port, AGENT_CONNECT_TIMEOUT).await
+        });
+        let grpc_client = tokio::select! {
+            client = connect => client.and_then(|client| {
+                client.map_err(|status| {
+                    error!("ERROR {:?}", status);
+                    status
+                })
+            }),
+            () = &mut expired => {
+                logs.finish();
+                return Err(self.past_deadline(deadline, &vm_id, &workload_name, &run_webhooks));
+            }
+        };
 
-        let agent_request = self.get_agent_request(vmm_request, language);
+        // The agent redacts the output itself, this covers the agents which predate `secret_env`.
+        let redactor = Redactor::new(&vmm_request.env, &vmm_request.secret_env);
+        let keep_on_failure = vmm_request.keep_on_failure;
+        let agent_request = self.get_agent_request(
+            vmm_request,
+            &network.host_ip.to_string(),
+            language,
+            stages,
+            artifact,
+            power_off,
+        );
 
         match grpc_client {
             Ok(mut client) => {
                 info!("Successfully connected to Agent service");
 
                 // Start the execution
-                let mut response_stream = client.execute(agent_request).await?;
+                let execution = tokio::select! {
+                    execution = self.execute(&mut client, &vm_id, agent_request) => execution,
+                    () = &mut expired => {
+                        logs.finish();
+                        return Err(self.past_deadline(deadline, &vm_id, &workload_name, &run_webhooks));
+                    }
+                };
+                let mut response_stream = match execution {
+                    Ok(response_stream) => response_stream,
+                    Err(mut e) => {
+                        logs.finish();
+                        if attempt.retried_on(&e) {
+                            e.metadata_mut()
+                                .insert(VM_ID_METADATA, vm_id.parse().unwrap());
+                            return Err(e);
+                        }
+                        let message = redactor.redact(e.message());
+                        self.events.publish(
+                            &vm_id,
+                            &workload_name,
+                            VmEventKind::RunFailed,
+                            &message,
+                        );
+                        self.webhooks.notify(
+                            Payload::new(
+                                &vm_id,
+                                &workload_name,
+                                VmEventKind::RunFailed,
+                                None,
+                                &message,
+                            ),
+                            &run_webhooks,
+                        );
+                        return Err(e);
+                    }
+                };
+                self.events
+                    .publish(&vm_id, &workload_name, VmEventKind::RunStarted, "");
 
-                // Process each message as it arrives
-                tokio::spawn(async move {
-                    while let Ok(Some(response)) = response_stream.message().await {
-                        let vmm_response = vmmorchestrator::ExecuteResponse {
-                            stage: response.stage,
-                            stdout: response.stdout,
-                            stderr: response.stderr,
-                            exit_code: response.exit_code,
+                // Process each message as it arrives, the run goes on if the client leaves
+                let events = self.events.clone();
+                let storage = self.storage.clone();
+                let artifacts = self.artifacts.clone();
+                let builds = self.builds.clone();
+                let webhooks = self.webhooks.clone();
+                let vms = self.vms.clone();
+                let vm_id = vm_id.clone();
+                let drop_connection = self.faults.drop_agent_connection();
+                let attempts = attempt.number;
+                tasks.spawn_relay("relay", tx.clone(), async move {
+                    let mut power_off = power_off;
+                    let mut outcome = None;
+                    // Held until the VM stopped, to carry its exit, when it doesn't outlive the
+                    // run.
+                    let mut final_message = None;
+                    let mut building_since = None;
+                    let mut preemption = preemption;
+                    let mut preemptible = true;
+                    let interrupted = loop {
+                        tokio::select! {
+                            message = response_stream.message() => {
+                                let Ok(Some(mut response)) = message else {
+                                    break None;
+                                };
+                                if !redactor.is_empty() {
+                                    response.stdout = response
+                                        .stdout
+                                        .map(|stdout| redactor.redact_bytes(&stdout));
+                                    response.stderr = response
+                                        .stderr
+                                        .map(|stderr| redactor.redact_bytes(&stderr));
+                                }
+                                // The built workload is cached, and only its id sent on.
+                                let artifact = std::mem::take(&mut response.artifact);
+                                let builds =
+                                    builds.clone().filter(|_| !artifact.is_empty());
+                                if let Some(builds) = builds {
+                                    let id = build_id.clone();
+                                    let put = tokio::task::spawn_blocking(move || {
+                                        builds.put(&id, &artifact)
+                                    });
+                                    match put.await {
+                                        Ok(Ok(())) => response.build_id = build_id.clone(),
+                                        Ok(Err(e)) => {
+                                            warn!(vm_id = %vm_id, error = %e, "Could not cache the build")
+                                        }
+                                        Err(e) => error!(error = %e, "Build cache task failed"),
+                                    }
+                                }
+                                match (response.stage(), building_since) {
+                                    (Stage::Building, None) => building_since = Some(Instant::now()),
+                                    (Stage::Building, Some(_)) | (_, None) => {}
+                                    (_, Some(since)) => {
+                                        let elapsed = since.elapsed().as_millis() as u64;
+                                        build_ms.fetch_add(elapsed, Ordering::Relaxed);
+                                        building_since = None;
+                                    }
+                                }
+                                crashes::keep(&artifacts, storage.clone(), &vm_id, &mut response)
+                                    .await;
+                                if matches!(response.stage(), Stage::Done | Stage::Failed) {
+                                    response.build_cached = build_cached;
+                                    response.attempts = attempts;
+                                    outcome = Some((
+                                        response.stage(),
+                                        response.exit_code,
+                                        run_summary(&response),
+                                    ));
+                                    if response.stage() == Stage::Failed && keep_on_failure {
+                                        // The agent keeps the guest up too.
+                                        info!(vm_id = %vm_id, "Run failed, keeping the VM until it is shut down");
+                                        power_off = false;
+                                    }
+                                    if power_off {
+                                        final_message = Some(response);
+                                        continue;
+                                    }
+                                }
+                                logs.push(response.clone());
+                                tx.send(Ok(response));
+                                if drop_connection {
+                                    warn!(vm_id = %vm_id, "Injected fault: dropping the agent connection");
+                                    tx.send(Err(ErrorCode::VmmAgentUnreachable.status(
+                                        Code::Unavailable,
+                                        "Lost the connection to the agent",
+                                    )));
+                                    break None;
+                                }
+                            }
+                            by = &mut preemption, if preemptible => {
+                                preemptible = false;
+                                if let Ok(by) = by {
+                                    break Some(Interruption::Preempted(by));
+                                }
+                            }
+                            () = &mut expired => break deadline.map(Interruption::DeadlineExceeded),
+                        }
+                    };
+
+                    match &interrupted {
+                        Some(Interruption::Preempted(by)) => {
+                            let message = format!("Preempted by {}", by);
+                            events.publish(&vm_id, &workload_name, VmEventKind::VmPreempted, &message);
+                            vms.mark_stopping(&vm_id, &format!("preempted by {}", by));
+                            tx.send(Err(
+                                ErrorCode::VmmPreempted.status(Code::Aborted, message.clone())
+                            ));
+                            if let Err(e) = client.shutdown(ShutdownVmRequest::default()).await {
+                                warn!(vm_id = %vm_id, error = %e, "Could not shut the preempted VM down");
+                            }
+                        }
+                        Some(Interruption::DeadlineExceeded(deadline)) => {
+                            tx.send(Err(deadline.enforce(&vms, &events, &vm_id, &workload_name)));
+                        }
+                        None => {}
+                    }
+                    // The guest powers itself off once the run is over, the stream to the
+                    // client ending with the VM. A run without an end may also have lost its VM.
+                    let mut vm_exit = None;
+                    if interrupted.is_none() && (power_off || outcome.is_none()) {
+                        let wait = if outcome.is_some() {
+                            POWER_OFF_TIMEOUT
+                        } else {
+                            VM_EXIT_TIMEOUT
                         };
-                        let _ = tx.send(Ok(vmm_response)).await;
+                        vm_exit = match tokio::time::timeout(wait, &mut stopped).await {
+                            Ok(exit) => exit.ok(),
+                            Err(_) if power_off => {
+                                let reason = "the guest didn't power off at the end of its run";
+                                warn!(vm_id = %vm_id, "The guest didn't power off at the end of its run");
+                                stop_vm(&vms, &vm_id, reason).await;
+                                tokio::time::timeout(VM_EXIT_TIMEOUT, &mut stopped)
+                                    .await
+                                    .ok()
+                                    .and_then(|exit| exit.ok())
+                            }
+                            Err(_) => None,
+                        };
+                    }
+                    let final_message = match (final_message, &vm_exit) {
+                        (Some(response), _) => Some(response),
+                        (None, Some(exit)) if interrupted.is_none() => Some(ExecuteResponse {
+                            stage: Stage::Failed as i32,
+                            stderr: Some(
+                                format!("The VM stopped during the run: {}\n", exit.description())
+                                    .into_bytes(),
+                            ),
+                            ..Default::default()
+                        }),
+                        (None, _) => None,
+                    };
+                    if let Some(mut response) = final_message {
+                        response.vm_exit = vm_exit.clone();
+                        logs.push(response.clone());
+                        tx.send(Ok(response));
                     }
+                    logs.finish();
+                    if let Some(storage) = storage {
+                        let (key, archive) = (logs_key(&vm_id), logs.archive());
+                        match tokio::task::spawn_blocking(move || storage.write(&key, &archive))
+                            .await
+                        {
+                            Ok(Ok(())) => {}
+                            Ok(Err(e)) => {
+                                warn!(vm_id = %vm_id, error = %e, "Could not store the logs")
+                            }
+                            Err(e) => error!(error = %e, "Logs storage task failed"),
+                        }
+                    }
+
+                    let exit_code = outcome.as_ref().and_then(|(_, exit_code, _)| *exit_code);
+                    // The run state tells a workload which ended from a VM which died under it.
+                    let vm_failure = vm_exit
+                        .as_ref()
+                        .filter(|exit| exit.reason() != VmExitReason::GuestShutdown)
+                        .map(AgentVmExit::description);
+                    let (kind, message) = match (outcome, interrupted) {
+                        (_, Some(interruption)) => (VmEventKind::RunFailed, interruption.to_string()),
+                        (Some((stage, _, summary)), None) => (
+                            if stage == Stage::Done {
+                                VmEventKind::RunFinished
+                            } else {
+                                VmEventKind::RunFailed
+                            },
+                            match vm_failure {
+                                Some(failure) => format!("{}, then {}", summary, failure),
+                                None => summary,
+                            },
+                        ),
+                        (None, None) => (
+                            VmEventKind::RunFailed,
+                            match vm_exit {
+                                Some(exit) => {
+                                    format!("the VM stopped during the run: {}", exit.description())
+                                }
+                                None => "the agent stopped before the end of the run".to_string(),
+                            },
+                        ),
+                    };
+                    events.publish(&vm_id, &workload_name, kind, &message);
+                    webhooks.notify(
+                        Payload::new(&vm_id, &workload_name, kind, exit_code, &message),
+                        &run_webhooks,
+                    );
                 });
             }
+            Err(mut status) => {
+                logs.finish();
+                if attempt.retried_on(&status) {
+                    status
+                        .metadata_mut()
+                        .insert(VM_ID_METADATA, vm_id.parse().unwrap());
+                    return Err(status);
+                }
+                let message = "could not connect to the agent";
+                self.events
+                    .publish(&vm_id, &workload_name, VmEventKind::RunFailed, message);
+                self.webhooks.notify(
+                    Payload::new(
+                        &vm_id,
+                        &workload_name,
+                        VmEventKind::RunFailed,
+                        None,
+                        message,
+                    ),
+                    &run_webhooks,
+                );
+                return Err(status);
+            }
+        }
+
+        let mut response = Response::new(rx);
+        response
+            .metadata_mut()
+            .insert(VM_ID_METADATA, vm_id.parse().unwrap());
+        Ok(response)
+    }
+}
+
+#[tonic::async_trait]
+impl VmmServiceTrait for VmmService {
+    type RunStream = ReceiverStream<std::result::Result<ExecuteResponse, tonic::Status>>;
+
+    async fn shutdown(&self, request: Request<ShutdownVmRequest>) -> Result<ShutdownVmResponse> {
+        let vm = self.vms.resolve(&request.get_ref().vm).ok_or_else(|| {
+            ErrorCode::VmmUnknownVm.status(
+                Code::NotFound,
+                match request.get_ref().vm.as_str() {
+                    "" => "No single running VM to shut down, give its id or workload name"
+                        .to_string(),
+                    vm => format!("No VM is running with the id or workload name {}", vm),
+                },
+            )
+        })?;
+        let guest_ip: Ipv4Addr = vm.guest_ip.parse().map_err(|_| {
+            ErrorCode::VmmShutdownFailed.status(
+                Code::Internal,
+                format!("Invalid guest address {}", vm.guest_ip),
+            )
+        })?;
+        let port = agent_port(&vm);
+        info!(vm_id = %vm.id, "Shutting down VM");
+        self.vms.mark_stopping(&vm.id, "shut down on request");
+
+        // Wait 2 seconds
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        info!("Connecting to Agent service");
+
+        match WorkloadClient::new(guest_ip, port).await {
+            Ok(mut client) => {
+                info!("Attempting to shutdown the VM...");
+                let response = client.shutdown(request.into_inner()).await.map_err(|e| {
+                    ErrorCode::VmmShutdownFailed.status(
+                        Code::Internal,
+                        format!("Failed to shutdown the VM: {}", e.message()),
+                    )
+                })?;
+                Ok(Response::new(response))
+            }
             Err(e) => {
                 error!("ERROR {:?}", e);
+                Err(ErrorCode::VmmShutdownFailed
+                    .status(Code::Internal, "Failed to shutdown the VM"))
+            }
+        }
+    }
+
+    async fn run(&self, request: Request<RunVmmRequest>) -> Result<Self::RunStream> {
+        let keep_vm = request.extensions().get::<KeepVm>().is_some();
+        let vmm_request = request.into_inner();
+        let mut attempt = Attempt::first(self.infra_retries);
+        loop {
+            let mut request = Request::new(vmm_request.clone());
+            if keep_vm {
+                request.extensions_mut().insert(KeepVm);
             }
+            let status = match self.run_attempt(request, &attempt).await {
+                Err(status) if attempt.retried_on(&status) => status,
+                result => return result,
+            };
+
+            let workload_name = &vmm_request.workload_name;
+            let vm_id = status
+                .metadata()
+                .get(VM_ID_METADATA)
+                .and_then(|vm_id| vm_id.to_str().ok())
+                .map(str::to_string);
+            warn!(
+                workload_name = %workload_name,
+                vm_id = ?vm_id,
+                attempt = attempt.number,
+                error = %status.message(),
+                "Infrastructure failure, retrying the run on a fresh VM"
+            );
+            if let Some(vm_id) = &vm_id {
+                retries::discard_vm(&self.vms, vm_id, workload_name, "its run is retried").await;
+            }
+            self.events.publish(
+                vm_id.as_deref().unwrap_or_default(),
+                workload_name,
+                VmEventKind::RunRetried,
+                format!(
+                    "attempt {} failed: {}, retrying on a fresh VM",
+                    attempt.number,
+                    status.message()
+                ),
+            );
+            tokio::time::sleep(attempt.delay()).await;
+            attempt = attempt.next(vm_id);
         }
+    }
+
+    type WatchEventsStream = ReceiverStream<std::result::Result<VmEvent, tonic::Status>>;
+
+    async fn watch_events(
+        &self,
+        request: Request<WatchEventsRequest>,
+    ) -> Result<Self::WatchEventsStream> {
+        let filter = request.into_inner();
+        let events = self.events.clone();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move { events.watch(filter, tx).await });
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    async fn register_workload(
+        &self,
+        request: Request<RegisterWorkloadRequest>,
+    ) -> Result<RegisteredWorkload> {
+        let request = request.into_inner();
+        let store = self.workload_store()?;
+        if !workloads::is_name(&request.name) || !workloads::is_version(&request.version) {
+            return Err(ErrorCode::VmmInvalidRequest.status(
+                Code::InvalidArgument,
+                format!(
+                    "Invalid workload name {:?} or version {:?}",
+                    request.name, request.version
+                ),
+            ));
+        }
+        let run = request.request.unwrap_or_default();
+        Language::try_from(run.language).map_err(|e| {
+            ErrorCode::VmmInvalidRequest.status(Code::InvalidArgument, e.to_string())
+        })?;
+
+        let registered = tokio::task::block_in_place(|| {
+            store.register(
+                &request.name,
+                &request.version,
+                run,
+                request.scaling,
+                request.cache,
+            )
+        })
+        .map_err(|e| Status::internal(format!("Could not register the workload: {}", e)))?;
+        match registered {
+            Some(workload) => {
+                info!(name = %workload.name, version = %workload.version, "Registered workload");
+                Ok(Response::new(RegisteredWorkload {
+                    request: None,
+                    ..workload
+                }))
+            }
+            None => Err(ErrorCode::VmmDuplicateVersion.status(
+                Code::AlreadyExists,
+                format!(
+                    "Version {} of the workload {} is already registered",
+                    request.version, request.name
+                ),
+            )),
+        }
+    }
+
+    type InvokeWorkloadStream = Self::RunStream;
+
+    async fn invoke_workload(
+        &self,
+        request: Request<InvokeWorkloadRequest>,
+    ) -> Result<Self::InvokeWorkloadStream> {
+        self.maintenance.check()?;
+        let request = request.into_inner();
+        let store = self.workload_store()?;
+        let workload = tokio::task::block_in_place(|| store.get(&request.name, &request.version))
+            .map_err(|e| Status::internal(format!("Could not read the workloads: {}", e)))?
+            .ok_or_else(|| unknown_workload(&request.name, &request.version))?;
+        info!(name = %workload.name, version = %workload.version, "Invoking workload");
+
+        let mut run = workload.request.unwrap_or_default();
+        run.env.extend(request.inputs);
+        run.stdin = request.input;
+        let bounds = self.pool.config().bounds(workload.scaling.as_ref());
+        let lease = self
+            .pool
+            .acquire(
+                &workload.name,
+                &workload.version,
+                metering::tenant(&run.tenant),
+                bounds,
+                &self.vms,
+            )
+            .await;
+        self.run_instance(lease, run).await
+    }
+
+    async fn list_pools(&self, request: Request<ListPoolsRequest>) -> Result<ListPoolsResponse> {
+        let name = request.into_inner().name;
+        let pools = self.pool.status(&name);
+        if !name.is_empty() && pools.is_empty() {
+            return Err(ErrorCode::VmmUnknownWorkload.status(
+                Code::NotFound,
+                format!(
+                    "The workload {} wasn't invoked since the orchestrator started",
+                    name
+                ),
+            ));
+        }
+
+        Ok(Response::new(ListPoolsResponse { pools }))
+    }
+
+    async fn upgrade_pools(
+        &self,
+        request: Request<UpgradePoolsRequest>,
+    ) -> Result<UpgradePoolsResponse> {
+        let request = request.into_inner();
+        if !request.name.is_empty() && self.pool.status(&request.name).is_empty() {
+            return Err(ErrorCode::VmmUnknownWorkload.status(
+                Code::NotFound,
+                format!(
+                    "The workload {} wasn't invoked since the orchestrator started",
+                    request.name
+                ),
+            ));
+        }
+
+        let pools = tokio::task::block_in_place(|| {
+            self.upgrade_pools_matching(&request, "upgrade requested")
+        })?;
+        Ok(Response::new(UpgradePoolsResponse { pools }))
+    }
+
+    async fn export_usage(
+        &self,
+        request: Request<ExportUsageRequest>,
+    ) -> Result<ExportUsageResponse> {
+        Ok(Response::new(self.usage.export(&request.into_inner())))
+    }
+
+    async fn list_vm_metrics(
+        &self,
+        request: Request<ListVmMetricsRequest>,
+    ) -> Result<ListVmMetricsResponse> {
+        let selector = LabelSelector::from_str(&request.into_inner().selector)
+            .map_err(|e| ErrorCode::VmmInvalidLabels.status(Code::InvalidArgument, e))?;
+        let vms = self
+            .vms
+            .list_with_stats()
+            .into_iter()
+            .filter(|(vm, _)| selector.matches(&vm.labels))
+            .map(|(vm, stats)| VmMetrics {
+                vm_id: vm.id,
+                workload_name: vm.workload_name,
+                language: vm.language,
+                cpus: vm.cpus,
+                memory_mb: vm.memory_mb,
+                started_at: vm.started_at,
+                cpu_seconds: stats.cpu_time().as_secs_f64(),
+                egress_bytes: stats.tx_bytes(),
+                priority_class: vm.priority_class,
+                labels: vm.labels,
+            })
+            .collect();
+        Ok(Response::new(ListVmMetricsResponse { vms }))
+    }
+
+    async fn list_runs(&self, request: Request<ListRunsRequest>) -> Result<ListRunsResponse> {
+        let mut runs = self
+            .history
+            .list(&request.into_inner())
+            .map_err(|e| ErrorCode::VmmInvalidLabels.status(Code::InvalidArgument, e))?;
+        // The history only holds the runs of this orchestrator, since it started.
+        for run in &mut runs {
+            run.emulated = self.hypervisor.emulated();
+        }
+        Ok(Response::new(ListRunsResponse { runs }))
+    }
+
+    async fn list_workloads(
+        &self,
+        request: Request<ListWorkloadsRequest>,
+    ) -> Result<ListWorkloadsResponse> {
+        let name = request.into_inner().name;
+        let store = self.workload_store()?;
+        let workloads = tokio::task::block_in_place(|| store.list(&name))
+            .map_err(|e| Status::internal(format!("Could not read the workloads: {}", e)))?;
+        if !name.is_empty() && workloads.is_empty() {
+            return Err(unknown_workload(&name, ""));
+        }
+
+        Ok(Response::new(ListWorkloadsResponse { workloads }))
+    }
+
+    type StreamLogsStream = ReceiverStream<std::result::Result<ExecuteResponse, tonic::Status>>;
+
+    async fn stream_logs(
+        &self,
+        request: Request<StreamLogsRequest>,
+    ) -> Result<Self::StreamLogsStream> {
+        let request = request.into_inner();
+        let logs = match self.logs.get(&request.vm) {
+            Some(logs) => Some(logs),
+            None => self.stored_logs(&request.vm).await?,
+        };
+        let logs = logs.ok_or_else(|| {
+            ErrorCode::VmmUnknownVm.status(
+                Code::NotFound,
+                format!("No run is known for the VM or workload {}", request.vm),
+            )
+        })?;
+
+        let grep = match request.grep.as_str() {
+            "" => None,
+            grep => Some(
+                RegexBuilder::new(grep)
+                    .size_limit(1 << 20)
+                    .build()
+                    .map_err(|e| {
+                        ErrorCode::VmmInvalidRequest.status(
+                            Code::InvalidArgument,
+                            format!("Invalid grep pattern: {}", e),
+                        )
+                    })?,
+            ),
+        };
+        let filter = LogFilter {
+            since_ms: request.since.saturating_mul(1000),
+            grep,
+            stream: request.stream(),
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move {
+            logs.stream(request.tail_lines as usize, request.follow, filter, tx)
+                .await
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn plan(&self, request: Request<RunVmmRequest>) -> Result<RunPlan> {
+        let mut vmm_request = request.into_inner();
+        let language = Language::try_from(vmm_request.language).map_err(|e| {
+            ErrorCode::VmmInvalidRequest.status(Code::InvalidArgument, e.to_string())
+        })?;
+        self.admit(&mut vmm_request).await?;
+        self.scheduler.class(&vmm_request.priority_class)?;
+
+        let curr_dir = current_dir()
+            .map_err(VmmErrors::VmmBuildEnvironment)?
+            .into_os_string();
+        let (cpus, memory_mb) = requested_resources(&vmm_request);
+        let runtime_version = requested_runtime_version(&vmm_request, &language)?;
+        let runtime_version = runtime_version.as_deref();
+        self.check_capabilities(&vmm_request)?;
+        self.check_node(&vmm_request)?;
+        self.check_devices(&vmm_request)?;
+
+        let kernel = self.select_kernel(&vmm_request)?;
+        let kernel_path = kernel
+            .path
+            .unwrap_or_else(|| builtin_kernel_path(&curr_dir));
+        let initramfs = initramfs_path(&curr_dir, language.as_str(), runtime_version);
+
+        // Looked up without reserving, fetching or building anything.
+        let warm_pool_hit = self.pool.has_warm(
+            &vmm_request.workload_name,
+            metering::tenant(&vmm_request.tenant),
+            &self.vms,
+        );
+        let build_id = builds::build_id(&vmm_request, &self.cpu_template);
+        let build_cached = self.builds.as_ref().is_some_and(|builds| {
+            tokio::task::block_in_place(|| builds.contains(&build_id)).unwrap_or_else(|e| {
+                warn!(build_id, error = %e, "Could not read the build cache");
+                false
+            })
+        });
+        let registry_pull = self
+            .rootfs_registry
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|registry| {
+                registry.would_pull(language.as_str(), runtime_version, &initramfs)
+            });
+
+        Ok(Response::new(RunPlan {
+            image: runtimes::image(language.as_str(), runtime_version),
+            kernel: Some(artifact_plan(kernel_path)),
+            initramfs: Some(artifact_plan(initramfs)),
+            cpus: cpus.into(),
+            memory_mb,
+            warm_pool_hit,
+            build_cached,
+            registry_pull,
+        }))
+    }
+
+    async fn get_server_info(&self, _request: Request<GetServerInfoRequest>) -> Result<ServerInfo> {
+        let runtimes = [Language::RUST, Language::PYTHON, Language::NODE]
+            .into_iter()
+            .map(|language| RuntimeInfo {
+                versions: runtimes::supported_versions(&language)
+                    .iter()
+                    .map(|version| version.to_string())
+                    .collect(),
+                language: shared_models::vmmorchestrator::Language::from(language) as i32,
+            })
+            .collect();
+
+        let registry = self.kernels.read().unwrap();
+        let kernels = registry
+            .kernels()
+            .iter()
+            .map(|kernel| KernelInfo {
+                name: kernel.name.clone(),
+                version: kernel.version.clone(),
+                features: kernel.features.iter().flatten().cloned().collect(),
+            })
+            .collect();
+
+        Ok(Response::new(ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            runtimes,
+            kernels,
+            default_kernel: registry.default_name().to_string(),
+            hypervisor: self.hypervisor.name().to_string(),
+            problems: health::problems(self.hypervisor.emulated(), self.tenant_networks.as_ref()),
+            capabilities: [
+                Capability::NetworkEgress,
+                Capability::ArtifactOutput,
+                Capability::SharedVolume,
+                Capability::Gpu,
+                Capability::SriovNic,
+            ]
+            .into_iter()
+            .filter(|capability| self.missing_capability(*capability).is_none())
+            .map(|capability| capability as i32)
+            .collect(),
+            uplink: self.uplink.clone().unwrap_or_default(),
+            sriov_vfs: self.sriov_vfs.len() as u32,
+            sriov_vfs_available: self.sriov_vfs.available() as u32,
+            node_labels: self.node_labels.clone(),
+            cordoned: self
+                .maintenance
+                .cordoned()
+                .map(|cordon| cordon.reason)
+                .unwrap_or_default(),
+            rootfs_builds: self.rootfs_builds.stats(),
+            emulated: self.hypervisor.emulated(),
+        }))
+    }
+
+    async fn get_run_inputs(&self, request: Request<GetRunInputsRequest>) -> Result<RunInputs> {
+        let run_id = request.into_inner().run_id;
+        let Some(store) = &self.runs else {
+            return Err(ErrorCode::VmmUnknownRun.status(
+                Code::FailedPrecondition,
+                "The orchestrator doesn't record the runs",
+            ));
+        };
+
+        match tokio::task::block_in_place(|| store.get(&run_id)) {
+            Ok(Some(inputs)) => Ok(Response::new(inputs)),
+            Ok(None) => Err(ErrorCode::VmmUnknownRun
+                .status(Code::NotFound, format!("No run {} was recorded", run_id))),
+            Err(e) => Err(Status::internal(format!(
+                "Could not read the inputs of run {}: {}",
+                run_id, e
+            ))),
+        }
+    }
+
+    async fn get_run_debug_info(
+        &self,
+        request: Request<GetRunDebugInfoRequest>,
+    ) -> Result<RunDebugInfo> {
+        let run_id = request.into_inner().run_id;
+        let mut recorded = match &self.runs {
+            Some(store) => tokio::task::block_in_place(|| store.get(&run_id))
+                .map_err(|e| {
+                    Status::internal(format!(
+                        "Could not read the inputs of run {}: {}",
+                        run_id, e
+                    ))
+                })?
+                .and_then(|inputs| inputs.request),
+            None => None,
+        };
+        let history = self.history.get(&run_id);
+        if history.is_none() && recorded.is_none() {
+            return Err(ErrorCode::VmmUnknownRun
+                .status(Code::NotFound, format!("No run {} is known", run_id)));
+        }
+
+        let request = match &mut recorded {
+            Some(request) => {
+                redact_env(request.env.iter_mut(), &request.secret_env);
+                for webhook in &mut request.webhooks {
+                    if !webhook.secret.is_empty() {
+                        webhook.secret = REDACTED.to_string();
+                    }
+                }
+                format!("{:#?}", request)
+            }
+            None => String::new(),
+        };
+        let (run, events) = history.unzip();
+        let console_log = self
+            .console_logs
+            .as_ref()
+            .filter(|_| runs::is_run_id(&run_id))
+            .map(|config| config.path(&run_id))
+            .filter(|path| path.is_file())
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(Response::new(RunDebugInfo {
+            run,
+            events: events.unwrap_or_default(),
+            request,
+            config: self.config_snapshot(),
+            console_log,
+        }))
+    }
+
+    async fn list_run_artifacts(
+        &self,
+        request: Request<ListRunArtifactsRequest>,
+    ) -> Result<ListRunArtifactsResponse> {
+        let run_id = request.into_inner().run_id;
+        if let Some(artifacts) = self.artifacts.list(&run_id) {
+            return Ok(Response::new(ListRunArtifactsResponse { artifacts }));
+        }
+        let unknown = || {
+            ErrorCode::VmmUnknownArtifact.status(
+                Code::NotFound,
+                format!("No artifact was collected from the run {}", run_id),
+            )
+        };
+        if !runs::is_run_id(&run_id) {
+            return Err(unknown());
+        }
+
+        match self.read_stored(crashes::index_key(&run_id)).await? {
+            Some(index) => ListRunArtifactsResponse::decode(index.as_slice())
+                .map(Response::new)
+                .map_err(|e| {
+                    Status::internal(format!(
+                        "Could not decode the artifacts of {}: {}",
+                        run_id, e
+                    ))
+                }),
+            None => Err(unknown()),
+        }
+    }
+
+    async fn get_run_artifact(
+        &self,
+        request: Request<GetRunArtifactRequest>,
+    ) -> Result<RunArtifact> {
+        let GetRunArtifactRequest { run_id, name } = request.into_inner();
+        if let Some(artifact) = self.artifacts.get(&run_id, &name) {
+            return Ok(Response::new(artifact));
+        }
+        let unknown = || {
+            ErrorCode::VmmUnknownArtifact.status(
+                Code::NotFound,
+                format!("No artifact {} was collected from the run {}", name, run_id),
+            )
+        };
+        if !runs::is_run_id(&run_id) || !workloads::is_name(&name) {
+            return Err(unknown());
+        }
+
+        // The description is kept with the artifacts, the content alone not telling it was cut.
+        let Some(index) = self.read_stored(crashes::index_key(&run_id)).await? else {
+            return Err(unknown());
+        };
+        let description = ListRunArtifactsResponse::decode(index.as_slice())
+            .ok()
+            .and_then(|index| {
+                index
+                    .artifacts
+                    .into_iter()
+                    .find(|artifact| artifact.name == name)
+            })
+            .ok_or_else(unknown)?;
+        match self.read_stored(crashes::key(&run_id, &name)).await? {
+            Some(content) => Ok(Response::new(RunArtifact {
+                content,
+                ..description
+            })),
+            None => Err(unknown()),
+        }
+    }
+
+    async fn list_guest_dir(
+        &self,
+        request: Request<ListGuestDirRequest>,
+    ) -> Result<ListDirResponse> {
+        let request = request.into_inner();
+        let mut client = self.guest_agent(&request.vm).await?;
+        Ok(Response::new(client.list_dir(request.path).await?))
+    }
+
+    async fn read_guest_file(
+        &self,
+        request: Request<ReadGuestFileRequest>,
+    ) -> Result<ReadFileResponse> {
+        let request = request.into_inner();
+        let mut client = self.guest_agent(&request.vm).await?;
+        Ok(Response::new(
+            client.read_file(request.path, request.max_bytes).await?,
+        ))
+    }
+
+    type ExecShellStream = Streaming<ShellOutput>;
+
+    async fn exec_shell(
+        &self,
+        request: Request<Streaming<ShellInput>>,
+    ) -> Result<Self::ExecShellStream> {
+        if !self.exec_shell {
+            return Err(ErrorCode::VmmExecShellDisabled.status(
+                Code::PermissionDenied,
+                "Shells are disabled on this orchestrator",
+            ));
+        }
+        let mut inputs = request.into_inner();
+        let start = inputs.message().await?;
+        let vm = match &start {
+            Some(ShellInput {
+                input: Some(ShellInputKind::Start(start)),
+            }) => start.vm.clone(),
+            _ => {
+                return Err(ErrorCode::VmmInvalidRequest.status(
+                    Code::InvalidArgument,
+                    "The first message must start the shell",
+                ))
+            }
+        };
+        let mut client = self.guest_agent(&vm).await?;
+        warn!(vm = %vm, "Opening a shell in the guest");
+
+        // The shell is hung up once the client stops sending.
+        let inputs = tokio_stream::iter(start).chain(inputs.map_while(|input| input.ok()));
+        Ok(Response::new(client.exec_shell(inputs).await?))
+    }
 }
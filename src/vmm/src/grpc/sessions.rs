@@ -0,0 +1,371 @@
+//! Exchanges between the orchestrator and the agents, recorded to be replayed.
+//!
+//! With `--record-sessions`, the request sent to the agent of each run, the responses it streams
+//! back and the status ending the stream are written to `<vm id>-<millis>.session` in the
+//! directory, as length-delimited [`SessionRecord`]s. Like the recorded run inputs, the requests
+//! hold the code and the environment of the runs, secrets included: the files are only readable
+//! by the orchestrator user.
+//!
+//! A [`ReplayAgent`] serves a recorded session back: `vmm replay` runs it through the
+//! orchestrator as if it came from a guest, so that a session which went wrong in production can
+//! become a regression test.
+
+use super::deterministic::SharedClock;
+use prost::Message;
+use shared_models::cloudlet::agent::{
+    workload_runner_server::{WorkloadRunner, WorkloadRunnerServer},
+    ExecuteRequest, ExecuteResponse, HelloRequest, HelloResponse, ListDirRequest, ListDirResponse,
+    ReadFileRequest, ReadFileResponse, ShellInput, ShellOutput, SignalRequest,
+};
+use shared_models::vmmorchestrator::{
+    session_record::Record, Language, RunVmmRequest, SessionError, SessionRecord,
+};
+use shared_models::{
+    agent_capabilities, AGENT_MAX_MESSAGE_SIZE, AGENT_PROTOCOL_VERSION, MIN_AGENT_PROTOCOL_VERSION,
+};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::warn;
+
+type Result<T> = std::result::Result<Response<T>, Status>;
+
+/// Writes the session of a run to its file.
+pub struct Recorder {
+    path: PathBuf,
+    file: BufWriter<File>,
+    started: Instant,
+    clock: SharedClock,
+}
+
+impl Recorder {
+    /// Record the session of the run of `vm_id` in `dir`, starting with `request`, timed with
+    /// `clock`.
+    pub fn create(
+        dir: &Path,
+        vm_id: &str,
+        request: &ExecuteRequest,
+        clock: SharedClock,
+    ) -> io::Result<Self> {
+        let millis = clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!("{}-{}.session", vm_id, millis));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        let mut recorder = Self {
+            path,
+            file: BufWriter::new(file),
+            started: clock.instant(),
+            clock,
+        };
+        recorder.write(Record::Request(request.clone()))?;
+        Ok(recorder)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn response(&mut self, response: &ExecuteResponse) -> io::Result<()> {
+        self.write(Record::Response(response.clone()))
+    }
+
+    pub fn error(&mut self, status: &Status) -> io::Result<()> {
+        self.write(Record::Error(SessionError {
+            code: status.code() as i32,
+            message: status.message().to_string(),
+        }))
+    }
+
+    fn write(&mut self, record: Record) -> io::Result<()> {
+        let record = SessionRecord {
+            elapsed_ms: (self.clock.instant() - self.started).as_millis() as u64,
+            record: Some(record),
+        };
+        self.file
+            .write_all(&record.encode_length_delimited_to_vec())?;
+        // Written as they come, for the session of a crashed orchestrator to be kept.
+        self.file.flush()
+    }
+}
+
+/// Responses of an agent, recorded if the sessions are.
+pub struct AgentStream {
+    stream: Streaming<ExecuteResponse>,
+    recorder: Option<Recorder>,
+}
+
+impl AgentStream {
+    pub fn new(stream: Streaming<ExecuteResponse>, recorder: Option<Recorder>) -> Self {
+        Self { stream, recorder }
+    }
+
+    /// The next response, `None` once the stream ended.
+    pub async fn message(&mut self) -> std::result::Result<Option<ExecuteResponse>, Status> {
+        let message = self.stream.message().await;
+        if let Some(recorder) = &mut self.recorder {
+            let written = match &message {
+                Ok(Some(response)) => recorder.response(response),
+                Ok(None) => Ok(()),
+                Err(status) => recorder.error(status),
+            };
+            if let Err(e) = written {
+                warn!(path = ?recorder.path(), error = %e, "Could not record the session, stopping");
+                self.recorder = None;
+            }
+        }
+        message
+    }
+}
+
+/// Recorded session, ready to be replayed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub request: ExecuteRequest,
+    /// The responses, then the status ending the stream if any.
+    pub records: Vec<SessionRecord>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let mut buffer = bytes.as_slice();
+        let invalid = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?}: {}", path, message),
+            )
+        };
+
+        let mut request = None;
+        let mut records = Vec::new();
+        while !buffer.is_empty() {
+            let record = SessionRecord::decode_length_delimited(&mut buffer)
+                .map_err(|e| invalid(&e.to_string()))?;
+            match (&record.record, &request) {
+                (Some(Record::Request(first)), None) => request = Some(first.clone()),
+                (Some(Record::Request(_)), Some(_)) => {
+                    return Err(invalid("more than one request"))
+                }
+                (_, None) => return Err(invalid("the session doesn't start with its request")),
+                (_, Some(_)) => records.push(record),
+            }
+        }
+
+        Ok(Self {
+            request: request.ok_or_else(|| invalid("empty session"))?,
+            records,
+        })
+    }
+
+    /// Run request sending the recorded request to the agent again, the stages aside.
+    pub fn run_request(&self) -> RunVmmRequest {
+        let request = &self.request;
+        RunVmmRequest {
+            workload_name: request.workload_name.clone(),
+            language: Language::from_str_name(&request.language.to_uppercase()).unwrap_or_default()
+                as i32,
+            code: request.code.clone(),
+            build: request.build.clone(),
+            env: request.env.clone(),
+            secret_env: request.secret_env.clone(),
+            stdin: request.stdin.clone(),
+            keep_on_failure: request.keep_on_failure,
+            tmp_quota_mb: request.tmp_quota_mb,
+            ..Default::default()
+        }
+    }
+}
+
+/// Agent answering the requests with a recorded session.
+pub struct ReplayAgent {
+    session: Session,
+    /// Wait between the responses as long as the agent did, instead of sending them at once.
+    realtime: bool,
+    clock: SharedClock,
+}
+
+impl ReplayAgent {
+    pub fn new(session: Session, realtime: bool, clock: SharedClock) -> Self {
+        Self {
+            session,
+            realtime,
+            clock,
+        }
+    }
+
+    /// Serve the session on a local port, until the returned task is aborted.
+    pub async fn serve(self) -> io::Result<(SocketAddr, tokio::task::JoinHandle<()>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = Server::builder().add_service(
+            WorkloadRunnerServer::new(self)
+                .max_decoding_message_size(AGENT_MAX_MESSAGE_SIZE)
+                .max_encoding_message_size(AGENT_MAX_MESSAGE_SIZE),
+        );
+        let task = tokio::spawn(async move {
+            if let Err(e) = server
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+            {
+                warn!(error = %e, "The replay agent stopped");
+            }
+        });
+        Ok((addr, task))
+    }
+}
+
+fn replayed() -> Status {
+    Status::unimplemented("Only the Execute stream of a recorded session can be replayed")
+}
+
+#[tonic::async_trait]
+impl WorkloadRunner for ReplayAgent {
+    type ExecuteStream = ReceiverStream<std::result::Result<ExecuteResponse, Status>>;
+
+    async fn hello(&self, _: Request<HelloRequest>) -> Result<HelloResponse> {
+        Ok(Response::new(HelloResponse {
+            protocol_version: AGENT_PROTOCOL_VERSION,
+            min_protocol_version: MIN_AGENT_PROTOCOL_VERSION,
+            version: format!("replay-{}", env!("CARGO_PKG_VERSION")),
+            capabilities: agent_capabilities::ALL
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+        }))
+    }
+
+    async fn execute(&self, request: Request<ExecuteRequest>) -> Result<Self::ExecuteStream> {
+        if request.get_ref().workload_name != self.session.request.workload_name {
+            warn!(
+                recorded = %self.session.request.workload_name,
+                requested = %request.get_ref().workload_name,
+                "Replaying the session of another workload"
+            );
+        }
+
+        let (tx, rx) = mpsc::channel(16);
+        let (records, realtime) = (self.session.records.clone(), self.realtime);
+        let started = self.clock.instant();
+        tokio::spawn(async move {
+            for record in records {
+                if realtime {
+                    let at = started + Duration::from_millis(record.elapsed_ms);
+                    tokio::time::sleep_until(at.into()).await;
+                }
+                let message = match record.record {
+                    Some(Record::Response(response)) => Ok(response),
+                    Some(Record::Error(error)) => {
+                        Err(Status::new(Code::from(error.code), error.message))
+                    }
+                    _ => continue,
+                };
+                if tx.send(message).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn signal(&self, _: Request<SignalRequest>) -> Result<()> {
+        Ok(Response::new(()))
+    }
+
+    async fn list_dir(&self, _: Request<ListDirRequest>) -> Result<ListDirResponse> {
+        Err(replayed())
+    }
+
+    async fn read_file(&self, _: Request<ReadFileRequest>) -> Result<ReadFileResponse> {
+        Err(replayed())
+    }
+
+    type ExecShellStream = ReceiverStream<std::result::Result<ShellOutput, Status>>;
+
+    async fn exec_shell(&self, _: Request<Streaming<ShellInput>>) -> Result<Self::ExecShellStream> {
+        Err(replayed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::{admin::VmTable, deterministic::system_clock, server::VmmService};
+    use cloudlet_test_registry::scratch_dir;
+    use shared_models::cloudlet::agent::execute_response::Stage;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_replay_recorded_session() {
+        let dir = scratch_dir("vmm-sessions");
+        let request = ExecuteRequest {
+            workload_name: "hello".into(),
+            language: "python".into(),
+            code: "print('hello')".into(),
+            ..Default::default()
+        };
+        let responses = [
+            ExecuteResponse {
+                stage: Stage::Running as i32,
+                stdout: Some(b"hello\n".to_vec()),
+                ..Default::default()
+            },
+            ExecuteResponse {
+                stage: Stage::Done as i32,
+                exit_code: Some(0),
+                ..Default::default()
+            },
+        ];
+        let mut recorder =
+            Recorder::create(&dir, "hello-0123abcd", &request, system_clock()).unwrap();
+        for response in &responses {
+            recorder.response(response).unwrap();
+        }
+        let path = recorder.path().to_path_buf();
+        drop(recorder);
+
+        let session = Session::load(&path).unwrap();
+        assert_eq!(session.request, request);
+        assert_eq!(session.records.len(), 2);
+        assert_eq!(session.run_request().language, Language::Python as i32);
+
+        let vms = VmTable::default();
+        let service = VmmService::new(vms.clone(), Default::default());
+        let replayed: Vec<ExecuteResponse> = service
+            .replay(&path, false)
+            .await
+            .unwrap()
+            .map(|message| message.unwrap())
+            .collect()
+            .await;
+        assert_eq!(replayed, responses);
+        // The replayed VM is gone with the end of the run.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!vms.is_running("replay-hello"));
+    }
+
+    #[test]
+    fn test_load_rejects_session_without_request() {
+        let path = scratch_dir("vmm-invalid-session").join("invalid.session");
+        let record = SessionRecord {
+            elapsed_ms: 0,
+            record: Some(Record::Response(ExecuteResponse::default())),
+        };
+        fs::write(&path, record.encode_length_delimited_to_vec()).unwrap();
+        assert!(Session::load(&path).is_err());
+    }
+}
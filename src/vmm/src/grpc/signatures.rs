@@ -0,0 +1,232 @@
+//! Signatures of the kernels and the initramfs images, checked before a guest boots them.
+//!
+//! The signature of an artifact is a minisign signature next to it, `<artifact>.minisig`, made
+//! by `fs-gen --sign-key` for the images, by `fs-gen sign` for the kernels, or by the
+//! orchestrator itself for the artifacts it builds when it is given a signing key. Once public
+//! keys are configured, an artifact without a signature made by one of them is refused: a cache
+//! or a shared storage tampered with can't make the guests boot another image.
+//!
+//! The signatures are fetched with the artifacts from the shared storage, and with the images
+//! from the registry, where `fs-gen push` annotates the artifact with it.
+
+use minisign::{PublicKey, PublicKeyBox, SecretKey, SecretKeyBox, SignatureBox};
+use std::{
+    ffi::OsString,
+    fmt,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Suffix of the signature of an artifact.
+pub const SIGNATURE_SUFFIX: &str = ".minisig";
+
+/// Path of the signature of the artifact at `path`.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name: OsString = path.file_name().unwrap_or_default().to_os_string();
+    name.push(SIGNATURE_SUFFIX);
+    path.with_file_name(name)
+}
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Key(PathBuf, String),
+    /// A signing key is configured without the public keys checking its signatures.
+    NoPublicKey,
+    Missing(PathBuf),
+    Invalid(PathBuf, String),
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Key(path, e) => write!(f, "Invalid minisign key {:?}: {}", path, e),
+            SignatureError::NoPublicKey => write!(
+                f,
+                "artifact-signing-key is set without artifact-public-keys to check its signatures"
+            ),
+            SignatureError::Missing(path) => write!(f, "{:?} isn't signed", path),
+            SignatureError::Invalid(path, e) => {
+                write!(f, "The signature of {:?} is invalid: {}", path, e)
+            }
+            SignatureError::Io(path, e) => write!(f, "Failed to read {:?}: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Keys the signatures of the artifacts are checked with, and the one signing the artifacts
+/// built by the orchestrator, if any.
+#[derive(Clone)]
+pub struct ArtifactSignatures {
+    public_keys: Vec<(PathBuf, PublicKey)>,
+    signing_key: Option<(PathBuf, Arc<SecretKey>)>,
+}
+
+impl fmt::Debug for ArtifactSignatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let public_keys: Vec<String> = self
+            .public_keys
+            .iter()
+            .map(|(path, key)| format!("{:?}={}", path, key.to_base64()))
+            .collect();
+        f.debug_struct("ArtifactSignatures")
+            .field("public_keys", &public_keys)
+            .field(
+                "signing_key",
+                &self.signing_key.as_ref().map(|(path, _)| path),
+            )
+            .finish()
+    }
+}
+
+impl ArtifactSignatures {
+    /// Load the minisign public keys at `public_keys`, and the unencrypted secret key at
+    /// `signing_key`. Returns `None` without public keys: the artifacts aren't checked.
+    pub fn load(
+        public_keys: &[PathBuf],
+        signing_key: Option<&Path>,
+    ) -> Result<Option<Self>, SignatureError> {
+        if public_keys.is_empty() {
+            return match signing_key {
+                Some(_) => Err(SignatureError::NoPublicKey),
+                None => Ok(None),
+            };
+        }
+
+        let public_keys = public_keys
+            .iter()
+            .map(|path| {
+                let key = read_key(path)?;
+                PublicKeyBox::from_string(&key)
+                    .and_then(|key| key.into_public_key())
+                    .map(|key| (path.clone(), key))
+                    .map_err(|e| SignatureError::Key(path.clone(), e.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        let signing_key = signing_key
+            .map(|path| {
+                let key = read_key(path)?;
+                SecretKeyBox::from_string(&key)
+                    .and_then(|key| key.into_unencrypted_secret_key())
+                    .map(|key| (path.to_path_buf(), Arc::new(key)))
+                    .map_err(|e| SignatureError::Key(path.to_path_buf(), e.to_string()))
+            })
+            .transpose()?;
+
+        Ok(Some(Self {
+            public_keys,
+            signing_key,
+        }))
+    }
+
+    /// Check that the artifact at `path` is signed by one of the public keys.
+    pub fn verify(&self, path: &Path) -> Result<(), SignatureError> {
+        let signature_path = signature_path(path);
+        let signature = match fs::read_to_string(&signature_path) {
+            Ok(signature) => signature,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(SignatureError::Missing(path.to_path_buf()))
+            }
+            Err(e) => return Err(SignatureError::Io(signature_path, e)),
+        };
+        let signature = SignatureBox::from_string(&signature)
+            .map_err(|e| SignatureError::Invalid(path.to_path_buf(), e.to_string()))?;
+
+        let mut error = String::new();
+        for (_, key) in &self.public_keys {
+            let artifact =
+                File::open(path).map_err(|e| SignatureError::Io(path.to_path_buf(), e))?;
+            // Legacy signatures sign the artifact itself rather than its hash, which would
+            // require reading it in memory.
+            match minisign::verify(key, &signature, artifact, true, false, false) {
+                Ok(()) => return Ok(()),
+                Err(e) => error = e.to_string(),
+            }
+        }
+        Err(SignatureError::Invalid(path.to_path_buf(), error))
+    }
+
+    /// Sign the artifact at `path`, writing its signature to `signature`, if the orchestrator
+    /// has a signing key. Returns whether the artifact was signed.
+    pub fn sign(&self, path: &Path, signature: &Path) -> Result<bool, SignatureError> {
+        let Some((_, key)) = &self.signing_key else {
+            return Ok(false);
+        };
+        let artifact = File::open(path).map_err(|e| SignatureError::Io(path.to_path_buf(), e))?;
+        let signature_box = minisign::sign(None, key, artifact, None, None)
+            .map_err(|e| SignatureError::Invalid(path.to_path_buf(), e.to_string()))?;
+        fs::write(signature, signature_box.to_string())
+            .map_err(|e| SignatureError::Io(signature.to_path_buf(), e))?;
+        Ok(true)
+    }
+}
+
+fn read_key(path: &Path) -> Result<String, SignatureError> {
+    fs::read_to_string(path).map_err(|e| SignatureError::Io(path.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use minisign::KeyPair;
+
+    /// Write a new key pair in `dir`, returning the paths of its public and secret keys.
+    fn key_pair(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let KeyPair { pk, sk } = KeyPair::generate_unencrypted_keypair().unwrap();
+        let (public, secret) = (dir.join(format!("{}.pub", name)), dir.join(name));
+        fs::write(&public, pk.to_box().unwrap().to_string()).unwrap();
+        fs::write(&secret, sk.to_box(None).unwrap().to_string()).unwrap();
+        (public, secret)
+    }
+
+    #[test]
+    fn test_signatures() {
+        let dir = std::env::temp_dir().join(format!("vmm-signatures-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (public, secret) = key_pair(&dir, "cloudlet");
+        let (other_public, other_secret) = key_pair(&dir, "other");
+        let artifact = dir.join("python.img");
+        fs::write(&artifact, b"initramfs").unwrap();
+
+        let signatures = ArtifactSignatures::load(&[public.clone()], Some(&secret))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            signatures.verify(&artifact),
+            Err(SignatureError::Missing(_))
+        ));
+        assert!(signatures
+            .sign(&artifact, &signature_path(&artifact))
+            .unwrap());
+        signatures.verify(&artifact).unwrap();
+
+        fs::write(&artifact, b"tampered").unwrap();
+        assert!(matches!(
+            signatures.verify(&artifact),
+            Err(SignatureError::Invalid(..))
+        ));
+
+        // Signed by a key which isn't trusted, then trusted along the first one.
+        fs::write(&artifact, b"initramfs").unwrap();
+        let other = ArtifactSignatures::load(&[other_public.clone()], Some(&other_secret))
+            .unwrap()
+            .unwrap();
+        other.sign(&artifact, &signature_path(&artifact)).unwrap();
+        assert!(signatures.verify(&artifact).is_err());
+        ArtifactSignatures::load(&[public, other_public], None)
+            .unwrap()
+            .unwrap()
+            .verify(&artifact)
+            .unwrap();
+
+        assert!(ArtifactSignatures::load(&[], None).unwrap().is_none());
+        assert!(matches!(
+            ArtifactSignatures::load(&[], Some(&secret)),
+            Err(SignatureError::NoPublicKey)
+        ));
+    }
+}
@@ -0,0 +1,716 @@
+//! Startup of the orchestrator: the options of `vmm grpc`, and the service, the janitor and the
+//! background tasks built from them. Shared by the `vmm` binary and the all-in-one mode of the
+//! server, which flattens the same options.
+
+use super::{
+    admin::VmTable,
+    builds::BuildCache,
+    config::{ConfigError, OrchestratorConfig, Settings},
+    console::ConsoleLogConfig,
+    deadline::DEFAULT_MAX_RUN_SECS,
+    deterministic::{system_clock, Deterministic},
+    faults::{self, Faults},
+    health,
+    hypervisor::{CloudHypervisorConfig, EmulatorConfig, Hypervisor, DEFAULT_QEMU},
+    janitor::{Janitor, JanitorConfig},
+    maintenance::Maintenance,
+    package_cache::{PackageCache, PackageCacheConfig, Upstreams},
+    pool::PoolConfig,
+    registry::{RootfsPin, RootfsRegistry},
+    retries::DEFAULT_INFRA_RETRIES,
+    runs::RunStore,
+    server::{PmemConfig, VmmService, VmmServiceConfig},
+    storage::{self, S3Config},
+    validate,
+    workloads::WorkloadStore,
+};
+use crate::core::{
+    cpu_template::CpuTemplate,
+    memory::{GuestMemoryConfig, HugePages},
+    placement::CpuPolicy,
+    rate_limiter::{
+        IoLimits, DEFAULT_DISK_FLUSHES_PER_SEC, DEFAULT_NET_BYTES_PER_SEC,
+        DEFAULT_NET_PACKETS_PER_SEC,
+    },
+    vfio::{VfPool, VfioDevice},
+    vmm::host_supports_nested,
+};
+use shared_models::{
+    validation::{Findings, Severity},
+    vmmorchestrator,
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tracing::{error, info, warn};
+
+/// Options of the orchestrator, without those of its gRPC server.
+#[derive(clap::Args, Debug, Clone)]
+pub struct OrchestratorArgs {
+    /// TOML configuration file overriding some of these options (the listen address, the log
+    /// level, the kernels, the admission policy, the scheduler, the output caps and the rootfs
+    /// pins), reloaded when it changes. See the config module.
+    #[arg(long, env)]
+    pub config: Option<PathBuf>,
+
+    /// Let the clients open shells in the running guests, `cli vm shell`. For debugging only:
+    /// anyone who can reach the orchestrator gets a root shell in the guests.
+    #[arg(long, env)]
+    pub enable_exec_shell: bool,
+
+    /// Give the guests of each tenant a bridge and a /24 of their own, `cltn<n>` and
+    /// `172.29.<n>.0/24`, and drop the traffic between the networks of different tenants.
+    /// Only supported by the built-in VMM.
+    #[arg(long, env, conflicts_with = "cloud_hypervisor")]
+    pub isolate_tenants: bool,
+
+    /// Bytes per second each guest can send over its network interface, and receive. The
+    /// requests can set lower limits. 0 for no limit.
+    #[arg(long, env, default_value_t = DEFAULT_NET_BYTES_PER_SEC)]
+    pub net_bytes_per_sec: u64,
+
+    /// Frames per second each guest can send, and receive. 0 for no limit.
+    #[arg(long, env, default_value_t = DEFAULT_NET_PACKETS_PER_SEC)]
+    pub net_packets_per_sec: u64,
+
+    /// Flushes of its disk per second each guest can make, each an fsync of the backing file on
+    /// the host. Not enforced by cloud-hypervisor. 0 for no limit.
+    #[arg(long, env, default_value_t = DEFAULT_DISK_FLUSHES_PER_SEC)]
+    pub disk_flushes_per_sec: u64,
+
+    /// Times a run failing on an infrastructure error, e.g. a VM which didn't boot or an agent
+    /// which couldn't be reached, is started again on a fresh VM. The failures of the workloads
+    /// themselves are never retried.
+    #[arg(long, env, default_value_t = DEFAULT_INFRA_RETRIES)]
+    pub infra_retries: u32,
+
+    /// Seconds a run may take from the boot of its VM, also the longest timeout the requests
+    /// can set. Past it, the VM is destroyed without relying on its agent. 0 for no limit.
+    #[arg(long, env, default_value_t = DEFAULT_MAX_RUN_SECS)]
+    pub max_run_duration: u64,
+
+    /// Port the agents listen on in the guests, passed to their init on the kernel command line.
+    #[arg(long, env)]
+    pub agent_port: Option<u16>,
+
+    /// Start the guests with this cloud-hypervisor binary, driven through its REST API, instead of
+    /// the built-in VMM. The guest kernel needs virtio-pci and PVH boot support.
+    #[arg(long, env)]
+    pub cloud_hypervisor: Option<PathBuf>,
+
+    /// Emulate the guests with QEMU, without KVM, when /dev/kvm can't be opened, e.g. on a
+    /// machine without nested virtualization, rather than serving no run. Much slower, for
+    /// development: the runs are labeled as emulated.
+    #[arg(long, env)]
+    pub emulation_fallback: bool,
+
+    /// QEMU binary emulating the guests with `--emulation-fallback`.
+    #[arg(long, env, default_value = DEFAULT_QEMU)]
+    pub qemu: PathBuf,
+
+    /// Placement of the guest vCPUs on the host CPUs: `none`, `spread` (least loaded NUMA node),
+    /// `pack` (fill a NUMA node first) or an explicit CPU list such as `0-3,8`.
+    #[arg(long, env, default_value = "none")]
+    pub cpu_policy: CpuPolicy,
+
+    /// Back the guest memory with 2 MB huge pages: `off`, `on` (fall back on regular pages
+    /// when not enough huge pages are reserved) or `strict`.
+    #[arg(long, env, default_value = "off")]
+    pub huge_pages: HugePages,
+
+    /// Allocate the whole guest memory when the VM starts, instead of on first access.
+    #[arg(long, env)]
+    pub prealloc_memory: bool,
+
+    /// Give each guest a virtio-pmem scratch device backed by a sparse file created in this
+    /// directory. Its pages live in the host page cache and can be reclaimed by the host.
+    #[arg(long, env)]
+    pub pmem_dir: Option<PathBuf>,
+
+    /// Size (in MB) of the virtio-pmem device of each guest.
+    #[arg(long, env, default_value_t = 1024)]
+    pub pmem_size_mb: u32,
+
+    /// Keep the virtio-pmem backing files once the guests stop.
+    #[arg(long, env)]
+    pub pmem_keep: bool,
+
+    /// PCI address of a host GPU bound to vfio-pci, which can be passed through to guests
+    /// requesting a `gpu` device. Can be repeated.
+    #[arg(long = "gpu", env = "GPUS", value_delimiter = ',')]
+    pub gpus: Vec<String>,
+
+    /// Host interface the guests reach the outside world through, e.g. `eth1`, their traffic to
+    /// any other interface being dropped. Any interface the host routes to by default.
+    #[arg(long, env)]
+    pub uplink: Option<String>,
+
+    /// PCI address of an SR-IOV virtual function of a host NIC bound to vfio-pci, which can be
+    /// passed through to guests requesting a `sriov-nic` device. Needs `--cloud-hypervisor`.
+    /// Can be repeated.
+    #[arg(long = "sriov-vf", env = "SRIOV_VFS", value_delimiter = ',')]
+    pub sriov_vfs: Vec<String>,
+
+    /// Label of the node, as `KEY=VALUE`, matched by the node affinity of the runs, e.g.
+    /// `gpu=a100`. Can be repeated.
+    #[arg(
+        long = "node-label",
+        env = "NODE_LABELS",
+        value_delimiter = ',',
+        value_parser = shared_models::labels::parse_label
+    )]
+    pub node_labels: Vec<(String, String)>,
+
+    /// Expose the virtualization extensions (VMX/SVM) to the guests, so that they can run
+    /// their own VMs. Requires nested virtualization to be enabled in the host KVM module.
+    #[arg(long, env)]
+    pub nested_virt: bool,
+
+    /// CPUID presented to the guests: `host`, `baseline` (the features common to the x86-64
+    /// hosts of the last decade) or the path of a custom template, in the JSON format of
+    /// Firecracker.
+    #[arg(long, env, default_value = "host")]
+    pub cpu_template: CpuTemplate,
+
+    /// TOML file registering the kernels the guests can boot, besides the built-in one.
+    #[arg(long, env)]
+    pub kernels: Option<PathBuf>,
+
+    /// TOML file of the admission policy the requests must comply with, see the admission module.
+    #[arg(long, env)]
+    pub admission_policy: Option<PathBuf>,
+
+    /// TOML file capping the guests the host runs and defining their priority classes, see the
+    /// scheduler module. The host runs any number of guests otherwise.
+    #[arg(long, env)]
+    pub scheduler: Option<PathBuf>,
+
+    /// Registry and namespace of the prebuilt rootfs images, e.g. `registry.example.com/cloudlet`.
+    /// The image of a language is pulled from `<registry>/<language>:<version>` before building it locally.
+    #[arg(long, env)]
+    pub rootfs_registry: Option<String>,
+
+    /// Digest of the rootfs artifact to use for a language, as `LANGUAGE[:VERSION]@sha256:DIGEST`,
+    /// instead of the one the tag points to. Can be repeated.
+    #[arg(long = "rootfs-pin", env = "ROOTFS_PINS", value_delimiter = ',')]
+    pub rootfs_pins: Vec<RootfsPin>,
+
+    /// Minisign public key the kernels and the rootfs images must be signed with before the
+    /// guests boot them, see the signatures module. Can be repeated, to rotate the keys.
+    #[arg(
+        long = "artifact-public-key",
+        env = "ARTIFACT_PUBLIC_KEYS",
+        value_delimiter = ','
+    )]
+    pub artifact_public_keys: Vec<PathBuf>,
+
+    /// Unencrypted minisign secret key signing the artifacts this orchestrator builds itself,
+    /// which are refused otherwise once public keys are set.
+    #[arg(long, env, requires = "artifact_public_keys")]
+    pub artifact_signing_key: Option<PathBuf>,
+
+    /// User to authenticate to the rootfs registry.
+    #[arg(long, env, requires = "rootfs_registry_password")]
+    pub rootfs_registry_username: Option<String>,
+
+    /// Password of the rootfs registry user.
+    #[arg(
+        long,
+        env,
+        hide_env_values = true,
+        requires = "rootfs_registry_username"
+    )]
+    pub rootfs_registry_password: Option<String>,
+
+    /// Maximum output (stdout and stderr, build and run) streamed back by each workload, in bytes.
+    /// 0 leaves it to the agent (16 MiB).
+    #[arg(long, env, default_value_t = 0)]
+    pub max_output_bytes: u64,
+
+    /// Maximum number of output lines streamed back by each workload. 0 leaves it to the agent (100000).
+    #[arg(long, env, default_value_t = 0)]
+    pub max_output_lines: u64,
+
+    /// Record the inputs of each run in this directory or `s3://BUCKET[/PREFIX]`, so that it can
+    /// be run again with `cli rerun`. The records hold the code and the environment of the runs,
+    /// secrets included.
+    #[arg(long, env)]
+    pub runs_dir: Option<String>,
+
+    /// Record the exchange with the agent of each run in this directory, to replay it later with
+    /// `vmm replay`. Like the run inputs, the sessions hold the code and the environment of the
+    /// runs, secrets included.
+    #[arg(long, env)]
+    pub record_sessions: Option<PathBuf>,
+
+    /// Run in the deterministic mode, for the integration tests: the VM ids and the faults are
+    /// drawn from SEED, the clock stands still at 2024-01-01 and the guests get the addresses
+    /// of a fake IPAM, `198.18.<n>.0/24`, which they can't be reached at. Not for production.
+    #[arg(long, env, value_name = "SEED")]
+    pub deterministic: Option<u64>,
+
+    /// Write the console of each VM to `<DIR>/<VM ID>.log` rather than to the standard output,
+    /// to read it once the VM stopped.
+    #[arg(long, env)]
+    pub console_log_dir: Option<PathBuf>,
+
+    /// Size in MB past which the console log of a VM is rotated.
+    #[arg(long, env, default_value_t = 10)]
+    pub console_log_max_size_mb: u64,
+
+    /// Rotated console logs kept per VM, besides the current one.
+    #[arg(long, env, default_value_t = 3)]
+    pub console_log_max_files: u32,
+
+    /// Remove the console logs which haven't been written to for this many hours.
+    #[arg(long, env, default_value_t = 168)]
+    pub console_log_max_age: u64,
+
+    /// Run a caching proxy of crates.io, PyPI and npm, keeping what it fetched in this directory,
+    /// and point the package managers of the guests to it, so that the dependencies of the
+    /// workloads are fetched from the host.
+    #[arg(long, env)]
+    pub package_cache_dir: Option<PathBuf>,
+
+    /// Address of the package cache, which the guests reach on the host side of their network,
+    /// that of the `br0` bridge by default. With `--isolate-tenants`, the guests of the tenant
+    /// networks only reach it on an address of all the bridges, e.g. `0.0.0.0:3142`.
+    #[arg(long, env, default_value = "172.29.0.1:3142")]
+    pub package_cache_listen: SocketAddr,
+
+    /// Seconds the package cache serves an index before fetching it again, the packages
+    /// themselves never changing.
+    #[arg(long, env, default_value_t = 300)]
+    pub package_cache_ttl: u64,
+
+    /// Size in MB past which the least recently used packages are evicted from the cache.
+    #[arg(long, env, default_value_t = 10240)]
+    pub package_cache_max_size_mb: u64,
+
+    /// Cache the workloads built by the runs in this directory or `s3://BUCKET[/PREFIX]`, so that
+    /// the later runs of the same code and build options, e.g. after `cli build`, skip their build.
+    #[arg(long, env)]
+    pub builds_dir: Option<String>,
+
+    /// Keep the workloads registered with `cli register` in this directory or
+    /// `s3://BUCKET[/PREFIX]`, so that `cli invoke` runs them with only their inputs. Like the
+    /// recorded runs, they hold the environment of the workloads, secrets included.
+    #[arg(long, env)]
+    pub workloads_dir: Option<String>,
+
+    /// VMs kept warm for each registered workload while it is invoked, unless it was registered
+    /// with its own minimum.
+    #[arg(long, env, default_value_t = 0)]
+    pub min_instances: u32,
+
+    /// VMs running each registered workload at most, unless it was registered with its own
+    /// maximum: the invocations which find them all busy wait for one of them.
+    #[arg(long, env, default_value_t = 4)]
+    pub max_instances: u32,
+
+    /// Stop the idle VMs of the registered workloads beyond the invocations in flight once they
+    /// have been idle for this many seconds.
+    #[arg(long, env, default_value_t = 300)]
+    pub idle_timeout: u64,
+
+    /// Stop all the VMs of a registered workload, even below its minimum, once it hasn't been
+    /// invoked for this many seconds.
+    #[arg(long, env, default_value_t = 900)]
+    pub idle_window: u64,
+
+    /// URL notified with a JSON payload at the end of every run, besides the webhooks of the run
+    /// requests, see the webhooks module. Can be repeated.
+    #[arg(long = "webhook", env = "WEBHOOKS", value_delimiter = ',')]
+    pub webhooks: Vec<String>,
+
+    /// Key of the HMAC-SHA256 signature of the payloads sent to the `--webhook` URLs, which are
+    /// sent unsigned without one.
+    #[arg(long, env, hide_env_values = true)]
+    pub webhook_secret: Option<String>,
+
+    /// Host, as in the webhook URLs, which may be notified though it is a loopback, private or
+    /// link-local address or resolves to one, e.g. a CI of the internal network. Can be repeated.
+    #[arg(
+        long = "webhook-internal-host",
+        env = "WEBHOOK_INTERNAL_HOSTS",
+        value_delimiter = ','
+    )]
+    pub webhook_internal_hosts: Vec<String>,
+
+    /// Storage shared by the orchestrators of a deployment, a directory or `s3://BUCKET[/PREFIX]`.
+    /// The kernel and rootfs images are fetched from it instead of being built, and the logs of
+    /// the runs are kept in it.
+    #[arg(long, env)]
+    pub storage: Option<String>,
+
+    /// URL of the S3-compatible object store, e.g. `http://localhost:9000`. Defaults to the AWS
+    /// endpoint of the region.
+    #[arg(long, env)]
+    pub s3_endpoint: Option<String>,
+
+    /// Region of the S3 buckets.
+    #[arg(long, env, default_value = "us-east-1")]
+    pub s3_region: String,
+
+    /// Access key id to sign the S3 requests with, they are sent anonymously without one.
+    #[arg(long, env = "AWS_ACCESS_KEY_ID", requires = "s3_secret_access_key")]
+    pub s3_access_key_id: Option<String>,
+
+    /// Secret key of the S3 access key.
+    #[arg(
+        long,
+        env = "AWS_SECRET_ACCESS_KEY",
+        hide_env_values = true,
+        requires = "s3_access_key_id"
+    )]
+    pub s3_secret_access_key: Option<String>,
+}
+
+impl OrchestratorArgs {
+    /// The options overridden by the configuration file, with the address the gRPC server
+    /// listens on.
+    pub fn options(
+        &self,
+        listen: Option<SocketAddr>,
+        unix_socket: Option<PathBuf>,
+    ) -> OrchestratorConfig {
+        OrchestratorConfig {
+            listen,
+            unix_socket,
+            agent_port: self.agent_port,
+            log_level: None,
+            kernels: self.kernels.clone(),
+            admission_policy: self.admission_policy.clone(),
+            scheduler: self.scheduler.clone(),
+            max_output_bytes: Some(self.max_output_bytes),
+            max_output_lines: Some(self.max_output_lines),
+            rootfs_pins: Some(self.rootfs_pins.clone()),
+            artifact_public_keys: Some(self.artifact_public_keys.clone()),
+            artifact_signing_key: self.artifact_signing_key.clone(),
+        }
+    }
+
+    /// The options overridden by the configuration file, if any.
+    pub fn load_config(
+        &self,
+        options: &OrchestratorConfig,
+    ) -> Result<OrchestratorConfig, ConfigError> {
+        Ok(match &self.config {
+            Some(path) => OrchestratorConfig::load(path)?.or(options),
+            None => options.clone(),
+        })
+    }
+}
+
+/// The orchestrator started by [`start`], to serve.
+pub struct Orchestrator {
+    pub service: Arc<VmmService>,
+    pub vms: VmTable,
+    pub janitor: Arc<Janitor>,
+    pub faults: Arc<Faults>,
+    pub maintenance: Arc<Maintenance>,
+}
+
+/// Build the service from the options and the loaded configuration, and spawn the janitor, the
+/// package cache and the background tasks of the service.
+pub async fn start(
+    args: &OrchestratorArgs,
+    config: &OrchestratorConfig,
+    settings: &Settings,
+) -> Result<Orchestrator, Box<dyn std::error::Error>> {
+    let hypervisor = match args.cloud_hypervisor.clone() {
+        _ if args.emulation_fallback && health::check_kvm().is_err() => {
+            warn!(
+                qemu = ?args.qemu,
+                "KVM is unavailable, emulating the guests with QEMU: the runs are much slower"
+            );
+            Hypervisor::Emulated(EmulatorConfig {
+                binary: args.qemu.clone(),
+            })
+        }
+        Some(binary) => {
+            info!(binary = ?binary, "Starting the guests with cloud-hypervisor");
+            Hypervisor::CloudHypervisor(CloudHypervisorConfig {
+                binary,
+                socket_dir: std::env::temp_dir(),
+            })
+        }
+        None => Hypervisor::Builtin,
+    };
+
+    let deterministic = args.deterministic.map(Deterministic::new);
+    let vms = match &deterministic {
+        Some(mode) => {
+            warn!(
+                seed = mode.seed,
+                "Running in the deterministic mode, do not use it in production"
+            );
+            VmTable::deterministic(mode)
+        }
+        None => VmTable::default(),
+    };
+    let janitor = Arc::new(Janitor::new(
+        std::env::current_dir()?,
+        vms.clone(),
+        JanitorConfig::default(),
+    ));
+    tokio::spawn(janitor.clone().run());
+
+    let gpus: Vec<VfioDevice> = args
+        .gpus
+        .iter()
+        .filter_map(|bdf| {
+            match VfioDevice::probe(bdf).and_then(|gpu| {
+                gpu.reset()?;
+                Ok(gpu)
+            }) {
+                Ok(gpu) => {
+                    info!(bdf, iommu_group = gpu.iommu_group, "GPU available");
+                    Some(gpu)
+                }
+                Err(e) => {
+                    warn!(bdf, error = %e, "Ignoring GPU");
+                    None
+                }
+            }
+        })
+        .collect();
+
+    let sriov_vfs: Vec<VfioDevice> = args
+        .sriov_vfs
+        .iter()
+        .filter_map(|bdf| {
+            match VfioDevice::probe_virtual_function(bdf).and_then(|vf| {
+                vf.reset()?;
+                Ok(vf)
+            }) {
+                Ok(vf) => {
+                    info!(
+                        bdf,
+                        iommu_group = vf.iommu_group,
+                        "SR-IOV virtual function available"
+                    );
+                    Some(vf)
+                }
+                Err(e) => {
+                    warn!(bdf, error = %e, "Ignoring SR-IOV virtual function");
+                    None
+                }
+            }
+        })
+        .collect();
+
+    if let Some(uplink) = &args.uplink {
+        let mut findings = Findings::default();
+        validate::check_uplink(uplink, &mut findings);
+        for finding in &findings.findings {
+            match finding.severity {
+                Severity::Ok => info!("{}", finding.message),
+                Severity::Warning => warn!("{}", finding.message),
+                Severity::Error => error!("{}", finding.message),
+            }
+        }
+        if findings.errors() > 0 {
+            return Err(format!("Invalid --uplink {}", uplink).into());
+        }
+    }
+
+    if args.nested_virt && !host_supports_nested() {
+        warn!("Nested virtualization is disabled in the host KVM module, guests won't see VMX/SVM");
+    }
+
+    if settings.signatures.is_some() {
+        info!("Checking the signatures of the kernels and the rootfs images");
+    }
+    for kernel in settings.kernels.kernels() {
+        info!(name = %kernel.name, version = %kernel.version, "Kernel available");
+    }
+
+    let rootfs_registry = args.rootfs_registry.as_deref().map(|location| {
+        info!(
+            registry = location,
+            "Pulling the rootfs images from a registry"
+        );
+        RootfsRegistry::new(
+            location,
+            settings.rootfs_pins.clone(),
+            args.rootfs_registry_username
+                .clone()
+                .zip(args.rootfs_registry_password.clone()),
+        )
+    });
+
+    if let Some(dir) = &args.pmem_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let s3 = S3Config {
+        endpoint: args.s3_endpoint.clone(),
+        region: args.s3_region.clone(),
+        credentials: args
+            .s3_access_key_id
+            .clone()
+            .zip(args.s3_secret_access_key.clone()),
+    };
+    if let Some(dir) = &args.record_sessions {
+        info!(dir = ?dir, "Recording the sessions with the agents");
+        std::fs::create_dir_all(dir)?;
+    }
+    let runs = match &args.runs_dir {
+        Some(location) => {
+            info!(location, "Recording the inputs of the runs");
+            Some(RunStore::new(storage::open(location, &s3)?))
+        }
+        None => None,
+    };
+    let builds = match &args.builds_dir {
+        Some(location) => {
+            info!(location, "Caching the builds of the workloads");
+            Some(BuildCache::new(storage::open(location, &s3)?))
+        }
+        None => None,
+    };
+    let workloads = match &args.workloads_dir {
+        Some(location) => {
+            info!(location, "Keeping the registered workloads");
+            Some(WorkloadStore::new(storage::open(location, &s3)?))
+        }
+        None => None,
+    };
+    let storage = match &args.storage {
+        Some(location) => {
+            info!(
+                location,
+                "Sharing the images and the logs through a storage"
+            );
+            Some(storage::open(location, &s3)?)
+        }
+        None => None,
+    };
+
+    if let Some(path) = &config.admission_policy {
+        info!(path = ?path, "Enforcing an admission policy");
+    }
+    for class in &settings.scheduler.classes {
+        info!(name = %class.name, priority = class.priority, preemptible = class.preemptible, "Priority class");
+    }
+
+    if args.isolate_tenants {
+        info!("Isolating the networks of the tenants");
+    }
+    if args.cpu_template != CpuTemplate::Host {
+        info!(template = %args.cpu_template, "Masking the CPUID of the guests");
+    }
+
+    let faults = Arc::new(match &deterministic {
+        Some(mode) => Faults::seeded(mode.seed),
+        None => Faults::default(),
+    });
+    if faults::ENABLED {
+        warn!("Built with the fault-injection feature, do not use this build in production");
+    }
+
+    let maintenance = Arc::new(Maintenance::new(
+        deterministic
+            .as_ref()
+            .map_or_else(system_clock, Deterministic::clock),
+    ));
+
+    let package_cache_port = match args.package_cache_dir.clone() {
+        Some(dir) => {
+            let cache = PackageCache::bind(PackageCacheConfig {
+                dir: dir.clone(),
+                listen: args.package_cache_listen,
+                ttl: Duration::from_secs(args.package_cache_ttl),
+                max_size: args.package_cache_max_size_mb << 20,
+                upstreams: Upstreams::default(),
+            })
+            .await?;
+            info!(dir = ?dir, listen = %args.package_cache_listen, "Caching the package registries");
+            let port = cache.port()?;
+            tokio::spawn(cache.serve());
+            Some(port)
+        }
+        None => None,
+    };
+
+    let service = Arc::new(VmmService::new(
+        vms.clone(),
+        VmmServiceConfig {
+            cpu_policy: args.cpu_policy.clone(),
+            memory: GuestMemoryConfig {
+                huge_pages: args.huge_pages,
+                prealloc: args.prealloc_memory,
+            },
+            pmem: args.pmem_dir.clone().map(|dir| PmemConfig {
+                dir,
+                size_mb: args.pmem_size_mb,
+                keep: args.pmem_keep,
+            }),
+            gpus,
+            uplink: args.uplink.clone(),
+            sriov_vfs: VfPool::new(sriov_vfs),
+            node_labels: args.node_labels.iter().cloned().collect(),
+            nested_virtualization: args.nested_virt,
+            cpu_template: args.cpu_template.clone(),
+            kernels: settings.kernels.clone(),
+            signatures: settings.signatures.clone(),
+            rootfs_registry,
+            output_limits: settings.output_limits.clone(),
+            runs,
+            builds,
+            workloads,
+            pool: PoolConfig {
+                min_instances: args.min_instances as usize,
+                max_instances: args.max_instances.max(1) as usize,
+                idle_timeout: Duration::from_secs(args.idle_timeout),
+                idle_window: Duration::from_secs(args.idle_window),
+            },
+            storage,
+            webhooks: args
+                .webhooks
+                .iter()
+                .map(|url| vmmorchestrator::Webhook {
+                    url: url.clone(),
+                    secret: args.webhook_secret.clone().unwrap_or_default(),
+                })
+                .collect(),
+            webhook_internal_hosts: args.webhook_internal_hosts.clone(),
+            hypervisor,
+            admission: settings.admission.clone(),
+            scheduler: settings.scheduler.clone(),
+            faults: faults.clone(),
+            maintenance: maintenance.clone(),
+            agent_port: config.agent_port.unwrap_or_default(),
+            exec_shell: args.enable_exec_shell,
+            sessions: args.record_sessions.clone(),
+            package_cache_port,
+            console_logs: args.console_log_dir.clone().map(|dir| ConsoleLogConfig {
+                dir,
+                max_size: args.console_log_max_size_mb << 20,
+                max_files: args.console_log_max_files,
+                max_age: Duration::from_secs(args.console_log_max_age * 60 * 60),
+            }),
+            isolate_tenants: args.isolate_tenants,
+            io_limits: IoLimits {
+                net_bytes_per_sec: args.net_bytes_per_sec,
+                net_packets_per_sec: args.net_packets_per_sec,
+                disk_flushes_per_sec: args.disk_flushes_per_sec,
+            },
+            infra_retries: args.infra_retries,
+            settings_snapshot: settings.snapshot(),
+            deterministic,
+            max_run_duration: (args.max_run_duration > 0)
+                .then(|| Duration::from_secs(args.max_run_duration)),
+        },
+    ));
+
+    tokio::spawn(service.clone().autoscale());
+    tokio::spawn(service.clone().track_runs());
+
+    Ok(Orchestrator {
+        service,
+        vms,
+        janitor,
+        faults,
+        maintenance,
+    })
+}
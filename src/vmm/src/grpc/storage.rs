@@ -0,0 +1,378 @@
+//! Storage shared by the orchestrators of a deployment: the kernel and initramfs images
+//! they build, the output of the runs and the recorded run inputs.
+//!
+//! A storage is either a local directory, or a bucket of an S3-compatible object store
+//! given as `s3://<bucket>[/<prefix>]`. Objects are addressed by keys such as
+//! `rootfs/python.img`, which map to paths under the directory or to objects under the
+//! prefix of the bucket.
+
+use hmac::{Hmac, Mac};
+use reqwest::{
+    blocking::{Body, Client, RequestBuilder},
+    Method, StatusCode, Url,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Hash of an empty payload, signed for the requests without a body.
+const EMPTY_PAYLOAD_SHA256: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+#[derive(Debug)]
+pub enum StorageError {
+    InvalidLocation(String),
+    Request(reqwest::Error),
+    Status(String, StatusCode),
+    Io(io::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::InvalidLocation(location) => write!(
+                f,
+                "Invalid storage {}, expected a directory or s3://BUCKET[/PREFIX]",
+                location
+            ),
+            StorageError::Request(e) => write!(f, "Storage request failed: {}", e),
+            StorageError::Status(url, status) => write!(f, "{} answered {}", url, status),
+            StorageError::Io(e) => write!(f, "Storage I/O failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<reqwest::Error> for StorageError {
+    fn from(e: reqwest::Error) -> Self {
+        StorageError::Request(e)
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+/// Objects addressed by key. The calls block, use them from blocking tasks.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Copy the object `key` to `path`. Returns `false` if there is no such object.
+    fn fetch(&self, key: &str, path: &Path) -> Result<bool, StorageError>;
+
+    /// Copy the file at `path` to the object `key`, replacing it.
+    fn store(&self, key: &str, path: &Path) -> Result<(), StorageError>;
+
+    /// Content of the object `key`, `None` if there is no such object.
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Set the content of the object `key`, replacing it.
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), StorageError>;
+}
+
+/// Settings of the S3-compatible object stores.
+#[derive(Debug, Clone, Default)]
+pub struct S3Config {
+    /// URL of the object store, e.g. `http://localhost:9000` for MinIO, the AWS endpoint of
+    /// `region` if unset.
+    pub endpoint: Option<String>,
+    pub region: String,
+    /// Access key id and secret key, anonymous requests are sent without them.
+    pub credentials: Option<(String, String)>,
+}
+
+/// Open the storage at `location`, a directory or `s3://<bucket>[/<prefix>]`.
+pub fn open(location: &str, s3: &S3Config) -> Result<Arc<dyn Storage>, StorageError> {
+    let Some(bucket) = location.strip_prefix("s3://") else {
+        if location.is_empty() {
+            return Err(StorageError::InvalidLocation(location.to_string()));
+        }
+        return Ok(Arc::new(LocalStorage::new(PathBuf::from(location))?));
+    };
+
+    let bucket = bucket.trim_end_matches('/');
+    let (bucket, prefix) = bucket.split_once('/').unwrap_or((bucket, ""));
+    if bucket.is_empty() {
+        return Err(StorageError::InvalidLocation(location.to_string()));
+    }
+
+    Ok(Arc::new(S3Storage::new(bucket, prefix, s3)?))
+}
+
+/// Objects stored as the files of a directory, only readable by the orchestrator user.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    /// Write an object through a temporary file, so that readers never see a partial one.
+    fn replace(
+        &self,
+        key: &str,
+        write: impl FnOnce(&mut File) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let path = self.path(key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".tmp-{}", std::process::id()));
+        let tmp_path = path.with_file_name(name);
+
+        let result = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .and_then(|mut file| {
+                write(&mut file)?;
+                file.sync_all()
+            });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        fs::rename(&tmp_path, &path)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn fetch(&self, key: &str, path: &Path) -> Result<bool, StorageError> {
+        match fs::copy(self.path(key), path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn store(&self, key: &str, path: &Path) -> Result<(), StorageError> {
+        let mut source = File::open(path)?;
+        Ok(self.replace(key, |file| io::copy(&mut source, file).map(|_| ()))?)
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        match fs::read(self.path(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), StorageError> {
+        Ok(self.replace(key, |file| file.write_all(data))?)
+    }
+}
+
+/// Objects of a bucket of an S3-compatible object store, addressed with path-style URLs
+/// and signed with AWS Signature Version 4.
+#[derive(Debug)]
+pub struct S3Storage {
+    client: Client,
+    endpoint: Url,
+    bucket: String,
+    prefix: String,
+    region: String,
+    credentials: Option<(String, String)>,
+}
+
+impl S3Storage {
+    pub fn new(bucket: &str, prefix: &str, config: &S3Config) -> Result<Self, StorageError> {
+        let endpoint = config
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", config.region));
+        let endpoint =
+            Url::parse(&endpoint).map_err(|_| StorageError::InvalidLocation(endpoint.clone()))?;
+
+        Ok(Self {
+            client: Client::builder().timeout(None).build()?,
+            endpoint,
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            region: config.region.clone(),
+            credentials: config.credentials.clone(),
+        })
+    }
+
+    /// Path of the object `key`, URI-encoded as signed.
+    fn object_path(&self, key: &str) -> String {
+        let mut path = format!("/{}/", uri_encode(&self.bucket));
+        if !self.prefix.is_empty() {
+            path.push_str(
+                &self
+                    .prefix
+                    .split('/')
+                    .map(uri_encode)
+                    .collect::<Vec<_>>()
+                    .join("/"),
+            );
+            path.push('/');
+        }
+        path.push_str(&key.split('/').map(uri_encode).collect::<Vec<_>>().join("/"));
+        path
+    }
+
+    fn request(&self, method: Method, key: &str, payload_sha256: &str) -> (String, RequestBuilder) {
+        let path = self.object_path(key);
+        let url = format!("{}{}", self.endpoint.as_str().trim_end_matches('/'), path);
+        let mut request = self.client.request(method.clone(), &url);
+
+        if let Some((access_key, secret_key)) = &self.credentials {
+            let (date, timestamp) = amz_date(SystemTime::now());
+            let host = match self.endpoint.port() {
+                Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap_or_default(), port),
+                None => self.endpoint.host_str().unwrap_or_default().to_string(),
+            };
+            let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+            let canonical_request = format!(
+                "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+                method, path, host, payload_sha256, timestamp, signed_headers, payload_sha256
+            );
+            let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+            let string_to_sign = format!(
+                "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+                timestamp,
+                scope,
+                Sha256::digest(canonical_request)
+            );
+
+            let key = [date.as_str(), self.region.as_str(), "s3", "aws4_request"]
+                .iter()
+                .fold(format!("AWS4{}", secret_key).into_bytes(), |key, part| {
+                    hmac_sha256(&key, part.as_bytes())
+                });
+            let signature = hmac_sha256(&key, string_to_sign.as_bytes())
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>();
+
+            request = request.header("x-amz-date", timestamp).header(
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    access_key, scope, signed_headers, signature
+                ),
+            );
+        }
+
+        (url, request.header("x-amz-content-sha256", payload_sha256))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<reqwest::blocking::Response>, StorageError> {
+        let (url, request) = self.request(Method::GET, key, EMPTY_PAYLOAD_SHA256);
+        let response = request.send()?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response)),
+            status => Err(StorageError::Status(url, status)),
+        }
+    }
+
+    fn put(&self, key: &str, body: Body) -> Result<(), StorageError> {
+        // The payload is streamed, so it's not part of the signature.
+        let (url, request) = self.request(Method::PUT, key, "UNSIGNED-PAYLOAD");
+        let response = request.body(body).send()?;
+        if !response.status().is_success() {
+            return Err(StorageError::Status(url, response.status()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for S3Storage {
+    fn fetch(&self, key: &str, path: &Path) -> Result<bool, StorageError> {
+        let Some(mut response) = self.get(key)? else {
+            return Ok(false);
+        };
+        let mut file = File::create(path)?;
+        response.copy_to(&mut file)?;
+
+        Ok(true)
+    }
+
+    fn store(&self, key: &str, path: &Path) -> Result<(), StorageError> {
+        self.put(key, Body::from(File::open(path)?))
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        match self.get(key)? {
+            Some(response) => Ok(Some(response.bytes()?.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.put(key, Body::from(data.to_vec()))
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encode a path segment as S3 expects it in the canonical request.
+fn uri_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// `YYYYMMDD` and `YYYYMMDDTHHMMSSZ` of `time`, in UTC.
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date of a day count since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    );
+    (date, timestamp)
+}
@@ -0,0 +1,261 @@
+//! Streams of the messages of the runs to their clients.
+//!
+//! The tasks forwarding a run never wait for its client: the messages are queued by a
+//! [`RunSender`] and sent by a task of their own, at most one output message per
+//! [`OUTPUT_INTERVAL`], the output queued meanwhile being coalesced. Past [`MAX_QUEUED_BYTES`]
+//! of queued output, the new output is dropped, and the next message sent carries a gap telling
+//! how much. The markers, the final messages and the errors are always kept, and the logs of the
+//! run keep the whole output.
+
+use shared_models::cloudlet::agent::{execute_response::Stage, ExecuteResponse, StreamGap};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+/// Output waiting for the client, past which the new output is dropped.
+pub const MAX_QUEUED_BYTES: usize = 4 * 1024 * 1024;
+
+/// Output coalesced into a single message, well under the message size limit of gRPC.
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Time between two output messages sent to a client.
+pub const OUTPUT_INTERVAL: Duration = Duration::from_millis(20);
+
+pub type RunMessage = Result<ExecuteResponse, Status>;
+
+/// Stream to a client, and the sender queuing its messages.
+pub fn channel() -> (RunSender, ReceiverStream<RunMessage>) {
+    let shared = Arc::new(Shared::default());
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(pump(shared.clone(), tx));
+    (
+        RunSender(Arc::new(SenderHandle(shared))),
+        ReceiverStream::new(rx),
+    )
+}
+
+/// Queues the messages of a run for its client, without ever waiting for it. The stream ends
+/// once every clone is dropped and the queue is sent.
+#[derive(Clone)]
+pub struct RunSender(Arc<SenderHandle>);
+
+struct SenderHandle(Arc<Shared>);
+
+impl Drop for SenderHandle {
+    fn drop(&mut self) {
+        self.0.queue.lock().unwrap().finished = true;
+        self.0.notify.notify_one();
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<RunMessage>,
+    /// Output of the queued messages.
+    queued_bytes: usize,
+    /// Output dropped since the last message sent.
+    gap: StreamGap,
+    /// Every sender is dropped.
+    finished: bool,
+    /// The client is gone.
+    closed: bool,
+}
+
+impl RunSender {
+    /// Queue `message`, returning `false` if the client is gone.
+    pub fn send(&self, message: RunMessage) -> bool {
+        let shared = self.shared();
+        let mut queue = shared.queue.lock().unwrap();
+        if queue.closed {
+            return false;
+        }
+        match message {
+            Ok(response) if is_output(&response) => {
+                let size = output_bytes(&response);
+                if queue.queued_bytes + size > MAX_QUEUED_BYTES {
+                    queue.gap.dropped_messages += 1;
+                    queue.gap.dropped_bytes += size as u64;
+                    queue.gap.dropped_lines += output_lines(&response);
+                    return true;
+                }
+                queue.queued_bytes += size;
+                match queue.messages.back_mut() {
+                    Some(Ok(last)) if can_coalesce(last, &response) => coalesce(last, response),
+                    _ => queue.messages.push_back(Ok(response)),
+                }
+            }
+            message => queue.messages.push_back(message),
+        }
+        drop(queue);
+        shared.notify.notify_one();
+        true
+    }
+
+    /// Whether the client is gone.
+    pub fn is_closed(&self) -> bool {
+        self.shared().queue.lock().unwrap().closed
+    }
+
+    fn shared(&self) -> &Shared {
+        &(self.0).0
+    }
+}
+
+/// Only output, which may be coalesced or dropped.
+fn is_output(response: &ExecuteResponse) -> bool {
+    matches!(
+        response.stage(),
+        Stage::Pending | Stage::Building | Stage::Running
+    ) && response.marker.is_none()
+        && response.exit_code.is_none()
+        && response.output.is_none()
+        && response.build_id.is_empty()
+        && response.artifact.is_empty()
+        && response.artifacts.is_empty()
+        && response.vm_exit.is_none()
+}
+
+fn output_bytes(response: &ExecuteResponse) -> usize {
+    [&response.stdout, &response.stderr]
+        .into_iter()
+        .flatten()
+        .map(Vec::len)
+        .sum()
+}
+
+fn output_lines(response: &ExecuteResponse) -> u64 {
+    [&response.stdout, &response.stderr]
+        .into_iter()
+        .flatten()
+        .map(|output| output.iter().filter(|byte| **byte == b'\n').count() as u64)
+        .sum()
+}
+
+/// Whether `next` may be appended to `last`: only the order between stdout and stderr is lost.
+fn can_coalesce(last: &ExecuteResponse, next: &ExecuteResponse) -> bool {
+    is_output(last)
+        && last.stage == next.stage
+        && last.encoding == next.encoding
+        && output_bytes(last) + output_bytes(next) <= MAX_MESSAGE_BYTES
+}
+
+fn coalesce(last: &mut ExecuteResponse, next: ExecuteResponse) {
+    for (output, more) in [
+        (&mut last.stdout, next.stdout),
+        (&mut last.stderr, next.stderr),
+    ] {
+        if let Some(more) = more {
+            output.get_or_insert_with(Vec::new).extend(more);
+        }
+    }
+    last.truncated |= next.truncated;
+}
+
+impl Shared {
+    /// The next message for the client, `None` once the stream ended.
+    async fn next(&self) -> Option<RunMessage> {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(mut message) = queue.messages.pop_front() {
+                    if let Ok(response) = &mut message {
+                        if is_output(response) {
+                            queue.queued_bytes -= output_bytes(response);
+                        }
+                        if queue.gap.dropped_messages > 0 {
+                            response.gap = Some(std::mem::take(&mut queue.gap));
+                        }
+                    }
+                    return Some(message);
+                }
+                if queue.finished {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        queue.messages.clear();
+        queue.queued_bytes = 0;
+    }
+}
+
+/// Send the queued messages to the client, until the stream ends or the client is gone.
+async fn pump(shared: Arc<Shared>, tx: mpsc::Sender<RunMessage>) {
+    while let Some(message) = shared.next().await {
+        let output = matches!(&message, Ok(response) if is_output(response));
+        if tx.send(message).await.is_err() {
+            shared.close();
+            return;
+        }
+        if output {
+            tokio::time::sleep(OUTPUT_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_models::cloudlet::agent::StageMarker;
+    use tokio_stream::StreamExt;
+
+    fn output(stdout: &[u8]) -> RunMessage {
+        Ok(ExecuteResponse {
+            stage: Stage::Running as i32,
+            stdout: Some(stdout.to_vec()),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_gets_coalesced_output_and_gap() {
+        let (tx, stream) = channel();
+        let line = vec![b'x'; 1023];
+        let lines = MAX_QUEUED_BYTES / 1024 + 10;
+        for _ in 0..lines {
+            let mut message = line.clone();
+            message.push(b'\n');
+            assert!(tx.send(output(&message)));
+        }
+        assert!(tx.send(Ok(ExecuteResponse {
+            stage: Stage::Done as i32,
+            exit_code: Some(0),
+            marker: Some(StageMarker::default()),
+            ..Default::default()
+        })));
+        drop(tx);
+
+        let messages: Vec<ExecuteResponse> = stream.map(|message| message.unwrap()).collect().await;
+        // The output was queued before the client read any.
+        assert_eq!(messages.len(), MAX_QUEUED_BYTES / MAX_MESSAGE_BYTES + 1);
+        assert_eq!(messages.last().unwrap().stage(), Stage::Done);
+        let received: usize = messages.iter().map(output_bytes).sum();
+        let gap = messages[0].gap.clone().unwrap();
+        assert_eq!(received as u64 + gap.dropped_bytes, (lines * 1024) as u64);
+        assert_eq!(gap.dropped_lines, gap.dropped_messages);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_gone_client() {
+        let (tx, stream) = channel();
+        drop(stream);
+        assert!(tx.send(output(b"a\n")));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(tx.is_closed());
+        assert!(!tx.send(output(b"b\n")));
+    }
+}
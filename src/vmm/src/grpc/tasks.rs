@@ -0,0 +1,200 @@
+//! Tasks spawned for the runs, tied to the VM they serve.
+//!
+//! The tasks of a run, running its VMM, connecting to its agent and relaying the agent to the
+//! client, are spawned on the [`VmTasks`] of its VM instead of being detached. A panic is logged
+//! and becomes a `CLDT-VMM-030` status, returned to the code awaiting the task or sent to the
+//! client of the run, instead of silently ending the stream. Once the VM stopped, the tasks left
+//! get [`SHUTDOWN_GRACE`] to send their last messages, then are aborted: none outlives its VM.
+
+use super::stream::RunSender;
+use futures::FutureExt;
+use shared_models::ErrorCode;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tonic::{Code, Status};
+use tracing::{error, warn};
+
+/// Time the tasks of a VM get to finish once it stopped.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Tasks of the VM `vm_id`.
+#[derive(Clone)]
+pub struct VmTasks {
+    vm_id: Arc<str>,
+    handles: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl VmTasks {
+    pub fn new(vm_id: &str) -> Self {
+        Self {
+            vm_id: vm_id.into(),
+            handles: Arc::default(),
+        }
+    }
+
+    /// Spawn `task`, returning its output, or the status of its panic or of its abortion. The
+    /// task runs even if the returned future is dropped.
+    pub fn spawn<T, F>(
+        &self,
+        name: &'static str,
+        task: F,
+    ) -> impl Future<Output = Result<T, Status>> + Send + 'static
+    where
+        T: Send + 'static,
+        F: Future<Output = T> + Send + 'static,
+    {
+        let handle = tokio::spawn(catch(self.vm_id.clone(), name, task));
+        self.track(handle.abort_handle());
+        async move {
+            handle.await.unwrap_or_else(|_| {
+                Err(Status::aborted(format!(
+                    "The {} task was stopped with its VM",
+                    name
+                )))
+            })
+        }
+    }
+
+    /// Spawn the blocking `task` on a thread of its own. It can't be aborted.
+    pub fn spawn_blocking<T, F>(
+        &self,
+        name: &'static str,
+        task: F,
+    ) -> impl Future<Output = Result<T, Status>> + Send + 'static
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let vm_id = self.vm_id.clone();
+        let handle = tokio::task::spawn_blocking(task);
+        async move {
+            handle.await.map_err(|e| match e.try_into_panic() {
+                Ok(panic) => panicked(&vm_id, name, panic),
+                Err(e) => Status::cancelled(e.to_string()),
+            })
+        }
+    }
+
+    /// Spawn `task`, which streams the messages of a run through `tx`, the client getting the
+    /// status of its panic if it panics.
+    pub fn spawn_relay<F>(&self, name: &'static str, tx: RunSender, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let result = catch(self.vm_id.clone(), name, task);
+        let handle = tokio::spawn(async move {
+            if let Err(status) = result.await {
+                tx.send(Err(status));
+            }
+        });
+        self.track(handle.abort_handle());
+    }
+
+    /// The VM stopped: abort the tasks still running after [`SHUTDOWN_GRACE`].
+    pub fn vm_stopped(&self) {
+        self.abort_after(SHUTDOWN_GRACE);
+    }
+
+    fn abort_after(&self, grace: Duration) {
+        let tasks = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            let handles = std::mem::take(&mut *tasks.handles.lock().unwrap());
+            let running: Vec<AbortHandle> =
+                handles.into_iter().filter(|h| !h.is_finished()).collect();
+            if !running.is_empty() {
+                warn!(
+                    vm_id = %tasks.vm_id,
+                    tasks = running.len(),
+                    "Aborting the tasks left after the VM stopped"
+                );
+            }
+            for handle in running {
+                handle.abort();
+            }
+        });
+    }
+
+    fn track(&self, handle: AbortHandle) {
+        let mut handles = self.handles.lock().unwrap();
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(handle);
+    }
+}
+
+/// `task`, its panic turned into a status.
+async fn catch<T>(
+    vm_id: Arc<str>,
+    name: &'static str,
+    task: impl Future<Output = T>,
+) -> Result<T, Status> {
+    AssertUnwindSafe(task)
+        .catch_unwind()
+        .await
+        .map_err(|panic| panicked(&vm_id, name, panic))
+}
+
+fn panicked(vm_id: &str, name: &str, panic: Box<dyn Any + Send>) -> Status {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+    error!(vm_id, task = name, panic = %message, "A task of the VM panicked");
+    ErrorCode::VmmTaskPanicked.status(
+        Code::Internal,
+        format!(
+            "The {} task of the VM {} panicked: {}",
+            name, vm_id, message
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::stream;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_panics_become_statuses() {
+        let tasks = VmTasks::new("hello-0123abcd");
+        assert_eq!(tasks.spawn("answer", async { 42 }).await.unwrap(), 42);
+
+        let status = tasks
+            .spawn::<(), _>("connect", async { panic!("no agent") })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            ErrorCode::from_status(&status),
+            Some(ErrorCode::VmmTaskPanicked)
+        );
+        assert!(status.message().contains("no agent"));
+
+        let status = tasks
+            .spawn_blocking("vmm", || -> u32 { panic!("KVM_RUN") })
+            .await
+            .unwrap_err();
+        assert!(status.message().contains("KVM_RUN"));
+
+        let (tx, mut rx) = stream::channel();
+        tasks.spawn_relay("relay", tx, async { panic!("lost the stream") });
+        let message = rx.next().await.unwrap();
+        assert!(message.unwrap_err().message().contains("lost the stream"));
+        assert!(rx.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tasks_stop_with_their_vm() {
+        let tasks = VmTasks::new("hello-0123abcd");
+        let pending = tasks.spawn("relay", std::future::pending::<()>());
+        tasks.abort_after(Duration::from_millis(10));
+        let status = pending.await.unwrap_err();
+        assert_eq!(status.code(), Code::Aborted);
+        assert!(tasks.handles.lock().unwrap().is_empty());
+    }
+}
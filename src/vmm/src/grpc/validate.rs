@@ -0,0 +1,583 @@
+//! Checks of a configuration file of the orchestrator, `vmm validate-config`, before it is
+//! deployed.
+//!
+//! Beyond what the orchestrator checks when it loads the file, the settings are checked against
+//! each other and against the host: the files and kernels named must exist, the orchestrator
+//! must be able to listen where it is told to without the guests reaching it, the guest networks
+//! must not overlap the networks the host already routes, and the admission policy must not
+//! admit guests the scheduler would never find room for.
+
+use super::{
+    admission::AdmissionPolicy,
+    config::OrchestratorConfig,
+    kernels::{KernelRegistry, BUILTIN_KERNEL_CONFIG, REQUIRED_FEATURES},
+    scheduler::SchedulerConfig,
+    signatures::ArtifactSignatures,
+};
+use crate::core::{devices::virtio::net::BRIDGE_NAME, network::GUEST_NETWORKS};
+use clap::ValueEnum;
+use shared_models::{validation::Findings, Language};
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, TcpListener},
+    os::unix::fs::FileTypeExt,
+    path::Path,
+};
+use tracing_subscriber::filter::LevelFilter;
+
+/// Network routed by the host, from `/proc/net/route`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub interface: String,
+    pub network: (Ipv4Addr, u8),
+}
+
+/// What the configuration is checked against.
+#[derive(Debug, Clone, Default)]
+pub struct Host {
+    pub memory_mb: Option<u64>,
+    pub cpus: Option<usize>,
+    pub routes: Vec<Route>,
+}
+
+impl Host {
+    pub fn probe() -> Self {
+        Self {
+            memory_mb: fs::read_to_string("/proc/meminfo")
+                .ok()
+                .and_then(|meminfo| parse_memory_mb(&meminfo)),
+            cpus: std::thread::available_parallelism().ok().map(usize::from),
+            routes: fs::read_to_string("/proc/net/route")
+                .map(|routes| parse_routes(&routes))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Interface of the default route of the host, through which the guests reach the outside
+/// world, if it has one.
+pub fn probe_default_route() -> Option<String> {
+    fs::read_to_string("/proc/net/route")
+        .ok()
+        .and_then(|routes| parse_default_route(&routes))
+}
+
+/// Interface of the default route of `/proc/net/route`, the guest bridges aside.
+fn parse_default_route(routes: &str) -> Option<String> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let default = fields.get(1) == Some(&"00000000") && fields.get(7) == Some(&"00000000");
+        (default && !is_guest_bridge(fields[0])).then(|| fields[0].to_string())
+    })
+}
+
+/// Total memory of the host in `/proc/meminfo`.
+fn parse_memory_mb(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
+
+/// Networks of `/proc/net/route`, the default route aside. Addresses are in hexadecimal, in the
+/// byte order of the host.
+fn parse_routes(routes: &str) -> Vec<Route> {
+    let address = |hex: &str| u32::from_str_radix(hex, 16).ok().map(u32::to_ne_bytes);
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (destination, mask) = (address(fields.get(1)?)?, address(fields.get(7)?)?);
+            let prefix = u32::from_be_bytes(mask).count_ones() as u8;
+            (prefix > 0).then(|| Route {
+                interface: fields[0].to_string(),
+                network: (Ipv4Addr::from(destination), prefix),
+            })
+        })
+        .collect()
+}
+
+/// Whether the networks `a` and `b` share addresses.
+fn overlaps(a: (Ipv4Addr, u8), b: (Ipv4Addr, u8)) -> bool {
+    let prefix = u32::from(a.1.min(b.1));
+    let network = |ip: Ipv4Addr| u32::from(ip).checked_shr(32 - prefix).unwrap_or(0);
+    network(a.0) == network(b.0)
+}
+
+/// Whether the interface is one of the guest bridges of the orchestrator.
+fn is_guest_bridge(interface: &str) -> bool {
+    interface == BRIDGE_NAME
+        || interface
+            .strip_prefix("cltn")
+            .is_some_and(|index| index.parse::<u8>().is_ok())
+}
+
+/// Check the interface `uplink` the guests are bound to, `--uplink`, when the orchestrator
+/// starts: it must be a host interface other than the guest bridges, and is expected up and
+/// routing the outside world.
+pub fn check_uplink(uplink: &str, findings: &mut Findings) {
+    check_uplink_in(
+        Path::new("/sys/class/net"),
+        probe_default_route().as_deref(),
+        uplink,
+        findings,
+    )
+}
+
+fn check_uplink_in(
+    sys_class_net: &Path,
+    default_route: Option<&str>,
+    uplink: &str,
+    findings: &mut Findings,
+) {
+    if is_guest_bridge(uplink) {
+        findings.error(format!(
+            "The uplink {} is a guest bridge of the orchestrator",
+            uplink
+        ));
+        return;
+    }
+    let interface = sys_class_net.join(uplink);
+    if uplink.is_empty() || uplink.contains('/') || !interface.exists() {
+        findings.error(format!("The uplink {} isn't a host interface", uplink));
+        return;
+    }
+
+    let state = fs::read_to_string(interface.join("operstate")).unwrap_or_default();
+    match state.trim() {
+        "up" => findings.ok(format!("The uplink {} is up", uplink)),
+        state => findings.warning(format!(
+            "The uplink {} is {}, the guests can't reach the outside until it's up",
+            uplink,
+            if state.is_empty() { "unknown" } else { state }
+        )),
+    }
+    if let Some(route) = default_route.filter(|route| *route != uplink) {
+        findings.warning(format!(
+            "The default route of the host goes through {}, the traffic of the guests through {} \
+             needs a route of its own",
+            route, uplink
+        ));
+    }
+}
+
+/// Load the configuration file at `path` and check it against this host.
+pub fn validate_config(path: &Path) -> Findings {
+    let mut findings = Findings::default();
+    match OrchestratorConfig::load(path) {
+        Ok(config) => {
+            findings.ok(format!("{:?} parses", path));
+            check_config(&config, &Host::probe(), &mut findings);
+            check_listen(&config, &mut findings);
+        }
+        Err(e) => findings.error(e.to_string()),
+    }
+    findings
+}
+
+/// Check `config`, and the files it names, against each other and against `host`.
+pub fn check_config(config: &OrchestratorConfig, host: &Host, findings: &mut Findings) {
+    findings.check(
+        config.validate().map_err(|e| e.to_string()),
+        "the addresses and ports are consistent",
+    );
+    if let Some(level) = &config.log_level {
+        if level.parse::<LevelFilter>().is_err() {
+            findings.error(format!("unknown log level {}", level));
+        }
+    }
+
+    let listen = config.listen();
+    if config.unix_socket.is_none() {
+        let guests_reach = match listen.ip() {
+            IpAddr::V4(ip) => ip.is_unspecified() || overlaps((ip, 32), GUEST_NETWORKS),
+            IpAddr::V6(ip) => ip.is_unspecified(),
+        };
+        if guests_reach {
+            findings.warning(format!(
+                "the orchestrator listens on {}, which the guests can reach through their bridge",
+                listen
+            ));
+        }
+    }
+
+    let guest_networks = format!("{}/{}", GUEST_NETWORKS.0, GUEST_NETWORKS.1);
+    let overlapping: Vec<String> = host
+        .routes
+        .iter()
+        .filter(|route| !is_guest_bridge(&route.interface))
+        .filter(|route| overlaps(route.network, GUEST_NETWORKS))
+        .map(|route| {
+            format!(
+                "{}/{} on {}",
+                route.network.0, route.network.1, route.interface
+            )
+        })
+        .collect();
+    if overlapping.is_empty() {
+        findings.ok(format!(
+            "the guest networks {} don't overlap the networks of the host",
+            guest_networks
+        ));
+    } else {
+        findings.error(format!(
+            "the guest networks {} overlap the networks routed by the host: {}",
+            guest_networks,
+            overlapping.join(", ")
+        ));
+    }
+
+    let signatures = match ArtifactSignatures::load(
+        config.artifact_public_keys.as_deref().unwrap_or_default(),
+        config.artifact_signing_key.as_deref(),
+    ) {
+        Ok(signatures) => {
+            if signatures.is_some() {
+                findings.ok("the artifact signing keys load");
+            }
+            signatures
+        }
+        Err(e) => {
+            findings.error(e.to_string());
+            None
+        }
+    };
+    let kernels = check_kernels(config, signatures.as_ref(), findings);
+    let admission =
+        config
+            .admission_policy
+            .as_deref()
+            .and_then(|path| match AdmissionPolicy::load(path) {
+                Ok(policy) => {
+                    findings.ok(format!("the admission policy {:?} parses", path));
+                    Some(policy)
+                }
+                Err(e) => {
+                    findings.error(e.to_string());
+                    None
+                }
+            });
+    let scheduler = match &config.scheduler {
+        Some(path) => match SchedulerConfig::load(path) {
+            Ok(scheduler) => {
+                findings.ok(format!("the scheduler configuration {:?} parses", path));
+                Some(scheduler)
+            }
+            Err(e) => {
+                findings.error(e.to_string());
+                None
+            }
+        },
+        None => Some(SchedulerConfig::default()),
+    };
+
+    if let Some(scheduler) = &scheduler {
+        check_scheduler(scheduler, host, findings);
+    }
+    if let Some(admission) = &admission {
+        check_admission(
+            admission,
+            kernels.as_ref(),
+            scheduler.as_ref(),
+            host,
+            findings,
+        );
+    }
+}
+
+/// Check that the kernels exist, are signed if `signatures` are checked, and can boot the
+/// guests, returning them if they could be loaded.
+fn check_kernels(
+    config: &OrchestratorConfig,
+    signatures: Option<&ArtifactSignatures>,
+    findings: &mut Findings,
+) -> Option<KernelRegistry> {
+    let registry =
+        match KernelRegistry::load(Path::new(BUILTIN_KERNEL_CONFIG), config.kernels.as_deref()) {
+            Ok(registry) => registry,
+            Err(e) => {
+                findings.error(e.to_string());
+                return None;
+            }
+        };
+
+    for kernel in registry.kernels() {
+        if let Some(path) = &kernel.path {
+            if !path.is_file() {
+                findings.error(format!(
+                    "the image {:?} of the kernel {} doesn't exist",
+                    path, kernel.name
+                ));
+                continue;
+            }
+            if let Some(Err(e)) = signatures.map(|signatures| signatures.verify(path)) {
+                findings.error(e.to_string());
+                continue;
+            }
+        }
+        let missing = kernel.missing_features(&REQUIRED_FEATURES);
+        if missing.is_empty() {
+            findings.ok(format!("the kernel {} can boot the guests", kernel.name));
+        } else {
+            findings.error(format!(
+                "the kernel {} lacks {}, which the guests need",
+                kernel.name,
+                missing.join(", ")
+            ));
+        }
+    }
+    Some(registry)
+}
+
+fn check_scheduler(scheduler: &SchedulerConfig, host: &Host, findings: &mut Findings) {
+    if scheduler.max_vms == Some(0) {
+        findings.error("the scheduler admits no guest, max-vms is 0");
+    }
+    match (scheduler.max_memory_mb, host.memory_mb) {
+        (Some(0), _) => findings.error("the scheduler admits no guest, max-memory-mb is 0"),
+        (Some(max), Some(host_mb)) if max > host_mb => findings.warning(format!(
+            "the scheduler lets the guests take {} MiB of memory, more than the {} MiB of the host",
+            max, host_mb
+        )),
+        _ => {}
+    }
+    let reserved = [
+        (
+            "reserved-memory-mb",
+            "MiB of memory",
+            scheduler.reserved_memory_mb,
+            host.memory_mb,
+        ),
+        (
+            "reserved-cpus",
+            "CPUs",
+            scheduler.reserved_cpus.map(|cpus| cpus as u64),
+            host.cpus.map(|cpus| cpus as u64),
+        ),
+    ];
+    for (setting, unit, reserved, available) in reserved {
+        if let (Some(reserved), Some(available)) = (reserved, available) {
+            if reserved >= available {
+                findings.error(format!(
+                    "the scheduler admits no guest, {} reserves {} {} of the {} of the host",
+                    setting, reserved, unit, available
+                ));
+            }
+        }
+    }
+}
+
+fn check_admission(
+    admission: &AdmissionPolicy,
+    kernels: Option<&KernelRegistry>,
+    scheduler: Option<&SchedulerConfig>,
+    host: &Host,
+    findings: &mut Findings,
+) {
+    for (prefix, rules) in admission.rules() {
+        let scope = match prefix {
+            Some(prefix) => format!("the admission rules of the workloads {}...", prefix),
+            None => "the admission rules".to_string(),
+        };
+        let before = findings.errors() + findings.warnings();
+
+        if let (Some(max), Some(capacity)) = (
+            rules.max_memory_mb,
+            scheduler.and_then(|scheduler| scheduler.max_memory_mb),
+        ) {
+            if u64::from(max) > capacity {
+                findings.error(format!(
+                    "{} admit guests of up to {} MiB, which never fit in the {} MiB of the scheduler",
+                    scope, max, capacity
+                ));
+            }
+        }
+        if let (Some(max), Some(host_mb)) = (rules.max_memory_mb, host.memory_mb) {
+            if u64::from(max) > host_mb {
+                findings.warning(format!(
+                    "{} admit guests of up to {} MiB, more than the {} MiB of the host",
+                    scope, max, host_mb
+                ));
+            }
+        }
+        if let (Some(max), Some(cpus)) = (rules.max_cpus, host.cpus) {
+            if max as usize > cpus {
+                findings.warning(format!(
+                    "{} admit guests of up to {} vCPUs, more than the {} CPUs of the host",
+                    scope, max, cpus
+                ));
+            }
+        }
+        for language in rules.languages.iter().flatten() {
+            if Language::from_str(language, true).is_err() {
+                findings.error(format!("{} allow the unknown language {}", scope, language));
+            }
+        }
+        if let Some(registry) = kernels {
+            for kernel in rules.kernels.iter().flatten() {
+                if registry.get(kernel).is_none() {
+                    findings.error(format!("{} allow the unknown kernel {}", scope, kernel));
+                }
+            }
+        }
+
+        if findings.errors() + findings.warnings() == before {
+            findings.ok(format!("{} are consistent", scope));
+        }
+    }
+}
+
+/// Check that the orchestrator can listen where it is configured to, which is only known by
+/// trying.
+fn check_listen(config: &OrchestratorConfig, findings: &mut Findings) {
+    if let Some(path) = &config.unix_socket {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.file_type().is_socket() => findings.error(format!(
+                "the socket path {:?} exists and is not a socket",
+                path
+            )),
+            _ => findings.ok(format!("the orchestrator can listen on {:?}", path)),
+        }
+        return;
+    }
+
+    let listen = config.listen();
+    match TcpListener::bind(listen) {
+        Ok(_) => findings.ok(format!("the orchestrator can listen on {}", listen)),
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => findings.warning(format!(
+            "{} is already in use, by a running orchestrator or another server",
+            listen
+        )),
+        Err(e) => findings.error(format!(
+            "the orchestrator can't listen on {}: {}",
+            listen, e
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cloudlet_test_registry::scratch_dir;
+    use shared_models::validation::Severity;
+
+    const ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t0102A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0002A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+br0\t00001DAC\t00000000\t0001\t0\t0\t0\t0000FFFF\t0\t0\t0
+wg0\t00401DAC\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+
+    #[test]
+    fn test_parse_routes() {
+        let routes = parse_routes(ROUTES);
+        assert_eq!(
+            routes,
+            vec![
+                Route {
+                    interface: "eth0".into(),
+                    network: (Ipv4Addr::new(192, 168, 2, 0), 24),
+                },
+                Route {
+                    interface: "br0".into(),
+                    network: (Ipv4Addr::new(172, 29, 0, 0), 16),
+                },
+                Route {
+                    interface: "wg0".into(),
+                    network: (Ipv4Addr::new(172, 29, 64, 0), 24),
+                },
+            ]
+        );
+        assert!(overlaps(routes[2].network, GUEST_NETWORKS));
+        assert!(!overlaps(routes[0].network, GUEST_NETWORKS));
+        assert_eq!(parse_default_route(ROUTES).as_deref(), Some("eth0"));
+        assert_eq!(
+            parse_default_route(&ROUTES.replace("eth0\t00000000", "cltn3\t00000000")),
+            None
+        );
+        assert_eq!(
+            parse_memory_mb("MemTotal:       16384000 kB\n"),
+            Some(16000)
+        );
+    }
+
+    #[test]
+    fn test_check_uplink() {
+        let sys_class_net = scratch_dir("vmm-validate-uplink");
+        fs::create_dir_all(sys_class_net.join("eth1")).unwrap();
+        fs::write(sys_class_net.join("eth1/operstate"), "down\n").unwrap();
+        let check = |uplink, default_route| {
+            let mut findings = Findings::default();
+            check_uplink_in(&sys_class_net, default_route, uplink, &mut findings);
+            (findings.errors(), findings.warnings())
+        };
+
+        assert_eq!(check("br0", Some("eth0")), (1, 0));
+        assert_eq!(check("eth2", Some("eth0")), (1, 0));
+        // Down and off the default route.
+        assert_eq!(check("eth1", Some("eth0")), (0, 2));
+        fs::write(sys_class_net.join("eth1/operstate"), "up\n").unwrap();
+        assert_eq!(check("eth1", Some("eth1")), (0, 0));
+        assert_eq!(check("eth1", None), (0, 0));
+    }
+
+    #[test]
+    fn test_check_config() {
+        let dir = scratch_dir("vmm-validate-config");
+        fs::write(dir.join("scheduler.toml"), "max-memory-mb = 4096\n").unwrap();
+        fs::write(
+            dir.join("admission.toml"),
+            "[rules]\nmax-memory-mb = 2048\nlanguages = [\"python\"]\n\n\
+             [[tenant]]\nprefix = \"batch-\"\nmax-memory-mb = 8192\nlanguages = [\"cobol\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("kernels.toml"),
+            "[[kernel]]\nname = \"lts\"\npath = \"missing/vmlinux\"\n",
+        )
+        .unwrap();
+        let host = Host {
+            memory_mb: Some(16384),
+            cpus: Some(8),
+            routes: parse_routes(ROUTES),
+        };
+
+        let config = OrchestratorConfig {
+            admission_policy: Some(dir.join("admission.toml")),
+            scheduler: Some(dir.join("scheduler.toml")),
+            ..Default::default()
+        };
+        let mut findings = Findings::default();
+        check_config(&config, &host, &mut findings);
+        let errors: Vec<&str> = findings
+            .findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Error)
+            .map(|finding| finding.message.as_str())
+            .collect();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].contains("wg0"));
+        assert!(errors[1].contains("batch-") && errors[1].contains("4096"));
+        assert!(errors[2].contains("cobol"));
+        assert!(findings
+            .findings
+            .iter()
+            .any(|finding| finding.message == "the admission rules are consistent"));
+
+        let config = OrchestratorConfig {
+            kernels: Some(dir.join("kernels.toml")),
+            listen: Some("172.29.0.1:50051".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut findings = Findings::default();
+        check_config(&config, &Host::default(), &mut findings);
+        assert_eq!(findings.errors(), 1);
+        assert!(findings
+            .findings
+            .iter()
+            .any(|finding| finding.message.contains("missing/vmlinux")));
+        assert_eq!(findings.warnings(), 1);
+        assert_eq!(findings.exit_code(), 1);
+    }
+}
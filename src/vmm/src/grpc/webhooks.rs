@@ -0,0 +1,459 @@
+//! Notification of the end of the runs to webhooks, e.g. to report them to a CI.
+//!
+//! Each webhook receives a [`Payload`] as the JSON body of a `POST`. The
+//! `X-Cloudlet-Timestamp` header holds the time of the attempt, in seconds since the Unix
+//! epoch, and with a secret the `X-Cloudlet-Signature` header holds
+//! `sha256=<hex HMAC-SHA256 of "<timestamp>.<body>">`, computed with it: a receiver refusing
+//! the old timestamps can't be sent a recorded delivery again. A delivery is attempted again,
+//! with an exponential backoff, after a network error, a 429 or a 5xx response; its outcome is
+//! published as a `WEBHOOK_DELIVERED` or `WEBHOOK_FAILED` event of the VM, which names the host
+//! of the webhook: the paths of some webhooks are secrets.
+//!
+//! The webhooks are given by the clients, so the orchestrator doesn't post to its own network:
+//! the loopback, private, link-local and unique local addresses are refused, whether in the
+//! URL or resolved from its host, unless the host is one of the internal hosts allowed by the
+//! operator. Redirects aren't followed, they could lead anywhere.
+
+use crate::grpc::events::EventBus;
+use hmac::{Hmac, Mac};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::Serialize;
+use sha2::Sha256;
+use shared_models::vmmorchestrator::{VmEventKind, Webhook};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+pub const SIGNATURE_HEADER: &str = "X-Cloudlet-Signature";
+
+pub const TIMESTAMP_HEADER: &str = "X-Cloudlet-Timestamp";
+
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the second attempt, doubled for each of the next ones.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of the notifications.
+#[derive(Debug, Clone, Serialize)]
+pub struct Payload {
+    /// `run.finished` or `run.failed`.
+    pub event: &'static str,
+    pub vm_id: String,
+    pub workload_name: String,
+    pub exit_code: Option<i32>,
+    /// Outcome of the run, as in its `RUN_FINISHED` or `RUN_FAILED` event.
+    pub message: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl Payload {
+    /// Payload of the end of the run of `vm_id`, `kind` being its `RUN_FINISHED` or
+    /// `RUN_FAILED` event.
+    pub fn new(
+        vm_id: &str,
+        workload_name: &str,
+        kind: VmEventKind,
+        exit_code: Option<i32>,
+        message: &str,
+    ) -> Self {
+        Self {
+            event: match kind {
+                VmEventKind::RunFinished => "run.finished",
+                _ => "run.failed",
+            },
+            vm_id: vm_id.to_string(),
+            workload_name: workload_name.to_string(),
+            exit_code,
+            message: message.to_string(),
+            timestamp: unix_secs(),
+        }
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// `sha256=<hex digest>` of `body`, signed with `secret`.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// What is signed of the `body` of an attempt at `timestamp`.
+pub fn signed_content(timestamp: u64, body: &[u8]) -> Vec<u8> {
+    let mut content = format!("{}.", timestamp).into_bytes();
+    content.extend_from_slice(body);
+    content
+}
+
+/// Whether `ip` is an address of the host or of its networks rather than of the Internet.
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Shared address space, RFC 6598.
+                || (ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local, fc00::/7, and link-local, fe80::/10.
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+/// Internal hosts the webhooks may be posted to.
+#[derive(Debug, Clone, Default)]
+struct Allowlist(Arc<[String]>);
+
+impl Allowlist {
+    fn allows(&self, host: &str) -> bool {
+        self.0
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    }
+
+    /// Why the webhook at `url` may not be posted to, if its host is an internal address;
+    /// the resolved addresses are checked by [`Resolver`].
+    fn refusal(&self, url: &str) -> Option<String> {
+        let url = reqwest::Url::parse(url).ok()?;
+        let host = url.host_str()?;
+        let ip: IpAddr = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .ok()?;
+        (is_internal(ip) && !self.allows(host)).then(|| format!("{} is an internal address", host))
+    }
+}
+
+/// Resolver of the hosts of the webhooks, without their internal addresses unless the host is
+/// allowed: checked as they are connected to, the addresses can't change in between.
+struct Resolver(Allowlist);
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self.0.allows(name.as_str());
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| allowed || !is_internal(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                let refusal = format!("{} only resolves to internal addresses", host);
+                return Err(Box::<dyn std::error::Error + Send + Sync>::from(refusal));
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Scheme and host of `url`, which can be logged.
+fn host(url: &str) -> &str {
+    let start = url.find("://").map_or(0, |scheme| scheme + 3);
+    match url[start..].find(|c: char| matches!(c, '/' | '?' | '#')) {
+        Some(end) => &url[..start + end],
+        None => url,
+    }
+}
+
+/// Sends the notifications of the end of the runs.
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    /// Notified of the end of every run, besides the webhooks of its request.
+    webhooks: Vec<Webhook>,
+    allowlist: Allowlist,
+    events: EventBus,
+    first_backoff: Duration,
+}
+
+impl WebhookNotifier {
+    /// Notifier of `webhooks` and of those of the requests, which may be posted to the
+    /// `internal_hosts` besides the Internet.
+    pub fn new(webhooks: Vec<Webhook>, internal_hosts: Vec<String>, events: EventBus) -> Self {
+        let allowlist = Allowlist(internal_hosts.into());
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(ATTEMPT_TIMEOUT)
+                .redirect(reqwest::redirect::Policy::none())
+                .dns_resolver(Arc::new(Resolver(allowlist.clone())))
+                .build()
+                .expect("the HTTP client has a valid configuration"),
+            webhooks,
+            allowlist,
+            events,
+            first_backoff: FIRST_BACKOFF,
+        }
+    }
+
+    /// URLs of the webhooks of the orchestrator.
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.webhooks.iter().map(|webhook| webhook.url.as_str())
+    }
+
+    /// Notify `payload` to the webhooks of the orchestrator and to `webhooks`, in the
+    /// background.
+    pub fn notify(&self, payload: Payload, webhooks: &[Webhook]) {
+        for webhook in self.webhooks.iter().chain(webhooks) {
+            let (notifier, webhook, payload) = (self.clone(), webhook.clone(), payload.clone());
+            tokio::spawn(async move {
+                let target = host(&webhook.url);
+                let (kind, message) = match notifier.deliver(&webhook, &payload).await {
+                    Ok(attempts) => {
+                        info!(vm_id = %payload.vm_id, webhook = target, attempts, "Notified the end of the run");
+                        (
+                            VmEventKind::WebhookDelivered,
+                            format!("{} after {} attempt(s)", target, attempts),
+                        )
+                    }
+                    Err(e) => {
+                        warn!(vm_id = %payload.vm_id, webhook = target, error = %e, "Could not notify the end of the run");
+                        (VmEventKind::WebhookFailed, format!("{}: {}", target, e))
+                    }
+                };
+                notifier
+                    .events
+                    .publish(&payload.vm_id, &payload.workload_name, kind, message);
+            });
+        }
+    }
+
+    /// Post `payload` to `webhook` until it is accepted, and return the number of attempts.
+    async fn deliver(&self, webhook: &Webhook, payload: &Payload) -> Result<u32, String> {
+        if let Some(refusal) = self.allowlist.refusal(&webhook.url) {
+            return Err(refusal);
+        }
+        let body = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+        let mut backoff = self.first_backoff;
+        let mut attempt = 1;
+        loop {
+            let timestamp = unix_secs();
+            let mut request = self
+                .client
+                .post(&webhook.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(TIMESTAMP_HEADER, timestamp)
+                .body(body.clone());
+            if !webhook.secret.is_empty() {
+                let signature = signature(&webhook.secret, &signed_content(timestamp, &body));
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            let error = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(attempt),
+                Ok(response)
+                    if response.status().is_server_error()
+                        || response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    format!("HTTP {}", response.status().as_u16())
+                }
+                Ok(response) => return Err(format!("HTTP {}", response.status().as_u16())),
+                // Without the URL, which may be secret.
+                Err(e) => e.without_url().to_string(),
+            };
+            if attempt == MAX_ATTEMPTS {
+                return Err(format!("{} after {} attempts", error, attempt));
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    type Received = Arc<Mutex<Vec<(String, u64, Vec<u8>)>>>;
+
+    /// Webhook answering with `statuses` in turn, and recording the signature, timestamp and
+    /// body of the requests it receives.
+    fn serve(statuses: &'static [u16]) -> (String, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/token", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        std::thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let (mut signature, mut timestamp, mut length) = (String::new(), 0, 0);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let Some((name, value)) = line.trim_end().split_once(": ") else {
+                        if line.trim_end().is_empty() {
+                            break;
+                        }
+                        continue;
+                    };
+                    match name.to_ascii_lowercase().as_str() {
+                        "content-length" => length = value.parse().unwrap(),
+                        "x-cloudlet-signature" => signature = value.to_string(),
+                        "x-cloudlet-timestamp" => timestamp = value.parse().unwrap(),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                received.lock().unwrap().push((signature, timestamp, body));
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn notifier() -> WebhookNotifier {
+        WebhookNotifier {
+            first_backoff: Duration::from_millis(10),
+            ..WebhookNotifier::new(Vec::new(), vec!["127.0.0.1".into()], EventBus::default())
+        }
+    }
+
+    fn payload() -> Payload {
+        Payload::new(
+            "hello-0123abcd",
+            "hello",
+            VmEventKind::RunFinished,
+            Some(0),
+            "exit code 0",
+        )
+    }
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!(
+            host("https://hooks.example.com/services/T0/B0/secret"),
+            "https://hooks.example.com"
+        );
+        assert_eq!(
+            host("http://127.0.0.1:8080?token=1"),
+            "http://127.0.0.1:8080"
+        );
+        assert_eq!(host("http://ci.example.com"), "http://ci.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_delivered() {
+        let (url, requests) = serve(&[503, 429, 200]);
+        let webhook = Webhook {
+            url,
+            secret: "secret".into(),
+        };
+
+        assert_eq!(notifier().deliver(&webhook, &payload()).await, Ok(3));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let (signature, timestamp, body) = &requests[2];
+        assert_eq!(
+            *signature,
+            super::signature("secret", &signed_content(*timestamp, body))
+        );
+        let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(body["event"], "run.finished");
+        assert_eq!(body["vm_id"], "hello-0123abcd");
+    }
+
+    #[test]
+    fn test_is_internal() {
+        for internal in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.29.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(is_internal(internal.parse().unwrap()), "{}", internal);
+        }
+        for public in ["1.1.1.1", "172.32.0.1", "2606:4700::1111"] {
+            assert!(!is_internal(public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_internal_hosts_are_refused() {
+        let (url, requests) = serve(&[200]);
+        let notifier = WebhookNotifier {
+            first_backoff: Duration::from_millis(10),
+            ..WebhookNotifier::new(Vec::new(), Vec::new(), EventBus::default())
+        };
+        let webhook = |url: String| Webhook {
+            url,
+            secret: String::new(),
+        };
+
+        assert_eq!(
+            notifier.deliver(&webhook(url), &payload()).await,
+            Err("127.0.0.1 is an internal address".to_string())
+        );
+        assert!(requests.lock().unwrap().is_empty());
+        // Resolved to the loopback.
+        assert!(notifier
+            .deliver(&webhook("http://localhost:1/".into()), &payload())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_not_retried() {
+        let (url, requests) = serve(&[404, 200]);
+        let webhook = Webhook {
+            url,
+            secret: String::new(),
+        };
+
+        assert_eq!(
+            notifier().deliver(&webhook, &payload()).await,
+            Err("HTTP 404".to_string())
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        // Unsigned without a secret.
+        assert!(requests[0].0.is_empty());
+    }
+}
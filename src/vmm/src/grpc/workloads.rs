@@ -0,0 +1,186 @@
+//! Workloads registered under a name and a version, so that their invocations only carry
+//! their inputs instead of their code and configuration.
+//!
+//! The registered workloads are kept together in the object `workloads.pb` of a storage, a
+//! `ListWorkloadsResponse` message. Like the recorded runs, they hold the environment of the
+//! workloads, secret variables included. Registrations aren't coordinated between the
+//! orchestrators sharing a bucket: register the workloads through a single one.
+
+use crate::grpc::runs;
+use crate::grpc::storage::{Storage, StorageError};
+use prost::Message;
+use shared_models::vmmorchestrator::{
+    CachePolicy, ListWorkloadsResponse, RegisteredWorkload, RunVmmRequest, ScalingPolicy,
+};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEY: &str = "workloads.pb";
+
+/// Where the registered workloads are kept.
+#[derive(Debug, Clone)]
+pub struct WorkloadStore {
+    storage: Arc<dyn Storage>,
+    /// Held while the workloads are read and written back.
+    lock: Arc<Mutex<()>>,
+}
+
+impl WorkloadStore {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            lock: Arc::default(),
+        }
+    }
+
+    fn read(&self) -> Result<Vec<RegisteredWorkload>, StorageError> {
+        match self.storage.read(KEY)? {
+            Some(bytes) => ListWorkloadsResponse::decode(bytes.as_slice())
+                .map(|response| response.workloads)
+                .map_err(|e| StorageError::Io(io::Error::other(e))),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Register `request` as `version` of the workload `name`, its pool of VMs bounded by
+    /// `scaling` and its results cached by the API along `cache`. Returns `None` if this
+    /// version is already registered.
+    pub fn register(
+        &self,
+        name: &str,
+        version: &str,
+        mut request: RunVmmRequest,
+        scaling: Option<ScalingPolicy>,
+        cache: Option<CachePolicy>,
+    ) -> Result<Option<RegisteredWorkload>, StorageError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut workloads = self.read()?;
+        if workloads
+            .iter()
+            .any(|workload| workload.name == name && workload.version == version)
+        {
+            return Ok(None);
+        }
+
+        request.workload_name = name.to_string();
+        let workload = RegisteredWorkload {
+            name: name.to_string(),
+            version: version.to_string(),
+            registered_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            code_digest: runs::code_digest(&request.code),
+            request: Some(request),
+            scaling,
+            cache,
+        };
+        workloads.push(workload.clone());
+        self.storage
+            .write(KEY, &ListWorkloadsResponse { workloads }.encode_to_vec())?;
+        Ok(Some(workload))
+    }
+
+    /// `version` of the workload `name`, or the version registered last if empty.
+    pub fn get(
+        &self,
+        name: &str,
+        version: &str,
+    ) -> Result<Option<RegisteredWorkload>, StorageError> {
+        Ok(self.read()?.into_iter().rev().find(|workload| {
+            workload.name == name && (version.is_empty() || workload.version == version)
+        }))
+    }
+
+    /// Registered workloads, only the versions of `name` if not empty, without their requests.
+    pub fn list(&self, name: &str) -> Result<Vec<RegisteredWorkload>, StorageError> {
+        Ok(self
+            .read()?
+            .into_iter()
+            .filter(|workload| name.is_empty() || workload.name == name)
+            .map(|workload| RegisteredWorkload {
+                request: None,
+                ..workload
+            })
+            .collect())
+    }
+}
+
+/// Names of workloads, as validated by the API: letters, digits, `-` and `_` only.
+pub fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+/// Versions are used in paths and printed as is: letters, digits, `.`, `-` and `_` only.
+pub fn is_version(version: &str) -> bool {
+    !version.is_empty()
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::storage::LocalStorage;
+    use cloudlet_test_registry::scratch_dir;
+
+    #[test]
+    fn test_register_versions() {
+        let storage = LocalStorage::new(scratch_dir("vmm-workloads")).unwrap();
+        let store = WorkloadStore::new(Arc::new(storage));
+        let request = |code: &str| RunVmmRequest {
+            workload_name: "ignored".into(),
+            code: code.into(),
+            ..Default::default()
+        };
+
+        let first = store
+            .register("hello", "1", request("v1"), None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.request.unwrap().workload_name, "hello");
+        store
+            .register("hello", "2", request("v2"), None, None)
+            .unwrap()
+            .unwrap();
+        store
+            .register("other", "1", request("other"), None, None)
+            .unwrap()
+            .unwrap();
+        assert!(store
+            .register("hello", "1", request("v3"), None, None)
+            .unwrap()
+            .is_none());
+
+        let code = |version| {
+            store
+                .get("hello", version)
+                .unwrap()
+                .unwrap()
+                .request
+                .unwrap()
+                .code
+        };
+        assert_eq!(code("1"), "v1");
+        assert_eq!(code(""), "v2");
+        assert!(store.get("hello", "3").unwrap().is_none());
+
+        let versions = store.list("hello").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(versions.iter().all(|workload| workload.request.is_none()));
+        assert_eq!(store.list("").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_is_version() {
+        assert!(is_version("1.2.0-rc_1"));
+        assert!(!is_version(""));
+        assert!(!is_version("../1"));
+        assert!(!is_version("1 2"));
+    }
+}
@@ -1,7 +1,45 @@
 pub mod core;
 pub mod grpc {
+    pub mod admin;
+    pub mod admission;
+    pub mod affinity;
+    pub mod artifacts;
+    pub mod builds;
     pub mod client;
+    pub mod config;
+    pub mod console;
+    pub mod crashes;
+    pub mod deadline;
+    pub mod deterministic;
+    pub mod events;
+    pub mod faults;
+    pub mod health;
+    pub mod history;
+    pub mod hypervisor;
+    pub mod integrity;
+    pub mod janitor;
+    pub mod kernels;
+    pub mod logs;
+    pub mod maintenance;
+    pub mod metering;
+    pub mod package_cache;
+    pub mod pool;
+    pub mod registry;
+    pub mod retries;
+    pub mod rootfs_builds;
+    pub mod runs;
+    pub mod runtimes;
+    pub mod scheduler;
     pub mod server;
+    pub mod sessions;
+    pub mod signatures;
+    pub mod startup;
+    pub mod storage;
+    pub mod stream;
+    pub mod tasks;
+    pub mod validate;
+    pub mod webhooks;
+    pub mod workloads;
 }
 
 #[derive(Debug)]
@@ -10,4 +48,5 @@ pub enum VmmErrors {
     VmmConfigure(core::Error),
     VmmRun(core::Error),
     VmmBuildEnvironment(std::io::Error),
+    VmmHypervisor(std::io::Error),
 }
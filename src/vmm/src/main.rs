@@ -1,13 +1,32 @@
 use crate::args::{CliArgs, Commands};
 use clap::Parser;
+use shared_models::cloudlet::agent::execute_response::Stage;
+use shared_models::{unix_socket, vmmorchestrator, FILE_DESCRIPTOR_SET};
+use std::io::Write;
+use std::sync::Arc;
+use tokio::net::UnixListener;
+use tokio_stream::{wrappers::UnixListenerStream, StreamExt};
 use tonic::transport::Server;
 use tracing::info;
+use tracing_subscriber::{filter::LevelFilter, prelude::*, reload};
 use vmm::{
-    core::vmm::VMM,
-    grpc::server::{vmmorchestrator, VmmService},
+    core::{
+        firecracker::{FirecrackerConfig, VmConfig},
+        root_disk::RootDisk,
+        vmm::VMM,
+    },
+    grpc::{
+        admin::{AdminService, VmTable},
+        config::{ConfigReloader, Settings},
+        deterministic::Deterministic,
+        server::{VmmService, VmmServiceConfig},
+        startup::{self, Orchestrator},
+        validate,
+    },
     VmmErrors,
 };
 mod args;
+mod bench;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -20,24 +39,137 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "Starting application",
     );
 
-    let addr = "[::1]:50051".parse().unwrap();
-    let vmm_service = VmmService;
-
     // check if the args is grpc or command
     match args.command {
-        Commands::Grpc => {
-            tracing_subscriber::fmt().init();
-            Server::builder()
-                .add_service(vmmorchestrator::vmm_service_server::VmmServiceServer::new(
-                    vmm_service,
+        Commands::Grpc(grpc_args) => {
+            // The options, overridden by the configuration file if any.
+            let options = grpc_args
+                .orchestrator
+                .options(grpc_args.listen, grpc_args.unix_socket.clone());
+            let config = grpc_args.orchestrator.load_config(&options)?;
+            let settings = Settings::load(&config)?;
+
+            let (log_filter, log_filter_handle) =
+                reload::Layer::new(args.log.filter(settings.log_level));
+            tracing_subscriber::registry()
+                .with(log_filter)
+                .with(args.log.layer())
+                .init();
+
+            let Orchestrator {
+                service,
+                vms,
+                janitor,
+                faults,
+                maintenance,
+            } = startup::start(&grpc_args.orchestrator, &config, &settings).await?;
+
+            let reflection_service = tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+                .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+                .build()?;
+
+            let config_file = grpc_args.orchestrator.config.clone();
+            let (health_reporter, health_service) = tonic_health::server::health_reporter();
+            tokio::spawn(service.clone().report_health(health_reporter));
+
+            let addr = config.listen();
+            let socket_path = config.unix_socket.clone();
+            let reloader = config_file.map(|path| {
+                info!(path = ?path, "Watching the configuration file");
+                Arc::new(ConfigReloader::new(
+                    path,
+                    options,
+                    config,
+                    settings,
+                    service.clone(),
+                    log_filter_handle,
+                    args.log.clone(),
                 ))
-                .serve(addr)
+            });
+            if let Some(reloader) = &reloader {
+                tokio::spawn(reloader.clone().watch());
+            }
+
+            let admin_service = grpc_args.enable_admin.then(|| {
+                info!("AdminService is enabled");
+                shared_models::compressed!(
+                    vmmorchestrator::admin::admin_service_server::AdminServiceServer::new(
+                        AdminService::new(
+                            vms,
+                            janitor,
+                            reloader,
+                            faults,
+                            maintenance,
+                            service.guest_addresses(),
+                            service.pool(),
+                        ),
+                    )
+                )
+            });
+
+            let server = Server::builder()
+                .add_service(health_service)
+                .add_service(reflection_service)
+                .add_optional_service(admin_service)
+                .add_service(shared_models::compressed!(
+                    vmmorchestrator::vmm_service_server::VmmServiceServer::from_arc(service)
+                ));
+            match &socket_path {
+                Some(path) => {
+                    let listener = unix_socket::bind(path, grpc_args.socket_mode)?;
+                    listener.set_nonblocking(true)?;
+                    info!(path = ?path, mode = format!("{:o}", grpc_args.socket_mode), "Listening on a Unix socket");
+                    server
+                        .serve_with_incoming(UnixListenerStream::new(UnixListener::from_std(
+                            listener,
+                        )?))
+                        .await?;
+                }
+                None => server.serve(addr).await?,
+            }
+        }
+        Commands::BenchBoot(bench_args) => {
+            args.log.init(LevelFilter::INFO);
+
+            bench::bench_boot(bench_args).await?;
+        }
+        Commands::Replay(replay_args) => {
+            args.log.init(LevelFilter::INFO);
+
+            let mode = Deterministic::new(replay_args.seed);
+            let service = VmmService::new(
+                VmTable::deterministic(&mode),
+                VmmServiceConfig {
+                    deterministic: Some(mode),
+                    ..Default::default()
+                },
+            );
+            let mut stream = service
+                .replay(&replay_args.session, replay_args.realtime)
                 .await?;
+            let mut exit_code = 1;
+            while let Some(message) = stream.next().await {
+                let response = message?;
+                std::io::stdout().write_all(response.stdout.as_deref().unwrap_or_default())?;
+                std::io::stderr().write_all(response.stderr.as_deref().unwrap_or_default())?;
+                if matches!(response.stage(), Stage::Done | Stage::Failed) {
+                    info!(stage = ?response.stage(), exit_code = ?response.exit_code, "The replayed run ended");
+                    exit_code = match response.stage() {
+                        Stage::Done => response.exit_code.unwrap_or_default(),
+                        _ => response.exit_code.filter(|code| *code != 0).unwrap_or(1),
+                    };
+                }
+            }
+            std::process::exit(exit_code);
+        }
+        Commands::ValidateConfig(validate_args) => {
+            let findings = validate::validate_config(&validate_args.file);
+            findings.print(&validate_args.file.display().to_string())?;
+            std::process::exit(findings.exit_code());
         }
         Commands::Cli(cli_args) => {
-            tracing_subscriber::fmt()
-                .with_max_level(cli_args.convert_log_to_tracing())
-                .init();
+            args.log.init(cli_args.convert_log_to_tracing());
 
             // Create a new VMM
             let mut vmm = VMM::new(
@@ -48,18 +180,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .map_err(VmmErrors::VmmNew)
             .unwrap();
 
+            let config = match (&cli_args.config_file, &cli_args.rootfs) {
+                (Some(path), _) => FirecrackerConfig::from_file(path)?.to_vm_config()?,
+                (None, Some(path)) => {
+                    let disk = RootDisk::open(path)?;
+                    VmConfig {
+                        cpus: cli_args.cpus,
+                        memory_mb: cli_args.memory,
+                        kernel: cli_args.kernel.clone().unwrap_or_default(),
+                        initramfs: None,
+                        kernel_cmdline: disk.kernel_cmdline(),
+                        pmem: Some((disk.path, disk.size_mb)),
+                    }
+                }
+                (None, None) => VmConfig {
+                    cpus: cli_args.cpus,
+                    memory_mb: cli_args.memory,
+                    kernel: cli_args.kernel.clone().unwrap_or_default(),
+                    initramfs: cli_args.initramfs.clone(),
+                    kernel_cmdline: Vec::new(),
+                    pmem: None,
+                },
+            };
+            vmm.set_kernel_cmdline(config.kernel_cmdline);
+            if let Some((path, size_mb)) = config.pmem {
+                vmm.set_pmem(path, size_mb);
+            }
+
             vmm.configure(
-                cli_args.cpus,
-                cli_args.memory,
-                cli_args.kernel,
-                &cli_args.initramfs,
+                config.cpus,
+                config.memory_mb,
+                config.kernel,
+                &config.initramfs,
             )
             .await
             .map_err(VmmErrors::VmmConfigure)
             .unwrap();
 
             // Run the VMM
-            vmm.run().map_err(VmmErrors::VmmRun).unwrap();
+            let exit = vmm.run().map_err(VmmErrors::VmmRun).unwrap();
+            info!(?exit, "The VM stopped");
         }
     }
 
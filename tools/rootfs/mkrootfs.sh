@@ -11,7 +11,9 @@ ulimit -Sn 8192
 
 if [ -d fs-gen ]
 then
-    cargo run --bin fs-gen -- $1 $2 -o $3 --no-compression
+    # the runtime of the language given as $4, if any, is warmed up in the image, and the
+    # metrics of the build are written to $5, if any
+    cargo run --bin fs-gen -- $1 $2 -o $3 --no-compression ${4:+--warmup $4} ${5:+--metrics-file $5}
 else
     echo "Module fs-gen not found"
 fi
\ No newline at end of file